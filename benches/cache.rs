@@ -0,0 +1,63 @@
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use pcap2socks::cache::{Queue, Window};
+
+/// Represents the size of each segment in benchmarks.
+const SEGMENT_SIZE: usize = 1460;
+/// Represents the count of segments in each iteration.
+const SEGMENTS: usize = 64;
+/// Represents the capacity of caches in benchmarks.
+const CAPACITY: usize = 256 * 1024;
+
+fn queue(c: &mut Criterion) {
+    let payload = vec![0u8; SEGMENT_SIZE];
+
+    let mut group = c.benchmark_group("queue");
+    group.throughput(Throughput::Bytes((SEGMENT_SIZE * SEGMENTS) as u64));
+    group.bench_function("append_invalidate", |b| {
+        let mut queue = Queue::with_capacity(CAPACITY, 0);
+        b.iter(|| {
+            for _ in 0..SEGMENTS {
+                queue.append(&payload, 1000).unwrap();
+            }
+            for _ in 0..SEGMENTS {
+                let sequence = queue.sequence().wrapping_add(SEGMENT_SIZE as u32);
+                queue.invalidate_to(sequence);
+            }
+        })
+    });
+    group.finish();
+}
+
+fn window(c: &mut Criterion) {
+    let payload = vec![0u8; SEGMENT_SIZE];
+
+    let mut group = c.benchmark_group("window");
+    group.throughput(Throughput::Bytes((SEGMENT_SIZE * SEGMENTS) as u64));
+    group.bench_function("append_in_order", |b| {
+        let mut window = Window::with_capacity(CAPACITY, 0);
+        let mut sequence = 0u32;
+        b.iter(|| {
+            for _ in 0..SEGMENTS {
+                window.append(sequence, &payload).unwrap().unwrap();
+                sequence = sequence.wrapping_add(SEGMENT_SIZE as u32);
+            }
+        })
+    });
+    group.bench_function("append_out_of_order", |b| {
+        let mut window = Window::with_capacity(CAPACITY, 0);
+        let mut sequence = 0u32;
+        b.iter(|| {
+            // Swap each pair of segments
+            for _ in 0..SEGMENTS / 2 {
+                let next = sequence.wrapping_add(SEGMENT_SIZE as u32);
+                assert!(window.append(next, &payload).unwrap().is_none());
+                window.append(sequence, &payload).unwrap().unwrap();
+                sequence = next.wrapping_add(SEGMENT_SIZE as u32);
+            }
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, queue, window);
+criterion_main!(benches);
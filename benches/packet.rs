@@ -0,0 +1,99 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use pcap2socks::packet::layer::ethernet::Ethernet;
+use pcap2socks::packet::layer::ipv4::Ipv4;
+use pcap2socks::packet::layer::tcp::Tcp;
+use pcap2socks::packet::layer::{Layer, Layers};
+use pcap2socks::packet::Indicator;
+use pnet::datalink::MacAddr;
+use pnet::packet::ethernet::EthernetPacket;
+use pnet::packet::ipv4::{self, Ipv4Packet};
+use pnet::packet::tcp::{self, TcpPacket};
+use pnet::packet::Packet;
+use std::net::Ipv4Addr;
+
+/// Represents the sizes of TCP payloads in benchmarks.
+const PAYLOAD_SIZES: [usize; 3] = [0, 536, 1460];
+
+/// Returns an `Indicator` of a TCP ACK from 1.1.1.1:80 to 10.6.0.2:1024.
+fn tcp_ack() -> Indicator {
+    let src = Ipv4Addr::new(10, 6, 0, 2);
+    let dst = Ipv4Addr::new(1, 1, 1, 1);
+
+    let mut tcp = Tcp::new_ack(80, 1024, 1, 1, 65535, None, None);
+    let ipv4 = Ipv4::new(0, tcp.kind(), dst, src).unwrap();
+    tcp.set_ipv4_layer(&ipv4);
+    let ethernet = Ethernet::new(
+        ipv4.kind(),
+        MacAddr(0x00, 0x11, 0x22, 0x33, 0x44, 0x55),
+        MacAddr(0x66, 0x77, 0x88, 0x99, 0xaa, 0xbb),
+    )
+    .unwrap();
+
+    Indicator::new(
+        Layers::Ethernet(ethernet),
+        Some(Layers::Ipv4(ipv4)),
+        Some(Layers::Tcp(tcp)),
+    )
+}
+
+/// Returns an Ethernet frame of a TCP ACK with the given size of payload.
+fn tcp_ack_frame(size: usize) -> Vec<u8> {
+    let indicator = tcp_ack();
+    let payload = vec![0u8; size];
+    let mut buffer = vec![0u8; indicator.len() + size];
+    indicator
+        .serialize_with_payload(&mut buffer, &payload)
+        .unwrap();
+
+    buffer
+}
+
+fn indicator(c: &mut Criterion) {
+    let mut group = c.benchmark_group("indicator");
+    for size in PAYLOAD_SIZES.iter() {
+        let frame = tcp_ack_frame(*size);
+        group.throughput(Throughput::Bytes(frame.len() as u64));
+        group.bench_with_input(BenchmarkId::new("parse", size), &frame, |b, frame| {
+            b.iter(|| Indicator::from(frame).unwrap())
+        });
+
+        let indicator = tcp_ack();
+        let payload = vec![0u8; *size];
+        let mut buffer = vec![0u8; indicator.len() + size];
+        group.bench_with_input(
+            BenchmarkId::new("serialize", size),
+            &payload,
+            |b, payload| {
+                b.iter(|| {
+                    indicator
+                        .serialize_with_payload(&mut buffer, payload)
+                        .unwrap()
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+fn checksum(c: &mut Criterion) {
+    let mut group = c.benchmark_group("checksum");
+    for size in PAYLOAD_SIZES.iter() {
+        let frame = tcp_ack_frame(*size);
+        let ethernet = EthernetPacket::new(&frame).unwrap();
+        let ipv4 = Ipv4Packet::new(ethernet.payload()).unwrap();
+        let tcp = TcpPacket::new(ipv4.payload()).unwrap();
+        let (src, dst) = (ipv4.get_source(), ipv4.get_destination());
+
+        group.throughput(Throughput::Bytes(ipv4.packet().len() as u64));
+        group.bench_function(BenchmarkId::new("ipv4", size), |b| {
+            b.iter(|| ipv4::checksum(&ipv4))
+        });
+        group.bench_function(BenchmarkId::new("tcp", size), |b| {
+            b.iter(|| tcp::ipv4_checksum(&tcp, &src, &dst))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, indicator, checksum);
+criterion_main!(benches);
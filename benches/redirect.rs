@@ -0,0 +1,151 @@
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use ipnetwork::Ipv4Network;
+use pcap2socks::socks::ForwardStream;
+use pcap2socks::{Config, Forwarder, Redirector, TcpTxState};
+use pnet::datalink::{DataLinkReceiver, DataLinkSender, MacAddr, NetworkInterface};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::{Arc, Mutex};
+use tokio::runtime::Runtime;
+
+/// Represents the count of frames redirected in each iteration.
+const FRAMES: usize = 4096;
+/// Represents the size of the payload forwarded in each iteration.
+const FORWARD_SIZE: usize = 64 * 1024;
+
+/// Represents a sender which drops all the frames.
+struct NullSender;
+
+impl DataLinkSender for NullSender {
+    fn build_and_send(
+        &mut self,
+        num_packets: usize,
+        packet_size: usize,
+        func: &mut dyn FnMut(&mut [u8]),
+    ) -> Option<io::Result<()>> {
+        let mut buffer = vec![0u8; packet_size];
+        for _ in 0..num_packets {
+            func(&mut buffer);
+        }
+
+        Some(Ok(()))
+    }
+
+    fn send_to(
+        &mut self,
+        _packet: &[u8],
+        _dst: Option<NetworkInterface>,
+    ) -> Option<io::Result<()>> {
+        Some(Ok(()))
+    }
+}
+
+/// Represents a receiver which replays the frames once, and then reports an error to stop the
+/// redirector.
+struct ReplayReceiver {
+    frames: Vec<Vec<u8>>,
+    index: usize,
+}
+
+impl DataLinkReceiver for ReplayReceiver {
+    fn next(&mut self) -> io::Result<&[u8]> {
+        if self.index >= self.frames.len() {
+            self.index = 0;
+            return Err(io::Error::new(io::ErrorKind::Other, "end of frames"));
+        }
+        self.index += 1;
+
+        Ok(&self.frames[self.index - 1])
+    }
+}
+
+/// Returns an Ethernet frame of a TCP ACK from 10.6.0.2 to 1.1.1.1:80, each in a different flow.
+fn tcp_ack(i: usize) -> Vec<u8> {
+    let port = (1024 + i % 60000) as u16;
+
+    let mut frame = vec![
+        0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xaa, 0xbb, 0x08,
+        0x00, // Ethernet
+        0x45, 0x00, 0x00, 0x28, 0x00, 0x00, 0x40, 0x00, 0x40, 0x06, 0x00, 0x00, 10, 6, 0, 2, 1, 1,
+        1, 1, // IPv4
+    ];
+    frame.extend_from_slice(&port.to_be_bytes());
+    frame.extend_from_slice(&[
+        0x00, 0x50, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x50, 0x10, 0xff, 0xff, 0x00,
+        0x00, 0x00, 0x00, // TCP
+    ]);
+
+    frame
+}
+
+fn redirect(c: &mut Criterion) {
+    let mut rt = Runtime::new().unwrap();
+
+    let forwarder = Forwarder::new(
+        Box::new(NullSender),
+        1500,
+        MacAddr(0x00, 0x11, 0x22, 0x33, 0x44, 0x55),
+        Ipv4Addr::new(10, 6, 255, 254),
+    );
+    let mut redirector = Redirector::new(
+        Arc::new(Mutex::new(forwarder)),
+        Ipv4Network::new(Ipv4Addr::new(10, 6, 0, 0), 16).unwrap(),
+        Ipv4Addr::new(10, 6, 255, 254),
+        None,
+        SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1080),
+        false,
+        false,
+        None,
+        Config::new(),
+    );
+    let mut rx: Box<dyn DataLinkReceiver> = Box::new(ReplayReceiver {
+        frames: (0..FRAMES).map(tcp_ack).collect(),
+        index: 0,
+    });
+
+    let mut group = c.benchmark_group("redirect");
+    group.throughput(Throughput::Elements(FRAMES as u64));
+    group.bench_function("tcp_ack_without_stream", |b| {
+        b.iter(|| {
+            let _ = rt.block_on(redirector.open(&mut rx));
+        })
+    });
+    group.finish();
+}
+
+fn forward(c: &mut Criterion) {
+    let src = SocketAddrV4::new(Ipv4Addr::new(10, 6, 0, 2), 1024);
+    let dst = SocketAddrV4::new(Ipv4Addr::new(1, 1, 1, 1), 80);
+
+    let mut forwarder = Forwarder::new(
+        Box::new(NullSender),
+        1500,
+        MacAddr(0x00, 0x11, 0x22, 0x33, 0x44, 0x55),
+        Ipv4Addr::new(10, 6, 255, 254),
+    );
+    forwarder.set_src_hardware_addr(*src.ip(), MacAddr(0x66, 0x77, 0x88, 0x99, 0xaa, 0xbb));
+    forwarder.set_state(
+        dst,
+        src,
+        TcpTxState::new(src, dst, 0, 1, u16::MAX, Some(8), true, Some(8)),
+    );
+    let payload = vec![0u8; FORWARD_SIZE];
+
+    let mut group = c.benchmark_group("forward");
+    group.throughput(Throughput::Bytes(FORWARD_SIZE as u64));
+    group.bench_function("segment_64k", |b| {
+        b.iter(|| {
+            forwarder.forward(dst, src, &payload).unwrap();
+
+            // Acknowledge all the segments
+            let state = forwarder.get_state(dst, src).unwrap();
+            let sequence = state.sequence();
+            state.acknowledge(sequence);
+            state.update_send_window(sequence, (u16::MAX as usize) << 8);
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, redirect, forward);
+criterion_main!(benches);
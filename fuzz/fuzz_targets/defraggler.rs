@@ -0,0 +1,33 @@
+//! Fuzzes reassembling arbitrary sequences of IPv4 fragments. The input is split into frames by
+//! a length byte before each frame, and the frames are added to the same `Defraggler`.
+
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use pcap2socks::packet::{Defraggler, Indicator};
+
+fuzz_target!(|data: &[u8]| {
+    let mut defraggler = Defraggler::new();
+
+    let mut data = data;
+    while let Some((&len, rest)) = data.split_first() {
+        let len = (len as usize).min(rest.len());
+        let (frame, rest) = rest.split_at(len);
+        data = rest;
+
+        let indicator = match Indicator::from(frame) {
+            Ok(indicator) => indicator,
+            Err(_) => continue,
+        };
+        match indicator.ipv4() {
+            Some(ipv4) if ipv4.is_fragment() => {}
+            _ => continue,
+        }
+
+        let frame_without_padding = &frame[..indicator.content_len()];
+        if let Some(frag) = defraggler.add(&indicator, frame_without_padding) {
+            if let Ok((transport, payload)) = frag.concatenate() {
+                let _ = (transport, payload.len());
+            }
+        }
+    }
+});
@@ -0,0 +1,20 @@
+//! Fuzzes parsing arbitrary frames, and everything done with the indicator of a frame before it
+//! is redirected.
+
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use pcap2socks::packet::Indicator;
+
+fuzz_target!(|frame: &[u8]| {
+    if let Ok(indicator) = Indicator::from(frame) {
+        let _ = indicator.brief();
+        let _ = format!("{}", indicator);
+        let _ = indicator.len();
+        let content_len = indicator.content_len();
+        assert!(content_len <= frame.len());
+
+        // Serialize the frame back
+        let mut buffer = vec![0u8; indicator.len()];
+        let _ = indicator.serialize(&mut buffer);
+    }
+});
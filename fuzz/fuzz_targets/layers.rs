@@ -0,0 +1,83 @@
+//! Fuzzes each layer parser. The first byte selects the layer, and the rest is placed where the
+//! layer is expected, behind valid headers of the lower layers if there are any.
+
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use pcap2socks::packet::discovery::Discovery;
+use pcap2socks::packet::layer::arp::Arp;
+use pcap2socks::packet::layer::ethernet::Ethernet;
+use pcap2socks::packet::layer::icmpv4::Icmpv4;
+use pcap2socks::packet::layer::pppoe::Pppoe;
+use pcap2socks::packet::Indicator;
+use pnet::packet::arp::ArpPacket;
+use pnet::packet::ethernet::EthernetPacket;
+use pnet::packet::icmp::IcmpPacket;
+
+/// Returns an Ethernet frame of the ethertype with the payload.
+fn ethernet(ethertype: u16, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0x02, 0, 0, 0, 0, 0x01, 0x02, 0, 0, 0, 0, 0x02];
+    frame.extend_from_slice(&ethertype.to_be_bytes());
+    frame.extend_from_slice(payload);
+
+    frame
+}
+
+/// Returns an Ethernet frame of an IPv4 packet of the protocol with the payload.
+fn ipv4(protocol: u8, payload: &[u8]) -> Vec<u8> {
+    let total_length = (20 + payload.len()).min(u16::MAX as usize) as u16;
+    let mut packet = vec![0x45, 0x00];
+    packet.extend_from_slice(&total_length.to_be_bytes());
+    packet.extend_from_slice(&[0x00, 0x01, 0x00, 0x00, 0x40, protocol, 0x00, 0x00]);
+    packet.extend_from_slice(&[10, 6, 0, 1, 1, 1, 1, 1]);
+    packet.extend_from_slice(payload);
+
+    ethernet(0x0800, &packet)
+}
+
+fuzz_target!(|data: &[u8]| {
+    let (selector, payload) = match data.split_first() {
+        Some((selector, payload)) => (*selector, payload),
+        None => return,
+    };
+
+    match selector % 8 {
+        0 => {
+            if let Some(packet) = EthernetPacket::new(payload) {
+                if let Some(ethernet) = Ethernet::parse(&packet) {
+                    let _ = format!("{}", ethernet);
+                }
+            }
+        }
+        1 => {
+            if let Some(packet) = ArpPacket::new(payload) {
+                let _ = format!("{}", Arp::parse(&packet));
+            }
+        }
+        2 => {
+            if let Some(pppoe) = Pppoe::parse(payload) {
+                let _ = (pppoe.payload_length(), pppoe.is_ipv4());
+            }
+        }
+        3 => {
+            if let Some(packet) = IcmpPacket::new(payload) {
+                let _ = format!("{}", Icmpv4::parse(&packet));
+            }
+        }
+        4 => {
+            if payload.len() >= 2 {
+                let ethertype = u16::from_be_bytes([payload[0], payload[1]]);
+                let _ = Discovery::parse(ethertype, &payload[2..]);
+            }
+        }
+        // IPv4, and TCP and UDP behind a valid IPv4 header
+        5 => {
+            let _ = Indicator::from(&ethernet(0x0800, payload));
+        }
+        6 => {
+            let _ = Indicator::from(&ipv4(6, payload));
+        }
+        _ => {
+            let _ = Indicator::from(&ipv4(17, payload));
+        }
+    }
+});
@@ -0,0 +1,37 @@
+//! Fuzzes parsing TCP options. The input is placed in the options of a TCP header behind valid
+//! Ethernet and IPv4 headers, and the data offset covers all of it.
+
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use pcap2socks::packet::Indicator;
+
+fuzz_target!(|options: &[u8]| {
+    let options = &options[..options.len().min(40)];
+    let data_offset = (20 + options.len() + 3) / 4;
+    let tcp_len = data_offset * 4;
+    let total_length = (20 + tcp_len) as u16;
+
+    let mut frame = vec![0x02, 0, 0, 0, 0, 0x01, 0x02, 0, 0, 0, 0, 0x02, 0x08, 0x00];
+    // IPv4
+    frame.extend_from_slice(&[0x45, 0x00]);
+    frame.extend_from_slice(&total_length.to_be_bytes());
+    frame.extend_from_slice(&[0x00, 0x01, 0x00, 0x00, 0x40, 0x06, 0x00, 0x00]);
+    frame.extend_from_slice(&[10, 6, 0, 1, 1, 1, 1, 1]);
+    // TCP
+    frame.extend_from_slice(&[0xc3, 0x50, 0x00, 0x50, 0, 0, 0x03, 0xe8, 0, 0, 0, 0]);
+    frame.extend_from_slice(&[(data_offset as u8) << 4, 0x12, 0xff, 0xff, 0, 0, 0, 0]);
+    frame.extend_from_slice(options);
+    frame.resize(14 + 20 + tcp_len, 0);
+
+    if let Ok(indicator) = Indicator::from(&frame) {
+        if let Some(tcp) = indicator.tcp() {
+            let _ = (tcp.mss(), tcp.wscale(), tcp.is_sack_perm());
+            let _ = (tcp.sack(), tcp.ts(), tcp.ts_ecr());
+            let _ = format!("{}", tcp);
+        }
+
+        // Serialize the options back
+        let mut buffer = vec![0u8; indicator.len()];
+        let _ = indicator.serialize(&mut buffer);
+    }
+});
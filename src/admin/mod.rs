@@ -0,0 +1,219 @@
+//! Support for a local admin channel over a Unix domain socket or a Windows named pipe.
+//!
+//! The admin channel speaks a line protocol. Each line is a command, and each response is
+//! terminated by an empty line. Supported commands are:
+//!
+//! - `status`: Returns the count of TCP connections and the statistics.
+//! - `connections`: Returns a line for each TCP connection.
+//! - `policy`: Returns a line for the hits of each rule of the port profiles.
+//! - `window <WINDOW> <WSCALE>`: Sets the unscaled receive window and the max window scale of
+//!   new TCP connections.
+//! - `shutdown`: Stops redirecting.
+
+use log::{info, trace, warn};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use tokio::runtime::Handle;
+
+use crate::control::Controller;
+
+/// Serves the admin channel on the path in background threads. The path is of a Unix domain
+/// socket in Unix, or of a named pipe like `\\.\pipe\pcap2socks` in Windows. `is_stopped` is set
+/// once a `shutdown` command is received. This function must be called in a Tokio runtime.
+pub fn serve<P: AsRef<Path>>(
+    path: P,
+    controller: Controller,
+    is_stopped: Arc<AtomicBool>,
+) -> io::Result<()> {
+    let admin = Admin {
+        controller,
+        is_stopped,
+        handle: Handle::current(),
+    };
+
+    listen(path.as_ref(), admin)
+}
+
+/// Represents the state shared by the connections of the admin channel.
+#[derive(Clone)]
+struct Admin {
+    controller: Controller,
+    is_stopped: Arc<AtomicBool>,
+    handle: Handle,
+}
+
+impl Admin {
+    fn handle<S: Read + Write>(&self, stream: S) -> io::Result<()> {
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if reader.read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+            let command = line.trim();
+            if command.is_empty() {
+                continue;
+            }
+            trace!("admin command {}", command);
+
+            let response = match self.execute(command) {
+                Ok(response) => response,
+                Err(e) => format!("error: {}\n", e),
+            };
+            let stream = reader.get_mut();
+            stream.write_all(response.as_bytes())?;
+            stream.write_all(b"\n")?;
+            stream.flush()?;
+        }
+    }
+
+    fn execute(&self, command: &str) -> io::Result<String> {
+        match command {
+            "status" => {
+                let connections = self.handle.block_on(self.controller.connections())?;
+
+                Ok(format!(
+                    "TCP: {} connections\n{}\n",
+                    connections.len(),
+                    self.controller.stats()
+                ))
+            }
+            "connections" => {
+                let connections = self.handle.block_on(self.controller.connections())?;
+
+                Ok(connections
+                    .iter()
+                    .map(|connection| format!("{}\n", connection))
+                    .collect())
+            }
+            "policy" => {
+                let hits = self.handle.block_on(self.controller.policy_hits())?;
+
+                Ok(hits.iter().map(|hit| format!("{}\n", hit)).collect())
+            }
+            "shutdown" => {
+                info!("Shut down by the admin channel");
+                self.is_stopped.store(true, Ordering::Relaxed);
+
+                Ok(String::from("ok\n"))
+            }
+            _ if command.starts_with("window ") => {
+                let invalid = || io::Error::new(io::ErrorKind::InvalidInput, "invalid window");
+                let mut args = command["window ".len()..].split_whitespace();
+                let window = args
+                    .next()
+                    .and_then(|s| s.parse().ok())
+                    .ok_or_else(invalid)?;
+                let wscale = args
+                    .next()
+                    .and_then(|s| s.parse().ok())
+                    .ok_or_else(invalid)?;
+                if args.next().is_some() {
+                    return Err(invalid());
+                }
+                self.controller.set_recv_window(window, wscale)?;
+
+                Ok(String::from("ok\n"))
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unknown command {}", command),
+            )),
+        }
+    }
+
+    fn spawn<S: Read + Write + Send + 'static>(&self, stream: S) {
+        let admin = self.clone();
+        thread::spawn(move || {
+            if let Err(ref e) = admin.handle(stream) {
+                warn!("handle {}: {}", "admin", e);
+            }
+        });
+    }
+}
+
+#[cfg(unix)]
+fn listen(path: &Path, admin: Admin) -> io::Result<()> {
+    use std::fs;
+    use std::os::unix::fs::FileTypeExt;
+    use std::os::unix::net::UnixListener;
+
+    // Remove the stale socket
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            fs::remove_file(path)?;
+        }
+    }
+
+    let listener = UnixListener::bind(path)?;
+    info!("Admin on {}", path.display());
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => admin.spawn(stream),
+                Err(ref e) => warn!("handle {}: {}", "admin", e),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(windows)]
+fn listen(path: &Path, admin: Admin) -> io::Result<()> {
+    use named_pipe::PipeOptions;
+
+    // Create the first instance to report errors like an occupied name
+    let mut options = PipeOptions::new(path);
+    let mut server = options.first(true).single()?;
+    info!("Admin on {}", path.display());
+
+    thread::spawn(move || loop {
+        match server.wait() {
+            Ok(stream) => admin.spawn(stream),
+            Err(ref e) => warn!("handle {}: {}", "admin", e),
+        }
+        server = match options.first(false).single() {
+            Ok(server) => server,
+            Err(ref e) => {
+                warn!("handle {}: {}", "admin", e);
+                return;
+            }
+        };
+    });
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn admin_handle() {
+    use std::net::Shutdown;
+    use std::os::unix::net::UnixStream;
+
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let is_stopped = Arc::new(AtomicBool::new(false));
+    let admin = Admin {
+        controller: Controller::new(Vec::new(), Vec::new()),
+        is_stopped: Arc::clone(&is_stopped),
+        handle: rt.handle().clone(),
+    };
+
+    let (mut client, server) = UnixStream::pair().unwrap();
+    admin.spawn(server);
+    client.write_all(b"status\nreload\nshutdown\n").unwrap();
+    client.shutdown(Shutdown::Write).unwrap();
+    let mut response = String::new();
+    client.read_to_string(&mut response).unwrap();
+
+    let responses: Vec<_> = response.split("\n\n").collect();
+    assert!(responses[0].starts_with("TCP: 0 connections\n"));
+    assert_eq!(responses[1], "error: unknown command reload");
+    assert_eq!(responses[2], "ok");
+    assert!(is_stopped.load(Ordering::Relaxed));
+}
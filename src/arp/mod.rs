@@ -0,0 +1,158 @@
+//! Support for detecting conflicts of the gateway IP address with ARP probes.
+
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+
+use crate::pcap::HardwareAddr;
+
+/// Represents the count of ARP probes sent before claiming the address.
+const PROBE_NUM: usize = 3;
+/// Represents the interval in milliseconds between ARP probes.
+const PROBE_INTERVAL: u64 = 1000;
+/// Represents the time in milliseconds waiting for replies after the last ARP probe.
+const PROBE_WAIT: u64 = 2000;
+
+/// Represents an action the `ArpGuard` asks for.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum GuardAction {
+    /// Represents an ARP probe for the address should be sent.
+    Probe,
+    /// Represents the address is claimed, and ARP requests for it can be replied.
+    Claim,
+    /// Represents the address is owned by another device, and ARP requests for it should not be
+    /// replied.
+    Conflict(HardwareAddr),
+}
+
+#[derive(Clone, Copy, Debug)]
+enum State {
+    /// Represents the count of ARP probes sent and the instant the last one is sent.
+    Probing(usize, Instant),
+    /// Represents the instant the address is claimed or last rechecked.
+    Claimed(Instant),
+    /// Represents the owner, the instant the owner is last heard, and the instant an ARP probe
+    /// is sent to recheck.
+    Conflicted(HardwareAddr, Instant, Option<Instant>),
+}
+
+/// Represents a guard of the gateway IP address, which probes the address before claiming it,
+/// rechecks it periodically, and backs off once another device owns it.
+#[derive(Debug)]
+pub(crate) struct ArpGuard {
+    ip_addr: Ipv4Addr,
+    recheck: Option<Duration>,
+    state: State,
+}
+
+impl ArpGuard {
+    /// Creates a new `ArpGuard` of the address, which rechecks the address in the given interval
+    /// in milliseconds. An interval of 0 disables rechecks.
+    pub(crate) fn new(ip_addr: Ipv4Addr, recheck: u64) -> ArpGuard {
+        ArpGuard {
+            ip_addr,
+            recheck: match recheck {
+                0 => None,
+                _ => Some(Duration::from_millis(recheck)),
+            },
+            state: State::Probing(0, Instant::now()),
+        }
+    }
+
+    /// Returns the guarded address.
+    pub(crate) fn ip_addr(&self) -> Ipv4Addr {
+        self.ip_addr
+    }
+
+    /// Returns if the address is claimed.
+    pub(crate) fn is_claimed(&self) -> bool {
+        matches!(self.state, State::Claimed(_))
+    }
+
+    /// Updates the guard over time, and returns the action to take.
+    pub(crate) fn update(&mut self) -> Option<GuardAction> {
+        match self.state {
+            State::Probing(sent, instant) => {
+                if sent == 0 || (sent < PROBE_NUM && elapsed(instant, PROBE_INTERVAL)) {
+                    self.state = State::Probing(sent + 1, Instant::now());
+                    Some(GuardAction::Probe)
+                } else if sent >= PROBE_NUM && elapsed(instant, PROBE_WAIT) {
+                    self.state = State::Claimed(Instant::now());
+                    Some(GuardAction::Claim)
+                } else {
+                    None
+                }
+            }
+            State::Claimed(instant) => match self.recheck {
+                Some(recheck) if instant.elapsed() >= recheck => {
+                    self.state = State::Claimed(Instant::now());
+                    Some(GuardAction::Probe)
+                }
+                _ => None,
+            },
+            State::Conflicted(owner, heard, probe) => match (self.recheck, probe) {
+                (Some(recheck), None) if heard.elapsed() >= recheck => {
+                    self.state = State::Conflicted(owner, heard, Some(Instant::now()));
+                    Some(GuardAction::Probe)
+                }
+                // The owner has left, probe again before claiming
+                (Some(_), Some(probe)) if elapsed(probe, PROBE_WAIT) => {
+                    self.state = State::Probing(0, Instant::now());
+                    self.update()
+                }
+                _ => None,
+            },
+        }
+    }
+
+    /// Receives an ARP packet sent from the address by another device, and returns the action to
+    /// take.
+    pub(crate) fn receive(&mut self, hardware_addr: HardwareAddr) -> Option<GuardAction> {
+        let action = match self.state {
+            State::Conflicted(owner, _, _) if owner == hardware_addr => None,
+            _ => Some(GuardAction::Conflict(hardware_addr)),
+        };
+        self.state = State::Conflicted(hardware_addr, Instant::now(), None);
+
+        action
+    }
+}
+
+fn elapsed(instant: Instant, millis: u64) -> bool {
+    instant.elapsed() >= Duration::from_millis(millis)
+}
+
+#[test]
+fn arp_guard_conflict() {
+    use pnet::datalink::MacAddr;
+
+    let owner = MacAddr(0x00, 0x11, 0x22, 0x33, 0x44, 0x55);
+    let mut guard = ArpGuard::new(Ipv4Addr::new(10, 6, 0, 1), 0);
+    assert_eq!(guard.update(), Some(GuardAction::Probe));
+    assert_eq!(guard.update(), None);
+    assert!(!guard.is_claimed());
+
+    // A reply from the owner stops probing, and it is reported once
+    assert_eq!(guard.receive(owner), Some(GuardAction::Conflict(owner)));
+    assert_eq!(guard.receive(owner), None);
+    assert_eq!(guard.update(), None);
+    assert!(!guard.is_claimed());
+
+    // Claim the address without conflicts
+    let mut guard = ArpGuard::new(Ipv4Addr::new(10, 6, 0, 1), 0);
+    guard.state = State::Probing(
+        PROBE_NUM,
+        Instant::now() - Duration::from_millis(PROBE_WAIT),
+    );
+    assert_eq!(guard.update(), Some(GuardAction::Claim));
+    assert!(guard.is_claimed());
+
+    // Recheck after the owner has left
+    let mut guard = ArpGuard::new(Ipv4Addr::new(10, 6, 0, 1), 1);
+    let past = Instant::now() - Duration::from_millis(PROBE_WAIT);
+    guard.state = State::Conflicted(owner, past, None);
+    assert_eq!(guard.update(), Some(GuardAction::Probe));
+    guard.state = State::Conflicted(owner, past, Some(past));
+    assert_eq!(guard.update(), Some(GuardAction::Probe));
+    assert!(!guard.is_claimed());
+    assert_eq!(guard.receive(owner), Some(GuardAction::Conflict(owner)));
+}
@@ -0,0 +1,245 @@
+//! Support for an append-only audit log recording every connection accepted or refused, like for
+//! compliance on shared networks.
+
+use log::warn;
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::net::SocketAddrV4;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::clock;
+use crate::pcap::HardwareAddr;
+use crate::policy::PortProtocol;
+
+/// Represents if a connection is accepted or refused.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum AuditAction {
+    /// Represents the connection is accepted and relayed.
+    Accept,
+    /// Represents the connection is refused.
+    Refuse,
+}
+
+impl Display for AuditAction {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            AuditAction::Accept => write!(f, "accept"),
+            AuditAction::Refuse => write!(f, "refuse"),
+        }
+    }
+}
+
+/// Represents a record of a connection in the audit log. A TCP connection accepted is recorded
+/// when it is closed, and a UDP port of a source is recorded when it is accepted without bytes
+/// and the duration.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AuditRecord {
+    /// Represents the time of the record.
+    pub time: SystemTime,
+    /// Represents if the connection is accepted or refused.
+    pub action: AuditAction,
+    /// Represents the transport protocol of the connection.
+    pub protocol: PortProtocol,
+    /// Represents the source of the connection.
+    pub src: SocketAddrV4,
+    /// Represents the hardware address of the source if it is known.
+    pub hardware_addr: Option<HardwareAddr>,
+    /// Represents the destination of the connection.
+    pub dst: SocketAddrV4,
+    /// Represents the rule deciding the action, like `port-policy consoles deny:tcp:25`.
+    pub rule: String,
+    /// Represents the bytes from the source.
+    pub rx_bytes: u64,
+    /// Represents the bytes to the source.
+    pub tx_bytes: u64,
+    /// Represents the duration of the connection.
+    pub duration: Duration,
+}
+
+impl Display for AuditRecord {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let time = self
+            .time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or(Duration::from_millis(0));
+        write!(
+            f,
+            "time={}.{:03} action={} protocol={} src={}",
+            time.as_secs(),
+            time.subsec_millis(),
+            self.action,
+            self.protocol,
+            self.src
+        )?;
+        if let Some(hardware_addr) = self.hardware_addr {
+            write!(f, " mac={}", hardware_addr)?;
+        }
+        write!(
+            f,
+            " dst={} rule=\"{}\" rx={} tx={} duration={}.{:03}",
+            self.dst,
+            self.rule,
+            self.rx_bytes,
+            self.tx_bytes,
+            self.duration.as_secs(),
+            self.duration.subsec_millis()
+        )
+    }
+}
+
+/// Represents a sink of records of the audit log.
+pub trait AuditSink: Send {
+    /// Records the connection.
+    fn record(&mut self, record: &AuditRecord);
+}
+
+impl<F> AuditSink for F
+where
+    F: FnMut(&AuditRecord) + Send,
+{
+    fn record(&mut self, record: &AuditRecord) {
+        self(record)
+    }
+}
+
+/// Represents an audit log appending a line for each record to a file.
+#[derive(Debug)]
+pub struct AuditLog {
+    file: File,
+}
+
+impl AuditLog {
+    /// Opens the audit log of the path, which is created if it does not exist. Records are
+    /// appended to the end of the file.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<AuditLog> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+        Ok(AuditLog { file })
+    }
+}
+
+impl AuditSink for AuditLog {
+    fn record(&mut self, record: &AuditRecord) {
+        // A record is written in a single write, so records of workers are not interleaved
+        let line = format!("{}\n", record);
+        if let Err(ref e) = self.file.write_all(line.as_bytes()) {
+            warn!("write audit log: {}", e);
+        }
+    }
+}
+
+/// Represents an auditor of a `Redirector`, which tracks accepted TCP connections until they are
+/// closed.
+pub(crate) struct Auditor {
+    sink: Arc<Mutex<dyn AuditSink>>,
+    connections: HashMap<(SocketAddrV4, SocketAddrV4), (Instant, String)>,
+}
+
+impl Auditor {
+    /// Creates a new `Auditor` recording to the sink, which may be shared by workers.
+    pub(crate) fn new(sink: Arc<Mutex<dyn AuditSink>>) -> Auditor {
+        Auditor {
+            sink,
+            connections: HashMap::new(),
+        }
+    }
+
+    /// Records a refused connection. A TCP connection refused after it is accepted, like one
+    /// blocked by its SNI, is no longer tracked.
+    pub(crate) fn refuse(
+        &mut self,
+        protocol: PortProtocol,
+        src: SocketAddrV4,
+        hardware_addr: Option<HardwareAddr>,
+        dst: SocketAddrV4,
+        rule: &str,
+    ) {
+        if protocol == PortProtocol::Tcp {
+            self.connections.remove(&(src, dst));
+        }
+
+        self.record(AuditRecord {
+            time: SystemTime::now(),
+            action: AuditAction::Refuse,
+            protocol,
+            src,
+            hardware_addr,
+            dst,
+            rule: rule.to_string(),
+            rx_bytes: 0,
+            tx_bytes: 0,
+            duration: Duration::from_millis(0),
+        });
+    }
+
+    /// Records an accepted UDP port of a source.
+    pub(crate) fn accept_udp(
+        &self,
+        src: SocketAddrV4,
+        hardware_addr: Option<HardwareAddr>,
+        dst: SocketAddrV4,
+        rule: &str,
+    ) {
+        self.record(AuditRecord {
+            time: SystemTime::now(),
+            action: AuditAction::Accept,
+            protocol: PortProtocol::Udp,
+            src,
+            hardware_addr,
+            dst,
+            rule: rule.to_string(),
+            rx_bytes: 0,
+            tx_bytes: 0,
+            duration: Duration::from_millis(0),
+        });
+    }
+
+    /// Tracks an accepted TCP connection, which is recorded when it is closed.
+    pub(crate) fn accept_tcp(&mut self, src: SocketAddrV4, dst: SocketAddrV4, rule: &str) {
+        self.connections
+            .insert((src, dst), (clock::now(), rule.to_string()));
+    }
+
+    /// Sets the rule of an accepted TCP connection.
+    pub(crate) fn set_rule(&mut self, src: SocketAddrV4, dst: SocketAddrV4, rule: &str) {
+        if let Some((_, ref mut r)) = self.connections.get_mut(&(src, dst)) {
+            *r = rule.to_string();
+        }
+    }
+
+    /// Records a closed TCP connection if it is tracked.
+    pub(crate) fn close_tcp(
+        &mut self,
+        src: SocketAddrV4,
+        hardware_addr: Option<HardwareAddr>,
+        dst: SocketAddrV4,
+        rx_bytes: u64,
+        tx_bytes: u64,
+    ) {
+        let (instant, rule) = match self.connections.remove(&(src, dst)) {
+            Some(connection) => connection,
+            None => return,
+        };
+
+        self.record(AuditRecord {
+            time: SystemTime::now(),
+            action: AuditAction::Accept,
+            protocol: PortProtocol::Tcp,
+            src,
+            hardware_addr,
+            dst,
+            rule,
+            rx_bytes,
+            tx_bytes,
+            duration: clock::now().saturating_duration_since(instant),
+        });
+    }
+
+    fn record(&self, record: AuditRecord) {
+        self.sink.lock().unwrap().record(&record);
+    }
+}
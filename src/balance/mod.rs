@@ -0,0 +1,283 @@
+//! Support for balancing new connections among proxies.
+
+use log::{debug, trace};
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio::time;
+
+/// Represents the timeout of a probe.
+const PROBE_TIMEOUT: u64 = 5000;
+
+/// Represents a balancer which selects proxies for new connections in round robin. Optionally, a
+/// destination, or the subnet of a destination, is pinned to the proxy selected for it until it
+/// has not been selected for a while, so the destination always sees the same exit. Proxies may
+/// be probed continuously, and new connections to latency-sensitive ports are sent through the
+/// fastest one.
+#[derive(Debug)]
+pub struct Balancer {
+    proxies: Vec<SocketAddrV4>,
+    next: usize,
+    sticky_ttl: u64,
+    sticky_prefix: u8,
+    /// Represents the map mapping a destination subnet to its proxy and the last time it is
+    /// selected.
+    sticky: HashMap<Ipv4Addr, (SocketAddrV4, Instant)>,
+    probe_interval: u64,
+    fastest_ports: Vec<u16>,
+    is_probing: bool,
+    /// Represents the map mapping a proxy to its RTT in the last probe, or `None` if it is
+    /// unreachable.
+    rtts: HashMap<SocketAddrV4, Option<Duration>>,
+}
+
+impl Balancer {
+    /// Creates a new `Balancer` with the primary proxy and other proxies.
+    pub fn new(primary: SocketAddrV4, proxies: &[SocketAddrV4]) -> Balancer {
+        Balancer {
+            proxies: dedup(primary, proxies),
+            next: 0,
+            sticky_ttl: 0,
+            sticky_prefix: 32,
+            sticky: HashMap::new(),
+            probe_interval: 0,
+            fastest_ports: Vec::new(),
+            is_probing: false,
+            rtts: HashMap::new(),
+        }
+    }
+
+    /// Sets the time in milliseconds a destination subnet is pinned to its proxy since it is
+    /// selected last time, and the prefix length of destination subnets. A TTL of 0 disables
+    /// pinning.
+    pub fn set_sticky(&mut self, ttl: u64, prefix: u8) {
+        self.sticky_ttl = ttl;
+        self.sticky_prefix = prefix.min(32);
+        self.sticky.clear();
+    }
+
+    /// Sets the interval in milliseconds between probes, and the destination ports of which new
+    /// connections are sent through the fastest proxy. An interval of 0 disables probing.
+    pub fn set_probe(&mut self, interval: u64, fastest_ports: Vec<u16>) {
+        self.probe_interval = interval;
+        self.fastest_ports = fastest_ports;
+    }
+
+    /// Replaces the primary proxy.
+    pub fn set_primary(&mut self, primary: SocketAddrV4) {
+        self.proxies = dedup(primary, &self.proxies[1..]);
+        let proxies = &self.proxies;
+        self.rtts.retain(|proxy, _| proxies.contains(proxy));
+    }
+
+    /// Returns the proxies, where the first is the primary.
+    pub fn proxies(&self) -> &[SocketAddrV4] {
+        &self.proxies
+    }
+
+    /// Returns the RTTs of the proxies in the last probe, where `None` represents a proxy is
+    /// unreachable. Proxies not probed yet are omitted.
+    pub fn rtts(&self) -> Vec<(SocketAddrV4, Option<Duration>)> {
+        self.proxies
+            .iter()
+            .filter_map(|proxy| self.rtts.get(proxy).map(|rtt| (*proxy, *rtt)))
+            .collect()
+    }
+
+    /// Returns the reachable proxy with the lowest RTT in the last probe.
+    pub fn fastest(&self) -> Option<SocketAddrV4> {
+        self.proxies
+            .iter()
+            .filter_map(|proxy| {
+                self.rtts
+                    .get(proxy)
+                    .and_then(|rtt| rtt.as_ref())
+                    .map(|rtt| (*rtt, *proxy))
+            })
+            .min()
+            .map(|(_, proxy)| proxy)
+    }
+
+    fn set_rtt(&mut self, proxy: SocketAddrV4, rtt: Option<Duration>) {
+        if self.proxies.contains(&proxy) {
+            self.rtts.insert(proxy, rtt);
+        }
+    }
+
+    /// Selects a proxy for a new connection to the destination.
+    pub fn select(&mut self, dst: SocketAddrV4) -> SocketAddrV4 {
+        if self.proxies.len() == 1 {
+            return self.proxies[0];
+        }
+
+        let subnet = self.subnet(*dst.ip());
+        if self.sticky_ttl > 0 {
+            if let Some((proxy, instant)) = self.sticky.get_mut(&subnet) {
+                if instant.elapsed() < Duration::from_millis(self.sticky_ttl)
+                    && self.proxies.contains(proxy)
+                {
+                    *instant = Instant::now();
+                    return *proxy;
+                }
+            }
+        }
+
+        let fastest = match self.fastest_ports.contains(&dst.port()) {
+            true => self.fastest(),
+            false => None,
+        };
+        let proxy = match fastest {
+            Some(proxy) => proxy,
+            None => {
+                let proxy = self.proxies[self.next % self.proxies.len()];
+                self.next = self.next.wrapping_add(1);
+
+                proxy
+            }
+        };
+        if self.sticky_ttl > 0 {
+            self.sticky.insert(subnet, (proxy, Instant::now()));
+            trace!("pin {}/{} to {}", subnet, self.sticky_prefix, proxy);
+        }
+
+        proxy
+    }
+
+    /// Expires destination subnets which have not been selected in the TTL.
+    pub fn expire(&mut self) {
+        let ttl = Duration::from_millis(self.sticky_ttl);
+        self.sticky
+            .retain(|_, (_, instant)| instant.elapsed() < ttl);
+    }
+
+    fn subnet(&self, dst: Ipv4Addr) -> Ipv4Addr {
+        let mask = match self.sticky_prefix {
+            0 => 0,
+            prefix => u32::MAX << (32 - prefix),
+        };
+
+        Ipv4Addr::from(u32::from(dst) & mask)
+    }
+}
+
+/// Spawns a task probing the proxies of the balancer in the interval by the time connecting to
+/// them. The task exits once the balancer is dropped, and only one task is spawned for a balancer.
+pub(crate) fn probe(balancer: &Arc<Mutex<Balancer>>) {
+    let interval = {
+        let mut balancer = balancer.lock().unwrap();
+        if balancer.probe_interval == 0 || balancer.is_probing {
+            return;
+        }
+        balancer.is_probing = true;
+
+        balancer.probe_interval
+    };
+
+    let balancer = Arc::downgrade(balancer);
+    tokio::spawn(async move {
+        while let Some(proxies) = balancer
+            .upgrade()
+            .map(|balancer| balancer.lock().unwrap().proxies().to_vec())
+        {
+            for proxy in proxies {
+                let rtt = connect_time(proxy).await;
+                match balancer.upgrade() {
+                    Some(balancer) => balancer.lock().unwrap().set_rtt(proxy, rtt),
+                    None => return,
+                }
+            }
+
+            time::delay_for(Duration::from_millis(interval)).await;
+        }
+    });
+}
+
+async fn connect_time(proxy: SocketAddrV4) -> Option<Duration> {
+    let instant = Instant::now();
+    match time::timeout(
+        Duration::from_millis(PROBE_TIMEOUT),
+        TcpStream::connect(proxy),
+    )
+    .await
+    {
+        Ok(Ok(_)) => {
+            let rtt = instant.elapsed();
+            trace!("probe {}: {} ms", proxy, rtt.as_millis());
+
+            Some(rtt)
+        }
+        Ok(Err(ref e)) => {
+            debug!("probe {}: {}", proxy, e);
+
+            None
+        }
+        Err(_) => {
+            debug!("probe {}: timed out", proxy);
+
+            None
+        }
+    }
+}
+
+fn dedup(primary: SocketAddrV4, proxies: &[SocketAddrV4]) -> Vec<SocketAddrV4> {
+    let mut all = vec![primary];
+    for proxy in proxies {
+        if !all.contains(proxy) {
+            all.push(*proxy);
+        }
+    }
+
+    all
+}
+
+#[test]
+fn balancer_sticky() {
+    let a = "10.0.0.1:1080".parse().unwrap();
+    let b = "10.0.0.2:1080".parse().unwrap();
+
+    // Round robin
+    let mut balancer = Balancer::new(a, &[b, a]);
+    assert_eq!(balancer.proxies(), &[a, b]);
+    let dst = "1.1.1.1:80".parse().unwrap();
+    assert_eq!(balancer.select(dst), a);
+    assert_eq!(balancer.select(dst), b);
+
+    // Pinned subnets
+    balancer.set_sticky(60000, 24);
+    let first = balancer.select(dst);
+    assert_eq!(balancer.select("1.1.1.2:80".parse().unwrap()), first);
+    assert_ne!(balancer.select("1.1.2.1:80".parse().unwrap()), first);
+    assert_eq!(balancer.select(dst), first);
+
+    // The pinned proxy is replaced
+    let c = "10.0.0.3:1080".parse().unwrap();
+    balancer.set_primary(c);
+    assert_eq!(balancer.proxies(), &[c, b]);
+    assert_ne!(balancer.select("1.1.1.3:80".parse().unwrap()), a);
+}
+
+#[test]
+fn balancer_fastest() {
+    let a = "10.0.0.1:1080".parse().unwrap();
+    let b = "10.0.0.2:1080".parse().unwrap();
+    let c = "10.0.0.3:1080".parse().unwrap();
+
+    let mut balancer = Balancer::new(a, &[b, c]);
+    balancer.set_probe(1000, vec![3074]);
+    assert_eq!(balancer.fastest(), None);
+
+    balancer.set_rtt(a, Some(Duration::from_millis(80)));
+    balancer.set_rtt(b, None);
+    balancer.set_rtt(c, Some(Duration::from_millis(20)));
+    assert_eq!(balancer.fastest(), Some(c));
+    assert_eq!(balancer.rtts()[1], (b, None));
+
+    // Latency-sensitive ports
+    for _ in 0..3 {
+        assert_eq!(balancer.select("1.1.1.1:3074".parse().unwrap()), c);
+    }
+    assert_eq!(balancer.select("1.1.1.1:80".parse().unwrap()), a);
+    assert_eq!(balancer.select("1.1.1.1:80".parse().unwrap()), b);
+}
@@ -0,0 +1,106 @@
+//! Support for binding each source IP address to a hardware address, which is configured
+//! statically or is learned from the first frame of the source, so a device on the LAN cannot
+//! spoof the IP address of another device to hijack its proxied sessions.
+
+use lru::LruCache;
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+
+use crate::clock;
+use crate::pcap::HardwareAddr;
+
+/// Represents the max count of bindings learned. Bindings evicted are learned again.
+const BINDING_CAPACITY: usize = 4096;
+
+/// Represents the expire time in milliseconds of a binding learned. A binding is refreshed by
+/// every frame of its source, and another device may take over the IP address once it expires,
+/// like after a DHCP lease is reassigned.
+const BINDING_EXPIRE_TIME: u64 = 600000;
+
+/// Represents the bindings of source IP addresses to hardware addresses.
+#[derive(Debug)]
+pub(crate) struct Bindings {
+    statics: HashMap<Ipv4Addr, HardwareAddr>,
+    learned: Option<LruCache<Ipv4Addr, (HardwareAddr, Instant)>>,
+}
+
+impl Bindings {
+    /// Creates a new `Bindings` with the static bindings. Sources without static bindings are
+    /// bound to the hardware addresses they are first seen from if `is_learning` is true, or are
+    /// not bound otherwise.
+    pub(crate) fn new(statics: &[(Ipv4Addr, HardwareAddr)], is_learning: bool) -> Bindings {
+        Bindings {
+            statics: statics.iter().cloned().collect(),
+            learned: if is_learning {
+                Some(LruCache::new(BINDING_CAPACITY))
+            } else {
+                None
+            },
+        }
+    }
+
+    /// Returns if the frame of the source from the hardware address is allowed, and binds the
+    /// source to the hardware address if it is not bound yet.
+    pub(crate) fn check(&mut self, ip_addr: Ipv4Addr, hardware_addr: HardwareAddr) -> bool {
+        if let Some(bound) = self.statics.get(&ip_addr) {
+            return *bound == hardware_addr;
+        }
+        let learned = match self.learned {
+            Some(ref mut learned) => learned,
+            None => return true,
+        };
+
+        let now = clock::now();
+        if let Some((bound, instant)) = learned.get_mut(&ip_addr) {
+            let is_expired = now.saturating_duration_since(*instant)
+                >= Duration::from_millis(BINDING_EXPIRE_TIME);
+            if *bound != hardware_addr && !is_expired {
+                return false;
+            }
+            *bound = hardware_addr;
+            *instant = now;
+
+            return true;
+        }
+        learned.put(ip_addr, (hardware_addr, now));
+
+        true
+    }
+}
+
+#[test]
+fn bindings_check() {
+    use crate::clock::VirtualClock;
+    use pnet::datalink::MacAddr;
+    use std::sync::Arc;
+
+    let clock = VirtualClock::new();
+    let _clock = clock::enter(Some(Arc::new(clock.clone())));
+
+    let printer = Ipv4Addr::new(10, 6, 0, 1);
+    let laptop = Ipv4Addr::new(10, 6, 0, 2);
+    let printer_mac = MacAddr(0x02, 0, 0, 0, 0, 1);
+    let laptop_mac = MacAddr(0x02, 0, 0, 0, 0, 2);
+    let attacker_mac = MacAddr(0x02, 0, 0, 0, 0, 3);
+    let mut bindings = Bindings::new(&[(printer, printer_mac)], true);
+
+    // Static bindings are never taken over
+    assert!(!bindings.check(printer, attacker_mac));
+    assert!(bindings.check(printer, printer_mac));
+
+    // The first hardware address is learned
+    assert!(bindings.check(laptop, laptop_mac));
+    assert!(!bindings.check(laptop, attacker_mac));
+
+    // A binding learned expires without frames
+    clock.advance(Duration::from_millis(BINDING_EXPIRE_TIME));
+    assert!(bindings.check(laptop, attacker_mac));
+    assert!(!bindings.check(laptop, laptop_mac));
+
+    // Sources are not learned without learning
+    let mut bindings = Bindings::new(&[(printer, printer_mac)], false);
+    assert!(!bindings.check(printer, attacker_mac));
+    assert!(bindings.check(laptop, laptop_mac));
+    assert!(bindings.check(laptop, attacker_mac));
+}
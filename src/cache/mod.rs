@@ -5,13 +5,17 @@ use std::collections::{BTreeMap, HashSet, VecDeque};
 use std::fmt::{self, Display};
 use std::io::{Error, ErrorKind, Result};
 use std::ops::Bound::Included;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+use super::seq::{seq_add, seq_sub};
 use super::Timer;
 
 /// Represents the max distance of u32 values between packets in an u32 window.
 const MAX_U32_WINDOW_SIZE: usize = 16 * 1024 * 1024;
 
+/// Represents the offset of the edges of a `Window` after the sequence wraps around.
+const WRAP: u64 = 1 << 32;
+
 /// Represents a queue cache. The `Queue` can hold continuos bytes constantly unless they are
 /// invalidated. The `Queue` can be used as a send window of a TCP connection.
 #[derive(Debug)]
@@ -74,10 +78,7 @@ impl Queue {
         }
 
         // Sequence and clock
-        let sequence = self
-            .sequence
-            .checked_add(self.size as u32)
-            .unwrap_or_else(|| self.size as u32 - (u32::MAX - self.sequence));
+        let sequence = seq_add(self.sequence, self.size as u32);
         self.clocks.push_back((sequence, Timer::new(rto)));
 
         // From the tail to the end of the buffer
@@ -98,9 +99,7 @@ impl Queue {
 
     /// Invalidates queue to the certain sequence and returns the RTT.
     pub fn invalidate_to(&mut self, sequence: u32) -> Option<Duration> {
-        let size = sequence
-            .checked_sub(self.sequence)
-            .unwrap_or_else(|| u32::MAX - self.sequence + sequence) as usize;
+        let size = seq_sub(sequence, self.sequence) as usize;
 
         if size <= MAX_U32_WINDOW_SIZE as usize {
             self.sequence = sequence;
@@ -115,18 +114,12 @@ impl Queue {
 
             // Pop clocks
             while !self.clocks.is_empty() {
-                let dist = sequence
-                    .checked_sub(self.clocks[0].0)
-                    .unwrap_or_else(|| sequence + (u32::MAX - self.clocks[0].0))
-                    as usize;
+                let dist = seq_sub(sequence, self.clocks[0].0) as usize;
                 let recv_next = match self.clocks.len() {
                     1 => self.recv_next(),
                     _ => self.clocks[1].0,
                 };
-                let dist_next = sequence
-                    .checked_sub(recv_next)
-                    .unwrap_or_else(|| sequence + (u32::MAX - recv_next))
-                    as usize;
+                let dist_next = seq_sub(sequence, recv_next) as usize;
 
                 if dist <= MAX_U32_WINDOW_SIZE as usize && dist_next <= MAX_U32_WINDOW_SIZE as usize
                 {
@@ -139,12 +132,7 @@ impl Queue {
 
                             // Rollback on retransmission
                             if let Some(retrans) = self.retrans {
-                                if retrans
-                                    .checked_sub(sequence)
-                                    .unwrap_or_else(|| retrans + (u32::MAX - sequence))
-                                    as usize
-                                    <= MAX_U32_WINDOW_SIZE
-                                {
+                                if seq_sub(retrans, sequence) as usize <= MAX_U32_WINDOW_SIZE {
                                     rtt = None;
                                 }
                             }
@@ -162,13 +150,7 @@ impl Queue {
 
             // Retransmission
             if let Some(retrans) = self.retrans {
-                if self
-                    .sequence
-                    .checked_sub(retrans)
-                    .unwrap_or_else(|| self.sequence + (u32::MAX - retrans))
-                    as usize
-                    <= MAX_U32_WINDOW_SIZE
-                {
+                if seq_sub(self.sequence, retrans) as usize <= MAX_U32_WINDOW_SIZE {
                     self.retrans = None;
                 }
             }
@@ -179,15 +161,20 @@ impl Queue {
         None
     }
 
+    /// Marks all the payload in the queue as retransmitted. RTT will not be sampled until the
+    /// retransmitted payload is acknowledged, following Karn's algorithm.
+    pub fn set_retrans(&mut self) {
+        if !self.is_empty() {
+            self.retrans = Some(self.recv_next());
+        }
+    }
+
     /// Returns the payload from the certain sequence of the queue in the given size.
     pub fn get(&self, sequence: u32, size: usize) -> Result<Vec<u8>> {
         if size == 0 {
             return Ok(Vec::new());
         }
-        let distance = sequence
-            .checked_sub(self.sequence)
-            .unwrap_or_else(|| sequence + (u32::MAX - self.sequence))
-            as usize;
+        let distance = seq_sub(sequence, self.sequence) as usize;
         if distance > self.size {
             return Err(Error::new(
                 ErrorKind::InvalidInput,
@@ -234,10 +221,7 @@ impl Queue {
 
         match recv_next {
             Some(recv_next) => {
-                let size = recv_next
-                    .checked_sub(self.sequence)
-                    .unwrap_or_else(|| recv_next + (u32::MAX - self.sequence))
-                    as usize;
+                let size = seq_sub(recv_next, self.sequence) as usize;
 
                 self.get(self.sequence, size).unwrap()
             }
@@ -259,20 +243,12 @@ impl Queue {
 
         match recv_next {
             Some(recv_next) => {
-                let size = recv_next
-                    .checked_sub(self.sequence)
-                    .unwrap_or_else(|| recv_next + (u32::MAX - self.sequence))
-                    as usize;
+                let size = seq_sub(recv_next, self.sequence) as usize;
 
                 // Update clock
                 while !self.clocks.is_empty() {
                     let next_sequence = self.clocks.front().unwrap().0;
-                    if recv_next
-                        .checked_sub(next_sequence)
-                        .unwrap_or_else(|| recv_next + (u32::MAX - next_sequence))
-                        as usize
-                        <= MAX_U32_WINDOW_SIZE
-                    {
+                    if seq_sub(recv_next, next_sequence) as usize <= MAX_U32_WINDOW_SIZE {
                         self.clocks.pop_front();
                     } else {
                         self.clocks.push_front((self.sequence, Timer::new(rto)));
@@ -325,15 +301,18 @@ impl Queue {
 
     /// Returns the receive next of the queue.
     pub fn recv_next(&self) -> u32 {
-        self.sequence
-            .checked_add(self.size as u32)
-            .unwrap_or_else(|| self.size as u32 - (u32::MAX - self.sequence))
+        seq_add(self.sequence, self.size as u32)
     }
 
     /// Returns if the queue is empty.
     pub fn is_empty(&self) -> bool {
         self.size == 0
     }
+
+    /// Returns the instant when the first byte of the queue is timed out.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.clocks.front().map(|clock| clock.1.deadline())
+    }
 }
 
 impl Display for Queue {
@@ -433,6 +412,10 @@ pub struct Window {
     size: usize,
     /// Represents edges of filled values. Use an u64 instead of an u32 because the sequence is used as a ring.
     edges: BTreeMap<u64, usize>,
+    /// Represents the order the edges are last filled in, which is used to evict the oldest
+    /// edge.
+    updated: BTreeMap<u64, u64>,
+    fills: u64,
 }
 
 impl Window {
@@ -450,25 +433,20 @@ impl Window {
             head: 0,
             size: 0,
             edges: BTreeMap::new(),
+            updated: BTreeMap::new(),
+            fills: 0,
         }
     }
 
     /// Appends some bytes to the window and returns continuous bytes from the beginning.
     pub fn append(&mut self, sequence: u32, payload: &[u8]) -> Result<Option<Vec<u8>>> {
-        let sub_sequence = sequence
-            .checked_sub(self.sequence)
-            .unwrap_or_else(|| sequence + (u32::MAX - self.sequence))
-            as usize;
+        let sub_sequence = seq_sub(sequence, self.sequence) as usize;
         let (sequence, payload, sub_sequence) = if sub_sequence > MAX_U32_WINDOW_SIZE {
-            let recv_next = sequence
-                .checked_add(payload.len() as u32)
-                .unwrap_or_else(|| payload.len() as u32 - (u32::MAX - sequence));
-            let sub_recv_next_to_sequence = recv_next
-                .checked_sub(self.sequence)
-                .unwrap_or_else(|| sequence + (u32::MAX - recv_next));
-
-            if sub_recv_next_to_sequence > 0 {
-                let sub_sequence = self.sequence - sequence;
+            let recv_next = seq_add(sequence, payload.len() as u32);
+            let sub_recv_next_to_sequence = seq_sub(recv_next, self.sequence) as usize;
+
+            if sub_recv_next_to_sequence > 0 && sub_recv_next_to_sequence <= MAX_U32_WINDOW_SIZE {
+                let sub_sequence = seq_sub(self.sequence, sequence);
                 (self.sequence, &payload[sub_sequence as usize..], 0)
             } else {
                 return Ok(None);
@@ -522,16 +500,9 @@ impl Window {
         }
 
         // Update size
-        let recv_next = sequence
-            .checked_add(payload.len() as u32)
-            .unwrap_or_else(|| payload.len() as u32 - (u32::MAX - sequence));
-        let record_recv_next = self
-            .sequence
-            .checked_add(self.size as u32)
-            .unwrap_or_else(|| self.size as u32 - (u32::MAX - self.sequence));
-        let sub_recv_next = recv_next
-            .checked_sub(record_recv_next)
-            .unwrap_or_else(|| recv_next + (u32::MAX - record_recv_next));
+        let recv_next = seq_add(sequence, payload.len() as u32);
+        let record_recv_next = seq_add(self.sequence, self.size as u32);
+        let sub_recv_next = seq_sub(recv_next, record_recv_next);
         if sub_recv_next as usize <= MAX_U32_WINDOW_SIZE {
             self.size += sub_recv_next as usize;
         }
@@ -540,7 +511,7 @@ impl Window {
         {
             let mut sequence = sequence as u64;
             if (sequence as u32) < self.sequence {
-                sequence += u32::MAX as u64;
+                sequence += WRAP;
             }
 
             // Select ranges which can be merged in a loop
@@ -562,6 +533,7 @@ impl Window {
                 // Pop
                 for ref pop_key in pop_keys {
                     self.edges.remove(pop_key);
+                    self.updated.remove(pop_key);
                 }
             }
 
@@ -585,12 +557,15 @@ impl Window {
 
             // Insert range
             self.edges.insert(sequence, size as usize);
+            self.fills += 1;
+            self.updated.insert(sequence, self.fills);
         }
 
         // Pop if possible
         let first_key = *self.edges.keys().next().unwrap();
         if first_key as u32 == self.sequence {
             let size = self.edges.remove(&first_key).unwrap();
+            self.updated.remove(&first_key);
 
             // Shrink range sequence is possible
             if ((u32::MAX - self.sequence) as usize) < size {
@@ -598,7 +573,9 @@ impl Window {
 
                 for key in keys {
                     let value = self.edges.remove(&key).unwrap();
-                    self.edges.insert(key - u32::MAX as u64, value);
+                    self.edges.insert(key - WRAP, value);
+                    let order = self.updated.remove(&key).unwrap();
+                    self.updated.insert(key - WRAP, order);
                 }
             }
 
@@ -615,10 +592,7 @@ impl Window {
                 cont_payload[len_a..].copy_from_slice(&self.buffer[..len_b]);
             }
 
-            self.sequence = self
-                .sequence
-                .checked_add(size as u32)
-                .unwrap_or_else(|| size as u32 - (u32::MAX - self.sequence));
+            self.sequence = seq_add(self.sequence, size as u32);
             self.head = (self.head + (size % self.buffer.len())) % self.buffer.len();
             self.size -= cont_payload.len();
 
@@ -628,6 +602,11 @@ impl Window {
         Ok(None)
     }
 
+    /// Returns the capacity of the window.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
     /// Returns the sequence of the window.
     pub fn sequence(&self) -> u32 {
         self.sequence
@@ -640,9 +619,7 @@ impl Window {
 
     /// Returns the receive next of the window.
     pub fn recv_next(&self) -> u32 {
-        self.sequence
-            .checked_add(self.size as u32)
-            .unwrap_or_else(|| self.size as u32 - (u32::MAX - self.sequence))
+        seq_add(self.sequence, self.size as u32)
     }
 
     /// Returns the remaining size of the window.
@@ -658,14 +635,42 @@ impl Window {
         tmp.checked_sub(self.buffer.len()).unwrap_or(tmp)
     }
 
+    /// Returns the size of bytes filled out of order, which wait for holes before them to be
+    /// filled.
+    pub fn out_of_order_len(&self) -> usize {
+        self.edges.values().sum()
+    }
+
+    /// Returns the count of holes before the bytes filled out of order.
+    pub fn holes(&self) -> usize {
+        self.edges.len()
+    }
+
+    /// Evicts the edge of bytes filled out of order which is filled the earliest, and returns
+    /// the size of bytes evicted. The evicted bytes are expected to be retransmitted.
+    pub fn evict_oldest(&mut self) -> usize {
+        let key = match self.updated.iter().min_by_key(|(_, &order)| order) {
+            Some((&key, _)) => key,
+            None => return 0,
+        };
+        self.updated.remove(&key);
+        let size = self.edges.remove(&key).unwrap();
+
+        // Shrink the window to the last edge
+        let last_end = self.edges.iter().next_back().map(|(&sequence, &size)| {
+            seq_sub(seq_add(sequence as u32, size as u32), self.sequence)
+        });
+        self.size = last_end.unwrap_or(0) as usize;
+
+        size
+    }
+
     /// Returns the filled edges of the window.
     pub fn filled(&self) -> Vec<(u32, u32)> {
         let mut v = Vec::new();
         for (&sequence, &size) in &self.edges {
-            let begin = sequence.checked_sub(u32::MAX as u64).unwrap_or(sequence) as u32;
-            let end = begin
-                .checked_add(size as u32)
-                .unwrap_or_else(|| size as u32 - (u32::MAX - begin));
+            let begin = sequence as u32;
+            let end = seq_add(begin, size as u32);
             v.push((begin, end));
         }
 
@@ -691,10 +696,7 @@ impl Display for Window {
         let mut edge_begin_set = HashSet::new();
         let mut edge_end_set = HashSet::new();
         self.edges.iter().for_each(|(edge_sequence, &size)| {
-            let begin = edge_sequence
-                .checked_sub(sequence as u64)
-                .unwrap_or_else(|| edge_sequence + (u32::MAX - sequence) as u64)
-                as usize;
+            let begin = seq_sub(*edge_sequence as u32, sequence) as usize;
             let begin = begin
                 .checked_add(head)
                 .unwrap_or_else(|| head - (usize::MAX - begin));
@@ -734,6 +736,24 @@ impl Display for Window {
     }
 }
 
+#[test]
+fn queue_rtt_karn() {
+    let mut q = Queue::with_capacity(24, 0);
+    let v = (0..8).into_iter().collect::<Vec<_>>();
+
+    q.append(v.as_slice(), 1000).unwrap();
+    assert!(q.invalidate_to(8).is_some());
+
+    // Retransmitted
+    q.append(v.as_slice(), 1000).unwrap();
+    q.set_retrans();
+    assert!(q.invalidate_to(16).is_none());
+
+    // Sample again after the retransmitted payload is acknowledged
+    q.append(v.as_slice(), 1000).unwrap();
+    assert!(q.invalidate_to(24).is_some());
+}
+
 #[test]
 fn window_append() {
     let mut w = Window::with_capacity(9, 0);
@@ -828,3 +848,73 @@ fn window_append_prev_and_next() {
 
     assert_eq!(w.to_string(), "[0, 1, 2, <0, <4, 5>>]");
 }
+
+#[test]
+fn window_evict_oldest() {
+    let mut w = Window::with_capacity(16, 0);
+
+    let v = (4..6).into_iter().collect::<Vec<_>>();
+    w.append(4, v.as_slice()).unwrap();
+    let v = (10..12).into_iter().collect::<Vec<_>>();
+    w.append(10, v.as_slice()).unwrap();
+    let v = (8..9).into_iter().collect::<Vec<_>>();
+    w.append(8, v.as_slice()).unwrap();
+    assert_eq!(w.out_of_order_len(), 5);
+    assert_eq!(w.holes(), 3);
+
+    // The edge at 4 is the oldest, and the window is kept to the last edge
+    assert_eq!(w.evict_oldest(), 2);
+    assert_eq!(w.filled(), vec![(8, 9), (10, 12)]);
+    assert_eq!(w.len(), 12);
+
+    // The last edge at 10, the window shrinks to the edge at 8
+    assert_eq!(w.evict_oldest(), 2);
+    assert_eq!(w.len(), 9);
+    assert_eq!(w.evict_oldest(), 1);
+    assert_eq!(w.len(), 0);
+    assert_eq!(w.evict_oldest(), 0);
+
+    // Filled again from the beginning
+    let v = (0..4).into_iter().collect::<Vec<_>>();
+    assert_eq!(w.append(0, v.as_slice()).unwrap(), Some(v));
+}
+
+#[cfg(test)]
+use proptest::prelude::*;
+
+#[cfg(test)]
+proptest! {
+    #[test]
+    fn queue_invalidate_wrap(offset in 0u32..4096, size in 1usize..4096, n in 0usize..4096) {
+        let sequence = u32::MAX - offset;
+        let n = n % (size + 1);
+        let mut q = Queue::with_capacity(4096, sequence);
+
+        let v = (0..size).map(|x| x as u8).collect::<Vec<_>>();
+        q.append(v.as_slice(), 0).unwrap();
+        prop_assert_eq!(q.recv_next(), seq_add(sequence, size as u32));
+
+        q.invalidate_to(seq_add(sequence, n as u32));
+        prop_assert_eq!(q.len(), size - n);
+        prop_assert_eq!(q.get_all(), v[n..].to_vec());
+    }
+
+    #[test]
+    fn window_append_wrap(offset in 0u32..4096, size in 2usize..4096) {
+        let sequence = u32::MAX - offset;
+        let half = size / 2;
+        let mut w = Window::new(sequence);
+
+        let v = (0..size).map(|x| x as u8).collect::<Vec<_>>();
+        let p = w.append(seq_add(sequence, half as u32), &v[half..]).unwrap();
+        prop_assert_eq!(p, None);
+        prop_assert_eq!(
+            w.filled(),
+            vec![(seq_add(sequence, half as u32), seq_add(sequence, size as u32))]
+        );
+
+        let p = w.append(sequence, &v[..half]).unwrap();
+        prop_assert_eq!(p, Some(v));
+        prop_assert_eq!(w.sequence(), seq_add(sequence, size as u32));
+    }
+}
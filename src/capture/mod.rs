@@ -0,0 +1,385 @@
+//! Support for keeping the last frames of TCP connections and dumping them when the connections
+//! hit errors, so the exact context can be attached to bug reports.
+
+use log::{info, warn};
+use lru::LruCache;
+use std::collections::VecDeque;
+use std::fmt::{self, Display, Formatter};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::net::SocketAddrV4;
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::socks::ConnectFailure;
+
+/// Represents the max count of TCP connections whose frames are kept.
+const RING_CAPACITY: usize = 1024;
+/// Represents the max size of each frame kept. Longer frames are truncated.
+const SNAPLEN: usize = 512;
+/// Represents the min interval in milliseconds between dumps of malformed frames.
+const MALFORMED_DUMP_INTERVAL: u64 = 60000;
+
+/// Represents the block type of section header blocks in pcapng.
+const BLOCK_SECTION_HEADER: u32 = 0x0a0d_0d0a;
+/// Represents the block type of interface description blocks in pcapng.
+const BLOCK_INTERFACE_DESCRIPTION: u32 = 0x0000_0001;
+/// Represents the block type of enhanced packet blocks in pcapng.
+const BLOCK_ENHANCED_PACKET: u32 = 0x0000_0006;
+/// Represents the byte-order magic of pcapng.
+const BYTE_ORDER_MAGIC: u32 = 0x1a2b_3c4d;
+/// Represents the option code of comments in pcapng.
+const OPTION_COMMENT: u16 = 1;
+/// Represents the option code of the application writing the section in pcapng.
+const OPTION_USER_APPLICATION: u16 = 4;
+/// Represents the option code of flags of enhanced packet blocks in pcapng.
+const OPTION_FLAGS: u16 = 2;
+/// Represents the link type of Ethernet in pcapng.
+const LINKTYPE_ETHERNET: u16 = 1;
+
+/// Represents the error which triggers a dump.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum Trigger {
+    /// Represents a failure connecting through the proxy.
+    ConnectFailure(ConnectFailure),
+    /// Represents repeated retransmissions due to timeout.
+    Retransmission(u64),
+    /// Represents frames which cannot be parsed.
+    Malformed,
+}
+
+impl Display for Trigger {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Trigger::ConnectFailure(failure) => write!(f, "connect-{}", failure),
+            Trigger::Retransmission(_) => write!(f, "rto"),
+            Trigger::Malformed => write!(f, "malformed"),
+        }
+    }
+}
+
+impl Trigger {
+    fn describe(&self) -> String {
+        match self {
+            Trigger::ConnectFailure(failure) => {
+                format!("failed to connect through the proxy ({})", failure)
+            }
+            Trigger::Retransmission(n) => format!("retransmitted due to timeout {} times", n),
+            Trigger::Malformed => "received malformed frames".to_string(),
+        }
+    }
+}
+
+/// Represents a frame kept in a ring buffer.
+struct Record {
+    timestamp: Duration,
+    frame: Vec<u8>,
+    len: usize,
+    is_inbound: bool,
+}
+
+impl Record {
+    fn new(frame: &[u8], is_inbound: bool) -> Record {
+        Record {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default(),
+            frame: frame[..frame.len().min(SNAPLEN)].to_vec(),
+            len: frame.len(),
+            is_inbound,
+        }
+    }
+}
+
+/// Represents a recorder keeping the last frames of each TCP connection in a ring buffer, and
+/// dumping them in pcapng to the directory when the connection hits an error.
+pub(crate) struct Recorder {
+    dir: PathBuf,
+    frames: usize,
+    rings: LruCache<(SocketAddrV4, SocketAddrV4), VecDeque<Record>>,
+    malformed: VecDeque<Record>,
+    malformed_instant: Option<Instant>,
+}
+
+impl Recorder {
+    /// Creates a new `Recorder` keeping the last `frames` frames of each TCP connection.
+    pub(crate) fn new(dir: PathBuf, frames: usize) -> Recorder {
+        Recorder {
+            dir,
+            frames: frames.max(1),
+            rings: LruCache::new(RING_CAPACITY),
+            malformed: VecDeque::new(),
+            malformed_instant: None,
+        }
+    }
+
+    /// Records a frame of the TCP connection from the source to the destination, which is either
+    /// received from or sent to the source.
+    pub(crate) fn record(
+        &mut self,
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+        frame: &[u8],
+        is_inbound: bool,
+    ) {
+        let key = (src, dst);
+        if !self.rings.contains(&key) {
+            self.rings.put(key, VecDeque::with_capacity(self.frames));
+        }
+        let ring = self.rings.get_mut(&key).unwrap();
+        if ring.len() >= self.frames {
+            ring.pop_front();
+        }
+        ring.push_back(Record::new(frame, is_inbound));
+    }
+
+    /// Removes the frames of the TCP connection from the source to the destination.
+    pub(crate) fn remove(&mut self, src: SocketAddrV4, dst: SocketAddrV4) {
+        self.rings.pop(&(src, dst));
+    }
+
+    /// Dumps the frames of the TCP connection from the source to the destination, which are
+    /// removed after the dump.
+    pub(crate) fn dump(&mut self, src: SocketAddrV4, dst: SocketAddrV4, trigger: Trigger) {
+        let records = match self.rings.pop(&(src, dst)) {
+            Some(records) => records,
+            None => return,
+        };
+
+        let name = format!("{}-{}", src, dst).replace(':', "_");
+        let comment = format!("TCP {} -> {} {}", src, dst, trigger.describe());
+        self.write(&name, &comment, trigger, &records);
+    }
+
+    /// Records a malformed frame received from sources, and dumps the last malformed frames
+    /// unless they have been dumped recently.
+    pub(crate) fn dump_malformed(&mut self, frame: &[u8]) {
+        if self.malformed.len() >= self.frames {
+            self.malformed.pop_front();
+        }
+        self.malformed.push_back(Record::new(frame, true));
+
+        if let Some(instant) = self.malformed_instant {
+            if instant.elapsed() < Duration::from_millis(MALFORMED_DUMP_INTERVAL) {
+                return;
+            }
+        }
+        self.malformed_instant = Some(Instant::now());
+
+        let records = self.malformed.drain(..).collect::<VecDeque<_>>();
+        let trigger = Trigger::Malformed;
+        self.write("frames", &trigger.describe(), trigger, &records);
+    }
+
+    fn write(&self, name: &str, comment: &str, trigger: Trigger, records: &VecDeque<Record>) {
+        let secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let path = self
+            .dir
+            .join(format!("{}-{}-{}.pcapng", secs, name, trigger));
+
+        let result = fs::create_dir_all(&self.dir)
+            .and_then(|_| File::create(&path))
+            .and_then(|mut file| file.write_all(&serialize(comment, records)));
+        match result {
+            Ok(_) => info!(
+                "Dump {} frames to {}: {}",
+                records.len(),
+                path.display(),
+                comment
+            ),
+            Err(ref e) => warn!("dump {}: {}", path.display(), e),
+        }
+    }
+}
+
+/// Serializes the frames in pcapng with the comment on the section.
+fn serialize(comment: &str, records: &VecDeque<Record>) -> Vec<u8> {
+    let mut buffer = serialize_header(comment, SNAPLEN as u32);
+    for record in records {
+        buffer.extend_from_slice(&serialize_frame(
+            record.timestamp,
+            &record.frame,
+            record.len,
+            record.is_inbound,
+        ));
+    }
+
+    buffer
+}
+
+/// Serializes the section header block with the comment and the interface description block of
+/// Ethernet in pcapng.
+pub(crate) fn serialize_header(comment: &str, snaplen: u32) -> Vec<u8> {
+    let mut buffer = Vec::new();
+
+    // Section header block
+    let mut body = Vec::new();
+    body.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+    body.extend_from_slice(&1u16.to_le_bytes());
+    body.extend_from_slice(&0u16.to_le_bytes());
+    body.extend_from_slice(&(-1i64).to_le_bytes());
+    push_option(&mut body, OPTION_COMMENT, comment.as_bytes());
+    push_option(&mut body, OPTION_USER_APPLICATION, b"pcap2socks");
+    push_option(&mut body, 0, &[]);
+    push_block(&mut buffer, BLOCK_SECTION_HEADER, &body);
+
+    // Interface description block
+    let mut body = Vec::new();
+    body.extend_from_slice(&LINKTYPE_ETHERNET.to_le_bytes());
+    body.extend_from_slice(&0u16.to_le_bytes());
+    body.extend_from_slice(&snaplen.to_le_bytes());
+    push_block(&mut buffer, BLOCK_INTERFACE_DESCRIPTION, &body);
+
+    buffer
+}
+
+/// Serializes the enhanced packet block of a frame, which is truncated from its original length,
+/// in pcapng with the timestamp since the UNIX epoch and the direction.
+pub(crate) fn serialize_frame(
+    timestamp: Duration,
+    frame: &[u8],
+    len: usize,
+    is_inbound: bool,
+) -> Vec<u8> {
+    let mut buffer = Vec::new();
+
+    let timestamp = timestamp.as_micros() as u64;
+    let mut body = Vec::new();
+    body.extend_from_slice(&0u32.to_le_bytes());
+    body.extend_from_slice(&((timestamp >> 32) as u32).to_le_bytes());
+    body.extend_from_slice(&(timestamp as u32).to_le_bytes());
+    body.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+    body.extend_from_slice(&(len as u32).to_le_bytes());
+    body.extend_from_slice(frame);
+    pad(&mut body);
+    // Direction in the lowest 2 bits, 1 for inbound and 2 for outbound
+    let flags: u32 = if is_inbound { 1 } else { 2 };
+    push_option(&mut body, OPTION_FLAGS, &flags.to_le_bytes());
+    push_option(&mut body, 0, &[]);
+    push_block(&mut buffer, BLOCK_ENHANCED_PACKET, &body);
+
+    buffer
+}
+
+/// Parses the frames in pcapng written by `serialize_header` and `serialize_frame`, and returns
+/// their timestamps since the UNIX epoch, their contents and if they are inbound. Blocks of other
+/// types are skipped.
+pub(crate) fn parse(buffer: &[u8]) -> io::Result<Vec<(Duration, Vec<u8>, bool)>> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+    let u16_at = |b: &[u8], i: usize| u16::from_le_bytes([b[i], b[i + 1]]);
+    let u32_at = |b: &[u8], i: usize| u32::from_le_bytes([b[i], b[i + 1], b[i + 2], b[i + 3]]);
+
+    let mut frames = Vec::new();
+    let mut i = 0;
+    while i < buffer.len() {
+        if buffer.len() - i < 12 {
+            return Err(invalid("truncated block"));
+        }
+        let block_type = u32_at(buffer, i);
+        let len = u32_at(buffer, i + 4) as usize;
+        if len < 12 || len & 0x3 != 0 || len > buffer.len() - i {
+            return Err(invalid("invalid block length"));
+        }
+        let body = &buffer[i + 8..i + len - 4];
+        match block_type {
+            BLOCK_SECTION_HEADER if body.len() < 4 || u32_at(body, 0) != BYTE_ORDER_MAGIC => {
+                return Err(invalid("unsupported byte order"));
+            }
+            BLOCK_ENHANCED_PACKET => {
+                if body.len() < 20 {
+                    return Err(invalid("truncated enhanced packet block"));
+                }
+                let timestamp = (u32_at(body, 4) as u64) << 32 | u32_at(body, 8) as u64;
+                let captured_len = u32_at(body, 12) as usize;
+                if captured_len > body.len() - 20 {
+                    return Err(invalid("truncated enhanced packet block"));
+                }
+                let frame = body[20..20 + captured_len].to_vec();
+
+                // Options
+                let mut is_inbound = true;
+                let mut j = 20 + padded(captured_len);
+                while j + 4 <= body.len() {
+                    let code = u16_at(body, j);
+                    let option_len = u16_at(body, j + 2) as usize;
+                    if code == 0 || j + 4 + option_len > body.len() {
+                        break;
+                    }
+                    if code == OPTION_FLAGS && option_len == 4 {
+                        is_inbound = u32_at(body, j + 4) & 0x3 != 2;
+                    }
+                    j += 4 + padded(option_len);
+                }
+
+                frames.push((Duration::from_micros(timestamp), frame, is_inbound));
+            }
+            _ => {}
+        }
+        i += len;
+    }
+
+    Ok(frames)
+}
+
+fn push_block(buffer: &mut Vec<u8>, block_type: u32, body: &[u8]) {
+    let len = (12 + body.len()) as u32;
+    buffer.extend_from_slice(&block_type.to_le_bytes());
+    buffer.extend_from_slice(&len.to_le_bytes());
+    buffer.extend_from_slice(body);
+    buffer.extend_from_slice(&len.to_le_bytes());
+}
+
+fn push_option(body: &mut Vec<u8>, code: u16, value: &[u8]) {
+    body.extend_from_slice(&code.to_le_bytes());
+    body.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    body.extend_from_slice(value);
+    pad(body);
+}
+
+fn pad(body: &mut Vec<u8>) {
+    body.resize(padded(body.len()), 0);
+}
+
+fn padded(len: usize) -> usize {
+    len + (4 - len % 4) % 4
+}
+
+#[test]
+fn recorder_dump() {
+    let dir = std::env::temp_dir().join(format!("pcap2socks-capture-{}", std::process::id()));
+    let src = "10.6.0.2:50000".parse().unwrap();
+    let dst = "1.1.1.1:443".parse().unwrap();
+
+    let mut recorder = Recorder::new(dir.clone(), 2);
+    for i in 0..3 {
+        recorder.record(src, dst, &[i; 60], i % 2 == 0);
+    }
+    let ring = recorder.rings.peek(&(src, dst)).unwrap();
+    assert_eq!(ring.len(), 2);
+    assert_eq!(ring[0].frame[0], 1);
+    assert!(!ring[0].is_inbound);
+    recorder.dump(src, dst, Trigger::ConnectFailure(ConnectFailure::Reply));
+    assert!(recorder.rings.peek(&(src, dst)).is_none());
+
+    let entries = fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect::<Vec<_>>();
+    let _ = fs::remove_dir_all(&dir);
+    assert_eq!(entries.len(), 1);
+    let name = entries[0]
+        .file_name()
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .to_string();
+    assert!(name.ends_with("-10.6.0.2_50000-1.1.1.1_443-connect-reply.pcapng"));
+
+    // Blocks are aligned to 4 bytes
+    let buffer = serialize("comment", &recorder.malformed);
+    assert_eq!(buffer.len() % 4, 0);
+    assert_eq!(buffer[..4], BLOCK_SECTION_HEADER.to_le_bytes());
+}
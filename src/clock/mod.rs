@@ -0,0 +1,206 @@
+//! Support for abstracting the time of the TCP stack, so retransmissions, FIN timers and
+//! cool-downs can run under a virtual clock, like reproducing timeouts deterministically in tests
+//! without real sleeps.
+//!
+//! The clock is owned by each `Redirector` and its `Forwarder`, and is entered while they process
+//! frames and in the tasks they spawn. The clock of the system is used if none is entered.
+
+use std::cell::RefCell;
+use std::fmt::{self, Debug, Formatter};
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+
+/// Represents a source of the current time.
+pub trait Clock: Debug + Send + Sync {
+    /// Returns the current instant.
+    fn now(&self) -> Instant;
+}
+
+/// Represents the clock of the system.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Represents a virtual clock which only advances when it is told to. Clones of a `VirtualClock`
+/// share the time.
+#[derive(Clone, Debug)]
+pub struct VirtualClock {
+    now: Arc<Mutex<Instant>>,
+}
+
+impl VirtualClock {
+    /// Creates a new `VirtualClock` starting at the current instant of the system.
+    pub fn new() -> VirtualClock {
+        VirtualClock {
+            now: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    /// Advances the clock by the duration.
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+}
+
+impl Default for VirtualClock {
+    fn default() -> Self {
+        VirtualClock::new()
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+}
+
+/// Represents a clock shared by a `Config`, which is compared and hashed by its identity.
+#[derive(Clone)]
+pub(crate) struct SharedClock(pub(crate) Arc<dyn Clock>);
+
+impl Debug for SharedClock {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl PartialEq for SharedClock {
+    fn eq(&self, other: &SharedClock) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for SharedClock {}
+
+impl Hash for SharedClock {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (Arc::as_ptr(&self.0) as *const u8 as usize).hash(state);
+    }
+}
+
+thread_local! {
+    static CLOCK: RefCell<Option<Arc<dyn Clock>>> = RefCell::new(None);
+}
+
+/// Represents a clock entered in the current thread, which leaves the clock when it is dropped.
+pub(crate) struct Entered {
+    prev: Option<Arc<dyn Clock>>,
+}
+
+impl Drop for Entered {
+    fn drop(&mut self) {
+        let prev = self.prev.take();
+        CLOCK.with(|c| *c.borrow_mut() = prev);
+    }
+}
+
+/// Enters the clock in the current thread until the returned guard is dropped, or the clock of
+/// the system if it is `None`. The guard must not be held across an `await`.
+pub(crate) fn enter(clock: Option<Arc<dyn Clock>>) -> Entered {
+    Entered {
+        prev: CLOCK.with(|c| c.replace(clock)),
+    }
+}
+
+/// Returns the clock entered in the current thread.
+pub(crate) fn current() -> Option<Arc<dyn Clock>> {
+    CLOCK.with(|c| c.borrow().clone())
+}
+
+/// Represents a future which enters a clock each time it is polled.
+pub struct Scoped<F> {
+    clock: Option<Arc<dyn Clock>>,
+    future: Pin<Box<F>>,
+}
+
+impl<F: Future> Future for Scoped<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<F::Output> {
+        let _entered = enter(self.clock.clone());
+        self.future.as_mut().poll(cx)
+    }
+}
+
+/// Runs the future under the clock.
+pub fn scope<F: Future>(clock: Arc<dyn Clock>, future: F) -> Scoped<F> {
+    scope_option(Some(clock), future)
+}
+
+/// Runs the future under the clock, or the clock of the system if it is `None`.
+pub(crate) fn scope_option<F: Future>(clock: Option<Arc<dyn Clock>>, future: F) -> Scoped<F> {
+    Scoped {
+        clock,
+        future: Box::pin(future),
+    }
+}
+
+/// Spawns the future as a task which runs under the clock entered in the current thread.
+pub(crate) fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    tokio::spawn(scope_option(current(), future))
+}
+
+/// Returns the current instant in the clock entered in the current thread.
+pub fn now() -> Instant {
+    CLOCK.with(|c| match *c.borrow() {
+        Some(ref clock) => clock.now(),
+        None => Instant::now(),
+    })
+}
+
+/// Returns the amount of time elapsed since the instant in the clock entered in the current
+/// thread.
+pub fn elapsed(instant: Instant) -> Duration {
+    now().saturating_duration_since(instant)
+}
+
+#[test]
+fn virtual_clock_advance() {
+    let clock = VirtualClock::new();
+    let entered = enter(Some(Arc::new(clock.clone())));
+
+    let instant = now();
+    assert_eq!(elapsed(instant), Duration::from_millis(0));
+    clock.advance(Duration::from_millis(1500));
+    assert_eq!(now(), instant + Duration::from_millis(1500));
+    assert_eq!(elapsed(instant), Duration::from_millis(1500));
+
+    // Other threads keep the clock of the system
+    let handle = std::thread::spawn(move || now() >= instant + Duration::from_millis(1500));
+    assert!(!handle.join().unwrap());
+
+    drop(entered);
+    assert!(now() < instant + Duration::from_millis(1500));
+}
+
+#[test]
+fn virtual_clock_spawn() {
+    let clock = VirtualClock::new();
+    let instant = clock.now();
+    clock.advance(Duration::from_millis(1500));
+
+    // Tasks spawned under the clock keep the clock in any thread
+    let mut rt = tokio::runtime::Runtime::new().unwrap();
+    let (spawned, outside) = rt.block_on(scope(Arc::new(clock), async move {
+        let spawned = spawn(async move { now() }).await.unwrap();
+        let outside = tokio::spawn(async move { now() }).await.unwrap();
+
+        (spawned, outside)
+    }));
+    assert_eq!(spawned, instant + Duration::from_millis(1500));
+    assert!(outside < instant + Duration::from_millis(1500));
+}
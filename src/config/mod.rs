@@ -0,0 +1,1120 @@
+//! Support for configuring the redirector.
+
+use ipnetwork::Ipv4Network;
+use std::cmp::min;
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::clock::{Clock, SharedClock};
+use crate::pcap::HardwareAddr;
+use crate::policy::PortProfile;
+use crate::qos::{QosClass, QosMatch};
+#[cfg(feature = "http2")]
+use crate::socks::Http2Option;
+#[cfg(feature = "ssh")]
+use crate::socks::SshOption;
+#[cfg(feature = "vmess")]
+use crate::socks::VmessOption;
+#[cfg(feature = "websocket")]
+use crate::socks::WebSocketOption;
+use crate::source::AddrRange;
+#[cfg(feature = "wireguard")]
+use crate::wireguard::WireGuardOption;
+
+/// Represents the default max limit of UDP port for binding in local.
+const DEFAULT_UDP_CAPACITY: usize = 256;
+/// Represents the default idle timeout of a UDP port for binding in local.
+const DEFAULT_UDP_TIMEOUT: u64 = 300000;
+/// Represents the default max limit of pending TCP connections of a source.
+const DEFAULT_TCP_PENDING_LIMIT: usize = 64;
+/// Represents the default destination port of TCP connections inspected for SNI filtering.
+const DEFAULT_SNI_PORT: u16 = 443;
+/// Represents the default duration of quarantining a source.
+const DEFAULT_QUARANTINE_TIME: u64 = 300000;
+/// Represents the default timeout of a pending TCP connection.
+const DEFAULT_TCP_PENDING_TIMEOUT: u64 = 20000;
+/// Represents the default timeout of an idle TCP connection, which is 2 hours and 4 minutes as
+/// required in RFC 5382.
+const DEFAULT_TCP_IDLE_TIMEOUT: u64 = 7440000;
+/// Represents the default max count of retransmissions of a TCP SYN/ACK.
+const DEFAULT_TCP_SYN_RETRIES: usize = 5;
+/// Represents the default initial backoff before retrying connecting to the proxy.
+const DEFAULT_TCP_CONNECT_BACKOFF: u64 = 1000;
+/// Represents the default interval of rechecking the gateway IP address with ARP probes.
+const DEFAULT_ARP_RECHECK: u64 = 60000;
+/// Represents the default count of frames kept of each TCP connection for dumps.
+const DEFAULT_CAPTURE_FRAMES: usize = 32;
+/// Represents the default high watermark of the queue of a TCP connection.
+const DEFAULT_TCP_QUEUE_HIGH: usize = 1024 * 1024;
+/// Represents the default low watermark of the queue of a TCP connection.
+const DEFAULT_TCP_QUEUE_LOW: usize = 256 * 1024;
+/// Represents the default max limit of the write queue of a TCP connection.
+const DEFAULT_TCP_WRITE_LIMIT: usize = 1024 * 1024;
+/// Represents the default max limit of the out-of-order data of a TCP connection.
+const DEFAULT_TCP_OUT_OF_ORDER_LIMIT: usize = 1024 * 1024;
+/// Represents the default unscaled receive window of a TCP connection.
+const DEFAULT_TCP_RECV_WINDOW: u16 = u16::MAX;
+/// Represents the default max window scale of the receive window of a TCP connection.
+const DEFAULT_TCP_RECV_WSCALE: u8 = 8;
+/// Represents the max window scale allowed in RFC 7323.
+pub(crate) const MAX_TCP_WSCALE: u8 = 14;
+
+/// Represents the behavior of filtering inbound datagrams of a UDP port for binding in local.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum NatMode {
+    /// Represents any peer can send datagrams to the source through the port (endpoint-independent
+    /// filtering).
+    FullCone,
+    /// Represents only the peer whose IP address has been sent to by the source can send datagrams
+    /// to the source (address-dependent filtering).
+    AddressRestricted,
+    /// Represents only the peer whose IP address and port have been sent to by the source can send
+    /// datagrams to the source (address and port-dependent filtering).
+    PortRestricted,
+}
+
+impl Display for NatMode {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            NatMode::FullCone => write!(f, "full-cone"),
+            NatMode::AddressRestricted => write!(f, "address-restricted"),
+            NatMode::PortRestricted => write!(f, "port-restricted"),
+        }
+    }
+}
+
+impl FromStr for NatMode {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "f" | "full-cone" => Ok(NatMode::FullCone),
+            "a" | "address-restricted" => Ok(NatMode::AddressRestricted),
+            "p" | "port-restricted" => Ok(NatMode::PortRestricted),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "unknown NAT mode",
+            )),
+        }
+    }
+}
+
+/// Represents the behavior of handling ICMPv4 redirect and source quench messages from the source.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum IcmpPolicy {
+    /// Represents the messages are counted and ignored silently.
+    Ignore,
+    /// Represents the messages are counted, logged and ignored.
+    Log,
+    /// Represents the messages are counted, logged and honored where applicable. A source quench
+    /// throttles the TCP connection until the source updates its window again. A redirect can
+    /// never be honored because pcap2socks is the gateway itself.
+    Honor,
+}
+
+impl Display for IcmpPolicy {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            IcmpPolicy::Ignore => write!(f, "ignore"),
+            IcmpPolicy::Log => write!(f, "log"),
+            IcmpPolicy::Honor => write!(f, "honor"),
+        }
+    }
+}
+
+impl FromStr for IcmpPolicy {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "i" | "ignore" => Ok(IcmpPolicy::Ignore),
+            "l" | "log" => Ok(IcmpPolicy::Log),
+            "h" | "honor" => Ok(IcmpPolicy::Honor),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "unknown ICMP policy",
+            )),
+        }
+    }
+}
+
+/// Represents the behavior of handling ICMPv4 echo requests from the source, which cannot be
+/// relayed through a SOCKS proxy. Echo requests are always relayed through a WireGuard tunnel.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum EchoMode {
+    /// Represents the requests are dropped.
+    Drop,
+    /// Represents the requests are answered with synthetic replies after a realistic RTT, so
+    /// connectivity checks like in games pass.
+    Reply,
+}
+
+impl Display for EchoMode {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            EchoMode::Drop => write!(f, "drop"),
+            EchoMode::Reply => write!(f, "reply"),
+        }
+    }
+}
+
+impl FromStr for EchoMode {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "d" | "drop" => Ok(EchoMode::Drop),
+            "r" | "reply" => Ok(EchoMode::Reply),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "unknown ICMP echo mode",
+            )),
+        }
+    }
+}
+
+/// Represents the behavior of handling TCP urgent data from the source, which cannot be sent out of
+/// band to a proxy.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum UrgentPolicy {
+    /// Represents the urgent data is relayed inline in the stream, like sockets with
+    /// `SO_OOBINLINE`, as recommended in RFC 6093.
+    Inline,
+    /// Represents the last byte of the urgent data, which the urgent pointer points to, is
+    /// stripped from the stream, like sockets reading urgent data out of band by default.
+    Strip,
+}
+
+impl Display for UrgentPolicy {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            UrgentPolicy::Inline => write!(f, "inline"),
+            UrgentPolicy::Strip => write!(f, "strip"),
+        }
+    }
+}
+
+impl FromStr for UrgentPolicy {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "i" | "inline" => Ok(UrgentPolicy::Inline),
+            "s" | "strip" => Ok(UrgentPolicy::Strip),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "unknown urgent policy",
+            )),
+        }
+    }
+}
+
+/// Represents the action on a TCP connection whose TLS ClientHello carries a server name matching
+/// a rule.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum SniAction {
+    /// Represents the connection is reset without any data sent through the proxy.
+    Block,
+    /// Represents the connection is connected to the destination directly instead of through the
+    /// proxy.
+    Bypass,
+}
+
+impl Display for SniAction {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            SniAction::Block => write!(f, "block"),
+            SniAction::Bypass => write!(f, "bypass"),
+        }
+    }
+}
+
+/// Represents the behavior of handling tunneled packets from the source, like GRE, IPsec and 6in4,
+/// which cannot be redirected to a proxy.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum TunnelPolicy {
+    /// Represents the packets are counted and dropped silently.
+    Drop,
+    /// Represents the packets are counted, dropped, and logged once for each tunnel.
+    Log,
+    /// Represents the packets are counted and forwarded as is to the peer in UDP datagrams. The
+    /// peer replies tunneled packets to the source in UDP datagrams too.
+    Forward(SocketAddrV4),
+}
+
+impl Display for TunnelPolicy {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            TunnelPolicy::Drop => write!(f, "drop"),
+            TunnelPolicy::Log => write!(f, "log"),
+            TunnelPolicy::Forward(peer) => write!(f, "forward to {}", peer),
+        }
+    }
+}
+
+impl FromStr for TunnelPolicy {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "d" | "drop" => Ok(TunnelPolicy::Drop),
+            "l" | "log" => Ok(TunnelPolicy::Log),
+            _ => match s.parse::<SocketAddrV4>() {
+                Ok(peer) => Ok(TunnelPolicy::Forward(peer)),
+                Err(_) => Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "unknown tunnel policy",
+                )),
+            },
+        }
+    }
+}
+
+/// Represents the behavior of handling UDP datagrams to the limited broadcast address or the
+/// subnet-directed broadcast address of the source.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum BroadcastMode {
+    /// Represents broadcast datagrams are sent to the proxy as is.
+    Proxy,
+    /// Represents broadcast datagrams are dropped.
+    Drop,
+    /// Represents broadcast datagrams are relayed to the unicast address with the same port.
+    Relay(Ipv4Addr),
+}
+
+impl Display for BroadcastMode {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            BroadcastMode::Proxy => write!(f, "proxy"),
+            BroadcastMode::Drop => write!(f, "drop"),
+            BroadcastMode::Relay(addr) => write!(f, "relay to {}", addr),
+        }
+    }
+}
+
+impl FromStr for BroadcastMode {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "proxy" => Ok(BroadcastMode::Proxy),
+            "drop" => Ok(BroadcastMode::Drop),
+            _ => match s.parse::<Ipv4Addr>() {
+                Ok(addr) => Ok(BroadcastMode::Relay(addr)),
+                Err(_) => Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "unknown broadcast mode",
+                )),
+            },
+        }
+    }
+}
+
+/// Represents the behavior of handling UDP datagrams to multicast addresses.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum MulticastMode {
+    /// Represents multicast datagrams are dropped.
+    Drop,
+    /// Represents multicast datagrams are reflected locally to other sources which have joined
+    /// the group.
+    Reflect,
+    /// Represents multicast datagrams are relayed to the unicast addresses with the same port
+    /// through the proxy.
+    Relay(Vec<Ipv4Addr>),
+}
+
+impl Display for MulticastMode {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            MulticastMode::Drop => write!(f, "drop"),
+            MulticastMode::Reflect => write!(f, "reflect"),
+            MulticastMode::Relay(addrs) => {
+                let addrs: Vec<_> = addrs.iter().map(|addr| addr.to_string()).collect();
+                write!(f, "relay to {}", addrs.join(", "))
+            }
+        }
+    }
+}
+
+impl FromStr for MulticastMode {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop" => Ok(MulticastMode::Drop),
+            "reflect" => Ok(MulticastMode::Reflect),
+            _ => {
+                let mut addrs = Vec::new();
+                for addr in s.split(',') {
+                    match addr.trim().parse::<Ipv4Addr>() {
+                        Ok(addr) => addrs.push(addr),
+                        Err(_) => {
+                            return Err(io::Error::new(
+                                io::ErrorKind::InvalidInput,
+                                "unknown multicast mode",
+                            ))
+                        }
+                    }
+                }
+
+                Ok(MulticastMode::Relay(addrs))
+            }
+        }
+    }
+}
+
+/// Represents a preset of tuning for a common workload, which sets the sizes of queues, pacing,
+/// the UDP ports for binding in local and the limits of TCP connections coherently.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Preset {
+    /// Represents latency-sensitive traffic of games. Queues are kept short to avoid
+    /// bufferbloat, more UDP ports are kept longer for peer-to-peer traffic, and connections to
+    /// the proxy are pre-connected and retried.
+    Gaming,
+    /// Represents bulk downloads. Queues and the receive window are long to keep fast proxies
+    /// busy, and segments are paced to avoid bursts overflowing the buffers of the source.
+    BulkDownload,
+    /// Represents routers with little memory. Queues and the receive window are short, and UDP
+    /// ports and TCP connections are limited and expired early.
+    LowMemoryRouter,
+}
+
+impl Display for Preset {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Preset::Gaming => write!(f, "gaming"),
+            Preset::BulkDownload => write!(f, "bulk-download"),
+            Preset::LowMemoryRouter => write!(f, "low-memory-router"),
+        }
+    }
+}
+
+impl FromStr for Preset {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "g" | "gaming" => Ok(Preset::Gaming),
+            "b" | "bulk-download" => Ok(Preset::BulkDownload),
+            "l" | "low-memory-router" => Ok(Preset::LowMemoryRouter),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "unknown preset",
+            )),
+        }
+    }
+}
+
+/// Represents the configuration of a `Redirector`.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct Config {
+    pub(crate) sources: Vec<Ipv4Network>,
+    pub(crate) source_excludes: Vec<AddrRange>,
+    pub(crate) udp_capacity: usize,
+    pub(crate) udp_timeout: u64,
+    pub(crate) nat_mode: NatMode,
+    pub(crate) broadcast_mode: BroadcastMode,
+    pub(crate) netbios_names: Vec<(String, Ipv4Addr)>,
+    pub(crate) multicast_mode: MulticastMode,
+    pub(crate) mtu_cache: Option<PathBuf>,
+    pub(crate) mtu_probing: bool,
+    pub(crate) snapshot: Option<PathBuf>,
+    pub(crate) tcp_capacity: usize,
+    pub(crate) tcp_eviction: bool,
+    pub(crate) tcp_pending_limit: usize,
+    pub(crate) connect_rate: u32,
+    pub(crate) connect_burst: u32,
+    pub(crate) quarantine_threshold: u32,
+    pub(crate) quarantine_time: u64,
+    pub(crate) source_binding: bool,
+    pub(crate) static_bindings: Vec<(Ipv4Addr, HardwareAddr)>,
+    pub(crate) sni_rules: Vec<(String, SniAction)>,
+    pub(crate) sni_ports: Vec<u16>,
+    pub(crate) sni_fail_open: bool,
+    pub(crate) port_profiles: Vec<PortProfile>,
+    pub(crate) port_profile_sources: Vec<(AddrRange, String)>,
+    pub(crate) direct: Vec<AddrRange>,
+    pub(crate) qos_classes: Vec<QosClass>,
+    pub(crate) qos_matches: Vec<QosMatch>,
+    pub(crate) tx_priority: bool,
+    pub(crate) priority_ports: Vec<u16>,
+    pub(crate) tcp_pending_timeout: u64,
+    pub(crate) tcp_idle_timeout: u64,
+    pub(crate) tcp_syn_retries: usize,
+    pub(crate) tcp_queue_high: usize,
+    pub(crate) tcp_queue_low: usize,
+    pub(crate) tcp_write_limit: usize,
+    pub(crate) tcp_out_of_order_limit: usize,
+    pub(crate) tcp_recv_window: u16,
+    pub(crate) tcp_recv_wscale: u8,
+    pub(crate) tcp_pacing: bool,
+    pub(crate) tcp_connect_retries: usize,
+    pub(crate) tcp_connect_backoff: u64,
+    pub(crate) socks_pool: usize,
+    pub(crate) icmp_policy: IcmpPolicy,
+    pub(crate) echo_mode: EchoMode,
+    pub(crate) echo_rtt: u64,
+    pub(crate) tunnel_policy: TunnelPolicy,
+    pub(crate) urgent_policy: UrgentPolicy,
+    pub(crate) log_discovery: bool,
+    pub(crate) arp_recheck: u64,
+    pub(crate) workers: usize,
+    pub(crate) proxies: Vec<SocketAddrV4>,
+    pub(crate) sticky_ttl: u64,
+    pub(crate) sticky_prefix: u8,
+    pub(crate) probe_interval: u64,
+    pub(crate) fastest_ports: Vec<u16>,
+    pub(crate) capture: Option<PathBuf>,
+    pub(crate) capture_frames: usize,
+    pub(crate) tcp_trace: Option<PathBuf>,
+    pub(crate) clock: Option<SharedClock>,
+    #[cfg(feature = "http2")]
+    pub(crate) http2: Option<Http2Option>,
+    #[cfg(feature = "ssh")]
+    pub(crate) ssh: Option<SshOption>,
+    #[cfg(feature = "vmess")]
+    pub(crate) vmess: Option<VmessOption>,
+    #[cfg(feature = "websocket")]
+    pub(crate) websocket: Option<WebSocketOption>,
+    #[cfg(feature = "wireguard")]
+    pub(crate) wireguard: Option<WireGuardOption>,
+}
+
+impl Config {
+    /// Creates a new `Config` with default values.
+    pub fn new() -> Config {
+        Config {
+            sources: Vec::new(),
+            source_excludes: Vec::new(),
+            udp_capacity: DEFAULT_UDP_CAPACITY,
+            udp_timeout: DEFAULT_UDP_TIMEOUT,
+            nat_mode: NatMode::FullCone,
+            broadcast_mode: BroadcastMode::Proxy,
+            netbios_names: Vec::new(),
+            multicast_mode: MulticastMode::Drop,
+            mtu_cache: None,
+            mtu_probing: false,
+            snapshot: None,
+            tcp_capacity: 0,
+            tcp_eviction: false,
+            tcp_pending_limit: DEFAULT_TCP_PENDING_LIMIT,
+            connect_rate: 0,
+            connect_burst: 0,
+            quarantine_threshold: 0,
+            quarantine_time: DEFAULT_QUARANTINE_TIME,
+            source_binding: false,
+            static_bindings: Vec::new(),
+            sni_rules: Vec::new(),
+            sni_ports: vec![DEFAULT_SNI_PORT],
+            sni_fail_open: false,
+            port_profiles: Vec::new(),
+            port_profile_sources: Vec::new(),
+            direct: Vec::new(),
+            qos_classes: Vec::new(),
+            qos_matches: Vec::new(),
+            tx_priority: false,
+            priority_ports: Vec::new(),
+            tcp_pending_timeout: DEFAULT_TCP_PENDING_TIMEOUT,
+            tcp_idle_timeout: DEFAULT_TCP_IDLE_TIMEOUT,
+            tcp_syn_retries: DEFAULT_TCP_SYN_RETRIES,
+            tcp_queue_high: DEFAULT_TCP_QUEUE_HIGH,
+            tcp_queue_low: DEFAULT_TCP_QUEUE_LOW,
+            tcp_write_limit: DEFAULT_TCP_WRITE_LIMIT,
+            tcp_out_of_order_limit: DEFAULT_TCP_OUT_OF_ORDER_LIMIT,
+            tcp_recv_window: DEFAULT_TCP_RECV_WINDOW,
+            tcp_recv_wscale: DEFAULT_TCP_RECV_WSCALE,
+            tcp_pacing: false,
+            tcp_connect_retries: 0,
+            tcp_connect_backoff: DEFAULT_TCP_CONNECT_BACKOFF,
+            socks_pool: 0,
+            icmp_policy: IcmpPolicy::Log,
+            echo_mode: EchoMode::Drop,
+            echo_rtt: 0,
+            tunnel_policy: TunnelPolicy::Log,
+            urgent_policy: UrgentPolicy::Inline,
+            log_discovery: false,
+            arp_recheck: DEFAULT_ARP_RECHECK,
+            workers: 1,
+            proxies: Vec::new(),
+            sticky_ttl: 0,
+            sticky_prefix: 32,
+            probe_interval: 0,
+            fastest_ports: Vec::new(),
+            capture: None,
+            capture_frames: DEFAULT_CAPTURE_FRAMES,
+            tcp_trace: None,
+            clock: None,
+            #[cfg(feature = "http2")]
+            http2: None,
+            #[cfg(feature = "ssh")]
+            ssh: None,
+            #[cfg(feature = "vmess")]
+            vmess: None,
+            #[cfg(feature = "websocket")]
+            websocket: None,
+            #[cfg(feature = "wireguard")]
+            wireguard: None,
+        }
+    }
+
+    /// Sets the values of the preset. Values set before are overwritten, and values set after
+    /// override the preset.
+    pub fn preset(self, preset: Preset) -> Config {
+        match preset {
+            Preset::Gaming => self
+                .udp_capacity(1024)
+                .udp_timeout(600000)
+                .tcp_queue_high(256 * 1024)
+                .tcp_queue_low(64 * 1024)
+                .tcp_write_limit(256 * 1024)
+                .tcp_out_of_order_limit(256 * 1024)
+                .tcp_pacing(false)
+                .tcp_connect_retries(2)
+                .socks_pool(4),
+            Preset::BulkDownload => self
+                .tcp_queue_high(8 * 1024 * 1024)
+                .tcp_queue_low(2 * 1024 * 1024)
+                .tcp_write_limit(4 * 1024 * 1024)
+                .tcp_out_of_order_limit(4 * 1024 * 1024)
+                .tcp_recv_wscale(10)
+                .tcp_pacing(true),
+            Preset::LowMemoryRouter => self
+                .udp_capacity(64)
+                .udp_timeout(60000)
+                .tcp_capacity(256)
+                .tcp_eviction(true)
+                .tcp_pending_limit(16)
+                .tcp_queue_high(128 * 1024)
+                .tcp_queue_low(32 * 1024)
+                .tcp_write_limit(128 * 1024)
+                .tcp_out_of_order_limit(64 * 1024)
+                .tcp_recv_wscale(2)
+                .socks_pool(0)
+                .capture_frames(8),
+        }
+    }
+
+    /// Sets the networks of sources besides the source passed to the `Redirector`.
+    pub fn sources(mut self, networks: Vec<Ipv4Network>) -> Config {
+        self.sources = networks;
+        self
+    }
+
+    /// Sets the ranges excluded from the sources, whose traffic is neither redirected nor
+    /// answered in ARP, like a NAS in the source network which should reach the gateway directly.
+    pub fn source_excludes(mut self, ranges: Vec<AddrRange>) -> Config {
+        self.source_excludes = ranges;
+        self
+    }
+
+    /// Sets the max limit of UDP port for binding in local. If all the ports are in use, the least
+    /// recently used one will be reused.
+    pub fn udp_capacity(mut self, capacity: usize) -> Config {
+        self.udp_capacity = capacity;
+        self
+    }
+
+    /// Sets the idle timeout in milliseconds of a UDP port for binding in local. A port which has
+    /// neither sent nor received any datagram within the timeout will be unbound. A timeout of 0
+    /// disables the expiry.
+    pub fn udp_timeout(mut self, timeout: u64) -> Config {
+        self.udp_timeout = timeout;
+        self
+    }
+
+    /// Sets the behavior of filtering inbound datagrams of UDP ports for binding in local. The
+    /// full-cone mode is the most open one and may lead to a NAT type A or open in game consoles.
+    pub fn nat_mode(mut self, mode: NatMode) -> Config {
+        self.nat_mode = mode;
+        self
+    }
+
+    /// Sets the behavior of handling UDP broadcast datagrams, e.g. LAN discovery of games.
+    pub fn broadcast_mode(mut self, mode: BroadcastMode) -> Config {
+        self.broadcast_mode = mode;
+        self
+    }
+
+    /// Adds a NetBIOS name answered locally. Broadcast NetBIOS name queries for the name, which
+    /// is case insensitive, are answered with the address regardless of the broadcast mode, so
+    /// sources can discover a host behind the proxy by its name, e.g. the host of a LAN game.
+    pub fn netbios_name(mut self, name: &str, addr: Ipv4Addr) -> Config {
+        self.netbios_names.push((name.to_string(), addr));
+        self
+    }
+
+    /// Sets the behavior of handling UDP multicast datagrams, e.g. mDNS, SSDP and LAN discovery of
+    /// games. A SOCKS proxy cannot send datagrams to multicast addresses in general, so they are
+    /// dropped by default.
+    pub fn multicast_mode(mut self, mode: MulticastMode) -> Config {
+        self.multicast_mode = mode;
+        self
+    }
+
+    /// Sets the path of the file persisting the learned path MTU to destinations across restarts.
+    pub fn mtu_cache(mut self, path: PathBuf) -> Config {
+        self.mtu_cache = Some(path);
+        self
+    }
+
+    /// Sets if the path MTU to destinations is probed, like packetization layer path MTU discovery
+    /// (PLPMTUD). If enabled, the path MTU falls back to the base MTU when TCP segments larger
+    /// than it keep timing out on a path dropping them silently, and is raised again by probing
+    /// with single TCP segments.
+    pub fn mtu_probing(mut self, is_enabled: bool) -> Config {
+        self.mtu_probing = is_enabled;
+        self
+    }
+
+    /// Sets the path of the file snapshotting UDP NAT bindings, the hardware addresses and the MTU
+    /// of sources, and the learned path MTU. The snapshot is saved periodically and restored on
+    /// startup, so sources keep their local UDP ports across restarts.
+    pub fn snapshot(mut self, path: PathBuf) -> Config {
+        self.snapshot = Some(path);
+        self
+    }
+
+    /// Sets the max limit of simultaneous TCP connections. If the limit is reached, new TCP
+    /// connections will be refused unless eviction is enabled. A limit of 0 disables the limit.
+    pub fn tcp_capacity(mut self, capacity: usize) -> Config {
+        self.tcp_capacity = capacity;
+        self
+    }
+
+    /// Sets if the longest idle TCP connection will be evicted instead of refusing new TCP
+    /// connections when the max limit of simultaneous TCP connections is reached.
+    pub fn tcp_eviction(mut self, eviction: bool) -> Config {
+        self.tcp_eviction = eviction;
+        self
+    }
+
+    /// Sets the max limit of pending TCP connections of a source. A pending TCP connection has
+    /// been connected to the proxy but has not completed the handshake with the source. TCP SYNs
+    /// beyond the limit will be dropped. A limit of 0 disables the limit.
+    pub fn tcp_pending_limit(mut self, limit: usize) -> Config {
+        self.tcp_pending_limit = limit;
+        self
+    }
+
+    /// Sets the max rate of connection attempts of a source per second, and the max count of
+    /// attempts at once, which are TCP SYNs and datagrams binding new UDP ports. TCP SYNs beyond
+    /// the rate will be refused, and datagrams will be dropped, before any SOCKS work is done. A
+    /// rate of 0 disables the limit, and a burst of 0 is the same with the rate.
+    pub fn connect_rate(mut self, rate: u32, burst: u32) -> Config {
+        self.connect_rate = rate;
+        self.connect_burst = if burst == 0 { rate } else { burst };
+        self
+    }
+
+    /// Sets the max count of errors of a source in a minute, which are failures connecting
+    /// through the proxy, RSTs and malformed packets, and the duration in milliseconds of
+    /// quarantining a source exceeding the threshold. Packets of a quarantined source will be
+    /// dropped until the quarantine expires. A threshold of 0 disables the quarantine.
+    pub fn quarantine(mut self, threshold: u32, time: u64) -> Config {
+        self.quarantine_threshold = threshold;
+        self.quarantine_time = time;
+        self
+    }
+
+    /// Sets if each source is bound to the hardware address it is first seen from. Frames of a
+    /// source from other hardware addresses will be dropped as spoofed until the binding expires
+    /// after the source is idle for 10 minutes.
+    pub fn source_binding(mut self, value: bool) -> Config {
+        self.source_binding = value;
+        self
+    }
+
+    /// Adds a static binding of the source to the hardware address, which never expires. Frames
+    /// of the source from other hardware addresses will be dropped as spoofed, regardless of
+    /// `source_binding`.
+    pub fn static_binding(mut self, ip_addr: Ipv4Addr, hardware_addr: HardwareAddr) -> Config {
+        self.static_bindings.push((ip_addr, hardware_addr));
+        self
+    }
+
+    /// Adds a rule filtering TCP connections by the server name in their TLS ClientHellos. A
+    /// pattern matches the domain and its subdomains, and a pattern starting with `*.` matches
+    /// only the subdomains. Rules are matched in the order they are added, and connections
+    /// matching no rule are connected through the proxy.
+    pub fn sni_rule(mut self, pattern: &str, action: SniAction) -> Config {
+        self.sni_rules.push((pattern.to_string(), action));
+        self
+    }
+
+    /// Sets the destination ports of TCP connections inspected for SNI filtering if any rule is
+    /// added. The handshake of an inspected connection is completed with the source first, and
+    /// the connection is connected after its ClientHello is received.
+    pub fn sni_ports(mut self, ports: Vec<u16>) -> Config {
+        self.sni_ports = ports;
+        self
+    }
+
+    /// Sets if inspected TCP connections whose server names cannot be parsed in time, or whose
+    /// ClientHellos are too large, are connected through the proxy even if any blocking rule is
+    /// added. By default such connections are blocked, so a blocklist cannot be evaded by
+    /// withholding the ClientHello.
+    pub fn sni_fail_open(mut self, value: bool) -> Config {
+        self.sni_fail_open = value;
+        self
+    }
+
+    /// Adds a named profile of destination port policies, which is applied to sources with
+    /// `port_profile_source`.
+    pub fn port_profile(mut self, profile: PortProfile) -> Config {
+        self.port_profiles.push(profile);
+        self
+    }
+
+    /// Applies the port profile of the name to the range of sources. New TCP connections and UDP
+    /// datagrams of the sources denied by the profile will be refused before connecting through
+    /// the proxy. Ranges are matched in the order they are added.
+    pub fn port_profile_source(mut self, range: AddrRange, profile: &str) -> Config {
+        self.port_profile_sources.push((range, profile.to_string()));
+        self
+    }
+
+    /// Adds a range of destinations which are connected directly with ordinary TCP and UDP
+    /// sockets of the host instead of through the proxy, like a NAT router. The range
+    /// `0.0.0.0-255.255.255.255` connects every destination directly, and the proxy is never used.
+    pub fn direct(mut self, range: AddrRange) -> Config {
+        self.direct.push(range);
+        self
+    }
+
+    /// Adds a class of traffic shaping, whose flows are classified with `qos_match`. The traffic
+    /// of a class is limited by a token bucket in each direction, which is shared by all its
+    /// flows. TCP data to sources is deferred and UDP datagrams to sources are dropped once the
+    /// bucket is empty, and data from sources waits before it is sent to the proxy.
+    pub fn qos_class(mut self, class: QosClass) -> Config {
+        self.qos_classes.push(class);
+        self
+    }
+
+    /// Adds a match of flows to a class of traffic shaping. Matches are matched in the order they
+    /// are added, and flows matching no match are not shaped.
+    pub fn qos_match(mut self, m: QosMatch) -> Config {
+        self.qos_matches.push(m);
+        self
+    }
+
+    /// Sets if frames sent to sources are scheduled in two queues. Small packets, pure TCP ACKs
+    /// and packets from the priority ports are sent ahead of bulk segments waiting while the pcap
+    /// device is busy, which reduces the latency induced by a large download sharing the link.
+    pub fn tx_priority(mut self, is_enabled: bool) -> Config {
+        self.tx_priority = is_enabled;
+        self
+    }
+
+    /// Sets the destination ports of flows whose packets are sent to sources in high priority,
+    /// like the ports of game servers.
+    pub fn priority_ports(mut self, ports: Vec<u16>) -> Config {
+        self.priority_ports = ports;
+        self
+    }
+
+    /// Sets the timeout in milliseconds of a pending TCP connection. A pending TCP connection
+    /// which has not completed the handshake within the timeout will be reset. A timeout of 0
+    /// disables the expiry.
+    pub fn tcp_pending_timeout(mut self, timeout: u64) -> Config {
+        self.tcp_pending_timeout = timeout;
+        self
+    }
+
+    /// Sets the timeout in milliseconds of an idle TCP connection. A TCP connection which has
+    /// neither received a segment from the source nor transferred data with the proxy within the
+    /// timeout will be reset and purged, like when its source disappears without a FIN or a RST.
+    /// A timeout of 0 disables the reaping.
+    pub fn tcp_idle_timeout(mut self, timeout: u64) -> Config {
+        self.tcp_idle_timeout = timeout;
+        self
+    }
+
+    /// Sets the max count of retransmissions of a TCP SYN/ACK to the source with exponential
+    /// backoff. A pending TCP connection whose last retransmission is not acknowledged will be
+    /// reset and its connection to the proxy closed, even before the pending timeout.
+    pub fn tcp_syn_retries(mut self, retries: usize) -> Config {
+        self.tcp_syn_retries = retries;
+        self
+    }
+
+    /// Sets the high watermark in bytes of the queue of a TCP connection. The queue holds data
+    /// received from the proxy but not sent to the source yet. Reading from the proxy pauses once
+    /// the queue reaches the high watermark. A watermark of 0 disables the limit.
+    pub fn tcp_queue_high(mut self, size: usize) -> Config {
+        self.tcp_queue_high = size;
+        self
+    }
+
+    /// Sets the low watermark in bytes of the queue of a TCP connection. Paused reading from the
+    /// proxy resumes once the queue drains to the low watermark. A low watermark above the high
+    /// watermark is lowered to it.
+    pub fn tcp_queue_low(mut self, size: usize) -> Config {
+        self.tcp_queue_low = size;
+        self
+    }
+
+    /// Sets the max limit in bytes of the write queue of a TCP connection. The queue holds data
+    /// received from the source but not written to the proxy yet. A zero window is advertised to
+    /// the source once the queue reaches the limit, and reopened after it drains to half of the
+    /// limit. A limit of 0 disables the backpressure.
+    pub fn tcp_write_limit(mut self, size: usize) -> Config {
+        self.tcp_write_limit = size;
+        self
+    }
+
+    /// Sets the max limit in bytes of the out-of-order data of a TCP connection, which is
+    /// received from the source but waits for holes before it to be filled. The data received
+    /// the earliest is dropped once the limit is exceeded, and is expected to be retransmitted
+    /// by the source. A limit of 0 keeps data up to the receive window.
+    pub fn tcp_out_of_order_limit(mut self, size: usize) -> Config {
+        self.tcp_out_of_order_limit = size;
+        self
+    }
+
+    /// Sets the unscaled receive window advertised to the source of a TCP connection. Default as
+    /// 65535.
+    pub fn tcp_recv_window(mut self, window: u16) -> Config {
+        self.tcp_recv_window = window;
+        self
+    }
+
+    /// Sets the max window scale of the receive window of a TCP connection, which is capped at 14.
+    /// The receive window can be scaled up to `window << wscale` bytes if the source supports the
+    /// window scale option. Default as 8.
+    pub fn tcp_recv_wscale(mut self, wscale: u8) -> Config {
+        self.tcp_recv_wscale = min(wscale, MAX_TCP_WSCALE);
+        self
+    }
+
+    /// Sets if TCP connections pace segments sent to the source, which spreads the send window
+    /// over the SRTT instead of sending it in a burst. Pacing of a single TCP connection can be
+    /// changed with `Forwarder::set_tcp_pacing`.
+    pub fn tcp_pacing(mut self, pacing: bool) -> Config {
+        self.tcp_pacing = pacing;
+        self
+    }
+
+    /// Sets the max retries of a TCP connection after the proxy is unreachable or the connection
+    /// to it is broken. Instead of being reset, the SYN is dropped, and SYNs retransmitted by the
+    /// source are dropped until the backoff elapses, so a momentary restart of the proxy does not
+    /// fail connections. Failures of authentication are never retried. A limit of 0 disables
+    /// retrying.
+    pub fn tcp_connect_retries(mut self, retries: usize) -> Config {
+        self.tcp_connect_retries = retries;
+        self
+    }
+
+    /// Sets the initial backoff in milliseconds before retrying connecting to the proxy, which is
+    /// doubled in each retry.
+    pub fn tcp_connect_backoff(mut self, backoff: u64) -> Config {
+        self.tcp_connect_backoff = backoff;
+        self
+    }
+
+    /// Sets the count of pre-connected connections kept to each SOCKS5 proxy in each worker. A
+    /// pre-connected connection has negotiated the method and authenticated, so a new TCP
+    /// connection only takes the round trip of the CONNECT request. A count of 0 disables
+    /// pooling.
+    pub fn socks_pool(mut self, size: usize) -> Config {
+        self.socks_pool = size;
+        self
+    }
+
+    /// Sets the behavior of handling ICMPv4 redirect and source quench messages from the source.
+    pub fn icmp_policy(mut self, policy: IcmpPolicy) -> Config {
+        self.icmp_policy = policy;
+        self
+    }
+
+    /// Sets the behavior of handling ICMPv4 echo requests from the source, and the RTT in
+    /// milliseconds of synthetic replies. An RTT of 0 uses the lowest RTT of proxies in the last
+    /// probe, or replies at once if proxies are not probed.
+    pub fn icmp_echo(mut self, mode: EchoMode, rtt: u64) -> Config {
+        self.echo_mode = mode;
+        self.echo_rtt = rtt;
+        self
+    }
+
+    /// Sets the behavior of handling tunneled packets from the source, like GRE, IPsec and 6in4.
+    pub fn tunnel_policy(mut self, policy: TunnelPolicy) -> Config {
+        self.tunnel_policy = policy;
+        self
+    }
+
+    /// Sets the behavior of handling TCP urgent data from the source.
+    pub fn urgent_policy(mut self, policy: UrgentPolicy) -> Config {
+        self.urgent_policy = policy;
+        self
+    }
+
+    /// Sets if the summaries of link-layer discovery frames, like LLDP, CDP and STP, will be
+    /// logged. A summary is logged when it is first received from a device or when it changes.
+    /// The frames are always counted.
+    pub fn log_discovery(mut self, log: bool) -> Config {
+        self.log_discovery = log;
+        self
+    }
+
+    /// Sets the interval in milliseconds of rechecking the gateway IP address with ARP probes.
+    /// The address is always probed before ARP requests for it are replied, and ARP requests are
+    /// no longer replied once another device owns the address. An interval of 0 disables
+    /// rechecks.
+    pub fn arp_recheck(mut self, interval: u64) -> Config {
+        self.arp_recheck = interval;
+        self
+    }
+
+    /// Sets the count of workers of a `Dispatcher`. Each worker is a `Redirector` running in its
+    /// own task, and frames are dispatched onto workers by flows. The max limits of UDP ports and
+    /// TCP connections are divided among workers. A `Redirector` alone ignores it.
+    pub fn workers(mut self, workers: usize) -> Config {
+        self.workers = workers;
+        self
+    }
+
+    /// Sets the additional proxies. New TCP connections and UDP ports for binding in local are
+    /// balanced among the proxy and the additional proxies in round robin.
+    pub fn proxies(mut self, proxies: Vec<SocketAddrV4>) -> Config {
+        self.proxies = proxies;
+        self
+    }
+
+    /// Sets the time in milliseconds a destination is pinned to the proxy selected for it since
+    /// it is selected last time, so the destination always sees the same exit. A TTL of 0
+    /// disables pinning.
+    pub fn sticky_ttl(mut self, ttl: u64) -> Config {
+        self.sticky_ttl = ttl;
+        self
+    }
+
+    /// Sets the prefix length of destination subnets pinned to proxies. Destinations in the same
+    /// subnet are pinned to the same proxy.
+    pub fn sticky_prefix(mut self, prefix: u8) -> Config {
+        self.sticky_prefix = prefix;
+        self
+    }
+
+    /// Sets the interval in milliseconds between probes of the time connecting to each proxy. The
+    /// RTTs in the last probe are reported in the statistics. An interval of 0 disables probing.
+    pub fn probe_interval(mut self, interval: u64) -> Config {
+        self.probe_interval = interval;
+        self
+    }
+
+    /// Sets the destination ports of latency-sensitive connections. New TCP connections and UDP
+    /// ports for binding in local to these ports are sent through the fastest proxy in the last
+    /// probe instead of balanced in round robin.
+    pub fn fastest_ports(mut self, ports: Vec<u16>) -> Config {
+        self.fastest_ports = ports;
+        self
+    }
+
+    /// Sets the directory to dump the last frames of TCP connections to in pcapng when they hit
+    /// errors, like failing to connect through the proxy or retransmitting repeatedly due to
+    /// timeout. Malformed frames received are dumped as well.
+    pub fn capture(mut self, dir: PathBuf) -> Config {
+        self.capture = Some(dir);
+        self
+    }
+
+    /// Sets the count of the last frames kept of each TCP connection for dumps.
+    pub fn capture_frames(mut self, frames: usize) -> Config {
+        self.capture_frames = frames;
+        self
+    }
+
+    /// Sets the directory to dump the protocol traces of TCP connections to when they are
+    /// closed. A trace keeps the state transitions, the sequence numbers, the acknowledgements and
+    /// the windows of segments, and the timers fired of a connection, and can also be got on
+    /// demand from the controller.
+    pub fn tcp_trace(mut self, dir: PathBuf) -> Config {
+        self.tcp_trace = Some(dir);
+        self
+    }
+
+    /// Sets the clock of the TCP stack, like a `VirtualClock` to replay a session
+    /// deterministically. Under a clock other than the system's, timers of TCP connections are
+    /// fired in the frame loop from the clock instead of by the timer driver in real time.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Config {
+        self.clock = Some(SharedClock(clock));
+        self
+    }
+
+    /// Sets the options of the HTTP/2 proxy. Once set, the proxy is an HTTP/2 proxy instead of a
+    /// SOCKS5 proxy, where TCP connections are multiplexed in a single connection, and UDP is
+    /// tunneled in CONNECT-UDP if the proxy supports it.
+    #[cfg(feature = "http2")]
+    pub fn http2(mut self, http2: Http2Option) -> Config {
+        self.http2 = Some(http2);
+        self
+    }
+
+    /// Sets the options of the SSH server. Once set, the proxy is an SSH server instead of a
+    /// SOCKS5 proxy, where TCP connections are opened in direct-tcpip channels of a single
+    /// connection, and UDP is not supported.
+    #[cfg(feature = "ssh")]
+    pub fn ssh(mut self, ssh: SshOption) -> Config {
+        self.ssh = Some(ssh);
+        self
+    }
+
+    /// Sets the options of the VMess server. Once set, the proxy is a VMess server instead of a
+    /// SOCKS5 proxy, and traffic is relayed in VMess without a local SOCKS bridge.
+    #[cfg(feature = "vmess")]
+    pub fn vmess(mut self, vmess: VmessOption) -> Config {
+        self.vmess = Some(vmess);
+        self
+    }
+
+    /// Sets the options of the WebSocket transport. Once set, the byte stream of SOCKS5 is carried
+    /// in a WebSocket connection to the proxy, and UDP is not supported.
+    #[cfg(feature = "websocket")]
+    pub fn websocket(mut self, websocket: WebSocketOption) -> Config {
+        self.websocket = Some(websocket);
+        self
+    }
+
+    /// Sets the options of the WireGuard tunnel. Once set, the destination is a WireGuard peer,
+    /// and routed IPv4 packets are translated and relayed in the tunnel instead of the proxy. A
+    /// `Dispatcher` ignores it.
+    #[cfg(feature = "wireguard")]
+    pub fn wireguard(mut self, wireguard: WireGuardOption) -> Config {
+        self.wireguard = Some(wireguard);
+        self
+    }
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config::new()
+    }
+}
+
+#[test]
+fn config_preset() {
+    let config = Config::new().tcp_pacing(true).preset(Preset::Gaming);
+    assert!(!config.tcp_pacing);
+    assert!(config.tcp_queue_low <= config.tcp_queue_high);
+
+    // Values set after override the preset
+    let config = Config::new()
+        .preset(Preset::LowMemoryRouter)
+        .udp_capacity(128);
+    assert_eq!(config.udp_capacity, 128);
+    assert_eq!(config.tcp_capacity, 256);
+    assert_eq!(config.tcp_recv_wscale, 2);
+    assert_eq!(
+        Config::new().tcp_recv_wscale(20).tcp_recv_wscale,
+        MAX_TCP_WSCALE
+    );
+
+    assert_eq!(
+        "bulk-download".parse::<Preset>().unwrap(),
+        Preset::BulkDownload
+    );
+    assert_eq!(Preset::LowMemoryRouter.to_string(), "low-memory-router");
+}
@@ -0,0 +1,243 @@
+//! Support for serving the controller over gRPC.
+
+use log::info;
+use std::net::{SocketAddr, SocketAddrV4};
+use std::time::Duration;
+use tokio::io;
+use tokio::sync::mpsc;
+use tokio::time;
+use tonic::transport::Server;
+use tonic::{Request, Response, Status};
+
+use super::Controller;
+use crate::packet::discovery::DiscoveryProtocol;
+use crate::packet::layer::LayerKinds;
+use crate::packet::tunnel::TunnelProtocol;
+use crate::socks::ConnectFailure;
+
+/// Generated protobuf messages and services of the control API.
+pub mod proto {
+    tonic::include_proto!("pcap2socks.control");
+}
+
+use proto::control_server::{Control, ControlServer};
+
+/// Represents the default interval in milliseconds of streaming statistics.
+const STATS_INTERVAL: u64 = 1000;
+
+/// Represents the gRPC service of a controller.
+pub struct ControlService {
+    controller: Controller,
+}
+
+impl ControlService {
+    /// Creates a new `ControlService`.
+    pub fn new(controller: Controller) -> ControlService {
+        ControlService { controller }
+    }
+
+    fn stats(&self) -> proto::Stats {
+        let stats = self.controller.stats();
+
+        proto::Stats {
+            udp_capacity: stats.udp_capacity() as u64,
+            udp_bindings: stats.udp_bindings() as u64,
+            udp_expirations: stats.udp_expirations(),
+            udp_reuses: stats.udp_reuses(),
+            udp_stall_drops: stats.udp_stall_drops(),
+            udp_rate_limits: stats.udp_rate_limits(),
+            quic_sessions: stats.quic_sessions() as u64,
+            quic_migrations: stats.quic_migrations(),
+            broadcast_drops: stats.broadcast_drops(),
+            broadcast_relays: stats.broadcast_relays(),
+            broadcast_answers: stats.broadcast_answers(),
+            multicast_groups: stats.multicast_groups() as u64,
+            multicast_drops: stats.multicast_drops(),
+            multicast_relays: stats.multicast_relays(),
+            multicast_reflections: stats.multicast_reflections(),
+            tcp_invalid_segments: stats.tcp_invalid_segments(),
+            tcp_challenge_acks: stats.tcp_challenge_acks(),
+            tcp_refusals: stats.tcp_refusals(),
+            tcp_evictions: stats.tcp_evictions(),
+            tcp_idle_reaps: stats.tcp_idle_reaps(),
+            tcp_syn_drops: stats.tcp_syn_drops(),
+            tcp_rate_limits: stats.tcp_rate_limits(),
+            tcp_pending_expirations: stats.tcp_pending_expirations(),
+            tcp_write_stalls: stats.tcp_write_stalls(),
+            icmp_redirects: stats.icmp_redirects(),
+            icmp_source_quenches: stats.icmp_source_quenches(),
+            icmp_echo_replies: stats.icmp_echo_replies(),
+            malformed_ethernet: stats.malformed(LayerKinds::Ethernet),
+            malformed_arp: stats.malformed(LayerKinds::Arp),
+            malformed_ipv4: stats.malformed(LayerKinds::Ipv4),
+            malformed_icmpv4: stats.malformed(LayerKinds::Icmpv4),
+            malformed_tcp: stats.malformed(LayerKinds::Tcp),
+            malformed_udp: stats.malformed(LayerKinds::Udp),
+            dispatch_drops: stats.dispatch_drops(),
+            rx_bytes: stats.rx_bytes(),
+            tx_bytes: stats.tx_bytes(),
+            proxy_rtts: stats
+                .proxy_rtts()
+                .into_iter()
+                .map(|(proxy, rtt)| proto::ProxyRtt {
+                    proxy: proxy.to_string(),
+                    rtt_us: rtt.map(|rtt| rtt.as_micros() as u64).unwrap_or(0),
+                })
+                .collect(),
+            tcp_connect_retries: stats.tcp_connect_retries(),
+            connect_auth_failures: stats.connect_failures(ConnectFailure::Auth),
+            connect_method_failures: stats.connect_failures(ConnectFailure::Method),
+            connect_reply_failures: stats.connect_failures(ConnectFailure::Reply),
+            connect_network_failures: stats.connect_failures(ConnectFailure::Network),
+            connect_other_failures: stats.connect_failures(ConnectFailure::Other),
+            tunneled_gre: stats.tunneled(TunnelProtocol::Gre),
+            tunneled_ipsec: stats.tunneled(TunnelProtocol::Ipsec),
+            tunneled_6in4: stats.tunneled(TunnelProtocol::Ipv6),
+            tunneled_forwards: stats.tunneled_forwards(),
+            discovery_lldp: stats.discovery(DiscoveryProtocol::Lldp),
+            discovery_cdp: stats.discovery(DiscoveryProtocol::Cdp),
+            discovery_stp: stats.discovery(DiscoveryProtocol::Stp),
+            arp_conflicts: stats.arp_conflicts(),
+            quarantines: stats.quarantines(),
+            quarantine_drops: stats.quarantine_drops(),
+            sni_blocks: stats.sni_blocks(),
+            sni_bypasses: stats.sni_bypasses(),
+            spoof_drops: stats.spoof_drops(),
+            frag_overlaps: stats.frag_overlaps(),
+            frag_drops: stats.frag_drops(),
+            mtu_probes: stats.mtu_probes(),
+            mtu_black_holes: stats.mtu_black_holes(),
+            qos_drops: stats.qos_drops(),
+            tx_drops: stats.tx_drops(),
+            tcp_out_of_order_bytes: stats.tcp_out_of_order_bytes(),
+            tcp_out_of_order_drops: stats.tcp_out_of_order_drops(),
+            tcp_retrans_segments: stats.tcp_retrans_segments(),
+            tcp_retrans_bytes: stats.tcp_retrans_bytes(),
+            tcp_fast_retransmits: stats.tcp_fast_retransmits(),
+            tcp_rto_retransmits: stats.tcp_rto_retransmits(),
+            tcp_duplicate_acks: stats.tcp_duplicate_acks(),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl Control for ControlService {
+    async fn list_connections(
+        &self,
+        _: Request<proto::ListConnectionsRequest>,
+    ) -> Result<Response<proto::ListConnectionsResponse>, Status> {
+        let connections = self
+            .controller
+            .connections()
+            .await
+            .map_err(to_status)?
+            .into_iter()
+            .map(|connection| proto::Connection {
+                src: connection.src.to_string(),
+                dst: connection.dst.to_string(),
+                idle_ms: connection.idle.as_millis() as u64,
+                written: connection.written,
+                write_queue_size: connection.write_queue_size as u64,
+                out_of_order: connection.out_of_order as u64,
+                holes: connection.holes as u64,
+                out_of_order_drops: connection.out_of_order_drops,
+                retrans_segments: connection.retrans_segments,
+                retrans_bytes: connection.retrans_bytes,
+                fast_retransmits: connection.fast_retransmits,
+                rto_retransmits: connection.rto_retransmits,
+                duplicate_acks: connection.duplicate_acks,
+                client_rtt_us: connection
+                    .client_rtt
+                    .map(|latency| latency.as_micros() as u64)
+                    .unwrap_or(0),
+                connect_latency_us: connection
+                    .connect_latency
+                    .map(|latency| latency.as_micros() as u64)
+                    .unwrap_or(0),
+                write_latency_us: connection
+                    .write_latency
+                    .map(|latency| latency.as_micros() as u64)
+                    .unwrap_or(0),
+            })
+            .collect();
+
+        Ok(Response::new(proto::ListConnectionsResponse {
+            connections,
+        }))
+    }
+
+    async fn kill_connection(
+        &self,
+        request: Request<proto::KillConnectionRequest>,
+    ) -> Result<Response<proto::KillConnectionResponse>, Status> {
+        let request = request.into_inner();
+        let src = parse_addr("source", &request.src)?;
+        let dst = parse_addr("destination", &request.dst)?;
+
+        let killed = self
+            .controller
+            .close(src, dst, request.graceful)
+            .await
+            .map_err(to_status)?;
+
+        Ok(Response::new(proto::KillConnectionResponse { killed }))
+    }
+
+    type StreamStatsStream = mpsc::Receiver<Result<proto::Stats, Status>>;
+
+    async fn stream_stats(
+        &self,
+        request: Request<proto::StreamStatsRequest>,
+    ) -> Result<Response<Self::StreamStatsStream>, Status> {
+        let interval = match request.into_inner().interval_ms {
+            0 => STATS_INTERVAL,
+            interval => interval,
+        };
+
+        let service = ControlService::new(self.controller.clone());
+        let (mut tx, rx) = mpsc::channel(1);
+        tokio::spawn(async move {
+            let mut interval = time::interval(Duration::from_millis(interval));
+            loop {
+                interval.tick().await;
+                // Stop once the client is gone
+                if tx.send(Ok(service.stats())).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(Response::new(rx))
+    }
+
+    async fn set_proxy(
+        &self,
+        request: Request<proto::SetProxyRequest>,
+    ) -> Result<Response<proto::SetProxyResponse>, Status> {
+        let remote = parse_addr("proxy", &request.into_inner().proxy)?;
+
+        self.controller.set_proxy(remote).map_err(to_status)?;
+
+        Ok(Response::new(proto::SetProxyResponse {}))
+    }
+}
+
+/// Serves the controller over gRPC on the address until an error occurs.
+pub async fn serve(controller: Controller, addr: SocketAddr) -> io::Result<()> {
+    info!("Control on {}", addr);
+
+    Server::builder()
+        .add_service(ControlServer::new(ControlService::new(controller)))
+        .serve(addr)
+        .await
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))
+}
+
+fn parse_addr(name: &str, addr: &str) -> Result<SocketAddrV4, Status> {
+    addr.parse()
+        .map_err(|e| Status::invalid_argument(format!("{} {}: {}", name, addr, e)))
+}
+
+fn to_status(e: io::Error) -> Status {
+    Status::unavailable(e.to_string())
+}
@@ -0,0 +1,287 @@
+//! Support for controlling redirectors while they are running.
+
+use std::fmt::{self, Display, Formatter};
+use std::net::SocketAddrV4;
+use std::sync::Arc;
+#[cfg(feature = "metrics")]
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::io;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::policy::PolicyHit;
+#[cfg(feature = "metrics")]
+use crate::throughput::{self, TopTalkers, Tracker, Window};
+use crate::Stats;
+
+#[cfg(feature = "grpc")]
+pub mod grpc;
+
+/// Represents a TCP connection redirected by a `Redirector`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Connection {
+    /// Represents the source of the connection.
+    pub src: SocketAddrV4,
+    /// Represents the destination of the connection.
+    pub dst: SocketAddrV4,
+    /// Represents the duration since the connection is last active.
+    pub idle: Duration,
+    /// Represents the bytes written to the proxy.
+    pub written: u64,
+    /// Represents the bytes waiting to be written to the proxy.
+    pub write_queue_size: usize,
+    /// Represents the bytes received out of order and waiting for holes to be filled.
+    pub out_of_order: usize,
+    /// Represents the count of holes before the bytes received out of order.
+    pub holes: usize,
+    /// Represents the bytes received out of order but dropped because of the limit.
+    pub out_of_order_drops: u64,
+    /// Represents the count of segments retransmitted to the source.
+    pub retrans_segments: u64,
+    /// Represents the bytes retransmitted to the source.
+    pub retrans_bytes: u64,
+    /// Represents the count of fast retransmissions triggered by duplicate ACKs.
+    pub fast_retransmits: u64,
+    /// Represents the count of retransmissions triggered by timeouts.
+    pub rto_retransmits: u64,
+    /// Represents the count of duplicate ACKs received from the source.
+    pub duplicate_acks: u64,
+    /// Represents the smoothed RTT between the source and pcap2socks, or `None` if it is not
+    /// measured yet.
+    pub client_rtt: Option<Duration>,
+    /// Represents the time of connecting to the destination through the proxy, or `None` if the
+    /// connection is not redirected to a proxy.
+    pub connect_latency: Option<Duration>,
+    /// Represents the smoothed time of writing to the proxy, or `None` if nothing is written.
+    pub write_latency: Option<Duration>,
+}
+
+impl Display for Connection {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} -> {} (idle {} ms, {} Bytes written, {} Bytes queued, {} Bytes out of order in {} holes, {} Bytes out of order dropped, {} segments ({} Bytes) retransmitted ({} fast, {} timed out), {} duplicate ACKs, client RTT {}, proxy connect {}, proxy write {})",
+            self.src,
+            self.dst,
+            self.idle.as_millis(),
+            self.written,
+            self.write_queue_size,
+            self.out_of_order,
+            self.holes,
+            self.out_of_order_drops,
+            self.retrans_segments,
+            self.retrans_bytes,
+            self.fast_retransmits,
+            self.rto_retransmits,
+            self.duplicate_acks,
+            Latency(self.client_rtt),
+            Latency(self.connect_latency),
+            Latency(self.write_latency)
+        )
+    }
+}
+
+/// Represents a latency displayed in milliseconds, or "-" if it is not measured.
+struct Latency(Option<Duration>);
+
+impl Display for Latency {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self.0 {
+            Some(latency) => write!(f, "{:.1} ms", latency.as_secs_f64() * 1000.0),
+            None => write!(f, "-"),
+        }
+    }
+}
+
+/// Represents a command sent to a `Redirector`, which is executed in its loop.
+pub(crate) enum Command {
+    /// Represents listing the TCP connections.
+    ListConnections(oneshot::Sender<Vec<Connection>>),
+    /// Represents killing the TCP connection from the source to the destination, gracefully or
+    /// not.
+    KillConnection(SocketAddrV4, SocketAddrV4, bool, oneshot::Sender<bool>),
+    /// Represents changing the SOCKS proxy of new connections.
+    SetProxy(SocketAddrV4),
+    /// Represents changing the unscaled receive window and the max window scale of new
+    /// connections.
+    SetRecvWindow(u16, u8),
+    /// Represents getting the trace of the TCP connection from the source to the destination.
+    Trace(SocketAddrV4, SocketAddrV4, oneshot::Sender<Option<String>>),
+    /// Represents getting the hits of each rule of the port profiles.
+    PolicyHits(oneshot::Sender<Vec<PolicyHit>>),
+}
+
+/// Represents a handle controlling one or more `Redirector`s. Commands are executed in the loops
+/// of the `Redirector`s, so they may be delayed until a frame is received or the loop sweeps.
+#[derive(Clone)]
+pub struct Controller {
+    txs: Vec<mpsc::UnboundedSender<Command>>,
+    stats: Vec<Arc<Stats>>,
+    #[cfg(feature = "metrics")]
+    trackers: Vec<Arc<Mutex<Tracker>>>,
+}
+
+impl Controller {
+    pub(crate) fn new(
+        txs: Vec<mpsc::UnboundedSender<Command>>,
+        stats: Vec<Arc<Stats>>,
+    ) -> Controller {
+        Controller {
+            txs,
+            stats,
+            #[cfg(feature = "metrics")]
+            trackers: Vec::new(),
+        }
+    }
+
+    /// Sets the trackers of the throughput of the `Redirector`s.
+    #[cfg(feature = "metrics")]
+    pub(crate) fn with_trackers(mut self, trackers: Vec<Arc<Mutex<Tracker>>>) -> Controller {
+        self.trackers = trackers;
+        self
+    }
+
+    /// Returns the TCP connections of all the `Redirector`s.
+    pub async fn connections(&self) -> io::Result<Vec<Connection>> {
+        let mut connections = Vec::new();
+        for tx in &self.txs {
+            let (reply, rx) = oneshot::channel();
+            send(tx, Command::ListConnections(reply))?;
+            connections.extend(rx.await.map_err(|_| closed())?);
+        }
+
+        Ok(connections)
+    }
+
+    /// Kills the TCP connection from the source to the destination by sending a RST to the
+    /// source. Returns `false` if the connection does not exist.
+    pub async fn kill(&self, src: SocketAddrV4, dst: SocketAddrV4) -> io::Result<bool> {
+        self.close(src, dst, false).await
+    }
+
+    /// Closes the TCP connection from the source to the destination, and tears down its SOCKS
+    /// worker. A graceful close sends the queued data followed by a FIN to the source, otherwise
+    /// a RST is sent. Returns `false` if the connection does not exist.
+    pub async fn close(
+        &self,
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+        graceful: bool,
+    ) -> io::Result<bool> {
+        let mut is_killed = false;
+        for tx in &self.txs {
+            let (reply, rx) = oneshot::channel();
+            send(tx, Command::KillConnection(src, dst, graceful, reply))?;
+            is_killed |= rx.await.map_err(|_| closed())?;
+        }
+
+        Ok(is_killed)
+    }
+
+    /// Sets the SOCKS proxy of new connections. Existing connections and UDP ports are kept on
+    /// the previous proxy until they are closed.
+    pub fn set_proxy(&self, remote: SocketAddrV4) -> io::Result<()> {
+        for tx in &self.txs {
+            send(tx, Command::SetProxy(remote))?;
+        }
+
+        Ok(())
+    }
+
+    /// Sets the unscaled receive window and the max window scale of new TCP connections, like
+    /// shrinking the window on memory-constrained routers or enlarging it for proxy links with a
+    /// high bandwidth-delay product. Existing connections keep their receive windows.
+    pub fn set_recv_window(&self, window: u16, wscale: u8) -> io::Result<()> {
+        for tx in &self.txs {
+            send(tx, Command::SetRecvWindow(window, wscale))?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the trace of the TCP connection from the source to the destination, or `None` if
+    /// the connection does not exist or TCP tracing is disabled.
+    pub async fn trace(&self, src: SocketAddrV4, dst: SocketAddrV4) -> io::Result<Option<String>> {
+        for tx in &self.txs {
+            let (reply, rx) = oneshot::channel();
+            send(tx, Command::Trace(src, dst, reply))?;
+            if let Some(trace) = rx.await.map_err(|_| closed())? {
+                return Ok(Some(trace));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Returns the hits of each rule of the port profiles, which adds up the hits of all the
+    /// `Redirector`s.
+    pub async fn policy_hits(&self) -> io::Result<Vec<PolicyHit>> {
+        let mut hits: Vec<PolicyHit> = Vec::new();
+        for tx in &self.txs {
+            let (reply, rx) = oneshot::channel();
+            send(tx, Command::PolicyHits(reply))?;
+            let worker_hits = rx.await.map_err(|_| closed())?;
+            if hits.is_empty() {
+                hits = worker_hits;
+            } else {
+                for (hit, worker_hit) in hits.iter_mut().zip(worker_hits) {
+                    hit.hits += worker_hit.hits;
+                }
+            }
+        }
+
+        Ok(hits)
+    }
+
+    /// Returns the statistics which adds up the statistics of all the `Redirector`s.
+    pub fn stats(&self) -> Stats {
+        let stats = Stats::new();
+        for worker_stats in &self.stats {
+            stats.accumulate(worker_stats);
+        }
+
+        stats
+    }
+
+    /// Returns at most `n` sources and TCP connections with the most throughput in the window
+    /// of all the `Redirector`s. The throughput is tracked since the controller is created.
+    #[cfg(feature = "metrics")]
+    pub fn top_n(&self, n: usize, window: Window) -> TopTalkers {
+        throughput::top_n(&self.trackers, n, window)
+    }
+}
+
+fn send(tx: &mpsc::UnboundedSender<Command>, command: Command) -> io::Result<()> {
+    tx.send(command).map_err(|_| closed())
+}
+
+fn closed() -> io::Error {
+    io::Error::new(io::ErrorKind::NotConnected, "redirector is closed")
+}
+
+#[tokio::test]
+async fn controller_kill() {
+    let src = "10.6.0.2:1024".parse().unwrap();
+    let dst = "1.1.1.1:80".parse().unwrap();
+
+    // Each worker kills the connection only if it has the connection
+    let mut txs = Vec::new();
+    for i in 0..2 {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Some(command) = rx.recv().await {
+                if let Command::KillConnection(_, _, _, reply) = command {
+                    let _ = reply.send(i == 1);
+                }
+            }
+        });
+        txs.push(tx);
+    }
+    let controller = Controller::new(txs, Vec::new());
+    assert!(controller.kill(src, dst).await.unwrap());
+
+    // Closed workers
+    let (tx, _) = mpsc::unbounded_channel();
+    let controller = Controller::new(vec![tx], Vec::new());
+    assert!(controller.kill(src, dst).await.is_err());
+}
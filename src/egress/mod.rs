@@ -0,0 +1,80 @@
+//! Support for pluggable egress backends, which carry the traffic of sources out instead of the
+//! built-in proxies, like test doubles or protocols not supported by the crate.
+//!
+//! A `Redirector` drives an `Egress` with the same workers as the built-in proxies. Data from
+//! sources is written to the streams and sockets of the `Egress`, and data read from them is
+//! forwarded back to sources through `ForwardStream` and `ForwardDatagram`.
+
+use std::fmt::{self, Debug, Formatter};
+use std::future::Future;
+use std::net::SocketAddrV4;
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::io::{self, AsyncRead, AsyncWrite};
+
+pub use crate::socks::{ForwardDatagram, ForwardFuture, ForwardStream};
+
+/// Represents a future returned by an `Egress`.
+pub type EgressFuture<'a, T> = Pin<Box<dyn Future<Output = io::Result<T>> + Send + 'a>>;
+
+/// Represents the read half of a stream connected by an `Egress`.
+pub type EgressReadHalf = Box<dyn AsyncRead + Send + Unpin>;
+
+/// Represents the write half of a stream connected by an `Egress`. The write half is shut down
+/// once the source closes the connection for writing.
+pub type EgressWriteHalf = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// Trait for the receive half of a datagram socket bound by an `Egress`.
+pub trait EgressRecvHalf: Send {
+    /// Receives a datagram, and returns its size and the peer which sent it.
+    fn recv_from<'a>(&'a mut self, buffer: &'a mut [u8])
+        -> EgressFuture<'a, (usize, SocketAddrV4)>;
+}
+
+/// Trait for the send half of a datagram socket bound by an `Egress`.
+pub trait EgressSendHalf: Send {
+    /// Sends a datagram to the destination, and returns the size sent.
+    fn send_to<'a>(&'a mut self, payload: &'a [u8], dst: SocketAddrV4) -> EgressFuture<'a, usize>;
+}
+
+/// Represents the halves of a datagram socket bound by an `Egress`, and its local port.
+pub type EgressBinding = (Box<dyn EgressRecvHalf>, Box<dyn EgressSendHalf>, u16);
+
+/// Trait for a factory of connections carrying the traffic of sources out. An `Egress` is shared
+/// by all the workers, so it may be called concurrently.
+pub trait Egress: Send + Sync {
+    /// Connects to the destination of a TCP connection of the source.
+    fn connect(
+        &self,
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+    ) -> EgressFuture<'_, (EgressReadHalf, EgressWriteHalf)>;
+
+    /// Binds a datagram socket for the UDP traffic of the source to any destination. Returns the
+    /// halves of the socket and its local port, which identifies the binding and must be unique
+    /// among the bindings not dropped yet. The local port is preferably the given one if it is not
+    /// 0, like the port bound before a restart.
+    fn bind(&self, src: SocketAddrV4, local_port: u16) -> EgressFuture<'_, EgressBinding>;
+}
+
+/// Represents an `Egress` shared by workers.
+#[derive(Clone)]
+pub(crate) struct SharedEgress(Arc<dyn Egress>);
+
+impl SharedEgress {
+    /// Creates a new `SharedEgress`.
+    pub(crate) fn new(egress: Arc<dyn Egress>) -> SharedEgress {
+        SharedEgress(egress)
+    }
+
+    /// Returns the `Egress`.
+    pub(crate) fn get(&self) -> &dyn Egress {
+        &*self.0
+    }
+}
+
+impl Debug for SharedEgress {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str("Egress")
+    }
+}
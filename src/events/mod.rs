@@ -0,0 +1,328 @@
+//! Support for publishing status updates of the redirector, like for GUI frontends.
+
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio::sync::broadcast;
+use tokio::time;
+
+use crate::pcap::HardwareAddr;
+use crate::socks::ConnectFailure;
+use crate::Stats;
+
+/// Represents a status update of a `Redirector`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Event {
+    /// Represents the throughput in bytes per second of frames received from and sent to sources.
+    Throughput { rx: u64, tx: u64 },
+    /// Represents the sources which have joined the network, sent when a source joins.
+    Clients(Vec<Client>),
+    /// Represents the health of the SOCKS proxy, sent periodically.
+    ProxyHealth(ProxyHealth),
+    /// Represents a failure connecting to a target server through a proxy, sent when a TCP
+    /// connection fails to connect. The connection is retried after a backoff if `retry` is set,
+    /// or reset otherwise.
+    ConnectFailure {
+        proxy: SocketAddrV4,
+        dst: SocketAddrV4,
+        failure: ConnectFailure,
+        retry: bool,
+    },
+    /// Represents the gateway IP address is owned by another device, sent when the conflict is
+    /// detected. ARP requests for the address are no longer replied until the owner leaves.
+    ArpConflict {
+        ip_addr: Ipv4Addr,
+        hardware_addr: HardwareAddr,
+    },
+    /// Represents a TCP connection is reaped because it has been idle for the idle timeout,
+    /// sent when the connection is reset.
+    ConnectionReaped {
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+        idle: Duration,
+    },
+    /// Represents a source is quarantined because its error rate exceeds the threshold, sent
+    /// when the source is quarantined. Packets of the source are dropped until the quarantine
+    /// expires after the duration.
+    Quarantined {
+        ip_addr: Ipv4Addr,
+        duration: Duration,
+    },
+}
+
+/// Represents a lifecycle event of a flow of a `Redirector`. Every TCP connection sends a
+/// `SynReceived` first and a `Closed` last, and every UDP port sends a `UdpBound` first and a
+/// `UdpExpired` last.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FlowEvent {
+    /// Represents a SYN of a TCP connection is admitted, sent before connecting to the
+    /// destination.
+    SynReceived {
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+    },
+    /// Represents the handshake of a TCP connection is completed by the source.
+    Established {
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+    },
+    /// Represents a TCP connection is closed for writing by one side, sent when the FIN of the
+    /// source is admitted if `by_source` is set, or when the destination closes otherwise.
+    HalfClosed {
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+        by_source: bool,
+    },
+    /// Represents a TCP connection is closed and its state is removed.
+    Closed {
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+        reason: CloseReason,
+    },
+    /// Represents a local UDP port is bound to the source, sent when the source sends its first
+    /// datagram, or when the port is reused by or migrated to the source.
+    UdpBound { src: SocketAddrV4, port: u16 },
+    /// Represents a local UDP port of the source is unbound, sent when the port expires after
+    /// being idle, or when the port is reused by or migrated to another source.
+    UdpExpired { src: SocketAddrV4, port: u16 },
+}
+
+/// Represents the reason a TCP connection is closed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CloseReason {
+    /// Represents the connection is closed by FINs of both sides.
+    Fin,
+    /// Represents the connection is reset by the source, or replaced by a new connection of the
+    /// source.
+    Reset,
+    /// Represents the connection is refused before being established, like failing to connect
+    /// to the destination or being blocked by the SNI filter.
+    Refused,
+    /// Represents the handshake or the FIN of the source is not completed in time.
+    Timeout,
+    /// Represents the connection is reaped after being idle for the idle timeout.
+    Idle,
+    /// Represents the connection is evicted because the connection limit is reached.
+    Evicted,
+    /// Represents the connection is killed by a command.
+    Killed,
+    /// Represents the connection is reset because writing to the destination fails.
+    Error,
+}
+
+/// Represents a source which has joined the network.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Client {
+    /// Represents the IP address of the source.
+    pub ip_addr: Ipv4Addr,
+    /// Represents the hardware address of the source.
+    pub hardware_addr: HardwareAddr,
+}
+
+/// Represents the health of a SOCKS proxy.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ProxyHealth {
+    /// Represents the address of the proxy.
+    pub proxy: SocketAddrV4,
+    /// Represents the time of connecting to the proxy, or `None` if the proxy is unreachable.
+    pub latency: Option<Duration>,
+}
+
+/// Represents the capacity of the queue of events of each subscriber. A subscriber lagging
+/// behind misses the oldest events.
+const EVENT_QUEUE_SIZE: usize = 64;
+
+/// Represents the capacity of the queue of flow events of each subscriber. A subscriber lagging
+/// behind misses the oldest events.
+const FLOW_EVENT_QUEUE_SIZE: usize = 1024;
+
+/// Represents the interval in milliseconds of publishing the throughput.
+const THROUGHPUT_INTERVAL: u64 = 1000;
+
+/// Represents the interval in milliseconds of checking the health of the proxy.
+const HEALTH_CHECK_INTERVAL: u64 = 10000;
+
+/// Represents the timeout in milliseconds of connecting to the proxy in a health check.
+const HEALTH_CHECK_TIMEOUT: u64 = 3000;
+
+/// Represents a publisher of events of a `Redirector`.
+pub(crate) struct Publisher {
+    tx: broadcast::Sender<Event>,
+    clients: HashMap<Ipv4Addr, HardwareAddr>,
+    throughput_instant: Instant,
+    rx_bytes: u64,
+    tx_bytes: u64,
+    health_check_instant: Option<Instant>,
+}
+
+impl Publisher {
+    pub(crate) fn new() -> Publisher {
+        let (tx, _) = broadcast::channel(EVENT_QUEUE_SIZE);
+
+        Publisher {
+            tx,
+            clients: HashMap::new(),
+            throughput_instant: Instant::now(),
+            rx_bytes: 0,
+            tx_bytes: 0,
+            health_check_instant: None,
+        }
+    }
+
+    /// Returns a new receiver of events.
+    pub(crate) fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.tx.subscribe()
+    }
+
+    /// Tracks the source, and publishes the sources if it has not joined before.
+    pub(crate) fn track_client(&mut self, ip_addr: Ipv4Addr, hardware_addr: HardwareAddr) {
+        if self.clients.get(&ip_addr) == Some(&hardware_addr) {
+            return;
+        }
+        self.clients.insert(ip_addr, hardware_addr);
+
+        let mut clients: Vec<_> = self
+            .clients
+            .iter()
+            .map(|(&ip_addr, &hardware_addr)| Client {
+                ip_addr,
+                hardware_addr,
+            })
+            .collect();
+        clients.sort_by_key(|client| client.ip_addr);
+        self.send(Event::Clients(clients));
+    }
+
+    /// Publishes a failure connecting to a target server through a proxy.
+    pub(crate) fn connect_failure(
+        &self,
+        proxy: SocketAddrV4,
+        dst: SocketAddrV4,
+        failure: ConnectFailure,
+        retry: bool,
+    ) {
+        self.send(Event::ConnectFailure {
+            proxy,
+            dst,
+            failure,
+            retry,
+        });
+    }
+
+    /// Publishes a conflict of the gateway IP address.
+    pub(crate) fn arp_conflict(&self, ip_addr: Ipv4Addr, hardware_addr: HardwareAddr) {
+        self.send(Event::ArpConflict {
+            ip_addr,
+            hardware_addr,
+        });
+    }
+
+    /// Publishes a TCP connection reaped after being idle.
+    pub(crate) fn connection_reaped(&self, src: SocketAddrV4, dst: SocketAddrV4, idle: Duration) {
+        self.send(Event::ConnectionReaped { src, dst, idle });
+    }
+
+    /// Publishes a source quarantined.
+    pub(crate) fn quarantined(&self, ip_addr: Ipv4Addr, duration: Duration) {
+        self.send(Event::Quarantined { ip_addr, duration });
+    }
+
+    /// Publishes the throughput and checks the health of the proxy if they are due.
+    pub(crate) fn publish(&mut self, stats: &Stats, proxy: SocketAddrV4) {
+        let elapsed = self.throughput_instant.elapsed();
+        if elapsed >= Duration::from_millis(THROUGHPUT_INTERVAL) {
+            let (rx_bytes, tx_bytes) = (stats.rx_bytes(), stats.tx_bytes());
+            let millis = elapsed.as_millis() as u64;
+            self.send(Event::Throughput {
+                rx: (rx_bytes - self.rx_bytes) * 1000 / millis,
+                tx: (tx_bytes - self.tx_bytes) * 1000 / millis,
+            });
+            self.rx_bytes = rx_bytes;
+            self.tx_bytes = tx_bytes;
+            self.throughput_instant = Instant::now();
+        }
+
+        let is_health_check_due = match self.health_check_instant {
+            Some(instant) => instant.elapsed() >= Duration::from_millis(HEALTH_CHECK_INTERVAL),
+            None => true,
+        };
+        if is_health_check_due {
+            self.health_check_instant = Some(Instant::now());
+            tokio::spawn(check_proxy_health(proxy, self.tx.clone()));
+        }
+    }
+
+    fn send(&self, event: Event) {
+        // There may be no subscriber
+        let _ = self.tx.send(event);
+    }
+}
+
+/// Represents a publisher of lifecycle events of flows, shared by a `Redirector` and its
+/// `Forwarder`.
+#[derive(Clone)]
+pub(crate) struct FlowPublisher {
+    tx: broadcast::Sender<FlowEvent>,
+}
+
+impl FlowPublisher {
+    pub(crate) fn new() -> FlowPublisher {
+        let (tx, _) = broadcast::channel(FLOW_EVENT_QUEUE_SIZE);
+
+        FlowPublisher { tx }
+    }
+
+    /// Returns a new receiver of flow events.
+    pub(crate) fn subscribe(&self) -> broadcast::Receiver<FlowEvent> {
+        self.tx.subscribe()
+    }
+
+    /// Publishes the flow event.
+    pub(crate) fn send(&self, event: FlowEvent) {
+        // There may be no subscriber
+        let _ = self.tx.send(event);
+    }
+}
+
+/// Checks the health of the proxy by connecting to it, and publishes the result.
+async fn check_proxy_health(proxy: SocketAddrV4, tx: broadcast::Sender<Event>) {
+    let instant = Instant::now();
+    let latency = match time::timeout(
+        Duration::from_millis(HEALTH_CHECK_TIMEOUT),
+        TcpStream::connect(proxy),
+    )
+    .await
+    {
+        Ok(Ok(_)) => Some(instant.elapsed()),
+        _ => None,
+    };
+
+    // There may be no subscriber
+    let _ = tx.send(Event::ProxyHealth(ProxyHealth { proxy, latency }));
+}
+
+#[test]
+fn publisher_track_client() {
+    use pnet::datalink::MacAddr;
+
+    let mut publisher = Publisher::new();
+    let mut rx = publisher.subscribe();
+    let hardware_addr = MacAddr(0x00, 0x11, 0x22, 0x33, 0x44, 0x55);
+
+    publisher.track_client(Ipv4Addr::new(10, 6, 0, 2), hardware_addr);
+    publisher.track_client(Ipv4Addr::new(10, 6, 0, 2), hardware_addr);
+    publisher.track_client(Ipv4Addr::new(10, 6, 0, 1), hardware_addr);
+    match rx.try_recv().unwrap() {
+        Event::Clients(clients) => assert_eq!(clients.len(), 1),
+        event => panic!("unexpected event {:?}", event),
+    }
+    match rx.try_recv().unwrap() {
+        Event::Clients(clients) => {
+            assert_eq!(clients.len(), 2);
+            assert_eq!(clients[0].ip_addr, Ipv4Addr::new(10, 6, 0, 1));
+        }
+        event => panic!("unexpected event {:?}", event),
+    }
+    assert!(rx.try_recv().is_err());
+}
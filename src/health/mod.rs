@@ -0,0 +1,220 @@
+//! Support for a health check endpoint over HTTP, so supervisors can detect a wedged capture loop
+//! which the process itself does not notice.
+//!
+//! Every request is answered with a JSON object of the health, in status `200 OK` if healthy or
+//! `503 Service Unavailable` otherwise.
+
+use log::{info, trace, warn};
+use std::fmt::{self, Display, Formatter};
+use std::net::{SocketAddr, SocketAddrV4};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time;
+
+use crate::control::Controller;
+use crate::pcap::Interface;
+
+/// Represents the max time in milliseconds since the last iteration of the capture loop before
+/// it is considered wedged.
+const STALL_TIMEOUT: u64 = 10000;
+
+/// Represents the timeout in milliseconds of connecting to the proxy in a health check.
+const PROXY_TIMEOUT: u64 = 3000;
+
+/// Represents the timeout in milliseconds of reading a request.
+const REQUEST_TIMEOUT: u64 = 5000;
+
+/// Represents the max size of a request.
+const MAX_REQUEST_SIZE: usize = 8192;
+
+/// Represents the health of pcap2socks.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Health {
+    /// Represents the time of the last iteration of the capture loop.
+    pub last_loop: Option<SystemTime>,
+    /// Represents the time of the last frame captured.
+    pub last_frame: Option<SystemTime>,
+    /// Represents the address of the proxy.
+    pub proxy: SocketAddrV4,
+    /// Represents the time of connecting to the proxy, or `None` if the proxy is unreachable.
+    pub proxy_latency: Option<Duration>,
+    /// Represents the count of frames dropped by the kernel, or `None` if it is not available.
+    pub kernel_drops: Option<u64>,
+}
+
+impl Health {
+    /// Returns if the capture loop is alive, which iterates at least once a second even if there
+    /// is no traffic.
+    pub fn is_alive(&self) -> bool {
+        match self
+            .last_loop
+            .and_then(|last_loop| last_loop.elapsed().ok())
+        {
+            Some(elapsed) => elapsed < Duration::from_millis(STALL_TIMEOUT),
+            None => false,
+        }
+    }
+
+    /// Returns if the capture loop is alive and the proxy is reachable.
+    pub fn is_healthy(&self) -> bool {
+        self.is_alive() && self.proxy_latency.is_some()
+    }
+}
+
+impl Display for Health {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{{\"status\":\"{}\",\"alive\":{},\"last_loop_ms\":{},\"last_frame_ms\":{},\"proxy\":\"{}\",\"proxy_reachable\":{},\"proxy_latency_ms\":{},\"kernel_drops\":{}}}",
+            if self.is_healthy() { "ok" } else { "unhealthy" },
+            self.is_alive(),
+            Json(self.last_loop.map(unix_millis)),
+            Json(self.last_frame.map(unix_millis)),
+            self.proxy,
+            self.proxy_latency.is_some(),
+            Json(self.proxy_latency.map(|latency| latency.as_millis() as u64)),
+            Json(self.kernel_drops)
+        )
+    }
+}
+
+/// Represents an optional number in JSON, where `None` is `null`.
+struct Json(Option<u64>);
+
+impl Display for Json {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self.0 {
+            Some(n) => write!(f, "{}", n),
+            None => write!(f, "null"),
+        }
+    }
+}
+
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_millis() as u64)
+}
+
+/// Checks the health of the capture loop of the controller, the proxy and the interface.
+pub async fn check(controller: &Controller, proxy: SocketAddrV4, inter: &Interface) -> Health {
+    let stats = controller.stats();
+
+    let instant = Instant::now();
+    let proxy_latency = match time::timeout(
+        Duration::from_millis(PROXY_TIMEOUT),
+        TcpStream::connect(proxy),
+    )
+    .await
+    {
+        Ok(Ok(_)) => Some(instant.elapsed()),
+        _ => None,
+    };
+
+    Health {
+        last_loop: stats.last_loop(),
+        last_frame: stats.last_frame(),
+        proxy,
+        proxy_latency,
+        kernel_drops: inter.kernel_drops(),
+    }
+}
+
+/// Serves the health check endpoint on the address. Requests of any path are answered with the
+/// health of the capture loop of the controller, the proxy and the interface.
+pub async fn serve(
+    controller: Controller,
+    addr: SocketAddr,
+    proxy: SocketAddrV4,
+    inter: Interface,
+) -> io::Result<()> {
+    let mut listener = TcpListener::bind(addr).await?;
+    info!("Health check on {}", addr);
+
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        trace!("health check from {}", peer);
+
+        let controller = controller.clone();
+        let inter = inter.clone();
+        tokio::spawn(async move {
+            if let Err(ref e) = handle(stream, &controller, proxy, &inter).await {
+                warn!("handle {}: {}", "health check", e);
+            }
+        });
+    }
+}
+
+async fn handle(
+    mut stream: TcpStream,
+    controller: &Controller,
+    proxy: SocketAddrV4,
+    inter: &Interface,
+) -> io::Result<()> {
+    // Read the request head, the body is ignored
+    let read = async {
+        let mut request = Vec::new();
+        let mut buffer = [0u8; 1024];
+        while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+            let n = stream.read(&mut buffer).await?;
+            if n == 0 {
+                break;
+            }
+            request.extend_from_slice(&buffer[..n]);
+            if request.len() > MAX_REQUEST_SIZE {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "request too large",
+                ));
+            }
+        }
+
+        Ok(())
+    };
+    time::timeout(Duration::from_millis(REQUEST_TIMEOUT), read)
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+
+    let health = check(controller, proxy, inter).await;
+    let status = if health.is_healthy() {
+        "200 OK"
+    } else {
+        "503 Service Unavailable"
+    };
+    let body = health.to_string();
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+
+    Ok(())
+}
+
+#[test]
+fn health_is_healthy() {
+    let mut health = Health {
+        last_loop: Some(SystemTime::now()),
+        last_frame: None,
+        proxy: "127.0.0.1:1080".parse().unwrap(),
+        proxy_latency: Some(Duration::from_millis(15)),
+        kernel_drops: Some(3),
+    };
+    assert!(health.is_healthy());
+    let s = health.to_string();
+    assert!(s.starts_with("{\"status\":\"ok\",\"alive\":true,"));
+    assert!(s.contains("\"last_frame_ms\":null,"));
+    assert!(s.ends_with("\"proxy_latency_ms\":15,\"kernel_drops\":3}"));
+
+    // Wedged capture loop
+    health.last_loop = Some(SystemTime::now() - Duration::from_millis(STALL_TIMEOUT));
+    assert!(!health.is_alive());
+    assert!(!health.is_healthy());
+
+    health.last_loop = Some(SystemTime::now());
+    health.proxy_latency = None;
+    assert!(health.is_alive());
+    assert!(!health.is_healthy());
+}
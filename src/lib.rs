@@ -3,35 +3,141 @@
 use ipnetwork::Ipv4Network;
 use log::{debug, info, trace, warn};
 use lru::LruCache;
-use rand::{self, Rng};
+use std::borrow::Cow;
 use std::cmp::{max, min};
+use std::collections::hash_map::{DefaultHasher, RandomState};
 use std::collections::{HashMap, VecDeque};
 use std::fmt::{self, Display};
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::mem;
 use std::net::{Ipv4Addr, Shutdown, SocketAddrV4};
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::{Duration, Instant};
 use tokio::io;
-
+use tokio::sync::{broadcast, mpsc, Notify};
+use tokio::task;
+use tokio::time;
+
+pub mod admin;
+mod arp;
+pub mod audit;
+pub mod balance;
+mod binding;
 pub mod cache;
+mod capture;
+pub mod clock;
+pub mod config;
+pub mod control;
+pub mod egress;
+pub mod events;
+pub mod health;
+pub mod middleware;
+pub mod mtu;
+#[cfg(feature = "otlp")]
+pub mod otlp;
 pub mod packet;
+mod passthrough;
 pub mod pcap;
+pub mod policy;
+#[cfg(unix)]
+pub mod privilege;
+#[cfg(feature = "python")]
+pub mod python;
+pub mod qos;
+mod quarantine;
+mod ratelimit;
+mod scheduler;
+pub mod seq;
+pub mod session;
+mod snapshot;
+mod sni;
 pub mod socks;
+pub mod source;
+pub mod stats;
+#[cfg(all(unix, feature = "systemd"))]
+pub mod systemd;
+mod tcptrace;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+#[cfg(feature = "metrics")]
+pub mod throughput;
+pub mod timer;
+#[cfg(feature = "wireguard")]
+pub mod wireguard;
 
 use self::socks::{
-    DatagramWorker, ForwardDatagram, ForwardStream, SocksAuth, SocksOption, StreamWorker,
+    ConnectFailure, ForwardFuture, ForwardStream, SocksAuth, SocksOption, StreamWorker,
 };
+#[cfg(feature = "udp")]
+use self::socks::{DatagramWorker, ForwardDatagram};
+use arp::{ArpGuard, GuardAction};
+use audit::{AuditSink, Auditor};
+use balance::Balancer;
+use binding::Bindings;
 use cache::{Queue, Window};
+use capture::{Recorder, Trigger};
+use clock::Clock;
+pub use config::{
+    BroadcastMode, Config, EchoMode, IcmpPolicy, MulticastMode, NatMode, Preset, SniAction,
+    TunnelPolicy, UrgentPolicy,
+};
+use control::{Command, Connection, Controller};
+use egress::Egress;
+use events::{CloseReason, Event, FlowEvent, FlowPublisher, Publisher};
+use middleware::Middlewares;
+pub use middleware::{Action, PacketMiddleware};
+use mtu::{MtuCache, MtuProbe, MtuSearch, BASE_MTU};
+use packet::discovery::{Discovery, DiscoveryProtocol};
+use packet::igmp::IgmpMembership;
 use packet::layer::arp::Arp;
-use packet::layer::ethernet::Ethernet;
+use packet::layer::ethernet::{Ethernet, VlanTag};
+#[cfg(feature = "icmp")]
 use packet::layer::icmpv4::Icmpv4;
 use packet::layer::ipv4::Ipv4;
+use packet::layer::pppoe::Pppoe;
 use packet::layer::tcp::Tcp;
+#[cfg(feature = "udp")]
 use packet::layer::udp::Udp;
 use packet::layer::{Layer, LayerKind, LayerKinds, Layers};
-use packet::{Defraggler, Indicator};
+#[cfg(feature = "udp")]
+use packet::netbios::{NameQuery, NBNS_PORT};
+#[cfg(feature = "udp")]
+use packet::quic::{QuicHeader, MAX_CONN_ID_LEN};
+use packet::tunnel::TunnelProtocol;
+use packet::Indicator;
+#[cfg(feature = "defrag")]
+use packet::{DefragError, Defraggler};
+use passthrough::Passthrough;
 use pcap::Interface;
 use pcap::{HardwareAddr, Receiver, Sender};
+use policy::{PortPolicy, PortProtocol};
+use qos::{QosDirection, Shaper};
+use quarantine::Quarantine;
+use ratelimit::RateLimiter;
+use scheduler::{TxPriority, TxScheduler, SMALL_FRAME_SIZE};
+use seq::{seq_add, seq_between, seq_sub};
+use snapshot::{Snapshot, Snapshots};
+use sni::SniFilter;
+pub use socks::{Flow, TcpConnection, UdpSession};
+use source::SourceSet;
+pub use stats::Stats;
+use tcptrace::Tracer;
+#[cfg(feature = "metrics")]
+use throughput::Tracker;
+use timer::TimerWheel;
+#[cfg(feature = "wireguard")]
+use wireguard::{ForwardPacket, Tunnel, WireGuardOption};
+
+/// Represents the builder of hashers of maps looked up for each packet.
+#[cfg(feature = "fast-hash")]
+type PacketHasher = fxhash::FxBuildHasher;
+/// Represents the builder of hashers of maps looked up for each packet.
+#[cfg(not(feature = "fast-hash"))]
+type PacketHasher = RandomState;
+
+/// Represents a map looked up for each packet.
+type PacketMap<K, V> = HashMap<K, V, PacketHasher>;
 
 /// Gets a list of available network interfaces for the current machine.
 pub fn interfaces() -> Vec<Interface> {
@@ -41,12 +147,19 @@ pub fn interfaces() -> Vec<Interface> {
         .collect()
 }
 
-/// Gets an available network interface.
+/// Gets an available network interface. Loopback interfaces are available only if they are
+/// designated. An interface is designated by its name, or by its hardware address, its index,
+/// its alias or a fragment of its name if no name matches exactly.
 pub fn interface(name: Option<String>) -> Option<Interface> {
     let mut inters = match name {
         Some(ref name) => {
-            let mut inters = interfaces();
-            inters.retain(|ref inter| inter.name() == name);
+            let mut inters = pcap::interfaces();
+            inters.retain(|inter| inter.is_up());
+            if inters.iter().any(|inter| inter.name() == name) {
+                inters.retain(|inter| inter.name() == name);
+            } else {
+                inters.retain(|inter| inter.matches(name));
+            }
 
             inters
         }
@@ -60,6 +173,29 @@ pub fn interface(name: Option<String>) -> Option<Interface> {
     }
 }
 
+/// Gets an available network interface, which is picked by heuristics if there are multiple
+/// ones. The interface carrying the default route is preferred, and then the one whose subnet
+/// contains the proxy. Use `interface` to designate an interface by name instead.
+pub fn interface_auto(proxy: Ipv4Addr) -> Option<Interface> {
+    let mut inters = interfaces();
+    if inters.len() <= 1 {
+        return inters.pop();
+    }
+
+    if let Some(name) = pcap::default_route_interface() {
+        if let Some(inter) = inters.iter().find(|inter| inter.name() == &name) {
+            return Some(inter.clone());
+        }
+    }
+
+    inters.retain(|inter| inter.contains(proxy));
+    if inters.len() != 1 {
+        None
+    } else {
+        Some(inters.pop().unwrap())
+    }
+}
+
 /// Represents a timer.
 #[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
 pub struct Timer {
@@ -71,19 +207,24 @@ impl Timer {
     /// Creates a new `Timer`.
     pub fn new(timeout: u64) -> Timer {
         Timer {
-            instant: Instant::now(),
+            instant: clock::now(),
             timeout: Duration::from_millis(timeout),
         }
     }
 
     /// Returns the amount of time elapsed since this timer was created.
     pub fn elapsed(&self) -> Duration {
-        self.instant.elapsed()
+        clock::elapsed(self.instant)
     }
 
     /// Returns if the timer is timed out.
     pub fn is_timedout(&self) -> bool {
-        self.instant.elapsed() > self.timeout
+        clock::elapsed(self.instant) > self.timeout
+    }
+
+    /// Returns the instant when the timer is timed out.
+    pub fn deadline(&self) -> Instant {
+        self.instant + self.timeout
     }
 }
 
@@ -93,6 +234,12 @@ const MAX_U32_WINDOW_SIZE: usize = 16 * 1024 * 1024;
 /// Represents the receive window size.
 const RECV_WINDOW: u16 = u16::MAX;
 
+/// Represents the minimum MTU learned from ICMP messages, which every host must accept as in
+/// [RFC 791](https://tools.ietf.org/html/rfc791), so bogus or forged messages cannot shrink
+/// segments to a few bytes.
+#[cfg(feature = "icmp")]
+const MIN_LEARNED_MTU: usize = 576;
+
 /// Represents if the RTO computation is enabled.
 const ENABLE_RTO_COMPUTE: bool = true;
 /// Represents the initial timeout for a retransmission in a TCP connection.
@@ -101,12 +248,18 @@ const INITIAL_RTO: u64 = 1000;
 const MIN_RTO: u64 = 1000;
 /// Represents the maximum timeout for a retransmission in a TCP connection.
 const MAX_RTO: u64 = 60000;
+/// Represents the default max count of retransmissions of a TCP SYN/ACK due to timeout.
+const SYN_RETRIES: usize = 5;
+
+/// Represents the count of segments a paced TCP connection can send in a burst.
+const PACING_BURST: usize = 4;
 
 /// Represents the TX state of a TCP connection.
 pub struct TcpTxState {
     src: SocketAddrV4,
     dst: SocketAddrV4,
     send_window: usize,
+    send_window_edge: u32,
     send_wscale: Option<u8>,
     sack_perm: bool,
     sequence: u32,
@@ -115,13 +268,36 @@ pub struct TcpTxState {
     sacks: Option<Vec<(u32, u32)>>,
     cache: Queue,
     cache_syn: Option<Instant>,
+    cache_syn_retrans: bool,
+    /// Represents the count of retransmissions of the TCP SYN/ACK due to timeout.
+    cache_syn_retries: usize,
+    max_syn_retries: usize,
     cache_fin: Option<Timer>,
     cache_fin_retrans: bool,
+    /// Represents the time the TCP FIN is acknowledged.
+    fin_acked: Option<Instant>,
     queue: VecDeque<u8>,
     queue_fin: bool,
+    probe: Option<Timer>,
     rto: u64,
     srtt: Option<u64>,
     rttvar: Option<u64>,
+    /// Represents if segments are spread over the SRTT instead of sent in bursts.
+    pacing: bool,
+    /// Represents the bytes allowed to be sent under pacing.
+    pacing_tokens: usize,
+    pacing_instant: Instant,
+    /// Represents the deadline of sending the queue deferred by pacing or traffic shaping.
+    pacing_deadline: Option<Instant>,
+    retrans_segments: u64,
+    retrans_bytes: u64,
+    fast_retransmits: u64,
+    rto_retransmits: u64,
+    /// Represents the count of consecutive retransmissions due to timeout since new data is
+    /// acknowledged.
+    timeouts: usize,
+    /// Represents the total bytes queued to be sent to the source.
+    queued_bytes: u64,
 }
 
 impl TcpTxState {
@@ -136,10 +312,16 @@ impl TcpTxState {
         sack_perm: bool,
         wscale: Option<u8>,
     ) -> TcpTxState {
+        // The window in the SYN is never scaled (RFC 7323)
+        let send_window = send_window as usize;
+        // The window advertised in the SYN starts after the SYN
+        let send_window_edge = seq_add(sequence, 1 + send_window as u32);
+
         TcpTxState {
             src,
             dst,
-            send_window: (send_window as usize) << send_wscale.unwrap_or(0),
+            send_window,
+            send_window_edge,
             send_wscale,
             sack_perm,
             sequence,
@@ -151,33 +333,83 @@ impl TcpTxState {
                 sequence,
             ),
             cache_syn: None,
+            cache_syn_retrans: false,
+            cache_syn_retries: 0,
+            max_syn_retries: SYN_RETRIES,
             cache_fin: None,
-            cache_fin_retrans: true,
+            cache_fin_retrans: false,
+            fin_acked: None,
             queue: VecDeque::new(),
             queue_fin: false,
+            probe: None,
             rto: INITIAL_RTO,
             srtt: None,
             rttvar: None,
+            pacing: false,
+            pacing_tokens: usize::MAX,
+            pacing_instant: clock::now(),
+            pacing_deadline: None,
+            retrans_segments: 0,
+            retrans_bytes: 0,
+            fast_retransmits: 0,
+            rto_retransmits: 0,
+            timeouts: 0,
+            queued_bytes: 0,
         }
     }
 
     /// Sets the window of the TCP connection.
     pub fn set_send_window(&mut self, window: usize) {
+        let acknowledgement = self.cache.sequence();
+
+        self.update_send_window(acknowledgement, window);
+    }
+
+    /// Updates the window of the TCP connection with the window advertised in a segment with the
+    /// given acknowledgement. Windows advertised in stale segments whose acknowledgement is before
+    /// the acknowledged sequence are ignored.
+    pub fn update_send_window(&mut self, acknowledgement: u32, window: usize) {
+        let una = self.cache.sequence();
+        let distance = seq_sub(una, acknowledgement) as usize;
+        if distance > 0 && distance <= MAX_U32_WINDOW_SIZE {
+            trace!(
+                "ignore TCP send window of {} -> {} at {} before {}",
+                self.dst,
+                self.src,
+                acknowledgement,
+                una
+            );
+            return;
+        }
+
+        let edge = seq_add(acknowledgement, window as u32);
+        let shrink = seq_sub(self.send_window_edge, edge) as usize;
+        if shrink > 0 && shrink <= MAX_U32_WINDOW_SIZE {
+            debug!(
+                "TCP send window of {} -> {} shrinks by {} Bytes",
+                self.dst, self.src, shrink
+            );
+        }
+
         self.send_window = window;
+        self.send_window_edge = edge;
         trace!(
             "set TCP send window of {} -> {} to {}",
             self.dst,
             self.src,
             window
         );
+
+        // Stop probing once the window reopens
+        if self.send_window_remaining() > 0 && self.probe.is_some() {
+            self.probe = None;
+            trace!("stop TCP window probe of {} -> {}", self.dst, self.src);
+        }
     }
 
     /// Adds sequence to the TCP connection.
     pub fn add_sequence(&mut self, n: u32) {
-        self.sequence = self
-            .sequence
-            .checked_add(n)
-            .unwrap_or_else(|| n - (u32::MAX - self.sequence));
+        self.sequence = seq_add(self.sequence, n);
         trace!(
             "add TCP sequence of {} -> {} to {}",
             self.dst,
@@ -188,10 +420,7 @@ impl TcpTxState {
 
     /// Adds acknowledgement to the TCP connection.
     pub fn add_acknowledgement(&mut self, n: u32) {
-        self.acknowledgement = self
-            .acknowledgement
-            .checked_add(n)
-            .unwrap_or_else(|| n - (u32::MAX - self.acknowledgement));
+        self.acknowledgement = seq_add(self.acknowledgement, n);
         trace!(
             "add TCP acknowledgement of {} -> {} to {}",
             self.dst,
@@ -235,14 +464,15 @@ impl TcpTxState {
         // SYN
         if let Some(instant) = self.cache_syn {
             let send_next = self.sequence;
-            if sequence
-                .checked_sub(send_next)
-                .unwrap_or_else(|| sequence + (u32::MAX - send_next)) as usize
-                <= MAX_U32_WINDOW_SIZE
-            {
-                rtt = Some(instant.elapsed());
+            if seq_sub(sequence, send_next) as usize <= MAX_U32_WINDOW_SIZE {
+                // Karn's algorithm
+                if !self.cache_syn_retrans {
+                    rtt = Some(clock::elapsed(instant));
+                }
 
                 self.cache_syn = None;
+                self.cache_syn_retrans = false;
+                self.cache_syn_retries = 0;
                 trace!("acknowledge TCP SYN of {} -> {}", self.dst, self.src);
 
                 // Update TCP sequence
@@ -251,7 +481,11 @@ impl TcpTxState {
         }
 
         // Invalidate cache
+        let prev_sequence = self.cache.sequence();
         let cache_rtt = self.cache.invalidate_to(sequence);
+        if self.cache.sequence() != prev_sequence {
+            self.timeouts = 0;
+        }
         if rtt.is_none() {
             rtt = cache_rtt;
         }
@@ -262,11 +496,7 @@ impl TcpTxState {
             sequence
         );
 
-        if sequence
-            .checked_sub(self.cache.recv_next())
-            .unwrap_or_else(|| sequence + (u32::MAX - self.cache.recv_next())) as usize
-            <= MAX_U32_WINDOW_SIZE
-        {
+        if seq_sub(sequence, self.cache.recv_next()) as usize <= MAX_U32_WINDOW_SIZE {
             if let Some(timer) = self.cache_fin {
                 if rtt.is_none() && !self.cache_fin_retrans && !timer.is_timedout() {
                     rtt = Some(timer.elapsed());
@@ -274,6 +504,7 @@ impl TcpTxState {
 
                 self.cache_fin = None;
                 self.cache_fin_retrans = false;
+                self.fin_acked = Some(clock::now());
                 trace!("acknowledge TCP FIN of {} -> {}", self.dst, self.src);
 
                 // Update TCP sequence
@@ -289,10 +520,32 @@ impl TcpTxState {
 
     /// Updates the TCP SYN timer of the TCP connection.
     pub fn update_syn_timer(&mut self) {
-        self.cache_syn = Some(Instant::now());
+        if self.cache_syn.is_some() {
+            self.cache_syn_retrans = true;
+        }
+        self.cache_syn = Some(clock::now());
         trace!("update TCP SYN timer of {} -> {}", self.dst, self.src);
     }
 
+    /// Sets the max count of retransmissions of the TCP SYN/ACK due to timeout, after which the
+    /// TCP connection is considered timed out in the handshake.
+    pub fn set_max_syn_retries(&mut self, retries: usize) {
+        self.max_syn_retries = retries;
+    }
+
+    /// Returns if the TCP SYN/ACK has been retransmitted for the max count and the last
+    /// retransmission is not acknowledged within the RTO, in which case the source is considered
+    /// gone and the TCP connection should be aborted.
+    pub fn is_syn_timedout(&self) -> bool {
+        match self.cache_syn {
+            Some(instant) => {
+                self.cache_syn_retries >= self.max_syn_retries
+                    && clock::elapsed(instant) >= Duration::from_millis(self.rto)
+            }
+            None => false,
+        }
+    }
+
     /// Updates the TCP FIN timer of the TCP connection.
     pub fn update_fin_timer(&mut self) {
         if self.cache_fin.is_some() {
@@ -318,6 +571,15 @@ impl TcpTxState {
         Ok(payload)
     }
 
+    /// Updates the TCP window probe timer of the TCP connection. The timer will only be started
+    /// if it is not started.
+    pub fn update_probe_timer(&mut self) {
+        if self.probe.is_none() {
+            self.probe = Some(Timer::new(self.rto));
+            trace!("start TCP window probe of {} -> {}", self.dst, self.src);
+        }
+    }
+
     /// Appends the TCP FIN from the queue to the cache of the TCP connection.
     pub fn append_cache_fin(&mut self) {
         self.queue_fin = false;
@@ -332,6 +594,7 @@ impl TcpTxState {
     /// Appends the payload to the queue of the TCP connection.
     pub fn append_queue(&mut self, payload: &[u8]) {
         self.queue.extend(payload);
+        self.queued_bytes += payload.len() as u64;
         trace!(
             "append {} Bytes to TCP queue of {} -> {}",
             payload.len(),
@@ -376,9 +639,9 @@ impl TcpTxState {
         let rttvar;
         match self.srtt {
             Some(prev_srtt) => {
-                // RTTVAR
+                // RTTVAR = (1 - 1/4) * RTTVAR + 1/4 * |SRTT - R|
                 let prev_rttvar = self.rttvar.unwrap();
-                rttvar = (prev_rttvar / 8 * 7)
+                rttvar = (prev_rttvar - prev_rttvar / 4)
                     .checked_add(
                         prev_srtt
                             .checked_sub(rtt)
@@ -387,8 +650,8 @@ impl TcpTxState {
                     )
                     .unwrap_or(u64::MAX);
 
-                // SRTT
-                srtt = (prev_rttvar / 8 * 7)
+                // SRTT = (1 - 1/8) * SRTT + 1/8 * R
+                srtt = (prev_srtt - prev_srtt / 8)
                     .checked_add(rtt / 8)
                     .unwrap_or(u64::MAX);
             }
@@ -421,12 +684,126 @@ impl TcpTxState {
         self.set_rto(rto);
     }
 
+    /// Sets if the TCP connection paces segments, which spreads the send window over the SRTT
+    /// instead of sending it in a burst.
+    pub fn set_pacing(&mut self, pacing: bool) {
+        self.pacing = pacing;
+        trace!(
+            "set TCP pacing of {} -> {} to {}",
+            self.dst,
+            self.src,
+            pacing
+        );
+    }
+
+    /// Returns the rate in bytes per second the TCP connection paces segments at. Returns `None`
+    /// if the TCP connection is not paced or its SRTT is not measured yet.
+    fn pacing_rate(&self) -> Option<u64> {
+        if !self.pacing || self.send_window == 0 {
+            return None;
+        }
+
+        match self.srtt {
+            Some(srtt) if srtt > 0 => Some(max(
+                1,
+                (self.send_window as u64).saturating_mul(1000) / srtt,
+            )),
+            _ => None,
+        }
+    }
+
+    /// Returns the size the TCP connection is allowed to send now under pacing, which refills
+    /// over time at the pacing rate and is bounded by a small burst of segments.
+    pub fn pacing_budget(&mut self, mss: usize) -> usize {
+        let rate = match self.pacing_rate() {
+            Some(rate) => rate,
+            None => return usize::MAX,
+        };
+
+        // A tick of the timer should not starve the rate
+        let burst = max(PACING_BURST * mss, (rate * TIMER_TICK / 1000) as usize);
+        let elapsed = clock::elapsed(self.pacing_instant).as_micros();
+        let refill = min(rate as u128 * elapsed / 1_000_000, usize::MAX as u128) as usize;
+        if refill > 0 {
+            self.pacing_tokens = self.pacing_tokens.saturating_add(refill);
+            self.pacing_instant = clock::now();
+        }
+        self.pacing_tokens = min(burst, self.pacing_tokens);
+
+        self.pacing_tokens
+    }
+
+    /// Consumes the pacing budget of the TCP connection by the size sent.
+    fn consume_pacing_budget(&mut self, size: usize) {
+        if self.pacing_rate().is_some() {
+            self.pacing_tokens = self.pacing_tokens.saturating_sub(size);
+        }
+    }
+
+    /// Defers sending the queue of the TCP connection until the pacing budget refills for a
+    /// segment.
+    fn defer_pacing(&mut self, mss: usize) {
+        if let Some(rate) = self.pacing_rate() {
+            let needed = mss.saturating_sub(self.pacing_tokens) as u64;
+            let delay = Duration::from_micros(needed.saturating_mul(1_000_000) / rate);
+            self.pacing_deadline = Some(clock::now() + delay);
+            trace!(
+                "defer TCP {} -> {} by {} us for pacing",
+                self.dst,
+                self.src,
+                delay.as_micros()
+            );
+        }
+    }
+
+    /// Defers sending the queue of the TCP connection until the bucket of its traffic shaping
+    /// class refills after the delay.
+    fn defer_shaping(&mut self, delay: Duration) {
+        let deadline = clock::now() + delay;
+        self.pacing_deadline = Some(match self.pacing_deadline {
+            Some(pacing_deadline) => min(pacing_deadline, deadline),
+            None => deadline,
+        });
+        trace!(
+            "defer TCP {} -> {} by {} us for shaping",
+            self.dst,
+            self.src,
+            delay.as_micros()
+        );
+    }
+
+    /// Returns if the deferred sending of the TCP connection is due, and clears it if so.
+    fn take_pacing_due(&mut self) -> bool {
+        match self.pacing_deadline {
+            Some(deadline) if deadline <= clock::now() => {
+                self.pacing_deadline = None;
+
+                true
+            }
+            _ => false,
+        }
+    }
+
     /// Returns the send window of the TCP connection. The send window represents the received
     /// window from the source and indicates how much payload it can receive next.
     pub fn send_window(&self) -> usize {
         self.send_window
     }
 
+    /// Returns the remaining send window of the TCP connection, which is the distance from the
+    /// send next to the right edge of the latest window advertisement. Returns 0 if the window
+    /// shrinks below the data in flight.
+    pub fn send_window_remaining(&self) -> usize {
+        let send_next = self.cache.recv_next();
+        let remaining = seq_sub(self.send_window_edge, send_next) as usize;
+
+        if remaining > MAX_U32_WINDOW_SIZE {
+            0
+        } else {
+            remaining
+        }
+    }
+
     /// Returns the send window scale of the TCP connection.
     pub fn send_wscale(&self) -> Option<u8> {
         self.send_wscale
@@ -477,6 +854,11 @@ impl TcpTxState {
         self.cache_fin
     }
 
+    /// Returns the time the TCP FIN of the TCP connection is acknowledged.
+    pub fn fin_acked(&self) -> Option<Instant> {
+        self.fin_acked
+    }
+
     /// Returns the queue of the TCP connection.
     pub fn queue(&self) -> &VecDeque<u8> {
         &self.queue
@@ -487,10 +869,75 @@ impl TcpTxState {
         self.queue_fin
     }
 
+    /// Returns the TCP window probe timer of the TCP connection.
+    pub fn probe(&self) -> Option<Timer> {
+        self.probe
+    }
+
     /// Returns the RTO if the TCP connection.
     pub fn rto(&self) -> u64 {
         self.rto
     }
+
+    /// Returns the SRTT of the TCP connection measured from the handshake and the ACKs of the
+    /// source, or `None` if it is not measured yet.
+    pub fn srtt(&self) -> Option<Duration> {
+        self.srtt.map(Duration::from_millis)
+    }
+
+    /// Returns the count of segments retransmitted of the TCP connection.
+    pub fn retrans_segments(&self) -> u64 {
+        self.retrans_segments
+    }
+
+    /// Returns the size of payloads retransmitted of the TCP connection.
+    pub fn retrans_bytes(&self) -> u64 {
+        self.retrans_bytes
+    }
+
+    /// Returns the count of fast retransmissions of the TCP connection, which are triggered by
+    /// duplicate ACKs.
+    pub fn fast_retransmits(&self) -> u64 {
+        self.fast_retransmits
+    }
+
+    /// Returns the count of retransmissions of the TCP connection triggered by timeouts.
+    pub fn rto_retransmits(&self) -> u64 {
+        self.rto_retransmits
+    }
+
+    /// Returns the total bytes queued to be sent to the source.
+    pub fn queued_bytes(&self) -> u64 {
+        self.queued_bytes
+    }
+
+    /// Returns the instant when the next SYN/ACK retransmission, retransmission, window probe,
+    /// FIN retransmission or deferred sending under pacing or shaping of the TCP connection is
+    /// due.
+    pub fn deadline(&self) -> Option<Instant> {
+        let deadline = if let Some(instant) = self.cache_syn {
+            if self.cache_syn_retries < self.max_syn_retries {
+                Some(instant + Duration::from_millis(self.rto))
+            } else {
+                None
+            }
+        } else if !self.cache.is_empty() {
+            self.cache.deadline()
+        } else if let Some(timer) = self.probe {
+            if self.queue.is_empty() {
+                None
+            } else {
+                Some(timer.deadline())
+            }
+        } else {
+            self.cache_fin.map(|timer| timer.deadline())
+        };
+
+        match (deadline, self.pacing_deadline) {
+            (Some(deadline), Some(pacing_deadline)) => Some(min(deadline, pacing_deadline)),
+            (deadline, pacing_deadline) => deadline.or(pacing_deadline),
+        }
+    }
 }
 
 impl Display for TcpTxState {
@@ -515,16 +962,54 @@ const ENABLE_MSS: bool = true;
 /// Exclude the 4 bytes used in FCS, the minimum frame size in pcap2socks is 60 Bytes.
 const MINIMUM_FRAME_SIZE: usize = 60;
 
+/// Represents the duration of a tick of the timer wheel of TCP connections.
+const TIMER_TICK: u64 = 10;
+/// Represents the count of slots of the timer wheel of TCP connections.
+const TIMER_SLOTS: usize = 1024;
+
+/// Represents the count of retransmissions due to timeout of a TCP connection which triggers a
+/// dump of its frames.
+const CAPTURE_RTO_RETRANSMITS: u64 = 3;
+
+/// Represents the count of consecutive retransmissions due to timeout of segments larger than the
+/// base MTU, after which the path is considered to drop large packets silently, and the path MTU
+/// falls back to the base MTU.
+const BLACK_HOLE_TIMEOUTS: usize = 2;
+
+/// Represents the max number of destinations whose path MTU is being searched by probing.
+const MTU_SEARCH_CAPACITY: usize = 256;
+
 /// Represents a channel forward traffic to the source in pcap.
 pub struct Forwarder {
     tx: Sender,
-    src_mtu: HashMap<Ipv4Addr, usize>,
+    src_mtu: PacketMap<Ipv4Addr, usize>,
+    dst_mtu: MtuCache,
+    /// Represents the searches of the path MTU to destinations by probing, if path MTU probing is
+    /// enabled.
+    mtu_searches: Option<LruCache<Ipv4Addr, MtuSearch>>,
     local_mtu: usize,
-    src_hardware_addr: HashMap<Ipv4Addr, HardwareAddr>,
+    src_hardware_addr: PacketMap<Ipv4Addr, HardwareAddr>,
+    pppoe_sessions: PacketMap<HardwareAddr, (u16, HardwareAddr)>,
+    vlan_tags: PacketMap<HardwareAddr, Vec<VlanTag>>,
     local_hardware_addr: HardwareAddr,
     local_ip_addr: Ipv4Addr,
-    ipv4_identification_map: HashMap<(Ipv4Addr, Ipv4Addr), u16>,
-    states: HashMap<(SocketAddrV4, SocketAddrV4), TcpTxState>,
+    ipv4_identification_map: PacketMap<(Ipv4Addr, Ipv4Addr), u16>,
+    states: PacketMap<(SocketAddrV4, SocketAddrV4), TcpTxState>,
+    timers: TimerWheel<(SocketAddrV4, SocketAddrV4)>,
+    timer_notify: Arc<Notify>,
+    middlewares: Option<Arc<Mutex<Middlewares>>>,
+    shaper: Option<Arc<Mutex<Shaper>>>,
+    scheduler: Option<TxScheduler>,
+    priority_ports: Vec<u16>,
+    stats: Option<Arc<Stats>>,
+    #[cfg(feature = "metrics")]
+    tracker: Option<Arc<Mutex<Tracker>>>,
+    recorder: Option<Arc<Mutex<Recorder>>>,
+    tracer: Option<Arc<Mutex<Tracer>>>,
+    flows: Option<FlowPublisher>,
+    #[cfg(feature = "udp")]
+    quic_conn_ids: Option<Arc<Mutex<QuicConnIds>>>,
+    clock: Option<Arc<dyn Clock>>,
 }
 
 impl Forwarder {
@@ -537,16 +1022,112 @@ impl Forwarder {
     ) -> Forwarder {
         Forwarder {
             tx,
-            src_mtu: HashMap::new(),
+            src_mtu: PacketMap::default(),
+            dst_mtu: MtuCache::new(),
+            mtu_searches: None,
             local_mtu: mtu,
-            src_hardware_addr: HashMap::new(),
+            src_hardware_addr: PacketMap::default(),
+            pppoe_sessions: PacketMap::default(),
+            vlan_tags: PacketMap::default(),
             local_hardware_addr,
             local_ip_addr,
-            ipv4_identification_map: HashMap::new(),
-            states: HashMap::new(),
+            ipv4_identification_map: PacketMap::default(),
+            states: PacketMap::default(),
+            timers: TimerWheel::new(TIMER_SLOTS, Duration::from_millis(TIMER_TICK)),
+            timer_notify: Arc::new(Notify::new()),
+            middlewares: None,
+            shaper: None,
+            scheduler: None,
+            priority_ports: Vec::new(),
+            stats: None,
+            #[cfg(feature = "metrics")]
+            tracker: None,
+            recorder: None,
+            tracer: None,
+            flows: None,
+            #[cfg(feature = "udp")]
+            quic_conn_ids: None,
+            clock: None,
         }
     }
 
+    /// Sets the clock of TCP connections, or the clock of the system if it is `None`.
+    pub(crate) fn set_clock(&mut self, clock: Option<Arc<dyn Clock>>) {
+        let _clock = clock::enter(clock.clone());
+        self.timers = TimerWheel::new(TIMER_SLOTS, Duration::from_millis(TIMER_TICK));
+        self.clock = clock;
+    }
+
+    /// Sets the QUIC connection IDs, which track connection IDs issued by servers in datagrams sent.
+    #[cfg(feature = "udp")]
+    fn set_quic_conn_ids(&mut self, quic_conn_ids: Arc<Mutex<QuicConnIds>>) {
+        self.quic_conn_ids = Some(quic_conn_ids);
+    }
+
+    /// Sets the middlewares which handle frames before they are sent.
+    pub(crate) fn set_middlewares(&mut self, middlewares: Arc<Mutex<Middlewares>>) {
+        self.middlewares = Some(middlewares);
+    }
+
+    /// Sets the shaper which shapes the traffic to sources by their classes.
+    pub(crate) fn set_shaper(&mut self, shaper: Arc<Mutex<Shaper>>) {
+        self.shaper = Some(shaper);
+    }
+
+    /// Sets if frames are scheduled in two queues, so small frames, pure TCP ACKs and frames from
+    /// the priority ports are sent ahead of bulk segments while the pcap device is busy.
+    pub fn set_tx_priority(&mut self, is_enabled: bool, priority_ports: Vec<u16>) {
+        self.scheduler = if is_enabled {
+            Some(TxScheduler::new())
+        } else {
+            None
+        };
+        self.priority_ports = priority_ports;
+    }
+
+    /// Returns the shaper and the class of a flow of the source to the destination if the flow is
+    /// shaped.
+    fn qos_class(
+        &self,
+        protocol: PortProtocol,
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+    ) -> Option<(Arc<Mutex<Shaper>>, usize)> {
+        let shaper = self.shaper.as_ref()?;
+        let class = shaper
+            .lock()
+            .unwrap()
+            .classify(protocol, *src.ip(), dst.port())?;
+
+        Some((Arc::clone(shaper), class))
+    }
+
+    /// Sets the statistics which count frames sent.
+    pub(crate) fn set_stats(&mut self, stats: Arc<Stats>) {
+        self.stats = Some(stats);
+    }
+
+    /// Sets the publisher of flow events, which publishes TCP connections closed by destinations.
+    pub(crate) fn set_flows(&mut self, flows: FlowPublisher) {
+        self.flows = Some(flows);
+    }
+
+    /// Sets the tracker which tracks the throughput of frames sent.
+    #[cfg(feature = "metrics")]
+    pub(crate) fn set_tracker(&mut self, tracker: Arc<Mutex<Tracker>>) {
+        self.tracker = Some(tracker);
+    }
+
+    /// Sets the recorder which keeps frames sent of TCP connections.
+    pub(crate) fn set_recorder(&mut self, recorder: Arc<Mutex<Recorder>>) {
+        self.recorder = Some(recorder);
+    }
+
+    /// Sets the tracer which traces segments sent and timers fired of TCP connections.
+    pub(crate) fn set_tracer(&mut self, tracer: Arc<Mutex<Tracer>>) {
+        self.tracer = Some(tracer);
+    }
+
     /// Sets the source MTU.
     pub fn set_src_mtu(&mut self, src_ip_addr: Ipv4Addr, mtu: usize) -> bool {
         let prev_mtu = *self.src_mtu.get(&src_ip_addr).unwrap_or(&self.local_mtu);
@@ -557,6 +1138,257 @@ impl Forwarder {
         return *self.src_mtu.get(&src_ip_addr).unwrap_or(&self.local_mtu) != prev_mtu;
     }
 
+    /// Sets the path MTU of the destination. The cache of the path MTU is persisted later by the
+    /// `Redirector` in its sweep.
+    pub fn set_dst_mtu(&mut self, dst_ip_addr: Ipv4Addr, mtu: usize) -> bool {
+        self.dst_mtu.set(dst_ip_addr, min(self.local_mtu, mtu))
+    }
+
+    /// Returns a copy of the cache of the path MTU to be persisted if it is changed since it is
+    /// persisted.
+    fn take_dirty_mtu_cache(&mut self) -> Option<MtuCache> {
+        self.dst_mtu.take_dirty()
+    }
+
+    /// Sets the cache of the path MTU to destinations.
+    pub fn set_mtu_cache(&mut self, cache: MtuCache) {
+        self.dst_mtu = cache;
+    }
+
+    /// Sets if the path MTU to destinations is probed. If enabled, the path MTU falls back to the
+    /// base MTU when TCP segments larger than it keep timing out, and is raised again by probing.
+    pub(crate) fn set_mtu_probing(&mut self, is_enabled: bool) {
+        self.mtu_searches = if is_enabled {
+            Some(LruCache::new(MTU_SEARCH_CAPACITY))
+        } else {
+            None
+        };
+    }
+
+    /// Acknowledges the probe of the path MTU carried by a TCP connection, which raises the path
+    /// MTU to the destination.
+    pub(crate) fn acknowledge_mtu_probe(
+        &mut self,
+        dst: SocketAddrV4,
+        src: SocketAddrV4,
+        sequence: u32,
+    ) {
+        let search = match self.mtu_searches {
+            Some(ref mut searches) => match searches.get_mut(dst.ip()) {
+                Some(search) => search,
+                None => return,
+            },
+            None => return,
+        };
+        match search.probe() {
+            Some(probe) if probe.src == src && probe.dst == dst => {
+                if seq_sub(sequence, probe.end) as usize > MAX_U32_WINDOW_SIZE {
+                    return;
+                }
+            }
+            _ => return,
+        }
+
+        let mtu = search.succeed().unwrap();
+        let is_completed = search.is_completed();
+        debug!("raise path MTU of {} to {} by probing", dst.ip(), mtu);
+        if is_completed {
+            self.mtu_searches.as_mut().unwrap().pop(dst.ip());
+        }
+        self.set_dst_mtu(*dst.ip(), mtu);
+    }
+
+    /// Marks the probe of the path MTU carried by a TCP connection lost, like a segment of the
+    /// connection is retransmitted. Returns if there is a probe in flight.
+    fn fail_mtu_probe(&mut self, dst: SocketAddrV4, src: SocketAddrV4) -> bool {
+        if let Some(ref mut searches) = self.mtu_searches {
+            if let Some(search) = searches.get_mut(dst.ip()) {
+                if let Some(probe) = search.probe() {
+                    if probe.src == src && probe.dst == dst {
+                        let mtu = search.fail().unwrap();
+                        trace!("lose path MTU probe {} of {}", mtu, dst.ip());
+
+                        return true;
+                    }
+                }
+            }
+        }
+
+        false
+    }
+
+    /// Detects a path dropping TCP segments larger than the base MTU silently, in which case the
+    /// path MTU to the destination falls back to the base MTU, and is searched by probing.
+    fn detect_black_hole(&mut self, dst: SocketAddrV4, src: SocketAddrV4, size: usize) {
+        if self.mtu_searches.is_none() {
+            return;
+        }
+        let mtu = self.get_mtu(*dst.ip(), *src.ip());
+        let base_mss = BASE_MTU - (Ipv4::minimum_len() + Tcp::minimum_len());
+        if mtu <= BASE_MTU || size <= base_mss {
+            return;
+        }
+        let state = match self.get_state(dst, src) {
+            Some(state) => state,
+            None => return,
+        };
+        state.timeouts += 1;
+        if state.timeouts < BLACK_HOLE_TIMEOUTS {
+            return;
+        }
+        state.timeouts = 0;
+
+        debug!(
+            "fall back path MTU of {} from {} to {} due to timeout",
+            dst.ip(),
+            mtu,
+            BASE_MTU
+        );
+        let dst_mtu = self.dst_mtu.get(*dst.ip()).unwrap_or(self.local_mtu);
+        self.mtu_searches
+            .as_mut()
+            .unwrap()
+            .put(*dst.ip(), MtuSearch::new(BASE_MTU, dst_mtu - 1));
+        self.set_dst_mtu(*dst.ip(), BASE_MTU);
+        if let Some(ref stats) = self.stats {
+            stats.increase_mtu_black_holes();
+        }
+    }
+
+    /// Sends a TCP segment of the MTU of the next probe of the search of the path MTU to the
+    /// destination. Returns if the probe is sent.
+    fn send_tcp_mtu_probe(
+        &mut self,
+        dst: SocketAddrV4,
+        src: SocketAddrV4,
+        remain_size: usize,
+    ) -> io::Result<bool> {
+        let mtu = match self.mtu_searches {
+            Some(ref mut searches) => match searches.get_mut(dst.ip()) {
+                Some(search) => match search.next_probe() {
+                    Some(mtu) => mtu,
+                    None => return Ok(false),
+                },
+                None => return Ok(false),
+            },
+            None => return Ok(false),
+        };
+        // The probe must fit the MTU of the source, and there must be more data to send after
+        // the probe
+        let src_mtu = *self.src_mtu.get(src.ip()).unwrap_or(&self.local_mtu);
+        if mtu > src_mtu || self.get_pppoe_session(*src.ip()).is_some() {
+            return Ok(false);
+        }
+        let mss = mtu - (Ipv4::minimum_len() + Tcp::minimum_len());
+        let state = match self.get_state(dst, src) {
+            Some(state) => state,
+            None => return Ok(false),
+        };
+        if state.cache_syn().is_some() || remain_size < mss || state.queue().len() <= mss {
+            return Ok(false);
+        }
+
+        let sequence = state.sequence();
+        let payload = state.append_cache(mss)?;
+        self.mtu_searches
+            .as_mut()
+            .unwrap()
+            .get_mut(dst.ip())
+            .unwrap()
+            .start(MtuProbe {
+                src,
+                dst,
+                end: seq_add(sequence, mss as u32),
+                mtu,
+            });
+        trace!(
+            "probe path MTU {} of {} with TCP {} -> {}",
+            mtu,
+            dst.ip(),
+            dst,
+            src
+        );
+        if let Some(ref stats) = self.stats {
+            stats.increase_mtu_probes();
+        }
+
+        self.send_tcp_segments(dst, src, sequence, &payload, false, mss)?;
+
+        Ok(true)
+    }
+
+    fn get_mtu(&self, dst_ip_addr: Ipv4Addr, src_ip_addr: Ipv4Addr) -> usize {
+        let src_mtu = *self.src_mtu.get(&src_ip_addr).unwrap_or(&self.local_mtu);
+        let dst_mtu = self.dst_mtu.get(dst_ip_addr).unwrap_or(self.local_mtu);
+        let mtu = min(src_mtu, dst_mtu);
+
+        // PPPoE session layer
+        match self.get_pppoe_session(src_ip_addr) {
+            Some((session_id, _)) => mtu.saturating_sub(Pppoe::new(session_id).len()),
+            None => mtu,
+        }
+    }
+
+    /// Sets the PPPoE session of the source hardware address, and the hardware address of the
+    /// access concentrator serving the session.
+    pub fn set_pppoe_session(
+        &mut self,
+        src_hardware_addr: HardwareAddr,
+        session_id: u16,
+        ac_hardware_addr: HardwareAddr,
+    ) {
+        let session = (session_id, ac_hardware_addr);
+        if self.pppoe_sessions.get(&src_hardware_addr) == Some(&session) {
+            return;
+        }
+
+        self.pppoe_sessions.insert(src_hardware_addr, session);
+        trace!(
+            "set PPPoE session of {} to {:#06x} through {}",
+            src_hardware_addr,
+            session_id,
+            ac_hardware_addr
+        );
+    }
+
+    /// Returns the hardware address of the source if it is known.
+    pub fn get_src_hardware_addr(&self, src_ip_addr: Ipv4Addr) -> Option<HardwareAddr> {
+        self.src_hardware_addr.get(&src_ip_addr).copied()
+    }
+
+    fn get_pppoe_session(&self, src_ip_addr: Ipv4Addr) -> Option<(u16, HardwareAddr)> {
+        let src_hardware_addr = self.src_hardware_addr.get(&src_ip_addr)?;
+
+        self.pppoe_sessions.get(src_hardware_addr).copied()
+    }
+
+    /// Sets the VLAN tags of the source hardware address, which are stacked in frames sent to
+    /// it. Frames sent in broadcast carry the VLAN tags most recently set.
+    pub fn set_vlan_tags(&mut self, src_hardware_addr: HardwareAddr, tags: &[VlanTag]) {
+        let prev_tags = self.vlan_tags.get(&src_hardware_addr);
+        if prev_tags.map_or(tags.is_empty(), |prev_tags| prev_tags.as_slice() == tags) {
+            return;
+        }
+
+        self.vlan_tags.insert(src_hardware_addr, tags.to_vec());
+        self.vlan_tags
+            .insert(pcap::HARDWARE_ADDR_BROADCAST, tags.to_vec());
+        trace!(
+            "set VLAN tags of {} to {:?}",
+            src_hardware_addr,
+            tags.iter().map(|tag| tag.vid()).collect::<Vec<_>>()
+        );
+    }
+
+    fn new_ethernet(&self, t: LayerKind, src: HardwareAddr, dst: HardwareAddr) -> Ethernet {
+        let ethernet = Ethernet::new(t, src, dst).unwrap();
+
+        match self.vlan_tags.get(&dst) {
+            Some(tags) => ethernet.with_tags(tags.clone()),
+            None => ethernet,
+        }
+    }
+
     /// Sets the source hardware address.
     pub fn set_src_hardware_addr(&mut self, src_ip_addr: Ipv4Addr, hardware_addr: HardwareAddr) {
         self.src_hardware_addr.insert(src_ip_addr, hardware_addr);
@@ -567,6 +1399,33 @@ impl Forwarder {
         );
     }
 
+    /// Records the hardware addresses and the MTU of sources, and the learned path MTU to
+    /// destinations in the snapshot.
+    fn snapshot(&self, snapshot: &mut Snapshot) {
+        snapshot.neighbors.extend(self.src_hardware_addr.iter());
+        snapshot.src_mtus.extend(self.src_mtu.iter());
+        snapshot.dst_mtus.extend(
+            self.dst_mtu
+                .entries()
+                .map(|(addr, mtu, instant)| (addr, (mtu, instant))),
+        );
+    }
+
+    /// Restores the hardware addresses and the MTU of sources, and the learned path MTU to
+    /// destinations from the snapshot.
+    fn restore(&mut self, snapshot: &Snapshot) {
+        for (&ip_addr, &hardware_addr) in &snapshot.neighbors {
+            self.set_src_hardware_addr(ip_addr, hardware_addr);
+        }
+        for (&ip_addr, &mtu) in &snapshot.src_mtus {
+            self.set_src_mtu(ip_addr, mtu);
+        }
+        for (&ip_addr, &(mtu, instant)) in &snapshot.dst_mtus {
+            self.dst_mtu
+                .insert(ip_addr, min(self.local_mtu, mtu), instant);
+        }
+    }
+
     /// Sets the local IP address.
     pub fn set_local_ip_addr(&mut self, ip_addr: Ipv4Addr) {
         self.local_ip_addr = ip_addr;
@@ -609,8 +1468,10 @@ impl Forwarder {
         // Avoid SWS
         if ENABLE_RECV_SWS_AVOID {
             let thresh = min((RECV_WINDOW / 2) as usize, self.local_mtu);
+            // The window is scaled while the threshold is not
+            let window = (state.window() as usize) << state.send_wscale().unwrap_or(0);
 
-            if (state.window() as usize) < thresh {
+            if window < thresh {
                 0
             } else {
                 state.window()
@@ -620,11 +1481,134 @@ impl Forwarder {
         }
     }
 
+    /// Aborts a TCP connection by sending an ACK/RST to the source, and removes all information
+    /// related to it. Returns `false` if the connection does not exist.
+    pub fn abort(&mut self, dst: SocketAddrV4, src: SocketAddrV4) -> io::Result<bool> {
+        if !self.states.contains_key(&(src, dst)) {
+            return Ok(false);
+        }
+
+        // Send ACK/RST
+        let result = self.send_tcp_ack_rst(dst, src);
+
+        // Clean up
+        self.clean_up(dst, src);
+
+        result.map(|_| true)
+    }
+
     /// Removes all information related to a TCP connection.
     pub fn clean_up(&mut self, dst: SocketAddrV4, src: SocketAddrV4) {
         let key = (src, dst);
 
         self.states.remove(&key);
+        self.timers.cancel(&key);
+        if let Some(ref mut searches) = self.mtu_searches {
+            if let Some(search) = searches.get_mut(dst.ip()) {
+                if let Some(&MtuProbe {
+                    src: probe_src,
+                    dst: probe_dst,
+                    ..
+                }) = search.probe()
+                {
+                    if probe_src == src && probe_dst == dst {
+                        search.cancel();
+                    }
+                }
+            }
+        }
+        if let Some(ref recorder) = self.recorder {
+            recorder.lock().unwrap().remove(dst, src);
+        }
+        if let Some(ref tracer) = self.tracer {
+            tracer.lock().unwrap().close(src, dst);
+        }
+    }
+
+    fn update_tcp_timer(&mut self, dst: SocketAddrV4, src: SocketAddrV4) {
+        let key = (src, dst);
+
+        match self.states.get(&key).and_then(|state| state.deadline()) {
+            Some(deadline) => {
+                let is_empty = self.timers.is_empty();
+                self.timers.schedule(key, deadline);
+
+                // Wake up the timer driver
+                if is_empty {
+                    self.timer_notify.notify();
+                }
+            }
+            None => self.timers.cancel(&key),
+        }
+    }
+
+    /// Fires the due timers of TCP connections, retransmitting timed out data, probing closed
+    /// windows and retransmitting FINs.
+    pub fn expire_tcp_timers(&mut self) {
+        let _clock = clock::enter(self.clock.clone());
+        let keys = self.timers.expire(clock::now());
+        for (src, dst) in keys {
+            if self.states.get(&(src, dst)).is_none() {
+                continue;
+            }
+            trace!("fire TCP timer of {} -> {}", dst, src);
+
+            if let Err(ref e) = self.retransmit_tcp_ack_timedout(dst, src) {
+                warn!("handle {}: {}", "TCP", e);
+            }
+
+            // Send the queue deferred by pacing
+            let is_pacing_due = self
+                .states
+                .get_mut(&(src, dst))
+                .map_or(false, |state| state.take_pacing_due());
+            if is_pacing_due {
+                if let Err(ref e) = self.send_tcp_ack(dst, src) {
+                    warn!("handle {}: {}", "TCP", e);
+                }
+            }
+            self.update_tcp_timer(dst, src);
+        }
+    }
+
+    /// Sets if a TCP connection paces segments over its SRTT. Returns if the TCP connection
+    /// exists.
+    pub fn set_tcp_pacing(&mut self, dst: SocketAddrV4, src: SocketAddrV4, pacing: bool) -> bool {
+        match self.get_state(dst, src) {
+            Some(state) => {
+                state.set_pacing(pacing);
+
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns if there is any timer of TCP connections scheduled.
+    pub fn has_tcp_timers(&self) -> bool {
+        !self.timers.is_empty()
+    }
+
+    /// Returns the notification which is notified when a timer of TCP connections is scheduled
+    /// while there is no other timer.
+    pub fn timer_notify(&self) -> Arc<Notify> {
+        Arc::clone(&self.timer_notify)
+    }
+
+    /// Throttles a TCP connection by shrinking its send window to the data in flight until the
+    /// source updates its window again. Returns if the TCP connection exists.
+    pub fn throttle_tcp(&mut self, dst: SocketAddrV4, src: SocketAddrV4) -> bool {
+        let key = (src, dst);
+
+        match self.states.get_mut(&key) {
+            Some(state) => {
+                let window = state.cache().len();
+                state.set_send_window(window);
+
+                true
+            }
+            None => false,
+        }
     }
 
     /// Returns the size of the cache and the queue of a TCP connection.
@@ -651,7 +1635,26 @@ impl Forwarder {
 
         // Ethernet
         let ethernet =
-            Ethernet::new(arp.kind(), arp.src_hardware_addr(), arp.dst_hardware_addr()).unwrap();
+            self.new_ethernet(arp.kind(), arp.src_hardware_addr(), arp.dst_hardware_addr());
+
+        // Indicator
+        let indicator = Indicator::new(Layers::Ethernet(ethernet), Some(Layers::Arp(arp)), None);
+
+        // Send
+        self.send(&indicator)
+    }
+
+    /// Sends an ARP probe for the IP address in broadcast.
+    pub fn send_arp_probe(&mut self, ip_addr: Ipv4Addr) -> io::Result<()> {
+        // ARP
+        let arp = Arp::new_probe(self.local_hardware_addr, ip_addr);
+
+        // Ethernet
+        let ethernet = self.new_ethernet(
+            arp.kind(),
+            arp.src_hardware_addr(),
+            pcap::HARDWARE_ADDR_BROADCAST,
+        );
 
         // Indicator
         let indicator = Indicator::new(Layers::Ethernet(ethernet), Some(Layers::Arp(arp)), None);
@@ -660,6 +1663,11 @@ impl Forwarder {
         self.send(&indicator)
     }
 
+    /// Returns the local hardware address.
+    pub fn local_hardware_addr(&self) -> HardwareAddr {
+        self.local_hardware_addr
+    }
+
     /// Appends TCP ACK payload to the queue.
     pub fn append_to_queue(
         &mut self,
@@ -674,12 +1682,89 @@ impl Forwarder {
         self.send_tcp_ack(dst, src)
     }
 
+    /// Counts a retransmission of a TCP connection, which is either a fast retransmission or a
+    /// retransmission due to timeout.
+    fn count_retransmit(&mut self, dst: SocketAddrV4, src: SocketAddrV4, is_timedout: bool) {
+        // The probe of the path MTU in flight is considered lost
+        self.fail_mtu_probe(dst, src);
+
+        let mut rto_retransmits = 0;
+        if let Some(state) = self.get_state(dst, src) {
+            if is_timedout {
+                state.rto_retransmits += 1;
+                rto_retransmits = state.rto_retransmits;
+            } else {
+                state.fast_retransmits += 1;
+            }
+        }
+        if let Some(ref tracer) = self.tracer {
+            let event = if is_timedout {
+                let rto = self.states.get(&(src, dst)).map_or(0, |state| state.rto());
+                tcptrace::Event::Rto(rto, rto_retransmits)
+            } else {
+                tcptrace::Event::FastRetransmit
+            };
+            tracer.lock().unwrap().event(src, dst, event);
+        }
+        if rto_retransmits == CAPTURE_RTO_RETRANSMITS {
+            if let Some(ref recorder) = self.recorder {
+                recorder
+                    .lock()
+                    .unwrap()
+                    .dump(dst, src, Trigger::Retransmission(rto_retransmits));
+            }
+        }
+        if let Some(ref stats) = self.stats {
+            if is_timedout {
+                stats.increase_tcp_rto_retransmits();
+            } else {
+                stats.increase_tcp_fast_retransmits();
+            }
+        }
+    }
+
+    /// Counts segments retransmitted of a TCP connection.
+    fn count_retrans(
+        &mut self,
+        dst: SocketAddrV4,
+        src: SocketAddrV4,
+        size: usize,
+        segments: usize,
+    ) {
+        if let Some(state) = self.get_state(dst, src) {
+            state.retrans_segments += segments as u64;
+            state.retrans_bytes += size as u64;
+        }
+        if let Some(ref stats) = self.stats {
+            stats.add_tcp_retrans_segments(segments);
+            stats.add_tcp_retrans_bytes(size);
+        }
+    }
+
+    /// Retransmits TCP ACK packets carrying the payload, and counts them.
+    fn retransmit_tcp_ack_raw(
+        &mut self,
+        dst: SocketAddrV4,
+        src: SocketAddrV4,
+        sequence: u32,
+        payload: &[u8],
+        is_fin: bool,
+    ) -> io::Result<()> {
+        let mss = self.get_mtu(*dst.ip(), *src.ip()) - (Ipv4::minimum_len() + Tcp::minimum_len());
+        let segments = payload.chunks(mss).count();
+        self.count_retrans(dst, src, payload.len(), segments);
+
+        self.send_tcp_ack_raw(dst, src, sequence, payload, is_fin)
+    }
+
     /// Retransmits TCP ACK packets from the cache. This method is used for fast retransmission.
     pub fn retransmit_tcp_ack(&mut self, dst: SocketAddrV4, src: SocketAddrV4) -> io::Result<()> {
         let key = (src, dst);
+        self.count_retransmit(dst, src, false);
 
         // Retransmit
-        let state = self.states.get(&key).unwrap();
+        let state = self.states.get_mut(&key).unwrap();
+        state.cache_mut().set_retrans();
         let payload = state.cache().get_all();
         let sequence = state.cache().sequence();
         let size = state.cache().len();
@@ -696,7 +1781,7 @@ impl Forwarder {
                 );
 
                 // Send
-                self.send_tcp_ack_raw(dst, src, sequence, payload.as_slice(), true)?;
+                self.retransmit_tcp_ack_raw(dst, src, sequence, payload.as_slice(), true)?;
             } else {
                 // ACK
                 trace!(
@@ -708,7 +1793,7 @@ impl Forwarder {
                 );
 
                 // Send
-                self.send_tcp_ack_raw(dst, src, sequence, payload.as_slice(), false)?;
+                self.retransmit_tcp_ack_raw(dst, src, sequence, payload.as_slice(), false)?;
             }
         }
 
@@ -724,8 +1809,10 @@ impl Forwarder {
         sacks: Vec<(u32, u32)>,
     ) -> io::Result<()> {
         let key = (src, dst);
+        self.count_retransmit(dst, src, false);
 
-        let state = self.states.get(&key).unwrap();
+        let state = self.states.get_mut(&key).unwrap();
+        state.cache_mut().set_retrans();
         let sequence = state.cache().sequence();
         let recv_next = state.cache().recv_next();
 
@@ -747,10 +1834,7 @@ impl Forwarder {
 
         // Retransmit
         for range in &ranges {
-            let size = range
-                .1
-                .checked_sub(range.0)
-                .unwrap_or_else(|| range.1 + (u32::MAX - range.0)) as usize;
+            let size = seq_sub(range.1, range.0) as usize;
             let state = self.states.get(&key).unwrap();
             let payload = state.cache().get(range.0, size)?;
             if payload.len() > 0 {
@@ -765,7 +1849,7 @@ impl Forwarder {
                     );
 
                     // Send
-                    self.send_tcp_ack_raw(dst, src, range.0, payload.as_slice(), true)?;
+                    self.retransmit_tcp_ack_raw(dst, src, range.0, payload.as_slice(), true)?;
                 } else {
                     // ACK
                     trace!(
@@ -777,7 +1861,7 @@ impl Forwarder {
                     );
 
                     // Send
-                    self.send_tcp_ack_raw(dst, src, range.0, payload.as_slice(), false)?;
+                    self.retransmit_tcp_ack_raw(dst, src, range.0, payload.as_slice(), false)?;
                 }
             }
         }
@@ -789,6 +1873,7 @@ impl Forwarder {
             trace!("retransmit TCP FIN {} -> {}", dst, src);
 
             // Send
+            self.count_retrans(dst, src, 0, 1);
             self.send_tcp_fin(dst, src)?;
         }
 
@@ -802,17 +1887,51 @@ impl Forwarder {
         dst: SocketAddrV4,
         src: SocketAddrV4,
     ) -> io::Result<()> {
+        // SYN/ACK
+        let state = self.get_state(dst, src).unwrap();
+        if let Some(instant) = state.cache_syn() {
+            if state.cache_syn_retries < state.max_syn_retries
+                && clock::elapsed(instant) >= Duration::from_millis(state.rto())
+            {
+                // Double RTO
+                state.double_rto();
+                state.update_syn_timer();
+                state.cache_syn_retries += 1;
+                trace!(
+                    "retransmit TCP ACK/SYN {} -> {} due to timeout ({} retries)",
+                    dst,
+                    src,
+                    state.cache_syn_retries
+                );
+
+                // Send
+                self.count_retransmit(dst, src, true);
+                self.count_retrans(dst, src, 0, 1);
+                self.send_tcp_ack_syn(dst, src)?;
+            }
+
+            return Ok(());
+        }
+
         let state = self.get_state(dst, src).unwrap();
         let next_rto = state.rto().checked_mul(2).unwrap_or(u64::MAX);
         let payload = state
             .cache_mut()
-            .get_timed_out_and_update(max(MAX_RTO, min(MIN_RTO, next_rto)));
+            .get_timed_out_and_update(min(MAX_RTO, max(MIN_RTO, next_rto)));
         let sequence = state.cache().sequence();
         let size = state.cache().len();
 
         if size > 0 {
             // Double RTO
             state.double_rto();
+            if !payload.is_empty() {
+                // A lost probe of the path MTU does not indicate a black hole
+                if !self.fail_mtu_probe(dst, src) {
+                    self.detect_black_hole(dst, src, payload.len());
+                }
+                self.count_retransmit(dst, src, true);
+            }
+            let state = self.get_state(dst, src).unwrap();
 
             // If all the cache is get, the FIN should also be sent
             if size == payload.len() && state.cache_fin().is_some() {
@@ -827,7 +1946,7 @@ impl Forwarder {
                 );
 
                 // Send
-                self.send_tcp_ack_raw(dst, src, sequence, payload.as_slice(), true)?;
+                self.retransmit_tcp_ack_raw(dst, src, sequence, payload.as_slice(), true)?;
             } else {
                 // ACK
                 trace!(
@@ -839,6 +1958,23 @@ impl Forwarder {
                 );
 
                 // Send
+                self.retransmit_tcp_ack_raw(dst, src, sequence, payload.as_slice(), false)?;
+            }
+        } else if let Some(timer) = state.probe() {
+            // Zero window probe
+            if timer.is_timedout() && !state.queue().is_empty() {
+                let payload = state.append_cache(1)?;
+                trace!("probe TCP window {} -> {} at {}", dst, src, sequence);
+                if let Some(ref tracer) = self.tracer {
+                    tracer
+                        .lock()
+                        .unwrap()
+                        .event(src, dst, tcptrace::Event::Probe);
+                }
+
+                // Send, the probe will be retransmitted with backoff as usual
+                let state = self.get_state(dst, src).unwrap();
+                state.probe = None;
                 self.send_tcp_ack_raw(dst, src, sequence, payload.as_slice(), false)?;
             }
         } else {
@@ -851,6 +1987,8 @@ impl Forwarder {
                     trace!("retransmit TCP FIN {} -> {} due to timeout", dst, src);
 
                     // Send
+                    self.count_retransmit(dst, src, true);
+                    self.count_retrans(dst, src, 0, 1);
                     self.send_tcp_fin(dst, src)?;
                 }
             }
@@ -864,30 +2002,77 @@ impl Forwarder {
         let key = (src, dst);
 
         // Retransmit unhandled SYN
-        let state = self.states.get(&key).unwrap();
+        let state = self.states.get_mut(&key).unwrap();
         if state.cache_syn().is_some() {
-            return self.send_tcp_ack_syn(dst, src);
+            state.update_syn_timer();
+            self.send_tcp_ack_syn(dst, src)?;
+            self.update_tcp_timer(dst, src);
+
+            return Ok(());
+        }
+        let state = self.states.get(&key).unwrap();
+
+        // Hold back data beyond the window, and probe the window if it is closed
+        let remain_size = state.send_window_remaining();
+        if remain_size == 0 && !state.queue().is_empty() && state.cache().is_empty() {
+            let state = self.get_state(dst, src).unwrap();
+            state.update_probe_timer();
+            self.update_tcp_timer(dst, src);
+
+            return Ok(());
+        }
+
+        // Probe the path MTU with the first segment, and send the rest later
+        if remain_size > 0 && self.send_tcp_mtu_probe(dst, src, remain_size)? {
+            return self.send_tcp_ack(dst, src);
         }
 
-        if state.send_window() > 0 {
+        if remain_size > 0 {
             // TCP sequence
-            let sent_size = state.cache().len();
-            let remain_size = state.send_window().checked_sub(sent_size).unwrap_or(0);
+            let state = self.states.get(&key).unwrap();
             let remain_size = min(remain_size, u16::MAX as usize) as u16;
 
             let mut size = min(remain_size as usize, state.queue().len());
+            let mtu = self.get_mtu(*dst.ip(), *src.ip());
+            let mss = mtu - (Ipv4::minimum_len() + Tcp::minimum_len());
             // Avoid SWS
             if ENABLE_SEND_SWS_AVOID {
-                let mtu = *self.src_mtu.get(src.ip()).unwrap_or(&self.local_mtu);
-                let mss = mtu - (Ipv4::minimum_len() + Tcp::minimum_len());
-
                 if size < mss && !state.cache().is_empty() {
                     size = 0;
                 }
             }
+            // Pace segments over the SRTT, and send the rest of the queue later
+            let state = self.get_state(dst, src).unwrap();
+            let budget = state.pacing_budget(mss);
+            if size > budget {
+                size = budget - budget % mss;
+                state.defer_pacing(mss);
+                self.update_tcp_timer(dst, src);
+            }
+            // Shape the queue by the class of the connection, and send the rest later
+            let qos = self.qos_class(PortProtocol::Tcp, src, dst);
+            if let Some((ref shaper, class)) = qos {
+                let mut shaper = shaper.lock().unwrap();
+                let budget = shaper.budget(class, QosDirection::Download);
+                if size > budget {
+                    let delay = shaper.delay(class, QosDirection::Download, min(size, mss));
+                    size = budget - budget % mss;
+                    drop(shaper);
+                    let state = self.get_state(dst, src).unwrap();
+                    state.defer_shaping(delay);
+                    self.update_tcp_timer(dst, src);
+                }
+            }
             let size = size;
             if size > 0 {
+                if let Some((ref shaper, class)) = qos {
+                    shaper
+                        .lock()
+                        .unwrap()
+                        .reserve(class, QosDirection::Download, size);
+                }
                 let state = self.get_state(dst, src).unwrap();
+                state.consume_pacing_budget(size);
                 let payload = state.append_cache(size)?;
 
                 // If the queue is empty and a FIN is in the queue, pop it
@@ -931,25 +2116,34 @@ impl Forwarder {
         sequence: u32,
         payload: &[u8],
         is_fin: bool,
+    ) -> io::Result<()> {
+        let mss = self.get_mtu(*dst.ip(), *src.ip()) - (Ipv4::minimum_len() + Tcp::minimum_len());
+
+        self.send_tcp_segments(dst, src, sequence, payload, is_fin, mss)
+    }
+
+    /// Sends TCP ACK packets carrying the payload in segments of the MSS.
+    fn send_tcp_segments(
+        &mut self,
+        dst: SocketAddrV4,
+        src: SocketAddrV4,
+        sequence: u32,
+        payload: &[u8],
+        is_fin: bool,
+        mss: usize,
     ) -> io::Result<()> {
         let key = (src, dst);
 
         // Segmentation
-        let mss = *self.src_mtu.get(src.ip()).unwrap_or(&self.local_mtu)
-            - (Ipv4::minimum_len() + Tcp::minimum_len());
         let mut i = 0;
         while mss * i < payload.len() {
             let state = self.states.get(&key).unwrap();
             let size = min(mss, payload.len() - i * mss);
-            let payload = &payload[i * mss..i * mss + size];
-            let sequence = sequence
-                .checked_add((i * mss) as u32)
-                .unwrap_or_else(|| (i * mss) as u32 - (u32::MAX - sequence));
-            let mut recv_next = sequence
-                .checked_add(size as u32)
-                .unwrap_or_else(|| size as u32 - (u32::MAX - sequence));
-
-            // TCP
+            let segment = &payload[i * mss..i * mss + size];
+            let sequence = seq_add(sequence, (i * mss) as u32);
+            let mut recv_next = seq_add(sequence, size as u32);
+
+            // TCP, only the last segment carries the FIN
             let tcp;
             if is_fin && mss * (i + 1) >= payload.len() {
                 // ACK/FIN
@@ -961,7 +2155,7 @@ impl Forwarder {
                     self.get_tcp_window(dst, src),
                     None,
                 );
-                recv_next = recv_next.checked_add(1).unwrap_or(0);
+                recv_next = seq_add(recv_next, 1);
             } else {
                 // ACK
                 tcp = Tcp::new_ack(
@@ -980,25 +2174,56 @@ impl Forwarder {
                 dst.ip().clone(),
                 src.ip().clone(),
                 Layers::Tcp(tcp),
-                Some(payload),
+                Some(segment),
             )?;
 
             // Update TCP sequence
             let state = self.get_state(dst, src).unwrap();
             let record_sequence = state.sequence();
-            let sub_sequence = recv_next
-                .checked_sub(record_sequence)
-                .unwrap_or_else(|| recv_next + (u32::MAX - record_sequence));
+            let sub_sequence = seq_sub(recv_next, record_sequence);
             if (sub_sequence as usize) <= MAX_U32_WINDOW_SIZE {
                 state.add_sequence(sub_sequence);
             }
 
             i = i + 1;
         }
+        self.update_tcp_timer(dst, src);
 
         Ok(())
     }
 
+    /// Sends an ICMPv4 time to live exceeded in transit packet. The payload should be the IPv4
+    /// header and the first 8 bytes of the data of the original datagram.
+    #[cfg(feature = "icmp")]
+    pub fn send_icmpv4_time_exceeded(
+        &mut self,
+        dst_ip_addr: Ipv4Addr,
+        src_ip_addr: Ipv4Addr,
+        payload: &[u8],
+    ) -> io::Result<()> {
+        // ICMPv4
+        let icmpv4 = Icmpv4::new_time_exceeded(payload);
+
+        // Send
+        self.send_ipv4_with_transport(dst_ip_addr, src_ip_addr, Layers::Icmpv4(icmpv4), None)
+    }
+
+    /// Sends an ICMPv4 echo reply to the echo request, which carries the identifier, the sequence
+    /// number and the data of the request.
+    #[cfg(feature = "icmp")]
+    pub fn send_icmpv4_echo_reply(
+        &mut self,
+        dst_ip_addr: Ipv4Addr,
+        src_ip_addr: Ipv4Addr,
+        request: &Icmpv4,
+    ) -> io::Result<()> {
+        // ICMPv4
+        let icmpv4 = Icmpv4::new_echo_reply_to(request);
+
+        // Send
+        self.send_ipv4_with_transport(dst_ip_addr, src_ip_addr, Layers::Icmpv4(icmpv4), None)
+    }
+
     /// Sends an TCP ACK packet without payload.
     pub fn send_tcp_ack_0(&mut self, dst: SocketAddrV4, src: SocketAddrV4) -> io::Result<()> {
         let key = (src, dst);
@@ -1024,7 +2249,8 @@ impl Forwarder {
 
         let mss = match ENABLE_MSS {
             true => {
-                let mss = self.local_mtu - (Ipv4::minimum_len() + Tcp::minimum_len());
+                let mss =
+                    self.get_mtu(*dst.ip(), *src.ip()) - (Ipv4::minimum_len() + Tcp::minimum_len());
                 let mss = if mss > u16::MAX as usize {
                     u16::MAX
                 } else {
@@ -1075,6 +2301,43 @@ impl Forwarder {
         self.send_ipv4_with_transport(dst.ip().clone(), src.ip().clone(), Layers::Tcp(tcp), None)
     }
 
+    /// Sends an TCP ACK/RST packet in reply to a TCP SYN packet which has no state.
+    pub fn send_tcp_rst_to_syn(
+        &mut self,
+        dst: SocketAddrV4,
+        src: SocketAddrV4,
+        acknowledgement: u32,
+    ) -> io::Result<()> {
+        // TCP
+        let tcp = Tcp::new_ack_rst(dst.port(), src.port(), 0, acknowledgement, 0, None);
+
+        // Send
+        self.send_ipv4_with_transport(dst.ip().clone(), src.ip().clone(), Layers::Tcp(tcp), None)
+    }
+
+    /// Sends an TCP ACK packet without payload for a TCP connection which has no state.
+    pub fn send_tcp_ack_to(
+        &mut self,
+        dst: SocketAddrV4,
+        src: SocketAddrV4,
+        sequence: u32,
+        acknowledgement: u32,
+    ) -> io::Result<()> {
+        // TCP
+        let tcp = Tcp::new_ack(
+            dst.port(),
+            src.port(),
+            sequence,
+            acknowledgement,
+            0,
+            None,
+            None,
+        );
+
+        // Send
+        self.send_ipv4_with_transport(dst.ip().clone(), src.ip().clone(), Layers::Tcp(tcp), None)
+    }
+
     /// Sends an TCP RST packet.
     pub fn send_tcp_rst(&mut self, dst: SocketAddrV4, src: SocketAddrV4) -> io::Result<()> {
         // TCP
@@ -1099,19 +2362,55 @@ impl Forwarder {
         );
 
         // Send
-        self.send_ipv4_with_transport(dst.ip().clone(), src.ip().clone(), Layers::Tcp(tcp), None)
+        self.send_ipv4_with_transport(dst.ip().clone(), src.ip().clone(), Layers::Tcp(tcp), None)?;
+        self.update_tcp_timer(dst, src);
+
+        Ok(())
     }
 
     /// Sends UDP packets.
+    #[cfg(feature = "udp")]
     pub fn send_udp(
         &mut self,
         dst: SocketAddrV4,
         src: SocketAddrV4,
         payload: &[u8],
     ) -> io::Result<()> {
+        // Drop datagrams beyond the bucket of the class of the flow
+        if let Some((shaper, class)) = self.qos_class(PortProtocol::Udp, src, dst) {
+            let mut shaper = shaper.lock().unwrap();
+            if shaper.budget(class, QosDirection::Download) < payload.len() {
+                trace!(
+                    "drop UDP {} -> {} ({} Bytes) for shaping",
+                    dst,
+                    src,
+                    payload.len()
+                );
+                if let Some(ref stats) = self.stats {
+                    stats.increase_qos_drops();
+                }
+
+                return Ok(());
+            }
+            shaper.reserve(class, QosDirection::Download, payload.len());
+        }
+
+        // Track QUIC connection IDs issued by the server
+        if let Some(ref quic_conn_ids) = self.quic_conn_ids {
+            if let Some(QuicHeader::Long { src_conn_id, .. }) = QuicHeader::parse(payload) {
+                let mut quic_conn_ids = quic_conn_ids.lock().unwrap();
+                if quic_conn_ids.track(src, &src_conn_id) {
+                    if let Some(ref stats) = self.stats {
+                        stats.set_quic_sessions(quic_conn_ids.len());
+                    }
+                    trace!("track QUIC connection ID of {}", src);
+                }
+            }
+        }
+
         // Fragmentation
         let size = Udp::minimum_len() + payload.len();
-        let mss = *self.src_mtu.get(src.ip()).unwrap_or(&self.local_mtu) - Ipv4::minimum_len();
+        let mss = self.get_mtu(*dst.ip(), *src.ip()) - Ipv4::minimum_len();
         if size <= mss {
             // Send
             self.send_udp_raw(dst, src, payload)?;
@@ -1170,6 +2469,7 @@ impl Forwarder {
         Ok(())
     }
 
+    #[cfg(feature = "udp")]
     fn send_udp_raw(
         &mut self,
         dst: SocketAddrV4,
@@ -1187,6 +2487,7 @@ impl Forwarder {
         )
     }
 
+    #[cfg(feature = "udp")]
     fn send_ipv4_with_fragment(
         &mut self,
         dst_ip_addr: Ipv4Addr,
@@ -1220,6 +2521,7 @@ impl Forwarder {
         )
     }
 
+    #[cfg(feature = "udp")]
     fn send_ipv4_with_last_fragment(
         &mut self,
         dst_ip_addr: Ipv4Addr,
@@ -1308,12 +2610,25 @@ impl Forwarder {
         transport: Option<Layers>,
         payload: Option<&[u8]>,
     ) -> io::Result<()> {
+        // PPPoE
+        let session = match network.kind() {
+            LayerKinds::Ipv4 => self.pppoe_sessions.get(&src_hardware_addr).copied(),
+            _ => None,
+        };
+
         // Ethernet
-        let ethernet =
-            Ethernet::new(network.kind(), self.local_hardware_addr, src_hardware_addr).unwrap();
+        let ethernet = match session {
+            Some((_, ac_hardware_addr)) => {
+                self.new_ethernet(LayerKinds::Pppoe, ac_hardware_addr, src_hardware_addr)
+            }
+            None => self.new_ethernet(network.kind(), self.local_hardware_addr, src_hardware_addr),
+        };
 
         // Indicator
-        let indicator = Indicator::new(Layers::Ethernet(ethernet), Some(network), transport);
+        let mut indicator = Indicator::new(Layers::Ethernet(ethernet), Some(network), transport);
+        if let Some((session_id, _)) = session {
+            indicator.set_pppoe(Pppoe::new(session_id));
+        }
 
         // Send
         match payload {
@@ -1322,15 +2637,75 @@ impl Forwarder {
         }
     }
 
+    /// Sends an IPv4 packet as is to the source.
+    pub(crate) fn send_ipv4_packet(&mut self, packet: &[u8]) -> io::Result<()> {
+        if packet.len() < 20 {
+            return Err(io::Error::from(io::ErrorKind::InvalidData));
+        }
+        let dst_ip_addr = Ipv4Addr::new(packet[16], packet[17], packet[18], packet[19]);
+
+        let dst_hardware_addr = *self
+            .src_hardware_addr
+            .get(&dst_ip_addr)
+            .unwrap_or(&pcap::HARDWARE_ADDR_UNSPECIFIED);
+        let session = self.get_pppoe_session(dst_ip_addr);
+
+        // Ethernet
+        let ethernet = match session {
+            Some((_, ac_hardware_addr)) => {
+                self.new_ethernet(LayerKinds::Pppoe, ac_hardware_addr, dst_hardware_addr)
+            }
+            None => self.new_ethernet(
+                LayerKinds::Ipv4,
+                self.local_hardware_addr,
+                dst_hardware_addr,
+            ),
+        };
+
+        // Serialize
+        let mut size = ethernet.len();
+        let pppoe = session.map(|(session_id, _)| Pppoe::new(session_id));
+        if let Some(ref pppoe) = pppoe {
+            size += pppoe.len();
+        }
+        let buffer_size = max(size + packet.len(), MINIMUM_FRAME_SIZE);
+        let mut buffer = vec![0u8; buffer_size];
+        let m = ethernet.serialize(&mut buffer[..size], size)?;
+        if let Some(ref pppoe) = pppoe {
+            pppoe.serialize(&mut buffer[m..size], size - m + packet.len())?;
+        }
+        buffer[size..size + packet.len()].copy_from_slice(packet);
+
+        // Send
+        let priority = if size + packet.len() <= SMALL_FRAME_SIZE {
+            TxPriority::High
+        } else {
+            TxPriority::Low
+        };
+        self.send_to(buffer, priority)?;
+        debug!(
+            "send to pcap: {} ({} + {} Bytes)",
+            ethernet,
+            size,
+            packet.len()
+        );
+
+        Ok(())
+    }
+
     fn send(&mut self, indicator: &Indicator) -> io::Result<()> {
         // Serialize
         let size = indicator.len();
         let buffer_size = max(size, MINIMUM_FRAME_SIZE);
         let mut buffer = vec![0u8; buffer_size];
         indicator.serialize(&mut buffer[..size])?;
+        self.record(indicator, &buffer);
 
         // Send
-        self.tx.send_to(&buffer, None).unwrap_or(Ok(()))?;
+        let priority = self.tx_priority(indicator, 0);
+        self.send_to(buffer, priority)?;
+        #[cfg(feature = "metrics")]
+        self.track(indicator, size);
         debug!("send to pcap: {} ({} Bytes)", indicator.brief(), size);
 
         Ok(())
@@ -1342,9 +2717,13 @@ impl Forwarder {
         let buffer_size = max(size + payload.len(), MINIMUM_FRAME_SIZE);
         let mut buffer = vec![0u8; buffer_size];
         indicator.serialize_with_payload(&mut buffer[..size + payload.len()], payload)?;
+        self.record(indicator, &buffer);
 
         // Send
-        self.tx.send_to(&buffer, None).unwrap_or(Ok(()))?;
+        let priority = self.tx_priority(indicator, payload.len());
+        self.send_to(buffer, priority)?;
+        #[cfg(feature = "metrics")]
+        self.track(indicator, size + payload.len());
         debug!(
             "send to pcap: {} ({} + {} Bytes)",
             indicator.brief(),
@@ -1354,60 +2733,250 @@ impl Forwarder {
 
         Ok(())
     }
-}
-
-impl ForwardStream for Forwarder {
-    fn open(&mut self, dst: SocketAddrV4, src: SocketAddrV4) -> io::Result<()> {
-        self.send_tcp_ack_syn(dst, src)?;
-
-        let state = self.get_state(dst, src).unwrap();
-        state.update_syn_timer();
-
-        Ok(())
-    }
-
-    fn forward(&mut self, dst: SocketAddrV4, src: SocketAddrV4, payload: &[u8]) -> io::Result<()> {
-        let key = (src, dst);
 
-        let state = self.states.get(&key).unwrap();
-        if state.cache_fin().is_some() || state.queue_fin() {
-            return Err(io::Error::from(io::ErrorKind::InvalidData));
+    #[cfg(feature = "metrics")]
+    fn track(&self, indicator: &Indicator, size: usize) {
+        if let Some(ref tracker) = self.tracker {
+            if let Some(ipv4) = indicator.ipv4() {
+                let connection = indicator.tcp().map(|tcp| {
+                    (
+                        SocketAddrV4::new(tcp.dst_ip_addr(), tcp.dst()),
+                        SocketAddrV4::new(tcp.src_ip_addr(), tcp.src()),
+                    )
+                });
+                tracker.lock().unwrap().add_tx(ipv4.dst(), connection, size);
+            }
         }
-
-        self.append_to_queue(dst, src, payload)
     }
 
-    fn tick(&mut self, dst: SocketAddrV4, src: SocketAddrV4) -> io::Result<()> {
-        self.retransmit_tcp_ack_timedout(dst, src)
+    fn record(&self, indicator: &Indicator, buffer: &[u8]) {
+        if let Some(ref recorder) = self.recorder {
+            if let Some(tcp) = indicator.tcp() {
+                recorder.lock().unwrap().record(
+                    SocketAddrV4::new(tcp.dst_ip_addr(), tcp.dst()),
+                    SocketAddrV4::new(tcp.src_ip_addr(), tcp.src()),
+                    buffer,
+                    false,
+                );
+            }
+        }
+        if let Some(ref tracer) = self.tracer {
+            if let Some(tcp) = indicator.tcp() {
+                tracer.lock().unwrap().segment(
+                    SocketAddrV4::new(tcp.dst_ip_addr(), tcp.dst()),
+                    SocketAddrV4::new(tcp.src_ip_addr(), tcp.src()),
+                    tcp,
+                    indicator.content_len() - indicator.len(),
+                    false,
+                );
+            }
+        }
+    }
+
+    /// Returns the priority of a frame sent to the source. Small frames, pure TCP ACKs and
+    /// frames from priority ports are of high priority.
+    fn tx_priority(&self, indicator: &Indicator, payload_len: usize) -> TxPriority {
+        if indicator.len() + payload_len <= SMALL_FRAME_SIZE {
+            return TxPriority::High;
+        }
+        let port = if let Some(tcp) = indicator.tcp() {
+            if payload_len == 0 {
+                return TxPriority::High;
+            }
+            tcp.src()
+        } else if let Some(udp) = indicator.udp() {
+            udp.src()
+        } else {
+            return TxPriority::Low;
+        };
+
+        if self.priority_ports.contains(&port) {
+            TxPriority::High
+        } else {
+            TxPriority::Low
+        }
+    }
+
+    fn send_to(&mut self, buffer: Vec<u8>, priority: TxPriority) -> io::Result<()> {
+        // Middlewares
+        let buffer = match self.middlewares {
+            Some(ref middlewares) => {
+                match middlewares.lock().unwrap().on_tx_frame(Cow::Owned(buffer)) {
+                    Some(buffer) => buffer,
+                    None => return Ok(()),
+                }
+            }
+            None => Cow::Owned(buffer),
+        };
+        // Frames sent by the task stepping a `Redirector` are recorded
+        let _ = EMITTED.try_with(|emitted| emitted.lock().unwrap().push(buffer.to_vec()));
+
+        // Schedule
+        if let Some(ref mut scheduler) = self.scheduler {
+            if !scheduler.push(buffer.into_owned(), priority) {
+                trace!("drop frame to pcap because the TX queue is full");
+                if let Some(ref stats) = self.stats {
+                    stats.increase_tx_drops();
+                }
+
+                return Ok(());
+            }
+
+            return self.flush_tx();
+        }
+
+        self.tx.send_to(&buffer, None).unwrap_or(Ok(()))?;
+        if let Some(ref stats) = self.stats {
+            stats.add_tx_bytes(buffer.len());
+        }
+
+        Ok(())
+    }
+
+    /// Sends the frames waiting in the TX scheduler, high priority ones first, until the pcap
+    /// device is busy.
+    pub fn flush_tx(&mut self) -> io::Result<()> {
+        let _clock = clock::enter(self.clock.clone());
+        loop {
+            let (frame, priority) = match self.scheduler.as_mut().and_then(|s| s.pop()) {
+                Some(frame) => frame,
+                None => return Ok(()),
+            };
+            match self.tx.send_to(&frame, None).unwrap_or(Ok(())) {
+                Ok(()) => {
+                    if let Some(ref stats) = self.stats {
+                        stats.add_tx_bytes(frame.len());
+                    }
+                }
+                Err(ref e) if scheduler::is_busy(e) => {
+                    if let Some(ref mut scheduler) = self.scheduler {
+                        scheduler.push_front(frame, priority);
+                    }
+                    // Wake up the timer driver to send the rest later
+                    self.timer_notify.notify();
+
+                    return Ok(());
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Returns if there is any frame waiting in the TX scheduler.
+    pub fn has_tx_backlog(&self) -> bool {
+        match self.scheduler {
+            Some(ref scheduler) => !scheduler.is_empty(),
+            None => false,
+        }
+    }
+}
+
+impl Forwarder {
+    fn open_stream(&mut self, dst: SocketAddrV4, src: SocketAddrV4) -> io::Result<()> {
+        self.send_tcp_ack_syn(dst, src)?;
+
+        let state = self.get_state(dst, src).unwrap();
+        state.update_syn_timer();
+        self.update_tcp_timer(dst, src);
+
+        Ok(())
+    }
+
+    fn forward_stream(
+        &mut self,
+        dst: SocketAddrV4,
+        src: SocketAddrV4,
+        payload: &[u8],
+    ) -> io::Result<()> {
+        let key = (src, dst);
+
+        let state = self.states.get(&key).unwrap();
+        if state.cache_fin().is_some() || state.queue_fin() {
+            return Err(io::Error::from(io::ErrorKind::InvalidData));
+        }
+
+        self.append_to_queue(dst, src, payload)
     }
 
-    fn close(&mut self, dst: SocketAddrV4, src: SocketAddrV4) -> io::Result<()> {
+    fn stream_queue_size(&self, dst: SocketAddrV4, src: SocketAddrV4) -> usize {
+        let key = (src, dst);
+
+        match self.states.get(&key) {
+            Some(state) => state.queue().len(),
+            None => 0,
+        }
+    }
+
+    fn close_stream(&mut self, dst: SocketAddrV4, src: SocketAddrV4) -> io::Result<()> {
         let state = self.get_state(dst, src).unwrap();
         state.append_queue_fin();
+        if let Some(ref flows) = self.flows {
+            flows.send(FlowEvent::HalfClosed {
+                src,
+                dst,
+                by_source: false,
+            });
+        }
 
         self.send_tcp_ack(dst, src)
     }
 }
 
-impl ForwardDatagram for Forwarder {
-    fn forward(&mut self, dst: SocketAddrV4, src: SocketAddrV4, payload: &[u8]) -> io::Result<()> {
-        self.send_udp(dst, src, payload)
+// The forwarder never awaits, so each call completes under the lock before its future is returned
+impl ForwardStream for Mutex<Forwarder> {
+    fn open(&self, dst: SocketAddrV4, src: SocketAddrV4) -> ForwardFuture<'_> {
+        let result = self.lock().unwrap().open_stream(dst, src);
+
+        Box::pin(async move { result })
+    }
+
+    fn forward<'a>(
+        &'a self,
+        dst: SocketAddrV4,
+        src: SocketAddrV4,
+        payload: &'a [u8],
+    ) -> ForwardFuture<'a> {
+        let result = self.lock().unwrap().forward_stream(dst, src, payload);
+
+        Box::pin(async move { result })
+    }
+
+    fn queue_size(&self, dst: SocketAddrV4, src: SocketAddrV4) -> usize {
+        self.lock().unwrap().stream_queue_size(dst, src)
+    }
+
+    fn close(&self, dst: SocketAddrV4, src: SocketAddrV4) -> ForwardFuture<'_> {
+        let result = self.lock().unwrap().close_stream(dst, src);
+
+        Box::pin(async move { result })
+    }
+}
+
+#[cfg(feature = "udp")]
+impl ForwardDatagram for Mutex<Forwarder> {
+    fn forward<'a>(
+        &'a self,
+        dst: SocketAddrV4,
+        src: SocketAddrV4,
+        payload: &'a [u8],
+    ) -> ForwardFuture<'a> {
+        let result = self.lock().unwrap().send_udp(dst, src, payload);
+
+        Box::pin(async move { result })
+    }
+}
+
+#[cfg(feature = "wireguard")]
+impl ForwardPacket for Forwarder {
+    fn forward(&mut self, packet: &[u8]) -> io::Result<()> {
+        self.send_ipv4_packet(packet)
     }
 }
 
 fn disjoint_u32_range(main: (u32, u32), sub: (u32, u32)) -> Vec<(u32, u32)> {
-    let size_main = main
-        .1
-        .checked_sub(main.0)
-        .unwrap_or_else(|| main.1 + (u32::MAX - main.0)) as usize;
-    let diff_first = sub
-        .0
-        .checked_sub(main.0)
-        .unwrap_or_else(|| sub.0 + (u32::MAX - main.0)) as usize;
-    let diff_second = sub
-        .1
-        .checked_sub(main.1)
-        .unwrap_or_else(|| sub.1 + (u32::MAX - main.1)) as usize;
+    let size_main = seq_sub(main.1, main.0) as usize;
+    let diff_first = seq_sub(sub.0, main.0) as usize;
+    let diff_second = seq_sub(sub.1, main.1) as usize;
     let mut vector = Vec::with_capacity(2);
 
     if diff_first <= MAX_U32_WINDOW_SIZE {
@@ -1427,10 +2996,7 @@ fn disjoint_u32_range(main: (u32, u32), sub: (u32, u32)) -> Vec<(u32, u32)> {
     } else {
         if diff_second > MAX_U32_WINDOW_SIZE {
             // The distance between the main's left edge and the sub's right edge
-            let diff = sub
-                .1
-                .checked_sub(main.0)
-                .unwrap_or_else(|| sub.1 + (u32::MAX - main.0)) as usize;
+            let diff = seq_sub(sub.1, main.0) as usize;
             if diff > MAX_U32_WINDOW_SIZE {
                 // sub is in the left of the main
                 vector.push((main.0, main.1));
@@ -1451,6 +3017,17 @@ const DUPLICATES_THRESHOLD: usize = 3;
 /// Represents the cool down time between 2 retransmissions.
 const RETRANS_COOL_DOWN: u128 = 200;
 
+/// Represents the size of contiguous TCP payloads coalesced before writing to the stream.
+const COALESCE_SIZE: usize = 64 * 1024;
+/// Represents the max time in milliseconds TCP payloads are coalesced before writing to the
+/// stream.
+const COALESCE_TIME: u64 = 10;
+
+/// Represents the interval in milliseconds of measuring the rate the stream accepts data.
+const DRAIN_SAMPLE_INTERVAL: u64 = 100;
+/// Represents the time in milliseconds of draining the receive window at the measured rate.
+const DRAIN_WINDOW_TIME: u64 = 200;
+
 /// Represents the RX state of a TCP connection.
 struct TcpRxState {
     src: SocketAddrV4,
@@ -1458,11 +3035,32 @@ struct TcpRxState {
     recv_next: u32,
     last_acknowledgement: u32,
     duplicate: usize,
+    /// Represents the count of duplicate ACKs received.
+    duplicate_acks: u64,
+    /// Represents the last time a segment is received from the source.
+    active_instant: Instant,
+    /// Represents the sequence of the urgent byte to be stripped from the stream.
+    urgent: Option<u32>,
     last_retrans: Option<Instant>,
     wscale: u8,
+    /// Represents the unscaled receive window advertised to the source.
+    recv_window: u16,
+    /// Represents the window scale of the receive window.
+    recv_wscale: u8,
     sack_perm: bool,
     cache: Window,
     fin_sequence: Option<u32>,
+    /// Represents the contiguous payloads which are not written to the stream yet.
+    coalesced: Vec<u8>,
+    coalesce_instant: Instant,
+    /// Represents if a zero window is advertised and should be reopened by a window update.
+    is_window_closed: bool,
+    /// Represents the rate in bytes per second the stream accepts data.
+    drain_rate: Option<u64>,
+    drain_instant: Instant,
+    drained: u64,
+    /// Represents the bytes received out of order but dropped because of the limit.
+    out_of_order_drops: u64,
 }
 
 impl TcpRxState {
@@ -1474,7 +3072,7 @@ impl TcpRxState {
         wscale: u8,
         sack_perm: bool,
     ) -> TcpRxState {
-        let recv_next = sequence.checked_add(1).unwrap_or(0);
+        let recv_next = seq_add(sequence, 1);
 
         trace!("admit TCP SYN of {} -> {}", src, dst);
 
@@ -1484,19 +3082,43 @@ impl TcpRxState {
             recv_next,
             last_acknowledgement: 0,
             duplicate: 0,
+            duplicate_acks: 0,
+            active_instant: clock::now(),
+            urgent: None,
             last_retrans: None,
             wscale,
+            recv_window: RECV_WINDOW,
+            recv_wscale: wscale,
             sack_perm,
             cache: Window::with_capacity((RECV_WINDOW as usize) << wscale as usize, recv_next),
             fin_sequence: None,
+            coalesced: Vec::new(),
+            coalesce_instant: clock::now(),
+            is_window_closed: false,
+            drain_rate: None,
+            drain_instant: clock::now(),
+            drained: 0,
+            out_of_order_drops: 0,
         }
     }
 
+    /// Sets the unscaled receive window and its window scale. This must be called before any
+    /// payload is received, for the cache is reallocated for the scaled receive window.
+    fn set_recv_window(&mut self, window: u16, wscale: u8) {
+        self.recv_window = window;
+        self.recv_wscale = wscale;
+        self.cache = Window::with_capacity((window as usize) << wscale as usize, self.recv_next);
+        trace!(
+            "set TCP receive window of {} -> {} to {} (scale {})",
+            self.src,
+            self.dst,
+            window,
+            wscale
+        );
+    }
+
     fn add_recv_next(&mut self, n: u32) {
-        self.recv_next = self
-            .recv_next
-            .checked_add(n)
-            .unwrap_or_else(|| n - (u32::MAX - self.recv_next));
+        self.recv_next = seq_add(self.recv_next, n);
         trace!(
             "add TCP receive next of {} -> {} to {}",
             self.src,
@@ -1510,6 +3132,7 @@ impl TcpRxState {
     fn increase_duplicate(&mut self, acknowledgement: u32) -> bool {
         if self.last_acknowledgement == acknowledgement {
             self.duplicate = self.duplicate.checked_add(1).unwrap_or(usize::MAX);
+            self.duplicate_acks += 1;
             trace!(
                 "increase TCP duplicate of {} -> {} at {} to {}",
                 self.src,
@@ -1520,7 +3143,7 @@ impl TcpRxState {
 
             if self.duplicate >= DUPLICATES_THRESHOLD {
                 let is_cooled_down = match self.last_retrans {
-                    Some(ref instant) => instant.elapsed().as_millis() < RETRANS_COOL_DOWN,
+                    Some(ref instant) => clock::elapsed(*instant).as_millis() < RETRANS_COOL_DOWN,
                     None => false,
                 };
 
@@ -1545,7 +3168,7 @@ impl TcpRxState {
     }
 
     fn set_last_retrans(&mut self) {
-        self.last_retrans = Some(Instant::now());
+        self.last_retrans = Some(clock::now());
         trace!(
             "set TCP last retransmission of {} -> {}",
             self.src,
@@ -1553,6 +3176,23 @@ impl TcpRxState {
         );
     }
 
+    /// Returns if the sequence is in the receive window [receive next, receive next + window).
+    fn is_in_window(&self, sequence: u32) -> bool {
+        let end = seq_add(self.recv_next, self.cache.capacity() as u32);
+
+        seq_between(self.recv_next, sequence, end)
+    }
+
+    /// Returns if the segment is beyond the right edge of the receive window. Segments before the
+    /// receive next are retransmissions and are not beyond the window.
+    fn is_beyond_window(&self, sequence: u32, len: usize) -> bool {
+        let last = seq_sub(seq_add(sequence, len as u32), 1);
+
+        !self.is_in_window(sequence)
+            && !self.is_in_window(last)
+            && seq_sub(sequence, self.recv_next) as usize <= MAX_U32_WINDOW_SIZE
+    }
+
     fn append_cache(&mut self, sequence: u32, payload: &[u8]) -> io::Result<Option<Vec<u8>>> {
         trace!(
             "append {} Bytes to TCP cache of {} -> {}",
@@ -1563,6 +3203,153 @@ impl TcpRxState {
         self.cache.append(sequence, payload)
     }
 
+    /// Drops the out-of-order payloads received the earliest until their size is no more than
+    /// the limit, and returns the size of the dropped payloads.
+    fn limit_out_of_order(&mut self, limit: usize) -> usize {
+        let mut dropped = 0;
+        while self.cache.out_of_order_len() > limit {
+            dropped += self.cache.evict_oldest();
+        }
+        if dropped > 0 {
+            self.out_of_order_drops += dropped as u64;
+            trace!(
+                "drop {} Bytes out of order of TCP {} -> {}",
+                dropped,
+                self.src,
+                self.dst
+            );
+        }
+
+        dropped
+    }
+
+    fn coalesce(&mut self, payload: &[u8]) {
+        if self.coalesced.is_empty() {
+            self.coalesce_instant = clock::now();
+        }
+        self.coalesced.extend_from_slice(payload);
+        trace!(
+            "coalesce {} Bytes to TCP {} -> {} ({} Bytes)",
+            payload.len(),
+            self.src,
+            self.dst,
+            self.coalesced.len()
+        );
+    }
+
+    /// Returns if the coalesced payloads reach the size threshold or the time threshold.
+    fn is_coalesce_due(&self) -> bool {
+        self.coalesced.len() >= COALESCE_SIZE || self.is_coalesce_expired()
+    }
+
+    /// Returns if the coalesced payloads reach the time threshold.
+    fn is_coalesce_expired(&self) -> bool {
+        !self.coalesced.is_empty()
+            && clock::elapsed(self.coalesce_instant) >= Duration::from_millis(COALESCE_TIME)
+    }
+
+    fn take_coalesced(&mut self) -> Vec<u8> {
+        mem::replace(&mut self.coalesced, Vec::new())
+    }
+
+    /// Marks the urgent byte at the sequence, which will be stripped when it is written to the
+    /// stream. Only the latest urgent byte is kept, and urgent bytes already written are ignored.
+    fn set_urgent(&mut self, sequence: u32) {
+        if seq_sub(sequence, self.recv_next) as usize > MAX_U32_WINDOW_SIZE {
+            return;
+        }
+
+        self.urgent = Some(sequence);
+        trace!(
+            "set TCP urgent byte of {} -> {} at {}",
+            self.src,
+            self.dst,
+            sequence
+        );
+    }
+
+    /// Strips the urgent byte from the payload, which starts at the receive next.
+    fn strip_urgent(&mut self, payload: &mut Vec<u8>) {
+        let sequence = match self.urgent {
+            Some(sequence) => sequence,
+            None => return,
+        };
+
+        let offset = seq_sub(sequence, self.recv_next) as usize;
+        if offset < payload.len() {
+            payload.remove(offset);
+            self.urgent = None;
+            trace!(
+                "strip TCP urgent byte of {} -> {} at {}",
+                self.src,
+                self.dst,
+                sequence
+            );
+        } else if offset > MAX_U32_WINDOW_SIZE {
+            self.urgent = None;
+        }
+    }
+
+    /// Updates the rate the stream accepts data by the total size of the data written to the
+    /// stream. The rate only decreases if the stream has data not written, so an idle source does
+    /// not shrink the window.
+    fn update_drain_rate(&mut self, written: u64, write_queue_size: usize) {
+        let elapsed = clock::elapsed(self.drain_instant).as_millis() as u64;
+        if elapsed < DRAIN_SAMPLE_INTERVAL {
+            return;
+        }
+        let sample = written.saturating_sub(self.drained).saturating_mul(1000) / elapsed;
+        self.drain_instant = clock::now();
+        self.drained = written;
+
+        let rate = match self.drain_rate {
+            Some(rate) if sample < rate => {
+                if write_queue_size == 0 {
+                    return;
+                }
+                // RATE = (1 - 1/4) * RATE + 1/4 * SAMPLE
+                rate - rate / 4 + sample / 4
+            }
+            _ => sample,
+        };
+        self.drain_rate = Some(rate);
+        trace!(
+            "set TCP drain rate of {} -> {} to {} Bytes/s",
+            self.src,
+            self.dst,
+            rate
+        );
+    }
+
+    /// Returns the target size of the receive window, which can be drained in a period of time at
+    /// the measured rate, but is not less than the unscaled receive window.
+    fn target_window(&self) -> usize {
+        let target = match self.drain_rate {
+            Some(rate) => (rate.saturating_mul(DRAIN_WINDOW_TIME) / 1000) as usize,
+            None => 0,
+        };
+
+        min(
+            max(target, self.recv_window as usize),
+            self.cache.capacity(),
+        )
+    }
+
+    /// Returns the receive window, which is limited by the remaining space of the cache, the
+    /// target size of the receive window, and the space of the write queue of the stream if the
+    /// limit is not 0.
+    fn window(&self, write_queue_size: usize, write_limit: usize) -> u16 {
+        let mut remaining = min(self.cache.remaining(), self.target_window());
+        if write_limit > 0 {
+            remaining = min(
+                remaining,
+                write_limit.saturating_sub(write_queue_size + self.coalesced.len()),
+            );
+        }
+
+        (remaining >> self.recv_wscale as usize) as u16
+    }
+
     fn set_fin_sequence(&mut self, sequence: u32) {
         self.fin_sequence = Some(sequence);
         trace!(
@@ -1585,661 +3372,5221 @@ impl Display for TcpRxState {
     }
 }
 
-/// Represents if the TCP window scale option is enabled.
-const ENABLE_WSCALE: bool = true;
-/// Represents the max window scale of the receive window.
-const MAX_RECV_WSCALE: u8 = 8;
+/// Represents the RX side of TCP connections, which is shared with the timer driver so coalesced
+/// payloads are written and windows are reopened while the frame loop is waiting for frames.
+struct TcpRx {
+    streams: PacketMap<(SocketAddrV4, SocketAddrV4), StreamWorker>,
+    states: PacketMap<(SocketAddrV4, SocketAddrV4), TcpRxState>,
+    write_limit: usize,
+    middlewares: Option<Arc<Mutex<Middlewares>>>,
+    /// Represents the connections failing to write to the stream, which are cleaned up in the
+    /// frame loop.
+    failed: Vec<(SocketAddrV4, SocketAddrV4)>,
+    timer_notify: Arc<Notify>,
+}
 
-/// Represents if the TCP selective acknowledgment option is enabled.
-const ENABLE_SACK: bool = true;
+impl TcpRx {
+    fn new(write_limit: usize, timer_notify: Arc<Notify>) -> TcpRx {
+        TcpRx {
+            streams: PacketMap::default(),
+            states: PacketMap::default(),
+            write_limit,
+            middlewares: None,
+            failed: Vec::new(),
+            timer_notify,
+        }
+    }
 
-/// Represents the max limit of UDP port for binding in local.
-const MAX_UDP_PORT: usize = 256;
+    /// Wakes the timer driver to write the coalesced payloads or reopen the windows.
+    fn wake(&self) {
+        self.timer_notify.notify();
+    }
 
-/// Represents a channel redirect traffic to the proxy of SOCKS or loopback to the source in pcap.
-pub struct Redirector {
-    tx: Arc<Mutex<Forwarder>>,
-    is_tx_src_hardware_addr_set: bool,
-    src_ip_addr: Ipv4Network,
-    local_ip_addr: Ipv4Addr,
-    gw_ip_addr: Option<Ipv4Addr>,
-    remote: SocketAddrV4,
-    options: SocksOption,
-    streams: HashMap<(SocketAddrV4, SocketAddrV4), StreamWorker>,
-    states: HashMap<(SocketAddrV4, SocketAddrV4), TcpRxState>,
-    datagrams: HashMap<u16, DatagramWorker>,
-    /// Represents the map mapping a source port to a local port.
-    datagram_map: HashMap<SocketAddrV4, u16>,
-    /// Represents the LRU mapping a local port to a source port.
-    udp_lru: LruCache<u16, SocketAddrV4>,
-    defrag: Defraggler,
-}
+    /// Returns if any TCP connection has coalesced payloads or a closed window.
+    fn is_pending(&self) -> bool {
+        self.states
+            .values()
+            .any(|state| !state.coalesced.is_empty() || state.is_window_closed)
+    }
 
-impl Redirector {
-    /// Creates a new `Redirector`.
-    pub fn new(
-        tx: Arc<Mutex<Forwarder>>,
-        src_ip_addr: Ipv4Network,
-        local_ip_addr: Ipv4Addr,
-        gw_ip_addr: Option<Ipv4Addr>,
-        remote: SocketAddrV4,
-        force_associate_dst: bool,
-        force_associate_bind_addr: bool,
-        auth: Option<(String, String)>,
-    ) -> Redirector {
-        let auth = match auth {
-            Some((username, password)) => Some(SocksAuth::new(username, password)),
-            None => None,
-        };
-        let redirector = Redirector {
-            tx,
-            is_tx_src_hardware_addr_set: false,
-            src_ip_addr,
-            local_ip_addr,
-            gw_ip_addr,
-            remote,
-            options: SocksOption::new(force_associate_dst, force_associate_bind_addr, auth),
-            streams: HashMap::new(),
-            states: HashMap::new(),
-            datagrams: HashMap::new(),
-            datagram_map: HashMap::new(),
-            udp_lru: LruCache::new(MAX_UDP_PORT),
-            defrag: Defraggler::new(),
+    /// Writes the coalesced payloads of the TCP connection to the stream. The connection is reset
+    /// if the write fails, and is cleaned up later in the frame loop.
+    fn flush(
+        &mut self,
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+        tx: &Mutex<Forwarder>,
+    ) -> io::Result<()> {
+        let key = (src, dst);
+        let state = match self.states.get_mut(&key) {
+            Some(state) => state,
+            None => return Ok(()),
         };
-        if let Some(gw_ip_addr) = gw_ip_addr {
-            redirector.tx.lock().unwrap().set_local_ip_addr(gw_ip_addr);
+        if state.coalesced.is_empty() {
+            return Ok(());
         }
+        let mut payload = state.take_coalesced();
+        let size = payload.len();
+        state.strip_urgent(&mut payload);
 
-        redirector
-    }
+        // Middlewares
+        let payload = match self.middlewares {
+            Some(ref middlewares) => middlewares
+                .lock()
+                .unwrap()
+                .on_payload(src, dst, LayerKinds::Tcp, Cow::Owned(payload))
+                .map(|payload| payload.into_owned())
+                .unwrap_or_default(),
+            None => payload,
+        };
 
-    /// Opens an `Interface` for redirect.
-    pub async fn open(&mut self, rx: &mut Receiver) -> io::Result<()> {
-        loop {
-            match rx.next() {
-                Ok(frame) => {
-                    if let Some(ref indicator) = Indicator::from(frame) {
-                        if let Some(t) = indicator.network_kind() {
-                            match t {
-                                LayerKinds::Arp => {
-                                    if let Err(ref e) = self.handle_arp(indicator) {
-                                        warn!("handle {}: {}", indicator.brief(), e);
-                                    }
-                                }
-                                LayerKinds::Ipv4 => {
-                                    if let Err(ref e) = self.handle_ipv4(indicator, frame).await {
-                                        warn!("handle {}: {}", indicator.brief(), e);
-                                    }
-                                }
-                                _ => unreachable!(),
-                            }
-                        }
-                    };
-                }
-                Err(e) => {
-                    if e.kind() == io::ErrorKind::TimedOut {
-                        thread::sleep(Duration::from_millis(TIMEDOUT_WAIT));
-                        continue;
-                    }
-                    return Err(e);
+        // Send
+        let stream = self.streams.get_mut(&key).unwrap();
+        let result = if payload.is_empty() {
+            Ok(())
+        } else {
+            stream.send(payload)
+        };
+        match result {
+            Ok(_) => {
+                state.update_drain_rate(stream.written(), stream.write_queue_size());
+                let cache_remaining_size =
+                    state.window(stream.write_queue_size(), self.write_limit);
+                if cache_remaining_size == 0 {
+                    state.is_window_closed = true;
+                    self.timer_notify.notify();
                 }
-            };
-        }
-    }
 
-    fn handle_arp(&mut self, indicator: &Indicator) -> io::Result<()> {
-        if let Some(gw_ip_addr) = self.gw_ip_addr {
-            if let Some(arp) = indicator.arp() {
-                let src = arp.src();
-                if src != self.local_ip_addr
-                    && self.src_ip_addr.contains(src)
-                    && arp.dst() == gw_ip_addr
-                {
-                    debug!(
+                state.add_recv_next(size as u32);
+
+                let mut tx_locked = tx.lock().unwrap();
+                let tx_state = tx_locked.get_state(dst, src).unwrap();
+
+                // Update window size
+                tx_state.set_window(cache_remaining_size);
+
+                // Update TCP acknowledgement
+                tx_state.add_acknowledgement(size as u32);
+
+                // Send ACK0
+                // If there is a heavy traffic, the ACK reported may be inaccurate, which would results in retransmission
+                tx_locked.send_tcp_ack_0(dst, src)?;
+
+                Ok(())
+            }
+            Err(e) => {
+                // Send ACK/RST
+                tx.lock().unwrap().send_tcp_ack_rst(dst, src)?;
+
+                // Clean up later
+                state.is_window_closed = false;
+                self.failed.push(key);
+
+                Err(e)
+            }
+        }
+    }
+
+    /// Writes the coalesced payloads of TCP connections which reach the time threshold, and reopens
+    /// the windows of TCP connections whose write queues drain.
+    fn update(&mut self, tx: &Mutex<Forwarder>) {
+        let keys: Vec<_> = self
+            .states
+            .iter()
+            .filter(|(_, state)| state.is_coalesce_expired())
+            .map(|(key, _)| *key)
+            .collect();
+        for (src, dst) in keys {
+            if let Err(ref e) = self.flush(src, dst, tx) {
+                warn!("flush TCP {} -> {}: {}", src, dst, e);
+            }
+        }
+
+        // Reopen windows
+        let keys: Vec<_> = self
+            .states
+            .iter()
+            .filter(|(_, state)| state.is_window_closed)
+            .map(|(key, _)| *key)
+            .collect();
+        for (src, dst) in keys {
+            if let Err(ref e) = self.reopen_window(src, dst, tx) {
+                warn!("reopen TCP window {} -> {}: {}", src, dst, e);
+            }
+        }
+    }
+
+    /// Sends a window update of the TCP connection if its write queue drains to half of the
+    /// limit and the window is not zero anymore.
+    fn reopen_window(
+        &mut self,
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+        tx: &Mutex<Forwarder>,
+    ) -> io::Result<()> {
+        let key = (src, dst);
+        let (written, write_queue_size) = match self.streams.get(&key) {
+            Some(stream) => (stream.written(), stream.write_queue_size()),
+            None => return Ok(()),
+        };
+        if write_queue_size > self.write_limit / 2 {
+            return Ok(());
+        }
+        let state = self.states.get_mut(&key).unwrap();
+        state.update_drain_rate(written, write_queue_size);
+        let window = state.window(write_queue_size, self.write_limit);
+        if window == 0 {
+            return Ok(());
+        }
+        state.is_window_closed = false;
+        debug!("resume TCP {} -> {}", src, dst);
+
+        let mut tx_locked = tx.lock().unwrap();
+        tx_locked.get_state(dst, src).unwrap().set_window(window);
+
+        // Send ACK0
+        tx_locked.send_tcp_ack_0(dst, src)
+    }
+}
+
+/// Represents the time in milliseconds a TCP connection stays in TIME-WAIT.
+const TIME_WAIT_TIMEOUT: u64 = 30000;
+/// Represents the max count of TCP connections in TIME-WAIT.
+const TIME_WAIT_CAPACITY: usize = 4096;
+/// Represents the time in milliseconds a TCP connection stays in FIN-WAIT-2 while the source is
+/// silent.
+const FIN_WAIT_2_TIMEOUT: u64 = 60000;
+
+/// Represents a TCP connection in TIME-WAIT, which is closed but still acknowledges the
+/// retransmitted FINs of its source.
+struct TimeWait {
+    sequence: u32,
+    acknowledgement: u32,
+    instant: Instant,
+}
+
+/// Represents the time in microseconds of each tick of the clock of initial sequence numbers.
+const ISN_CLOCK_TICK: u128 = 4;
+
+/// Represents a generator of TCP initial sequence numbers in the way of RFC 6528. The ISN is
+/// the sum of a keyed hash of the 4-tuple and a clock ticking every 4 microseconds, so ISNs are
+/// unpredictable, but are monotonic across reconnects with the same 4-tuple.
+struct IsnGenerator {
+    key: RandomState,
+    instant: Instant,
+}
+
+impl IsnGenerator {
+    /// Creates a new `IsnGenerator` with a random key.
+    fn new() -> IsnGenerator {
+        IsnGenerator {
+            key: RandomState::new(),
+            instant: clock::now(),
+        }
+    }
+
+    /// Generates the initial sequence number of the TCP connection.
+    fn generate(&self, src: SocketAddrV4, dst: SocketAddrV4) -> u32 {
+        let hash = self.key.hash_one((src, dst)) as u32;
+        let clock = (clock::elapsed(self.instant).as_micros() / ISN_CLOCK_TICK) as u32;
+
+        hash.wrapping_add(clock)
+    }
+}
+
+/// Represents if the TCP window scale option is enabled.
+const ENABLE_WSCALE: bool = true;
+
+/// Represents if the TCP selective acknowledgment option is enabled.
+const ENABLE_SACK: bool = true;
+
+/// Represents the max limit of TCP challenge ACKs sent per second.
+const CHALLENGE_ACK_LIMIT: usize = 1000;
+
+/// Represents the minimum length of a QUIC connection ID for tracking. Shorter connection IDs are
+/// easy to collide with other traffic.
+#[cfg(feature = "udp")]
+const MIN_QUIC_CONN_ID_LEN: usize = 4;
+
+/// Represents the max limit of QUIC connection IDs tracked of a source.
+#[cfg(feature = "udp")]
+const MAX_QUIC_CONN_ID: usize = 8;
+
+/// Represents the QUIC connection IDs issued by servers to sources, which are the source
+/// connection IDs in long headers from servers, and the destination connection IDs of packets
+/// from sources afterwards. A QUIC session moving to another source port is found by them.
+#[cfg(feature = "udp")]
+#[derive(Debug)]
+struct QuicConnIds {
+    /// Represents the map mapping a QUIC connection ID to its source.
+    map: HashMap<Vec<u8>, SocketAddrV4>,
+    /// Represents the map mapping a source to its QUIC connection IDs.
+    sources: HashMap<SocketAddrV4, VecDeque<Vec<u8>>>,
+    /// Represents the count of QUIC connection IDs of each length, so the connection ID of a
+    /// short header, whose length is not carried, is looked up in each length in use.
+    lens: [usize; MAX_CONN_ID_LEN + 1],
+}
+
+#[cfg(feature = "udp")]
+impl QuicConnIds {
+    fn new() -> QuicConnIds {
+        QuicConnIds {
+            map: HashMap::new(),
+            sources: HashMap::new(),
+            lens: [0; MAX_CONN_ID_LEN + 1],
+        }
+    }
+
+    /// Tracks the QUIC connection ID issued to the source. Returns if it is tracked newly.
+    fn track(&mut self, src: SocketAddrV4, conn_id: &[u8]) -> bool {
+        if conn_id.len() < MIN_QUIC_CONN_ID_LEN || self.map.contains_key(conn_id) {
+            return false;
+        }
+
+        let conn_ids = self.sources.entry(src).or_insert_with(VecDeque::new);
+        if conn_ids.len() >= MAX_QUIC_CONN_ID {
+            if let Some(prev_conn_id) = conn_ids.pop_front() {
+                self.map.remove(&prev_conn_id);
+                self.lens[prev_conn_id.len()] -= 1;
+            }
+        }
+        conn_ids.push_back(conn_id.to_vec());
+        self.map.insert(conn_id.to_vec(), src);
+        self.lens[conn_id.len()] += 1;
+
+        true
+    }
+
+    /// Returns the source the QUIC packet from a source is destined by its connection ID.
+    fn lookup(&self, header: &QuicHeader, payload: &[u8]) -> Option<SocketAddrV4> {
+        match header {
+            QuicHeader::Long { dst_conn_id, .. } => self.map.get(dst_conn_id).copied(),
+            QuicHeader::Short => (MIN_QUIC_CONN_ID_LEN..=MAX_CONN_ID_LEN)
+                .filter(|&len| self.lens[len] > 0 && payload.len() > len)
+                .find_map(|len| self.map.get(&payload[1..1 + len]).copied()),
+        }
+    }
+
+    /// Moves the QUIC connection IDs of the source to another source.
+    fn migrate(&mut self, prev_src: SocketAddrV4, src: SocketAddrV4) {
+        if let Some(conn_ids) = self.sources.remove(&prev_src) {
+            for conn_id in conn_ids.iter() {
+                self.map.insert(conn_id.clone(), src);
+            }
+            self.sources.insert(src, conn_ids);
+        }
+    }
+
+    /// Untracks the QUIC connection IDs of the source.
+    fn untrack(&mut self, src: SocketAddrV4) {
+        if let Some(conn_ids) = self.sources.remove(&src) {
+            for conn_id in conn_ids {
+                self.map.remove(&conn_id);
+                self.lens[conn_id.len()] -= 1;
+            }
+        }
+    }
+
+    /// Returns the count of sources with QUIC connection IDs.
+    fn len(&self) -> usize {
+        self.sources.len()
+    }
+}
+
+/// Represents the interval in milliseconds of expiring idle UDP ports and pending TCP connections.
+const SWEEP_INTERVAL: u64 = 1000;
+
+/// Represents the interval in milliseconds of saving the snapshot.
+const SNAPSHOT_INTERVAL: u64 = 10000;
+
+/// Represents the time after the backoff of a TCP connection elapses before forgetting its
+/// retries.
+const BACKOFF_EXPIRE_TIME: u64 = 60000;
+
+/// Represents the time in milliseconds a source stays in a multicast group without reporting its
+/// membership again, the Group Membership Interval of IGMP (RFC 2236).
+const GROUP_MEMBERSHIP_INTERVAL: u64 = 260000;
+
+/// Represents the max count of tunnels remembered as logged.
+const LOGGED_TUNNEL_CAPACITY: usize = 256;
+/// Represents the max count of devices remembered with their logged link-layer discovery frames.
+const LOGGED_DISCOVERY_CAPACITY: usize = 64;
+
+/// Returns the sources of the source network and the networks and the exclusions in the config.
+fn sources(src_ip_addr: Ipv4Network, config: &Config) -> SourceSet {
+    let mut includes = Vec::with_capacity(1 + config.sources.len());
+    includes.push(src_ip_addr);
+    includes.extend_from_slice(&config.sources);
+
+    SourceSet::new(includes, config.source_excludes.clone())
+}
+
+tokio::task_local! {
+    /// Represents the frames sent by the task stepping a `Redirector`. Frames sent by other tasks
+    /// meanwhile, like the timer driver and workers, are not recorded.
+    static EMITTED: Arc<Mutex<Vec<Vec<u8>>>>;
+}
+
+/// Represents an action emitted by a `Redirector` when it is stepped.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum StepAction {
+    /// Represents a frame sent to the source, which is sent to pcap as well.
+    Transmit(Vec<u8>),
+    /// Represents no frame is received before the receiver times out, so the caller may wait for
+    /// a while before polling again.
+    Idle,
+}
+
+/// Represents a channel redirect traffic to the proxy of SOCKS or loopback to the source in pcap.
+/// The pcap device and the proxy are defaults of the backends. Frames can be sent and received
+/// with any `DataLinkSender` and `DataLinkReceiver` like a TUN device, and traffic can be carried
+/// out by any `Egress`.
+pub struct Redirector {
+    tx: Arc<Mutex<Forwarder>>,
+    is_tx_src_hardware_addr_set: bool,
+    sources: SourceSet,
+    local_ip_addr: Ipv4Addr,
+    gw_ip_addr: Option<Ipv4Addr>,
+    arp_guard: Option<ArpGuard>,
+    remote: SocketAddrV4,
+    options: SocksOption,
+    balancer: Arc<Mutex<Balancer>>,
+    tcp: Arc<Mutex<TcpRx>>,
+    #[cfg(feature = "udp")]
+    datagrams: PacketMap<u16, DatagramWorker>,
+    /// Represents the map mapping a source port to a local port.
+    #[cfg(feature = "udp")]
+    datagram_map: PacketMap<SocketAddrV4, u16>,
+    /// Represents the LRU mapping a local port to a source port.
+    #[cfg(feature = "udp")]
+    udp_lru: LruCache<u16, SocketAddrV4>,
+    /// Represents the map mapping a source port to the local port it was bound to before the
+    /// restart, which is bound again once the source sends.
+    #[cfg(feature = "udp")]
+    restored_ports: PacketMap<SocketAddrV4, u16>,
+    #[cfg(feature = "udp")]
+    restore_instant: Instant,
+    #[cfg(feature = "udp")]
+    udp_timeout: u64,
+    #[cfg(feature = "udp")]
+    nat_mode: NatMode,
+    #[cfg(feature = "udp")]
+    broadcast_mode: BroadcastMode,
+    netbios_names: Vec<(String, Ipv4Addr)>,
+    multicast_mode: MulticastMode,
+    /// Represents the map mapping a multicast group to the sources which have joined the group and
+    /// the time they reported their membership last.
+    multicast_groups: HashMap<Ipv4Addr, HashMap<Ipv4Addr, Instant>>,
+    /// Represents the map mapping a pending TCP connection, which has not completed the handshake,
+    /// to the time it is connected.
+    pending: PacketMap<(SocketAddrV4, SocketAddrV4), Instant>,
+    /// Represents the map mapping a TCP connection which failed to connect to the proxy to its
+    /// count of retries and the time its backoff elapses.
+    backoffs: PacketMap<(SocketAddrV4, SocketAddrV4), (usize, Instant)>,
+    /// Represents the TCP connections closed by both sides but kept in TIME-WAIT.
+    time_waits: LruCache<(SocketAddrV4, SocketAddrV4), TimeWait>,
+    isn: IsnGenerator,
+    tcp_capacity: usize,
+    tcp_eviction: bool,
+    tcp_pending_limit: usize,
+    tcp_pending_timeout: u64,
+    tcp_idle_timeout: u64,
+    tcp_syn_retries: usize,
+    limiter: Option<RateLimiter>,
+    quarantine: Option<Quarantine>,
+    bindings: Option<Arc<Mutex<Bindings>>>,
+    shaper: Option<Arc<Mutex<Shaper>>>,
+    sni_filter: Option<SniFilter>,
+    port_policy: Option<PortPolicy>,
+    auditor: Option<Auditor>,
+    tcp_queue_high: usize,
+    tcp_queue_low: usize,
+    tcp_out_of_order_limit: usize,
+    tcp_recv_window: u16,
+    tcp_recv_wscale: u8,
+    tcp_pacing: bool,
+    tcp_connect_retries: usize,
+    tcp_connect_backoff: u64,
+    #[cfg(feature = "icmp")]
+    icmp_policy: IcmpPolicy,
+    #[cfg(feature = "icmp")]
+    echo_mode: EchoMode,
+    #[cfg(feature = "icmp")]
+    echo_rtt: u64,
+    tunnel_policy: TunnelPolicy,
+    urgent_policy: UrgentPolicy,
+    /// Represents the tunnels which have been logged.
+    logged_tunnels: LruCache<(Ipv4Addr, Ipv4Addr, TunnelProtocol), ()>,
+    passthrough: Option<Passthrough>,
+    log_discovery: bool,
+    /// Represents the last logged link-layer discovery frame of each device in each protocol.
+    logged_discoveries: LruCache<(HardwareAddr, DiscoveryProtocol), Discovery>,
+    snapshots: Option<(Arc<Mutex<Snapshots>>, usize)>,
+    sweep_instant: Instant,
+    snapshot_instant: Instant,
+    #[cfg(all(unix, feature = "systemd"))]
+    notifier: Option<systemd::Notifier>,
+    is_opened: bool,
+    is_timer_driven: bool,
+    challenge_acks: usize,
+    challenge_ack_instant: Instant,
+    #[cfg(feature = "udp")]
+    quic_conn_ids: Arc<Mutex<QuicConnIds>>,
+    #[cfg(feature = "defrag")]
+    defrag: Defraggler,
+    /// Represents the sender of flows handed out to user code instead of the SOCKS proxy.
+    acceptor: Option<mpsc::UnboundedSender<Flow>>,
+    #[cfg(feature = "udp")]
+    session_port: u16,
+    commands: Option<mpsc::UnboundedReceiver<Command>>,
+    middlewares: Option<Arc<Mutex<Middlewares>>>,
+    events: Option<Publisher>,
+    flows: Option<FlowPublisher>,
+    #[cfg(feature = "metrics")]
+    tracker: Option<Arc<Mutex<Tracker>>>,
+    recorder: Option<Arc<Mutex<Recorder>>>,
+    tracer: Option<Arc<Mutex<Tracer>>>,
+    stats: Arc<Stats>,
+    #[cfg(feature = "wireguard")]
+    wireguard: Option<WireGuardOption>,
+    #[cfg(feature = "wireguard")]
+    tunnel: Option<Tunnel>,
+    clock: Option<Arc<dyn Clock>>,
+}
+
+impl Redirector {
+    /// Creates a new `Redirector`.
+    pub fn new(
+        tx: Arc<Mutex<Forwarder>>,
+        src_ip_addr: Ipv4Network,
+        local_ip_addr: Ipv4Addr,
+        gw_ip_addr: Option<Ipv4Addr>,
+        remote: SocketAddrV4,
+        force_associate_dst: bool,
+        force_associate_bind_addr: bool,
+        auth: Option<SocksAuth>,
+        config: Config,
+    ) -> Redirector {
+        let clock = config.clock.clone().map(|clock| clock.0);
+        let _clock = clock::enter(clock.clone());
+        tx.lock().unwrap().set_clock(clock.clone());
+        if let Some(ref path) = config.mtu_cache {
+            match MtuCache::load(path) {
+                Ok(cache) => tx.lock().unwrap().set_mtu_cache(cache),
+                Err(ref e) => warn!("load MTU cache {}: {}", path.display(), e),
+            }
+        }
+        let snapshot = config
+            .snapshot
+            .as_ref()
+            .and_then(|path| match Snapshot::load(path) {
+                Ok(snapshot) => Some(snapshot),
+                Err(ref e) => {
+                    warn!("load snapshot {}: {}", path.display(), e);
+                    None
+                }
+            });
+        if let Some(ref snapshot) = snapshot {
+            tx.lock().unwrap().restore(snapshot);
+        }
+        let stats = Arc::new(Stats::new());
+        stats.set_udp_capacity(config.udp_capacity);
+        tx.lock().unwrap().set_stats(Arc::clone(&stats));
+        #[allow(unused_mut)]
+        let mut options = SocksOption::new(force_associate_dst, force_associate_bind_addr, auth);
+        options.set_pool(config.socks_pool);
+        options.set_direct(&config.direct);
+        #[cfg(feature = "http2")]
+        if let Some(ref http2) = config.http2 {
+            options.set_http2(http2.clone());
+        }
+        #[cfg(feature = "ssh")]
+        if let Some(ref ssh) = config.ssh {
+            options.set_ssh(ssh.clone());
+        }
+        #[cfg(feature = "vmess")]
+        if let Some(ref vmess) = config.vmess {
+            options.set_vmess(vmess.clone());
+        }
+        #[cfg(feature = "websocket")]
+        if let Some(ref websocket) = config.websocket {
+            options.set_websocket(websocket.clone());
+        }
+        let mut balancer = Balancer::new(remote, &config.proxies);
+        balancer.set_sticky(config.sticky_ttl, config.sticky_prefix);
+        balancer.set_probe(config.probe_interval, config.fastest_ports.clone());
+        let recorder = config.capture.clone().map(|dir| {
+            let recorder = Arc::new(Mutex::new(Recorder::new(dir, config.capture_frames)));
+            tx.lock().unwrap().set_recorder(Arc::clone(&recorder));
+
+            recorder
+        });
+        let tracer = config.tcp_trace.clone().map(|dir| {
+            let tracer = Arc::new(Mutex::new(Tracer::new(dir)));
+            tx.lock().unwrap().set_tracer(Arc::clone(&tracer));
+
+            tracer
+        });
+        if config.tx_priority {
+            tx.lock()
+                .unwrap()
+                .set_tx_priority(true, config.priority_ports.clone());
+        }
+        if config.mtu_probing {
+            tx.lock().unwrap().set_mtu_probing(true);
+        }
+        let shaper = if config.qos_classes.is_empty() {
+            None
+        } else {
+            let shaper = Arc::new(Mutex::new(Shaper::new(
+                &config.qos_classes,
+                &config.qos_matches,
+            )));
+            options.set_shaper(Arc::clone(&shaper));
+            tx.lock().unwrap().set_shaper(Arc::clone(&shaper));
+
+            Some(shaper)
+        };
+        #[cfg(feature = "udp")]
+        let quic_conn_ids = Arc::new(Mutex::new(QuicConnIds::new()));
+        #[cfg(feature = "udp")]
+        tx.lock()
+            .unwrap()
+            .set_quic_conn_ids(Arc::clone(&quic_conn_ids));
+        let tcp = TcpRx::new(config.tcp_write_limit, tx.lock().unwrap().timer_notify());
+        let redirector = Redirector {
+            tx,
+            is_tx_src_hardware_addr_set: false,
+            sources: sources(src_ip_addr, &config),
+            local_ip_addr,
+            gw_ip_addr,
+            arp_guard: gw_ip_addr.map(|gw_ip_addr| ArpGuard::new(gw_ip_addr, config.arp_recheck)),
+            remote,
+            options,
+            balancer: Arc::new(Mutex::new(balancer)),
+            tcp: Arc::new(Mutex::new(tcp)),
+            #[cfg(feature = "udp")]
+            datagrams: PacketMap::default(),
+            #[cfg(feature = "udp")]
+            datagram_map: PacketMap::default(),
+            #[cfg(feature = "udp")]
+            udp_lru: LruCache::new(config.udp_capacity),
+            #[cfg(feature = "udp")]
+            restored_ports: match snapshot {
+                Some(snapshot) => snapshot.udp_ports.into_iter().collect(),
+                None => PacketMap::default(),
+            },
+            #[cfg(feature = "udp")]
+            restore_instant: clock::now(),
+            #[cfg(feature = "udp")]
+            udp_timeout: config.udp_timeout,
+            #[cfg(feature = "udp")]
+            nat_mode: config.nat_mode,
+            #[cfg(feature = "udp")]
+            broadcast_mode: config.broadcast_mode,
+            netbios_names: config.netbios_names.clone(),
+            multicast_mode: config.multicast_mode,
+            multicast_groups: HashMap::new(),
+            pending: PacketMap::default(),
+            backoffs: PacketMap::default(),
+            time_waits: LruCache::new(TIME_WAIT_CAPACITY),
+            isn: IsnGenerator::new(),
+            tcp_capacity: config.tcp_capacity,
+            tcp_eviction: config.tcp_eviction,
+            tcp_pending_limit: config.tcp_pending_limit,
+            tcp_pending_timeout: config.tcp_pending_timeout,
+            tcp_idle_timeout: config.tcp_idle_timeout,
+            tcp_syn_retries: config.tcp_syn_retries,
+            limiter: match config.connect_rate {
+                0 => None,
+                rate => Some(RateLimiter::new(rate, config.connect_burst)),
+            },
+            sni_filter: match config.sni_rules.len() {
+                0 => None,
+                _ => Some(SniFilter::new(
+                    &config.sni_rules,
+                    &config.sni_ports,
+                    config.sni_fail_open,
+                )),
+            },
+            port_policy: match config.port_profile_sources.len() {
+                0 => None,
+                _ => Some(PortPolicy::new(
+                    &config.port_profiles,
+                    &config.port_profile_sources,
+                )),
+            },
+            auditor: None,
+            quarantine: match config.quarantine_threshold {
+                0 => None,
+                threshold => Some(Quarantine::new(
+                    threshold,
+                    Duration::from_millis(config.quarantine_time),
+                )),
+            },
+            bindings: if config.source_binding || !config.static_bindings.is_empty() {
+                Some(Arc::new(Mutex::new(Bindings::new(
+                    &config.static_bindings,
+                    config.source_binding,
+                ))))
+            } else {
+                None
+            },
+            shaper,
+            tcp_queue_high: config.tcp_queue_high,
+            tcp_queue_low: min(config.tcp_queue_low, config.tcp_queue_high),
+            tcp_out_of_order_limit: config.tcp_out_of_order_limit,
+            tcp_recv_window: config.tcp_recv_window,
+            tcp_recv_wscale: config.tcp_recv_wscale,
+            tcp_pacing: config.tcp_pacing,
+            tcp_connect_retries: config.tcp_connect_retries,
+            tcp_connect_backoff: config.tcp_connect_backoff,
+            #[cfg(feature = "icmp")]
+            icmp_policy: config.icmp_policy,
+            #[cfg(feature = "icmp")]
+            echo_mode: config.echo_mode,
+            #[cfg(feature = "icmp")]
+            echo_rtt: config.echo_rtt,
+            tunnel_policy: config.tunnel_policy,
+            urgent_policy: config.urgent_policy,
+            logged_tunnels: LruCache::new(LOGGED_TUNNEL_CAPACITY),
+            passthrough: None,
+            log_discovery: config.log_discovery,
+            logged_discoveries: LruCache::new(LOGGED_DISCOVERY_CAPACITY),
+            snapshots: config
+                .snapshot
+                .clone()
+                .map(|path| (Arc::new(Mutex::new(Snapshots::new(path, 1))), 0)),
+            sweep_instant: clock::now(),
+            snapshot_instant: clock::now(),
+            #[cfg(all(unix, feature = "systemd"))]
+            notifier: None,
+            is_opened: false,
+            is_timer_driven: false,
+            challenge_acks: 0,
+            challenge_ack_instant: clock::now(),
+            #[cfg(feature = "udp")]
+            quic_conn_ids,
+            #[cfg(feature = "defrag")]
+            defrag: Defraggler::new(),
+            acceptor: None,
+            #[cfg(feature = "udp")]
+            session_port: 0,
+            commands: None,
+            middlewares: None,
+            events: None,
+            flows: None,
+            #[cfg(feature = "metrics")]
+            tracker: None,
+            recorder,
+            tracer,
+            stats,
+            #[cfg(feature = "wireguard")]
+            wireguard: config.wireguard,
+            #[cfg(feature = "wireguard")]
+            tunnel: None,
+            clock,
+        };
+        if let Some(gw_ip_addr) = gw_ip_addr {
+            redirector.tx.lock().unwrap().set_local_ip_addr(gw_ip_addr);
+        }
+
+        redirector
+    }
+
+    /// Sets the unscaled receive window and the max window scale of new TCP connections.
+    /// Existing connections keep their receive windows.
+    pub fn set_recv_window(&mut self, window: u16, wscale: u8) {
+        let wscale = min(wscale, config::MAX_TCP_WSCALE);
+        if window != self.tcp_recv_window || wscale != self.tcp_recv_wscale {
+            info!(
+                "TCP receive window changed from {} (scale {}) to {} (scale {})",
+                self.tcp_recv_window, self.tcp_recv_wscale, window, wscale
+            );
+            self.tcp_recv_window = window;
+            self.tcp_recv_wscale = wscale;
+        }
+    }
+
+    /// Sets the balancer of proxies, which may be shared with other `Redirector`s.
+    pub(crate) fn set_balancer(&mut self, balancer: Arc<Mutex<Balancer>>) {
+        self.balancer = balancer;
+    }
+
+    /// Sets the shaper which shapes the traffic of flows by their classes.
+    pub(crate) fn set_shaper(&mut self, shaper: Arc<Mutex<Shaper>>) {
+        self.options.set_shaper(Arc::clone(&shaper));
+        self.tx.lock().unwrap().set_shaper(Arc::clone(&shaper));
+        self.shaper = Some(shaper);
+    }
+
+    /// Sets the systemd notifier, whose watchdog is fed in the loop of the `Redirector`.
+    #[cfg(all(unix, feature = "systemd"))]
+    pub fn set_notifier(&mut self, notifier: systemd::Notifier) {
+        self.notifier = Some(notifier);
+    }
+
+    /// Returns a receiver of the flows accepted from sources. Once called, new TCP connections and
+    /// UDP sessions are handed out to the receiver instead of being relayed to the SOCKS proxy.
+    pub fn incoming(&mut self) -> mpsc::UnboundedReceiver<Flow> {
+        let (acceptor, incoming) = mpsc::unbounded_channel();
+        self.acceptor = Some(acceptor);
+
+        incoming
+    }
+
+    /// Returns a controller of the `Redirector`. Once called, the `Redirector` executes commands
+    /// from the controller in its loop.
+    pub fn controller(&mut self) -> Controller {
+        let (tx, commands) = mpsc::unbounded_channel();
+        self.commands = Some(commands);
+
+        let controller = Controller::new(vec![tx], vec![self.stats()]);
+        #[cfg(feature = "metrics")]
+        let controller = controller.with_trackers(vec![self.tracker()]);
+
+        controller
+    }
+
+    /// Returns the tracker of the throughput of sources and TCP connections. Once called, the
+    /// `Redirector` tracks the throughput of frames received and sent.
+    #[cfg(feature = "metrics")]
+    fn tracker(&mut self) -> Arc<Mutex<Tracker>> {
+        if let Some(ref tracker) = self.tracker {
+            return Arc::clone(tracker);
+        }
+
+        let tracker = Arc::new(Mutex::new(Tracker::new()));
+        self.tx.lock().unwrap().set_tracker(Arc::clone(&tracker));
+        self.tracker = Some(Arc::clone(&tracker));
+
+        tracker
+    }
+
+    /// Returns a receiver of status updates of the `Redirector`, including the throughput, the
+    /// sources joined and the health of the proxy, so frontends can be updated without polling.
+    pub fn events(&mut self) -> broadcast::Receiver<Event> {
+        self.events.get_or_insert_with(Publisher::new).subscribe()
+    }
+
+    /// Returns a receiver of lifecycle events of TCP connections and UDP ports, from which
+    /// frontends, metrics and accounting can follow every flow.
+    pub fn subscribe(&mut self) -> broadcast::Receiver<FlowEvent> {
+        if self.flows.is_none() {
+            self.set_flows(FlowPublisher::new());
+        }
+
+        self.flows.as_ref().unwrap().subscribe()
+    }
+
+    fn set_flows(&mut self, flows: FlowPublisher) {
+        self.tx.lock().unwrap().set_flows(flows.clone());
+        self.flows = Some(flows);
+    }
+
+    fn publish_flow(&self, event: FlowEvent) {
+        if let Some(ref flows) = self.flows {
+            flows.send(event);
+        }
+    }
+
+    /// Sets the sink of the audit log, which records every connection accepted or refused.
+    pub fn set_audit<S: AuditSink + 'static>(&mut self, sink: S) {
+        self.auditor = Some(Auditor::new(Arc::new(Mutex::new(sink))));
+    }
+
+    /// Creates a new `Redirector` whose traffic is carried out by the egress without any proxy.
+    /// Others are the same as `Redirector::new`.
+    pub fn with_egress<E: Egress + 'static>(
+        tx: Arc<Mutex<Forwarder>>,
+        src_ip_addr: Ipv4Network,
+        local_ip_addr: Ipv4Addr,
+        gw_ip_addr: Option<Ipv4Addr>,
+        egress: E,
+        config: Config,
+    ) -> Redirector {
+        let mut redirector = Redirector::new(
+            tx,
+            src_ip_addr,
+            local_ip_addr,
+            gw_ip_addr,
+            SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0),
+            false,
+            false,
+            None,
+            config,
+        );
+        redirector.set_egress(egress);
+
+        redirector
+    }
+
+    /// Sets the egress, which connects and binds for sources instead of the proxy. Traffic to the
+    /// destinations connected directly is not carried by the egress.
+    pub fn set_egress<E: Egress + 'static>(&mut self, egress: E) {
+        self.options.set_egress(Arc::new(egress));
+    }
+
+    /// Adds a middleware, which is executed after the middlewares added before.
+    pub fn add_middleware<M: PacketMiddleware + 'static>(&mut self, middleware: M) {
+        let middlewares = match self.middlewares {
+            Some(ref middlewares) => Arc::clone(middlewares),
+            None => {
+                let middlewares = Arc::new(Mutex::new(Middlewares::default()));
+                self.tx
+                    .lock()
+                    .unwrap()
+                    .set_middlewares(Arc::clone(&middlewares));
+                self.tcp.lock().unwrap().middlewares = Some(Arc::clone(&middlewares));
+                self.middlewares = Some(Arc::clone(&middlewares));
+
+                middlewares
+            }
+        };
+
+        middlewares.lock().unwrap().push(Box::new(middleware));
+    }
+
+    /// Returns the statistics of the `Redirector`.
+    pub fn stats(&self) -> Arc<Stats> {
+        Arc::clone(&self.stats)
+    }
+
+    /// Opens an `Interface` for redirect. Frames are received from the `rx`, which is a pcap
+    /// device or any capture backend.
+    pub async fn open(&mut self, rx: &mut Receiver) -> io::Result<()> {
+        self.prepare().await?;
+
+        clock::scope_option(self.clock.clone(), async {
+            loop {
+                if let Err(e) = self.poll(rx).await {
+                    if e.kind() != io::ErrorKind::TimedOut {
+                        return Err(e);
+                    }
+                    thread::sleep(Duration::from_millis(TIMEDOUT_WAIT));
+                }
+            }
+        })
+        .await
+    }
+
+    /// Receives a frame from the `rx` and processes it, for callers driving the `Redirector` in
+    /// their own event loops instead of `open`. Returns the actions emitted while processing the
+    /// frame, or `Idle` if no frame is received before the `rx` times out. The call blocks in
+    /// receiving for up to the read timeout of the `rx`, like 1 second of a pcap device, but does
+    /// not sleep after a timeout as `open` does.
+    pub async fn poll_once(&mut self, rx: &mut Receiver) -> io::Result<Vec<StepAction>> {
+        self.prepare().await?;
+
+        let emitted = Arc::new(Mutex::new(Vec::new()));
+        let result = EMITTED
+            .scope(
+                Arc::clone(&emitted),
+                clock::scope_option(self.clock.clone(), self.poll(rx)),
+            )
+            .await;
+        let emitted = mem::take(&mut *emitted.lock().unwrap());
+
+        match result {
+            Ok(_) => Ok(emitted.into_iter().map(StepAction::Transmit).collect()),
+            Err(ref e) if e.kind() == io::ErrorKind::TimedOut => Ok(vec![StepAction::Idle]),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Processes the frame as if it is received from pcap, for callers which receive frames
+    /// themselves. Returns the actions emitted while processing the frame, which include the
+    /// frames sent to sources. Frames sent meanwhile by the timer driver and workers are not
+    /// included, and are only sent to pcap.
+    pub async fn step(&mut self, frame: &[u8]) -> io::Result<Vec<StepAction>> {
+        self.prepare().await?;
+
+        let emitted = Arc::new(Mutex::new(Vec::new()));
+        let clock = self.clock.clone();
+        EMITTED
+            .scope(
+                Arc::clone(&emitted),
+                clock::scope_option(clock, async {
+                    self.stats.mark_loop();
+                    self.sweep();
+                    self.tick_tcp_timers();
+                    self.stats.mark_frame();
+                    self.handle_frame(frame).await;
+                }),
+            )
+            .await;
+        let emitted = mem::take(&mut *emitted.lock().unwrap());
+
+        Ok(emitted.into_iter().map(StepAction::Transmit).collect())
+    }
+
+    /// Starts the background tasks of the `Redirector` once before any frame is processed.
+    async fn prepare(&mut self) -> io::Result<()> {
+        if self.is_opened {
+            return Ok(());
+        }
+
+        self.drive_tcp_timers();
+        balance::probe(&self.balancer);
+        self.warm_up_pool();
+        self.open_passthrough().await?;
+        #[cfg(feature = "wireguard")]
+        self.open_tunnel().await?;
+        self.is_opened = true;
+
+        Ok(())
+    }
+
+    /// Receives a frame from the `rx` and processes it.
+    async fn poll(&mut self, rx: &mut Receiver) -> io::Result<()> {
+        self.stats.mark_loop();
+        self.sweep();
+
+        let frame = rx.next()?;
+        self.tick_tcp_timers();
+        self.stats.mark_frame();
+        self.handle_frame(frame).await;
+
+        Ok(())
+    }
+
+    #[cfg(feature = "wireguard")]
+    async fn open_tunnel(&mut self) -> io::Result<()> {
+        if self.tunnel.is_some() {
+            return Ok(());
+        }
+        let tunnel = match self.wireguard {
+            Some(ref options) => Tunnel::open(self.remote, options, self.get_tx()).await?,
+            None => return Ok(()),
+        };
+        self.tunnel = Some(tunnel);
+
+        Ok(())
+    }
+
+    async fn open_passthrough(&mut self) -> io::Result<()> {
+        if self.passthrough.is_some() {
+            return Ok(());
+        }
+        let passthrough = match self.tunnel_policy {
+            TunnelPolicy::Forward(peer) => Passthrough::open(peer, self.get_tx()).await?,
+            _ => return Ok(()),
+        };
+        self.passthrough = Some(passthrough);
+
+        Ok(())
+    }
+
+    fn warm_up_pool(&self) {
+        let proxies = self.balancer.lock().unwrap().proxies().to_vec();
+        for proxy in proxies {
+            self.options.warm_up(proxy);
+        }
+    }
+
+    fn drive_tcp_timers(&mut self) {
+        // Timers under a clock other than the system's are fired in the frame loop
+        if self.is_timer_driven || self.clock.is_some() {
+            return;
+        }
+        self.is_timer_driven = true;
+
+        // Fire timers of TCP connections, write coalesced payloads and reopen windows in each
+        // tick, and sleep while there is nothing to do
+        let tx = Arc::downgrade(&self.tx);
+        let tcp = Arc::downgrade(&self.tcp);
+        let notify = self.tx.lock().unwrap().timer_notify();
+        tokio::spawn(async move {
+            loop {
+                let has_timers = match (tx.upgrade(), tcp.upgrade()) {
+                    (Some(tx), Some(tcp)) => {
+                        let is_pending = tcp.lock().unwrap().is_pending();
+                        let tx_locked = tx.lock().unwrap();
+                        is_pending || tx_locked.has_tcp_timers() || tx_locked.has_tx_backlog()
+                    }
+                    _ => break,
+                };
+                if !has_timers {
+                    notify.notified().await;
+                }
+                time::delay_for(Duration::from_millis(TIMER_TICK)).await;
+
+                match (tx.upgrade(), tcp.upgrade()) {
+                    (Some(tx), Some(tcp)) => {
+                        tcp.lock().unwrap().update(&tx);
+
+                        let mut tx_locked = tx.lock().unwrap();
+                        if let Err(ref e) = tx_locked.flush_tx() {
+                            warn!("send to pcap: {}", e);
+                        }
+                        tx_locked.expire_tcp_timers();
+                    }
+                    _ => break,
+                }
+            }
+        });
+    }
+
+    /// Fires the due timers of TCP connections, writes coalesced payloads and reopens windows in
+    /// the frame loop under a clock other than the system's, whose time does not pass in the
+    /// timer driver.
+    fn tick_tcp_timers(&mut self) {
+        if self.clock.is_none() {
+            return;
+        }
+
+        self.tcp.lock().unwrap().update(&self.tx);
+
+        let mut tx_locked = self.tx.lock().unwrap();
+        if let Err(ref e) = tx_locked.flush_tx() {
+            warn!("send to pcap: {}", e);
+        }
+        tx_locked.expire_tcp_timers();
+    }
+
+    fn sweep(&mut self) {
+        // Feed the watchdog
+        #[cfg(all(unix, feature = "systemd"))]
+        if let Some(ref mut notifier) = self.notifier {
+            if let Err(ref e) = notifier.watchdog() {
+                warn!("notify systemd: {}", e);
+            }
+        }
+
+        // Publish events
+        if let Some(ref mut events) = self.events {
+            events.publish(&self.stats, self.remote);
+        }
+
+        // Clean up TCP connections failing in the timer driver
+        self.clean_up_failed_tcp();
+
+        // Probe the gateway
+        let action = self.arp_guard.as_mut().and_then(|guard| guard.update());
+        if let Some(action) = action {
+            self.handle_guard_action(action);
+        }
+
+        // Execute commands
+        while let Some(command) = self
+            .commands
+            .as_mut()
+            .and_then(|commands| commands.try_recv().ok())
+        {
+            self.execute(command);
+        }
+
+        // Expire idle UDP ports, pending, idle and FIN-WAIT-2 TCP connections, and multicast
+        // memberships
+        if clock::elapsed(self.sweep_instant) >= Duration::from_millis(SWEEP_INTERVAL) {
+            #[cfg(feature = "udp")]
+            self.expire_local_udp_ports();
+            if let Err(ref e) = self.expire_pending_tcp() {
+                warn!("expire pending TCP: {}", e);
+            }
+            if let Err(ref e) = self.expire_idle_tcp() {
+                warn!("expire idle TCP: {}", e);
+            }
+            self.expire_fin_wait_tcp();
+            self.expire_multicast_groups();
+            #[cfg(feature = "metrics")]
+            if let Some(ref tracker) = self.tracker {
+                tracker.lock().unwrap().expire();
+            }
+            self.backoffs.retain(|_, (_, instant)| {
+                clock::now() < *instant + Duration::from_millis(BACKOFF_EXPIRE_TIME)
+            });
+            let rtts = {
+                let mut balancer = self.balancer.lock().unwrap();
+                balancer.expire();
+
+                balancer.rtts()
+            };
+            self.stats.set_proxy_rtts(rtts);
+            self.save_mtu_cache();
+            self.sweep_instant = clock::now();
+        }
+
+        // Save the snapshot
+        if clock::elapsed(self.snapshot_instant) >= Duration::from_millis(SNAPSHOT_INTERVAL) {
+            self.save_snapshot();
+            self.snapshot_instant = clock::now();
+        }
+    }
+
+    /// Persists the cache of the path MTU if it is changed, without holding the `Forwarder`.
+    fn save_mtu_cache(&self) {
+        let cache = match self.tx.lock() {
+            Ok(mut tx_locked) => tx_locked.take_dirty_mtu_cache(),
+            Err(_) => return,
+        };
+        if let Some(mut cache) = cache {
+            if let Err(ref e) = cache.save() {
+                warn!("save MTU cache: {}", e);
+            }
+        }
+    }
+
+    fn save_snapshot(&mut self) {
+        let (snapshots, i) = match self.snapshots {
+            Some((ref snapshots, i)) => (snapshots, i),
+            None => return,
+        };
+
+        let mut snapshot = Snapshot::new();
+        self.tx.lock().unwrap().snapshot(&mut snapshot);
+        // Sessions handed out to user code are not bound to local ports
+        #[cfg(feature = "udp")]
+        if self.acceptor.is_none() {
+            // Restored ports are kept until their sources could have sent again
+            if clock::elapsed(self.restore_instant) >= Duration::from_millis(self.udp_timeout) {
+                self.restored_ports.clear();
+            }
+            snapshot.udp_ports.extend(self.restored_ports.iter());
+            snapshot.udp_ports.extend(self.datagram_map.iter());
+        }
+
+        let mut snapshots = snapshots.lock().unwrap();
+        if let Err(ref e) = snapshots.update(i, snapshot) {
+            warn!("save snapshot {}: {}", snapshots.path().display(), e);
+        }
+    }
+
+    fn execute(&mut self, command: Command) {
+        match command {
+            Command::ListConnections(reply) => {
+                let tcp_locked = self.tcp.lock().unwrap();
+                let mut tx_locked = self.tx.lock().unwrap();
+                let connections = tcp_locked
+                    .streams
+                    .iter()
+                    .map(|(&(src, dst), stream)| {
+                        let state = tcp_locked.states.get(&(src, dst));
+                        let tx_state = tx_locked.get_state(dst, src);
+                        Connection {
+                            src,
+                            dst,
+                            idle: stream.idle(),
+                            written: stream.written(),
+                            write_queue_size: stream.write_queue_size(),
+                            out_of_order: state.map_or(0, |state| state.cache.out_of_order_len()),
+                            holes: state.map_or(0, |state| state.cache.holes()),
+                            out_of_order_drops: state.map_or(0, |state| state.out_of_order_drops),
+                            retrans_segments: tx_state
+                                .as_ref()
+                                .map_or(0, |tx_state| tx_state.retrans_segments()),
+                            retrans_bytes: tx_state
+                                .as_ref()
+                                .map_or(0, |tx_state| tx_state.retrans_bytes()),
+                            fast_retransmits: tx_state
+                                .as_ref()
+                                .map_or(0, |tx_state| tx_state.fast_retransmits()),
+                            rto_retransmits: tx_state
+                                .as_ref()
+                                .map_or(0, |tx_state| tx_state.rto_retransmits()),
+                            duplicate_acks: state.map_or(0, |state| state.duplicate_acks),
+                            client_rtt: tx_state.as_ref().and_then(|tx_state| tx_state.srtt()),
+                            connect_latency: stream.connect_latency(),
+                            write_latency: stream.write_latency(),
+                        }
+                    })
+                    .collect();
+                let _ = reply.send(connections);
+            }
+            Command::KillConnection(src, dst, is_graceful, reply) => {
+                let is_killed = match self.close_connection(src, dst, is_graceful) {
+                    Ok(is_killed) => is_killed,
+                    Err(ref e) => {
+                        warn!("handle {}: {}", "TCP", e);
+
+                        true
+                    }
+                };
+                let _ = reply.send(is_killed);
+            }
+            Command::SetProxy(remote) => {
+                if remote != self.remote {
+                    info!("Proxy changed from {} to {}", self.remote, remote);
+                    self.remote = remote;
+                    self.balancer.lock().unwrap().set_primary(remote);
+                }
+            }
+            Command::SetRecvWindow(window, wscale) => self.set_recv_window(window, wscale),
+            Command::Trace(src, dst, reply) => {
+                let trace = self
+                    .tracer
+                    .as_ref()
+                    .and_then(|tracer| tracer.lock().unwrap().get(src, dst));
+                let _ = reply.send(trace);
+            }
+            Command::PolicyHits(reply) => {
+                let hits = match self.port_policy {
+                    Some(ref policy) => policy.hits(),
+                    None => Vec::new(),
+                };
+                let _ = reply.send(hits);
+            }
+        }
+    }
+
+    async fn handle_frame(&mut self, frame: &[u8]) {
+        // Middlewares
+        let frame = match self.middlewares {
+            Some(ref middlewares) => match middlewares
+                .lock()
+                .unwrap()
+                .on_rx_frame(Cow::Borrowed(frame))
+            {
+                Some(frame) => frame,
+                None => return,
+            },
+            None => Cow::Borrowed(frame),
+        };
+        let frame = frame.as_ref();
+
+        match Indicator::from(frame) {
+            Ok(ref indicator) => {
+                if let Some(t) = indicator.network_kind() {
+                    match t {
+                        LayerKinds::Arp => {
+                            if let Err(ref e) = self.handle_arp(indicator) {
+                                warn!("handle {}: {}", indicator.brief(), e);
+                            }
+                        }
+                        LayerKinds::Ipv4 => {
+                            if let Err(ref e) = self.handle_ipv4(indicator, frame).await {
+                                warn!("handle {}: {}", indicator.brief(), e);
+                            }
+                        }
+                        _ => {}
+                    }
+                } else if let Some(discovery) = indicator.discovery() {
+                    self.handle_discovery(indicator.ethernet().unwrap().src(), discovery);
+                }
+            }
+            Err(e) => {
+                self.stats.increase_malformed(e.kind());
+                trace!("drop malformed frame ({} Bytes): {}", frame.len(), e);
+                if let Some(ref recorder) = self.recorder {
+                    recorder.lock().unwrap().dump_malformed(frame);
+                }
+            }
+        }
+    }
+
+    fn handle_guard_action(&mut self, action: GuardAction) {
+        let gw_ip_addr = match self.arp_guard {
+            Some(ref guard) => guard.ip_addr(),
+            None => return,
+        };
+
+        match action {
+            GuardAction::Probe => {
+                trace!("probe gateway {}", gw_ip_addr);
+                if let Err(ref e) = self.tx.lock().unwrap().send_arp_probe(gw_ip_addr) {
+                    warn!("probe gateway {}: {}", gw_ip_addr, e);
+                }
+            }
+            GuardAction::Claim => info!("Claim gateway {}", gw_ip_addr),
+            GuardAction::Conflict(owner) => {
+                warn!(
+                    "Gateway {} is owned by {}, stop replying ARP requests for it",
+                    gw_ip_addr, owner
+                );
+                self.stats.increase_arp_conflicts();
+                if let Some(ref events) = self.events {
+                    events.arp_conflict(gw_ip_addr, owner);
+                }
+            }
+        }
+    }
+
+    fn handle_discovery(&mut self, src: HardwareAddr, discovery: &Discovery) {
+        self.stats.increase_discovery(discovery.protocol());
+        if !self.log_discovery {
+            trace!("receive {} from {}", discovery, src);
+            return;
+        }
+
+        let key = (src, discovery.protocol());
+        if self.logged_discoveries.get(&key) == Some(discovery) {
+            trace!("receive {} from {}", discovery, src);
+            return;
+        }
+        info!("Receive {} from {}", discovery, src);
+        self.logged_discoveries.put(key, discovery.clone());
+    }
+
+    fn handle_arp(&mut self, indicator: &Indicator) -> io::Result<()> {
+        if let Some(gw_ip_addr) = self.gw_ip_addr {
+            if let Some(arp) = indicator.arp() {
+                // Another device owns the gateway
+                if arp.src() == gw_ip_addr
+                    && arp.src_hardware_addr() != self.tx.lock().unwrap().local_hardware_addr()
+                {
+                    let action = self
+                        .arp_guard
+                        .as_mut()
+                        .and_then(|guard| guard.receive(arp.src_hardware_addr()));
+                    if let Some(action) = action {
+                        self.handle_guard_action(action);
+                    }
+
+                    return Ok(());
+                }
+
+                let src = arp.src();
+                if src != self.local_ip_addr
+                    && self.sources.contains(src)
+                    && arp.dst() == gw_ip_addr
+                {
+                    debug!(
                         "receive from pcap: {} ({} Bytes)",
                         indicator.brief(),
                         indicator.len()
                     );
 
-                    // Set forwarder's hardware address
-                    if !self.is_tx_src_hardware_addr_set {
-                        self.tx
-                            .lock()
-                            .unwrap()
-                            .set_src_hardware_addr(src, arp.src_hardware_addr());
-                        self.is_tx_src_hardware_addr_set = true;
-                        info!(
-                            "Device {} ({}) joined the network",
+                    // Source binding
+                    if self.is_spoofed(src, arp.src_hardware_addr()) {
+                        trace!(
+                            "drop {} because its source is not bound to {}",
+                            indicator.brief(),
+                            arp.src_hardware_addr()
+                        );
+                        self.stats.increase_spoof_drops();
+
+                        return Ok(());
+                    }
+                    // Set forwarder's hardware address
+                    if !self.is_tx_src_hardware_addr_set {
+                        self.tx
+                            .lock()
+                            .unwrap()
+                            .set_src_hardware_addr(src, arp.src_hardware_addr());
+                        self.is_tx_src_hardware_addr_set = true;
+                        info!(
+                            "Device {} ({}) joined the network",
+                            src,
+                            arp.src_hardware_addr()
+                        );
+                    }
+                    if let Some(ref mut events) = self.events {
+                        events.track_client(src, arp.src_hardware_addr());
+                    }
+                    // Set forwarder's VLAN tags
+                    let ethernet = indicator.ethernet().unwrap();
+                    self.tx
+                        .lock()
+                        .unwrap()
+                        .set_vlan_tags(ethernet.src(), ethernet.tags());
+
+                    // Send only if the gateway is claimed
+                    if let Some(ref guard) = self.arp_guard {
+                        if !guard.is_claimed() {
+                            trace!("not reply ARP for unclaimed gateway {}", gw_ip_addr);
+                            return Ok(());
+                        }
+                    }
+                    self.tx.lock().unwrap().send_arp_reply(src)?
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn handle_ipv4(&mut self, indicator: &Indicator, frame: &[u8]) -> io::Result<()> {
+        if let Some(ipv4) = indicator.ipv4() {
+            let src = ipv4.src();
+            if src != self.local_ip_addr && self.sources.contains(src) {
+                debug!(
+                    "receive from pcap: {} ({} + {} Bytes)",
+                    indicator.brief(),
+                    indicator.len(),
+                    indicator.content_len() - indicator.len()
+                );
+                // Quarantine
+                if self.is_quarantined(src) {
+                    trace!(
+                        "drop {} because its source is quarantined",
+                        indicator.brief()
+                    );
+                    self.stats.increase_quarantine_drops();
+
+                    return Ok(());
+                }
+                // Source binding
+                let src_hardware_addr = indicator.ethernet().unwrap().src();
+                if self.is_spoofed(src, src_hardware_addr) {
+                    trace!(
+                        "drop {} because its source is not bound to {}",
+                        indicator.brief(),
+                        src_hardware_addr
+                    );
+                    self.stats.increase_spoof_drops();
+
+                    return Ok(());
+                }
+                // Set forwarder's hardware address
+                if !self.is_tx_src_hardware_addr_set {
+                    self.tx
+                        .lock()
+                        .unwrap()
+                        .set_src_hardware_addr(src, indicator.ethernet().unwrap().src());
+                    self.is_tx_src_hardware_addr_set = true;
+                    info!(
+                        "Device {} joined the network",
+                        indicator.ethernet().unwrap().src()
+                    );
+                }
+                // Set forwarder's VLAN tags and PPPoE session
+                let ethernet = indicator.ethernet().unwrap();
+                self.tx
+                    .lock()
+                    .unwrap()
+                    .set_vlan_tags(ethernet.src(), ethernet.tags());
+                if let Some(pppoe) = indicator.pppoe() {
+                    let ethernet = indicator.ethernet().unwrap();
+                    self.tx.lock().unwrap().set_pppoe_session(
+                        ethernet.src(),
+                        pppoe.session_id(),
+                        ethernet.dst(),
+                    );
+                }
+                if let Some(ref mut events) = self.events {
+                    events.track_client(src, indicator.ethernet().unwrap().src());
+                }
+                self.stats.add_rx_bytes(frame.len());
+                #[cfg(feature = "metrics")]
+                if let Some(ref tracker) = self.tracker {
+                    let connection = indicator.tcp().map(|tcp| {
+                        (
+                            SocketAddrV4::new(tcp.src_ip_addr(), tcp.src()),
+                            SocketAddrV4::new(tcp.dst_ip_addr(), tcp.dst()),
+                        )
+                    });
+                    tracker.lock().unwrap().add_rx(src, connection, frame.len());
+                }
+                if let Some(ref recorder) = self.recorder {
+                    if let Some(tcp) = indicator.tcp() {
+                        recorder.lock().unwrap().record(
+                            SocketAddrV4::new(tcp.src_ip_addr(), tcp.src()),
+                            SocketAddrV4::new(tcp.dst_ip_addr(), tcp.dst()),
+                            frame,
+                            true,
+                        );
+                    }
+                }
+                if let Some(ref tracer) = self.tracer {
+                    if let Some(tcp) = indicator.tcp() {
+                        tracer.lock().unwrap().segment(
+                            SocketAddrV4::new(tcp.src_ip_addr(), tcp.src()),
+                            SocketAddrV4::new(tcp.dst_ip_addr(), tcp.dst()),
+                            tcp,
+                            indicator.content_len() - indicator.len(),
+                            true,
+                        );
+                    }
+                }
+
+                let frame_without_padding = &frame[..indicator.content_len()];
+
+                // TTL, packets to the local, broadcast and multicast addresses are not routed
+                let dst = ipv4.dst();
+                let is_routed =
+                    dst != self.local_ip_addr && !self.is_broadcast(&dst) && !dst.is_multicast();
+                if ipv4.ttl() <= 1 && is_routed {
+                    #[cfg(feature = "icmp")]
+                    return self.handle_ttl_exceeded(indicator, frame_without_padding);
+                    #[cfg(not(feature = "icmp"))]
+                    {
+                        trace!("drop {} because its TTL is exceeded", indicator.brief());
+                        return Ok(());
+                    }
+                }
+
+                // WireGuard
+                #[cfg(feature = "wireguard")]
+                if let Some(ref mut tunnel) = self.tunnel {
+                    if is_routed {
+                        let link_len = indicator.link_len();
+                        return tunnel.send(&frame_without_padding[link_len..]);
+                    }
+                }
+
+                // Tunnels, including their fragments
+                if let Some(protocol) = TunnelProtocol::from(ipv4.next_level_protocol()) {
+                    let link_len = indicator.link_len();
+                    return self.handle_tunneled(
+                        protocol,
+                        ipv4,
+                        &frame_without_padding[link_len..],
+                    );
+                }
+
+                if ipv4.is_fragment() {
+                    #[cfg(feature = "defrag")]
+                    self.handle_fragment(indicator, frame_without_padding)
+                        .await?;
+                    #[cfg(not(feature = "defrag"))]
+                    trace!("drop {}: fragments are not supported", indicator.brief());
+                } else {
+                    if let Some(transport) = indicator.transport() {
+                        self.handle_transport(
+                            indicator,
+                            transport,
+                            &frame_without_padding[indicator.len()..],
+                        )
+                        .await?;
+                    } else if ipv4.is_igmp() {
+                        self.handle_igmp(
+                            src,
+                            indicator.ethernet().unwrap().src(),
+                            &frame_without_padding[indicator.len()..],
+                        );
+                    } else {
+                        trace!("drop {}: unsupported protocol", indicator.brief());
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    #[cfg(feature = "defrag")]
+    async fn handle_fragment(&mut self, indicator: &Indicator, frame: &[u8]) -> io::Result<()> {
+        let frag = match self.defrag.add(indicator, frame) {
+            Ok(Some(frag)) => frag,
+            Ok(None) => return Ok(()),
+            Err(DefragError::Overlap) => {
+                trace!(
+                    "drop {} and its fragments: {}",
+                    indicator.brief(),
+                    DefragError::Overlap
+                );
+                self.stats.increase_frag_overlaps();
+                self.record_error(indicator.ipv4().unwrap().src());
+                return Ok(());
+            }
+            Err(e) => {
+                trace!("drop {}: {}", indicator.brief(), e);
+                self.stats.increase_frag_drops();
+                return Ok(());
+            }
+        };
+        let (transport, payload) = match frag.concatenate() {
+            Ok(concatenated) => concatenated,
+            Err(e) => {
+                self.stats.increase_malformed(e.kind());
+                self.record_error(indicator.ipv4().unwrap().src());
+                return Err(e.into());
+            }
+        };
+
+        match transport {
+            Some(ref transport) => self.handle_transport(indicator, transport, payload).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn handle_transport(
+        &mut self,
+        indicator: &Indicator,
+        transport: &Layers,
+        payload: &[u8],
+    ) -> io::Result<()> {
+        match transport {
+            #[cfg(feature = "icmp")]
+            Layers::Icmpv4(icmpv4) => {
+                let ipv4 = indicator.ipv4().unwrap();
+                self.handle_icmpv4(ipv4.src(), ipv4.dst(), icmpv4)
+            }
+            #[cfg(not(feature = "icmp"))]
+            Layers::Icmpv4(_) => {
+                trace!("drop {}: ICMPv4 is not supported", indicator.brief());
+
+                Ok(())
+            }
+            Layers::Tcp(tcp) => self.handle_tcp(tcp, payload).await,
+            #[cfg(feature = "udp")]
+            Layers::Udp(udp) => self.handle_udp(udp, payload).await,
+            #[cfg(not(feature = "udp"))]
+            Layers::Udp(_) => {
+                trace!("drop {}: UDP is not supported", indicator.brief());
+
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn handle_tunneled(
+        &mut self,
+        protocol: TunnelProtocol,
+        ipv4: &Ipv4,
+        packet: &[u8],
+    ) -> io::Result<()> {
+        self.stats.increase_tunneled(protocol);
+
+        match self.tunnel_policy {
+            TunnelPolicy::Drop => {
+                trace!("drop {} packet {} -> {}", protocol, ipv4.src(), ipv4.dst());
+            }
+            TunnelPolicy::Log => {
+                let key = (ipv4.src(), ipv4.dst(), protocol);
+                if self.logged_tunnels.get(&key).is_none() {
+                    self.logged_tunnels.put(key, ());
+                    info!(
+                        "Drop {} tunnel {} -> {}, which cannot be redirected",
+                        protocol,
+                        ipv4.src(),
+                        ipv4.dst()
+                    );
+                } else {
+                    trace!("drop {} packet {} -> {}", protocol, ipv4.src(), ipv4.dst());
+                }
+            }
+            TunnelPolicy::Forward(_) => {
+                if let Some(ref mut passthrough) = self.passthrough {
+                    passthrough.send(packet)?;
+                    self.stats.increase_tunneled_forwards();
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    #[cfg(feature = "icmp")]
+    fn handle_ttl_exceeded(&mut self, indicator: &Indicator, frame: &[u8]) -> io::Result<()> {
+        let ipv4 = indicator.ipv4().unwrap();
+        trace!(
+            "drop {} -> {} because its TTL is exceeded",
+            ipv4.src(),
+            ipv4.dst()
+        );
+
+        // Only the first fragment is reported, and ICMPv4 errors are never reported
+        if ipv4.fragment_offset() > 0 {
+            return Ok(());
+        }
+        if let Some(Layers::Icmpv4(ref icmpv4)) = indicator.transport() {
+            if icmpv4.is_error() {
+                return Ok(());
+            }
+        }
+
+        // The IPv4 header and the first 8 bytes of the data
+        let link_len = indicator.link_len();
+        let size = min(frame.len(), link_len + ipv4.len() + 8);
+        let payload = &frame[link_len..size];
+
+        self.tx
+            .lock()
+            .unwrap()
+            .send_icmpv4_time_exceeded(self.local_ip_addr, ipv4.src(), payload)
+    }
+
+    fn handle_igmp(&mut self, src: Ipv4Addr, src_hardware_addr: HardwareAddr, payload: &[u8]) {
+        for membership in IgmpMembership::parse(payload) {
+            match membership {
+                IgmpMembership::Join(group) => {
+                    let members = self
+                        .multicast_groups
+                        .entry(group)
+                        .or_insert_with(HashMap::new);
+                    if members.insert(src, clock::now()).is_none() {
+                        debug!("{} joined multicast group {}", src, group);
+                    }
+                }
+                IgmpMembership::Leave(group) => {
+                    if let Some(members) = self.multicast_groups.get_mut(&group) {
+                        if members.remove(&src).is_some() {
+                            debug!("{} left multicast group {}", src, group);
+                        }
+                        if members.is_empty() {
+                            self.multicast_groups.remove(&group);
+                        }
+                    }
+                }
+            }
+        }
+        self.stats.set_multicast_groups(self.multicast_groups.len());
+
+        // Set forwarder's hardware address for reflecting
+        if self.multicast_mode == MulticastMode::Reflect {
+            self.tx
+                .lock()
+                .unwrap()
+                .set_src_hardware_addr(src, src_hardware_addr);
+        }
+    }
+
+    /// Expires the membership of sources which have not reported for the Group Membership
+    /// Interval, including IGMPv1 hosts which leave groups silently.
+    fn expire_multicast_groups(&mut self) {
+        let timeout = Duration::from_millis(GROUP_MEMBERSHIP_INTERVAL);
+        self.multicast_groups.retain(|group, members| {
+            members.retain(|member, instant| {
+                let is_expired = clock::elapsed(*instant) >= timeout;
+                if is_expired {
+                    debug!("expire {} in multicast group {}", member, group);
+                }
+
+                !is_expired
+            });
+
+            !members.is_empty()
+        });
+        self.stats.set_multicast_groups(self.multicast_groups.len());
+    }
+
+    #[cfg(feature = "icmp")]
+    fn handle_icmpv4(&mut self, src: Ipv4Addr, dst: Ipv4Addr, icmpv4: &Icmpv4) -> io::Result<()> {
+        if icmpv4.is_echo_request() {
+            // Echo request
+            self.handle_icmpv4_echo(src, dst, icmpv4)?;
+        } else if icmpv4.is_destination_port_unreachable() {
+            // Destination port unreachable
+            #[cfg(feature = "udp")]
+            if let Some(LayerKinds::Udp) = icmpv4.next_level_layer_kind() {
+                let dst = icmpv4.dst().unwrap();
+                self.unbind_local_udp_port(dst);
+            }
+        } else if icmpv4.is_fragmentation_required_and_df_flag_set() {
+            // Fragmentation required, and DF flag set
+            let mtu = max(icmpv4.next_hop_mtu().unwrap() as usize, MIN_LEARNED_MTU);
+            let dst_ip_addr = icmpv4.src_ip_addr().unwrap();
+            let src_ip_addr = icmpv4.dst_ip_addr().unwrap();
+            if src == src_ip_addr {
+                // Reported by the source itself
+                if self.tx.lock().unwrap().set_src_mtu(src_ip_addr, mtu) {
+                    info!("Update MTU of {} to {}", src_ip_addr, mtu);
+                }
+            } else {
+                // Reported by a router on the path, which may vary between destinations
+                if self.tx.lock().unwrap().set_dst_mtu(dst_ip_addr, mtu) {
+                    info!("Update path MTU of {} to {}", dst_ip_addr, mtu);
+                }
+            }
+        } else if icmpv4.is_redirect() {
+            // Redirect, pcap2socks is the gateway and will never be redirected
+            self.stats.increase_icmp_redirects();
+            let gateway = match icmpv4.gateway_ip_addr() {
+                Some(gateway) => gateway.to_string(),
+                None => String::from("unknown"),
+            };
+            match self.icmp_policy {
+                IcmpPolicy::Ignore => trace!("ignore ICMP redirect from {} to {}", src, gateway),
+                IcmpPolicy::Log | IcmpPolicy::Honor => {
+                    info!("Ignore ICMP redirect from {} to {}", src, gateway)
+                }
+            }
+        } else if icmpv4.is_source_quench() {
+            // Source quench
+            self.stats.increase_icmp_source_quenches();
+            match self.icmp_policy {
+                IcmpPolicy::Ignore => trace!("ignore ICMP source quench from {}", src),
+                IcmpPolicy::Log => info!("Ignore ICMP source quench from {}", src),
+                IcmpPolicy::Honor => {
+                    if let (Some(LayerKinds::Tcp), Some(dst), Some(src)) =
+                        (icmpv4.next_level_layer_kind(), icmpv4.src(), icmpv4.dst())
+                    {
+                        if self.tx.lock().unwrap().throttle_tcp(dst, src) {
+                            info!("Throttle TCP {} -> {} due to ICMP source quench", dst, src);
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    #[cfg(feature = "icmp")]
+    fn handle_icmpv4_echo(
+        &mut self,
+        src: Ipv4Addr,
+        dst: Ipv4Addr,
+        icmpv4: &Icmpv4,
+    ) -> io::Result<()> {
+        if self.echo_mode == EchoMode::Drop {
+            trace!("drop ICMP echo request {} -> {}", src, dst);
+            return Ok(());
+        }
+        if self.is_broadcast(&dst) || dst.is_multicast() {
+            return Ok(());
+        }
+        self.stats.increase_icmp_echo_replies();
+
+        // The gateway replies at once, and other destinations reply after the RTT of the proxy
+        let rtt = if dst == self.local_ip_addr {
+            Duration::from_millis(0)
+        } else if self.echo_rtt > 0 {
+            Duration::from_millis(self.echo_rtt)
+        } else {
+            self.balancer
+                .lock()
+                .unwrap()
+                .rtts()
+                .iter()
+                .filter_map(|(_, rtt)| *rtt)
+                .min()
+                .unwrap_or_else(|| Duration::from_millis(0))
+        };
+        trace!(
+            "reply ICMP echo request {} -> {} after {} ms",
+            src,
+            dst,
+            rtt.as_millis()
+        );
+        if rtt == Duration::from_millis(0) {
+            return self
+                .tx
+                .lock()
+                .unwrap()
+                .send_icmpv4_echo_reply(dst, src, icmpv4);
+        }
+
+        let tx = self.get_tx();
+        let icmpv4 = icmpv4.clone();
+        tokio::spawn(async move {
+            time::delay_for(rtt).await;
+            if let Err(ref e) = tx.lock().unwrap().send_icmpv4_echo_reply(dst, src, &icmpv4) {
+                warn!("reply ICMP echo request {} -> {}: {}", src, dst, e);
+            }
+        });
+
+        Ok(())
+    }
+
+    async fn handle_tcp(&mut self, tcp: &Tcp, payload: &[u8]) -> io::Result<()> {
+        if tcp.is_rst() {
+            self.handle_tcp_rst(tcp)?;
+        } else if tcp.is_ack() {
+            self.handle_tcp_ack(tcp, payload).await?;
+        } else if tcp.is_syn() {
+            // Pure TCP SYN
+            self.handle_tcp_syn(tcp).await?;
+        } else if tcp.is_fin() {
+            // Pure TCP FIN
+            let src = SocketAddrV4::new(tcp.src_ip_addr(), tcp.src());
+            let dst = SocketAddrV4::new(tcp.dst_ip_addr(), tcp.dst());
+            self.flush_tcp(src, dst)?;
+            self.handle_tcp_fin(tcp, payload)?;
+        } else {
+            // TCP segments without any of SYN, ACK, FIN and RST are invalid
+            self.stats.increase_malformed(LayerKinds::Tcp);
+            self.record_error(tcp.src_ip_addr());
+            trace!("ignore TCP {} without control flags", tcp);
+        }
+
+        Ok(())
+    }
+
+    async fn handle_tcp_ack(&mut self, tcp: &Tcp, payload: &[u8]) -> io::Result<()> {
+        let src = SocketAddrV4::new(tcp.src_ip_addr(), tcp.src());
+        let dst = SocketAddrV4::new(tcp.dst_ip_addr(), tcp.dst());
+        let key = (src, dst);
+        let mut tcp_locked = self.tcp.lock().unwrap();
+        let (is_exist, is_writable, write_queue_size) = match tcp_locked.streams.get(&key) {
+            Some(stream) => (true, !stream.is_write_closed(), stream.write_queue_size()),
+            None => (false, false, 0),
+        };
+        let write_limit = tcp_locked.write_limit;
+
+        if is_exist {
+            // ACK
+            let state = tcp_locked.states.get_mut(&key).unwrap();
+            state.active_instant = clock::now();
+            if tcp.sequence() != seq_add(state.recv_next, state.coalesced.len() as u32) {
+                trace!(
+                    "TCP out of order of {} -> {} at {}",
+                    src,
+                    dst,
+                    tcp.sequence()
+                );
+            }
+            {
+                let mut tx_locked = self.tx.lock().unwrap();
+                let tx_state = tx_locked.get_state(dst, src).unwrap();
+
+                tx_state.acknowledge(tcp.acknowledgement());
+                tx_state.update_send_window(
+                    tcp.acknowledgement(),
+                    (tcp.window() as usize) << state.wscale as usize,
+                );
+
+                // Handshake completed
+                if tx_state.cache_syn().is_none() && self.pending.remove(&key).is_some() {
+                    trace!("complete TCP handshake of {} -> {}", src, dst);
+                    if let Some(ref flows) = self.flows {
+                        flows.send(FlowEvent::Established { src, dst });
+                    }
+                }
+
+                tx_locked.acknowledge_mtu_probe(dst, src, tcp.acknowledgement());
+            }
+
+            if payload.len() > 0 {
+                // Validate the sequence
+                if state.is_beyond_window(tcp.sequence(), payload.len()) {
+                    trace!(
+                        "ignore TCP segment of {} -> {} at {} beyond the window",
+                        src,
+                        dst,
+                        tcp.sequence()
+                    );
+                    self.stats.increase_tcp_invalid_segments();
+                    drop(tcp_locked);
+
+                    return self.send_challenge_ack(dst, src);
+                }
+
+                // Backpressure, the segment will be retransmitted after the window reopens
+                if write_limit > 0 && write_queue_size >= write_limit {
+                    trace!(
+                        "ignore TCP segment of {} -> {} at {} due to the stalled proxy",
+                        src,
+                        dst,
+                        tcp.sequence()
+                    );
+                    if !state.is_window_closed {
+                        state.is_window_closed = true;
+                        tcp_locked.wake();
+                        self.stats.increase_tcp_write_stalls();
+                        debug!("stall TCP {} -> {}", src, dst);
+                    }
+
+                    let mut tx_locked = self.tx.lock().unwrap();
+                    tx_locked.get_state(dst, src).unwrap().set_window(0);
+
+                    // Send ACK0
+                    tx_locked.send_tcp_ack_0(dst, src)?;
+
+                    // Trigger sending remaining data
+                    return tx_locked.send_tcp_ack(dst, src);
+                }
+
+                // Urgent data
+                if let Some(sequence) = tcp.urgent_sequence() {
+                    trace!(
+                        "receive TCP urgent data of {} -> {} to {}",
+                        src,
+                        dst,
+                        sequence
+                    );
+                    if self.urgent_policy == UrgentPolicy::Strip {
+                        state.set_urgent(sequence);
+                    }
+                }
+
+                // ACK
+                // Append to cache
+                let out_of_order_len = state.cache.out_of_order_len();
+                let cont_payload = state.append_cache(tcp.sequence(), payload)?;
+                self.stats.add_tcp_out_of_order_bytes(
+                    state
+                        .cache
+                        .out_of_order_len()
+                        .saturating_sub(out_of_order_len),
+                );
+                if self.tcp_out_of_order_limit > 0 {
+                    let dropped = state.limit_out_of_order(self.tcp_out_of_order_limit);
+                    self.stats.add_tcp_out_of_order_drops(dropped);
+                }
+
+                // SACK
+                if state.sack_perm {
+                    let sacks = state.cache.filled();
+                    self.tx
+                        .lock()
+                        .unwrap()
+                        .get_state(dst, src)
+                        .unwrap()
+                        .set_sacks(&sacks);
+                }
+
+                match cont_payload {
+                    Some(payload) => {
+                        // SNI filter
+                        let action = match self.sni_filter {
+                            Some(ref mut filter) => filter.append(src, dst, &payload),
+                            None => None,
+                        };
+                        match action {
+                            Some(SniAction::Block) => {
+                                drop(tcp_locked);
+                                trace!("block TCP {} -> {} by SNI", src, dst);
+                                self.stats.increase_sni_blocks();
+                                self.audit_refusal(PortProtocol::Tcp, src, dst, "sni-block");
+
+                                // Send ACK/RST
+                                self.tx.lock().unwrap().send_tcp_ack_rst(dst, src)?;
+
+                                // Clean up
+                                self.clean_up(src, dst, CloseReason::Refused);
+
+                                return Ok(());
+                            }
+                            Some(SniAction::Bypass) => {
+                                trace!("bypass TCP {} -> {} by SNI", src, dst);
+                                self.stats.increase_sni_bypasses();
+                                if let Some(ref mut auditor) = self.auditor {
+                                    auditor.set_rule(src, dst, "sni-bypass");
+                                }
+                            }
+                            None => {}
+                        }
+
+                        // Coalesce, and send on push or reaching the thresholds
+                        state.coalesce(payload.as_slice());
+                        if tcp.is_psh() || state.is_coalesce_due() {
+                            tcp_locked.flush(src, dst, &self.tx)?;
+                        } else {
+                            tcp_locked.wake();
+                        }
+                    }
+                    None if !state.coalesced.is_empty() => {
+                        // Retransmission or unordered, send the coalesced payloads first to avoid
+                        // reporting a stale acknowledgement
+                        tcp_locked.flush(src, dst, &self.tx)?;
+                    }
+                    None => {
+                        // Retransmission or unordered
+                        let cache_remaining_size = state.window(write_queue_size, write_limit);
+                        if cache_remaining_size == 0 {
+                            state.is_window_closed = true;
+                            tcp_locked.wake();
+                        }
+
+                        // Update window size
+                        let mut tx_locked = self.tx.lock().unwrap();
+                        let tx_state = tx_locked.get_state(dst, src).unwrap();
+
+                        tx_state.set_window(cache_remaining_size);
+
+                        // Send ACK0
+                        tx_locked.send_tcp_ack_0(dst, src)?;
+                    }
+                }
+            } else {
+                // ACK0
+                if !is_writable && self.tx.lock().unwrap().get_cache_size(dst, src) == 0 {
+                    // LAST_ACK
+                    // Clean up
+                    drop(tcp_locked);
+                    self.clean_up(src, dst, CloseReason::Fin);
+
+                    return Ok(());
+                } else {
+                    let duplicate_acks = state.duplicate_acks;
+                    let is_retrans = state.increase_duplicate(tcp.acknowledgement());
+                    if state.duplicate_acks > duplicate_acks {
+                        self.stats.increase_tcp_duplicate_acks();
+                    }
+                    // Duplicate ACK
+                    if is_retrans && !tcp.is_zero_window() {
+                        // Fast retransmit
+                        let mut is_sr = false;
+                        if state.sack_perm {
+                            if let Some(sacks) = tcp.sack() {
+                                if sacks.len() > 0 {
+                                    // Selective retransmission
+                                    self.tx
+                                        .lock()
+                                        .unwrap()
+                                        .retransmit_tcp_ack_without(dst, src, sacks)?;
+                                    is_sr = true;
+                                }
+                            }
+                        }
+
+                        if !is_sr {
+                            // Back N
+                            self.tx.lock().unwrap().retransmit_tcp_ack(dst, src)?;
+                        }
+
+                        state.clear_duplicate();
+                        state.set_last_retrans();
+                    }
+                }
+            }
+
+            // FIN
+            let is_fin_pending = match tcp_locked.states.get(&key) {
+                Some(state) => state.fin_sequence.is_some(),
+                None => false,
+            };
+            drop(tcp_locked);
+
+            // Trigger sending remaining data
+            self.tx.lock().unwrap().send_tcp_ack(dst, src)?;
+
+            if tcp.is_fin() || is_fin_pending {
+                self.flush_tcp(src, dst)?;
+                self.handle_tcp_fin(tcp, payload)?;
+            }
+        } else {
+            drop(tcp_locked);
+            if !self.handle_tcp_time_wait(tcp)? {
+                // Send RST
+                self.tx.lock().unwrap().send_tcp_rst(dst, src)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn handle_tcp_syn(&mut self, tcp: &Tcp) -> io::Result<()> {
+        let src = SocketAddrV4::new(tcp.src_ip_addr(), tcp.src());
+        let dst = SocketAddrV4::new(tcp.dst_ip_addr(), tcp.dst());
+        let key = (src, dst);
+        let is_exist = self.tcp.lock().unwrap().streams.contains_key(&key);
+
+        // Connect if not connected, drop if established
+        if !is_exist {
+            // Clean up, a new connection ends TIME-WAIT
+            self.clean_up(src, dst, CloseReason::Reset);
+            self.time_waits.pop(&key);
+
+            // Middlewares
+            if !self.accept_connection(src, dst, LayerKinds::Tcp) {
+                trace!("refuse TCP SYN of {} -> {} by middlewares", src, dst);
+                self.stats.increase_tcp_refusals();
+                self.audit_refusal(PortProtocol::Tcp, src, dst, "middleware");
+
+                // Send ACK/RST
+                let acknowledgement = seq_add(tcp.sequence(), 1);
+                return self
+                    .tx
+                    .lock()
+                    .unwrap()
+                    .send_tcp_rst_to_syn(dst, src, acknowledgement);
+            }
+
+            // Port policy
+            if !self.check_port_policy(*src.ip(), PortProtocol::Tcp, dst.port()) {
+                trace!("refuse TCP SYN of {} -> {} by the port policy", src, dst);
+                self.stats.increase_tcp_refusals();
+                let rule = self.port_policy_rule(*src.ip(), PortProtocol::Tcp, dst.port());
+                self.audit_refusal(PortProtocol::Tcp, src, dst, &rule);
+
+                // Send ACK/RST
+                let acknowledgement = seq_add(tcp.sequence(), 1);
+                return self
+                    .tx
+                    .lock()
+                    .unwrap()
+                    .send_tcp_rst_to_syn(dst, src, acknowledgement);
+            }
+
+            // Limit the rate of connection attempts
+            if !self.admit_attempt(*src.ip()) {
+                trace!("refuse TCP SYN of {} -> {} (rate limited)", src, dst);
+                self.stats.increase_tcp_rate_limits();
+                self.audit_refusal(PortProtocol::Tcp, src, dst, "rate-limit");
+
+                // Send ACK/RST
+                let acknowledgement = seq_add(tcp.sequence(), 1);
+                return self
+                    .tx
+                    .lock()
+                    .unwrap()
+                    .send_tcp_rst_to_syn(dst, src, acknowledgement);
+            }
+
+            // Limit connections
+            let len = self.tcp.lock().unwrap().streams.len();
+            if self.tcp_capacity > 0 && len >= self.tcp_capacity {
+                if !self.tcp_eviction {
+                    trace!("refuse TCP SYN of {} -> {} ({} connections)", src, dst, len);
+                    self.stats.increase_tcp_refusals();
+                    self.audit_refusal(PortProtocol::Tcp, src, dst, "capacity");
+
+                    // Send ACK/RST
+                    let acknowledgement = seq_add(tcp.sequence(), 1);
+                    return self
+                        .tx
+                        .lock()
+                        .unwrap()
+                        .send_tcp_rst_to_syn(dst, src, acknowledgement);
+                }
+
+                self.evict_tcp()?;
+            }
+
+            // Limit pending connections
+            if self.tcp_pending_limit > 0 {
+                let pending = self
+                    .pending
+                    .keys()
+                    .filter(|(pending_src, _)| pending_src.ip() == src.ip())
+                    .count();
+                if pending >= self.tcp_pending_limit {
+                    trace!("drop TCP SYN of {} -> {} ({} pending)", src, dst, pending);
+                    self.stats.increase_tcp_syn_drops();
+
+                    return Ok(());
+                }
+            }
+
+            // Back off from the proxy
+            if let Some(&(_, instant)) = self.backoffs.get(&key) {
+                if clock::now() < instant {
+                    trace!("drop TCP SYN of {} -> {} (backing off)", src, dst);
+
+                    return Ok(());
+                }
+            }
+
+            // Admit SYN
+            let wscale = match ENABLE_WSCALE {
+                true => tcp.wscale(),
+                false => None,
+            };
+            let recv_wscale = match wscale {
+                Some(wscale) => Some(min(wscale, self.tcp_recv_wscale)),
+                None => None,
+            };
+            let sack_perm = ENABLE_SACK && tcp.is_sack_perm();
+            let mut state =
+                TcpRxState::new(src, dst, tcp.sequence(), wscale.unwrap_or(0), sack_perm);
+            state.set_recv_window(self.tcp_recv_window, recv_wscale.unwrap_or(0));
+
+            {
+                let mut tx_locked = self.tx.lock().unwrap();
+
+                let sequence = self.isn.generate(src, dst);
+                let acknowledgement = seq_add(tcp.sequence(), 1);
+                if let Some(mss) = tcp.mss() {
+                    let mtu = Ipv4::minimum_len() + Tcp::minimum_len() + mss as usize;
+                    if tx_locked.set_src_mtu(tcp.src_ip_addr(), mtu) {
+                        info!("Update MTU of {} to {}", tcp.src_ip_addr(), mtu);
+                    }
+                }
+
+                let mut tx_state = TcpTxState::new(
+                    src,
+                    dst,
+                    sequence,
+                    acknowledgement,
+                    tcp.window(),
+                    recv_wscale,
+                    sack_perm,
+                    wscale,
+                );
+                tx_state.set_window(self.tcp_recv_window);
+                tx_state.set_pacing(self.tcp_pacing);
+                tx_state.set_max_syn_retries(self.tcp_syn_retries);
+                tx_locked.set_state(dst, src, tx_state);
+            }
+            self.publish_flow(FlowEvent::SynReceived { src, dst });
+
+            // Connect
+            let remote = match self.acceptor {
+                Some(_) => None,
+                None => Some(self.balancer.lock().unwrap().select(dst)),
+            };
+            let stream = match remote {
+                None => self.accept_tcp(src, dst).await,
+                Some(remote) if self.is_sni_inspected(dst) => {
+                    self.inspect_tcp(src, dst, remote).await
+                }
+                Some(remote) => {
+                    StreamWorker::connect(
+                        self.get_tx(),
+                        src,
+                        dst,
+                        remote,
+                        &self.options,
+                        self.tcp_queue_high,
+                        self.tcp_queue_low,
+                    )
+                    .await
+                }
+            };
+
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    if let Some(remote) = remote {
+                        if self.back_off_tcp(src, dst, remote, &e) {
+                            // Clean up, the source will retransmit the SYN
+                            self.clean_up(src, dst, CloseReason::Refused);
+
+                            return Ok(());
+                        }
+                    }
+
+                    {
+                        let mut tx_locked = self.tx.lock().unwrap();
+                        let tx_state = tx_locked.get_state(dst, src).unwrap();
+
+                        tx_state.add_acknowledgement(1);
+
+                        // Send ACK/RST
+                        tx_locked.send_tcp_ack_rst(dst, src)?;
+                    }
+                    let rule = if self.options.is_direct(*dst.ip()) {
+                        "direct"
+                    } else {
+                        "proxy"
+                    };
+                    self.audit_refusal(PortProtocol::Tcp, src, dst, rule);
+
+                    // Clean up
+                    self.clean_up(src, dst, CloseReason::Refused);
+
+                    return Err(e);
+                }
+            };
+
+            self.backoffs.remove(&key);
+            {
+                let mut tcp_locked = self.tcp.lock().unwrap();
+                tcp_locked.states.insert(key, state);
+                tcp_locked.streams.insert(key, stream);
+            }
+            self.pending.insert(key, clock::now());
+            self.audit_acceptance(PortProtocol::Tcp, src, dst);
+        }
+
+        Ok(())
+    }
+
+    /// Records a failure connecting to the proxy, and returns if the TCP connection should back
+    /// off and be retried instead of being reset.
+    fn back_off_tcp(
+        &mut self,
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+        remote: SocketAddrV4,
+        e: &io::Error,
+    ) -> bool {
+        let key = (src, dst);
+        let failure = ConnectFailure::from_error(e);
+        self.stats.increase_connect_failures(failure);
+        self.record_error(*src.ip());
+        if let Some(ref recorder) = self.recorder {
+            recorder
+                .lock()
+                .unwrap()
+                .dump(src, dst, Trigger::ConnectFailure(failure));
+        }
+
+        let retries = self.backoffs.get(&key).map_or(0, |&(retries, _)| retries);
+        let is_retry = failure.is_transient() && retries < self.tcp_connect_retries;
+        if let Some(ref events) = self.events {
+            events.connect_failure(remote, dst, failure, is_retry);
+        }
+        if !is_retry {
+            self.backoffs.remove(&key);
+            return false;
+        }
+
+        let backoff = self
+            .tcp_connect_backoff
+            .saturating_mul(1 << min(retries, 16) as u64);
+        debug!(
+            "back off {} -> {} for {} ms ({} failure): {}",
+            src, dst, backoff, failure, e
+        );
+        self.stats.increase_tcp_connect_retries();
+        self.backoffs.insert(
+            key,
+            (retries + 1, clock::now() + Duration::from_millis(backoff)),
+        );
+
+        true
+    }
+
+    async fn accept_tcp(
+        &mut self,
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+    ) -> io::Result<StreamWorker> {
+        let (stream, connection) = StreamWorker::accept(
+            self.get_tx(),
+            src,
+            dst,
+            self.tcp_queue_high,
+            self.tcp_queue_low,
+        )
+        .await?;
+
+        match self.acceptor.as_ref().unwrap().send(Flow::Tcp(connection)) {
+            Ok(_) => Ok(stream),
+            Err(mpsc::error::SendError(flow)) => {
+                // Drop the worker first so the connection will not send a FIN
+                drop(stream);
+                drop(flow);
+
+                Err(io::Error::new(
+                    io::ErrorKind::BrokenPipe,
+                    "incoming flows are closed",
+                ))
+            }
+        }
+    }
+
+    /// Returns if the TCP connection to the destination is inspected by the SNI filter.
+    fn is_sni_inspected(&self, dst: SocketAddrV4) -> bool {
+        match self.sni_filter {
+            Some(ref filter) => filter.is_inspected(dst.port()),
+            None => false,
+        }
+    }
+
+    /// Completes the handshake of the TCP connection before connecting it, and inspects its
+    /// ClientHello with the SNI filter.
+    async fn inspect_tcp(
+        &mut self,
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+        remote: SocketAddrV4,
+    ) -> io::Result<StreamWorker> {
+        let (stream, connection) = StreamWorker::accept(
+            self.get_tx(),
+            src,
+            dst,
+            self.tcp_queue_high,
+            self.tcp_queue_low,
+        )
+        .await?;
+        self.sni_filter
+            .as_mut()
+            .unwrap()
+            .inspect(connection, remote, self.options.clone());
+
+        Ok(stream)
+    }
+
+    fn evict_tcp(&mut self) -> io::Result<()> {
+        let key = match self
+            .tcp
+            .lock()
+            .unwrap()
+            .streams
+            .iter()
+            .max_by_key(|(_, stream)| stream.idle())
+            .map(|(&key, _)| key)
+        {
+            Some(key) => key,
+            None => return Ok(()),
+        };
+        let (src, dst) = key;
+
+        info!(
+            "Evict TCP connection {} -> {} because the connection limit {} is reached",
+            src, dst, self.tcp_capacity
+        );
+        self.stats.increase_tcp_evictions();
+
+        // Send ACK/RST
+        self.tx.lock().unwrap().send_tcp_ack_rst(dst, src)?;
+
+        // Clean up
+        self.clean_up(src, dst, CloseReason::Evicted);
+
+        Ok(())
+    }
+
+    fn expire_pending_tcp(&mut self) -> io::Result<()> {
+        // Expire pending connections exceeding the timeout, or whose SYN/ACK is retransmitted
+        // for the max count without acknowledgement
+        let timeout = Duration::from_millis(self.tcp_pending_timeout);
+        let expired: Vec<_> = {
+            let mut tx_locked = self.tx.lock().unwrap();
+            self.pending
+                .iter()
+                .filter(|(&(src, dst), instant)| {
+                    (self.tcp_pending_timeout > 0 && clock::elapsed(**instant) >= timeout)
+                        || tx_locked
+                            .get_state(dst, src)
+                            .map_or(false, |tx_state| tx_state.is_syn_timedout())
+                })
+                .map(|(&key, _)| key)
+                .collect()
+        };
+        for (src, dst) in expired {
+            debug!("expire pending TCP connection {} -> {}", src, dst);
+            self.stats.increase_tcp_pending_expirations();
+
+            // Send ACK/RST
+            self.tx.lock().unwrap().send_tcp_ack_rst(dst, src)?;
+
+            // Clean up
+            self.clean_up(src, dst, CloseReason::Timeout);
+        }
+
+        Ok(())
+    }
+
+    fn expire_idle_tcp(&mut self) -> io::Result<()> {
+        if self.tcp_idle_timeout == 0 {
+            return Ok(());
+        }
+
+        // A connection is active if either the source or the proxy is active
+        let timeout = Duration::from_millis(self.tcp_idle_timeout);
+        let tcp_locked = self.tcp.lock().unwrap();
+        let expired: Vec<_> = tcp_locked
+            .streams
+            .iter()
+            .filter_map(|(&key, stream)| {
+                let idle = match tcp_locked.states.get(&key) {
+                    Some(state) => min(stream.idle(), clock::elapsed(state.active_instant)),
+                    None => stream.idle(),
+                };
+
+                if idle >= timeout {
+                    Some((key, idle))
+                } else {
+                    None
+                }
+            })
+            .collect();
+        drop(tcp_locked);
+        for ((src, dst), idle) in expired {
+            info!(
+                "Reap TCP connection {} -> {} because it is idle for {} s",
+                src,
+                dst,
+                idle.as_secs()
+            );
+            self.stats.increase_tcp_idle_reaps();
+            if let Some(ref events) = self.events {
+                events.connection_reaped(src, dst, idle);
+            }
+
+            // Send ACK/RST
+            self.tx.lock().unwrap().send_tcp_ack_rst(dst, src)?;
+
+            // Clean up
+            self.clean_up(src, dst, CloseReason::Idle);
+        }
+
+        Ok(())
+    }
+
+    fn expire_fin_wait_tcp(&mut self) {
+        // A connection is in FIN-WAIT-2 if its FIN is acknowledged, and expires if the source
+        // is silent for the timeout
+        let timeout = Duration::from_millis(FIN_WAIT_2_TIMEOUT);
+        let expired: Vec<_> = {
+            let tcp_locked = self.tcp.lock().unwrap();
+            let mut tx_locked = self.tx.lock().unwrap();
+            tcp_locked
+                .states
+                .iter()
+                .filter(|(&(src, dst), state)| {
+                    clock::elapsed(state.active_instant) >= timeout
+                        && tx_locked
+                            .get_state(dst, src)
+                            .and_then(|tx_state| tx_state.fin_acked())
+                            .map_or(false, |instant| clock::elapsed(instant) >= timeout)
+                })
+                .map(|(&key, _)| key)
+                .collect()
+        };
+        for (src, dst) in expired {
+            debug!("expire TCP FIN-WAIT-2 of {} -> {}", src, dst);
+
+            self.time_wait_tcp(src, dst, CloseReason::Timeout);
+        }
+    }
+
+    /// Cleans up a TCP connection whose FIN is sent, and keeps it in TIME-WAIT, so the
+    /// retransmitted FINs of the source are still acknowledged instead of being reset.
+    fn time_wait_tcp(&mut self, src: SocketAddrV4, dst: SocketAddrV4, reason: CloseReason) {
+        let time_wait = self
+            .tx
+            .lock()
+            .unwrap()
+            .get_state(dst, src)
+            .map(|tx_state| TimeWait {
+                sequence: seq_add(tx_state.sequence(), tx_state.cache_fin().is_some() as u32),
+                acknowledgement: tx_state.acknowledgement(),
+                instant: clock::now(),
+            });
+
+        self.clean_up(src, dst, reason);
+        if let Some(time_wait) = time_wait {
+            self.time_waits.put((src, dst), time_wait);
+            trace!("enter TCP TIME-WAIT of {} -> {}", src, dst);
+        }
+    }
+
+    /// Handles a TCP segment of a TCP connection in TIME-WAIT, acknowledging retransmitted FINs
+    /// and ignoring others. Returns if the TCP connection is in TIME-WAIT.
+    fn handle_tcp_time_wait(&mut self, tcp: &Tcp) -> io::Result<bool> {
+        let src = SocketAddrV4::new(tcp.src_ip_addr(), tcp.src());
+        let dst = SocketAddrV4::new(tcp.dst_ip_addr(), tcp.dst());
+        let key = (src, dst);
+
+        let time_wait = match self.time_waits.get_mut(&key) {
+            Some(time_wait) => time_wait,
+            None => return Ok(false),
+        };
+        if clock::elapsed(time_wait.instant) >= Duration::from_millis(TIME_WAIT_TIMEOUT) {
+            self.time_waits.pop(&key);
+            trace!("leave TCP TIME-WAIT of {} -> {}", src, dst);
+
+            return Ok(false);
+        }
+
+        if tcp.is_fin() {
+            // Restart the timer
+            time_wait.instant = clock::now();
+            let (sequence, acknowledgement) = (time_wait.sequence, time_wait.acknowledgement);
+            trace!("acknowledge TCP FIN of {} -> {} in TIME-WAIT", src, dst);
+
+            // Send ACK0
+            self.tx
+                .lock()
+                .unwrap()
+                .send_tcp_ack_to(dst, src, sequence, acknowledgement)?;
+        } else {
+            trace!("ignore TCP segment of {} -> {} in TIME-WAIT", src, dst);
+        }
+
+        Ok(true)
+    }
+
+    fn handle_tcp_rst(&mut self, tcp: &Tcp) -> io::Result<()> {
+        let src = SocketAddrV4::new(tcp.src_ip_addr(), tcp.src());
+        let dst = SocketAddrV4::new(tcp.dst_ip_addr(), tcp.dst());
+        let key = (src, dst);
+        self.record_error(*src.ip());
+
+        // Validate the sequence (RFC 5961)
+        let state = self
+            .tcp
+            .lock()
+            .unwrap()
+            .states
+            .get(&key)
+            .map(|state| (state.recv_next, state.is_in_window(tcp.sequence())));
+        if let Some((recv_next, is_in_window)) = state {
+            let acknowledgement = match self.tx.lock().unwrap().get_state(dst, src) {
+                Some(tx_state) => tx_state.acknowledgement(),
+                None => recv_next,
+            };
+            let sequence = tcp.sequence();
+            if sequence != recv_next && sequence != acknowledgement {
+                if is_in_window {
+                    // Challenge ACK
+                    trace!("challenge TCP RST of {} -> {} at {}", src, dst, sequence);
+                    self.send_challenge_ack(dst, src)?;
+                } else {
+                    trace!("ignore TCP RST of {} -> {} at {}", src, dst, sequence);
+                }
+                self.stats.increase_tcp_invalid_segments();
+
+                return Ok(());
+            }
+        }
+
+        // Clean up
+        self.clean_up(src, dst, CloseReason::Reset);
+
+        Ok(())
+    }
+
+    /// Writes the coalesced payloads of the TCP connection to the stream.
+    fn flush_tcp(&mut self, src: SocketAddrV4, dst: SocketAddrV4) -> io::Result<()> {
+        let result = self.tcp.lock().unwrap().flush(src, dst, &self.tx);
+        self.clean_up_failed_tcp();
+
+        result
+    }
+
+    /// Cleans up TCP connections failing to write to their streams.
+    fn clean_up_failed_tcp(&mut self) {
+        let failed = mem::take(&mut self.tcp.lock().unwrap().failed);
+        for (src, dst) in failed {
+            self.clean_up(src, dst, CloseReason::Error);
+        }
+    }
+
+    fn send_challenge_ack(&mut self, dst: SocketAddrV4, src: SocketAddrV4) -> io::Result<()> {
+        // Limit the rate
+        if clock::elapsed(self.challenge_ack_instant) >= Duration::from_secs(1) {
+            self.challenge_acks = 0;
+            self.challenge_ack_instant = clock::now();
+        }
+        if self.challenge_acks >= CHALLENGE_ACK_LIMIT {
+            return Ok(());
+        }
+        self.challenge_acks += 1;
+        self.stats.increase_tcp_challenge_acks();
+
+        self.tx.lock().unwrap().send_tcp_ack_0(dst, src)
+    }
+
+    fn handle_tcp_fin(&mut self, tcp: &Tcp, payload: &[u8]) -> io::Result<()> {
+        let src = SocketAddrV4::new(tcp.src_ip_addr(), tcp.src());
+        let dst = SocketAddrV4::new(tcp.dst_ip_addr(), tcp.dst());
+        let key = (src, dst);
+        let mut tcp_locked = self.tcp.lock().unwrap();
+        let (is_exist, is_readable) = match tcp_locked.streams.get(&key) {
+            Some(stream) => (true, !stream.is_read_closed()),
+            None => (false, false),
+        };
+
+        if is_exist {
+            let state = tcp_locked.states.get_mut(&key).unwrap();
+            if tcp.is_fin() {
+                // Update FIN sequence
+                state.set_fin_sequence(seq_add(tcp.sequence(), payload.len() as u32));
+            }
+
+            // If the receive next is the same as the FIN sequence, the FIN should be popped
+            if let Some(fin_sequence) = state.fin_sequence {
+                if fin_sequence == state.recv_next {
+                    // Admit FIN
+                    state.admit_fin();
+                    state.add_recv_next(1);
+
+                    {
+                        let mut tx_locked = self.tx.lock().unwrap();
+                        let tx_state = tx_locked.get_state(dst, src).unwrap();
+
+                        tx_state.add_acknowledgement(1);
+
+                        // Send ACK0
+                        tx_locked.send_tcp_ack_0(dst, src)?;
+                    }
+                    if is_readable {
+                        // Close by local
+                        let stream = tcp_locked.streams.get_mut(&key).unwrap();
+                        stream.shutdown(Shutdown::Write);
+                        self.publish_flow(FlowEvent::HalfClosed {
+                            src,
+                            dst,
+                            by_source: true,
+                        });
+                    } else {
+                        // Close by remote
+                        // Clean up, and keep in TIME-WAIT
+                        drop(tcp_locked);
+                        self.time_wait_tcp(src, dst, CloseReason::Fin);
+                    }
+                } else {
+                    trace!(
+                        "TCP out of order of {} -> {} at {}",
+                        src,
+                        dst,
+                        tcp.sequence()
+                    );
+
+                    if payload.len() == 0 {
+                        // Send ACK0
+                        self.tx.lock().unwrap().send_tcp_ack_0(dst, src)?;
+                    }
+                }
+            }
+        } else {
+            drop(tcp_locked);
+            if !self.handle_tcp_time_wait(tcp)? {
+                // Send RST
+                self.tx.lock().unwrap().send_tcp_rst(dst, src)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Closes the TCP connection from the source to the destination and tears down its SOCKS
+    /// worker. A graceful close sends the queued data followed by a FIN to the source, while
+    /// others reset the connection immediately. Returns `false` if the connection does not exist.
+    pub fn close_connection(
+        &mut self,
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+        graceful: bool,
+    ) -> io::Result<bool> {
+        let key = (src, dst);
+
+        if !self.tcp.lock().unwrap().streams.contains_key(&key) {
+            return Ok(false);
+        }
+
+        if graceful {
+            info!("Close TCP connection {} -> {}", src, dst);
+
+            // Stop the SOCKS worker, the data already sent to it is still written
+            self.tcp
+                .lock()
+                .unwrap()
+                .streams
+                .get_mut(&key)
+                .unwrap()
+                .close();
+
+            // Send FIN after the queued data, unless the proxy has closed
+            let mut tx_locked = self.tx.lock().unwrap();
+            let is_fin = match tx_locked.get_state(dst, src) {
+                Some(tx_state) => tx_state.cache_fin().is_some() || tx_state.queue_fin(),
+                None => true,
+            };
+            if !is_fin {
+                tx_locked.close_stream(dst, src)?;
+            }
+        } else {
+            info!("Kill TCP connection {} -> {}", src, dst);
+
+            // Log before the state is removed
+            self.log_tcp_summary(src, dst);
+
+            // Send ACK/RST
+            let result = self.tx.lock().unwrap().abort(dst, src);
+
+            // Clean up
+            self.clean_up(src, dst, CloseReason::Killed);
+            result?;
+        }
+
+        Ok(true)
+    }
+
+    fn clean_up(&mut self, src: SocketAddrV4, dst: SocketAddrV4, reason: CloseReason) {
+        let key = (src, dst);
+
+        // Only connections whose SYN is admitted are closed
+        let is_exist = self.tcp.lock().unwrap().states.contains_key(&key)
+            || self.tx.lock().unwrap().get_state(dst, src).is_some();
+        if is_exist {
+            self.publish_flow(FlowEvent::Closed { src, dst, reason });
+        }
+
+        self.log_tcp_summary(src, dst);
+        self.audit_tcp_close(src, dst);
+        {
+            let mut tcp_locked = self.tcp.lock().unwrap();
+            tcp_locked.streams.remove(&key);
+            tcp_locked.states.remove(&key);
+        }
+        self.pending.remove(&key);
+        if let Some(ref mut filter) = self.sni_filter {
+            filter.remove(src, dst);
+        }
+
+        self.tx.lock().unwrap().clean_up(dst, src);
+    }
+
+    /// Logs the retransmissions and the losses of a TCP connection before it is cleaned up.
+    fn log_tcp_summary(&self, src: SocketAddrV4, dst: SocketAddrV4) {
+        let tcp_locked = self.tcp.lock().unwrap();
+        let state = match tcp_locked.states.get(&(src, dst)) {
+            Some(state) => state,
+            None => return,
+        };
+        let mut tx_locked = self.tx.lock().unwrap();
+        let tx_state = match tx_locked.get_state(dst, src) {
+            Some(tx_state) => tx_state,
+            None => return,
+        };
+
+        debug!(
+            "close TCP {} -> {}: {} segments ({} Bytes) retransmitted ({} fast, {} timed out), {} duplicate ACKs, {} Bytes out of order dropped",
+            src,
+            dst,
+            tx_state.retrans_segments(),
+            tx_state.retrans_bytes(),
+            tx_state.fast_retransmits(),
+            tx_state.rto_retransmits(),
+            state.duplicate_acks,
+            state.out_of_order_drops
+        );
+    }
+
+    #[cfg(feature = "udp")]
+    async fn handle_udp(&mut self, udp: &Udp, payload: &[u8]) -> io::Result<()> {
+        let src = SocketAddrV4::new(udp.src_ip_addr(), udp.src());
+        let mut dst = SocketAddrV4::new(udp.dst_ip_addr(), udp.dst());
+
+        // Broadcast
+        if self.is_broadcast(dst.ip()) {
+            // Answer NetBIOS name queries locally
+            if dst.port() == NBNS_PORT {
+                if let Some(query) = NameQuery::parse(payload) {
+                    let answer = self
+                        .netbios_names
+                        .iter()
+                        .find(|(name, _)| query.is_match(name))
+                        .map(|(_, addr)| *addr);
+                    if let Some(addr) = answer {
+                        trace!(
+                            "answer NetBIOS name query {} -> {} of {} with {}",
                             src,
-                            arp.src_hardware_addr()
+                            dst,
+                            query.name(),
+                            addr
                         );
+                        self.tx.lock().unwrap().send_udp(
+                            SocketAddrV4::new(self.local_ip_addr, NBNS_PORT),
+                            src,
+                            &query.answer(addr),
+                        )?;
+                        self.stats.increase_broadcast_answers();
+
+                        return Ok(());
+                    }
+                }
+            }
+
+            match self.broadcast_mode {
+                BroadcastMode::Proxy => {}
+                BroadcastMode::Drop => {
+                    trace!("drop broadcast datagram {} -> {}", src, dst);
+                    self.stats.increase_broadcast_drops();
+
+                    return Ok(());
+                }
+                BroadcastMode::Relay(addr) => {
+                    trace!("relay broadcast datagram {} -> {} to {}", src, dst, addr);
+                    dst = SocketAddrV4::new(addr, dst.port());
+                    self.stats.increase_broadcast_relays();
+                }
+            }
+        }
+
+        // Multicast
+        if dst.ip().is_multicast() {
+            return self.handle_multicast_udp(src, dst, payload).await;
+        }
+
+        // Keep QUIC sessions on the same port
+        if let Some(ref header) = QuicHeader::parse(payload) {
+            self.migrate_quic_session(src, header, payload);
+        }
+
+        // Middlewares
+        if !self.datagram_map.contains_key(&src)
+            && !self.accept_connection(src, dst, LayerKinds::Udp)
+        {
+            trace!("refuse UDP datagram {} -> {} by middlewares", src, dst);
+            self.audit_refusal(PortProtocol::Udp, src, dst, "middleware");
+
+            return Ok(());
+        }
+        // Port policy
+        if !self.check_port_policy(*src.ip(), PortProtocol::Udp, dst.port()) {
+            trace!("refuse UDP datagram {} -> {} by the port policy", src, dst);
+            if !self.datagram_map.contains_key(&src) {
+                let rule = self.port_policy_rule(*src.ip(), PortProtocol::Udp, dst.port());
+                self.audit_refusal(PortProtocol::Udp, src, dst, &rule);
+            }
+
+            return Ok(());
+        }
+        // Limit the rate of connection attempts
+        if !self.datagram_map.contains_key(&src) && !self.admit_attempt(*src.ip()) {
+            trace!("drop UDP datagram {} -> {} (rate limited)", src, dst);
+            self.stats.increase_udp_rate_limits();
+            self.audit_refusal(PortProtocol::Udp, src, dst, "rate-limit");
+
+            return Ok(());
+        }
+        let payload = match self.middlewares {
+            Some(ref middlewares) => match middlewares.lock().unwrap().on_payload(
+                src,
+                dst,
+                LayerKinds::Udp,
+                Cow::Borrowed(payload),
+            ) {
+                Some(payload) => payload,
+                None => return Ok(()),
+            },
+            None => Cow::Borrowed(payload),
+        };
+
+        // Bind
+        let is_new = !self.datagram_map.contains_key(&src);
+        let port = self.bind_local_udp_port(src, dst).await?;
+        if is_new {
+            self.audit_acceptance(PortProtocol::Udp, src, dst);
+        }
+
+        // Send
+        self.send_datagram(port, &payload, dst)
+    }
+
+    /// Returns if a connection attempt of the source is admitted by the rate limiter.
+    fn admit_attempt(&mut self, ip_addr: Ipv4Addr) -> bool {
+        match self.limiter {
+            Some(ref mut limiter) => limiter.admit(ip_addr),
+            None => true,
+        }
+    }
+
+    /// Returns if a connection of the source to the destination port is allowed by the port
+    /// policy.
+    fn check_port_policy(&mut self, ip_addr: Ipv4Addr, protocol: PortProtocol, port: u16) -> bool {
+        match self.port_policy {
+            Some(ref mut policy) => policy.check(ip_addr, protocol, port),
+            None => true,
+        }
+    }
+
+    /// Returns the rule of the port policy matching a connection of the source to the destination
+    /// port for the audit log, or `default` if no rule matches.
+    fn port_policy_rule(&self, ip_addr: Ipv4Addr, protocol: PortProtocol, port: u16) -> String {
+        match self
+            .port_policy
+            .as_ref()
+            .and_then(|policy| policy.rule(ip_addr, protocol, port))
+        {
+            Some(rule) => format!("port-policy {}", rule),
+            None => "default".to_string(),
+        }
+    }
+
+    /// Records an accepted connection in the audit log. A TCP connection is tracked until it is
+    /// closed.
+    fn audit_acceptance(&mut self, protocol: PortProtocol, src: SocketAddrV4, dst: SocketAddrV4) {
+        let rule = match self.auditor {
+            Some(_) => self.port_policy_rule(*src.ip(), protocol, dst.port()),
+            None => return,
+        };
+        if let Some(ref mut auditor) = self.auditor {
+            match protocol {
+                PortProtocol::Tcp => auditor.accept_tcp(src, dst, &rule),
+                PortProtocol::Udp => {
+                    let hardware_addr = self.tx.lock().unwrap().get_src_hardware_addr(*src.ip());
+                    auditor.accept_udp(src, hardware_addr, dst, &rule);
+                }
+            }
+        }
+    }
+
+    /// Records a refused connection in the audit log.
+    fn audit_refusal(
+        &mut self,
+        protocol: PortProtocol,
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+        rule: &str,
+    ) {
+        if let Some(ref mut auditor) = self.auditor {
+            let hardware_addr = self.tx.lock().unwrap().get_src_hardware_addr(*src.ip());
+            auditor.refuse(protocol, src, hardware_addr, dst, rule);
+        }
+    }
+
+    /// Records a TCP connection in the audit log when it is closed.
+    fn audit_tcp_close(&mut self, src: SocketAddrV4, dst: SocketAddrV4) {
+        let auditor = match self.auditor {
+            Some(ref mut auditor) => auditor,
+            None => return,
+        };
+        let rx_bytes = match self.tcp.lock().unwrap().streams.get(&(src, dst)) {
+            Some(stream) => stream.written(),
+            None => 0,
+        };
+        let mut tx_locked = self.tx.lock().unwrap();
+        let hardware_addr = tx_locked.get_src_hardware_addr(*src.ip());
+        let tx_bytes = match tx_locked.get_state(dst, src) {
+            Some(tx_state) => tx_state.queued_bytes(),
+            None => 0,
+        };
+
+        auditor.close_tcp(src, hardware_addr, dst, rx_bytes, tx_bytes);
+    }
+
+    /// Records an error of the source, and quarantines the source if its error rate exceeds the
+    /// threshold.
+    fn record_error(&mut self, ip_addr: Ipv4Addr) {
+        let quarantine = match self.quarantine {
+            Some(ref mut quarantine) => quarantine,
+            None => return,
+        };
+        if !quarantine.record(ip_addr) {
+            return;
+        }
+
+        let duration = quarantine.duration();
+        warn!(
+            "quarantine {} for {} seconds because of too many errors",
+            ip_addr,
+            duration.as_secs()
+        );
+        self.stats.increase_quarantines();
+        if let Some(ref events) = self.events {
+            events.quarantined(ip_addr, duration);
+        }
+    }
+
+    /// Returns if the source is quarantined.
+    fn is_quarantined(&mut self, ip_addr: Ipv4Addr) -> bool {
+        match self.quarantine {
+            Some(ref mut quarantine) => quarantine.is_quarantined(ip_addr),
+            None => false,
+        }
+    }
+
+    /// Returns if the frame of the source from the hardware address is spoofed, which is if the
+    /// source is bound to another hardware address.
+    fn is_spoofed(&self, ip_addr: Ipv4Addr, hardware_addr: HardwareAddr) -> bool {
+        match self.bindings {
+            Some(ref bindings) => !bindings.lock().unwrap().check(ip_addr, hardware_addr),
+            None => false,
+        }
+    }
+
+    fn accept_connection(&mut self, src: SocketAddrV4, dst: SocketAddrV4, kind: LayerKind) -> bool {
+        match self.middlewares {
+            Some(ref middlewares) => middlewares
+                .lock()
+                .unwrap()
+                .on_new_connection(src, dst, kind),
+            None => true,
+        }
+    }
+
+    /// Sends the datagram to the destination on the local port. The datagram is dropped if the
+    /// queue of the port is full because the proxy is stalled.
+    #[cfg(feature = "udp")]
+    fn send_datagram(&mut self, port: u16, payload: &[u8], dst: SocketAddrV4) -> io::Result<()> {
+        match self.datagrams.get_mut(&port).unwrap().send_to(payload, dst) {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                trace!("drop datagram {} -> {} due to the stalled proxy", port, dst);
+                self.stats.increase_udp_stall_drops();
+
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    #[cfg(feature = "udp")]
+    async fn handle_multicast_udp(
+        &mut self,
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+        payload: &[u8],
+    ) -> io::Result<()> {
+        match self.multicast_mode.clone() {
+            MulticastMode::Drop => {
+                trace!("drop multicast datagram {} -> {}", src, dst);
+                self.stats.increase_multicast_drops();
+            }
+            MulticastMode::Reflect => {
+                let members = match self.multicast_groups.get(dst.ip()) {
+                    Some(members) => members.keys().cloned().collect(),
+                    None => Vec::new(),
+                };
+                for member in members {
+                    if member == *src.ip() {
+                        continue;
+                    }
+                    trace!(
+                        "reflect multicast datagram {} -> {} to {}",
+                        src,
+                        dst,
+                        member
+                    );
+                    self.tx.lock().unwrap().send_udp(
+                        src,
+                        SocketAddrV4::new(member, dst.port()),
+                        payload,
+                    )?;
+                    self.stats.increase_multicast_reflections();
+                }
+            }
+            MulticastMode::Relay(addrs) => {
+                let port = self.bind_local_udp_port(src, dst).await?;
+                for addr in addrs {
+                    trace!("relay multicast datagram {} -> {} to {}", src, dst, addr);
+                    self.send_datagram(port, payload, SocketAddrV4::new(addr, dst.port()))?;
+                    self.stats.increase_multicast_relays();
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn is_broadcast(&self, addr: &Ipv4Addr) -> bool {
+        addr.is_broadcast() || self.sources.is_broadcast(*addr)
+    }
+
+    #[cfg(feature = "udp")]
+    async fn bind_local_udp_port(
+        &mut self,
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+    ) -> io::Result<u16> {
+        let local_port = self.datagram_map.get(&src);
+        match local_port {
+            Some(&local_port) => {
+                // Update LRU
+                self.udp_lru.get(&local_port);
+
+                Ok(local_port)
+            }
+            None => {
+                // Expire idle UDP ports before reusing any port
+                if self.udp_lru.len() >= self.udp_lru.cap() {
+                    self.expire_local_udp_ports();
+                }
+
+                // Sessions are never reused by another source
+                if self.acceptor.is_some() {
+                    if self.udp_lru.len() >= self.udp_lru.cap() {
+                        if let Some((_, prev_src)) = self.udp_lru.pop_lru() {
+                            self.unbind_local_udp_port(prev_src);
+                        }
+                    }
+
+                    return self.accept_udp(src);
+                }
+
+                let bind_port = if self.udp_lru.len() < self.udp_lru.cap() {
+                    let remote = self.balancer.lock().unwrap().select(dst);
+                    // Bind the local port of the source before the restart if possible, so the
+                    // NAT binding seen by its peers is kept
+                    let local_port = self.restored_ports.remove(&src).unwrap_or(0);
+                    match DatagramWorker::bind_at(
+                        self.get_tx(),
+                        src,
+                        remote,
+                        &self.options,
+                        self.nat_mode,
+                        local_port,
+                    )
+                    .await
+                    {
+                        Ok((worker, port)) => {
+                            self.datagrams.insert(port, worker);
+
+                            // Update map and LRU
+                            self.datagram_map.insert(src, port);
+                            self.udp_lru.put(port, src);
+                            self.stats.set_udp_bindings(self.udp_lru.len());
+                            self.publish_flow(FlowEvent::UdpBound { src, port });
+
+                            trace!("bind UDP port {} = {}", port, src);
+
+                            Ok(port)
+                        }
+                        Err(e) => Err(e),
+                    }
+                } else {
+                    warn!(
+                        "UDP port table is full ({} ports), consider increasing it by --udp-capacity",
+                        self.udp_lru.cap()
+                    );
+
+                    Err(io::Error::new(io::ErrorKind::Other, "cannot bind UDP port"))
+                };
+
+                match bind_port {
+                    Ok(port) => Ok(port),
+                    Err(e) => {
+                        if self.udp_lru.is_empty() {
+                            Err(e)
+                        } else {
+                            let pair = self.udp_lru.pop_lru().unwrap();
+                            let port = pair.0;
+                            let prev_src = pair.1;
+
+                            // Reuse
+                            self.datagram_map.remove(&prev_src);
+                            self.untrack_quic_conn_ids(prev_src);
+                            let worker = self.datagrams.get_mut(&port).unwrap();
+                            worker.set_src(&src);
+                            // Peers of the previous source should not pass the filter
+                            worker.clear_peers();
+                            trace!("reuse UDP port {} = {} to {}", port, prev_src, src);
+                            self.datagram_map.insert(src, port);
+                            self.stats.increase_udp_reuses();
+                            self.publish_flow(FlowEvent::UdpExpired {
+                                src: prev_src,
+                                port,
+                            });
+                            self.publish_flow(FlowEvent::UdpBound { src, port });
+
+                            // Update LRU
+                            self.udp_lru.put(port, src);
+
+                            Ok(port)
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[cfg(feature = "udp")]
+    fn accept_udp(&mut self, src: SocketAddrV4) -> io::Result<u16> {
+        // Find a port which is not in use
+        loop {
+            self.session_port = self.session_port.checked_add(1).unwrap_or(1);
+            if !self.datagrams.contains_key(&self.session_port) {
+                break;
+            }
+        }
+        let port = self.session_port;
+
+        let (worker, session) = DatagramWorker::accept(self.get_tx(), src, port, self.nat_mode);
+        if let Err(_) = self.acceptor.as_ref().unwrap().send(Flow::Udp(session)) {
+            return Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "incoming flows are closed",
+            ));
+        }
+        self.datagrams.insert(port, worker);
+
+        // Update map and LRU
+        self.datagram_map.insert(src, port);
+        self.udp_lru.put(port, src);
+        self.stats.set_udp_bindings(self.udp_lru.len());
+        self.publish_flow(FlowEvent::UdpBound { src, port });
+
+        trace!("accept UDP port {} = {}", port, src);
+
+        Ok(port)
+    }
+
+    #[cfg(feature = "udp")]
+    fn unbind_local_udp_port(&mut self, src: SocketAddrV4) {
+        let local_port = self.datagram_map.get(&src);
+        match local_port {
+            Some(&local_port) => {
+                self.datagrams.remove(&local_port);
+                self.udp_lru.pop(&local_port);
+                self.datagram_map.remove(&src);
+                self.untrack_quic_conn_ids(src);
+                self.stats.set_udp_bindings(self.udp_lru.len());
+                self.publish_flow(FlowEvent::UdpExpired {
+                    src,
+                    port: local_port,
+                });
+
+                trace!("unbind UDP port {} = {}", local_port, src);
+            }
+            None => {}
+        }
+    }
+
+    #[cfg(feature = "udp")]
+    fn migrate_quic_session(&mut self, src: SocketAddrV4, header: &QuicHeader, payload: &[u8]) {
+        if self.datagram_map.contains_key(&src) {
+            return;
+        }
+
+        let prev_src = match self.quic_conn_ids.lock().unwrap().lookup(header, payload) {
+            Some(prev_src) => prev_src,
+            None => return,
+        };
+        let port = match self.datagram_map.get(&prev_src) {
+            Some(&port) => port,
+            None => return,
+        };
+        let worker = match self.datagrams.get_mut(&port) {
+            Some(worker) => worker,
+            None => return,
+        };
+
+        // Only the source port may change
+        if prev_src.ip() != src.ip() {
+            return;
+        }
+
+        // Migrate
+        worker.set_src(&src);
+        self.quic_conn_ids.lock().unwrap().migrate(prev_src, src);
+        self.datagram_map.remove(&prev_src);
+        self.datagram_map.insert(src, port);
+        self.udp_lru.pop(&port);
+        self.udp_lru.put(port, src);
+        self.stats.increase_quic_migrations();
+        self.publish_flow(FlowEvent::UdpExpired {
+            src: prev_src,
+            port,
+        });
+        self.publish_flow(FlowEvent::UdpBound { src, port });
+
+        debug!(
+            "migrate QUIC session on UDP port {} from {} to {}",
+            port, prev_src, src
+        );
+    }
+
+    #[cfg(feature = "udp")]
+    fn untrack_quic_conn_ids(&mut self, src: SocketAddrV4) {
+        let mut quic_conn_ids = self.quic_conn_ids.lock().unwrap();
+        quic_conn_ids.untrack(src);
+        self.stats.set_quic_sessions(quic_conn_ids.len());
+    }
+
+    #[cfg(feature = "udp")]
+    fn expire_local_udp_ports(&mut self) {
+        if self.udp_timeout == 0 {
+            return;
+        }
+
+        let timeout = Duration::from_millis(self.udp_timeout);
+        let expired: Vec<_> = self
+            .datagrams
+            .values()
+            .filter(|worker| worker.is_closed() || worker.idle() >= timeout)
+            .map(|worker| worker.src())
+            .collect();
+        for src in expired {
+            self.unbind_local_udp_port(src);
+            self.stats.increase_udp_expirations();
+
+            debug!("expire UDP port of {}", src);
+        }
+    }
+
+    fn get_tx(&self) -> Arc<Mutex<Forwarder>> {
+        Arc::clone(&self.tx)
+    }
+}
+
+impl Drop for Redirector {
+    fn drop(&mut self) {
+        // Persist the path MTU learned since the last sweep
+        self.save_mtu_cache();
+    }
+}
+
+/// Represents the capacity of the frame queue of a worker.
+const WORKER_QUEUE_SIZE: usize = 1024;
+
+/// Represents the expire time of the worker of a group of fragments, since its first fragment is
+/// received.
+const FRAGMENT_EXPIRE_TIME: u64 = 10000;
+
+/// Represents the max number of fragments of a group waiting for the first fragment.
+const MAX_PENDING_FRAGMENTS: usize = 64;
+
+/// Represents the max number of groups of fragments of a source waiting for the first fragment,
+/// so a single source cannot occupy all the memory.
+const MAX_SOURCE_PENDING_FRAGMENTATIONS: usize = 16;
+
+/// Represents the max bytes of all the fragments waiting for the first fragment.
+const MAX_PENDING_FRAGMENT_MEMORY: usize = 2 * 1024 * 1024;
+
+/// Represents the destination of a frame in a `Dispatcher`.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+enum Dispatch {
+    /// Represents the frame is dispatched to the worker.
+    Worker(usize),
+    /// Represents the frame is dispatched to all the workers.
+    All,
+}
+
+/// Represents a dispatcher distributes frames onto multiple `Redirector`s as workers by flows,
+/// like receive side scaling (RSS). TCP segments are hashed by their 4-tuple and UDP datagrams
+/// are hashed by their source, so frames in a flow are always handled by the same worker in
+/// order.
+pub struct Dispatcher {
+    workers: Vec<Redirector>,
+    sources: SourceSet,
+    /// Represents the map mapping a group of fragments to its worker, or the fragments waiting for
+    /// the first fragment.
+    fragments: HashMap<
+        (Ipv4Addr, Ipv4Addr, Option<LayerKind>, u16),
+        (Option<Dispatch>, Vec<Vec<u8>>, Instant),
+    >,
+    /// Represents the number of groups of fragments of each source waiting for the first fragment.
+    pending_sources: HashMap<Ipv4Addr, usize>,
+    /// Represents the bytes of all the fragments waiting for the first fragment.
+    pending_memory: usize,
+    queues: Vec<mpsc::Sender<Vec<u8>>>,
+    sweep_instant: Instant,
+    #[cfg(all(unix, feature = "systemd"))]
+    notifier: Option<systemd::Notifier>,
+    stats: Arc<Stats>,
+    worker_stats: Vec<Arc<Stats>>,
+    recorder: Option<Arc<Mutex<Recorder>>>,
+}
+
+impl Dispatcher {
+    /// Creates a new `Dispatcher` with as many workers as configured. The `hardware_addr` and the
+    /// `ip_addr` are of the interface, and others are the same as `Redirector::new`.
+    pub fn new(
+        tx: Sender,
+        mtu: usize,
+        hardware_addr: HardwareAddr,
+        ip_addr: Ipv4Addr,
+        src_ip_addr: Ipv4Network,
+        local_ip_addr: Ipv4Addr,
+        gw_ip_addr: Option<Ipv4Addr>,
+        remote: SocketAddrV4,
+        force_associate_dst: bool,
+        force_associate_bind_addr: bool,
+        auth: Option<SocksAuth>,
+        config: Config,
+    ) -> Dispatcher {
+        let n = max(config.workers, 1);
+        let tx = pcap::SharedSender::new(tx);
+
+        let mut worker_config = config.clone();
+        worker_config.udp_capacity = max((config.udp_capacity + n - 1) / n, 1);
+        worker_config.tcp_capacity = (config.tcp_capacity + n - 1) / n;
+
+        let mut workers = Vec::with_capacity(n);
+        for i in 0..n {
+            let mut forwarder = Forwarder::new(Box::new(tx.clone()), mtu, hardware_addr, ip_addr);
+            let mut config = worker_config.clone();
+            // Only the first worker persists the learned path MTU
+            if i > 0 {
+                if let Some(path) = config.mtu_cache.take() {
+                    if let Ok(cache) = MtuCache::load(path) {
+                        forwarder.set_mtu_cache(cache.detach());
                     }
-
-                    // Send
-                    self.tx.lock().unwrap().send_arp_reply(src)?
                 }
             }
-        }
 
-        Ok(())
+            let mut worker = Redirector::new(
+                Arc::new(Mutex::new(forwarder)),
+                src_ip_addr,
+                local_ip_addr,
+                gw_ip_addr,
+                remote,
+                force_associate_dst,
+                force_associate_bind_addr,
+                auth.clone(),
+                config,
+            );
+            // ARP is only dispatched to the first worker
+            if i > 0 {
+                worker.arp_guard = None;
+            }
+            workers.push(worker);
+        }
+        // Workers share the balancer, so a pinned destination is pinned in all the workers
+        let mut balancer = Balancer::new(remote, &config.proxies);
+        balancer.set_sticky(config.sticky_ttl, config.sticky_prefix);
+        balancer.set_probe(config.probe_interval, config.fastest_ports.clone());
+        let balancer = Arc::new(Mutex::new(balancer));
+        for worker in workers.iter_mut() {
+            worker.set_balancer(Arc::clone(&balancer));
+        }
+        // Workers share the bindings, so a source is bound regardless of the workers of its flows
+        let bindings = workers[0].bindings.clone();
+        for worker in workers.iter_mut().skip(1) {
+            worker.bindings = bindings.clone();
+        }
+        // Workers share the shaper, so a class is shaped as a whole across the workers of its flows
+        if let Some(shaper) = workers[0].shaper.clone() {
+            for worker in workers.iter_mut().skip(1) {
+                worker.set_shaper(Arc::clone(&shaper));
+            }
+        }
+        // Workers save their parts of the snapshot to the same file as a whole
+        if let Some(path) = config.snapshot.clone() {
+            let snapshots = Arc::new(Mutex::new(Snapshots::new(path, n)));
+            for (i, worker) in workers.iter_mut().enumerate() {
+                worker.snapshots = Some((Arc::clone(&snapshots), i));
+            }
+        }
+        let worker_stats = workers.iter().map(|worker| worker.stats()).collect();
+        // Malformed frames are dropped before dispatched, and are dumped by the recorder of the first
+        // worker
+        let recorder = workers[0].recorder.clone();
+
+        Dispatcher {
+            workers,
+            sources: sources(src_ip_addr, &config),
+            fragments: HashMap::new(),
+            pending_sources: HashMap::new(),
+            pending_memory: 0,
+            queues: Vec::new(),
+            sweep_instant: clock::now(),
+            #[cfg(all(unix, feature = "systemd"))]
+            notifier: None,
+            stats: Arc::new(Stats::new()),
+            worker_stats,
+            recorder,
+        }
     }
 
-    async fn handle_ipv4(&mut self, indicator: &Indicator, frame: &[u8]) -> io::Result<()> {
-        if let Some(ipv4) = indicator.ipv4() {
-            let src = ipv4.src();
-            if src != self.local_ip_addr && self.src_ip_addr.contains(src) {
-                debug!(
-                    "receive from pcap: {} ({} + {} Bytes)",
-                    indicator.brief(),
-                    indicator.len(),
-                    indicator.content_len() - indicator.len()
-                );
-                // Set forwarder's hardware address
-                if !self.is_tx_src_hardware_addr_set {
-                    self.tx
-                        .lock()
-                        .unwrap()
-                        .set_src_hardware_addr(src, indicator.ethernet().unwrap().src());
-                    self.is_tx_src_hardware_addr_set = true;
-                    info!(
-                        "Device {} joined the network",
-                        indicator.ethernet().unwrap().src()
-                    );
-                }
+    /// Sets the systemd notifier, whose watchdog is fed in the loop of the `Dispatcher`.
+    #[cfg(all(unix, feature = "systemd"))]
+    pub fn set_notifier(&mut self, notifier: systemd::Notifier) {
+        self.notifier = Some(notifier);
+    }
 
-                let frame_without_padding = &frame[..indicator.content_len()];
-                if ipv4.is_fragment() {
-                    // Fragmentation
-                    let frag = match self.defrag.add(indicator, frame_without_padding) {
-                        Some(frag) => frag,
-                        None => return Ok(()),
-                    };
-                    let (transport, payload) = frag.concatenate();
-
-                    if let Some(transport) = transport {
-                        match transport {
-                            Layers::Icmpv4(ref icmpv4) => self.handle_icmpv4(icmpv4)?,
-                            Layers::Tcp(ref tcp) => self.handle_tcp(tcp, &payload).await?,
-                            Layers::Udp(ref udp) => self.handle_udp(udp, &payload).await?,
-                            _ => unreachable!(),
-                        }
-                    }
-                } else {
-                    if let Some(transport) = indicator.transport() {
-                        match transport {
-                            Layers::Icmpv4(icmpv4) => self.handle_icmpv4(icmpv4)?,
-                            Layers::Tcp(tcp) => {
-                                self.handle_tcp(tcp, &frame_without_padding[indicator.len()..])
-                                    .await?
-                            }
-                            Layers::Udp(udp) => {
-                                self.handle_udp(udp, &frame_without_padding[indicator.len()..])
-                                    .await?
-                            }
-                            _ => unreachable!(),
-                        }
-                    }
-                }
-            }
+    /// Returns a receiver of the flows accepted from sources by all the workers, the same as
+    /// `Redirector::incoming`.
+    pub fn incoming(&mut self) -> mpsc::UnboundedReceiver<Flow> {
+        let (acceptor, incoming) = mpsc::unbounded_channel();
+        for worker in self.workers.iter_mut() {
+            worker.acceptor = Some(acceptor.clone());
         }
 
-        Ok(())
+        incoming
     }
 
-    fn handle_icmpv4(&mut self, icmpv4: &Icmpv4) -> io::Result<()> {
-        if icmpv4.is_destination_port_unreachable() {
-            // Destination port unreachable
-            let kind = match icmpv4.next_level_layer_kind() {
-                Some(kind) => kind,
-                None => return Ok(()),
-            };
-            match kind {
-                LayerKinds::Udp => {
-                    let dst = icmpv4.dst().unwrap();
-                    self.unbind_local_udp_port(dst);
+    /// Returns a receiver of lifecycle events of flows of all the workers, the same as
+    /// `Redirector::subscribe`.
+    pub fn subscribe(&mut self) -> broadcast::Receiver<FlowEvent> {
+        let flows = match self.workers.first().and_then(|worker| worker.flows.clone()) {
+            Some(flows) => flows,
+            None => {
+                let flows = FlowPublisher::new();
+                for worker in self.workers.iter_mut() {
+                    worker.set_flows(flows.clone());
                 }
-                _ => {}
-            }
-        } else if icmpv4.is_fragmentation_required_and_df_flag_set() {
-            // Fragmentation required, and DF flag set
-            let mtu = icmpv4.next_hop_mtu().unwrap();
-            if self
-                .tx
-                .lock()
-                .unwrap()
-                .set_src_mtu(icmpv4.dst_ip_addr().unwrap(), mtu as usize)
-            {
-                info!("Update MTU of {} to {}", icmpv4.dst_ip_addr().unwrap(), mtu);
+
+                flows
             }
-        }
+        };
 
-        Ok(())
+        flows.subscribe()
     }
 
-    async fn handle_tcp(&mut self, tcp: &Tcp, payload: &[u8]) -> io::Result<()> {
-        if tcp.is_rst() {
-            self.handle_tcp_rst(tcp);
-        } else if tcp.is_ack() {
-            self.handle_tcp_ack(tcp, payload).await?;
-        } else if tcp.is_syn() {
-            // Pure TCP SYN
-            self.handle_tcp_syn(tcp).await?;
-        } else if tcp.is_fin() {
-            // Pure TCP FIN
-            self.handle_tcp_fin(tcp, payload)?;
-        } else {
-            unreachable!();
+    /// Returns a controller of all the workers, the same as `Redirector::controller`.
+    pub fn controller(&mut self) -> Controller {
+        let mut txs = Vec::with_capacity(self.workers.len());
+        for worker in self.workers.iter_mut() {
+            let (tx, commands) = mpsc::unbounded_channel();
+            worker.commands = Some(commands);
+
+            txs.push(tx);
         }
+        let mut stats = self.worker_stats.clone();
+        stats.push(Arc::clone(&self.stats));
+
+        let controller = Controller::new(txs, stats);
+        #[cfg(feature = "metrics")]
+        let controller = controller.with_trackers(
+            self.workers
+                .iter_mut()
+                .map(|worker| worker.tracker())
+                .collect(),
+        );
 
-        Ok(())
+        controller
     }
 
-    async fn handle_tcp_ack(&mut self, tcp: &Tcp, payload: &[u8]) -> io::Result<()> {
-        let src = SocketAddrV4::new(tcp.src_ip_addr(), tcp.src());
-        let dst = SocketAddrV4::new(tcp.dst_ip_addr(), tcp.dst());
-        let key = (src, dst);
-        let is_exist = self.streams.get(&key).is_some();
-        let is_writable = match self.streams.get(&key) {
-            Some(stream) => !stream.is_write_closed(),
-            None => false,
-        };
+    /// Sets the sink of the audit log shared by all the workers, the same as
+    /// `Redirector::set_audit`.
+    pub fn set_audit<S: AuditSink + 'static>(&mut self, sink: S) {
+        let sink: Arc<Mutex<dyn AuditSink>> = Arc::new(Mutex::new(sink));
+        for worker in self.workers.iter_mut() {
+            worker.auditor = Some(Auditor::new(Arc::clone(&sink)));
+        }
+    }
 
-        if is_exist {
-            // ACK
-            let state = self.states.get_mut(&key).unwrap();
-            if tcp.sequence() != state.recv_next {
-                trace!(
-                    "TCP out of order of {} -> {} at {}",
-                    src,
-                    dst,
-                    tcp.sequence()
-                );
-            }
-            {
-                let mut tx_locked = self.tx.lock().unwrap();
-                let tx_state = tx_locked.get_state(dst, src).unwrap();
+    /// Sets the egress shared by all the workers, the same as `Redirector::set_egress`.
+    pub fn set_egress<E: Egress + 'static>(&mut self, egress: E) {
+        let egress: Arc<dyn Egress> = Arc::new(egress);
+        for worker in self.workers.iter_mut() {
+            worker.options.set_egress(Arc::clone(&egress));
+        }
+    }
 
-                tx_state.acknowledge(tcp.acknowledgement());
-                tx_state.set_send_window((tcp.window() as usize) << state.wscale as usize);
-            }
+    /// Adds a middleware to all the workers, the same as `Redirector::add_middleware`. Each
+    /// worker executes its own clone of the middleware.
+    pub fn add_middleware<M: PacketMiddleware + Clone + 'static>(&mut self, middleware: M) {
+        for worker in self.workers.iter_mut() {
+            worker.add_middleware(middleware.clone());
+        }
+    }
 
-            if payload.len() > 0 {
-                // ACK
-                // Append to cache
-                let cont_payload = state.append_cache(tcp.sequence(), payload)?;
+    /// Returns the statistics of the `Dispatcher`, which adds up the statistics of all the
+    /// workers.
+    pub fn stats(&self) -> Stats {
+        let stats = Stats::new();
+        stats.accumulate(&self.stats);
+        for worker_stats in &self.worker_stats {
+            stats.accumulate(worker_stats);
+        }
 
-                // SACK
-                if state.sack_perm {
-                    let sacks = state.cache.filled();
-                    self.tx
-                        .lock()
-                        .unwrap()
-                        .get_state(dst, src)
-                        .unwrap()
-                        .set_sacks(&sacks);
+        stats
+    }
+
+    /// Opens an `Interface` for redirect. Workers are spawned as tasks, and frames are received
+    /// and dispatched in the current thread.
+    pub async fn open(&mut self, rx: &mut Receiver) -> io::Result<()> {
+        for mut worker in self.workers.drain(..) {
+            worker.drive_tcp_timers();
+            balance::probe(&worker.balancer);
+            worker.warm_up_pool();
+            worker.open_passthrough().await?;
+            let (tx, mut frames) = mpsc::channel::<Vec<u8>>(WORKER_QUEUE_SIZE);
+            let clock = worker.clock.clone();
+            tokio::spawn(clock::scope_option(clock, async move {
+                loop {
+                    match time::timeout(Duration::from_millis(SWEEP_INTERVAL), frames.recv()).await
+                    {
+                        Ok(Some(ref frame)) => {
+                            worker.tick_tcp_timers();
+                            worker.handle_frame(frame).await
+                        }
+                        Ok(None) => break,
+                        Err(_) => {}
+                    }
+                    worker.sweep();
                 }
+            }));
+            self.queues.push(tx);
+        }
+        info!("Dispatch to {} workers", self.queues.len());
 
-                match cont_payload {
-                    Some(payload) => {
-                        // Send
-                        let stream = self.streams.get_mut(&key).unwrap();
-                        match stream.send(payload.as_slice()).await {
-                            Ok(_) => {
-                                let cache_remaining_size =
-                                    (state.cache.remaining() >> state.wscale as usize) as u16;
+        task::block_in_place(|| loop {
+            self.stats.mark_loop();
+            self.sweep();
 
-                                state.add_recv_next(payload.len() as u32);
+            match rx.next() {
+                Ok(frame) => {
+                    self.stats.mark_frame();
+                    self.dispatch_frame(frame)
+                }
+                Err(e) => {
+                    if e.kind() == io::ErrorKind::TimedOut {
+                        thread::sleep(Duration::from_millis(TIMEDOUT_WAIT));
+                        continue;
+                    }
+                    return Err(e);
+                }
+            };
+        })
+    }
 
-                                let mut tx_locked = self.tx.lock().unwrap();
-                                let tx_state = tx_locked.get_state(dst, src).unwrap();
+    fn sweep(&mut self) {
+        // Feed the watchdog
+        #[cfg(all(unix, feature = "systemd"))]
+        if let Some(ref mut notifier) = self.notifier {
+            if let Err(ref e) = notifier.watchdog() {
+                warn!("notify systemd: {}", e);
+            }
+        }
 
-                                // Update window size
-                                tx_state.set_window(cache_remaining_size);
+        // Expire groups of fragments
+        if clock::elapsed(self.sweep_instant) >= Duration::from_millis(SWEEP_INTERVAL) {
+            self.expire_fragments();
+            self.sweep_instant = clock::now();
+        }
+    }
 
-                                // Update TCP acknowledgement
-                                tx_state.add_acknowledgement(payload.len() as u32);
+    fn dispatch_frame(&mut self, frame: &[u8]) {
+        let indicator = match Indicator::from(frame) {
+            Ok(indicator) => indicator,
+            Err(e) => {
+                self.stats.increase_malformed(e.kind());
+                trace!("drop malformed frame ({} Bytes): {}", frame.len(), e);
+                if let Some(ref recorder) = self.recorder {
+                    recorder.lock().unwrap().dump_malformed(frame);
+                }
+                return;
+            }
+        };
 
-                                // Send ACK0
-                                // If there is a heavy traffic, the ACK reported may be inaccurate, which would results in retransmission
-                                tx_locked.send_tcp_ack_0(dst, src)?;
-                            }
-                            Err(e) => {
-                                {
-                                    // Send ACK/RST
-                                    let mut tx_locked = self.tx.lock().unwrap();
+        let dispatch = match indicator.ipv4() {
+            Some(ipv4) => {
+                if ipv4.is_fragment() {
+                    return self.dispatch_fragment(&indicator, frame);
+                }
 
-                                    tx_locked.send_tcp_ack_rst(dst, src)?;
+                match indicator.transport() {
+                    Some(Layers::Tcp(tcp)) => self.dispatch_tcp(
+                        SocketAddrV4::new(tcp.src_ip_addr(), tcp.src()),
+                        SocketAddrV4::new(tcp.dst_ip_addr(), tcp.dst()),
+                    ),
+                    Some(Layers::Udp(udp)) => self.dispatch_udp(
+                        SocketAddrV4::new(udp.src_ip_addr(), udp.src()),
+                        SocketAddrV4::new(udp.dst_ip_addr(), udp.dst()),
+                    ),
+                    Some(Layers::Icmpv4(icmpv4)) => {
+                        if icmpv4.is_fragmentation_required_and_df_flag_set() {
+                            // The path MTU is learned by every worker
+                            Dispatch::All
+                        } else {
+                            match (icmpv4.next_level_layer_kind(), icmpv4.src(), icmpv4.dst()) {
+                                (Some(LayerKinds::Tcp), Some(src), Some(dst)) => {
+                                    self.dispatch_tcp(src, dst)
                                 }
-
-                                // Clean up
-                                self.clean_up(src, dst);
-
-                                return Err(e);
+                                (Some(LayerKinds::Udp), Some(src), Some(dst)) => {
+                                    self.dispatch_udp(src, dst)
+                                }
+                                _ => Dispatch::Worker(0),
                             }
                         }
                     }
-                    None => {
-                        // Retransmission or unordered
-                        let cache_remaining_size =
-                            (state.cache.remaining() >> state.wscale as usize) as u16;
+                    _ => {
+                        if ipv4.is_igmp() {
+                            // Multicast groups are joined in every worker
+                            Dispatch::All
+                        } else {
+                            Dispatch::Worker(0)
+                        }
+                    }
+                }
+            }
+            None => Dispatch::Worker(0),
+        };
 
-                        // Update window size
-                        let mut tx_locked = self.tx.lock().unwrap();
-                        let tx_state = tx_locked.get_state(dst, src).unwrap();
+        self.send(dispatch, frame);
+    }
 
-                        tx_state.set_window(cache_remaining_size);
+    fn dispatch_fragment(&mut self, indicator: &Indicator, frame: &[u8]) {
+        let ipv4 = indicator.ipv4().unwrap();
+        let key = (
+            ipv4.src(),
+            ipv4.dst(),
+            ipv4.next_level_layer_kind(),
+            ipv4.identification(),
+        );
 
-                        // Send ACK0
-                        tx_locked.send_tcp_ack_0(dst, src)?;
-                    }
+        // Ports are only in the first fragment
+        let dispatch = if ipv4.fragment_offset() == 0 {
+            let payload = &frame[indicator.len()..indicator.content_len()];
+            if payload.len() >= 4 {
+                let src =
+                    SocketAddrV4::new(ipv4.src(), u16::from_be_bytes([payload[0], payload[1]]));
+                let dst =
+                    SocketAddrV4::new(ipv4.dst(), u16::from_be_bytes([payload[2], payload[3]]));
+                match ipv4.next_level_layer_kind() {
+                    Some(LayerKinds::Tcp) => Some(self.dispatch_tcp(src, dst)),
+                    Some(LayerKinds::Udp) => Some(self.dispatch_udp(src, dst)),
+                    _ => Some(Dispatch::Worker(0)),
                 }
             } else {
-                // ACK0
-                if !is_writable && self.tx.lock().unwrap().get_cache_size(dst, src) == 0 {
-                    // LAST_ACK
-                    // Clean up
-                    self.streams.remove(&key);
-                    self.states.remove(&key);
-                    self.tx.lock().unwrap().clean_up(dst, src);
-
-                    return Ok(());
-                } else {
-                    let is_retrans = state.increase_duplicate(tcp.acknowledgement());
-                    // Duplicate ACK
-                    if is_retrans && !tcp.is_zero_window() {
-                        // Fast retransmit
-                        let mut is_sr = false;
-                        if state.sack_perm {
-                            if let Some(sacks) = tcp.sack() {
-                                if sacks.len() > 0 {
-                                    // Selective retransmission
-                                    self.tx
-                                        .lock()
-                                        .unwrap()
-                                        .retransmit_tcp_ack_without(dst, src, sacks)?;
-                                    is_sr = true;
-                                }
-                            }
-                        }
+                Some(Dispatch::Worker(0))
+            }
+        } else {
+            None
+        };
 
-                        if !is_sr {
-                            // Back N
-                            self.tx.lock().unwrap().retransmit_tcp_ack(dst, src)?;
-                        }
+        match dispatch {
+            Some(dispatch) => {
+                let (prev_dispatch, pending, _) = self
+                    .fragments
+                    .entry(key)
+                    .or_insert_with(|| (None, Vec::new(), clock::now()));
+                let is_pending = prev_dispatch.is_none() && !pending.is_empty();
+                *prev_dispatch = Some(dispatch);
+                let pending = mem::replace(pending, Vec::new());
+                if is_pending {
+                    self.release_pending(key.0, pending.iter().map(Vec::len).sum());
+                }
+                for frame in pending {
+                    self.send(dispatch, &frame);
+                }
+                self.send(dispatch, frame);
+            }
+            None => {
+                if let Some((Some(dispatch), _, _)) = self.fragments.get(&key) {
+                    let dispatch = *dispatch;
+                    self.send(dispatch, frame);
+                    return;
+                }
 
-                        state.clear_duplicate();
-                        state.set_last_retrans();
+                // Wait for the first fragment
+                if self.is_pending_full(&key, frame.len()) {
+                    self.expire_fragments();
+                    if self.is_pending_full(&key, frame.len()) {
+                        trace!(
+                            "drop {}: too many fragments waiting for the first fragment",
+                            indicator.brief()
+                        );
+                        self.stats.increase_dispatch_drops();
+                        return;
                     }
                 }
+                let (_, pending, _) = self
+                    .fragments
+                    .entry(key)
+                    .or_insert_with(|| (None, Vec::new(), clock::now()));
+                if pending.is_empty() {
+                    *self.pending_sources.entry(key.0).or_insert(0) += 1;
+                }
+                pending.push(frame.to_vec());
+                self.pending_memory += frame.len();
             }
+        }
+    }
 
-            // Trigger sending remaining data
-            self.tx.lock().unwrap().send_tcp_ack(dst, src)?;
+    /// Returns if the fragment cannot wait for the first fragment of its group because of the
+    /// limits.
+    fn is_pending_full(
+        &self,
+        key: &(Ipv4Addr, Ipv4Addr, Option<LayerKind>, u16),
+        size: usize,
+    ) -> bool {
+        if self.pending_memory + size > MAX_PENDING_FRAGMENT_MEMORY {
+            return true;
+        }
+        match self.fragments.get(key) {
+            Some((_, pending, _)) => pending.len() >= MAX_PENDING_FRAGMENTS,
+            None => {
+                self.pending_sources.get(&key.0).copied().unwrap_or(0)
+                    >= MAX_SOURCE_PENDING_FRAGMENTATIONS
+            }
+        }
+    }
 
-            // FIN
-            if tcp.is_fin() || state.fin_sequence.is_some() {
-                self.handle_tcp_fin(tcp, payload)?;
+    /// Releases a group of fragments of the source with the size in bytes, which no longer waits
+    /// for the first fragment, from the limits.
+    fn release_pending(&mut self, src: Ipv4Addr, size: usize) {
+        self.pending_memory -= size;
+        if let Some(count) = self.pending_sources.get_mut(&src) {
+            *count -= 1;
+            if *count == 0 {
+                self.pending_sources.remove(&src);
+            }
+        }
+    }
+
+    /// Expires groups of fragments, including the fragments waiting for the first fragment.
+    fn expire_fragments(&mut self) {
+        let mut expired = Vec::new();
+        self.fragments.retain(|key, (dispatch, pending, instant)| {
+            if clock::elapsed(*instant) < Duration::from_millis(FRAGMENT_EXPIRE_TIME) {
+                return true;
+            }
+            if dispatch.is_none() && !pending.is_empty() {
+                expired.push((key.0, pending.iter().map(Vec::len).sum()));
             }
+
+            false
+        });
+        for (src, size) in expired {
+            self.release_pending(src, size);
+        }
+    }
+
+    fn dispatch_tcp(&self, src: SocketAddrV4, dst: SocketAddrV4) -> Dispatch {
+        // Both directions of a connection are hashed the same
+        let key = if src <= dst { (src, dst) } else { (dst, src) };
+
+        self.hash(key)
+    }
+
+    fn dispatch_udp(&self, src: SocketAddrV4, dst: SocketAddrV4) -> Dispatch {
+        // A source is bound to a local port regardless of its destinations
+        let key = if self.sources.contains(*src.ip()) {
+            src
         } else {
-            // Send RST
-            self.tx.lock().unwrap().send_tcp_rst(dst, src)?;
+            dst
+        };
+
+        self.hash(key)
+    }
+
+    fn hash<T: Hash>(&self, key: T) -> Dispatch {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+
+        Dispatch::Worker((hasher.finish() % self.queues.len() as u64) as usize)
+    }
+
+    fn send(&mut self, dispatch: Dispatch, frame: &[u8]) {
+        let workers = match dispatch {
+            Dispatch::Worker(i) => i..i + 1,
+            Dispatch::All => 0..self.queues.len(),
+        };
+        for i in workers {
+            match self.queues[i].try_send(frame.to_vec()) {
+                Ok(_) => {}
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    self.stats.increase_dispatch_drops();
+                    trace!("drop frame ({} Bytes) to worker {}", frame.len(), i);
+                }
+                Err(mpsc::error::TrySendError::Closed(_)) => {
+                    warn!("worker {} is closed", i);
+                }
+            }
         }
+    }
+}
+
+#[test]
+fn tcp_tx_state_update_rto() {
+    let addr = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0);
+    let mut state = TcpTxState::new(addr, addr, 0, 0, 0, None, false, None);
+
+    // First measurement
+    state.update_rto(Duration::from_millis(2000));
+    assert_eq!(state.srtt, Some(2000));
+    assert_eq!(state.rttvar, Some(1000));
+    assert_eq!(state.rto(), 6000);
+
+    // Subsequent measurement
+    state.update_rto(Duration::from_millis(1000));
+    assert_eq!(state.srtt, Some(1875));
+    assert_eq!(state.rttvar, Some(1000));
+    assert_eq!(state.rto(), 5875);
+
+    // Lower bound
+    state.update_rto(Duration::from_millis(0));
+    state.update_rto(Duration::from_millis(0));
+    state.update_rto(Duration::from_millis(0));
+    assert!(state.rto() >= MIN_RTO);
+}
+
+#[test]
+fn tcp_tx_state_double_rto() {
+    let addr = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0);
+    let mut state = TcpTxState::new(addr, addr, 0, 0, 0, None, false, None);
+
+    state.double_rto();
+    assert_eq!(state.rto(), INITIAL_RTO * 2);
 
-        Ok(())
+    for _ in 0..16 {
+        state.double_rto();
     }
+    assert_eq!(state.rto(), MAX_RTO);
+}
 
-    async fn handle_tcp_syn(&mut self, tcp: &Tcp) -> io::Result<()> {
-        let src = SocketAddrV4::new(tcp.src_ip_addr(), tcp.src());
-        let dst = SocketAddrV4::new(tcp.dst_ip_addr(), tcp.dst());
-        let key = (src, dst);
-        let is_exist = self.streams.get(&key).is_some();
+#[test]
+fn tcp_tx_state_send_window_shrink() {
+    let addr = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0);
+    let mut state = TcpTxState::new(addr, addr, 0, 0, 1000, None, false, None);
+    state.acknowledge(1);
+    assert_eq!(state.send_window_remaining(), 1000);
+
+    state.append_queue(&[0; 800]);
+    state.append_cache(800).unwrap();
+    assert_eq!(state.send_window_remaining(), 200);
+
+    // Shrink below the data in flight
+    state.acknowledge(101);
+    state.update_send_window(101, 500);
+    assert_eq!(state.send_window_remaining(), 0);
+
+    // Stale window advertisement
+    state.update_send_window(1, 1000);
+    assert_eq!(state.send_window_remaining(), 0);
+
+    // Reopen
+    state.acknowledge(801);
+    state.update_send_window(801, 1000);
+    assert_eq!(state.send_window_remaining(), 1000);
+}
 
-        // Connect if not connected, drop if established
-        if !is_exist {
-            // Clean up
-            self.clean_up(src, dst);
+#[test]
+fn tcp_tx_state_syn_retries() {
+    let addr = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0);
+    let mut state = TcpTxState::new(addr, addr, 0, 0, 1000, None, false, None);
+    state.set_max_syn_retries(1);
+
+    // SYN/ACK is retransmitted until the limit
+    state.update_syn_timer();
+    assert!(state.deadline().is_some());
+    state.update_syn_timer();
+    state.cache_syn_retries += 1;
+    assert!(state.deadline().is_none());
+    assert!(!state.is_syn_timedout());
+
+    // Handshake completed
+    state.acknowledge(1);
+    assert!(state.cache_syn().is_none());
+    assert!(!state.is_syn_timedout());
+    assert_eq!(state.sequence(), 1);
+}
 
-            // Admit SYN
-            let wscale = match ENABLE_WSCALE {
-                true => tcp.wscale(),
-                false => None,
-            };
-            let recv_wscale = match wscale {
-                Some(wscale) => Some(min(wscale, MAX_RECV_WSCALE)),
-                None => None,
-            };
-            let sack_perm = ENABLE_SACK && tcp.is_sack_perm();
-            let state = TcpRxState::new(src, dst, tcp.sequence(), wscale.unwrap_or(0), sack_perm);
+#[test]
+fn tcp_tx_state_virtual_clock() {
+    let clock = clock::VirtualClock::new();
+    let _clock = clock::enter(Some(std::sync::Arc::new(clock.clone())));
+
+    let addr = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0);
+    let mut state = TcpTxState::new(addr, addr, 0, 0, 1000, None, false, None);
+    state.append_queue(b"hello");
+    state.append_cache(5).unwrap();
+
+    // Retransmitted only after the RTO
+    clock.advance(Duration::from_millis(INITIAL_RTO));
+    assert!(state
+        .cache_mut()
+        .get_timed_out_and_update(INITIAL_RTO)
+        .is_empty());
+    clock.advance(Duration::from_millis(1));
+    assert_eq!(
+        state.cache_mut().get_timed_out_and_update(INITIAL_RTO),
+        b"hello"
+    );
+    assert!(state
+        .cache_mut()
+        .get_timed_out_and_update(INITIAL_RTO)
+        .is_empty());
+
+    // FIN timer
+    state.update_fin_timer();
+    assert!(!state.cache_fin().unwrap().is_timedout());
+    clock.advance(Duration::from_millis(INITIAL_RTO + 1));
+    assert!(state.cache_fin().unwrap().is_timedout());
+}
 
-            {
-                let mut tx_locked = self.tx.lock().unwrap();
+#[cfg(feature = "udp")]
+#[test]
+fn quic_conn_ids_server_issued() {
+    let mut conn_ids = QuicConnIds::new();
+    let src: SocketAddrV4 = "10.6.0.1:50000".parse().unwrap();
+    let next_src: SocketAddrV4 = "10.6.0.1:50001".parse().unwrap();
+
+    // Connection IDs issued by the server
+    assert!(conn_ids.track(src, &[9; 8]));
+    assert!(!conn_ids.track(src, &[9; 8]));
+    assert!(!conn_ids.track(src, &[7; MIN_QUIC_CONN_ID_LEN - 1]));
+    assert_eq!(conn_ids.len(), 1);
+
+    // Packets from sources are destined to them
+    let long = QuicHeader::Long {
+        version: 1,
+        dst_conn_id: vec![9; 8],
+        src_conn_id: vec![1; 8],
+    };
+    assert_eq!(conn_ids.lookup(&long, &[]), Some(src));
+    let mut short = vec![0x40];
+    short.extend_from_slice(&[9; 8]);
+    short.extend_from_slice(b"payload");
+    assert_eq!(conn_ids.lookup(&QuicHeader::Short, &short), Some(src));
+    short[1] = 8;
+    assert_eq!(conn_ids.lookup(&QuicHeader::Short, &short), None);
+    short[1] = 9;
+
+    // Migrated and untracked
+    conn_ids.migrate(src, next_src);
+    assert_eq!(conn_ids.lookup(&QuicHeader::Short, &short), Some(next_src));
+    conn_ids.untrack(next_src);
+    assert_eq!(conn_ids.lookup(&QuicHeader::Short, &short), None);
+    assert_eq!(conn_ids.len(), 0);
+}
 
-                let mut rng = rand::thread_rng();
-                let sequence = rng.gen::<u32>();
-                let acknowledgement = tcp.sequence().checked_add(1).unwrap_or(0);
-                if let Some(mss) = tcp.mss() {
-                    let mtu = Ipv4::minimum_len() + Tcp::minimum_len() + mss as usize;
-                    if tx_locked.set_src_mtu(tcp.src_ip_addr(), mtu) {
-                        info!("Update MTU of {} to {}", tcp.src_ip_addr(), mtu);
-                    }
-                }
+#[test]
+fn isn_generator_monotonic() {
+    let generator = IsnGenerator::new();
+    let src = SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 2), 50000);
+    let dst = SocketAddrV4::new(Ipv4Addr::new(1, 1, 1, 1), 443);
+
+    let first = generator.generate(src, dst);
+    thread::sleep(Duration::from_millis(10));
+    let second = generator.generate(src, dst);
+    let diff = seq_sub(second, first);
+    assert!(diff >= 2500 && diff < u32::MAX / 2);
+}
 
-                let tx_state = TcpTxState::new(
-                    src,
-                    dst,
-                    sequence,
-                    acknowledgement,
-                    tcp.window(),
-                    recv_wscale,
-                    sack_perm,
-                    wscale,
-                );
-                tx_locked.set_state(dst, src, tx_state);
-            }
+#[test]
+fn tcp_tx_state_fin_acked() {
+    let addr = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0);
+    let mut state = TcpTxState::new(addr, addr, 0, 0, 1000, None, false, None);
 
-            // Connect
-            let stream =
-                StreamWorker::connect(self.get_tx(), src, dst, self.remote, &self.options).await;
+    state.append_queue_fin();
+    state.append_cache_fin();
+    assert!(state.fin_acked().is_none());
 
-            let stream = match stream {
-                Ok(stream) => stream,
-                Err(e) => {
-                    {
-                        let mut tx_locked = self.tx.lock().unwrap();
-                        let tx_state = tx_locked.get_state(dst, src).unwrap();
+    state.acknowledge(1);
+    assert!(state.cache_fin().is_none());
+    assert!(state.fin_acked().is_some());
+    assert_eq!(state.sequence(), 1);
+}
 
-                        tx_state.add_acknowledgement(1);
+#[test]
+fn tcp_tx_state_pacing() {
+    let addr = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0);
+    let mut state = TcpTxState::new(addr, addr, 0, 0, 10000, None, false, None);
+
+    // Not paced before the SRTT is measured
+    state.set_pacing(true);
+    assert_eq!(state.pacing_budget(1000), usize::MAX);
+
+    // 10000 Bytes over 100 ms, in a burst of 4 segments
+    state.update_rto(Duration::from_millis(100));
+    assert_eq!(state.pacing_budget(1000), 4000);
+    state.consume_pacing_budget(4000);
+    assert!(state.pacing_budget(1000) < 1000);
+    state.defer_pacing(1000);
+    assert!(state.deadline().is_some());
+    assert!(!state.take_pacing_due());
+
+    thread::sleep(Duration::from_millis(20));
+    assert!(state.pacing_budget(1000) >= 2000);
+    assert!(state.take_pacing_due());
+    assert!(state.deadline().is_none());
+
+    // Not paced if disabled
+    state.set_pacing(false);
+    assert_eq!(state.pacing_budget(1000), usize::MAX);
+}
 
-                        // Send ACK/RST
-                        tx_locked.send_tcp_ack_rst(dst, src)?;
-                    }
+#[test]
+fn tcp_rx_state_strip_urgent() {
+    let addr = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0);
+    let mut state = TcpRxState::new(addr, addr, 0, 0, false);
+
+    // Telnet IAC IP followed by IAC DM as urgent data, where the DM is the urgent byte
+    state.set_urgent(4);
+    let mut payload = vec![0xff, 0xf4, 0xff, 0xf2];
+    state.strip_urgent(&mut payload);
+    assert_eq!(payload, vec![0xff, 0xf4, 0xff]);
+    state.add_recv_next(4);
+
+    // Retransmitted urgent data already written is ignored
+    state.set_urgent(4);
+    let mut payload = vec![b'a'];
+    state.strip_urgent(&mut payload);
+    assert_eq!(payload, vec![b'a']);
+    state.add_recv_next(1);
+
+    // Urgent byte in a later payload
+    state.set_urgent(8);
+    let mut payload = vec![b'b'];
+    state.strip_urgent(&mut payload);
+    assert_eq!(payload, vec![b'b']);
+    state.add_recv_next(1);
+    let mut payload = vec![b'c', 0xf2];
+    state.strip_urgent(&mut payload);
+    assert_eq!(payload, vec![b'c']);
+}
 
-                    // Clean up
-                    self.clean_up(src, dst);
+#[test]
+fn tcp_rx_state_coalesce() {
+    let addr = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0);
+    let mut state = TcpRxState::new(addr, addr, 0, 0, false);
 
-                    return Err(e);
-                }
-            };
+    state.coalesce(&[0; 1000]);
+    assert!(!state.is_coalesce_due());
+    state.coalesce(&vec![0; COALESCE_SIZE]);
+    assert!(state.is_coalesce_due());
 
-            self.states.insert(key, state);
-            self.streams.insert(key, stream);
-        }
+    assert_eq!(state.take_coalesced().len(), COALESCE_SIZE + 1000);
+    assert!(!state.is_coalesce_due());
+    assert_eq!(state.recv_next, 1);
+}
 
-        Ok(())
-    }
+#[test]
+fn tcp_rx_state_window() {
+    let addr = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0);
+    let mut state = TcpRxState::new(addr, addr, 0, 0, false);
 
-    fn handle_tcp_rst(&mut self, tcp: &Tcp) {
-        let src = SocketAddrV4::new(tcp.src_ip_addr(), tcp.src());
-        let dst = SocketAddrV4::new(tcp.dst_ip_addr(), tcp.dst());
+    assert_eq!(state.window(0, 0), RECV_WINDOW);
+    assert_eq!(state.window(0, 1000), 1000);
+    state.coalesce(&[0; 400]);
+    assert_eq!(state.window(500, 1000), 100);
+    assert_eq!(state.window(1000, 1000), 0);
+}
 
-        // Clean up
-        self.clean_up(src, dst);
-    }
+#[test]
+fn tcp_rx_state_recv_window() {
+    let addr = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0);
+    let mut state = TcpRxState::new(addr, addr, 0, 7, false);
+    state.set_recv_window(16384, 2);
+
+    assert_eq!(state.cache.capacity(), 16384 << 2);
+    assert_eq!(state.target_window(), 16384);
+    // Scaled by the window scale advertised instead of the one of the source
+    assert_eq!(state.window(0, 0), 16384 >> 2);
+    assert_eq!(state.window(0, 1000), 1000 >> 2);
+}
 
-    fn handle_tcp_fin(&mut self, tcp: &Tcp, payload: &[u8]) -> io::Result<()> {
-        let src = SocketAddrV4::new(tcp.src_ip_addr(), tcp.src());
-        let dst = SocketAddrV4::new(tcp.dst_ip_addr(), tcp.dst());
-        let key = (src, dst);
-        let is_exist = self.streams.get(&key).is_some();
-        let is_readable = match self.streams.get(&key) {
-            Some(stream) => !stream.is_read_closed(),
-            None => false,
-        };
+#[test]
+fn tcp_rx_state_drain_rate() {
+    let addr = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0);
+    let mut state = TcpRxState::new(addr, addr, 0, 8, false);
+    assert_eq!(state.target_window(), RECV_WINDOW as usize);
+
+    // 1 MB/s
+    state.drain_instant = Instant::now() - Duration::from_millis(1000);
+    state.update_drain_rate(1000000, 0);
+    let target = state.target_window();
+    assert!(target > 150000 && target <= 200000);
+
+    // Idle source does not shrink the window
+    state.drain_instant = Instant::now() - Duration::from_millis(1000);
+    state.update_drain_rate(1000000, 0);
+    assert_eq!(state.target_window(), target);
+
+    // Stalled proxy shrinks the window
+    state.drain_instant = Instant::now() - Duration::from_millis(1000);
+    state.update_drain_rate(1000000, 1);
+    assert!(state.target_window() < target);
+}
 
-        if is_exist {
-            let state = self.states.get_mut(&key).unwrap();
-            if tcp.is_fin() {
-                // Update FIN sequence
-                state.set_fin_sequence(
-                    tcp.sequence()
-                        .checked_add(payload.len() as u32)
-                        .unwrap_or_else(|| payload.len() as u32 - (u32::MAX - tcp.sequence())),
-                );
-            }
+#[test]
+fn forwarder_abort() {
+    let mut forwarder = Forwarder::new(
+        Box::new(pcap::BlackHole::new()),
+        1500,
+        pcap::HARDWARE_ADDR_UNSPECIFIED,
+        Ipv4Addr::new(192, 168, 1, 2),
+    );
+    let src = SocketAddrV4::new(Ipv4Addr::new(10, 6, 0, 1), 50000);
+    let dst = SocketAddrV4::new(Ipv4Addr::new(1, 1, 1, 1), 80);
+    let state = TcpTxState::new(src, dst, 0, 0, 1000, None, false, None);
+    forwarder.set_state(dst, src, state);
+
+    assert!(forwarder.abort(dst, src).unwrap());
+    assert!(forwarder.get_state(dst, src).is_none());
+    assert!(!forwarder.abort(dst, src).unwrap());
+}
 
-            // If the receive next is the same as the FIN sequence, the FIN should be popped
-            if let Some(fin_sequence) = state.fin_sequence {
-                if fin_sequence == state.recv_next {
-                    // Admit FIN
-                    state.admit_fin();
-                    state.add_recv_next(1);
+#[test]
+fn forwarder_mtu_probing() {
+    let clock = clock::VirtualClock::new();
+    let _clock = clock::enter(Some(Arc::new(clock.clone())));
+
+    let (tx, _, loopback) = pcap::memory();
+    let mut forwarder = testing::forwarder(tx);
+    forwarder.set_clock(Some(Arc::new(clock.clone())));
+    forwarder.set_mtu_probing(true);
+    let stats = Arc::new(Stats::new());
+    forwarder.set_stats(Arc::clone(&stats));
+    let src = SocketAddrV4::new(Ipv4Addr::new(10, 6, 0, 1), 50000);
+    let dst = SocketAddrV4::new(Ipv4Addr::new(1, 1, 1, 1), 80);
+    let state = TcpTxState::new(src, dst, 0, 0, 65535, None, false, None);
+    forwarder.set_state(dst, src, state);
+    let sizes = || {
+        loopback
+            .sent()
+            .iter()
+            .map(|frame| {
+                let indicator = Indicator::from(frame).unwrap();
+                indicator.content_len() - indicator.len()
+            })
+            .collect::<Vec<_>>()
+    };
+    let timeout = |forwarder: &mut Forwarder| {
+        clock.advance(Duration::from_millis(MAX_RTO));
+        forwarder.expire_tcp_timers();
+    };
+    let acknowledge = |forwarder: &mut Forwarder, sequence| {
+        forwarder.get_state(dst, src).unwrap().acknowledge(sequence);
+        forwarder.acknowledge_mtu_probe(dst, src, sequence);
+    };
 
-                    {
-                        let mut tx_locked = self.tx.lock().unwrap();
-                        let tx_state = tx_locked.get_state(dst, src).unwrap();
+    // Falls back to the base MTU after segments keep timing out
+    forwarder.append_to_queue(dst, src, &[0u8; 2920]).unwrap();
+    assert_eq!(sizes(), vec![1460, 1460]);
+    timeout(&mut forwarder);
+    assert_eq!(sizes(), vec![1460, 1460]);
+    timeout(&mut forwarder);
+    assert_eq!(sizes(), vec![984, 984, 952]);
+    assert_eq!(stats.mtu_black_holes(), 1);
+    assert_eq!(forwarder.get_mtu(*dst.ip(), *src.ip()), BASE_MTU);
+    acknowledge(&mut forwarder, 2920);
+
+    // An acknowledged probe raises the path MTU
+    forwarder.append_to_queue(dst, src, &[0u8; 4000]).unwrap();
+    assert_eq!(sizes(), vec![1222, 984, 984, 810]);
+    assert_eq!(stats.mtu_probes(), 1);
+    acknowledge(&mut forwarder, 2920 + 1222);
+    assert_eq!(forwarder.get_mtu(*dst.ip(), *src.ip()), 1262);
+    acknowledge(&mut forwarder, 6920);
+
+    // A lost probe keeps the path MTU and does not indicate a black hole
+    forwarder.append_to_queue(dst, src, &[0u8; 4000]).unwrap();
+    assert_eq!(sizes(), vec![1341, 1222, 1222, 215]);
+    timeout(&mut forwarder);
+    assert_eq!(sizes(), vec![1222, 1222, 1222, 334]);
+    assert_eq!(stats.mtu_black_holes(), 1);
+    assert_eq!(forwarder.get_mtu(*dst.ip(), *src.ip()), 1262);
+    let search = forwarder
+        .mtu_searches
+        .as_mut()
+        .unwrap()
+        .get_mut(dst.ip())
+        .unwrap();
+    assert_eq!(search.next_probe(), Some(1321));
+}
 
-                        tx_state.add_acknowledgement(1);
+#[test]
+fn dispatcher_dispatch_flows() {
+    let src_ip_addr = Ipv4Network::new(Ipv4Addr::new(10, 6, 0, 0), 24).unwrap();
+    let mut dispatcher = Dispatcher::new(
+        Box::new(pcap::BlackHole::new()),
+        1500,
+        pcap::HARDWARE_ADDR_UNSPECIFIED,
+        Ipv4Addr::new(192, 168, 1, 2),
+        src_ip_addr,
+        Ipv4Addr::new(10, 6, 0, 254),
+        None,
+        SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1080),
+        false,
+        false,
+        None,
+        Config::new().workers(4),
+    );
+    for _ in 0..4 {
+        let (tx, _) = mpsc::channel(1);
+        dispatcher.queues.push(tx);
+    }
 
-                        // Send ACK0
-                        tx_locked.send_tcp_ack_0(dst, src)?;
-                    }
-                    if is_readable {
-                        // Close by local
-                        let stream = self.streams.get_mut(&key).unwrap();
-                        stream.shutdown(Shutdown::Write);
-                    } else {
-                        // Close by remote
-                        // Clean up
-                        self.clean_up(src, dst);
-                    }
-                } else {
-                    trace!(
-                        "TCP out of order of {} -> {} at {}",
-                        src,
-                        dst,
-                        tcp.sequence()
-                    );
+    let src = SocketAddrV4::new(Ipv4Addr::new(10, 6, 0, 1), 50000);
+    let mut workers = std::collections::HashSet::new();
+    for port in 0..64 {
+        let dst = SocketAddrV4::new(Ipv4Addr::new(1, 1, 1, 1), port);
+        // Both directions of a TCP connection
+        let dispatch = dispatcher.dispatch_tcp(src, dst);
+        assert_eq!(dispatch, dispatcher.dispatch_tcp(dst, src));
+        workers.insert(dispatch);
+        // All destinations of a UDP source
+        assert_eq!(
+            dispatcher.dispatch_udp(src, dst),
+            dispatcher.dispatch_udp(dst, src)
+        );
+        assert_eq!(
+            dispatcher.dispatch_udp(src, dst),
+            dispatcher.dispatch_udp(src, SocketAddrV4::new(Ipv4Addr::new(8, 8, 8, 8), 53))
+        );
+    }
+    assert!(workers.len() > 1);
+}
 
-                    if payload.len() == 0 {
-                        // Send ACK0
-                        self.tx.lock().unwrap().send_tcp_ack_0(dst, src)?;
-                    }
-                }
+#[test]
+fn dispatcher_dispatch_orphan_fragments() {
+    let clock = clock::VirtualClock::new();
+    let _clock = clock::enter(Some(Arc::new(clock.clone())));
+
+    let src_ip_addr = Ipv4Network::new(Ipv4Addr::new(10, 6, 0, 0), 24).unwrap();
+    let mut dispatcher = Dispatcher::new(
+        Box::new(pcap::BlackHole::new()),
+        1500,
+        pcap::HARDWARE_ADDR_UNSPECIFIED,
+        Ipv4Addr::new(192, 168, 1, 2),
+        src_ip_addr,
+        Ipv4Addr::new(10, 6, 0, 254),
+        None,
+        SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1080),
+        false,
+        false,
+        None,
+        Config::new().workers(2),
+    );
+    let (tx, mut rx) = mpsc::channel(4096);
+    dispatcher.queues.push(tx);
+
+    // Fragments of a group are bounded
+    let payload = vec![0u8; 1400];
+    let builder = testing::FrameBuilder::new(
+        "10.6.0.1:50000".parse().unwrap(),
+        "1.1.1.1:53".parse().unwrap(),
+    );
+    let fragments = builder.udp_fragments(&payload, 256);
+    for _ in 0..MAX_PENDING_FRAGMENTS {
+        dispatcher.dispatch_frame(&fragments[1]);
+    }
+    assert_eq!(dispatcher.stats.dispatch_drops(), 0);
+    dispatcher.dispatch_frame(&fragments[1]);
+    assert_eq!(dispatcher.stats.dispatch_drops(), 1);
+
+    // Groups of a source are bounded
+    for identification in 1..1024 {
+        let fragments = builder
+            .clone()
+            .identification(identification)
+            .udp_fragments(&payload, 256);
+        for fragment in &fragments[1..] {
+            dispatcher.dispatch_frame(fragment);
+        }
+    }
+    assert_eq!(
+        dispatcher.fragments.len(),
+        MAX_SOURCE_PENDING_FRAGMENTATIONS
+    );
+
+    // Fragments of all the sources are bounded
+    for host in 2..130 {
+        for identification in 0..MAX_SOURCE_PENDING_FRAGMENTATIONS as u16 {
+            let fragments = testing::FrameBuilder::new(
+                SocketAddrV4::new(Ipv4Addr::new(10, 6, 0, host), 50000),
+                "1.1.1.1:53".parse().unwrap(),
+            )
+            .identification(identification)
+            .udp_fragments(&payload, 256);
+            for fragment in &fragments[1..] {
+                dispatcher.dispatch_frame(fragment);
             }
-        } else {
-            // Send RST
-            self.tx.lock().unwrap().send_tcp_rst(dst, src)?;
         }
+    }
+    assert!(dispatcher.pending_memory <= MAX_PENDING_FRAGMENT_MEMORY);
+    assert!(dispatcher.pending_memory + fragments[1].len() > MAX_PENDING_FRAGMENT_MEMORY);
+    assert!(dispatcher.pending_sources.values().sum::<usize>() <= dispatcher.fragments.len());
+    assert!(rx.try_recv().is_err());
+
+    // A trickle of fragments does not keep a group alive
+    clock.advance(Duration::from_millis(FRAGMENT_EXPIRE_TIME / 2));
+    dispatcher.dispatch_frame(&fragments[2]);
+    clock.advance(Duration::from_millis(FRAGMENT_EXPIRE_TIME / 2));
+    dispatcher.expire_fragments();
+    assert!(dispatcher.fragments.is_empty());
+    assert!(dispatcher.pending_sources.is_empty());
+    assert_eq!(dispatcher.pending_memory, 0);
+
+    // Fragments waiting are dispatched with the first fragment
+    for fragment in &fragments[1..] {
+        dispatcher.dispatch_frame(fragment);
+    }
+    dispatcher.dispatch_frame(&fragments[0]);
+    assert_eq!(dispatcher.pending_memory, 0);
+    for _ in 0..fragments.len() {
+        rx.try_recv().unwrap();
+    }
+}
 
-        Ok(())
+#[test]
+fn redirector_loopback() {
+    struct Refusal;
+
+    impl PacketMiddleware for Refusal {
+        fn on_new_connection(&mut self, _: SocketAddrV4, _: SocketAddrV4, _: LayerKind) -> bool {
+            false
+        }
     }
 
-    fn clean_up(&mut self, src: SocketAddrV4, dst: SocketAddrV4) {
-        let key = (src, dst);
+    let local_hardware_addr = testing::DST_HARDWARE_ADDR;
+    let (mut redirector, mut rx, mut loopback) = testing::redirector(Config::new());
+    redirector.add_middleware(Refusal);
+
+    let builder = testing::FrameBuilder::new(
+        "10.6.0.1:50000".parse().unwrap(),
+        "1.1.1.1:80".parse().unwrap(),
+    );
+    loopback.inject(&builder.syn(1000));
+    loopback.close();
+    let mut rt = tokio::runtime::Runtime::new().unwrap();
+    let e = rt.block_on(redirector.open(&mut rx)).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+
+    // Refused by an ACK/RST
+    let frames = loopback.sent();
+    assert_eq!(frames.len(), 1);
+    let indicator = Indicator::from(&frames[0]).unwrap();
+    assert_eq!(indicator.ethernet().unwrap().src(), local_hardware_addr);
+    let tcp = match indicator.transport() {
+        Some(Layers::Tcp(tcp)) => tcp,
+        _ => panic!("not TCP"),
+    };
+    assert!(tcp.is_rst() && tcp.is_ack());
+    assert_eq!(tcp.src(), 80);
+    assert_eq!(tcp.acknowledgement(), 1001);
+}
 
-        self.streams.remove(&key);
-        self.states.remove(&key);
+#[test]
+fn redirector_egress() {
+    use egress::{EgressBinding, EgressFuture, EgressReadHalf, EgressWriteHalf};
 
-        self.tx.lock().unwrap().clean_up(dst, src);
+    // An egress records the connections and refuses them
+    struct Refusal {
+        connects: Arc<Mutex<Vec<(SocketAddrV4, SocketAddrV4)>>>,
     }
 
-    async fn handle_udp(&mut self, udp: &Udp, payload: &[u8]) -> io::Result<()> {
-        let src = SocketAddrV4::new(udp.src_ip_addr(), udp.src());
+    impl Egress for Refusal {
+        fn connect(
+            &self,
+            src: SocketAddrV4,
+            dst: SocketAddrV4,
+        ) -> EgressFuture<'_, (EgressReadHalf, EgressWriteHalf)> {
+            self.connects.lock().unwrap().push((src, dst));
 
-        // Bind
-        let port = self.bind_local_udp_port(src).await?;
+            Box::pin(async { Err(io::Error::from(io::ErrorKind::ConnectionRefused)) })
+        }
 
-        // Send
-        self.datagrams
-            .get_mut(&port)
-            .unwrap()
-            .send_to(payload, SocketAddrV4::new(udp.dst_ip_addr(), udp.dst()))
-            .await?;
+        fn bind(&self, _: SocketAddrV4, _: u16) -> EgressFuture<'_, EgressBinding> {
+            Box::pin(async { Err(io::Error::from(io::ErrorKind::ConnectionRefused)) })
+        }
+    }
 
-        Ok(())
+    let (tx, mut rx, mut loopback) = pcap::memory();
+    let connects = Arc::new(Mutex::new(Vec::new()));
+    let mut redirector = Redirector::with_egress(
+        Arc::new(Mutex::new(testing::forwarder(tx))),
+        Ipv4Network::new(Ipv4Addr::new(10, 6, 0, 0), 24).unwrap(),
+        Ipv4Addr::new(10, 6, 0, 254),
+        None,
+        Refusal {
+            connects: Arc::clone(&connects),
+        },
+        Config::new(),
+    );
+
+    let src = "10.6.0.1:50000".parse().unwrap();
+    let dst = "1.1.1.1:80".parse().unwrap();
+    let builder = testing::FrameBuilder::new(src, dst);
+    loopback.inject(&builder.syn(1000));
+    loopback.close();
+    let mut rt = tokio::runtime::Runtime::new().unwrap();
+    let e = rt.block_on(redirector.open(&mut rx)).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+
+    // Connected by the egress instead of the proxy
+    assert_eq!(*connects.lock().unwrap(), vec![(src, dst)]);
+}
+
+#[test]
+fn redirector_step() {
+    let (mut redirector, mut rx, mut loopback) = testing::redirector(Config::new());
+    let _incoming = redirector.incoming();
+
+    let is_syn_ack = |actions: &[StepAction]| match actions {
+        [StepAction::Transmit(frame)] => match Indicator::from(frame).unwrap().transport() {
+            Some(Layers::Tcp(tcp)) => tcp.is_syn() && tcp.is_ack(),
+            _ => false,
+        },
+        _ => false,
+    };
+    let first = testing::FrameBuilder::new(
+        "10.6.0.1:50000".parse().unwrap(),
+        "1.1.1.1:80".parse().unwrap(),
+    );
+    let second = testing::FrameBuilder::new(
+        "10.6.0.1:50001".parse().unwrap(),
+        "1.1.1.1:80".parse().unwrap(),
+    );
+    let mut transmitted = Vec::new();
+    let mut rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        // Frames from the caller
+        let actions = redirector.step(&first.syn(1000)).await.unwrap();
+        assert!(is_syn_ack(&actions));
+        transmitted.extend(actions);
+
+        // Frames from the receiver
+        let actions = redirector.poll_once(&mut rx).await.unwrap();
+        assert_eq!(actions, vec![StepAction::Idle]);
+        loopback.inject(&second.syn(2000));
+        loopback.close();
+        let actions = redirector.poll_once(&mut rx).await.unwrap();
+        assert!(is_syn_ack(&actions));
+        transmitted.extend(actions);
+        let e = redirector.poll_once(&mut rx).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+    });
+
+    // Frames emitted are sent to pcap as well, so are retransmissions by the timer driver
+    let sent = loopback.sent();
+    for action in transmitted {
+        match action {
+            StepAction::Transmit(frame) => assert!(sent.contains(&frame)),
+            StepAction::Idle => unreachable!(),
+        }
     }
+}
 
-    async fn bind_local_udp_port(&mut self, src: SocketAddrV4) -> io::Result<u16> {
-        let local_port = self.datagram_map.get(&src);
-        match local_port {
-            Some(&local_port) => {
-                // Update LRU
-                self.udp_lru.get(&local_port);
+#[test]
+fn redirector_virtual_clock() {
+    let clock = clock::VirtualClock::new();
+    let (mut redirector, _, _loopback) =
+        testing::redirector(Config::new().clock(Arc::new(clock.clone())));
+    let _incoming = redirector.incoming();
+
+    let syn_acks = |actions: &[StepAction]| {
+        actions
+            .iter()
+            .filter(|action| match action {
+                StepAction::Transmit(frame) => match Indicator::from(frame).unwrap().transport() {
+                    Some(Layers::Tcp(tcp)) => tcp.is_syn() && tcp.is_ack(),
+                    _ => false,
+                },
+                StepAction::Idle => false,
+            })
+            .count()
+    };
+    let frame = |port| {
+        testing::FrameBuilder::new(
+            SocketAddrV4::new(Ipv4Addr::new(10, 6, 0, 1), port),
+            "1.1.1.1:80".parse().unwrap(),
+        )
+        .syn(1000)
+    };
+    let mut rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        let actions = redirector.step(&frame(50000)).await.unwrap();
+        assert_eq!(syn_acks(&actions), 1);
+
+        // Nothing is retransmitted while the clock stands still
+        let actions = redirector.step(&frame(50001)).await.unwrap();
+        assert_eq!(syn_acks(&actions), 1);
+        let actions = redirector.step(&frame(50002)).await.unwrap();
+        assert_eq!(syn_acks(&actions), 1);
+
+        // All SYN-ACKs are retransmitted before the frame is processed
+        clock.advance(Duration::from_millis(INITIAL_RTO + 2 * TIMER_TICK));
+        let actions = redirector.step(&frame(50003)).await.unwrap();
+        assert_eq!(syn_acks(&actions), 4);
+    });
+}
 
-                Ok(local_port)
-            }
-            None => {
-                let bind_port = if self.udp_lru.len() < self.udp_lru.cap() {
-                    match DatagramWorker::bind(self.get_tx(), src, self.remote, &self.options).await
-                    {
-                        Ok((worker, port)) => {
-                            self.datagrams.insert(port, worker);
+#[test]
+fn redirector_flush_coalesced() {
+    use tokio::io::AsyncReadExt;
+
+    let (mut redirector, _, _loopback) = testing::redirector(Config::new());
+    let mut incoming = redirector.incoming();
+
+    let builder = testing::FrameBuilder::new(
+        "10.6.0.1:50000".parse().unwrap(),
+        "1.1.1.1:80".parse().unwrap(),
+    );
+    let mut rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        let actions = redirector.step(&builder.syn(1000)).await.unwrap();
+        let isn = match &actions[..] {
+            [StepAction::Transmit(frame)] => match Indicator::from(frame).unwrap().transport() {
+                Some(Layers::Tcp(tcp)) => tcp.sequence(),
+                _ => unreachable!(),
+            },
+            _ => unreachable!(),
+        };
+        redirector
+            .step(&builder.ack(1001, seq_add(isn, 1), &[]))
+            .await
+            .unwrap();
+        let mut connection = match incoming.recv().await.unwrap() {
+            Flow::Tcp(connection) => connection,
+            _ => unreachable!(),
+        };
 
-                            // Update map and LRU
-                            self.datagram_map.insert(src, port);
-                            self.udp_lru.put(port, src);
+        // The segment without PSH is coalesced, and written by the timer driver while no frame
+        // is processed
+        redirector
+            .step(&builder.ack(1001, seq_add(isn, 1), b"hello"))
+            .await
+            .unwrap();
+        let mut buffer = [0u8; 5];
+        time::timeout(Duration::from_secs(1), connection.read_exact(&mut buffer))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buffer, b"hello");
+    });
+}
 
-                            trace!("bind UDP port {} = {}", port, src);
+#[test]
+fn redirector_subscribe() {
+    let (mut redirector, _, _loopback) = testing::redirector(Config::new());
+    let _incoming = redirector.incoming();
+    let mut flows = redirector.subscribe();
+
+    let src = "10.6.0.1:50000".parse().unwrap();
+    let dst = "1.1.1.1:80".parse().unwrap();
+    let builder = testing::FrameBuilder::new(src, dst);
+    let mut rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        let actions = redirector.step(&builder.syn(1000)).await.unwrap();
+        let sequence = match actions.as_slice() {
+            [StepAction::Transmit(frame)] => match Indicator::from(frame).unwrap().transport() {
+                Some(Layers::Tcp(tcp)) => tcp.sequence(),
+                _ => panic!("not TCP"),
+            },
+            _ => panic!("no SYN/ACK"),
+        };
+        redirector
+            .step(&builder.ack(1001, seq_add(sequence, 1), &[]))
+            .await
+            .unwrap();
+        redirector
+            .step(&builder.fin(1001, seq_add(sequence, 1)))
+            .await
+            .unwrap();
+        redirector.step(&builder.rst(1002)).await.unwrap();
+    });
+
+    assert_eq!(
+        flows.try_recv().unwrap(),
+        FlowEvent::SynReceived { src, dst }
+    );
+    assert_eq!(
+        flows.try_recv().unwrap(),
+        FlowEvent::Established { src, dst }
+    );
+    assert_eq!(
+        flows.try_recv().unwrap(),
+        FlowEvent::HalfClosed {
+            src,
+            dst,
+            by_source: true
+        }
+    );
+    assert_eq!(
+        flows.try_recv().unwrap(),
+        FlowEvent::Closed {
+            src,
+            dst,
+            reason: CloseReason::Reset
+        }
+    );
+    assert!(flows.try_recv().is_err());
+}
 
-                            Ok(port)
-                        }
-                        Err(e) => Err(e),
-                    }
-                } else {
-                    Err(io::Error::new(io::ErrorKind::Other, "cannot bind UDP port"))
-                };
+#[test]
+fn redirector_rst_validation() {
+    let clock = clock::VirtualClock::new();
+    let (mut redirector, _, _loopback) =
+        testing::redirector(Config::new().clock(Arc::new(clock.clone())));
+    let _incoming = redirector.incoming();
+    let mut flows = redirector.subscribe();
+    let stats = redirector.stats();
+
+    let acks = |actions: &[StepAction]| {
+        actions
+            .iter()
+            .filter(|action| match action {
+                StepAction::Transmit(frame) => match Indicator::from(frame).unwrap().transport() {
+                    Some(Layers::Tcp(tcp)) => tcp.is_ack() && !tcp.is_syn() && !tcp.is_rst(),
+                    _ => false,
+                },
+                StepAction::Idle => false,
+            })
+            .count()
+    };
+    let src = "10.6.0.1:50000".parse().unwrap();
+    let dst = "1.1.1.1:80".parse().unwrap();
+    let builder = testing::FrameBuilder::new(src, dst);
+    let mut rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        let actions = redirector.step(&builder.syn(1000)).await.unwrap();
+        let sequence = match actions.as_slice() {
+            [StepAction::Transmit(frame)] => match Indicator::from(frame).unwrap().transport() {
+                Some(Layers::Tcp(tcp)) => tcp.sequence(),
+                _ => panic!("not TCP"),
+            },
+            _ => panic!("no SYN/ACK"),
+        };
+        redirector
+            .step(&builder.ack(1001, seq_add(sequence, 1), &[]))
+            .await
+            .unwrap();
+
+        // RST in the window is challenged with exactly one ACK
+        let actions = redirector.step(&builder.rst(1101)).await.unwrap();
+        assert_eq!(acks(&actions), 1);
+        assert_eq!(stats.tcp_challenge_acks(), 1);
+        assert_eq!(stats.tcp_invalid_segments(), 1);
+
+        // RST out of the window is dropped silently
+        let actions = redirector.step(&builder.rst(1_001_001)).await.unwrap();
+        assert_eq!(acks(&actions), 0);
+        assert_eq!(stats.tcp_challenge_acks(), 1);
+        assert_eq!(stats.tcp_invalid_segments(), 2);
+
+        // Segment beyond the window is challenged
+        let actions = redirector
+            .step(&builder.ack(1_001_001, seq_add(sequence, 1), b"hello"))
+            .await
+            .unwrap();
+        assert_eq!(acks(&actions), 1);
+        assert_eq!(stats.tcp_challenge_acks(), 2);
+        assert_eq!(stats.tcp_invalid_segments(), 3);
+
+        // Challenge ACKs are limited per second
+        redirector.challenge_acks = CHALLENGE_ACK_LIMIT - 1;
+        let actions = redirector.step(&builder.rst(1101)).await.unwrap();
+        assert_eq!(acks(&actions), 1);
+        let actions = redirector.step(&builder.rst(1101)).await.unwrap();
+        assert_eq!(acks(&actions), 0);
+        assert_eq!(stats.tcp_challenge_acks(), 3);
+        clock.advance(Duration::from_secs(1));
+        let actions = redirector.step(&builder.rst(1101)).await.unwrap();
+        assert_eq!(acks(&actions), 1);
+        assert_eq!(stats.tcp_challenge_acks(), 4);
+        assert_eq!(stats.tcp_invalid_segments(), 6);
+
+        // RST at the exact sequence resets the connection
+        let actions = redirector.step(&builder.rst(1001)).await.unwrap();
+        assert_eq!(acks(&actions), 0);
+        assert_eq!(stats.tcp_invalid_segments(), 6);
+    });
+
+    assert_eq!(
+        flows.try_recv().unwrap(),
+        FlowEvent::SynReceived { src, dst }
+    );
+    assert_eq!(
+        flows.try_recv().unwrap(),
+        FlowEvent::Established { src, dst }
+    );
+    assert_eq!(
+        flows.try_recv().unwrap(),
+        FlowEvent::Closed {
+            src,
+            dst,
+            reason: CloseReason::Reset
+        }
+    );
+    assert!(flows.try_recv().is_err());
+}
 
-                match bind_port {
-                    Ok(port) => Ok(port),
-                    Err(e) => {
-                        if self.udp_lru.is_empty() {
-                            Err(e)
-                        } else {
-                            let pair = self.udp_lru.pop_lru().unwrap();
-                            let port = pair.0;
-                            let prev_src = pair.1;
+#[test]
+fn redirector_multicast_membership_expiry() {
+    let clock = clock::VirtualClock::new();
+    let _clock = clock::enter(Some(Arc::new(clock.clone())));
+    let (mut redirector, _, _loopback) = testing::redirector(Config::new());
+    let stats = redirector.stats();
+
+    let group = Ipv4Addr::new(224, 0, 0, 251);
+    let v1_host = Ipv4Addr::new(10, 6, 0, 1);
+    let v2_host = Ipv4Addr::new(10, 6, 0, 2);
+    let v1_report = [0x12, 0x00, 0x00, 0x00, 224, 0, 0, 251];
+    let v2_report = [0x16, 0x00, 0x00, 0x00, 224, 0, 0, 251];
+    let hardware_addr = testing::SRC_HARDWARE_ADDR;
+    let members = |redirector: &Redirector| {
+        let mut members = redirector
+            .multicast_groups
+            .get(&group)
+            .map(|members| members.keys().cloned().collect::<Vec<_>>())
+            .unwrap_or_default();
+        members.sort();
+        members
+    };
 
-                            // Reuse
-                            self.datagram_map.remove(&prev_src);
-                            trace!("reuse UDP port {} = {} to {}", port, prev_src, src);
-                            self.datagram_map.insert(src.clone(), port);
+    redirector.handle_igmp(v1_host, hardware_addr, &v1_report);
+    redirector.handle_igmp(v2_host, hardware_addr, &v2_report);
+    assert_eq!(members(&redirector), vec![v1_host, v2_host]);
+
+    // The IGMPv1 host never leaves, and lapses after the Group Membership Interval, while the
+    // membership reported again is kept
+    clock.advance(Duration::from_millis(GROUP_MEMBERSHIP_INTERVAL / 2));
+    redirector.handle_igmp(v2_host, hardware_addr, &v2_report);
+    clock.advance(Duration::from_millis(GROUP_MEMBERSHIP_INTERVAL / 2));
+    redirector.expire_multicast_groups();
+    assert_eq!(members(&redirector), vec![v2_host]);
+    assert_eq!(stats.multicast_groups(), 1);
+
+    // The group is removed once all memberships lapse
+    clock.advance(Duration::from_millis(GROUP_MEMBERSHIP_INTERVAL / 2));
+    redirector.expire_multicast_groups();
+    assert!(redirector.multicast_groups.is_empty());
+    assert_eq!(stats.multicast_groups(), 0);
+}
 
-                            // Update LRU
-                            self.udp_lru.put(port, src.clone());
+#[test]
+fn redirector_replay() {
+    struct Refusal;
 
-                            Ok(port)
-                        }
-                    }
-                }
-            }
+    impl PacketMiddleware for Refusal {
+        fn on_new_connection(&mut self, _: SocketAddrV4, _: SocketAddrV4, _: LayerKind) -> bool {
+            false
         }
     }
 
-    fn unbind_local_udp_port(&mut self, src: SocketAddrV4) {
-        let local_port = self.datagram_map.get(&src);
-        match local_port {
-            Some(&local_port) => {
-                self.datagrams.remove(&local_port);
-                self.udp_lru.pop(&local_port);
-                self.datagram_map.remove(&src);
+    fn run(tx: pcap::Sender, rx: &mut pcap::Receiver) {
+        let mut redirector = testing::redirector_with_sender(tx, Config::new());
+        redirector.add_middleware(Refusal);
 
-                trace!("unbind UDP port {} = {}", local_port, src);
-            }
-            None => {}
-        }
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let e = rt.block_on(redirector.open(rx)).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
     }
 
-    fn get_tx(&self) -> Arc<Mutex<Forwarder>> {
-        Arc::clone(&self.tx)
+    // Record a session
+    let path = std::env::temp_dir().join(format!("pcap2socks-replay-{}", std::process::id()));
+    let builder = testing::FrameBuilder::new(
+        "10.6.0.1:50000".parse().unwrap(),
+        "1.1.1.1:80".parse().unwrap(),
+    );
+    let (tx, rx, mut loopback) = pcap::memory();
+    let (tx, mut rx) = session::record(tx, rx, &path).unwrap();
+    loopback.inject(&builder.syn(1000));
+    loopback.inject(&builder.rst(1001));
+    loopback.close();
+    run(tx, &mut rx);
+    drop(rx);
+    let session = session::Session::open(&path).unwrap();
+    let _ = std::fs::remove_file(&path);
+    assert_eq!(session.inbound().len(), 2);
+    assert_eq!(session.outbound(), loopback.sent());
+
+    // The replay matches the golden trace
+    let (tx, mut rx, loopback) = session::replay(&session, None);
+    run(tx, &mut rx);
+    let golden = session.trace();
+    assert!(!golden.is_empty());
+    assert_eq!(session::trace(&session.inbound(), &loopback.sent()), golden);
+}
+
+#[test]
+fn redirector_sni_block() {
+    let (mut redirector, mut rx, mut loopback) =
+        testing::redirector(Config::new().sni_rule("example.com", SniAction::Block));
+
+    let builder = testing::FrameBuilder::new(
+        "10.6.0.1:50000".parse().unwrap(),
+        "1.1.1.1:443".parse().unwrap(),
+    );
+    loopback.inject(&builder.syn(1000));
+    loopback.inject(&builder.ack(1001, 1, &sni::client_hello("www.example.com")));
+    loopback.close();
+    let mut rt = tokio::runtime::Runtime::new().unwrap();
+    let e = rt.block_on(redirector.open(&mut rx)).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+
+    // Accepted without the proxy, and reset after the ClientHello
+    let frames = loopback.sent();
+    assert_eq!(frames.len(), 2);
+    let tcps = frames
+        .iter()
+        .map(|frame| match Indicator::from(frame).unwrap().transport() {
+            Some(Layers::Tcp(tcp)) => tcp.clone(),
+            _ => panic!("not TCP"),
+        })
+        .collect::<Vec<_>>();
+    assert!(tcps[0].is_syn() && tcps[0].is_ack());
+    assert!(tcps[1].is_rst());
+    assert_eq!(redirector.stats().sni_blocks(), 1);
+}
+
+#[test]
+fn redirector_sni_fail_closed() {
+    let (mut redirector, mut rx, mut loopback) =
+        testing::redirector(Config::new().sni_rule("example.com", SniAction::Block));
+
+    // A ClientHello never completing within the max size cannot be inspected
+    let builder = testing::FrameBuilder::new(
+        "10.6.0.1:50000".parse().unwrap(),
+        "1.1.1.1:443".parse().unwrap(),
+    );
+    loopback.inject(&builder.syn(1000));
+    let mut hello = vec![22, 3, 1, 0xff, 0xff];
+    hello.resize(sni::MAX_HELLO_SIZE, 0);
+    let mut sequence = 1001;
+    for chunk in hello.chunks(1400) {
+        loopback.inject(&builder.ack(sequence, 1, chunk));
+        sequence += chunk.len() as u32;
+    }
+    loopback.close();
+    let mut rt = tokio::runtime::Runtime::new().unwrap();
+    let e = rt.block_on(redirector.open(&mut rx)).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+
+    // Reset as blocked
+    let is_reset =
+        loopback
+            .sent()
+            .iter()
+            .any(|frame| match Indicator::from(frame).unwrap().transport() {
+                Some(Layers::Tcp(tcp)) => tcp.is_rst(),
+                _ => false,
+            });
+    assert!(is_reset);
+    assert_eq!(redirector.stats().sni_blocks(), 1);
+}
+
+#[test]
+fn redirector_audit() {
+    use audit::{AuditAction, AuditRecord};
+    use policy::PortProfile;
+
+    let profile: PortProfile = "consoles=allow:tcp:443,deny:tcp:25".parse().unwrap();
+    let (mut redirector, mut rx, mut loopback) = testing::redirector(
+        Config::new()
+            .port_profile(profile)
+            .port_profile_source("10.6.0.1".parse().unwrap(), "consoles"),
+    );
+    let records = Arc::new(Mutex::new(Vec::new()));
+    let records_cloned = Arc::clone(&records);
+    redirector
+        .set_audit(move |record: &AuditRecord| records_cloned.lock().unwrap().push(record.clone()));
+
+    let builder = testing::FrameBuilder::new(
+        "10.6.0.1:50000".parse().unwrap(),
+        "1.1.1.1:25".parse().unwrap(),
+    );
+    loopback.inject(&builder.syn(1000));
+    loopback.close();
+    let mut rt = tokio::runtime::Runtime::new().unwrap();
+    let e = rt.block_on(redirector.open(&mut rx)).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+
+    let records = records.lock().unwrap();
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].action, AuditAction::Refuse);
+    assert_eq!(records[0].hardware_addr, Some(testing::SRC_HARDWARE_ADDR));
+    assert_eq!(records[0].rule, "port-policy consoles deny:tcp:25");
+    let line = records[0].to_string();
+    assert!(line.contains(" action=refuse protocol=tcp src=10.6.0.1:50000 mac="));
+    assert!(line.ends_with(
+        " dst=1.1.1.1:25 rule=\"port-policy consoles deny:tcp:25\" rx=0 tx=0 duration=0.000"
+    ));
+}
+
+#[cfg(feature = "udp")]
+#[test]
+fn redirector_netbios_name() {
+    let (mut redirector, mut rx, mut loopback) = testing::redirector(
+        Config::new()
+            .broadcast_mode(BroadcastMode::Drop)
+            .netbios_name("GameHost", Ipv4Addr::new(192, 168, 1, 10)),
+    );
+
+    // Name queries of "GAMEHOST<00>" and "OTHER<00>"
+    let query = |name: &[u8]| {
+        let mut payload = vec![0x8a, 0x21, 0x01, 0x10, 0, 1, 0, 0, 0, 0, 0, 0, 0x20];
+        for i in 0..16 {
+            let c = match i {
+                15 => 0x00,
+                _ => name.get(i).copied().unwrap_or(b' '),
+            };
+            payload.push(b'A' + (c >> 4));
+            payload.push(b'A' + (c & 0x0f));
+        }
+        payload.extend_from_slice(&[0x00, 0x00, 0x20, 0x00, 0x01]);
+
+        payload
+    };
+    let builder = testing::FrameBuilder::new(
+        "10.6.0.1:137".parse().unwrap(),
+        "255.255.255.255:137".parse().unwrap(),
+    );
+    loopback.inject(&builder.udp(&query(b"GAMEHOST")));
+    loopback.inject(&builder.udp(&query(b"OTHER")));
+    loopback.close();
+    let mut rt = tokio::runtime::Runtime::new().unwrap();
+    let e = rt.block_on(redirector.open(&mut rx)).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+
+    // Only the configured name is answered, and the other query falls back to the broadcast mode
+    let frames = loopback.sent();
+    assert_eq!(frames.len(), 1);
+    let indicator = Indicator::from(&frames[0]).unwrap();
+    let udp = match indicator.transport() {
+        Some(Layers::Udp(udp)) => udp,
+        _ => panic!("not UDP"),
+    };
+    assert_eq!(udp.src_ip_addr(), Ipv4Addr::new(10, 6, 0, 254));
+    assert_eq!(udp.src(), 137);
+    assert_eq!(udp.dst_ip_addr(), Ipv4Addr::new(10, 6, 0, 1));
+    assert_eq!(udp.dst(), 137);
+    let payload = &frames[0][indicator.len()..indicator.content_len()];
+    assert_eq!(&payload[..4], &[0x8a, 0x21, 0x85, 0x00]);
+    assert_eq!(&payload[payload.len() - 4..], &[192, 168, 1, 10]);
+    assert_eq!(redirector.stats().broadcast_answers(), 1);
+    assert_eq!(redirector.stats().broadcast_drops(), 1);
+}
+
+#[cfg(feature = "icmp")]
+#[test]
+fn redirector_icmp_echo() {
+    let (mut redirector, mut rx, mut loopback) =
+        testing::redirector(Config::new().icmp_echo(EchoMode::Reply, 0));
+
+    let builder =
+        testing::FrameBuilder::new("10.6.0.1:0".parse().unwrap(), "1.1.1.1:0".parse().unwrap());
+    loopback.inject(&builder.icmpv4_echo(1, 1, b"ping"));
+    loopback.inject(&builder.icmpv4_echo(1, 2, b"pong"));
+    loopback.close();
+    let mut rt = tokio::runtime::Runtime::new().unwrap();
+    let e = rt.block_on(redirector.open(&mut rx)).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+
+    // Replied with the identifiers, the sequence numbers and the data of the requests
+    let frames = loopback.sent();
+    assert_eq!(frames.len(), 2);
+    for (frame, (sequence_number, data)) in frames.iter().zip(&[(1, b"ping"), (2, b"pong")]) {
+        let indicator = Indicator::from(frame).unwrap();
+        let ipv4 = indicator.ipv4().unwrap();
+        assert_eq!(ipv4.src(), Ipv4Addr::new(1, 1, 1, 1));
+        assert_eq!(ipv4.dst(), Ipv4Addr::new(10, 6, 0, 1));
+        let icmpv4 = match indicator.transport() {
+            Some(Layers::Icmpv4(icmpv4)) => icmpv4,
+            _ => panic!("not ICMPv4"),
+        };
+        assert!(icmpv4.is_echo_reply());
+        assert_eq!(icmpv4.identifier(), Some(1));
+        assert_eq!(icmpv4.sequence_number(), Some(*sequence_number));
+        assert!(frame[indicator.len() - 4..indicator.len()] == data[..]);
     }
+    assert_eq!(redirector.stats().icmp_echo_replies(), 2);
 }
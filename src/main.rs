@@ -5,27 +5,62 @@ use std::clone::Clone;
 use std::fmt::Display;
 use std::io::{self, Write};
 use std::net::{AddrParseError, IpAddr, Ipv4Addr, SocketAddrV4};
+use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::atomic::AtomicBool;
 use std::sync::{Arc, Mutex};
 use structopt::StructOpt;
 
-use pcap2socks::{self as lib, Forwarder, Redirector};
+use pcap2socks::audit::AuditLog;
+use pcap2socks::control::Controller;
+use pcap2socks::middleware::mirror::Mirror;
+use pcap2socks::pcap::{HardwareAddr, Interface, Receiver, StoppableReceiver};
+use pcap2socks::policy::PortProfile;
+#[cfg(unix)]
+use pcap2socks::privilege::{self, Credential};
+use pcap2socks::qos::{QosClass, QosMatch};
+use pcap2socks::socks::{Secret, SocksAuth};
+use pcap2socks::source::{AddrRange, SourceSet};
+use pcap2socks::{
+    self as lib, BroadcastMode, Config, Dispatcher, EchoMode, Forwarder, IcmpPolicy, MulticastMode,
+    NatMode, Preset, Redirector, SniAction, TunnelPolicy, UrgentPolicy,
+};
 
 #[tokio::main]
 async fn main() {
     // Parse arguments
     let flags = Flags::from_args();
 
+    // Run as a Windows service
+    #[cfg(all(windows, feature = "service"))]
+    if flags.service {
+        if let Err(ref e) = service::start(flags.verbose) {
+            // The dispatcher fails if the process is not started by the service control manager
+            error!("service: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     // Log
     set_logger(flags.verbose);
 
+    run(flags, Arc::new(AtomicBool::new(false))).await;
+}
+
+/// Redirects traffic until an error occurs or `is_stopped` is set.
+async fn run(flags: Flags, is_stopped: Arc<AtomicBool>) {
     // Interface
-    let inter = match lib::interface(flags.inter) {
+    let inter = match flags.inter {
+        Some(_) => lib::interface(flags.inter.clone()),
+        None => lib::interface_auto(*flags.dst.addr().ip()),
+    };
+    let inter = match inter {
         Some(inter) => inter,
         None => {
             error!("Cannot determine the interface. Available interfaces are listed below, and please use -i <INTERFACE> to designate:");
             for inter in lib::interfaces().iter() {
-                info!("    {}", inter);
+                info!("    {}: {}", inter.index(), inter);
             }
             return;
         }
@@ -63,8 +98,16 @@ async fn main() {
                 return;
             }
         },
-        None => flags.src.unwrap(),
+        None => flags.src[0],
+    };
+    // Additional sources are only available without presets
+    let src_others = match flags.preset {
+        Some(_) => Vec::new(),
+        None => flags.src[1..].to_vec(),
     };
+    let mut includes = vec![src];
+    includes.extend_from_slice(&src_others);
+    let sources = SourceSet::new(includes, flags.exclude.clone());
     let publish = match flags.preset {
         Some(ref preset) => match preset.as_str() {
             "t" | "tencent" => Some(Ipv4Addr::new(10, 6, 0, 2)),
@@ -90,43 +133,715 @@ async fn main() {
 
     // Gateway
     let gw = publish.unwrap_or(inter.ip_addr().unwrap());
-    if src.size() == 1 && src.network() == gw {
+    if sources
+        .includes()
+        .iter()
+        .any(|network| network.size() == 1 && network.network() == gw)
+    {
         error!("The source cannot be the same with the gateway (publish)");
         return;
     }
+    // Traffic to the proxy passes loopback interfaces, and redirecting it loops forever
+    if sources.contains(*flags.dst.addr().ip()) {
+        error!("The destination cannot be in the source");
+        return;
+    }
+
+    // Config
+    let mut config = Config::new();
+    if let Some(tuning) = flags.tuning {
+        info!("Use tuning preset {}", tuning);
+        config = config.preset(tuning);
+    }
+    config = config
+        .sources(src_others)
+        .source_excludes(flags.exclude.clone());
+    if let Some(udp_capacity) = flags.udp_capacity {
+        if udp_capacity == 0 {
+            error!("The UDP capacity cannot be 0");
+            return;
+        }
+        config = config.udp_capacity(udp_capacity);
+    }
+    if let Some(udp_timeout) = flags.udp_timeout {
+        config = config.udp_timeout(udp_timeout.saturating_mul(1000));
+    }
+    if let Some(nat_mode) = flags.nat_mode {
+        info!("Use NAT mode {}", nat_mode);
+        config = config.nat_mode(nat_mode);
+    }
+    if let Some(broadcast_mode) = flags.broadcast_mode {
+        info!("Use broadcast mode {}", broadcast_mode);
+        config = config.broadcast_mode(broadcast_mode);
+    }
+    for netbios_name in flags.netbios_name.iter() {
+        info!(
+            "Answer NetBIOS name {} with {}",
+            netbios_name.name, netbios_name.addr
+        );
+        config = config.netbios_name(&netbios_name.name, netbios_name.addr);
+    }
+    if let Some(ref multicast_mode) = flags.multicast_mode {
+        info!("Use multicast mode {}", multicast_mode);
+        config = config.multicast_mode(multicast_mode.clone());
+    }
+    if let Some(ref mtu_cache) = flags.mtu_cache {
+        config = config.mtu_cache(mtu_cache.clone());
+    }
+    if flags.mtu_probing {
+        info!("Probe path MTU");
+        config = config.mtu_probing(true);
+    }
+    if let Some(ref snapshot) = flags.snapshot {
+        info!("Snapshot state to {}", snapshot.display());
+        config = config.snapshot(snapshot.clone());
+    }
+    if let Some(ref capture) = flags.capture {
+        info!(
+            "Dump frames of TCP connections with errors to {}",
+            capture.display()
+        );
+        config = config.capture(capture.clone());
+    }
+    if let Some(capture_frames) = flags.capture_frames {
+        if capture_frames == 0 {
+            error!("The count of frames kept for dumps cannot be 0");
+            return;
+        }
+        config = config.capture_frames(capture_frames);
+    }
+    if let Some(ref tcp_trace) = flags.tcp_trace {
+        info!(
+            "Dump protocol traces of TCP connections to {}",
+            tcp_trace.display()
+        );
+        config = config.tcp_trace(tcp_trace.clone());
+    }
+    if let Some(tcp_capacity) = flags.tcp_capacity {
+        config = config.tcp_capacity(tcp_capacity);
+    }
+    if flags.tcp_evict {
+        config = config.tcp_eviction(true);
+    }
+    if let Some(tcp_pending_limit) = flags.tcp_pending_limit {
+        config = config.tcp_pending_limit(tcp_pending_limit);
+    }
+    if let Some(connect_rate) = flags.connect_rate {
+        config = config.connect_rate(connect_rate, flags.connect_burst.unwrap_or(0));
+    }
+    if let Some(quarantine_threshold) = flags.quarantine_threshold {
+        let quarantine_time = flags.quarantine_time.unwrap_or(300);
+        config = config.quarantine(quarantine_threshold, quarantine_time.saturating_mul(1000));
+    }
+    if flags.source_binding {
+        config = config.source_binding(true);
+    }
+    for binding in flags.static_binding.iter() {
+        config = config.static_binding(binding.ip_addr, binding.hardware_addr);
+    }
+    for pattern in flags.sni_block.iter() {
+        config = config.sni_rule(pattern, SniAction::Block);
+    }
+    for pattern in flags.sni_bypass.iter() {
+        config = config.sni_rule(pattern, SniAction::Bypass);
+    }
+    if flags.sni_fail_open {
+        config = config.sni_fail_open(true);
+    }
+    if !flags.sni_port.is_empty() {
+        config = config.sni_ports(flags.sni_port.clone());
+    }
+    for range in flags.direct.iter() {
+        config = config.direct(*range);
+    }
+    if flags.direct_all {
+        config = config.direct(AddrRange::new(Ipv4Addr::UNSPECIFIED, Ipv4Addr::BROADCAST));
+    }
+    for class in flags.qos_class.iter() {
+        config = config.qos_class(class.clone());
+    }
+    for m in flags.qos_match.iter() {
+        if !flags
+            .qos_class
+            .iter()
+            .any(|class| class.name() == m.class())
+        {
+            error!("The QoS class {} is not available", m.class());
+            return;
+        }
+        config = config.qos_match(m.clone());
+    }
+    if flags.tx_priority {
+        config = config
+            .tx_priority(true)
+            .priority_ports(flags.priority_port.clone());
+    }
+    for profile in flags.port_profile.iter() {
+        config = config.port_profile(profile.clone());
+    }
+    for source in flags.port_profile_source.iter() {
+        if !flags
+            .port_profile
+            .iter()
+            .any(|profile| profile.name() == source.profile)
+        {
+            error!("The port profile {} is not available", source.profile);
+            return;
+        }
+        config = config.port_profile_source(source.range, &source.profile);
+    }
+    if let Some(tcp_pending_timeout) = flags.tcp_pending_timeout {
+        config = config.tcp_pending_timeout(tcp_pending_timeout.saturating_mul(1000));
+    }
+    if let Some(tcp_idle_timeout) = flags.tcp_idle_timeout {
+        config = config.tcp_idle_timeout(tcp_idle_timeout.saturating_mul(1000));
+    }
+    if let Some(tcp_syn_retries) = flags.tcp_syn_retries {
+        config = config.tcp_syn_retries(tcp_syn_retries);
+    }
+    if let (Some(tcp_queue_high), Some(tcp_queue_low)) = (flags.tcp_queue_high, flags.tcp_queue_low)
+    {
+        if tcp_queue_high > 0 && tcp_queue_low > tcp_queue_high {
+            error!("The low watermark of TCP queues cannot be greater than the high watermark");
+            return;
+        }
+    }
+    if let Some(tcp_queue_high) = flags.tcp_queue_high {
+        config = config.tcp_queue_high(tcp_queue_high);
+    }
+    if let Some(tcp_queue_low) = flags.tcp_queue_low {
+        config = config.tcp_queue_low(tcp_queue_low);
+    }
+    if let Some(tcp_write_limit) = flags.tcp_write_limit {
+        config = config.tcp_write_limit(tcp_write_limit);
+    }
+    if let Some(tcp_out_of_order_limit) = flags.tcp_out_of_order_limit {
+        config = config.tcp_out_of_order_limit(tcp_out_of_order_limit);
+    }
+    if let Some(tcp_recv_window) = flags.tcp_recv_window {
+        if tcp_recv_window == 0 {
+            error!("The TCP receive window cannot be 0");
+            return;
+        }
+        config = config.tcp_recv_window(tcp_recv_window);
+    }
+    if let Some(tcp_recv_wscale) = flags.tcp_recv_wscale {
+        if tcp_recv_wscale > 14 {
+            error!("The TCP window scale cannot be greater than 14");
+            return;
+        }
+        config = config.tcp_recv_wscale(tcp_recv_wscale);
+    }
+    if flags.tcp_pacing {
+        config = config.tcp_pacing(true);
+    }
+    if let Some(tcp_connect_retries) = flags.tcp_connect_retries {
+        config = config.tcp_connect_retries(tcp_connect_retries);
+    }
+    if let Some(tcp_connect_backoff) = flags.tcp_connect_backoff {
+        config = config.tcp_connect_backoff(tcp_connect_backoff.saturating_mul(1000));
+    }
+    if let Some(socks_pool) = flags.socks_pool {
+        config = config.socks_pool(socks_pool);
+    }
+    if let Some(icmp_policy) = flags.icmp_policy {
+        info!("Use ICMP policy {}", icmp_policy);
+        config = config.icmp_policy(icmp_policy);
+    }
+    if let Some(icmp_echo) = flags.icmp_echo {
+        info!("Use ICMP echo mode {}", icmp_echo);
+        config = config.icmp_echo(icmp_echo, flags.icmp_echo_rtt.unwrap_or(0));
+    }
+    if let Some(tunnel_policy) = flags.tunnel_policy {
+        info!("Use tunnel policy {}", tunnel_policy);
+        config = config.tunnel_policy(tunnel_policy);
+    }
+    if let Some(urgent_policy) = flags.urgent_policy {
+        info!("Use urgent policy {}", urgent_policy);
+        config = config.urgent_policy(urgent_policy);
+    }
+    config = config.log_discovery(flags.log_discovery);
+    if let Some(arp_recheck) = flags.arp_recheck {
+        config = config.arp_recheck(arp_recheck.saturating_mul(1000));
+    }
+    let workers = flags.workers.unwrap_or(1);
+    if workers == 0 {
+        error!("The count of workers cannot be 0");
+        return;
+    }
+    config = config.workers(workers);
+    if !flags.proxy.is_empty() {
+        let proxies: Vec<_> = flags.proxy.iter().map(|proxy| proxy.addr()).collect();
+        info!("Balance among {} proxies", proxies.len() + 1);
+        config = config.proxies(proxies);
+    }
+    if let Some(sticky_ttl) = flags.sticky_ttl {
+        let sticky_prefix = flags.sticky_prefix.unwrap_or(32);
+        if sticky_prefix > 32 {
+            error!("The prefix length of destination subnets cannot be greater than 32");
+            return;
+        }
+        info!(
+            "Pin destinations in /{} to proxies for {} s",
+            sticky_prefix, sticky_ttl
+        );
+        config = config
+            .sticky_ttl(sticky_ttl.saturating_mul(1000))
+            .sticky_prefix(sticky_prefix);
+    }
+    if let Some(probe_interval) = flags.probe_interval {
+        if probe_interval > 0 {
+            info!("Probe proxies every {} s", probe_interval);
+        }
+        config = config
+            .probe_interval(probe_interval.saturating_mul(1000))
+            .fastest_ports(flags.fastest_port.clone());
+    }
+    #[cfg(feature = "http2")]
+    if flags.http2 {
+        let mut http2 = lib::socks::Http2Option::new();
+        match flags.http2_tls {
+            Some(ref domain) => {
+                info!("Use HTTP/2 with TLS to {}", domain);
+                http2.set_tls(domain.clone());
+            }
+            None => info!("Use HTTP/2"),
+        }
+        config = config.http2(http2);
+    }
+    #[cfg(feature = "ssh")]
+    if let Some(ref user) = flags.ssh_user {
+        let path = flags.ssh_key.as_ref().unwrap();
+        let private_key = match std::fs::read_to_string(path) {
+            Ok(private_key) => private_key,
+            Err(ref e) => {
+                error!("{}: {}", path.display(), e);
+                return;
+            }
+        };
+        let mut ssh = match lib::socks::SshOption::new(user.clone(), &private_key) {
+            Ok(ssh) => ssh,
+            Err(ref e) => {
+                error!("{}", e);
+                return;
+            }
+        };
+        if let Some(ref host_key) = flags.ssh_host_key {
+            ssh.set_host_key(host_key.clone());
+        }
+        if let Some(ref path) = flags.ssh_known_hosts {
+            ssh.set_known_hosts(path.clone());
+        }
+        ssh.set_insecure(flags.ssh_insecure);
+        info!("Use SSH as {}", user);
+        config = config.ssh(ssh);
+    }
+    #[cfg(feature = "vmess")]
+    if let Some(ref id) = flags.vmess {
+        let security = flags
+            .vmess_security
+            .unwrap_or(lib::socks::VmessSecurity::Aes128Gcm);
+        match lib::socks::VmessOption::new(id, security) {
+            Ok(vmess) => {
+                info!("Use VMess with security {}", security);
+                config = config.vmess(vmess);
+            }
+            Err(ref e) => {
+                error!("{}", e);
+                return;
+            }
+        }
+    }
+    #[cfg(feature = "websocket")]
+    if let Some(ref path) = flags.websocket {
+        let mut websocket = lib::socks::WebSocketOption::new(path.clone());
+        if let Some(ref host) = flags.websocket_host {
+            websocket.set_host(host.clone());
+        }
+        websocket.set_tls(flags.websocket_tls);
+        for header in flags.websocket_header.iter() {
+            if let Err(ref e) = websocket.add_header(header) {
+                error!("{}: {}", e, header);
+                return;
+            }
+        }
+        match flags.websocket_tls {
+            true => info!("Use WebSocket with TLS on {}", path),
+            false => info!("Use WebSocket on {}", path),
+        }
+        config = config.websocket(websocket);
+    }
+    #[cfg(feature = "wireguard")]
+    if let Some(ref private_key) = flags.wireguard_private_key {
+        if workers > 1 {
+            error!("WireGuard cannot be used with multiple workers");
+            return;
+        }
+        let mut wireguard = match lib::wireguard::WireGuardOption::new(
+            private_key,
+            flags.wireguard_public_key.as_ref().unwrap(),
+            flags.wireguard_addr.unwrap(),
+        ) {
+            Ok(wireguard) => wireguard,
+            Err(ref e) => {
+                error!("{}", e);
+                return;
+            }
+        };
+        if let Some(ref preshared_key) = flags.wireguard_preshared_key {
+            if let Err(ref e) = wireguard.set_preshared_key(preshared_key) {
+                error!("{}", e);
+                return;
+            }
+        }
+        if let Some(keepalive) = flags.wireguard_keepalive {
+            wireguard.set_keepalive(keepalive);
+        }
+        info!("Use WireGuard with address {}", wireguard.addr());
+        config = config.wireguard(wireguard);
+    }
+
+    // Authentication
+    let auth = match flags.username {
+        Some(ref username) => match password(&flags) {
+            Ok(password) => Some(SocksAuth::new(username.clone(), password)),
+            Err(ref e) => {
+                error!("password: {}", e);
+                return;
+            }
+        },
+        None => None,
+    };
+
+    // Privileges
+    #[cfg(unix)]
+    let credential = match flags.user {
+        Some(ref user) => {
+            let credential = match flags.group {
+                Some(ref group) => Credential::from_user(user).and_then(|c| c.group(group)),
+                None => Credential::from_user(user),
+            };
+            match credential {
+                Ok(credential) => Some(credential),
+                Err(ref e) => {
+                    error!("user {}: {}", user, e);
+                    return;
+                }
+            }
+        }
+        None => None,
+    };
 
     // Instructions
     show_info(src, gw, mtu);
 
     // Proxy
-    let (tx, mut rx) = match inter.open() {
+    let (tx, rx) = match inter.open() {
         Ok((tx, rx)) => (tx, rx),
         Err(ref e) => {
             error!("{}", e);
             return;
         }
     };
-    let forwarder = Forwarder::new(tx, mtu, inter.hardware_addr(), inter.ip_addr().unwrap());
-    let auth = match flags.username {
-        Some(ref username) => Some((username.clone(), flags.password.unwrap())),
-        None => None,
+    let (tx, rx) = match flags.record {
+        Some(ref path) => match lib::session::record(tx, rx, path) {
+            Ok((tx, rx)) => {
+                info!("Record frames to {}", path.display());
+                (tx, rx)
+            }
+            Err(ref e) => {
+                error!("record {}: {}", path.display(), e);
+                return;
+            }
+        },
+        None => (tx, rx),
     };
-    let mut redirector = Redirector::new(
-        Arc::new(Mutex::new(forwarder)),
-        src,
-        gw,
-        publish,
-        flags.dst.addr(),
-        flags.force_associate_dst,
-        flags.force_associate_bind_addr,
-        auth,
-    );
+    let mut rx: Receiver = Box::new(StoppableReceiver::new(rx, Arc::clone(&is_stopped)));
     match flags.username {
-        Some(username) => info!("Proxy {} to {}@{}", src, username, flags.dst),
-        None => info!("Proxy {} to {}", src, flags.dst),
+        Some(ref username) => info!("Proxy {} to {}@{}", sources, username, flags.dst),
+        None => info!("Proxy {} to {}", sources, flags.dst),
+    }
+
+    // Notify systemd
+    #[cfg(all(unix, feature = "systemd"))]
+    let notifier = lib::systemd::Notifier::from_env();
+    #[cfg(all(unix, feature = "systemd"))]
+    if let Some(ref notifier) = notifier {
+        if let Err(ref e) = notifier.ready(&format!("Proxy {} to {}", sources, flags.dst)) {
+            warn!("notify systemd: {}", e);
+        }
+    }
+
+    // Mirror
+    let mut mirror = None;
+    if let Some(ref path) = flags.mirror {
+        match Mirror::to_file(path) {
+            Ok(file_mirror) => {
+                info!("Mirror frames to {}", path.display());
+                mirror = Some(file_mirror);
+            }
+            Err(ref e) => {
+                error!("mirror {}: {}", path.display(), e);
+                return;
+            }
+        }
+    }
+    if let Some(ref name) = flags.mirror_interface {
+        let mirror_inter = match lib::interface(Some(name.clone())) {
+            Some(mirror_inter) => mirror_inter,
+            None => {
+                error!("Cannot find the mirror interface {}", name);
+                return;
+            }
+        };
+        match mirror_inter.open() {
+            Ok((mirror_tx, _)) => {
+                info!("Mirror frames to {}", mirror_inter);
+                mirror = Some(Mirror::to_interface(mirror_tx));
+            }
+            Err(ref e) => {
+                error!("mirror {}: {}", name, e);
+                return;
+            }
+        }
+    }
+    if let Some(mut m) = mirror.take() {
+        for sample in flags.mirror_sample.iter() {
+            let (protocol, rate) = match sample.find('=') {
+                Some(i) => (Some(&sample[..i]), &sample[i + 1..]),
+                None => (None, sample.as_str()),
+            };
+            let rate = match rate.parse() {
+                Ok(rate) => rate,
+                Err(_) => {
+                    error!("The mirror sample {} is not valid", sample);
+                    return;
+                }
+            };
+            m = match protocol {
+                Some(protocol) => match protocol.parse() {
+                    Ok(protocol) => m.sample_protocol(protocol, rate),
+                    Err(ref e) => {
+                        error!("mirror sample {}: {}", sample, e);
+                        return;
+                    }
+                },
+                None => m.sample(rate),
+            };
+        }
+        mirror = Some(m);
+    }
+
+    // Audit log
+    let audit = match flags.audit_log {
+        Some(ref path) => match AuditLog::open(path) {
+            Ok(audit) => {
+                info!("Audit connections to {}", path.display());
+                Some(audit)
+            }
+            Err(ref e) => {
+                error!("audit log {}: {}", path.display(), e);
+                return;
+            }
+        },
+        None => None,
+    };
+
+    if workers > 1 {
+        let mut dispatcher = Dispatcher::new(
+            tx,
+            mtu,
+            inter.hardware_addr(),
+            inter.ip_addr().unwrap(),
+            src,
+            gw,
+            publish,
+            flags.dst.addr(),
+            flags.force_associate_dst,
+            flags.force_associate_bind_addr,
+            auth,
+            config,
+        );
+        #[cfg(all(unix, feature = "systemd"))]
+        if let Some(notifier) = notifier
+            .as_ref()
+            .and_then(|notifier| notifier.try_clone().ok())
+        {
+            dispatcher.set_notifier(notifier);
+        }
+        if let Some(mirror) = mirror {
+            dispatcher.add_middleware(mirror);
+        }
+        if let Some(audit) = audit {
+            dispatcher.set_audit(audit);
+        }
+        serve_control(&flags, dispatcher.controller(), &inter, &is_stopped);
+        #[cfg(unix)]
+        if let Err(ref e) = restrict(&flags, &credential) {
+            error!("{}", e);
+            return;
+        }
+        match dispatcher.open(&mut rx).await {
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => info!("Stop"),
+            Err(ref e) => {
+                error!("{}", e);
+                #[cfg(all(unix, feature = "systemd"))]
+                if let Some(ref notifier) = notifier {
+                    let _ = notifier.stopping(&e.to_string());
+                }
+            }
+            Ok(_) => {}
+        }
+    } else {
+        let forwarder = Forwarder::new(tx, mtu, inter.hardware_addr(), inter.ip_addr().unwrap());
+        let mut redirector = Redirector::new(
+            Arc::new(Mutex::new(forwarder)),
+            src,
+            gw,
+            publish,
+            flags.dst.addr(),
+            flags.force_associate_dst,
+            flags.force_associate_bind_addr,
+            auth,
+            config,
+        );
+        #[cfg(all(unix, feature = "systemd"))]
+        if let Some(notifier) = notifier
+            .as_ref()
+            .and_then(|notifier| notifier.try_clone().ok())
+        {
+            redirector.set_notifier(notifier);
+        }
+        if let Some(mirror) = mirror {
+            redirector.add_middleware(mirror);
+        }
+        if let Some(audit) = audit {
+            redirector.set_audit(audit);
+        }
+        serve_control(&flags, redirector.controller(), &inter, &is_stopped);
+        #[cfg(unix)]
+        if let Err(ref e) = restrict(&flags, &credential) {
+            error!("{}", e);
+            return;
+        }
+        match redirector.open(&mut rx).await {
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => info!("Stop"),
+            Err(ref e) => {
+                error!("{}", e);
+                #[cfg(all(unix, feature = "systemd"))]
+                if let Some(ref notifier) = notifier {
+                    let _ = notifier.stopping(&e.to_string());
+                }
+            }
+            Ok(_) => {}
+        }
+    }
+}
+
+/// Returns the password of the proxy, which is read from the file descriptor or the kernel
+/// keyring if they are set in the flags.
+fn password(flags: &Flags) -> io::Result<Secret> {
+    #[cfg(unix)]
+    if let Some(fd) = flags.password_fd {
+        return Secret::from_fd(fd);
+    }
+    #[cfg(target_os = "linux")]
+    if let Some(ref description) = flags.password_keyring {
+        return Secret::from_keyring(description);
     }
-    if let Err(ref e) = redirector.open(&mut rx).await {
-        error!("{}", e);
+
+    match flags.password {
+        Some(ref password) => Ok(Secret::new(password.clone())),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the password is required",
+        )),
+    }
+}
+
+/// Drops the privileges to the credential and enters the restricted seccomp profile if they are
+/// set in the flags, once the capture is opened and the control channels are served.
+#[cfg(unix)]
+fn restrict(flags: &Flags, credential: &Option<Credential>) -> io::Result<()> {
+    if let Some(ref credential) = credential {
+        privilege::drop_privileges(credential)
+            .map_err(|e| io::Error::new(e.kind(), format!("drop privileges: {}", e)))?;
+        info!("Run as {}", credential);
+    }
+    if flags.seccomp {
+        privilege::restrict()
+            .map_err(|e| io::Error::new(e.kind(), format!("enter seccomp: {}", e)))?;
+        info!("Enter the restricted seccomp profile");
+    }
+
+    Ok(())
+}
+
+/// Serves the admin channel, the health check endpoint and the gRPC control API, and exports to
+/// the OpenTelemetry collector and the StatsD server if they are set in the flags.
+fn serve_control(
+    flags: &Flags,
+    controller: Controller,
+    inter: &Interface,
+    is_stopped: &Arc<AtomicBool>,
+) {
+    if let Some(ref path) = flags.admin {
+        if let Err(ref e) = lib::admin::serve(path, controller.clone(), Arc::clone(is_stopped)) {
+            warn!("admin {}: {}", path.display(), e);
+        }
+    }
+
+    if let Some(addr) = flags.health {
+        let controller = controller.clone();
+        let proxy = flags.dst.addr();
+        let inter = inter.clone();
+        tokio::spawn(async move {
+            if let Err(ref e) = lib::health::serve(controller, addr, proxy, inter).await {
+                error!("health check: {}", e);
+            }
+        });
+    }
+
+    #[cfg(feature = "metrics")]
+    if let Some(addr) = flags.statsd {
+        let prefix = flags
+            .statsd_prefix
+            .as_deref()
+            .unwrap_or(lib::stats::statsd::DEFAULT_PREFIX);
+        let client = lib::stats::statsd::Client::new(prefix, flags.dogstatsd);
+        let interval = match flags.statsd_interval {
+            Some(interval) => interval.max(1).saturating_mul(1000),
+            None => lib::stats::statsd::DEFAULT_INTERVAL,
+        };
+        info!(
+            "Emit metrics to StatsD server {} every {} ms",
+            addr, interval
+        );
+        let controller = controller.clone();
+        tokio::spawn(async move {
+            if let Err(ref e) = lib::stats::statsd::emit(controller, addr, client, interval).await {
+                error!("StatsD: {}", e);
+            }
+        });
+    }
+
+    #[cfg(feature = "otlp")]
+    if let Some(addr) = flags.otlp {
+        let controller = controller.clone();
+        tokio::spawn(async move {
+            if let Err(ref e) = lib::otlp::export(controller, addr).await {
+                error!("OTLP: {}", e);
+            }
+        });
+    }
+
+    #[cfg(feature = "grpc")]
+    if let Some(addr) = flags.control {
+        tokio::spawn(async move {
+            if let Err(ref e) = lib::control::grpc::serve(controller, addr).await {
+                error!("control: {}", e);
+            }
+        });
     }
 }
 
@@ -215,9 +930,10 @@ struct Flags {
         help = "Source",
         value_name = "ADDRESS",
         required_unless("preset"),
+        number_of_values(1),
         display_order(3)
     )]
-    pub src: Option<Ipv4Network>,
+    pub src: Vec<Ipv4Network>,
     #[structopt(
         long,
         short,
@@ -248,13 +964,7 @@ struct Flags {
         display_order(1001)
     )]
     pub force_associate_bind_addr: bool,
-    #[structopt(
-        long,
-        help = "Username",
-        value_name = "VALUE",
-        requires("password"),
-        display_order(1000)
-    )]
+    #[structopt(long, help = "Username", value_name = "VALUE", display_order(1000))]
     pub username: Option<String>,
     #[structopt(
         long,
@@ -264,42 +974,790 @@ struct Flags {
         display_order(1001)
     )]
     pub password: Option<String>,
-}
-
-/// Represents a logger.
-struct Logger {
-    stderr_logger: env_logger::Logger,
-    stdout_logger: env_logger::Logger,
-}
-
-impl Logger {
-    /// Initializes the global logger.
-    pub fn init(level: LevelFilter) {
-        let fmt = |buf: &mut Formatter, record: &Record| {
-            let mut style = buf.style();
-
-            let level = match &record.level() {
-                Level::Error => style.set_bold(true).set_color(Color::Red).value("error: "),
-                Level::Warn => style
-                    .set_bold(true)
-                    .set_color(Color::Yellow)
-                    .value("warning: "),
-                Level::Info => style.set_bold(true).set_color(Color::Green).value(""),
-                _ => style.set_color(Color::Rgb(165, 165, 165)).value(""),
-            };
-            writeln!(buf, "{}{}", level, record.args())
-        };
-
-        let stderr_logger = env_logger::builder()
-            .target(Target::Stderr)
-            .filter_level(level)
-            .format(fmt)
-            .build();
-        let stdout_logger = env_logger::builder()
-            .target(Target::Stdout)
-            .filter_level(level)
-            .format(fmt)
-            .build();
+    #[cfg(unix)]
+    #[structopt(
+        long,
+        help = "File descriptor to read the password from",
+        value_name = "FD",
+        requires("username"),
+        conflicts_with("password"),
+        display_order(1081)
+    )]
+    pub password_fd: Option<i32>,
+    #[cfg(target_os = "linux")]
+    #[structopt(
+        long,
+        help = "Description of the user key in the kernel keyring to read the password from",
+        value_name = "DESCRIPTION",
+        requires("username"),
+        conflicts_with_all(&["password", "password-fd"]),
+        display_order(1082)
+    )]
+    pub password_keyring: Option<String>,
+    #[structopt(
+        long,
+        help = "Max limit of UDP ports for binding in local",
+        value_name = "VALUE",
+        display_order(1002)
+    )]
+    pub udp_capacity: Option<usize>,
+    #[structopt(
+        long,
+        help = "Idle timeout in seconds of UDP ports for binding in local (0 for never)",
+        value_name = "VALUE",
+        display_order(1003)
+    )]
+    pub udp_timeout: Option<u64>,
+    #[structopt(
+        long = "nat",
+        help = "NAT mode of UDP",
+        value_name = "MODE",
+        display_order(1004)
+    )]
+    pub nat_mode: Option<NatMode>,
+    #[structopt(
+        long = "broadcast",
+        help = "Handling of UDP broadcast (proxy, drop or an address to relay)",
+        value_name = "MODE",
+        display_order(1005)
+    )]
+    pub broadcast_mode: Option<BroadcastMode>,
+    #[structopt(
+        long,
+        help = "Answer broadcast NetBIOS name queries for the name with the address",
+        value_name = "NAME=ADDRESS",
+        number_of_values(1),
+        display_order(1098)
+    )]
+    pub netbios_name: Vec<NetbiosName>,
+    #[structopt(
+        long = "multicast",
+        help = "Handling of UDP multicast (drop, reflect or addresses to relay)",
+        value_name = "MODE",
+        display_order(1006)
+    )]
+    pub multicast_mode: Option<MulticastMode>,
+    #[structopt(
+        long,
+        help = "File for persisting the learned path MTU",
+        value_name = "FILE",
+        display_order(1007)
+    )]
+    pub mtu_cache: Option<PathBuf>,
+    #[structopt(
+        long,
+        help = "Probe the path MTU when large TCP segments are dropped silently",
+        display_order(1099)
+    )]
+    pub mtu_probing: bool,
+    #[structopt(
+        long,
+        help = "Max limit of pending TCP connections of a source (0 for unlimited)",
+        value_name = "VALUE",
+        display_order(1008)
+    )]
+    pub tcp_pending_limit: Option<usize>,
+    #[structopt(
+        long,
+        help = "Max rate of new TCP connections and UDP ports of a source per second (0 for unlimited)",
+        value_name = "VALUE",
+        display_order(1069)
+    )]
+    pub connect_rate: Option<u32>,
+    #[structopt(
+        long,
+        help = "Max count of new TCP connections and UDP ports of a source at once",
+        value_name = "VALUE",
+        requires("connect-rate"),
+        display_order(1070)
+    )]
+    pub connect_burst: Option<u32>,
+    #[structopt(
+        long,
+        help = "Max errors of a source in a minute before quarantining it (0 for unlimited)",
+        value_name = "VALUE",
+        display_order(1071)
+    )]
+    pub quarantine_threshold: Option<u32>,
+    #[structopt(
+        long,
+        help = "Time in seconds of quarantining a source",
+        value_name = "VALUE",
+        requires("quarantine-threshold"),
+        display_order(1072)
+    )]
+    pub quarantine_time: Option<u64>,
+    #[structopt(
+        long,
+        help = "Bind each source to the MAC address it is first seen from, and drop spoofed frames",
+        display_order(1084)
+    )]
+    pub source_binding: bool,
+    #[structopt(
+        long,
+        help = "Bind the source to the MAC address statically, like 10.6.0.1=02:00:00:00:00:01",
+        value_name = "IP=MAC",
+        number_of_values(1),
+        display_order(1085)
+    )]
+    pub static_binding: Vec<StaticBinding>,
+    #[structopt(
+        long,
+        help = "Domain of TLS connections to reset by their SNI, including subdomains",
+        value_name = "PATTERN",
+        number_of_values(1),
+        display_order(1073)
+    )]
+    pub sni_block: Vec<String>,
+    #[structopt(
+        long,
+        help = "Domain of TLS connections to connect directly instead of through the proxy by their SNI, including subdomains",
+        value_name = "PATTERN",
+        number_of_values(1),
+        display_order(1074)
+    )]
+    pub sni_bypass: Vec<String>,
+    #[structopt(
+        long,
+        help = "Destination port of TCP connections inspected for their SNI",
+        value_name = "PORT",
+        number_of_values(1),
+        display_order(1075)
+    )]
+    pub sni_port: Vec<u16>,
+    #[structopt(
+        long,
+        help = "Connect TLS connections whose SNI cannot be inspected through the proxy instead of resetting them",
+        display_order(1095)
+    )]
+    pub sni_fail_open: bool,
+    #[structopt(
+        long,
+        help = "Range of destinations to connect directly instead of through the proxy, like 192.168.0.0/16",
+        value_name = "RANGE",
+        number_of_values(1),
+        display_order(1088)
+    )]
+    pub direct: Vec<AddrRange>,
+    #[structopt(
+        long,
+        help = "Connect every destination directly instead of through the proxy",
+        display_order(1089)
+    )]
+    pub direct_all: bool,
+    #[structopt(
+        long,
+        help = "Class of traffic shaping with the rate in Bytes per second and the burst in Bytes, like bulk=1048576/262144",
+        value_name = "NAME=RATE[/BURST]",
+        number_of_values(1),
+        display_order(1090)
+    )]
+    pub qos_class: Vec<QosClass>,
+    #[structopt(
+        long,
+        help = "Classify flows to the class of traffic shaping, like bulk=tcp:443@10.6.0.10-10.6.0.20",
+        value_name = "NAME=MATCH",
+        number_of_values(1),
+        display_order(1091)
+    )]
+    pub qos_match: Vec<QosMatch>,
+    #[structopt(
+        long,
+        help = "Send small packets, pure ACKs and game traffic ahead of bulk segments",
+        display_order(1092)
+    )]
+    pub tx_priority: bool,
+    #[structopt(
+        long,
+        help = "Destination port of game traffic sent in high priority",
+        value_name = "PORT",
+        number_of_values(1),
+        requires("tx-priority"),
+        display_order(1093)
+    )]
+    pub priority_port: Vec<u16>,
+    #[structopt(
+        long,
+        help = "File for snapshotting UDP NAT bindings, neighbors and learned MTU across restarts",
+        value_name = "FILE",
+        display_order(1094)
+    )]
+    pub snapshot: Option<PathBuf>,
+    #[structopt(
+        long,
+        help = "Named profile of destination port policies, like consoles=allow:tcp:443,allow:udp:1024-65535,deny",
+        value_name = "PROFILE",
+        number_of_values(1),
+        display_order(1076)
+    )]
+    pub port_profile: Vec<PortProfile>,
+    #[structopt(
+        long,
+        help = "Apply the port profile to the range of sources",
+        value_name = "RANGE=PROFILE",
+        number_of_values(1),
+        display_order(1077)
+    )]
+    pub port_profile_source: Vec<PortProfileSource>,
+    #[cfg(unix)]
+    #[structopt(
+        long,
+        help = "User to run as after opening the capture",
+        value_name = "USER",
+        display_order(1078)
+    )]
+    pub user: Option<String>,
+    #[cfg(unix)]
+    #[structopt(
+        long,
+        help = "Group to run as after opening the capture instead of the primary group of the user",
+        value_name = "GROUP",
+        requires("user"),
+        display_order(1079)
+    )]
+    pub group: Option<String>,
+    #[cfg(unix)]
+    #[structopt(
+        long,
+        help = "Enter a restricted seccomp profile after opening the capture",
+        display_order(1080)
+    )]
+    pub seccomp: bool,
+    #[structopt(
+        long,
+        help = "Max limit of simultaneous TCP connections (0 for unlimited)",
+        value_name = "VALUE",
+        display_order(1010)
+    )]
+    pub tcp_capacity: Option<usize>,
+    #[structopt(
+        long,
+        help = "Evict the longest idle TCP connection instead of refusing new ones if the limit is reached",
+        display_order(1011)
+    )]
+    pub tcp_evict: bool,
+    #[structopt(
+        long,
+        help = "Timeout in seconds of pending TCP connections (0 for never)",
+        value_name = "VALUE",
+        display_order(1009)
+    )]
+    pub tcp_pending_timeout: Option<u64>,
+    #[structopt(
+        long,
+        help = "Timeout in seconds of idle TCP connections (0 for never)",
+        value_name = "VALUE",
+        display_order(1049)
+    )]
+    pub tcp_idle_timeout: Option<u64>,
+    #[structopt(
+        long,
+        help = "Max retransmissions of TCP SYN/ACK to sources before resetting pending TCP connections",
+        value_name = "VALUE",
+        display_order(1050)
+    )]
+    pub tcp_syn_retries: Option<usize>,
+    #[structopt(
+        long,
+        help = "High watermark in bytes of TCP queues to pause reading from the proxy (0 for unlimited)",
+        value_name = "VALUE",
+        display_order(1012)
+    )]
+    pub tcp_queue_high: Option<usize>,
+    #[structopt(
+        long,
+        help = "Low watermark in bytes of TCP queues to resume reading from the proxy",
+        value_name = "VALUE",
+        display_order(1013)
+    )]
+    pub tcp_queue_low: Option<usize>,
+    #[structopt(
+        long = "icmp",
+        help = "Handling of ICMP redirect and source quench (ignore, log or honor)",
+        value_name = "POLICY",
+        display_order(1014)
+    )]
+    pub icmp_policy: Option<IcmpPolicy>,
+    #[structopt(
+        long,
+        help = "Handling of ICMP echo requests which cannot be relayed through the proxy (drop or reply)",
+        value_name = "MODE",
+        display_order(1086)
+    )]
+    pub icmp_echo: Option<EchoMode>,
+    #[structopt(
+        long,
+        help = "RTT in milliseconds of synthetic ICMP echo replies (0 for the RTT of proxies)",
+        value_name = "VALUE",
+        requires("icmp-echo"),
+        display_order(1087)
+    )]
+    pub icmp_echo_rtt: Option<u64>,
+    #[structopt(
+        long = "tunnel",
+        help = "Handling of GRE, IPsec and 6in4 packets (drop, log or a peer to forward to)",
+        value_name = "POLICY",
+        display_order(1044)
+    )]
+    pub tunnel_policy: Option<TunnelPolicy>,
+    #[structopt(
+        long = "urgent",
+        help = "Handling of TCP urgent data (inline or strip)",
+        value_name = "POLICY",
+        display_order(1051)
+    )]
+    pub urgent_policy: Option<UrgentPolicy>,
+    #[structopt(
+        long,
+        help = "Log summaries of LLDP, CDP and STP frames on the network",
+        display_order(1045)
+    )]
+    pub log_discovery: bool,
+    #[structopt(
+        long,
+        help = "Interval in seconds of rechecking the gateway with ARP probes (0 to disable)",
+        value_name = "VALUE",
+        display_order(1046)
+    )]
+    pub arp_recheck: Option<u64>,
+    #[structopt(
+        long,
+        help = "Count of workers to dispatch traffic onto by flows",
+        value_name = "VALUE",
+        display_order(1015)
+    )]
+    pub workers: Option<usize>,
+    #[structopt(
+        long,
+        help = "Max limit in bytes of TCP write queues to the proxy before closing the window (0 for unlimited)",
+        value_name = "VALUE",
+        display_order(1016)
+    )]
+    pub tcp_write_limit: Option<usize>,
+    #[structopt(
+        long,
+        help = "Max limit in bytes of out-of-order data of TCP connections before dropping the oldest (0 for unlimited)",
+        value_name = "VALUE",
+        display_order(1047)
+    )]
+    pub tcp_out_of_order_limit: Option<usize>,
+    #[structopt(
+        long,
+        help = "Unscaled receive window of TCP connections",
+        value_name = "VALUE",
+        display_order(1065)
+    )]
+    pub tcp_recv_window: Option<u16>,
+    #[structopt(
+        long,
+        help = "Max window scale of the receive window of TCP connections (0-14)",
+        value_name = "VALUE",
+        display_order(1066)
+    )]
+    pub tcp_recv_wscale: Option<u8>,
+    #[structopt(
+        long,
+        help = "Pace TCP segments sent to the source over the RTT instead of sending them in bursts",
+        display_order(1048)
+    )]
+    pub tcp_pacing: bool,
+    #[structopt(
+        long,
+        help = "Max retries of TCP connections after failing to reach the proxy (0 for never)",
+        value_name = "VALUE",
+        display_order(1041)
+    )]
+    pub tcp_connect_retries: Option<usize>,
+    #[structopt(
+        long,
+        help = "Initial backoff in seconds before retrying TCP connections, doubled in each retry",
+        value_name = "VALUE",
+        requires("tcp-connect-retries"),
+        display_order(1042)
+    )]
+    pub tcp_connect_backoff: Option<u64>,
+    #[structopt(
+        long,
+        help = "Count of pre-connected connections kept to each SOCKS proxy",
+        value_name = "VALUE",
+        display_order(1043)
+    )]
+    pub socks_pool: Option<usize>,
+    #[cfg(all(windows, feature = "service"))]
+    #[structopt(long, help = "Runs as a Windows service", display_order(1017))]
+    pub service: bool,
+    #[cfg(feature = "grpc")]
+    #[structopt(
+        long,
+        help = "Address to serve the gRPC control API on",
+        value_name = "ADDRESS",
+        display_order(1018)
+    )]
+    pub control: Option<std::net::SocketAddr>,
+    #[cfg(feature = "otlp")]
+    #[structopt(
+        long,
+        help = "Address of the OpenTelemetry collector to export metrics and traces to",
+        value_name = "ADDRESS",
+        display_order(1052)
+    )]
+    pub otlp: Option<std::net::SocketAddr>,
+    #[cfg(feature = "metrics")]
+    #[structopt(
+        long,
+        help = "Address of the StatsD server to emit metrics to",
+        value_name = "ADDRESS",
+        display_order(1053)
+    )]
+    pub statsd: Option<std::net::SocketAddr>,
+    #[cfg(feature = "metrics")]
+    #[structopt(
+        long,
+        help = "Prefix of the names of metrics emitted to the StatsD server",
+        value_name = "PREFIX",
+        requires("statsd"),
+        display_order(1054)
+    )]
+    pub statsd_prefix: Option<String>,
+    #[cfg(feature = "metrics")]
+    #[structopt(
+        long,
+        help = "Interval in seconds between emissions to the StatsD server",
+        value_name = "VALUE",
+        requires("statsd"),
+        display_order(1055)
+    )]
+    pub statsd_interval: Option<u64>,
+    #[cfg(feature = "metrics")]
+    #[structopt(
+        long,
+        help = "Tags metrics emitted to the StatsD server in DogStatsD",
+        requires("statsd"),
+        display_order(1056)
+    )]
+    pub dogstatsd: bool,
+    #[structopt(
+        long,
+        help = "Path of the pcap file to mirror sampled frames to",
+        value_name = "PATH",
+        conflicts_with("mirror-interface"),
+        display_order(1057)
+    )]
+    pub mirror: Option<PathBuf>,
+    #[structopt(
+        long,
+        help = "Path of the audit log to append every connection accepted or refused to",
+        value_name = "PATH",
+        display_order(1083)
+    )]
+    pub audit_log: Option<PathBuf>,
+    #[structopt(
+        long,
+        help = "Interface to mirror sampled frames to",
+        value_name = "INTERFACE",
+        display_order(1058)
+    )]
+    pub mirror_interface: Option<String>,
+    #[structopt(
+        long,
+        help = "Mirror 1 in N frames, or frames of the protocol (arp, icmp, tcp, udp or other) (0 for never)",
+        value_name = "[PROTOCOL=]N",
+        number_of_values(1),
+        display_order(1059)
+    )]
+    pub mirror_sample: Vec<String>,
+    #[structopt(
+        long,
+        help = "Directory to dump the last frames of TCP connections to when they hit errors",
+        value_name = "PATH",
+        display_order(1060)
+    )]
+    pub capture: Option<PathBuf>,
+    #[structopt(
+        long,
+        help = "Count of the last frames kept of each TCP connection for dumps",
+        value_name = "VALUE",
+        requires("capture"),
+        display_order(1061)
+    )]
+    pub capture_frames: Option<usize>,
+    #[structopt(
+        long,
+        help = "Path of the pcapng file to record all frames of the session to for replays",
+        value_name = "PATH",
+        display_order(1067)
+    )]
+    pub record: Option<PathBuf>,
+    #[structopt(
+        long,
+        help = "Directory to dump the protocol traces of TCP connections to when they are closed",
+        value_name = "PATH",
+        display_order(1068)
+    )]
+    pub tcp_trace: Option<PathBuf>,
+    #[structopt(
+        long,
+        help = "Address to serve the health check endpoint over HTTP on",
+        value_name = "ADDRESS",
+        display_order(1062)
+    )]
+    pub health: Option<std::net::SocketAddr>,
+    #[structopt(
+        long,
+        help = "Tuning preset (gaming, bulk-download or low-memory-router)",
+        value_name = "PRESET",
+        display_order(1063)
+    )]
+    pub tuning: Option<Preset>,
+    #[structopt(
+        long,
+        help = "Range excluded from the source",
+        value_name = "RANGE",
+        number_of_values(1),
+        display_order(1064)
+    )]
+    pub exclude: Vec<AddrRange>,
+    #[structopt(
+        long,
+        help = "Path of the Unix domain socket or Windows named pipe to serve the admin channel on",
+        value_name = "PATH",
+        display_order(1019)
+    )]
+    pub admin: Option<PathBuf>,
+    #[cfg(feature = "vmess")]
+    #[structopt(
+        long,
+        help = "UUID of the user to relay through the destination as a VMess server",
+        value_name = "ID",
+        display_order(1020)
+    )]
+    pub vmess: Option<String>,
+    #[cfg(feature = "vmess")]
+    #[structopt(
+        long,
+        help = "Security of VMess (aes-128-gcm, chacha20-poly1305 or none)",
+        value_name = "SECURITY",
+        requires("vmess"),
+        display_order(1021)
+    )]
+    pub vmess_security: Option<lib::socks::VmessSecurity>,
+    #[cfg(feature = "wireguard")]
+    #[structopt(
+        long,
+        help = "Private key in base64 to relay through the destination as a WireGuard peer",
+        value_name = "KEY",
+        requires_all(&["wireguard-public-key", "wireguard-addr"]),
+        display_order(1022)
+    )]
+    pub wireguard_private_key: Option<String>,
+    #[cfg(feature = "wireguard")]
+    #[structopt(
+        long,
+        help = "Public key in base64 of the WireGuard peer",
+        value_name = "KEY",
+        requires("wireguard-private-key"),
+        display_order(1023)
+    )]
+    pub wireguard_public_key: Option<String>,
+    #[cfg(feature = "wireguard")]
+    #[structopt(
+        long = "wireguard-address",
+        help = "Address in the WireGuard tunnel",
+        value_name = "ADDRESS",
+        requires("wireguard-private-key"),
+        display_order(1024)
+    )]
+    pub wireguard_addr: Option<Ipv4Addr>,
+    #[cfg(feature = "wireguard")]
+    #[structopt(
+        long,
+        help = "Preshared key in base64 of the WireGuard tunnel",
+        value_name = "KEY",
+        requires("wireguard-private-key"),
+        display_order(1025)
+    )]
+    pub wireguard_preshared_key: Option<String>,
+    #[cfg(feature = "wireguard")]
+    #[structopt(
+        long,
+        help = "Interval in seconds of persistent keepalives of the WireGuard tunnel (0 for never)",
+        value_name = "VALUE",
+        requires("wireguard-private-key"),
+        display_order(1026)
+    )]
+    pub wireguard_keepalive: Option<u64>,
+    #[cfg(feature = "http2")]
+    #[structopt(
+        long,
+        help = "Relay through the destination as an HTTP/2 proxy",
+        display_order(1027)
+    )]
+    pub http2: bool,
+    #[cfg(feature = "http2")]
+    #[structopt(
+        long,
+        help = "Domain of the HTTP/2 proxy to connect in TLS",
+        value_name = "DOMAIN",
+        requires("http2"),
+        display_order(1028)
+    )]
+    pub http2_tls: Option<String>,
+    #[cfg(feature = "websocket")]
+    #[structopt(
+        long,
+        help = "Path of the WebSocket endpoint to carry SOCKS5 in",
+        value_name = "PATH",
+        display_order(1029)
+    )]
+    pub websocket: Option<String>,
+    #[cfg(feature = "websocket")]
+    #[structopt(
+        long,
+        help = "Host of the WebSocket endpoint",
+        value_name = "HOST",
+        requires("websocket"),
+        display_order(1030)
+    )]
+    pub websocket_host: Option<String>,
+    #[cfg(feature = "websocket")]
+    #[structopt(
+        long,
+        help = "Connect to the WebSocket endpoint in TLS",
+        requires("websocket-host"),
+        display_order(1031)
+    )]
+    pub websocket_tls: bool,
+    #[cfg(feature = "websocket")]
+    #[structopt(
+        long,
+        help = "Header of the WebSocket request",
+        value_name = "HEADER",
+        requires("websocket"),
+        number_of_values(1),
+        display_order(1032)
+    )]
+    pub websocket_header: Vec<String>,
+    #[cfg(feature = "ssh")]
+    #[structopt(
+        long,
+        help = "Username to relay through the destination as an SSH server",
+        value_name = "USER",
+        requires("ssh-key"),
+        display_order(1033)
+    )]
+    pub ssh_user: Option<String>,
+    #[cfg(feature = "ssh")]
+    #[structopt(
+        long,
+        help = "Path of the private key of the SSH user",
+        value_name = "PATH",
+        requires("ssh-user"),
+        display_order(1034)
+    )]
+    pub ssh_key: Option<PathBuf>,
+    #[cfg(feature = "ssh")]
+    #[structopt(
+        long,
+        help = "Fingerprint of the host key of the SSH server",
+        value_name = "FINGERPRINT",
+        requires("ssh-user"),
+        display_order(1035)
+    )]
+    pub ssh_host_key: Option<String>,
+    #[cfg(feature = "ssh")]
+    #[structopt(
+        long,
+        help = "Path of the known hosts file of SSH servers, where unknown host keys are trusted on first use",
+        value_name = "PATH",
+        requires("ssh-user"),
+        display_order(1096)
+    )]
+    pub ssh_known_hosts: Option<PathBuf>,
+    #[cfg(feature = "ssh")]
+    #[structopt(
+        long,
+        help = "Accept the host key of the SSH server without verification",
+        requires("ssh-user"),
+        conflicts_with_all(&["ssh-host-key", "ssh-known-hosts"]),
+        display_order(1097)
+    )]
+    pub ssh_insecure: bool,
+    #[structopt(
+        long,
+        help = "Additional proxy to balance new connections across with the destination",
+        value_name = "ADDRESS",
+        number_of_values(1),
+        display_order(1036)
+    )]
+    pub proxy: Vec<ResolvableSocketAddrV4>,
+    #[structopt(
+        long,
+        help = "Time in seconds a destination is pinned to its proxy since it is last used (0 for never)",
+        value_name = "VALUE",
+        requires("proxy"),
+        display_order(1037)
+    )]
+    pub sticky_ttl: Option<u64>,
+    #[structopt(
+        long,
+        help = "Prefix length of destination subnets pinned to the same proxy",
+        value_name = "VALUE",
+        requires("sticky-ttl"),
+        display_order(1038)
+    )]
+    pub sticky_prefix: Option<u8>,
+    #[structopt(
+        long,
+        help = "Interval in seconds between probes of the latency to proxies (0 for never)",
+        value_name = "VALUE",
+        display_order(1039)
+    )]
+    pub probe_interval: Option<u64>,
+    #[structopt(
+        long,
+        help = "Destination port of latency-sensitive connections to send through the fastest proxy",
+        value_name = "PORT",
+        requires("probe-interval"),
+        number_of_values(1),
+        display_order(1040)
+    )]
+    pub fastest_port: Vec<u16>,
+}
+
+/// Represents a logger.
+struct Logger {
+    stderr_logger: env_logger::Logger,
+    stdout_logger: env_logger::Logger,
+}
+
+impl Logger {
+    /// Initializes the global logger.
+    pub fn init(level: LevelFilter) {
+        let fmt = |buf: &mut Formatter, record: &Record| {
+            let mut style = buf.style();
+
+            let level = match &record.level() {
+                Level::Error => style.set_bold(true).set_color(Color::Red).value("error: "),
+                Level::Warn => style
+                    .set_bold(true)
+                    .set_color(Color::Yellow)
+                    .value("warning: "),
+                Level::Info => style.set_bold(true).set_color(Color::Green).value(""),
+                _ => style.set_color(Color::Rgb(165, 165, 165)).value(""),
+            };
+            writeln!(buf, "{}{}", level, record.args())
+        };
+
+        let stderr_logger = env_logger::builder()
+            .target(Target::Stderr)
+            .filter_level(level)
+            .format(fmt)
+            .build();
+        let stdout_logger = env_logger::builder()
+            .target(Target::Stdout)
+            .filter_level(level)
+            .format(fmt)
+            .build();
 
         let logger = Logger {
             stderr_logger,
@@ -333,12 +1791,15 @@ impl Log for Logger {
 }
 
 fn set_logger(verbose: usize) {
-    let level = match verbose {
+    Logger::init(log_level(verbose));
+}
+
+fn log_level(verbose: usize) -> LevelFilter {
+    match verbose {
         0 => LevelFilter::Info,
         1 => LevelFilter::Debug,
         _ => LevelFilter::Trace,
-    };
-    Logger::init(level);
+    }
 }
 
 #[derive(Debug)]
@@ -441,3 +1902,170 @@ impl FromStr for ResolvableSocketAddrV4 {
         Ok(ResolvableSocketAddrV4 { addr, alias })
     }
 }
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+struct PortProfileSource {
+    range: AddrRange,
+    profile: String,
+}
+
+impl FromStr for PortProfileSource {
+    type Err = io::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let i = s.find('=').ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "invalid port profile source")
+        })?;
+        let range = s[..i].parse()?;
+
+        Ok(PortProfileSource {
+            range,
+            profile: s[i + 1..].trim().to_string(),
+        })
+    }
+}
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+struct NetbiosName {
+    name: String,
+    addr: Ipv4Addr,
+}
+
+impl FromStr for NetbiosName {
+    type Err = io::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidInput, "invalid NetBIOS name");
+
+        let i = s.find('=').ok_or_else(invalid)?;
+        let name = s[..i].trim();
+        // NetBIOS names are at most 15 characters with a suffix
+        if name.is_empty() || name.len() > 15 || !name.is_ascii() {
+            return Err(invalid());
+        }
+        let addr = s[i + 1..].trim().parse().map_err(|_| invalid())?;
+
+        Ok(NetbiosName {
+            name: name.to_string(),
+            addr,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+struct StaticBinding {
+    ip_addr: Ipv4Addr,
+    hardware_addr: HardwareAddr,
+}
+
+impl FromStr for StaticBinding {
+    type Err = io::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidInput, "invalid static binding");
+
+        let i = s.find('=').ok_or_else(invalid)?;
+        let ip_addr = s[..i].trim().parse().map_err(|_| invalid())?;
+        let hardware_addr = s[i + 1..].trim().parse().map_err(|_| invalid())?;
+
+        Ok(StaticBinding {
+            ip_addr,
+            hardware_addr,
+        })
+    }
+}
+
+#[cfg(all(windows, feature = "service"))]
+mod service {
+    use log::error;
+    use std::ffi::OsString;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use structopt::StructOpt;
+    use tokio::runtime::Runtime;
+    use windows_service::service::{
+        ServiceControl, ServiceControlAccept, ServiceExitCode, ServiceState, ServiceStatus,
+        ServiceType,
+    };
+    use windows_service::service_control_handler::{self, ServiceControlHandlerResult};
+    use windows_service::{define_windows_service, service_dispatcher};
+
+    use super::{log_level, run, set_logger, Flags};
+
+    /// Represents the name of the service, which is also the source in the event log.
+    const SERVICE_NAME: &str = "pcap2socks";
+
+    define_windows_service!(ffi_service_main, service_main);
+
+    /// Starts the service dispatcher, which blocks until the service is stopped.
+    pub fn start(verbose: usize) -> windows_service::Result<()> {
+        // Log to the event log, since there is no console for a service, or to the console if the
+        // event log is unavailable
+        let _ = winlog::register(SERVICE_NAME);
+        match winlog::init(SERVICE_NAME) {
+            Ok(_) => log::set_max_level(log_level(verbose)),
+            Err(_) => set_logger(verbose),
+        }
+
+        service_dispatcher::start(SERVICE_NAME, ffi_service_main)
+    }
+
+    fn service_main(_: Vec<OsString>) {
+        // The service is started with the arguments in its command line
+        let flags = Flags::from_args();
+
+        if let Err(ref e) = run_service(flags) {
+            error!("service: {}", e);
+        }
+    }
+
+    fn run_service(flags: Flags) -> windows_service::Result<()> {
+        let is_stopped = Arc::new(AtomicBool::new(false));
+        let is_stopped_cloned = Arc::clone(&is_stopped);
+
+        let status_handle =
+            service_control_handler::register(SERVICE_NAME, move |control| match control {
+                ServiceControl::Stop | ServiceControl::Shutdown => {
+                    // Stop gracefully in the next receiving
+                    is_stopped_cloned.store(true, Ordering::Relaxed);
+                    ServiceControlHandlerResult::NoError
+                }
+                ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+                _ => ServiceControlHandlerResult::NotImplemented,
+            })?;
+        status_handle.set_service_status(status(
+            ServiceState::Running,
+            ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+            0,
+        ))?;
+
+        match Runtime::new() {
+            Ok(mut rt) => rt.block_on(run(flags, Arc::clone(&is_stopped))),
+            Err(ref e) => error!("{}", e),
+        }
+
+        // The service failed if it stopped by itself
+        let code = if is_stopped.load(Ordering::Relaxed) {
+            0
+        } else {
+            1
+        };
+        status_handle.set_service_status(status(
+            ServiceState::Stopped,
+            ServiceControlAccept::empty(),
+            code,
+        ))?;
+
+        Ok(())
+    }
+
+    fn status(state: ServiceState, controls: ServiceControlAccept, code: u32) -> ServiceStatus {
+        ServiceStatus {
+            service_type: ServiceType::OWN_PROCESS,
+            current_state: state,
+            controls_accepted: controls,
+            exit_code: ServiceExitCode::Win32(code),
+            checkpoint: 0,
+            wait_hint: Duration::default(),
+            process_id: None,
+        }
+    }
+}
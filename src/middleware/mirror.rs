@@ -0,0 +1,285 @@
+//! Support for mirroring sampled frames to a pcap file or an interface for external analysis.
+
+use log::warn;
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::{Action, PacketMiddleware};
+use crate::pcap::Sender;
+
+/// Represents the magic number of pcap files with timestamps in microseconds.
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+/// Represents the max size of frames in the pcap file.
+const PCAP_SNAPLEN: u32 = 65535;
+/// Represents the link type of Ethernet in pcap files.
+const PCAP_LINKTYPE_ETHERNET: u32 = 1;
+
+/// Represents the EtherType of IPv4.
+const ETHER_TYPE_IPV4: u16 = 0x0800;
+/// Represents the EtherType of ARP.
+const ETHER_TYPE_ARP: u16 = 0x0806;
+/// Represents the EtherType of 802.1Q VLAN tags.
+const ETHER_TYPE_VLAN: u16 = 0x8100;
+/// Represents the EtherType of PPPoE sessions.
+const ETHER_TYPE_PPPOE_SESSION: u16 = 0x8864;
+/// Represents the PPP protocol of IPv4.
+const PPP_PROTOCOL_IPV4: u16 = 0x0021;
+
+/// Represents the protocol of a frame sampled by a `Mirror`.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum MirrorProtocol {
+    /// Represents ARP.
+    Arp,
+    /// Represents ICMPv4.
+    Icmp,
+    /// Represents TCP.
+    Tcp,
+    /// Represents UDP.
+    Udp,
+    /// Represents frames of other protocols.
+    Other,
+}
+
+impl Display for MirrorProtocol {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            MirrorProtocol::Arp => write!(f, "arp"),
+            MirrorProtocol::Icmp => write!(f, "icmp"),
+            MirrorProtocol::Tcp => write!(f, "tcp"),
+            MirrorProtocol::Udp => write!(f, "udp"),
+            MirrorProtocol::Other => write!(f, "other"),
+        }
+    }
+}
+
+impl FromStr for MirrorProtocol {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "arp" => Ok(MirrorProtocol::Arp),
+            "icmp" | "icmpv4" => Ok(MirrorProtocol::Icmp),
+            "tcp" => Ok(MirrorProtocol::Tcp),
+            "udp" => Ok(MirrorProtocol::Udp),
+            "other" => Ok(MirrorProtocol::Other),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "unknown mirror protocol",
+            )),
+        }
+    }
+}
+
+/// Represents the destination of mirrored frames.
+enum Sink {
+    File(File),
+    Interface(Sender),
+}
+
+/// Represents the state of a `Mirror` shared by its clones.
+struct MirrorState {
+    sink: Option<Sink>,
+    rate: u64,
+    rates: HashMap<MirrorProtocol, u64>,
+    counts: HashMap<MirrorProtocol, u64>,
+}
+
+/// Represents a middleware mirroring 1 in N frames received from and sent to sources, like for
+/// debugging without full captures. Clones of a `Mirror` share the destination and the
+/// sampling, so it can be added to multiple workers. Frames are mirrored as is, and mirroring
+/// stops after an error writing the destination.
+#[derive(Clone)]
+pub struct Mirror {
+    state: Arc<Mutex<MirrorState>>,
+}
+
+impl Mirror {
+    fn new(sink: Sink) -> Mirror {
+        Mirror {
+            state: Arc::new(Mutex::new(MirrorState {
+                sink: Some(sink),
+                rate: 1,
+                rates: HashMap::new(),
+                counts: HashMap::new(),
+            })),
+        }
+    }
+
+    /// Creates a new `Mirror` writing frames to the pcap file, which is truncated if it exists.
+    pub fn to_file<P: AsRef<Path>>(path: P) -> io::Result<Mirror> {
+        let mut file = File::create(path)?;
+
+        let mut header = Vec::with_capacity(24);
+        header.extend_from_slice(&PCAP_MAGIC.to_le_bytes());
+        header.extend_from_slice(&2u16.to_le_bytes());
+        header.extend_from_slice(&4u16.to_le_bytes());
+        header.extend_from_slice(&0i32.to_le_bytes());
+        header.extend_from_slice(&0u32.to_le_bytes());
+        header.extend_from_slice(&PCAP_SNAPLEN.to_le_bytes());
+        header.extend_from_slice(&PCAP_LINKTYPE_ETHERNET.to_le_bytes());
+        file.write_all(&header)?;
+
+        Ok(Mirror::new(Sink::File(file)))
+    }
+
+    /// Creates a new `Mirror` sending frames to the send half of an interface.
+    pub fn to_interface(tx: Sender) -> Mirror {
+        Mirror::new(Sink::Interface(tx))
+    }
+
+    /// Sets mirroring 1 in `rate` frames of protocols without their own rates, 0 for never.
+    /// Default as 1, which mirrors all frames.
+    pub fn sample(self, rate: u64) -> Mirror {
+        self.state.lock().unwrap().rate = rate;
+
+        self
+    }
+
+    /// Sets mirroring 1 in `rate` frames of the protocol, 0 for never.
+    pub fn sample_protocol(self, protocol: MirrorProtocol, rate: u64) -> Mirror {
+        self.state.lock().unwrap().rates.insert(protocol, rate);
+
+        self
+    }
+
+    fn mirror(&mut self, frame: &[u8]) {
+        let mut state = self.state.lock().unwrap();
+        let protocol = protocol(frame);
+        let rate = *state.rates.get(&protocol).unwrap_or(&state.rate);
+        if rate == 0 {
+            return;
+        }
+        // Mirror the first frame of every `rate` frames
+        let count = state.counts.entry(protocol).or_insert(0);
+        let is_sampled = *count == 0;
+        *count = (*count + 1) % rate;
+        if !is_sampled {
+            return;
+        }
+
+        let result = match state.sink {
+            Some(Sink::File(ref mut file)) => write_record(file, frame),
+            Some(Sink::Interface(ref mut tx)) => tx.send_to(frame, None).unwrap_or(Ok(())),
+            None => return,
+        };
+        if let Err(ref e) = result {
+            warn!("mirror: {}", e);
+            state.sink = None;
+        }
+    }
+}
+
+impl PacketMiddleware for Mirror {
+    fn on_rx_frame(&mut self, frame: &[u8]) -> Action {
+        self.mirror(frame);
+
+        Action::Continue
+    }
+
+    fn on_tx_frame(&mut self, frame: &[u8]) -> Action {
+        self.mirror(frame);
+
+        Action::Continue
+    }
+}
+
+fn write_record<W: Write>(w: &mut W, frame: &[u8]) -> io::Result<()> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let size = frame.len().min(PCAP_SNAPLEN as usize);
+
+    let mut record = Vec::with_capacity(16 + size);
+    record.extend_from_slice(&(timestamp.as_secs() as u32).to_le_bytes());
+    record.extend_from_slice(&timestamp.subsec_micros().to_le_bytes());
+    record.extend_from_slice(&(size as u32).to_le_bytes());
+    record.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+    record.extend_from_slice(&frame[..size]);
+
+    w.write_all(&record)
+}
+
+/// Returns the protocol of the Ethernet frame.
+fn protocol(frame: &[u8]) -> MirrorProtocol {
+    let mut offset = 12;
+    let mut ether_type = match read_u16(frame, offset) {
+        Some(ether_type) => ether_type,
+        None => return MirrorProtocol::Other,
+    };
+    while ether_type == ETHER_TYPE_VLAN {
+        offset += 4;
+        ether_type = match read_u16(frame, offset) {
+            Some(ether_type) => ether_type,
+            None => return MirrorProtocol::Other,
+        };
+    }
+    offset += 2;
+    if ether_type == ETHER_TYPE_PPPOE_SESSION {
+        // PPPoE header and PPP protocol
+        if read_u16(frame, offset + 6) != Some(PPP_PROTOCOL_IPV4) {
+            return MirrorProtocol::Other;
+        }
+        offset += 8;
+        ether_type = ETHER_TYPE_IPV4;
+    }
+
+    match ether_type {
+        ETHER_TYPE_ARP => MirrorProtocol::Arp,
+        ETHER_TYPE_IPV4 => match frame.get(offset + 9) {
+            Some(1) => MirrorProtocol::Icmp,
+            Some(6) => MirrorProtocol::Tcp,
+            Some(17) => MirrorProtocol::Udp,
+            _ => MirrorProtocol::Other,
+        },
+        _ => MirrorProtocol::Other,
+    }
+}
+
+fn read_u16(frame: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([
+        *frame.get(offset)?,
+        *frame.get(offset + 1)?,
+    ]))
+}
+
+#[test]
+fn mirror_sample() {
+    let mut tcp = vec![0u8; 54];
+    tcp[12..14].copy_from_slice(&ETHER_TYPE_IPV4.to_be_bytes());
+    tcp[14 + 9] = 6;
+    let mut udp = tcp.clone();
+    udp[14 + 9] = 17;
+    let mut vlan_udp = vec![0u8; 58];
+    vlan_udp[12..14].copy_from_slice(&ETHER_TYPE_VLAN.to_be_bytes());
+    vlan_udp[16..18].copy_from_slice(&ETHER_TYPE_IPV4.to_be_bytes());
+    vlan_udp[18 + 9] = 17;
+    assert_eq!(protocol(&tcp), MirrorProtocol::Tcp);
+    assert_eq!(protocol(&vlan_udp), MirrorProtocol::Udp);
+    assert_eq!(protocol(&tcp[..10]), MirrorProtocol::Other);
+
+    let path = std::env::temp_dir().join(format!("pcap2socks-mirror-{}.pcap", std::process::id()));
+    let mut mirror = Mirror::to_file(&path)
+        .unwrap()
+        .sample(0)
+        .sample_protocol(MirrorProtocol::Tcp, 2);
+    for _ in 0..4 {
+        assert_eq!(mirror.on_rx_frame(&tcp), Action::Continue);
+        assert_eq!(mirror.on_tx_frame(&udp), Action::Continue);
+    }
+    drop(mirror);
+
+    // 2 TCP frames of 4 are mirrored
+    let size = std::fs::metadata(&path).unwrap().len();
+    let _ = std::fs::remove_file(&path);
+    assert_eq!(size, 24 + 2 * (16 + 54));
+    assert_eq!(
+        "ICMPv4".parse::<MirrorProtocol>().unwrap(),
+        MirrorProtocol::Icmp
+    );
+}
@@ -0,0 +1,170 @@
+//! Support for inspecting and altering traffic with user-defined middlewares.
+
+use std::borrow::Cow;
+use std::net::SocketAddrV4;
+
+use crate::packet::layer::LayerKind;
+
+pub mod mirror;
+
+/// Represents the action a middleware takes on a frame or a payload.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Action {
+    /// Represents passing the data to the next middleware as is.
+    Continue,
+    /// Represents dropping the data. Later middlewares will not see the data.
+    Drop,
+    /// Represents replacing the data, which is passed to the next middleware.
+    Rewrite(Vec<u8>),
+}
+
+/// Represents a middleware which inspects and alters the traffic of a `Redirector`. Middlewares
+/// are executed in the order they are added as a chain of responsibility, and every hook does
+/// nothing by default, so a middleware only implements the hooks it needs.
+pub trait PacketMiddleware: Send {
+    /// Handles a frame received from pcap before it is handled by the `Redirector`.
+    fn on_rx_frame(&mut self, _frame: &[u8]) -> Action {
+        Action::Continue
+    }
+
+    /// Handles a frame before it is sent to pcap.
+    fn on_tx_frame(&mut self, _frame: &[u8]) -> Action {
+        Action::Continue
+    }
+
+    /// Handles a new TCP connection, or a new UDP port of a source with the destination of its
+    /// first datagram. Returns `false` if the connection should be refused.
+    fn on_new_connection(
+        &mut self,
+        _src: SocketAddrV4,
+        _dst: SocketAddrV4,
+        _kind: LayerKind,
+    ) -> bool {
+        true
+    }
+
+    /// Handles a payload from the source to the destination before it is sent to the proxy.
+    /// Dropping or rewriting a TCP payload does not affect the sequence seen by the source.
+    fn on_payload(
+        &mut self,
+        _src: SocketAddrV4,
+        _dst: SocketAddrV4,
+        _kind: LayerKind,
+        _payload: &[u8],
+    ) -> Action {
+        Action::Continue
+    }
+}
+
+/// Represents a chain of middlewares.
+#[derive(Default)]
+pub(crate) struct Middlewares {
+    middlewares: Vec<Box<dyn PacketMiddleware>>,
+}
+
+impl Middlewares {
+    pub(crate) fn push(&mut self, middleware: Box<dyn PacketMiddleware>) {
+        self.middlewares.push(middleware);
+    }
+
+    /// Executes `on_rx_frame` of the chain. Returns `None` if the frame is dropped.
+    pub(crate) fn on_rx_frame<'a>(&mut self, frame: Cow<'a, [u8]>) -> Option<Cow<'a, [u8]>> {
+        self.execute(frame, |middleware, frame| middleware.on_rx_frame(frame))
+    }
+
+    /// Executes `on_tx_frame` of the chain. Returns `None` if the frame is dropped.
+    pub(crate) fn on_tx_frame<'a>(&mut self, frame: Cow<'a, [u8]>) -> Option<Cow<'a, [u8]>> {
+        self.execute(frame, |middleware, frame| middleware.on_tx_frame(frame))
+    }
+
+    /// Executes `on_new_connection` of the chain. Returns `false` if any middleware refuses the
+    /// connection.
+    pub(crate) fn on_new_connection(
+        &mut self,
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+        kind: LayerKind,
+    ) -> bool {
+        self.middlewares
+            .iter_mut()
+            .all(|middleware| middleware.on_new_connection(src, dst, kind))
+    }
+
+    /// Executes `on_payload` of the chain. Returns `None` if the payload is dropped.
+    pub(crate) fn on_payload<'a>(
+        &mut self,
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+        kind: LayerKind,
+        payload: Cow<'a, [u8]>,
+    ) -> Option<Cow<'a, [u8]>> {
+        self.execute(payload, |middleware, payload| {
+            middleware.on_payload(src, dst, kind, payload)
+        })
+    }
+
+    fn execute<'a, F>(&mut self, data: Cow<'a, [u8]>, mut f: F) -> Option<Cow<'a, [u8]>>
+    where
+        F: FnMut(&mut dyn PacketMiddleware, &[u8]) -> Action,
+    {
+        let mut data = data;
+        for middleware in self.middlewares.iter_mut() {
+            match f(middleware.as_mut(), &data) {
+                Action::Continue => {}
+                Action::Drop => return None,
+                Action::Rewrite(rewritten) => data = Cow::Owned(rewritten),
+            }
+        }
+
+        Some(data)
+    }
+}
+
+#[test]
+fn middlewares_execute() {
+    use crate::packet::layer::LayerKinds;
+
+    struct Upper;
+
+    impl PacketMiddleware for Upper {
+        fn on_payload(
+            &mut self,
+            _: SocketAddrV4,
+            _: SocketAddrV4,
+            _: LayerKind,
+            payload: &[u8],
+        ) -> Action {
+            Action::Rewrite(payload.to_ascii_uppercase())
+        }
+    }
+
+    struct DropHello;
+
+    impl PacketMiddleware for DropHello {
+        fn on_payload(
+            &mut self,
+            _: SocketAddrV4,
+            _: SocketAddrV4,
+            _: LayerKind,
+            payload: &[u8],
+        ) -> Action {
+            if payload == b"HELLO" {
+                Action::Drop
+            } else {
+                Action::Continue
+            }
+        }
+    }
+
+    let mut middlewares = Middlewares::default();
+    middlewares.push(Box::new(Upper));
+    middlewares.push(Box::new(DropHello));
+    let src = "10.6.0.2:1024".parse().unwrap();
+    let dst = "1.1.1.1:80".parse().unwrap();
+
+    let payload = middlewares.on_payload(src, dst, LayerKinds::Tcp, Cow::Borrowed(b"world"));
+    assert_eq!(payload.unwrap().as_ref(), b"WORLD");
+    let payload = middlewares.on_payload(src, dst, LayerKinds::Tcp, Cow::Borrowed(b"hello"));
+    assert!(payload.is_none());
+    assert!(middlewares.on_new_connection(src, dst, LayerKinds::Tcp));
+}
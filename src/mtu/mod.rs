@@ -0,0 +1,363 @@
+//! Support for caching learned path MTU, and searching the path MTU by probing.
+
+use log::trace;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Represents the time in seconds a learned path MTU is valid, as recommended in
+/// [RFC 1191](https://tools.ietf.org/html/rfc1191).
+const MTU_EXPIRE_TIME: u64 = 600;
+
+/// Represents the MTU falling back to when a path drops large packets silently, as recommended in
+/// [RFC 4821](https://tools.ietf.org/html/rfc4821).
+pub const BASE_MTU: usize = 1024;
+
+/// Represents the gap between the sizes known to pass and known to fail, below which a search of
+/// the path MTU completes.
+const SEARCH_GRANULARITY: usize = 32;
+
+/// Represents a cache of the path MTU to destinations, which can be optionally persisted to a
+/// file.
+#[derive(Clone, Debug)]
+pub struct MtuCache {
+    entries: HashMap<Ipv4Addr, (usize, SystemTime)>,
+    path: Option<PathBuf>,
+    is_dirty: bool,
+}
+
+impl MtuCache {
+    /// Creates a new `MtuCache`.
+    pub fn new() -> MtuCache {
+        MtuCache {
+            entries: HashMap::new(),
+            path: None,
+            is_dirty: false,
+        }
+    }
+
+    /// Creates a new `MtuCache` persisted to the given path. Entries which have not expired are
+    /// loaded if the file exists.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<MtuCache> {
+        let mut cache = MtuCache {
+            entries: HashMap::new(),
+            path: Some(path.as_ref().to_path_buf()),
+            is_dirty: false,
+        };
+
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(cache),
+            Err(e) => return Err(e),
+        };
+        for line in content.lines() {
+            let fields: Vec<_> = line.split_whitespace().collect();
+            if fields.len() != 3 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "malformed MTU cache",
+                ));
+            }
+            let (addr, mtu, secs) = match (
+                fields[0].parse::<Ipv4Addr>(),
+                fields[1].parse::<usize>(),
+                fields[2].parse::<u64>(),
+            ) {
+                (Ok(addr), Ok(mtu), Ok(secs)) => (addr, mtu, secs),
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "malformed MTU cache",
+                    ))
+                }
+            };
+
+            let instant = UNIX_EPOCH + Duration::from_secs(secs);
+            if !is_expired(instant) {
+                cache.entries.insert(addr, (mtu, instant));
+            }
+        }
+
+        Ok(cache)
+    }
+
+    /// Sets the path MTU of the destination. Returns if the path MTU is changed, which marks the
+    /// cache dirty until it is saved.
+    pub fn set(&mut self, dst_ip_addr: Ipv4Addr, mtu: usize) -> bool {
+        let prev_mtu = self.get(dst_ip_addr);
+
+        self.entries.insert(dst_ip_addr, (mtu, SystemTime::now()));
+        trace!("set path MTU of {} to {}", dst_ip_addr, mtu);
+
+        let is_changed = prev_mtu != Some(mtu);
+        self.is_dirty |= is_changed && self.path.is_some();
+
+        is_changed
+    }
+
+    /// Returns the path MTU of the destination if it is learned and not expired.
+    pub fn get(&self, dst_ip_addr: Ipv4Addr) -> Option<usize> {
+        match self.entries.get(&dst_ip_addr) {
+            Some(&(mtu, instant)) => {
+                if is_expired(instant) {
+                    None
+                } else {
+                    Some(mtu)
+                }
+            }
+            None => None,
+        }
+    }
+
+    /// Returns the learned path MTU which has not expired, and the time it is learned.
+    pub(crate) fn entries(&self) -> impl Iterator<Item = (Ipv4Addr, usize, SystemTime)> + '_ {
+        self.entries
+            .iter()
+            .filter(|(_, (_, instant))| !is_expired(*instant))
+            .map(|(&addr, &(mtu, instant))| (addr, mtu, instant))
+    }
+
+    /// Inserts the path MTU of the destination learned at the time, like one restored from a
+    /// snapshot. The path MTU is ignored if it has expired or a newer one is learned.
+    pub(crate) fn insert(&mut self, dst_ip_addr: Ipv4Addr, mtu: usize, instant: SystemTime) {
+        if is_expired(instant) {
+            return;
+        }
+        if let Some(&(_, prev_instant)) = self.entries.get(&dst_ip_addr) {
+            if prev_instant >= instant {
+                return;
+            }
+        }
+
+        self.entries.insert(dst_ip_addr, (mtu, instant));
+    }
+
+    /// Returns a copy of the cache to be saved if it is changed since it is saved, and marks it
+    /// clean, so it can be saved without holding the cache.
+    pub(crate) fn take_dirty(&mut self) -> Option<MtuCache> {
+        if !self.is_dirty {
+            return None;
+        }
+        self.is_dirty = false;
+
+        Some(self.clone())
+    }
+
+    /// Saves the cache to the persisted path. Expired entries are dropped.
+    pub fn save(&mut self) -> io::Result<()> {
+        self.entries.retain(|_, (_, instant)| !is_expired(*instant));
+        self.is_dirty = false;
+
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+
+        let mut content = String::new();
+        for (addr, (mtu, instant)) in &self.entries {
+            let secs = instant
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or(Duration::from_secs(0))
+                .as_secs();
+            content.push_str(&format!("{} {} {}\n", addr, mtu, secs));
+        }
+
+        fs::write(path, content)
+    }
+
+    /// Returns the cache without the persisted path, which is only kept in memory.
+    pub fn detach(mut self) -> MtuCache {
+        self.path = None;
+        self
+    }
+
+    /// Returns the persisted path of the cache.
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+}
+
+impl Default for MtuCache {
+    fn default() -> MtuCache {
+        MtuCache::new()
+    }
+}
+
+/// Represents a probe of the path MTU carried by a TCP segment.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct MtuProbe {
+    /// Represents the source of the TCP connection carrying the probe.
+    pub src: SocketAddrV4,
+    /// Represents the destination of the TCP connection carrying the probe.
+    pub dst: SocketAddrV4,
+    /// Represents the sequence after the probe, which acknowledges the probe.
+    pub end: u32,
+    /// Represents the MTU probed.
+    pub mtu: usize,
+}
+
+/// Represents a search of the path MTU to a destination by probing with TCP segments larger than
+/// the current path MTU, like packetization layer path MTU discovery (PLPMTUD,
+/// [RFC 4821](https://tools.ietf.org/html/rfc4821)). An acknowledged probe raises the path MTU,
+/// and a lost probe lowers the upper bound of the search without affecting the path MTU.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct MtuSearch {
+    low: usize,
+    high: usize,
+    probe: Option<MtuProbe>,
+}
+
+impl MtuSearch {
+    /// Creates a new `MtuSearch` between the MTU known to pass and the max MTU which may pass.
+    pub fn new(low: usize, high: usize) -> MtuSearch {
+        MtuSearch {
+            low,
+            high,
+            probe: None,
+        }
+    }
+
+    /// Returns the MTU of the next probe, or `None` if the search is completed or a probe is in
+    /// flight.
+    pub fn next_probe(&self) -> Option<usize> {
+        if self.probe.is_some() || self.is_completed() {
+            return None;
+        }
+
+        Some(self.high - (self.high - self.low) / 2)
+    }
+
+    /// Starts a probe.
+    pub fn start(&mut self, probe: MtuProbe) {
+        self.probe = Some(probe);
+    }
+
+    /// Returns the probe in flight.
+    pub fn probe(&self) -> Option<&MtuProbe> {
+        self.probe.as_ref()
+    }
+
+    /// Marks the probe in flight acknowledged, and returns the MTU probed.
+    pub fn succeed(&mut self) -> Option<usize> {
+        let probe = self.probe.take()?;
+        self.low = probe.mtu;
+
+        Some(probe.mtu)
+    }
+
+    /// Marks the probe in flight lost, and returns the MTU probed.
+    pub fn fail(&mut self) -> Option<usize> {
+        let probe = self.probe.take()?;
+        self.high = probe.mtu - 1;
+
+        Some(probe.mtu)
+    }
+
+    /// Cancels the probe in flight, like the TCP connection carrying the probe is closed.
+    pub fn cancel(&mut self) {
+        self.probe = None;
+    }
+
+    /// Returns if the search is completed.
+    pub fn is_completed(&self) -> bool {
+        self.high < self.low + SEARCH_GRANULARITY
+    }
+}
+
+fn is_expired(instant: SystemTime) -> bool {
+    match instant.elapsed() {
+        Ok(elapsed) => elapsed > Duration::from_secs(MTU_EXPIRE_TIME),
+        // Learned in the future, may be caused by a clock change
+        Err(_) => false,
+    }
+}
+
+#[test]
+fn mtu_cache_set_get() {
+    let mut cache = MtuCache::new();
+    let addr = Ipv4Addr::new(1, 1, 1, 1);
+
+    assert_eq!(cache.get(addr), None);
+    assert!(cache.set(addr, 1400));
+    assert!(!cache.set(addr, 1400));
+    assert_eq!(cache.get(addr), Some(1400));
+}
+
+#[test]
+fn mtu_cache_take_dirty() {
+    let path = std::env::temp_dir().join(format!("pcap2socks-mtu-dirty-{}", std::process::id()));
+    let addr = Ipv4Addr::new(1, 1, 1, 1);
+
+    let mut cache = MtuCache::load(&path).unwrap();
+    assert!(cache.take_dirty().is_none());
+    cache.set(addr, 1400);
+    assert!(cache.take_dirty().is_some());
+    assert!(cache.take_dirty().is_none());
+    cache.set(addr, 1400);
+    assert!(cache.take_dirty().is_none());
+
+    // Caches only kept in memory are never dirty
+    let mut cache = cache.detach();
+    cache.set(addr, 1300);
+    assert!(cache.take_dirty().is_none());
+}
+
+#[test]
+fn mtu_search_probe() {
+    let src = "10.6.0.1:50000".parse().unwrap();
+    let dst = "1.1.1.1:80".parse().unwrap();
+    let mut search = MtuSearch::new(BASE_MTU, 1499);
+
+    // Binary search, which raises on acknowledgements and lowers on losses
+    let mtu = search.next_probe().unwrap();
+    assert_eq!(mtu, 1262);
+    search.start(MtuProbe {
+        src,
+        dst,
+        end: 1000,
+        mtu,
+    });
+    assert!(search.next_probe().is_none());
+    assert_eq!(search.succeed(), Some(1262));
+    let mtu = search.next_probe().unwrap();
+    assert_eq!(mtu, 1381);
+    search.start(MtuProbe {
+        src,
+        dst,
+        end: 2000,
+        mtu,
+    });
+    assert_eq!(search.fail(), Some(1381));
+    assert_eq!(search.next_probe(), Some(1321));
+
+    // Completes within the granularity
+    while let Some(mtu) = search.next_probe() {
+        search.start(MtuProbe {
+            src,
+            dst,
+            end: 0,
+            mtu,
+        });
+        search.fail();
+    }
+    assert!(search.is_completed());
+    assert!(search.high < search.low + SEARCH_GRANULARITY);
+}
+
+#[test]
+fn mtu_cache_persist() {
+    let path = std::env::temp_dir().join(format!("pcap2socks-mtu-{}", std::process::id()));
+    let addr = Ipv4Addr::new(1, 1, 1, 1);
+
+    let mut cache = MtuCache::load(&path).unwrap();
+    cache.set(addr, 1400);
+    cache.save().unwrap();
+
+    let cache = MtuCache::load(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+
+    assert_eq!(cache.get(addr), Some(1400));
+}
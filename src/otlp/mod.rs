@@ -0,0 +1,504 @@
+//! Support for exporting metrics and traces to an OpenTelemetry collector in OTLP/HTTP with the
+//! JSON encoding.
+//!
+//! The statistics are exported as sums and gauges, the latencies of closed TCP connections as
+//! histograms, and each closed TCP connection as a span.
+
+use log::{debug, warn};
+use rand::{self, Rng};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::net::{SocketAddr, SocketAddrV4};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time;
+
+use crate::control::{Connection, Controller};
+use crate::packet::discovery::DiscoveryProtocol;
+use crate::packet::layer::LayerKinds;
+use crate::packet::tunnel::TunnelProtocol;
+use crate::socks::ConnectFailure;
+use crate::Stats;
+
+/// Represents the interval in milliseconds between exports.
+const EXPORT_INTERVAL: u64 = 10000;
+
+/// Represents the timeout in milliseconds of each request to the collector.
+const EXPORT_TIMEOUT: u64 = 5000;
+
+/// Represents the prefix of the names of metrics.
+const METRIC_PREFIX: &str = "pcap2socks.";
+
+/// Represents the bounds in milliseconds of the buckets of histograms.
+const HISTOGRAM_BOUNDS: [f64; 11] = [
+    1.0, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0, 1000.0, 2500.0, 5000.0,
+];
+
+/// Represents the kind of server of spans.
+const SPAN_KIND_SERVER: u32 = 2;
+
+/// Represents the delta aggregation temporality.
+const TEMPORALITY_DELTA: u32 = 1;
+/// Represents the cumulative aggregation temporality.
+const TEMPORALITY_CUMULATIVE: u32 = 2;
+
+/// Exports the metrics and the traces of the controller to the OpenTelemetry collector
+/// periodically. Failures of exports are logged and retried in the next interval.
+pub async fn export(controller: Controller, collector: SocketAddr) -> io::Result<()> {
+    let mut exporter = Exporter::new(collector);
+    let mut interval = time::interval(Duration::from_millis(EXPORT_INTERVAL));
+    loop {
+        interval.tick().await;
+
+        let connections = controller.connections().await?;
+        let stats = controller.stats();
+        if let Err(ref e) = exporter.export(&stats, connections).await {
+            warn!("export to OTLP collector {}: {}", collector, e);
+        }
+    }
+}
+
+/// Represents a histogram of latencies in milliseconds.
+#[derive(Clone, Debug, Default)]
+struct Histogram {
+    count: u64,
+    sum: f64,
+    bucket_counts: [u64; HISTOGRAM_BOUNDS.len() + 1],
+}
+
+impl Histogram {
+    fn record(&mut self, latency: Duration) {
+        let millis = latency.as_secs_f64() * 1000.0;
+        let i = HISTOGRAM_BOUNDS
+            .iter()
+            .position(|&bound| millis <= bound)
+            .unwrap_or(HISTOGRAM_BOUNDS.len());
+
+        self.count += 1;
+        self.sum += millis;
+        self.bucket_counts[i] += 1;
+    }
+}
+
+/// Represents a closed TCP connection to be exported as a span.
+struct Span {
+    start: u128,
+    end: u128,
+    connection: Connection,
+}
+
+/// Represents an exporter of a pcap2socks instance.
+struct Exporter {
+    collector: SocketAddr,
+    start: u128,
+    /// Represents the time the histograms are last exported.
+    histogram_start: u128,
+    /// Represents the TCP connections seen in the last export with the time they are first
+    /// seen.
+    connections: HashMap<(SocketAddrV4, SocketAddrV4), (u128, Connection)>,
+    client_rtts: Histogram,
+    connect_latencies: Histogram,
+    write_latencies: Histogram,
+}
+
+impl Exporter {
+    fn new(collector: SocketAddr) -> Exporter {
+        let now = unix_nanos();
+
+        Exporter {
+            collector,
+            start: now,
+            histogram_start: now,
+            connections: HashMap::new(),
+            client_rtts: Histogram::default(),
+            connect_latencies: Histogram::default(),
+            write_latencies: Histogram::default(),
+        }
+    }
+
+    async fn export(&mut self, stats: &Stats, connections: Vec<Connection>) -> io::Result<()> {
+        let now = unix_nanos();
+        let spans = self.update_connections(connections, now);
+
+        let metrics = self.metrics_json(stats, now);
+        post(self.collector, "/v1/metrics", &metrics).await?;
+        // The histograms are deltas since the last successful export
+        self.histogram_start = now;
+        self.client_rtts = Histogram::default();
+        self.connect_latencies = Histogram::default();
+        self.write_latencies = Histogram::default();
+
+        if !spans.is_empty() {
+            post(self.collector, "/v1/traces", &traces_json(&spans)).await?;
+        }
+        debug!(
+            "export to OTLP collector {}: {} spans",
+            self.collector,
+            spans.len()
+        );
+
+        Ok(())
+    }
+
+    /// Updates the TCP connections, and returns the spans of the connections closed since the
+    /// last export.
+    fn update_connections(&mut self, connections: Vec<Connection>, now: u128) -> Vec<Span> {
+        let mut prev = std::mem::take(&mut self.connections);
+        for connection in connections {
+            let key = (connection.src, connection.dst);
+            let start = prev.remove(&key).map_or(now, |(start, _)| start);
+            self.connections.insert(key, (start, connection));
+        }
+
+        prev.into_iter()
+            .map(|(_, (start, connection))| {
+                for (histogram, latency) in [
+                    (&mut self.client_rtts, connection.client_rtt),
+                    (&mut self.connect_latencies, connection.connect_latency),
+                    (&mut self.write_latencies, connection.write_latency),
+                ] {
+                    if let Some(latency) = latency {
+                        histogram.record(latency);
+                    }
+                }
+
+                Span {
+                    start,
+                    end: now,
+                    connection,
+                }
+            })
+            .collect()
+    }
+
+    fn metrics_json(&self, stats: &Stats, now: u128) -> String {
+        let mut metrics = Vec::new();
+        for &(name, value) in &[
+            ("udp_capacity", stats.udp_capacity() as u64),
+            ("udp_bindings", stats.udp_bindings() as u64),
+            ("quic_sessions", stats.quic_sessions() as u64),
+            ("multicast_groups", stats.multicast_groups() as u64),
+            ("tcp_connections", self.connections.len() as u64),
+        ] {
+            metrics.push(format!(
+                r#"{{"name":"{}{}","gauge":{{"dataPoints":[{{"timeUnixNano":"{}","asInt":"{}"}}]}}}}"#,
+                METRIC_PREFIX, name, now, value
+            ));
+        }
+        for (name, value) in counters(stats) {
+            metrics.push(format!(
+                r#"{{"name":"{}{}","sum":{{"aggregationTemporality":{},"isMonotonic":true,"dataPoints":[{{"startTimeUnixNano":"{}","timeUnixNano":"{}","asInt":"{}"}}]}}}}"#,
+                METRIC_PREFIX, name, TEMPORALITY_CUMULATIVE, self.start, now, value
+            ));
+        }
+        for &(name, histogram) in &[
+            ("tcp_client_rtt", &self.client_rtts),
+            ("tcp_connect_latency", &self.connect_latencies),
+            ("tcp_write_latency", &self.write_latencies),
+        ] {
+            metrics.push(format!(
+                r#"{{"name":"{}{}","unit":"ms","histogram":{{"aggregationTemporality":{},"dataPoints":[{{"startTimeUnixNano":"{}","timeUnixNano":"{}","count":"{}","sum":{},"bucketCounts":[{}],"explicitBounds":[{}]}}]}}}}"#,
+                METRIC_PREFIX,
+                name,
+                TEMPORALITY_DELTA,
+                self.histogram_start,
+                now,
+                histogram.count,
+                histogram.sum,
+                join(histogram.bucket_counts.iter().map(|count| format!("\"{}\"", count))),
+                join(HISTOGRAM_BOUNDS.iter().map(|bound| bound.to_string()))
+            ));
+        }
+
+        format!(
+            r#"{{"resourceMetrics":[{{"resource":{},"scopeMetrics":[{{"scope":{},"metrics":[{}]}}]}}]}}"#,
+            resource_json(),
+            scope_json(),
+            metrics.join(",")
+        )
+    }
+}
+
+/// Returns the counters of the statistics.
+fn counters(stats: &Stats) -> Vec<(&'static str, u64)> {
+    vec![
+        ("udp_expirations", stats.udp_expirations()),
+        ("udp_reuses", stats.udp_reuses()),
+        ("udp_stall_drops", stats.udp_stall_drops()),
+        ("udp_rate_limits", stats.udp_rate_limits()),
+        ("quic_migrations", stats.quic_migrations()),
+        ("broadcast_drops", stats.broadcast_drops()),
+        ("broadcast_relays", stats.broadcast_relays()),
+        ("broadcast_answers", stats.broadcast_answers()),
+        ("multicast_drops", stats.multicast_drops()),
+        ("multicast_relays", stats.multicast_relays()),
+        ("multicast_reflections", stats.multicast_reflections()),
+        ("tcp_invalid_segments", stats.tcp_invalid_segments()),
+        ("tcp_challenge_acks", stats.tcp_challenge_acks()),
+        ("tcp_refusals", stats.tcp_refusals()),
+        ("tcp_evictions", stats.tcp_evictions()),
+        ("tcp_idle_reaps", stats.tcp_idle_reaps()),
+        ("tcp_syn_drops", stats.tcp_syn_drops()),
+        ("tcp_rate_limits", stats.tcp_rate_limits()),
+        ("tcp_pending_expirations", stats.tcp_pending_expirations()),
+        ("tcp_write_stalls", stats.tcp_write_stalls()),
+        ("tcp_connect_retries", stats.tcp_connect_retries()),
+        ("tcp_out_of_order_bytes", stats.tcp_out_of_order_bytes()),
+        ("tcp_out_of_order_drops", stats.tcp_out_of_order_drops()),
+        ("tcp_retrans_segments", stats.tcp_retrans_segments()),
+        ("tcp_retrans_bytes", stats.tcp_retrans_bytes()),
+        ("tcp_fast_retransmits", stats.tcp_fast_retransmits()),
+        ("tcp_rto_retransmits", stats.tcp_rto_retransmits()),
+        ("tcp_duplicate_acks", stats.tcp_duplicate_acks()),
+        (
+            "connect_auth_failures",
+            stats.connect_failures(ConnectFailure::Auth),
+        ),
+        (
+            "connect_method_failures",
+            stats.connect_failures(ConnectFailure::Method),
+        ),
+        (
+            "connect_reply_failures",
+            stats.connect_failures(ConnectFailure::Reply),
+        ),
+        (
+            "connect_network_failures",
+            stats.connect_failures(ConnectFailure::Network),
+        ),
+        (
+            "connect_other_failures",
+            stats.connect_failures(ConnectFailure::Other),
+        ),
+        ("arp_conflicts", stats.arp_conflicts()),
+        ("quarantines", stats.quarantines()),
+        ("quarantine_drops", stats.quarantine_drops()),
+        ("sni_blocks", stats.sni_blocks()),
+        ("sni_bypasses", stats.sni_bypasses()),
+        ("spoof_drops", stats.spoof_drops()),
+        ("frag_overlaps", stats.frag_overlaps()),
+        ("frag_drops", stats.frag_drops()),
+        ("mtu_probes", stats.mtu_probes()),
+        ("mtu_black_holes", stats.mtu_black_holes()),
+        ("qos_drops", stats.qos_drops()),
+        ("tx_drops", stats.tx_drops()),
+        ("icmp_redirects", stats.icmp_redirects()),
+        ("icmp_source_quenches", stats.icmp_source_quenches()),
+        ("icmp_echo_replies", stats.icmp_echo_replies()),
+        ("tunneled_gre", stats.tunneled(TunnelProtocol::Gre)),
+        ("tunneled_ipsec", stats.tunneled(TunnelProtocol::Ipsec)),
+        ("tunneled_6in4", stats.tunneled(TunnelProtocol::Ipv6)),
+        ("tunneled_forwards", stats.tunneled_forwards()),
+        ("discovery_lldp", stats.discovery(DiscoveryProtocol::Lldp)),
+        ("discovery_cdp", stats.discovery(DiscoveryProtocol::Cdp)),
+        ("discovery_stp", stats.discovery(DiscoveryProtocol::Stp)),
+        ("malformed_ethernet", stats.malformed(LayerKinds::Ethernet)),
+        ("malformed_arp", stats.malformed(LayerKinds::Arp)),
+        ("malformed_ipv4", stats.malformed(LayerKinds::Ipv4)),
+        ("malformed_icmpv4", stats.malformed(LayerKinds::Icmpv4)),
+        ("malformed_tcp", stats.malformed(LayerKinds::Tcp)),
+        ("malformed_udp", stats.malformed(LayerKinds::Udp)),
+        ("dispatch_drops", stats.dispatch_drops()),
+        ("rx_bytes", stats.rx_bytes()),
+        ("tx_bytes", stats.tx_bytes()),
+    ]
+}
+
+fn traces_json(spans: &[Span]) -> String {
+    let mut rng = rand::thread_rng();
+    let spans: Vec<_> = spans
+        .iter()
+        .map(|span| {
+            let connection = &span.connection;
+            let mut attributes = vec![
+                string_attribute("net.peer.name", &connection.src.to_string()),
+                string_attribute("net.host.name", &connection.dst.to_string()),
+            ];
+            for &(key, value) in &[
+                ("pcap2socks.written", Some(connection.written)),
+                (
+                    "pcap2socks.retrans_segments",
+                    Some(connection.retrans_segments),
+                ),
+                ("pcap2socks.retrans_bytes", Some(connection.retrans_bytes)),
+                ("pcap2socks.duplicate_acks", Some(connection.duplicate_acks)),
+                (
+                    "pcap2socks.out_of_order_drops",
+                    Some(connection.out_of_order_drops),
+                ),
+                (
+                    "pcap2socks.client_rtt_us",
+                    connection.client_rtt.map(micros),
+                ),
+                (
+                    "pcap2socks.connect_latency_us",
+                    connection.connect_latency.map(micros),
+                ),
+                (
+                    "pcap2socks.write_latency_us",
+                    connection.write_latency.map(micros),
+                ),
+            ] {
+                if let Some(value) = value {
+                    attributes.push(format!(
+                        r#"{{"key":"{}","value":{{"intValue":"{}"}}}}"#,
+                        key, value
+                    ));
+                }
+            }
+
+            format!(
+                r#"{{"traceId":"{}","spanId":"{}","name":"tcp {}","kind":{},"startTimeUnixNano":"{}","endTimeUnixNano":"{}","attributes":[{}]}}"#,
+                hex(&rng.gen::<[u8; 16]>()),
+                hex(&rng.gen::<[u8; 8]>()),
+                connection.dst,
+                SPAN_KIND_SERVER,
+                span.start,
+                span.end,
+                attributes.join(",")
+            )
+        })
+        .collect();
+
+    format!(
+        r#"{{"resourceSpans":[{{"resource":{},"scopeSpans":[{{"scope":{},"spans":[{}]}}]}}]}}"#,
+        resource_json(),
+        scope_json(),
+        spans.join(",")
+    )
+}
+
+fn resource_json() -> String {
+    format!(
+        r#"{{"attributes":[{}]}}"#,
+        string_attribute("service.name", env!("CARGO_PKG_NAME"))
+    )
+}
+
+fn scope_json() -> String {
+    format!(
+        r#"{{"name":"{}","version":"{}"}}"#,
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION")
+    )
+}
+
+fn string_attribute(key: &str, value: &str) -> String {
+    format!(
+        r#"{{"key":"{}","value":{{"stringValue":"{}"}}}}"#,
+        escape(key),
+        escape(value)
+    )
+}
+
+/// Escapes a string in JSON.
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(escaped, "\\u{:04x}", c as u32);
+            }
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+fn join<I: Iterator<Item = String>>(iter: I) -> String {
+    iter.collect::<Vec<_>>().join(",")
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn micros(duration: Duration) -> u64 {
+    duration.as_micros() as u64
+}
+
+fn unix_nanos() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_nanos())
+        .unwrap_or(0)
+}
+
+/// Posts the JSON body to the path of the collector in HTTP/1.1.
+async fn post(collector: SocketAddr, path: &str, body: &str) -> io::Result<()> {
+    let request = async {
+        let mut stream = TcpStream::connect(collector).await?;
+        let header = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            path,
+            collector,
+            body.len()
+        );
+        stream.write_all(header.as_bytes()).await?;
+        stream.write_all(body.as_bytes()).await?;
+
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+
+        Ok::<_, io::Error>(response)
+    };
+    let response = time::timeout(Duration::from_millis(EXPORT_TIMEOUT), request)
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+
+    // HTTP/1.1 2xx
+    let status = response
+        .split(|&b| b == b'\r' || b == b'\n')
+        .next()
+        .and_then(|line| std::str::from_utf8(line).ok())
+        .unwrap_or("");
+    match status.split(' ').nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("unexpected response {:?}", status),
+        )),
+    }
+}
+
+#[test]
+fn exporter_update_connections() {
+    let mut exporter = Exporter::new("127.0.0.1:4318".parse().unwrap());
+    let connection = Connection {
+        src: "10.6.0.2:50000".parse().unwrap(),
+        dst: "1.1.1.1:443".parse().unwrap(),
+        idle: Duration::from_millis(0),
+        written: 100,
+        write_queue_size: 0,
+        out_of_order: 0,
+        holes: 0,
+        out_of_order_drops: 0,
+        retrans_segments: 0,
+        retrans_bytes: 0,
+        fast_retransmits: 0,
+        rto_retransmits: 0,
+        duplicate_acks: 0,
+        client_rtt: Some(Duration::from_millis(30)),
+        connect_latency: Some(Duration::from_millis(200)),
+        write_latency: None,
+    };
+
+    assert!(exporter
+        .update_connections(vec![connection.clone()], 1)
+        .is_empty());
+    let spans = exporter.update_connections(Vec::new(), 2);
+    assert_eq!(spans.len(), 1);
+    assert_eq!((spans[0].start, spans[0].end), (1, 2));
+    assert_eq!(exporter.client_rtts.bucket_counts[4], 1);
+    assert_eq!(exporter.connect_latencies.bucket_counts[6], 1);
+    assert_eq!(exporter.write_latencies.count, 0);
+
+    let traces = traces_json(&spans);
+    assert!(traces.contains(r#""stringValue":"10.6.0.2:50000""#));
+    assert!(traces.contains(r#""key":"pcap2socks.client_rtt_us","value":{"intValue":"30000"}"#));
+    assert_eq!(escape("a\"b\\c\n"), "a\\\"b\\\\c\\u000a");
+}
@@ -0,0 +1,291 @@
+//! Support for inspecting link-layer discovery frames, like LLDP, CDP and STP.
+
+use std::fmt::{self, Display, Formatter};
+
+/// Represents the EtherType of LLDP.
+const ETHERTYPE_LLDP: u16 = 0x88cc;
+/// Represents the max value of the length field of an IEEE 802.3 frame, above which the field is
+/// an EtherType.
+const MAX_8023_LENGTH: u16 = 1500;
+
+/// Represents the LLC header of SNAP.
+const LLC_SNAP: [u8; 3] = [0xaa, 0xaa, 0x03];
+/// Represents the LLC header of STP.
+const LLC_STP: [u8; 3] = [0x42, 0x42, 0x03];
+/// Represents the SNAP OUI of Cisco.
+const SNAP_OUI_CISCO: [u8; 3] = [0x00, 0x00, 0x0c];
+/// Represents the SNAP protocol ID of CDP.
+const SNAP_PID_CDP: u16 = 0x2000;
+
+/// Represents the LLDP TLV type of chassis ID.
+const LLDP_CHASSIS_ID: u8 = 1;
+/// Represents the LLDP TLV type of port ID.
+const LLDP_PORT_ID: u8 = 2;
+/// Represents the LLDP TLV type of system name.
+const LLDP_SYSTEM_NAME: u8 = 5;
+/// Represents the LLDP chassis ID or port ID subtype of MAC address.
+const LLDP_SUBTYPE_MAC_ADDR: u8 = 3;
+/// Represents the LLDP chassis ID subtype of MAC address, which differs from the port ID one.
+const LLDP_CHASSIS_SUBTYPE_MAC_ADDR: u8 = 4;
+
+/// Represents the CDP TLV type of device ID.
+const CDP_DEVICE_ID: u16 = 1;
+/// Represents the CDP TLV type of port ID.
+const CDP_PORT_ID: u16 = 3;
+/// Represents the CDP TLV type of platform.
+const CDP_PLATFORM: u16 = 6;
+
+/// Represents the BPDU type of topology change notification.
+const BPDU_TCN: u8 = 0x80;
+
+/// Represents the protocol of a link-layer discovery frame.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum DiscoveryProtocol {
+    /// Represents Link Layer Discovery Protocol.
+    Lldp,
+    /// Represents Cisco Discovery Protocol.
+    Cdp,
+    /// Represents Spanning Tree Protocol, including RSTP and MSTP.
+    Stp,
+}
+
+impl Display for DiscoveryProtocol {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            DiscoveryProtocol::Lldp => write!(f, "LLDP"),
+            DiscoveryProtocol::Cdp => write!(f, "CDP"),
+            DiscoveryProtocol::Stp => write!(f, "STP"),
+        }
+    }
+}
+
+/// Represents a link-layer discovery frame, which is sent by switches and other devices on the
+/// network and never redirected.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct Discovery {
+    protocol: DiscoveryProtocol,
+    summary: Vec<(&'static str, String)>,
+}
+
+impl Discovery {
+    /// Creates a `Discovery` according to the given EtherType, or the length of an IEEE 802.3
+    /// frame, and the payload of an Ethernet packet. Returns `None` if the frame is not a
+    /// link-layer discovery frame. Malformed fields are left out of the summary.
+    pub fn parse(ethertype: u16, payload: &[u8]) -> Option<Discovery> {
+        if ethertype == ETHERTYPE_LLDP {
+            return Some(Discovery {
+                protocol: DiscoveryProtocol::Lldp,
+                summary: parse_lldp(payload),
+            });
+        }
+        if ethertype > MAX_8023_LENGTH {
+            return None;
+        }
+
+        let payload = &payload[..payload.len().min(ethertype as usize)];
+        if payload.len() >= 8
+            && payload[..3] == LLC_SNAP
+            && payload[3..6] == SNAP_OUI_CISCO
+            && u16::from_be_bytes([payload[6], payload[7]]) == SNAP_PID_CDP
+        {
+            return Some(Discovery {
+                protocol: DiscoveryProtocol::Cdp,
+                summary: parse_cdp(&payload[8..]),
+            });
+        }
+        if payload.len() >= 3 && payload[..3] == LLC_STP {
+            return Some(Discovery {
+                protocol: DiscoveryProtocol::Stp,
+                summary: parse_bpdu(&payload[3..]),
+            });
+        }
+
+        None
+    }
+
+    /// Returns the protocol of the frame.
+    pub fn protocol(&self) -> DiscoveryProtocol {
+        self.protocol
+    }
+
+    /// Returns the summary of the frame in pairs of the field name and the value.
+    pub fn summary(&self) -> &[(&'static str, String)] {
+        &self.summary
+    }
+}
+
+impl Display for Discovery {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}", self.protocol)?;
+        for (i, (name, value)) in self.summary.iter().enumerate() {
+            let sep = if i == 0 { ":" } else { "," };
+            write!(f, "{} {} = {}", sep, name, value)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn parse_lldp(payload: &[u8]) -> Vec<(&'static str, String)> {
+    let mut summary = Vec::new();
+
+    let mut i = 0;
+    while i + 2 <= payload.len() {
+        let header = u16::from_be_bytes([payload[i], payload[i + 1]]);
+        let t = (header >> 9) as u8;
+        let length = (header & 0x1ff) as usize;
+        if t == 0 || i + 2 + length > payload.len() {
+            break;
+        }
+        let value = &payload[i + 2..i + 2 + length];
+        match t {
+            LLDP_CHASSIS_ID if !value.is_empty() => {
+                let is_mac_addr = value[0] == LLDP_CHASSIS_SUBTYPE_MAC_ADDR;
+                summary.push(("Chassis", format_id(&value[1..], is_mac_addr)));
+            }
+            LLDP_PORT_ID if !value.is_empty() => {
+                let is_mac_addr = value[0] == LLDP_SUBTYPE_MAC_ADDR;
+                summary.push(("Port", format_id(&value[1..], is_mac_addr)));
+            }
+            LLDP_SYSTEM_NAME => summary.push(("System", format_id(value, false))),
+            _ => {}
+        }
+        i += 2 + length;
+    }
+
+    summary
+}
+
+fn parse_cdp(payload: &[u8]) -> Vec<(&'static str, String)> {
+    let mut summary = Vec::new();
+
+    // Skip the version, the TTL and the checksum
+    let mut i = 4;
+    while i + 4 <= payload.len() {
+        let t = u16::from_be_bytes([payload[i], payload[i + 1]]);
+        let length = u16::from_be_bytes([payload[i + 2], payload[i + 3]]) as usize;
+        if length < 4 || i + length > payload.len() {
+            break;
+        }
+        let value = &payload[i + 4..i + length];
+        match t {
+            CDP_DEVICE_ID => summary.push(("Device", format_id(value, false))),
+            CDP_PORT_ID => summary.push(("Port", format_id(value, false))),
+            CDP_PLATFORM => summary.push(("Platform", format_id(value, false))),
+            _ => {}
+        }
+        i += length;
+    }
+
+    summary
+}
+
+fn parse_bpdu(payload: &[u8]) -> Vec<(&'static str, String)> {
+    let mut summary = Vec::new();
+    if payload.len() < 4 {
+        return summary;
+    }
+
+    let version = match payload[2] {
+        0 => "STP",
+        2 => "RSTP",
+        3 => "MSTP",
+        _ => "unknown",
+    };
+    summary.push(("Version", version.to_string()));
+    if payload[3] == BPDU_TCN {
+        summary.push(("Type", "topology change notification".to_string()));
+        return summary;
+    }
+
+    // Configuration and RST BPDUs
+    if payload.len() >= 27 {
+        summary.push(("Root", format_bridge_id(&payload[5..13])));
+        let cost = u32::from_be_bytes([payload[13], payload[14], payload[15], payload[16]]);
+        summary.push(("Cost", cost.to_string()));
+        summary.push(("Bridge", format_bridge_id(&payload[17..25])));
+        let port = u16::from_be_bytes([payload[25], payload[26]]);
+        summary.push(("Port", format!("{:#06x}", port)));
+    }
+
+    summary
+}
+
+fn format_id(value: &[u8], is_mac_addr: bool) -> String {
+    if is_mac_addr && value.len() == 6 {
+        return format_mac_addr(value);
+    }
+    match std::str::from_utf8(value) {
+        Ok(s) if s.chars().all(|c| !c.is_control()) => s.to_string(),
+        _ => value
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<Vec<_>>()
+            .join(""),
+    }
+}
+
+fn format_mac_addr(value: &[u8]) -> String {
+    value
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+fn format_bridge_id(value: &[u8]) -> String {
+    let priority = u16::from_be_bytes([value[0], value[1]]);
+
+    format!("{}/{}", priority, format_mac_addr(&value[2..8]))
+}
+
+#[test]
+fn discovery_parse() {
+    // LLDP with a chassis ID of MAC address, a port ID of interface name and a system name
+    let lldp = [
+        0x02, 0x07, 0x04, 0x00, 0x11, 0x22, 0x33, 0x44, 0x55, // Chassis ID
+        0x04, 0x04, 0x05, b'g', b'e', b'0', // Port ID
+        0x06, 0x02, 0x00, 0x78, // TTL
+        0x0a, 0x02, b's', b'w', // System name
+        0x00, 0x00, // End
+    ];
+    let discovery = Discovery::parse(ETHERTYPE_LLDP, &lldp).unwrap();
+    assert_eq!(discovery.protocol(), DiscoveryProtocol::Lldp);
+    assert_eq!(
+        discovery.to_string(),
+        "LLDP: Chassis = 00:11:22:33:44:55, Port = ge0, System = sw"
+    );
+
+    // CDP with a device ID and a truncated TLV
+    let mut cdp = vec![0xaa, 0xaa, 0x03, 0x00, 0x00, 0x0c, 0x20, 0x00];
+    cdp.extend_from_slice(&[0x02, 0xb4, 0x00, 0x00]);
+    cdp.extend_from_slice(&[0x00, 0x01, 0x00, 0x06, b's', b'w']);
+    cdp.extend_from_slice(&[0x00, 0x03, 0x00, 0x10, b'g']);
+    let discovery = Discovery::parse(cdp.len() as u16, &cdp).unwrap();
+    assert_eq!(discovery.to_string(), "CDP: Device = sw");
+
+    // STP configuration BPDU with padding
+    let mut bpdu = vec![0x42, 0x42, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00];
+    bpdu.extend_from_slice(&[0x80, 0x00, 0x00, 0x11, 0x22, 0x33, 0x44, 0x55]);
+    bpdu.extend_from_slice(&[0x00, 0x00, 0x00, 0x04]);
+    bpdu.extend_from_slice(&[0x80, 0x00, 0x00, 0x11, 0x22, 0x33, 0x44, 0x66]);
+    bpdu.extend_from_slice(&[0x80, 0x01, 0x00, 0x00, 0x14, 0x00, 0x02, 0x00, 0x0f, 0x00]);
+    let length = bpdu.len() as u16;
+    bpdu.resize(46, 0);
+    let discovery = Discovery::parse(length, &bpdu).unwrap();
+    assert_eq!(discovery.protocol(), DiscoveryProtocol::Stp);
+    assert_eq!(
+        discovery.to_string(),
+        "STP: Version = STP, Root = 32768/00:11:22:33:44:55, Cost = 4, Bridge = 32768/00:11:22:33:44:66, Port = 0x8001"
+    );
+    let tcn = [0x42, 0x42, 0x03, 0x00, 0x00, 0x00, 0x80];
+    assert_eq!(
+        Discovery::parse(7, &tcn).unwrap().to_string(),
+        "STP: Version = STP, Type = topology change notification"
+    );
+
+    // Others
+    assert!(Discovery::parse(0x0800, &lldp).is_none());
+    assert!(Discovery::parse(8, &[0xaa, 0xaa, 0x03, 0x00, 0x00, 0x00, 0x08, 0x00]).is_none());
+    assert!(Discovery::parse(0, &[]).is_none());
+}
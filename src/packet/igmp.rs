@@ -0,0 +1,131 @@
+//! Support for inspecting IGMP packets.
+
+use std::net::Ipv4Addr;
+
+/// Represents the type of IGMPv1 membership report.
+const TYPE_V1_MEMBERSHIP_REPORT: u8 = 0x12;
+/// Represents the type of IGMPv2 membership report.
+const TYPE_V2_MEMBERSHIP_REPORT: u8 = 0x16;
+/// Represents the type of IGMPv2 leave group.
+const TYPE_V2_LEAVE_GROUP: u8 = 0x17;
+/// Represents the type of IGMPv3 membership report.
+const TYPE_V3_MEMBERSHIP_REPORT: u8 = 0x22;
+
+/// Represents the record type of IGMPv3 `MODE_IS_INCLUDE`.
+const RECORD_MODE_IS_INCLUDE: u8 = 1;
+/// Represents the record type of IGMPv3 `MODE_IS_EXCLUDE`.
+const RECORD_MODE_IS_EXCLUDE: u8 = 2;
+/// Represents the record type of IGMPv3 `CHANGE_TO_INCLUDE_MODE`.
+const RECORD_CHANGE_TO_INCLUDE_MODE: u8 = 3;
+/// Represents the record type of IGMPv3 `CHANGE_TO_EXCLUDE_MODE`.
+const RECORD_CHANGE_TO_EXCLUDE_MODE: u8 = 4;
+/// Represents the record type of IGMPv3 `ALLOW_NEW_SOURCES`.
+const RECORD_ALLOW_NEW_SOURCES: u8 = 5;
+
+/// Represents a change of the multicast group membership reported in an IGMP packet
+/// ([RFC 2236](https://tools.ietf.org/html/rfc2236), [RFC 3376](https://tools.ietf.org/html/rfc3376)).
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum IgmpMembership {
+    /// Represents the host joins the group.
+    Join(Ipv4Addr),
+    /// Represents the host leaves the group.
+    Leave(Ipv4Addr),
+}
+
+impl IgmpMembership {
+    /// Parses the membership changes from the IPv4 payload of an IGMP packet. Queries and
+    /// malformed packets give no changes.
+    pub fn parse(payload: &[u8]) -> Vec<IgmpMembership> {
+        let mut memberships = Vec::new();
+        if payload.len() < 8 {
+            return memberships;
+        }
+
+        match payload[0] {
+            TYPE_V1_MEMBERSHIP_REPORT | TYPE_V2_MEMBERSHIP_REPORT => {
+                memberships.push(IgmpMembership::Join(group(&payload[4..8])));
+            }
+            TYPE_V2_LEAVE_GROUP => {
+                memberships.push(IgmpMembership::Leave(group(&payload[4..8])));
+            }
+            TYPE_V3_MEMBERSHIP_REPORT => {
+                let records = u16::from_be_bytes([payload[6], payload[7]]) as usize;
+
+                let mut n = 8;
+                for _ in 0..records {
+                    if payload.len() < n + 8 {
+                        break;
+                    }
+                    let record_type = payload[n];
+                    let aux_len = payload[n + 1] as usize * 4;
+                    let sources = u16::from_be_bytes([payload[n + 2], payload[n + 3]]) as usize;
+                    let group = group(&payload[n + 4..n + 8]);
+
+                    match record_type {
+                        RECORD_MODE_IS_EXCLUDE | RECORD_CHANGE_TO_EXCLUDE_MODE => {
+                            memberships.push(IgmpMembership::Join(group))
+                        }
+                        RECORD_MODE_IS_INCLUDE
+                        | RECORD_CHANGE_TO_INCLUDE_MODE
+                        | RECORD_ALLOW_NEW_SOURCES => {
+                            if sources > 0 {
+                                memberships.push(IgmpMembership::Join(group));
+                            } else if record_type == RECORD_CHANGE_TO_INCLUDE_MODE {
+                                memberships.push(IgmpMembership::Leave(group));
+                            }
+                        }
+                        _ => {}
+                    }
+
+                    n = n + 8 + sources * 4 + aux_len;
+                }
+            }
+            _ => {}
+        }
+
+        memberships
+    }
+}
+
+fn group(bytes: &[u8]) -> Ipv4Addr {
+    Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3])
+}
+
+#[test]
+fn igmp_parse_v2() {
+    let report = [0x16, 0x00, 0x00, 0x00, 224, 0, 0, 251];
+    let leave = [0x17, 0x00, 0x00, 0x00, 239, 255, 255, 250];
+
+    assert_eq!(
+        IgmpMembership::parse(&report),
+        vec![IgmpMembership::Join(Ipv4Addr::new(224, 0, 0, 251))]
+    );
+    assert_eq!(
+        IgmpMembership::parse(&leave),
+        vec![IgmpMembership::Leave(Ipv4Addr::new(239, 255, 255, 250))]
+    );
+}
+
+#[test]
+fn igmp_parse_v3() {
+    let report = [
+        0x22, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, // Header
+        0x04, 0x00, 0x00, 0x00, 224, 0, 0, 251, // CHANGE_TO_EXCLUDE_MODE
+        0x03, 0x00, 0x00, 0x00, 239, 255, 255, 250, // CHANGE_TO_INCLUDE_MODE
+    ];
+
+    assert_eq!(
+        IgmpMembership::parse(&report),
+        vec![
+            IgmpMembership::Join(Ipv4Addr::new(224, 0, 0, 251)),
+            IgmpMembership::Leave(Ipv4Addr::new(239, 255, 255, 250))
+        ]
+    );
+}
+
+#[test]
+fn igmp_parse_query() {
+    let query = [0x11, 0x64, 0x00, 0x00, 0, 0, 0, 0];
+
+    assert!(IgmpMembership::parse(&query).is_empty());
+}
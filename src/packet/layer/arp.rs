@@ -38,6 +38,24 @@ impl Arp {
         Arp::from(arp)
     }
 
+    /// Creates a `Arp` represents an ARP probe, which asks if the address is owned by any device
+    /// without claiming it ([RFC 5227](https://tools.ietf.org/html/rfc5227)).
+    pub fn new_probe(src_hardware_addr: MacAddr, dst_ip_addr: Ipv4Addr) -> Arp {
+        let arp = arp::Arp {
+            hardware_type: ArpHardwareTypes::Ethernet,
+            protocol_type: EtherTypes::Ipv4,
+            hw_addr_len: 6,
+            proto_addr_len: 4,
+            operation: ArpOperations::Request,
+            sender_hw_addr: src_hardware_addr,
+            sender_proto_addr: Ipv4Addr::UNSPECIFIED,
+            target_hw_addr: MacAddr::zero(),
+            target_proto_addr: dst_ip_addr,
+            payload: vec![],
+        };
+        Arp::from(arp)
+    }
+
     /// Creates an `Arp` according to the given `Arp`.
     pub fn from(arp: arp::Arp) -> Arp {
         Arp { layer: arp }
@@ -1,16 +1,73 @@
 //! Support for serializing and deserializing the Ethernet layer.
 
 use super::{Layer, LayerKind, LayerKinds};
-use pnet::packet::ethernet::{self, EtherTypes, EthernetPacket, MutableEthernetPacket};
+use pnet::packet::ethernet::{self, EtherType, EtherTypes, EthernetPacket, MutableEthernetPacket};
+use pnet::packet::{MutablePacket, Packet};
 use pnet::util::MacAddr;
 use std::clone::Clone;
 use std::fmt::{self, Display, Formatter};
 use std::io;
 
-/// Represents an Ethernet layer.
+/// Represents the length of a VLAN tag.
+const VLAN_TAG_LEN: usize = 4;
+/// Represents the TPID of an IEEE 802.1ad service VLAN tag.
+const TPID_SERVICE: u16 = 0x88a8;
+/// Represents the TPID of an IEEE 802.1Q customer VLAN tag.
+const TPID_CUSTOMER: u16 = 0x8100;
+/// Represents the TPID of a service VLAN tag used by legacy QinQ devices.
+const TPID_SERVICE_LEGACY: u16 = 0x9100;
+
+/// Represents a VLAN tag.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct VlanTag {
+    tpid: u16,
+    tci: u16,
+}
+
+impl VlanTag {
+    /// Creates a `VlanTag`.
+    pub fn new(tpid: u16, tci: u16) -> VlanTag {
+        VlanTag { tpid, tci }
+    }
+
+    /// Returns the TPID of the tag.
+    pub fn tpid(&self) -> u16 {
+        self.tpid
+    }
+
+    /// Returns the TCI of the tag, including the priority, the DEI and the VLAN ID.
+    pub fn tci(&self) -> u16 {
+        self.tci
+    }
+
+    /// Returns the VLAN ID of the tag.
+    pub fn vid(&self) -> u16 {
+        self.tci & 0x0fff
+    }
+}
+
+impl Display for VlanTag {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self.tpid {
+            TPID_CUSTOMER => write!(f, "{}", self.vid()),
+            _ => write!(f, "{}/{:#06x}", self.vid(), self.tpid),
+        }
+    }
+}
+
+fn is_vlan_tpid(ethertype: EtherType) -> bool {
+    matches!(
+        ethertype.0,
+        TPID_SERVICE | TPID_CUSTOMER | TPID_SERVICE_LEGACY
+    )
+}
+
+/// Represents an Ethernet layer, which includes the VLAN tags stacked in it, like an IEEE
+/// 802.1ad service tag followed by an IEEE 802.1Q customer tag.
 #[derive(Clone, Debug)]
 pub struct Ethernet {
     pub layer: ethernet::Ethernet,
+    tags: Vec<VlanTag>,
 }
 
 impl Ethernet {
@@ -19,6 +76,7 @@ impl Ethernet {
         let ethertype = match t {
             LayerKinds::Arp => EtherTypes::Arp,
             LayerKinds::Ipv4 => EtherTypes::Ipv4,
+            LayerKinds::Pppoe => EtherTypes::PppoeSession,
             _ => return None,
         };
         let ethernet = ethernet::Ethernet {
@@ -32,18 +90,57 @@ impl Ethernet {
 
     /// Creates an `Ethernet` according to the given `Ethernet`.
     pub fn from(ethernet: ethernet::Ethernet) -> Ethernet {
-        Ethernet { layer: ethernet }
+        Ethernet {
+            layer: ethernet,
+            tags: vec![],
+        }
     }
 
-    /// Creates an `Ethernet` according to the given Ethernet packet.
-    pub fn parse(packet: &EthernetPacket) -> Ethernet {
+    /// Creates an `Ethernet` according to the given Ethernet packet. VLAN tags are parsed into
+    /// the layer, whose EtherType is the one following the tags. Returns `None` if the tags are
+    /// truncated.
+    pub fn parse(packet: &EthernetPacket) -> Option<Ethernet> {
+        let mut tags = Vec::new();
+        let mut ethertype = packet.get_ethertype();
+        let payload = packet.payload();
+        while is_vlan_tpid(ethertype) {
+            let i = tags.len() * VLAN_TAG_LEN;
+            if payload.len() < i + VLAN_TAG_LEN {
+                return None;
+            }
+            let tci = u16::from_be_bytes([payload[i], payload[i + 1]]);
+            tags.push(VlanTag::new(ethertype.0, tci));
+            ethertype = EtherType::new(u16::from_be_bytes([payload[i + 2], payload[i + 3]]));
+        }
+
         let ethernet = ethernet::Ethernet {
             destination: packet.get_destination(),
             source: packet.get_source(),
-            ethertype: packet.get_ethertype(),
+            ethertype,
             payload: vec![],
         };
-        Ethernet::from(ethernet)
+        Some(Ethernet::from(ethernet).with_tags(tags))
+    }
+
+    /// Sets the VLAN tags of the layer, from the outermost to the innermost.
+    pub fn with_tags(mut self, tags: Vec<VlanTag>) -> Ethernet {
+        self.tags = tags;
+        self
+    }
+
+    /// Returns the VLAN tags of the layer, from the outermost to the innermost.
+    pub fn tags(&self) -> &[VlanTag] {
+        &self.tags
+    }
+
+    /// Returns the length of the VLAN tags of the layer.
+    pub fn tags_len(&self) -> usize {
+        self.tags.len() * VLAN_TAG_LEN
+    }
+
+    /// Returns the EtherType of the layer following the VLAN tags.
+    pub fn ethertype(&self) -> EtherType {
+        self.layer.ethertype
     }
 
     /// Returns the source of the layer.
@@ -65,7 +162,18 @@ impl Display for Ethernet {
             LayerKinds::Ethernet,
             self.layer.source,
             self.layer.destination
-        )
+        )?;
+        if !self.tags.is_empty() {
+            let tags = self
+                .tags
+                .iter()
+                .map(|tag| tag.to_string())
+                .collect::<Vec<_>>()
+                .join(", ");
+            write!(f, ", VLAN = [{}]", tags)?;
+        }
+
+        Ok(())
     }
 }
 
@@ -75,15 +183,33 @@ impl Layer for Ethernet {
     }
 
     fn len(&self) -> usize {
-        EthernetPacket::packet_size(&self.layer)
+        EthernetPacket::packet_size(&self.layer) + self.tags_len()
     }
 
     fn serialize(&self, buffer: &mut [u8], _: usize) -> io::Result<usize> {
+        if buffer.len() < self.len() {
+            return Err(io::Error::new(io::ErrorKind::WriteZero, "buffer too small"));
+        }
         let mut packet = MutableEthernetPacket::new(buffer)
             .ok_or(io::Error::new(io::ErrorKind::WriteZero, "buffer too small"))?;
 
         packet.populate(&self.layer);
 
+        // VLAN tags, which shift the EtherType of the layer after them
+        if let Some(tag) = self.tags.first() {
+            packet.set_ethertype(EtherType::new(tag.tpid()));
+            let payload = packet.payload_mut();
+            for (i, tag) in self.tags.iter().enumerate() {
+                let next = match self.tags.get(i + 1) {
+                    Some(next) => next.tpid(),
+                    None => self.layer.ethertype.0,
+                };
+                let i = i * VLAN_TAG_LEN;
+                payload[i..i + 2].copy_from_slice(&tag.tci().to_be_bytes());
+                payload[i + 2..i + 4].copy_from_slice(&next.to_be_bytes());
+            }
+        }
+
         Ok(self.len())
     }
 
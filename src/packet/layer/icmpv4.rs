@@ -4,6 +4,7 @@ use super::{Layer, LayerKind, LayerKinds};
 use pnet::packet::icmp::destination_unreachable;
 use pnet::packet::icmp::echo_reply;
 use pnet::packet::icmp::echo_request;
+use pnet::packet::icmp::time_exceeded;
 use pnet::packet::icmp::{self, Icmp, IcmpPacket, IcmpTypes, MutableIcmpPacket};
 use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
 use pnet::packet::ipv4::Ipv4Packet;
@@ -41,6 +42,33 @@ impl Icmpv4 {
         Icmpv4::from(icmp)
     }
 
+    /// Creates a `Icmpv4` represents an ICMPv4 echo request with the data.
+    pub fn new_echo_request(identifier: u16, sequence_number: u16, data: &[u8]) -> Icmpv4 {
+        let mut payload = vec![0u8; 4 + data.len()];
+        payload[..2].copy_from_slice(&identifier.to_ne_bytes());
+        payload[2..4].copy_from_slice(&sequence_number.to_ne_bytes());
+        payload[4..].copy_from_slice(data);
+        let icmp = Icmp {
+            icmp_type: IcmpTypes::EchoRequest,
+            icmp_code: echo_request::IcmpCodes::NoCode,
+            checksum: 0,
+            payload,
+        };
+        Icmpv4::from(icmp)
+    }
+
+    /// Creates a `Icmpv4` represents an ICMPv4 echo reply to the echo request, which carries the
+    /// identifier, the sequence number and the data of the request.
+    pub fn new_echo_reply_to(request: &Icmpv4) -> Icmpv4 {
+        let icmp = Icmp {
+            icmp_type: IcmpTypes::EchoReply,
+            icmp_code: echo_reply::IcmpCodes::NoCode,
+            checksum: 0,
+            payload: request.layer.payload.clone(),
+        };
+        Icmpv4::from(icmp)
+    }
+
     /// Creates a `Icmpv4` represents an ICMPv4 destination port unreachable.
     pub fn new_destination_port_unreachable(payload: &[u8]) -> Icmpv4 {
         let mut next_payload = vec![0u8; 4 + payload.len()];
@@ -54,6 +82,20 @@ impl Icmpv4 {
         Icmpv4::from(icmp)
     }
 
+    /// Creates a `Icmpv4` represents an ICMPv4 time to live exceeded in transit. The payload
+    /// should be the IPv4 header and the first 8 bytes of the data of the original datagram.
+    pub fn new_time_exceeded(payload: &[u8]) -> Icmpv4 {
+        let mut next_payload = vec![0u8; 4 + payload.len()];
+        next_payload[4..].copy_from_slice(payload);
+        let icmp = Icmp {
+            icmp_type: IcmpTypes::TimeExceeded,
+            icmp_code: time_exceeded::IcmpCodes::TimeToLiveExceededInTransit,
+            checksum: 0,
+            payload: next_payload,
+        };
+        Icmpv4::from(icmp)
+    }
+
     /// Creates an `Icmpv4` according to the given `Icmp`.
     pub fn from(icmp: Icmp) -> Icmpv4 {
         Icmpv4 { layer: icmp }
@@ -76,6 +118,12 @@ impl Icmpv4 {
             String::from("Fragmentation required, and DF flag set")
         } else if self.is_echo_request() {
             String::from("Echo request")
+        } else if self.is_time_exceeded() {
+            String::from("Time to live exceeded in transit")
+        } else if self.is_redirect() {
+            String::from("Redirect")
+        } else if self.is_source_quench() {
+            String::from("Source quench")
         } else {
             format!(
                 "Type = {}, Code = {}",
@@ -86,7 +134,7 @@ impl Icmpv4 {
 
     /// Returns the identifier (NE) of the layer.
     pub fn identifier(&self) -> Option<u16> {
-        if self.is_echo_reply() || self.is_echo_request() {
+        if (self.is_echo_reply() || self.is_echo_request()) && self.layer.payload.len() >= 4 {
             let buffer = [self.layer.payload[0], self.layer.payload[1]];
             Some(u16::from_ne_bytes(buffer))
         } else {
@@ -96,7 +144,7 @@ impl Icmpv4 {
 
     /// Returns the sequence number (NE) of the layer.
     pub fn sequence_number(&self) -> Option<u16> {
-        if self.is_echo_reply() || self.is_echo_request() {
+        if (self.is_echo_reply() || self.is_echo_request()) && self.layer.payload.len() >= 4 {
             let buffer = [self.layer.payload[2], self.layer.payload[3]];
             Some(u16::from_ne_bytes(buffer))
         } else {
@@ -106,7 +154,7 @@ impl Icmpv4 {
 
     /// Returns the next-hop MTU of the layer.
     pub fn next_hop_mtu(&self) -> Option<u16> {
-        if self.is_fragmentation_required_and_df_flag_set() {
+        if self.is_fragmentation_required_and_df_flag_set() && self.layer.payload.len() >= 4 {
             let buffer = [self.layer.payload[2], self.layer.payload[3]];
             Some(u16::from_be_bytes(buffer))
         } else {
@@ -114,11 +162,21 @@ impl Icmpv4 {
         }
     }
 
+    /// Returns the gateway IP address of the layer.
+    pub fn gateway_ip_addr(&self) -> Option<Ipv4Addr> {
+        if self.is_redirect() && self.layer.payload.len() >= 4 {
+            let payload = &self.layer.payload;
+            Some(Ipv4Addr::new(
+                payload[0], payload[1], payload[2], payload[3],
+            ))
+        } else {
+            None
+        }
+    }
+
     /// Returns the source IP address in the payload of the layer.
     pub fn src_ip_addr(&self) -> Option<Ipv4Addr> {
-        if self.is_destination_port_unreachable()
-            || self.is_fragmentation_required_and_df_flag_set()
-        {
+        if self.has_datagram() {
             let (ipv4, _) = self.parse_payload().unwrap();
             Some(ipv4.src())
         } else {
@@ -128,9 +186,7 @@ impl Icmpv4 {
 
     /// Returns the destination IP address in the payload of the layer.
     pub fn dst_ip_addr(&self) -> Option<Ipv4Addr> {
-        if self.is_destination_port_unreachable()
-            || self.is_fragmentation_required_and_df_flag_set()
-        {
+        if self.has_datagram() {
             let (ipv4, _) = self.parse_payload().unwrap();
             Some(ipv4.dst())
         } else {
@@ -140,9 +196,7 @@ impl Icmpv4 {
 
     /// Returns the next level protocol in the payload of the layer.
     pub fn next_level_protocol(&self) -> Option<IpNextHeaderProtocol> {
-        if self.is_destination_port_unreachable()
-            || self.is_fragmentation_required_and_df_flag_set()
-        {
+        if self.has_datagram() {
             let (ipv4, _) = self.parse_payload().unwrap();
             Some(ipv4.next_level_protocol())
         } else {
@@ -152,9 +206,7 @@ impl Icmpv4 {
 
     /// Returns the next level layer kind in the payload of the layer.
     pub fn next_level_layer_kind(&self) -> Option<LayerKind> {
-        if self.is_destination_port_unreachable()
-            || self.is_fragmentation_required_and_df_flag_set()
-        {
+        if self.has_datagram() {
             let (ipv4, _) = self.parse_payload().unwrap();
             ipv4.next_level_layer_kind()
         } else {
@@ -164,9 +216,7 @@ impl Icmpv4 {
 
     /// Returns the source in the payload of the layer.
     pub fn src(&self) -> Option<SocketAddrV4> {
-        if self.is_destination_port_unreachable()
-            || self.is_fragmentation_required_and_df_flag_set()
-        {
+        if self.has_datagram() {
             let (_, transport) = self.parse_payload().unwrap();
             match transport {
                 Some(transport) => match transport {
@@ -183,9 +233,7 @@ impl Icmpv4 {
 
     /// Returns the destination in the payload of the layer.
     pub fn dst(&self) -> Option<SocketAddrV4> {
-        if self.is_destination_port_unreachable()
-            || self.is_fragmentation_required_and_df_flag_set()
-        {
+        if self.has_datagram() {
             let (_, transport) = self.parse_payload().unwrap();
             match transport {
                 Some(transport) => match transport {
@@ -200,6 +248,14 @@ impl Icmpv4 {
         }
     }
 
+    fn has_datagram(&self) -> bool {
+        (self.is_destination_port_unreachable()
+            || self.is_fragmentation_required_and_df_flag_set()
+            || self.is_redirect()
+            || self.is_source_quench())
+            && self.parse_payload().is_some()
+    }
+
     fn parse_payload(&self) -> Option<(Ipv4, Option<Layers>)> {
         if self.layer.payload.len() < 4 {
             return None;
@@ -220,10 +276,10 @@ impl Icmpv4 {
                             }
                         }
                         IpNextHeaderProtocols::Tcp => match TcpPacket::new(ipv4_packet.payload()) {
-                            Some(ref tcp_packet) => {
+                            Some(ref tcp_packet) if Tcp::is_options_valid(tcp_packet) => {
                                 Some(Layers::Tcp(Tcp::parse(tcp_packet, &ipv4)))
                             }
-                            None => None,
+                            _ => None,
                         },
                         IpNextHeaderProtocols::Udp => match UdpPacket::new(ipv4_packet.payload()) {
                             Some(ref udp_packet) => {
@@ -263,6 +319,32 @@ impl Icmpv4 {
                 == destination_unreachable::IcmpCodes::FragmentationRequiredAndDFFlagSet
     }
 
+    /// Returns if the layer is an ICMPv4 time to live exceeded in transit.
+    pub fn is_time_exceeded(&self) -> bool {
+        self.layer.icmp_type == IcmpTypes::TimeExceeded
+            && self.layer.icmp_code == time_exceeded::IcmpCodes::TimeToLiveExceededInTransit
+    }
+
+    /// Returns if the layer is an ICMPv4 redirect.
+    pub fn is_redirect(&self) -> bool {
+        self.layer.icmp_type == IcmpTypes::RedirectMessage
+    }
+
+    /// Returns if the layer is an ICMPv4 source quench.
+    pub fn is_source_quench(&self) -> bool {
+        self.layer.icmp_type == IcmpTypes::SourceQuench
+    }
+
+    /// Returns if the layer is an ICMPv4 error message, which should never be answered with
+    /// another ICMPv4 error message.
+    pub fn is_error(&self) -> bool {
+        self.layer.icmp_type == IcmpTypes::DestinationUnreachable
+            || self.layer.icmp_type == IcmpTypes::SourceQuench
+            || self.layer.icmp_type == IcmpTypes::RedirectMessage
+            || self.layer.icmp_type == IcmpTypes::TimeExceeded
+            || self.layer.icmp_type == IcmpTypes::ParameterProblem
+    }
+
     /// Returns if the layer is an ICMPv4 echo request.
     pub fn is_echo_request(&self) -> bool {
         self.layer.icmp_type == IcmpTypes::EchoRequest
@@ -2,8 +2,13 @@
 
 use super::{Layer, LayerKind, LayerKinds};
 use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
-use pnet::packet::ipv4::{self, Ipv4Flags, Ipv4OptionPacket, Ipv4Packet, MutableIpv4Packet};
+use pnet::packet::ipv4::{
+    self, Ipv4Flags, Ipv4OptionNumber, Ipv4OptionNumbers, Ipv4OptionPacket, Ipv4Packet,
+    MutableIpv4Packet,
+};
+use pnet::packet::Packet;
 use std::clone::Clone;
+use std::cmp::min;
 use std::fmt::{self, Display, Formatter};
 use std::io;
 use std::net::Ipv4Addr;
@@ -113,6 +118,32 @@ impl Ipv4 {
         20
     }
 
+    /// Returns if the options of the given IPv4 packet are well-formed, which means every option
+    /// can be parsed without running past the options of the packet.
+    pub fn is_options_valid(packet: &Ipv4Packet) -> bool {
+        let buffer = packet.packet();
+        let end = min(packet.get_header_length() as usize * 4, buffer.len());
+
+        let mut i = Ipv4::minimum_len();
+        while i < end {
+            let number = Ipv4OptionNumber(buffer[i] & 0x1f);
+            if number == Ipv4OptionNumbers::EOL || number == Ipv4OptionNumbers::NOP {
+                i += 1;
+                continue;
+            }
+            if i + 1 >= end {
+                return false;
+            }
+            let length = buffer[i + 1] as usize;
+            if length < 2 || i + length > end {
+                return false;
+            }
+            i += length;
+        }
+
+        true
+    }
+
     /// Returns the total length of the layer.
     pub fn total_length(&self) -> u16 {
         self.layer.total_length
@@ -133,6 +164,11 @@ impl Ipv4 {
         self.layer.fragment_offset
     }
 
+    /// Returns the TTL of the layer.
+    pub fn ttl(&self) -> u8 {
+        self.layer.ttl
+    }
+
     /// Returns if the layer is a IPv4 fragment.
     pub fn is_fragment(&self) -> bool {
         self.is_more_fragment() || self.fragment_offset() > 0
@@ -143,6 +179,11 @@ impl Ipv4 {
         self.layer.next_level_protocol
     }
 
+    /// Returns if the next level protocol of the layer is IGMP.
+    pub fn is_igmp(&self) -> bool {
+        self.layer.next_level_protocol == IpNextHeaderProtocols::Igmp
+    }
+
     /// Returns the next level layer kind of the layer.
     pub fn next_level_layer_kind(&self) -> Option<LayerKind> {
         match self.layer.next_level_protocol {
@@ -10,6 +10,7 @@ pub mod arp;
 pub mod ethernet;
 pub mod icmpv4;
 pub mod ipv4;
+pub mod pppoe;
 pub mod tcp;
 pub mod udp;
 
@@ -29,6 +30,7 @@ impl Display for LayerKind {
                 LayerKinds::Icmpv4 => "ICMPv4",
                 LayerKinds::Tcp => "TCP",
                 LayerKinds::Udp => "UDP",
+                LayerKinds::Pppoe => "PPPoE",
                 _ => "unknown",
             }
         )
@@ -53,6 +55,8 @@ pub mod LayerKinds {
     pub const Tcp: LayerKind = LayerKind(4);
     /// Represents the layer kind of UDP.
     pub const Udp: LayerKind = LayerKind(5);
+    /// Represents the layer kind of PPPoE.
+    pub const Pppoe: LayerKind = LayerKind(6);
 }
 
 /// Represents a layer.
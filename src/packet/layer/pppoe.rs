@@ -0,0 +1,129 @@
+//! Support for serializing and deserializing the PPPoE session layer.
+
+use super::{Layer, LayerKind, LayerKinds};
+use std::clone::Clone;
+use std::fmt::{self, Display, Formatter};
+use std::io;
+
+/// Represents the version and the type of PPPoE.
+const VERSION_TYPE: u8 = 0x11;
+/// Represents the code of PPPoE session data.
+const CODE_SESSION_DATA: u8 = 0x00;
+/// Represents the length of the PPPoE header.
+const PPPOE_HEADER_LEN: usize = 6;
+/// Represents the length of the PPP protocol field.
+const PPP_PROTOCOL_LEN: usize = 2;
+
+/// Represents the PPP protocol of IPv4.
+pub const PPP_PROTOCOL_IPV4: u16 = 0x0021;
+
+/// Represents a PPPoE session layer, which includes the PPP protocol field.
+#[derive(Clone, Debug)]
+pub struct Pppoe {
+    version_type: u8,
+    code: u8,
+    session_id: u16,
+    length: u16,
+    protocol: u16,
+}
+
+impl Pppoe {
+    /// Creates a `Pppoe` carrying IPv4.
+    pub fn new(session_id: u16) -> Pppoe {
+        Pppoe {
+            version_type: VERSION_TYPE,
+            code: CODE_SESSION_DATA,
+            session_id,
+            length: 0,
+            protocol: PPP_PROTOCOL_IPV4,
+        }
+    }
+
+    /// Creates a `Pppoe` according to the given PPPoE session payload of an Ethernet packet.
+    /// Returns `None` if the payload is too short.
+    pub fn parse(payload: &[u8]) -> Option<Pppoe> {
+        if payload.len() < PPPOE_HEADER_LEN + PPP_PROTOCOL_LEN {
+            return None;
+        }
+
+        Some(Pppoe {
+            version_type: payload[0],
+            code: payload[1],
+            session_id: u16::from_be_bytes([payload[2], payload[3]]),
+            length: u16::from_be_bytes([payload[4], payload[5]]),
+            protocol: u16::from_be_bytes([payload[6], payload[7]]),
+        })
+    }
+
+    /// Returns if the layer is PPPoE session data.
+    pub fn is_session_data(&self) -> bool {
+        self.version_type == VERSION_TYPE && self.code == CODE_SESSION_DATA
+    }
+
+    /// Returns the session ID of the layer.
+    pub fn session_id(&self) -> u16 {
+        self.session_id
+    }
+
+    /// Returns the length of the PPP payload, excluding the PPP protocol field. Returns `None`
+    /// if the length is too short to include the PPP protocol field.
+    pub fn payload_length(&self) -> Option<usize> {
+        (self.length as usize).checked_sub(PPP_PROTOCOL_LEN)
+    }
+
+    /// Returns the PPP protocol of the layer.
+    pub fn protocol(&self) -> u16 {
+        self.protocol
+    }
+
+    /// Returns if the layer carries IPv4.
+    pub fn is_ipv4(&self) -> bool {
+        self.protocol == PPP_PROTOCOL_IPV4
+    }
+}
+
+impl Display for Pppoe {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: Session = {:#06x}, Protocol = {:#06x}",
+            LayerKinds::Pppoe,
+            self.session_id,
+            self.protocol
+        )
+    }
+}
+
+impl Layer for Pppoe {
+    fn kind(&self) -> LayerKind {
+        LayerKinds::Pppoe
+    }
+
+    fn len(&self) -> usize {
+        PPPOE_HEADER_LEN + PPP_PROTOCOL_LEN
+    }
+
+    fn serialize(&self, buffer: &mut [u8], n: usize) -> io::Result<usize> {
+        if buffer.len() < self.len() {
+            return Err(io::Error::new(io::ErrorKind::WriteZero, "buffer too small"));
+        }
+        if n < PPPOE_HEADER_LEN || n - PPPOE_HEADER_LEN > u16::MAX as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "length too big",
+            ));
+        }
+
+        buffer[0] = VERSION_TYPE;
+        buffer[1] = CODE_SESSION_DATA;
+        buffer[2..4].copy_from_slice(&self.session_id.to_be_bytes());
+        buffer[4..6].copy_from_slice(&((n - PPPOE_HEADER_LEN) as u16).to_be_bytes());
+        buffer[6..8].copy_from_slice(&self.protocol.to_be_bytes());
+
+        Ok(self.len())
+    }
+
+    fn serialize_with_payload(&self, buffer: &mut [u8], _: &[u8], n: usize) -> io::Result<usize> {
+        self.serialize(buffer, n)
+    }
+}
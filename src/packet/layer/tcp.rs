@@ -6,6 +6,7 @@ use pnet::packet::tcp::{
     self, MutableTcpOptionPacket, MutableTcpPacket, TcpFlags, TcpOption, TcpOptionNumber,
     TcpOptionNumbers, TcpOptionPacket, TcpPacket,
 };
+use pnet::packet::Packet;
 use std::clone::Clone;
 use std::cmp::min;
 use std::fmt::{self, Display, Formatter};
@@ -145,6 +146,22 @@ impl Tcp {
         tcp
     }
 
+    /// Creates a `Tcp` represents a TCP SYN.
+    pub fn new_syn(
+        src: u16,
+        dst: u16,
+        sequence: u32,
+        window: u16,
+        mss: Option<u16>,
+        wscale: Option<u8>,
+        sack_perm: bool,
+        ts: Option<(u32, u32)>,
+    ) -> Tcp {
+        let mut tcp = Tcp::new_ack_syn(src, dst, sequence, 0, window, mss, wscale, sack_perm, ts);
+        tcp.layer.flags = TcpFlags::SYN;
+        tcp
+    }
+
     /// Creates a `Tcp` represents a TCP ACK/RST.
     pub fn new_ack_rst(
         src: u16,
@@ -237,6 +254,32 @@ impl Tcp {
         20
     }
 
+    /// Returns if the options of the given TCP packet are well-formed, which means every option
+    /// can be parsed without running past the options of the packet.
+    pub fn is_options_valid(packet: &TcpPacket) -> bool {
+        let buffer = packet.packet();
+        let end = min(packet.get_data_offset() as usize * 4, buffer.len());
+
+        let mut i = Tcp::minimum_len();
+        while i < end {
+            let number = TcpOptionNumber(buffer[i]);
+            if number == TcpOptionNumbers::EOL || number == TcpOptionNumbers::NOP {
+                i += 1;
+                continue;
+            }
+            if i + 1 >= end {
+                return false;
+            }
+            let length = buffer[i + 1] as usize;
+            if length < 2 || i + length > end {
+                return false;
+            }
+            i += length;
+        }
+
+        true
+    }
+
     /// Sets the source and destination IP address for the layer with the given `Ipv4`.
     pub fn set_ipv4_layer(&mut self, ipv4: &Ipv4) {
         self.src = ipv4.src();
@@ -288,6 +331,9 @@ impl Tcp {
         if self.is_ack() {
             flags = flags + ".";
         }
+        if self.is_urg() {
+            flags = flags + "U";
+        }
         flags = flags + "]";
 
         flags
@@ -347,7 +393,7 @@ impl Tcp {
                 TcpOptionNumbers::SACK => {
                     let mut vector = Vec::with_capacity(4);
 
-                    let pair_length = (buffer[1] as usize - 2) / 8;
+                    let pair_length = min((buffer[1] as usize).saturating_sub(2) / 8, 4);
                     for i in 0..pair_length {
                         let left = bytes_to_u32(&buffer[2 + 8 * i..2 + 8 * i + 4]);
                         let right = bytes_to_u32(&buffer[2 + 8 * i + 4..2 + 8 * i + 8]);
@@ -427,6 +473,37 @@ impl Tcp {
         self.layer.flags & TcpFlags::FIN != 0
     }
 
+    /// Returns if the layer is a TCP push.
+    pub fn is_psh(&self) -> bool {
+        self.layer.flags & TcpFlags::PSH != 0
+    }
+
+    /// Returns if the layer has urgent data.
+    pub fn is_urg(&self) -> bool {
+        self.layer.flags & TcpFlags::URG != 0
+    }
+
+    /// Returns the urgent pointer of the layer, which is the offset from the sequence to the byte
+    /// following the urgent data.
+    pub fn urgent_ptr(&self) -> u16 {
+        self.layer.urgent_ptr
+    }
+
+    /// Returns the sequence of the last byte of the urgent data of the layer, which is the byte
+    /// before the urgent pointer as clarified in RFC 6093. Returns `None` if the layer has no
+    /// urgent data.
+    pub fn urgent_sequence(&self) -> Option<u32> {
+        if !self.is_urg() || self.layer.urgent_ptr == 0 {
+            return None;
+        }
+
+        Some(
+            self.layer
+                .sequence
+                .wrapping_add(self.layer.urgent_ptr as u32 - 1),
+        )
+    }
+
     /// Returns if the layer is a TCP reset or finish.
     pub fn is_rst_or_fin(&self) -> bool {
         self.is_rst() || self.is_fin()
@@ -572,3 +649,34 @@ fn get_number_from_option(option: &TcpOption) -> TcpOptionNumber {
     }
     */
 }
+
+#[test]
+fn tcp_urgent_sequence() {
+    let mut layer = tcp::Tcp {
+        source: 23,
+        destination: 49152,
+        sequence: u32::MAX,
+        acknowledgement: 0,
+        data_offset: 5,
+        reserved: 0,
+        flags: TcpFlags::ACK,
+        window: 65535,
+        checksum: 0,
+        urgent_ptr: 2,
+        options: vec![],
+        payload: vec![],
+    };
+
+    // The urgent pointer is ignored without URG
+    assert_eq!(Tcp::from(layer.clone()).urgent_sequence(), None);
+
+    // Telnet IAC DM sent as urgent data, where the DM is the urgent byte
+    layer.flags |= TcpFlags::URG | TcpFlags::PSH;
+    let tcp = Tcp::from(layer.clone());
+    assert!(tcp.is_urg());
+    assert_eq!(tcp.urgent_sequence(), Some(0));
+    assert_eq!(tcp.flag_string(), "[.U]");
+
+    layer.urgent_ptr = 0;
+    assert_eq!(Tcp::from(layer).urgent_sequence(), None);
+}
@@ -3,32 +3,179 @@
 use pnet::packet::arp::ArpPacket;
 use pnet::packet::ethernet::{EtherTypes, EthernetPacket};
 use pnet::packet::icmp::IcmpPacket;
-use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
 use pnet::packet::ipv4::Ipv4Packet;
 use pnet::packet::tcp::TcpPacket;
 use pnet::packet::udp::UdpPacket;
 use pnet::packet::Packet;
+#[cfg(feature = "defrag")]
 use std::collections::HashMap;
 use std::fmt::{self, Display, Formatter};
 use std::io;
+#[cfg(any(feature = "defrag", test))]
 use std::net::Ipv4Addr;
+#[cfg(feature = "defrag")]
 use std::time::Instant;
 
+pub mod discovery;
+pub mod igmp;
 pub mod layer;
+pub mod netbios;
+pub mod quic;
+pub mod tunnel;
+use discovery::Discovery;
 use layer::arp::Arp;
 use layer::ethernet::Ethernet;
 use layer::icmpv4::Icmpv4;
 use layer::ipv4::Ipv4;
+use layer::pppoe::Pppoe;
 use layer::tcp::Tcp;
 use layer::udp::Udp;
-use layer::{Layer, LayerKind, Layers};
+use layer::{Layer, LayerKind, LayerKinds, Layers};
+
+/// Represents an error when parsing a malformed frame.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum ParseError {
+    /// Represents the frame is too short for the layer.
+    Truncated(LayerKind),
+    /// Represents some fields of the layer are invalid.
+    Invalid(LayerKind),
+}
+
+impl ParseError {
+    /// Returns the kind of the layer which is malformed.
+    pub fn kind(&self) -> LayerKind {
+        match self {
+            ParseError::Truncated(kind) | ParseError::Invalid(kind) => *kind,
+        }
+    }
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            ParseError::Truncated(kind) => write!(f, "truncated {}", kind),
+            ParseError::Invalid(kind) => write!(f, "invalid {}", kind),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl From<ParseError> for io::Error {
+    fn from(e: ParseError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+/// Represents an error when reassembling fragments.
+#[cfg(feature = "defrag")]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum DefragError {
+    /// Represents the fragment overlaps other fragments or the end of its group, like in a
+    /// teardrop attack. The whole group of fragments is dropped.
+    Overlap,
+    /// Represents the source has too many groups of fragments being reassembled.
+    SourceLimit,
+    /// Represents groups of fragments being reassembled exceed the limit of count or memory.
+    Capacity,
+}
+
+#[cfg(feature = "defrag")]
+impl Display for DefragError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            DefragError::Overlap => write!(f, "overlapping fragments"),
+            DefragError::SourceLimit => write!(f, "too many fragmented packets of the source"),
+            DefragError::Capacity => write!(f, "fragment reassembly capacity exceeded"),
+        }
+    }
+}
+
+#[cfg(feature = "defrag")]
+impl std::error::Error for DefragError {}
+
+/// Parses the transport layer of the given next level protocol from the IPv4 payload.
+fn parse_transport(
+    protocol: IpNextHeaderProtocol,
+    payload: &[u8],
+    ipv4: &Ipv4,
+) -> Result<Option<Layers>, ParseError> {
+    match protocol {
+        IpNextHeaderProtocols::Icmp => match IcmpPacket::new(payload) {
+            Some(ref icmp_packet) => Ok(Some(Layers::Icmpv4(Icmpv4::parse(icmp_packet)))),
+            None => Err(ParseError::Truncated(LayerKinds::Icmpv4)),
+        },
+        IpNextHeaderProtocols::Tcp => match TcpPacket::new(payload) {
+            Some(ref tcp_packet) => {
+                let data_offset = tcp_packet.get_data_offset() as usize;
+                if data_offset < 5 {
+                    return Err(ParseError::Invalid(LayerKinds::Tcp));
+                }
+                if data_offset * 4 > payload.len() {
+                    return Err(ParseError::Truncated(LayerKinds::Tcp));
+                }
+                if !Tcp::is_options_valid(tcp_packet) {
+                    return Err(ParseError::Invalid(LayerKinds::Tcp));
+                }
+
+                Ok(Some(Layers::Tcp(Tcp::parse(tcp_packet, ipv4))))
+            }
+            None => Err(ParseError::Truncated(LayerKinds::Tcp)),
+        },
+        IpNextHeaderProtocols::Udp => match UdpPacket::new(payload) {
+            Some(ref udp_packet) => Ok(Some(Layers::Udp(Udp::parse(udp_packet, ipv4)))),
+            None => Err(ParseError::Truncated(LayerKinds::Udp)),
+        },
+        _ => Ok(None),
+    }
+}
+
+/// Parses the IPv4 layer and its transport layer from the given payload.
+fn parse_ipv4(payload: &[u8]) -> Result<(Layers, Option<Layers>), ParseError> {
+    match Ipv4Packet::new(payload) {
+        Some(ref ipv4_packet) => {
+            // Validate the header before parsing options and the payload
+            let header_length = ipv4_packet.get_header_length() as usize * 4;
+            let total_length = ipv4_packet.get_total_length() as usize;
+            if ipv4_packet.get_version() != 4
+                || header_length < Ipv4::minimum_len()
+                || total_length < header_length
+            {
+                return Err(ParseError::Invalid(LayerKinds::Ipv4));
+            }
+            if total_length > payload.len() {
+                return Err(ParseError::Truncated(LayerKinds::Ipv4));
+            }
+            if !Ipv4::is_options_valid(ipv4_packet) {
+                return Err(ParseError::Invalid(LayerKinds::Ipv4));
+            }
+
+            let ipv4 = Ipv4::parse(ipv4_packet);
+            // Fragment
+            let mut transport = None;
+            if !ipv4.is_fragment() {
+                transport = parse_transport(
+                    ipv4_packet.get_next_level_protocol(),
+                    ipv4_packet.payload(),
+                    &ipv4,
+                )?;
+            }
+
+            Ok((Layers::Ipv4(ipv4), transport))
+        }
+        None => Err(ParseError::Truncated(LayerKinds::Ipv4)),
+    }
+}
 
 /// Represents a packet indicator.
 #[derive(Clone, Debug)]
 pub struct Indicator {
     link: Layers,
+    pppoe: Option<Pppoe>,
     network: Option<Layers>,
     transport: Option<Layers>,
+    discovery: Option<Discovery>,
 }
 
 impl Indicator {
@@ -36,74 +183,95 @@ impl Indicator {
     pub fn new(link: Layers, network: Option<Layers>, transport: Option<Layers>) -> Indicator {
         Indicator {
             link,
+            pppoe: None,
             network,
             transport,
+            discovery: None,
         }
     }
 
-    /// Creates a `Indicator` by the given Ethernet packet.
-    pub fn parse(packet: &EthernetPacket) -> Indicator {
+    /// Sets the PPPoE session layer between the link layer and the network layer.
+    pub fn set_pppoe(&mut self, pppoe: Pppoe) {
+        self.pppoe = Some(pppoe);
+    }
+
+    /// Creates a `Indicator` by the given Ethernet packet. Returns an error if the packet is
+    /// malformed.
+    pub fn parse(packet: &EthernetPacket) -> Result<Indicator, ParseError> {
+        let mut pppoe = None;
         let mut transport = None;
+        let mut discovery = None;
 
-        let link = Layers::Ethernet(Ethernet::parse(packet));
-        let network = match packet.get_ethertype() {
-            EtherTypes::Arp => match ArpPacket::new(packet.payload()) {
+        let ethernet = match Ethernet::parse(packet) {
+            Some(ethernet) => ethernet,
+            None => return Err(ParseError::Truncated(LayerKinds::Ethernet)),
+        };
+        // Skip VLAN tags
+        let payload = &packet.payload()[ethernet.tags_len()..];
+        let ethertype = ethernet.ethertype();
+        let link = Layers::Ethernet(ethernet);
+        let network = match ethertype {
+            EtherTypes::Arp => match ArpPacket::new(payload) {
                 Some(ref arp_packet) => Some(Layers::Arp(Arp::parse(arp_packet))),
-                None => None,
+                None => return Err(ParseError::Truncated(LayerKinds::Arp)),
             },
-            EtherTypes::Ipv4 => match Ipv4Packet::new(packet.payload()) {
-                Some(ref ipv4_packet) => {
-                    let ipv4 = Ipv4::parse(ipv4_packet);
-                    // Fragment
-                    if !ipv4.is_fragment() {
-                        transport = match ipv4_packet.get_next_level_protocol() {
-                            IpNextHeaderProtocols::Icmp => {
-                                match IcmpPacket::new(ipv4_packet.payload()) {
-                                    Some(ref icmp_packet) => {
-                                        Some(Layers::Icmpv4(Icmpv4::parse(icmp_packet)))
-                                    }
-                                    None => None,
-                                }
-                            }
-                            IpNextHeaderProtocols::Tcp => {
-                                match TcpPacket::new(ipv4_packet.payload()) {
-                                    Some(ref tcp_packet) => {
-                                        Some(Layers::Tcp(Tcp::parse(tcp_packet, &ipv4)))
-                                    }
-                                    None => None,
-                                }
-                            }
-                            IpNextHeaderProtocols::Udp => {
-                                match UdpPacket::new(ipv4_packet.payload()) {
-                                    Some(ref udp_packet) => {
-                                        Some(Layers::Udp(Udp::parse(udp_packet, &ipv4)))
-                                    }
-                                    None => None,
-                                }
-                            }
-                            _ => None,
-                        };
-                    }
-
-                    Some(Layers::Ipv4(ipv4))
+            EtherTypes::Ipv4 => {
+                let (ipv4, t) = parse_ipv4(payload)?;
+                transport = t;
+
+                Some(ipv4)
+            }
+            EtherTypes::PppoeSession => {
+                let layer = match Pppoe::parse(payload) {
+                    Some(layer) => layer,
+                    None => return Err(ParseError::Truncated(LayerKinds::Pppoe)),
+                };
+                if !layer.is_session_data() {
+                    return Err(ParseError::Invalid(LayerKinds::Pppoe));
                 }
-                None => None,
-            },
-            _ => None,
+                let payload = &payload[layer.len()..];
+                let payload_length = match layer.payload_length() {
+                    Some(payload_length) => payload_length,
+                    None => return Err(ParseError::Invalid(LayerKinds::Pppoe)),
+                };
+                if payload_length > payload.len() {
+                    return Err(ParseError::Truncated(LayerKinds::Pppoe));
+                }
+                let payload = &payload[..payload_length];
+                let network = if layer.is_ipv4() {
+                    let (ipv4, t) = parse_ipv4(payload)?;
+                    transport = t;
+
+                    Some(ipv4)
+                } else {
+                    // LCP, IPCP and others are left to the access concentrator
+                    None
+                };
+                pppoe = Some(layer);
+
+                network
+            }
+            ethertype => {
+                discovery = Discovery::parse(ethertype.0, payload);
+
+                None
+            }
         };
 
-        Indicator {
+        Ok(Indicator {
             link,
+            pppoe,
             network,
             transport,
-        }
+            discovery,
+        })
     }
 
-    /// Creates a `Indicator` by the given frame.
-    pub fn from(frame: &[u8]) -> Option<Indicator> {
+    /// Creates a `Indicator` by the given frame. Returns an error if the frame is malformed.
+    pub fn from(frame: &[u8]) -> Result<Indicator, ParseError> {
         match EthernetPacket::new(frame) {
-            Some(ref packet) => Some(Indicator::parse(packet)),
-            None => None,
+            Some(ref packet) => Indicator::parse(packet),
+            None => Err(ParseError::Truncated(LayerKinds::Ethernet)),
         }
     }
 
@@ -139,15 +307,15 @@ impl Indicator {
                             udp.dst(),
                             udp.length(),
                         ),
-                        _ => unreachable!(),
+                        transport => format!("{}", transport),
                     },
                     None => format!("{}", ipv4),
                 },
-                _ => unreachable!(),
+                network => format!("{}", network),
             },
-            None => match self.link() {
-                Layers::Ethernet(ethernet) => format!("{}", ethernet),
-                _ => unreachable!(),
+            None => match self.discovery() {
+                Some(discovery) => format!("{}", discovery),
+                None => format!("{}", self.link()),
             },
         }
     }
@@ -158,6 +326,10 @@ impl Indicator {
 
         // Link
         size = size + self.link().len();
+        // PPPoE
+        if let Some(pppoe) = self.pppoe() {
+            size = size + pppoe.len();
+        }
         // Network
         if let Some(network) = self.network() {
             size = size + network.len();
@@ -173,15 +345,15 @@ impl Indicator {
     /// Returns the content length of the indicator when converted into a byte-array.
     pub fn content_len(&self) -> usize {
         match self.link() {
-            Layers::Ethernet(ethernet) => match self.network() {
+            Layers::Ethernet(_) => match self.network() {
                 Some(network) => match network {
-                    Layers::Arp(arp) => ethernet.len() + arp.len(),
-                    Layers::Ipv4(ipv4) => ethernet.len() + ipv4.total_length() as usize,
-                    _ => unreachable!(),
+                    Layers::Arp(arp) => self.link_len() + arp.len(),
+                    Layers::Ipv4(ipv4) => self.link_len() + ipv4.total_length() as usize,
+                    network => self.link_len() + network.len(),
                 },
-                None => ethernet.len(),
+                None => self.link_len(),
             },
-            _ => unreachable!(),
+            link => link.len(),
         }
     }
 
@@ -194,6 +366,12 @@ impl Indicator {
         let m = self.link().serialize(&mut buffer[begin..], total)?;
         begin = begin + m;
         total = total - m;
+        // PPPoE
+        if let Some(pppoe) = self.pppoe() {
+            let m = pppoe.serialize(&mut buffer[begin..], total)?;
+            begin = begin + m;
+            total = total - m;
+        };
         // Network
         if let Some(network) = self.network() {
             let m = network.serialize(&mut buffer[begin..], total)?;
@@ -220,6 +398,12 @@ impl Indicator {
             .serialize_with_payload(&mut buffer[begin..], payload, total)?;
         begin = begin + m;
         total = total - m;
+        // PPPoE
+        if let Some(pppoe) = self.pppoe() {
+            let m = pppoe.serialize_with_payload(&mut buffer[begin..], payload, total)?;
+            begin = begin + m;
+            total = total - m;
+        };
         // Network
         if let Some(network) = self.network() {
             let m = network.serialize_with_payload(&mut buffer[begin..], payload, total)?;
@@ -254,6 +438,25 @@ impl Indicator {
         None
     }
 
+    /// Returns the PPPoE session layer.
+    pub fn pppoe(&self) -> Option<&Pppoe> {
+        self.pppoe.as_ref()
+    }
+
+    /// Returns the length of the link layer, including the PPPoE session layer.
+    pub fn link_len(&self) -> usize {
+        match self.pppoe() {
+            Some(pppoe) => self.link().len() + pppoe.len(),
+            None => self.link().len(),
+        }
+    }
+
+    /// Returns the link-layer discovery frame, like LLDP, CDP and STP, which is not parsed into
+    /// layers.
+    pub fn discovery(&self) -> Option<&Discovery> {
+        self.discovery.as_ref()
+    }
+
     /// Returns the network layer.
     pub fn network(&self) -> Option<&Layers> {
         if let Some(layer) = &self.network {
@@ -348,7 +551,10 @@ impl Indicator {
 
 impl Display for Indicator {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        let link_string = format!("\n- {} ({} Bytes)", self.link, self.link.len());
+        let mut link_string = format!("\n- {} ({} Bytes)", self.link, self.link.len());
+        if let Some(pppoe) = &self.pppoe {
+            link_string.push_str(&format!("\n- {} ({} Bytes)", pppoe, pppoe.len()));
+        }
         let mut network_string = String::new();
         if let Some(network) = &self.network {
             network_string = format!("\n- {} ({} Bytes)", network, network.len());
@@ -367,19 +573,38 @@ impl Display for Indicator {
 }
 
 /// Represents the expire time of each group of fragments.
+#[cfg(feature = "defrag")]
 const EXPIRE_TIME: u128 = 10000;
 
+/// Represents the max number of groups of fragments being reassembled at the same time, which
+/// bounds the memory a flood of fragments can hold.
+#[cfg(feature = "defrag")]
+const MAX_FRAGMENTATIONS: usize = 256;
+
+/// Represents the max number of groups of fragments of a source being reassembled at the same
+/// time, so a single source cannot occupy all the groups.
+#[cfg(feature = "defrag")]
+const MAX_SOURCE_FRAGMENTATIONS: usize = 16;
+
+/// Represents the max bytes of the buffers of all the groups of fragments being reassembled.
+#[cfg(feature = "defrag")]
+const MAX_FRAGMENTATION_MEMORY: usize = 2 * 1024 * 1024;
+
 /// Represents a fragmentation.
+#[cfg(feature = "defrag")]
 #[derive(Debug)]
 pub struct Fragmentation {
     ethernet: Ethernet,
     ipv4: Ipv4,
     buffer: Vec<u8>,
+    /// Represents the ranges of the fragments received.
+    ranges: Vec<(usize, usize)>,
     length: usize,
     total_length: Option<usize>,
     last_seen: Instant,
 }
 
+#[cfg(feature = "defrag")]
 impl Fragmentation {
     /// Creates a `Fragmentation`.
     pub fn new(indicator: &Indicator) -> Option<Fragmentation> {
@@ -395,7 +620,8 @@ impl Fragmentation {
         let frag = Fragmentation {
             ethernet: ethernet.clone(),
             ipv4: ipv4.clone(),
-            buffer: vec![0; u16::MAX as usize],
+            buffer: Vec::new(),
+            ranges: Vec::new(),
             length: 0,
             total_length: None,
             last_seen: Instant::now(),
@@ -404,45 +630,75 @@ impl Fragmentation {
         Some(frag)
     }
 
-    /// Adds a fragmentation.
-    pub fn add(&mut self, indicator: &Indicator, payload: &[u8]) {
+    /// Adds a fragmentation. Duplicate fragments are ignored, and returns an error if the
+    /// fragment overlaps other fragments or the end of the group.
+    pub fn add(&mut self, indicator: &Indicator, payload: &[u8]) -> Result<(), DefragError> {
         // Payload
         let ipv4 = match indicator.ipv4() {
             Some(ipv4) => ipv4,
-            None => return,
+            None => return Ok(()),
         };
         let offset = (ipv4.fragment_offset() as usize) * 8;
+        let end = offset + payload.len();
+        if end > u16::MAX as usize {
+            return Ok(());
+        }
+        if self.ranges.contains(&(offset, end)) {
+            return Ok(());
+        }
+        if self
+            .ranges
+            .iter()
+            .any(|&(start, stop)| offset < stop && start < end)
+        {
+            return Err(DefragError::Overlap);
+        }
+        if let Some(total_length) = self.total_length {
+            if end > total_length || !ipv4.is_more_fragment() {
+                return Err(DefragError::Overlap);
+            }
+        }
         if !ipv4.is_more_fragment() {
-            self.total_length = Some(offset + payload.len());
+            if self.ranges.iter().any(|&(_, stop)| stop > end) {
+                return Err(DefragError::Overlap);
+            }
+            self.total_length = Some(end);
         }
 
-        self.buffer[offset..offset + payload.len()].copy_from_slice(payload);
+        if self.buffer.len() < end {
+            self.buffer.resize(end, 0);
+        }
+        self.buffer[offset..end].copy_from_slice(payload);
+        self.ranges.push((offset, end));
         self.length += payload.len();
+
+        Ok(())
     }
 
-    /// Concatenates fragmentations and returns the transport layer and the payload.
-    pub fn concatenate(&self) -> (Option<Layers>, &[u8]) {
-        let transport = match self.ipv4.next_level_protocol() {
-            IpNextHeaderProtocols::Icmp => match IcmpPacket::new(&self.buffer[..self.length]) {
-                Some(ref icmp_packet) => Some(Layers::Icmpv4(Icmpv4::parse(icmp_packet))),
-                None => None,
-            },
-            IpNextHeaderProtocols::Tcp => match TcpPacket::new(&self.buffer[..self.length]) {
-                Some(ref tcp_packet) => Some(Layers::Tcp(Tcp::parse(tcp_packet, &self.ipv4))),
-                None => None,
-            },
-            IpNextHeaderProtocols::Udp => match UdpPacket::new(&self.buffer[..self.length]) {
-                Some(ref udp_packet) => Some(Layers::Udp(Udp::parse(udp_packet, &self.ipv4))),
-                None => None,
-            },
-            _ => None,
-        };
+    /// Returns the bytes the buffer grows by if the fragment is added.
+    fn growth(&self, ipv4: &Ipv4, payload: &[u8]) -> usize {
+        let end = (ipv4.fragment_offset() as usize) * 8 + payload.len();
+        if end > u16::MAX as usize {
+            return 0;
+        }
+
+        end.saturating_sub(self.buffer.len())
+    }
+
+    /// Concatenates fragmentations and returns the transport layer and the payload. Returns an
+    /// error if the transport layer is malformed.
+    pub fn concatenate(&self) -> Result<(Option<Layers>, &[u8]), ParseError> {
+        let transport = parse_transport(
+            self.ipv4.next_level_protocol(),
+            &self.buffer[..self.length],
+            &self.ipv4,
+        )?;
 
         let header_size = match &transport {
             Some(transport) => transport.len(),
             None => 0,
         };
-        (transport, &self.buffer[header_size..self.length])
+        Ok((transport, &self.buffer[header_size..self.length]))
     }
 
     /// Returns if the fragmentation is completed.
@@ -460,11 +716,13 @@ impl Fragmentation {
 }
 
 /// Represents a defragmentation machine.
+#[cfg(feature = "defrag")]
 #[derive(Debug)]
 pub struct Defraggler {
     frags: HashMap<(Ipv4Addr, Ipv4Addr, LayerKind, u16), Fragmentation>,
 }
 
+#[cfg(feature = "defrag")]
 impl Defraggler {
     /// Creates a new empty `Defraggler`.
     pub fn new() -> Defraggler {
@@ -473,11 +731,17 @@ impl Defraggler {
         }
     }
 
-    /// Adds a fragmentation and returns the fragmentation if it is completed.
-    pub fn add(&mut self, indicator: &Indicator, frame: &[u8]) -> Option<Fragmentation> {
+    /// Adds a fragmentation and returns the fragmentation if it is completed. Returns an error
+    /// if the fragment is dropped, in which case the whole group of fragments is dropped if the
+    /// fragment overlaps.
+    pub fn add(
+        &mut self,
+        indicator: &Indicator,
+        frame: &[u8],
+    ) -> Result<Option<Fragmentation>, DefragError> {
         let ipv4 = match indicator.ipv4() {
             Some(ipv4) => ipv4,
-            None => return None,
+            None => return Ok(None),
         };
 
         let key = (ipv4.src(), ipv4.dst(), ipv4.kind(), ipv4.identification());
@@ -487,22 +751,378 @@ impl Defraggler {
             None => true,
         };
         if is_create {
+            if self.frags.len() >= MAX_FRAGMENTATIONS
+                || self.source_frags(ipv4.src()) >= MAX_SOURCE_FRAGMENTATIONS
+            {
+                self.frags.retain(|_, frag| !frag.is_expired());
+            }
+            if self.frags.len() >= MAX_FRAGMENTATIONS {
+                return Err(DefragError::Capacity);
+            }
+            if self.source_frags(ipv4.src()) >= MAX_SOURCE_FRAGMENTATIONS {
+                return Err(DefragError::SourceLimit);
+            }
+
             let frag = match Fragmentation::new(indicator) {
                 Some(frag) => frag,
-                None => return None,
+                None => return Ok(None),
             };
 
             self.frags.insert(key, frag);
         }
 
+        // Memory
+        let header_size = indicator.link_len() + ipv4.len();
+        let payload = &frame[header_size..];
+        let growth = self.frags[&key].growth(ipv4, payload);
+        if growth > 0 && self.memory() + growth > MAX_FRAGMENTATION_MEMORY {
+            self.frags.retain(|k, frag| *k == key || !frag.is_expired());
+            if self.memory() + growth > MAX_FRAGMENTATION_MEMORY {
+                if is_create {
+                    self.frags.remove(&key);
+                }
+                return Err(DefragError::Capacity);
+            }
+        }
+
         // Add fragmentation
-        let frag = self.frags.get_mut(&key).unwrap();
-        let header_size = indicator.ethernet().unwrap().len() + ipv4.len();
-        frag.add(indicator, &frame[header_size..]);
-        if frag.is_completed() {
-            self.frags.remove(&key)
+        if let Err(e) = self.frags.get_mut(&key).unwrap().add(indicator, payload) {
+            self.frags.remove(&key);
+            return Err(e);
+        }
+        if self.frags[&key].is_completed() {
+            Ok(self.frags.remove(&key))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Returns the number of groups of fragments of the source being reassembled.
+    fn source_frags(&self, src: Ipv4Addr) -> usize {
+        self.frags.keys().filter(|key| key.0 == src).count()
+    }
+
+    /// Returns the bytes of the buffers of all the groups of fragments being reassembled.
+    fn memory(&self) -> usize {
+        self.frags.values().map(|frag| frag.buffer.len()).sum()
+    }
+}
+
+#[test]
+fn indicator_from_malformed() {
+    // Ethernet + IPv4 + UDP with 4 bytes of payload
+    let frame: Vec<u8> = vec![
+        0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xaa, 0xbb, 0x08,
+        0x00, // Ethernet
+        0x45, 0x00, 0x00, 0x20, 0x00, 0x01, 0x00, 0x00, 0x40, 0x11, 0x00, 0x00, 10, 0, 0, 1, 10, 0,
+        0, 2, // IPv4
+        0x04, 0xd2, 0x00, 0x35, 0x00, 0x0c, 0x00, 0x00, // UDP
+        0x01, 0x02, 0x03, 0x04,
+    ];
+    assert!(Indicator::from(&frame).is_ok());
+
+    let with = |len: usize, bytes: &[(usize, u8)]| {
+        let mut frame = frame[..len].to_vec();
+        for &(i, b) in bytes {
+            frame[i] = b;
+        }
+        frame
+    };
+    let corpus = vec![
+        (vec![], ParseError::Truncated(LayerKinds::Ethernet)),
+        (with(13, &[]), ParseError::Truncated(LayerKinds::Ethernet)),
+        (
+            with(14, &[(13, 0x06)]),
+            ParseError::Truncated(LayerKinds::Arp),
+        ),
+        (with(20, &[]), ParseError::Truncated(LayerKinds::Ipv4)),
+        (with(36, &[]), ParseError::Truncated(LayerKinds::Ipv4)),
+        // Version
+        (
+            with(46, &[(14, 0x65)]),
+            ParseError::Invalid(LayerKinds::Ipv4),
+        ),
+        // Header length
+        (
+            with(46, &[(14, 0x44)]),
+            ParseError::Invalid(LayerKinds::Ipv4),
+        ),
+        (
+            with(46, &[(14, 0x4f)]),
+            ParseError::Invalid(LayerKinds::Ipv4),
+        ),
+        // Total length
+        (
+            with(46, &[(17, 0x10)]),
+            ParseError::Invalid(LayerKinds::Ipv4),
+        ),
+        (
+            with(46, &[(17, 0xff)]),
+            ParseError::Truncated(LayerKinds::Ipv4),
+        ),
+        // Transport
+        (
+            with(46, &[(17, 0x17), (23, 0x01)]),
+            ParseError::Truncated(LayerKinds::Icmpv4),
+        ),
+        (
+            with(46, &[(23, 0x06)]),
+            ParseError::Truncated(LayerKinds::Tcp),
+        ),
+        (
+            with(46, &[(17, 0x1b)]),
+            ParseError::Truncated(LayerKinds::Udp),
+        ),
+        // IPv4 options running past the header
+        (
+            with(46, &[(14, 0x46), (34, 0x94), (35, 0x08)]),
+            ParseError::Invalid(LayerKinds::Ipv4),
+        ),
+    ];
+    for (frame, e) in corpus {
+        assert_eq!(Indicator::from(&frame).err(), Some(e));
+    }
+
+    // TCP with data offset less than 5, and with options running past the header
+    let mut tcp = with(34, &[(17, 0x3c), (23, 0x06)]);
+    tcp.resize(74, 0);
+    tcp[46] = 0x40;
+    assert_eq!(
+        Indicator::from(&tcp).err(),
+        Some(ParseError::Invalid(LayerKinds::Tcp))
+    );
+    tcp[46] = 0x60;
+    tcp[54] = 0x02;
+    tcp[55] = 0x08;
+    assert_eq!(
+        Indicator::from(&tcp).err(),
+        Some(ParseError::Invalid(LayerKinds::Tcp))
+    );
+    tcp[55] = 0x04;
+    assert!(Indicator::from(&tcp).is_ok());
+
+    // Garbage transport layers never panic
+    let mut seed = 0x2545_f491u32;
+    for i in 0..3072 {
+        let mut garbage = tcp.clone();
+        for b in garbage.iter_mut().skip(34) {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            *b = seed as u8;
+        }
+        garbage[23] = [0x01, 0x06, 0x11][i % 3];
+        if let Ok(indicator) = Indicator::from(&garbage) {
+            let _ = indicator.brief();
+            let _ = indicator.content_len();
+            if let Some(tcp) = indicator.tcp() {
+                let _ = (tcp.mss(), tcp.wscale(), tcp.sack(), tcp.ts());
+            }
+        }
+    }
+}
+
+#[test]
+fn indicator_pppoe() {
+    // Ethernet + PPPoE + IPv4 + UDP with 4 bytes of payload, and 2 bytes of padding
+    let frame: Vec<u8> = vec![
+        0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xaa, 0xbb, 0x88,
+        0x64, // Ethernet
+        0x11, 0x00, 0x12, 0x34, 0x00, 0x22, 0x00, 0x21, // PPPoE
+        0x45, 0x00, 0x00, 0x20, 0x00, 0x01, 0x00, 0x00, 0x40, 0x11, 0x00, 0x00, 10, 0, 0, 1, 10, 0,
+        0, 2, // IPv4
+        0x04, 0xd2, 0x00, 0x35, 0x00, 0x0c, 0x00, 0x00, // UDP
+        0x01, 0x02, 0x03, 0x04, 0x00, 0x00,
+    ];
+    let indicator = Indicator::from(&frame).unwrap();
+    assert_eq!(indicator.pppoe().unwrap().session_id(), 0x1234);
+    assert_eq!(indicator.ipv4().unwrap().dst(), Ipv4Addr::new(10, 0, 0, 2));
+    assert_eq!(indicator.udp().unwrap().dst(), 53);
+    assert_eq!(indicator.link_len(), 22);
+    assert_eq!(indicator.content_len(), frame.len() - 2);
+
+    // Replies are re-encapsulated in the session
+    let mut buffer = vec![0u8; indicator.len() + 4];
+    indicator
+        .serialize_with_payload(&mut buffer, &[0x01, 0x02, 0x03, 0x04])
+        .unwrap();
+    assert_eq!(&buffer[12..22], &frame[12..22]);
+    assert_eq!(Indicator::from(&buffer).unwrap().udp().unwrap().src(), 1234);
+
+    // Control protocols and malformed sessions
+    let mut lcp = frame.clone();
+    lcp[21] = 0x21;
+    lcp[20] = 0xc0;
+    let lcp = Indicator::from(&lcp).unwrap();
+    assert!(lcp.pppoe().is_some() && lcp.network().is_none());
+    assert_eq!(
+        Indicator::from(&frame[..20]).err(),
+        Some(ParseError::Truncated(LayerKinds::Pppoe))
+    );
+    let mut discovery = frame.clone();
+    discovery[15] = 0x09;
+    assert_eq!(
+        Indicator::from(&discovery).err(),
+        Some(ParseError::Invalid(LayerKinds::Pppoe))
+    );
+    let mut long = frame.clone();
+    long[19] = 0x30;
+    assert_eq!(
+        Indicator::from(&long).err(),
+        Some(ParseError::Truncated(LayerKinds::Pppoe))
+    );
+}
+
+#[test]
+fn indicator_vlan() {
+    // Ethernet + 802.1ad service tag + 802.1Q customer tag + IPv4 + UDP with 4 bytes of payload
+    let frame: Vec<u8> = vec![
+        0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xaa, 0xbb, 0x88,
+        0xa8, // Ethernet
+        0x00, 0x64, 0x81, 0x00, // Service tag
+        0x20, 0xc8, 0x08, 0x00, // Customer tag
+        0x45, 0x00, 0x00, 0x20, 0x00, 0x01, 0x00, 0x00, 0x40, 0x11, 0x00, 0x00, 10, 0, 0, 1, 10, 0,
+        0, 2, // IPv4
+        0x04, 0xd2, 0x00, 0x35, 0x00, 0x0c, 0x00, 0x00, // UDP
+        0x01, 0x02, 0x03, 0x04,
+    ];
+    let indicator = Indicator::from(&frame).unwrap();
+    let tags = indicator.ethernet().unwrap().tags();
+    assert_eq!(tags.len(), 2);
+    assert_eq!((tags[0].vid(), tags[1].vid()), (100, 200));
+    assert_eq!(indicator.udp().unwrap().dst(), 53);
+    assert_eq!(indicator.link_len(), 22);
+    assert_eq!(indicator.content_len(), frame.len());
+
+    // Replies are re-emitted with the same tags
+    let mut buffer = vec![0u8; indicator.len() + 4];
+    indicator
+        .serialize_with_payload(&mut buffer, &[0x01, 0x02, 0x03, 0x04])
+        .unwrap();
+    assert_eq!(&buffer[12..22], &frame[12..22]);
+    assert_eq!(Indicator::from(&buffer).unwrap().udp().unwrap().src(), 1234);
+
+    // Truncated tags
+    assert_eq!(
+        Indicator::from(&frame[..20]).err(),
+        Some(ParseError::Truncated(LayerKinds::Ethernet))
+    );
+}
+
+#[cfg(feature = "defrag")]
+#[test]
+fn defraggler_add_bounded() {
+    use crate::testing::FrameBuilder;
+    use std::net::SocketAddrV4;
+
+    let dst = "1.1.1.1:53".parse().unwrap();
+
+    // Incomplete groups of fragments never exceed the limit
+    let mut defraggler = Defraggler::new();
+    for i in 0..(MAX_FRAGMENTATIONS + 16) {
+        let src = SocketAddrV4::new(Ipv4Addr::new(10, 6, (i / 256) as u8, i as u8), 50000);
+        let fragments = FrameBuilder::new(src, dst)
+            .identification(1)
+            .udp_fragments(&[0u8; 32], 16);
+        let indicator = Indicator::from(&fragments[0]).unwrap();
+        let frame = &fragments[0][..indicator.content_len()];
+        if i < MAX_FRAGMENTATIONS {
+            assert!(defraggler.add(&indicator, frame).unwrap().is_none());
         } else {
-            None
+            assert_eq!(
+                defraggler.add(&indicator, frame).err(),
+                Some(DefragError::Capacity)
+            );
         }
     }
+    assert_eq!(defraggler.frags.len(), MAX_FRAGMENTATIONS);
+
+    // Groups of fragments being reassembled are still completed
+    let src = "10.6.0.1:50000".parse().unwrap();
+    let fragments = FrameBuilder::new(src, dst)
+        .identification(1)
+        .udp_fragments(&[0u8; 32], 16);
+    let mut frag = None;
+    for fragment in &fragments[1..] {
+        let indicator = Indicator::from(fragment).unwrap();
+        frag = defraggler
+            .add(&indicator, &fragment[..indicator.content_len()])
+            .unwrap();
+    }
+    let frag = frag.unwrap();
+    let (transport, payload) = frag.concatenate().unwrap();
+    assert!(matches!(transport, Some(Layers::Udp(_))));
+    assert_eq!(payload, &[0u8; 32][..]);
+}
+
+#[cfg(feature = "defrag")]
+#[test]
+fn defraggler_add_hostile() {
+    use crate::testing::FrameBuilder;
+    use std::net::SocketAddrV4;
+
+    let src = "10.6.0.1:50000".parse().unwrap();
+    let dst = "1.1.1.1:53".parse().unwrap();
+    let builder = FrameBuilder::new(src, dst);
+    let add = |defraggler: &mut Defraggler, fragment: &[u8]| {
+        let indicator = Indicator::from(fragment).unwrap();
+        defraggler
+            .add(&indicator, &fragment[..indicator.content_len()])
+            .map(|frag| frag.is_some())
+    };
+
+    // Duplicate fragments are ignored, but overlapping fragments drop the whole group
+    let mut defraggler = Defraggler::new();
+    let fragments = builder.udp_fragments(&[0u8; 32], 16);
+    let overlapping = builder.udp_fragments(&[0xffu8; 32], 24);
+    assert_eq!(add(&mut defraggler, &fragments[0]), Ok(false));
+    assert_eq!(add(&mut defraggler, &fragments[0]), Ok(false));
+    assert_eq!(
+        add(&mut defraggler, &overlapping[0]),
+        Err(DefragError::Overlap)
+    );
+    assert!(defraggler.frags.is_empty());
+    assert_eq!(add(&mut defraggler, &fragments[2]), Ok(false));
+    assert_eq!(
+        add(&mut defraggler, &overlapping[1]),
+        Err(DefragError::Overlap)
+    );
+    assert!(defraggler.frags.is_empty());
+
+    // A source cannot occupy all the groups
+    for identification in 0..MAX_SOURCE_FRAGMENTATIONS as u16 {
+        let fragments = builder
+            .clone()
+            .identification(identification)
+            .udp_fragments(&[0u8; 32], 16);
+        assert_eq!(add(&mut defraggler, &fragments[0]), Ok(false));
+    }
+    let fragments = builder
+        .clone()
+        .identification(MAX_SOURCE_FRAGMENTATIONS as u16)
+        .udp_fragments(&[0u8; 32], 16);
+    assert_eq!(
+        add(&mut defraggler, &fragments[0]),
+        Err(DefragError::SourceLimit)
+    );
+    let other = FrameBuilder::new("10.6.0.2:50000".parse().unwrap(), dst);
+    let fragments = other.udp_fragments(&[0u8; 32], 16);
+    assert_eq!(add(&mut defraggler, &fragments[0]), Ok(false));
+
+    // Fragments at the end of large packets cannot exhaust the memory
+    let mut defraggler = Defraggler::new();
+    let payload = vec![0u8; 65000];
+    let mut results = Vec::new();
+    for i in 0..64 {
+        let src = SocketAddrV4::new(Ipv4Addr::new(10, 6, 1, i / 8), 50000);
+        let fragments = FrameBuilder::new(src, dst)
+            .identification(i as u16)
+            .udp_fragments(&payload, 8192);
+        results.push(add(&mut defraggler, fragments.last().unwrap()));
+    }
+    assert!(results[0..32].iter().all(|result| *result == Ok(false)));
+    assert!(results[32..]
+        .iter()
+        .all(|result| *result == Err(DefragError::Capacity)));
+    assert!(defraggler.memory() <= MAX_FRAGMENTATION_MEMORY);
 }
@@ -0,0 +1,171 @@
+//! Support for answering NetBIOS name queries
+//! ([RFC 1002](https://tools.ietf.org/html/rfc1002)), which are broadcast by Windows hosts and
+//! LAN games to discover hosts by names.
+
+use std::net::Ipv4Addr;
+
+/// Represents the UDP port of the NetBIOS name service.
+pub const NBNS_PORT: u16 = 137;
+
+/// Represents the length of a NetBIOS name, including the suffix.
+const NAME_LEN: usize = 16;
+/// Represents the length of a NetBIOS name in the first-level encoding.
+const ENCODED_NAME_LEN: usize = NAME_LEN * 2;
+/// Represents the length of the header.
+const HEADER_LEN: usize = 12;
+
+/// Represents the flag of responses.
+const FLAG_RESPONSE: u16 = 0x8000;
+/// Represents the mask of the opcode.
+const OPCODE_MASK: u16 = 0x7800;
+/// Represents the flag of authoritative answers.
+const FLAG_AUTHORITATIVE: u16 = 0x0400;
+/// Represents the flag of recursion desired.
+const FLAG_RECURSION_DESIRED: u16 = 0x0100;
+
+/// Represents the question type of NetBIOS general names.
+const TYPE_NB: u16 = 0x0020;
+/// Represents the question class of Internet.
+const CLASS_IN: u16 = 0x0001;
+
+/// Represents the TTL of answers in seconds.
+const ANSWER_TTL: u32 = 300;
+
+/// Represents a NetBIOS name query request.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct NameQuery {
+    id: u16,
+    flags: u16,
+    /// Represents the encoded name in the question, with its length and the empty scope.
+    question: Vec<u8>,
+    name: String,
+    suffix: u8,
+}
+
+impl NameQuery {
+    /// Parses a NetBIOS name query request from the UDP payload. Returns `None` if the payload is
+    /// not a name query request of a NetBIOS general name.
+    pub fn parse(payload: &[u8]) -> Option<NameQuery> {
+        if payload.len() < HEADER_LEN + 1 + ENCODED_NAME_LEN + 1 + 4 {
+            return None;
+        }
+
+        // Header
+        let id = u16::from_be_bytes([payload[0], payload[1]]);
+        let flags = u16::from_be_bytes([payload[2], payload[3]]);
+        if flags & (FLAG_RESPONSE | OPCODE_MASK) != 0 {
+            return None;
+        }
+        let questions = u16::from_be_bytes([payload[4], payload[5]]);
+        if questions != 1 {
+            return None;
+        }
+
+        // Question, whose scope is always empty in practice
+        let question = &payload[HEADER_LEN..HEADER_LEN + 1 + ENCODED_NAME_LEN + 1];
+        if question[0] as usize != ENCODED_NAME_LEN || question[ENCODED_NAME_LEN + 1] != 0 {
+            return None;
+        }
+        let mut decoded = [0u8; NAME_LEN];
+        for (i, pair) in question[1..1 + ENCODED_NAME_LEN].chunks(2).enumerate() {
+            if !(b'A'..=b'P').contains(&pair[0]) || !(b'A'..=b'P').contains(&pair[1]) {
+                return None;
+            }
+            decoded[i] = ((pair[0] - b'A') << 4) | (pair[1] - b'A');
+        }
+        let i = HEADER_LEN + question.len();
+        let kind = u16::from_be_bytes([payload[i], payload[i + 1]]);
+        let class = u16::from_be_bytes([payload[i + 2], payload[i + 3]]);
+        if kind != TYPE_NB || class != CLASS_IN {
+            return None;
+        }
+
+        let name = String::from_utf8_lossy(&decoded[..NAME_LEN - 1])
+            .trim_end()
+            .to_string();
+
+        Some(NameQuery {
+            id,
+            flags,
+            question: question.to_vec(),
+            name,
+            suffix: decoded[NAME_LEN - 1],
+        })
+    }
+
+    /// Returns the name queried, without the padding and the suffix.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the suffix of the name queried, which indicates the service like workstations or
+    /// file servers.
+    pub fn suffix(&self) -> u8 {
+        self.suffix
+    }
+
+    /// Returns if the query is for the name, which is case insensitive.
+    pub fn is_match(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name)
+    }
+
+    /// Returns the positive name query response of the query with the address as a unique name of
+    /// a B-node.
+    pub fn answer(&self, addr: Ipv4Addr) -> Vec<u8> {
+        let flags = FLAG_RESPONSE | FLAG_AUTHORITATIVE | (self.flags & FLAG_RECURSION_DESIRED);
+
+        let mut buffer = Vec::with_capacity(HEADER_LEN + self.question.len() + 16);
+        buffer.extend_from_slice(&self.id.to_be_bytes());
+        buffer.extend_from_slice(&flags.to_be_bytes());
+        buffer.extend_from_slice(&0u16.to_be_bytes());
+        buffer.extend_from_slice(&1u16.to_be_bytes());
+        buffer.extend_from_slice(&0u16.to_be_bytes());
+        buffer.extend_from_slice(&0u16.to_be_bytes());
+        // Answer
+        buffer.extend_from_slice(&self.question);
+        buffer.extend_from_slice(&TYPE_NB.to_be_bytes());
+        buffer.extend_from_slice(&CLASS_IN.to_be_bytes());
+        buffer.extend_from_slice(&ANSWER_TTL.to_be_bytes());
+        buffer.extend_from_slice(&6u16.to_be_bytes());
+        buffer.extend_from_slice(&0u16.to_be_bytes());
+        buffer.extend_from_slice(&addr.octets());
+
+        buffer
+    }
+}
+
+#[test]
+fn netbios_name_query() {
+    // Name query of "GAMEHOST<00>" broadcast by Windows
+    let mut payload = vec![
+        0x8a, 0x21, 0x01, 0x10, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x20,
+    ];
+    payload.extend_from_slice(b"EHEBENEFEIEPFDFECACACACACACACAAA");
+    payload.extend_from_slice(&[0x00, 0x00, 0x20, 0x00, 0x01]);
+
+    let query = NameQuery::parse(&payload).unwrap();
+    assert_eq!(query.name(), "GAMEHOST");
+    assert_eq!(query.suffix(), 0x00);
+    assert!(query.is_match("gamehost"));
+    assert!(!query.is_match("game"));
+
+    let answer = query.answer(Ipv4Addr::new(10, 6, 0, 2));
+    assert_eq!(
+        &answer[..12],
+        &[0x8a, 0x21, 0x85, 0x00, 0, 0, 0, 1, 0, 0, 0, 0]
+    );
+    assert_eq!(&answer[12..46], &payload[12..46]);
+    assert_eq!(
+        &answer[46..],
+        &[0x00, 0x20, 0x00, 0x01, 0, 0, 0x01, 0x2c, 0x00, 0x06, 0, 0, 10, 6, 0, 2]
+    );
+
+    // Responses and other questions are not queries
+    let mut response = payload.clone();
+    response[2] |= 0x80;
+    assert!(NameQuery::parse(&response).is_none());
+    let mut status = payload.clone();
+    status[47] = 0x21;
+    assert!(NameQuery::parse(&status).is_none());
+    assert!(NameQuery::parse(&payload[..40]).is_none());
+}
@@ -0,0 +1,130 @@
+//! Support for inspecting QUIC packets.
+
+/// Represents the max length of a QUIC connection ID.
+pub const MAX_CONN_ID_LEN: usize = 20;
+
+/// Represents the header form bit of a QUIC packet.
+const HEADER_FORM_BIT: u8 = 0x80;
+/// Represents the fixed bit of a QUIC packet.
+const FIXED_BIT: u8 = 0x40;
+
+/// Represents the header of a QUIC packet ([RFC 9000](https://tools.ietf.org/html/rfc9000)).
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum QuicHeader {
+    /// Represents a long header, which is used before the connection is established.
+    Long {
+        version: u32,
+        dst_conn_id: Vec<u8>,
+        src_conn_id: Vec<u8>,
+    },
+    /// Represents a short header. The length of the destination connection ID is not carried in
+    /// the header, and it can only be matched with known connection IDs.
+    Short,
+}
+
+impl QuicHeader {
+    /// Parses the header of a QUIC packet from the UDP payload. Returns `None` if the payload is
+    /// not likely a QUIC packet.
+    pub fn parse(payload: &[u8]) -> Option<QuicHeader> {
+        let first = *payload.first()?;
+        if first & FIXED_BIT == 0 {
+            return None;
+        }
+
+        if first & HEADER_FORM_BIT == 0 {
+            return Some(QuicHeader::Short);
+        }
+
+        // Version
+        if payload.len() < 6 {
+            return None;
+        }
+        let mut version_bytes = [0u8; 4];
+        version_bytes.copy_from_slice(&payload[1..5]);
+        let version = u32::from_be_bytes(version_bytes);
+        // Version negotiation
+        if version == 0 {
+            return None;
+        }
+
+        // Destination connection ID
+        let dst_len = payload[5] as usize;
+        if dst_len > MAX_CONN_ID_LEN || payload.len() < 7 + dst_len {
+            return None;
+        }
+        let dst_conn_id = payload[6..6 + dst_len].to_vec();
+
+        // Source connection ID
+        let src_len = payload[6 + dst_len] as usize;
+        if src_len > MAX_CONN_ID_LEN || payload.len() < 7 + dst_len + src_len {
+            return None;
+        }
+        let src_conn_id = payload[7 + dst_len..7 + dst_len + src_len].to_vec();
+
+        Some(QuicHeader::Long {
+            version,
+            dst_conn_id,
+            src_conn_id,
+        })
+    }
+
+    /// Returns if the header is a long header.
+    pub fn is_long(&self) -> bool {
+        match self {
+            QuicHeader::Long { .. } => true,
+            QuicHeader::Short => false,
+        }
+    }
+
+    /// Returns if the packet with the header is destined to the given connection ID.
+    pub fn is_destined_to(&self, payload: &[u8], conn_id: &[u8]) -> bool {
+        match self {
+            QuicHeader::Long { dst_conn_id, .. } => dst_conn_id.as_slice() == conn_id,
+            QuicHeader::Short => payload.len() > conn_id.len() && payload[1..].starts_with(conn_id),
+        }
+    }
+}
+
+#[test]
+fn quic_parse_long() {
+    let payload = [
+        0xc3, 0x00, 0x00, 0x00, 0x01, 0x04, 0x01, 0x02, 0x03, 0x04, 0x02, 0x05, 0x06, 0x00,
+    ];
+    let header = QuicHeader::parse(&payload).unwrap();
+
+    assert_eq!(
+        header,
+        QuicHeader::Long {
+            version: 1,
+            dst_conn_id: vec![1, 2, 3, 4],
+            src_conn_id: vec![5, 6]
+        }
+    );
+    assert!(header.is_destined_to(&payload, &[1, 2, 3, 4]));
+}
+
+#[test]
+fn quic_parse_short() {
+    let payload = [0x41, 0x01, 0x02, 0x03, 0x04, 0xff];
+    let header = QuicHeader::parse(&payload).unwrap();
+
+    assert_eq!(header, QuicHeader::Short);
+    assert!(header.is_destined_to(&payload, &[1, 2, 3, 4]));
+    assert!(!header.is_destined_to(&payload, &[2, 3, 4, 5]));
+}
+
+#[test]
+fn quic_parse_invalid() {
+    assert_eq!(QuicHeader::parse(&[]), None);
+    assert_eq!(QuicHeader::parse(&[0x00, 0x01]), None);
+    // Version negotiation
+    assert_eq!(
+        QuicHeader::parse(&[0xc0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]),
+        None
+    );
+    // Truncated connection ID
+    assert_eq!(
+        QuicHeader::parse(&[0xc0, 0x00, 0x00, 0x00, 0x01, 0x08, 0x01]),
+        None
+    );
+}
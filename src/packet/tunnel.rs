@@ -0,0 +1,61 @@
+//! Support for classifying tunneled IPv4 packets.
+
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use std::fmt::{self, Display, Formatter};
+
+/// Represents the protocol of a tunnel carried directly in IPv4, which cannot be redirected to a
+/// proxy.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum TunnelProtocol {
+    /// Represents Generic Routing Encapsulation, used by PPTP and others.
+    Gre,
+    /// Represents IPsec Encapsulating Security Payload and Authentication Header.
+    Ipsec,
+    /// Represents IPv6 encapsulated in IPv4 (6in4).
+    Ipv6,
+}
+
+impl TunnelProtocol {
+    /// Returns the tunnel protocol of the given next level protocol of IPv4. Returns `None` if
+    /// the protocol is not a tunnel.
+    pub fn from(protocol: IpNextHeaderProtocol) -> Option<TunnelProtocol> {
+        match protocol {
+            IpNextHeaderProtocols::Gre => Some(TunnelProtocol::Gre),
+            IpNextHeaderProtocols::Esp | IpNextHeaderProtocols::Ah => Some(TunnelProtocol::Ipsec),
+            IpNextHeaderProtocols::Ipv6 => Some(TunnelProtocol::Ipv6),
+            _ => None,
+        }
+    }
+}
+
+impl Display for TunnelProtocol {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            TunnelProtocol::Gre => write!(f, "GRE"),
+            TunnelProtocol::Ipsec => write!(f, "IPsec"),
+            TunnelProtocol::Ipv6 => write!(f, "6in4"),
+        }
+    }
+}
+
+#[test]
+fn tunnel_protocol_from() {
+    assert_eq!(
+        TunnelProtocol::from(IpNextHeaderProtocol::new(47)),
+        Some(TunnelProtocol::Gre)
+    );
+    assert_eq!(
+        TunnelProtocol::from(IpNextHeaderProtocol::new(50)),
+        Some(TunnelProtocol::Ipsec)
+    );
+    assert_eq!(
+        TunnelProtocol::from(IpNextHeaderProtocol::new(51)),
+        Some(TunnelProtocol::Ipsec)
+    );
+    assert_eq!(
+        TunnelProtocol::from(IpNextHeaderProtocol::new(41)),
+        Some(TunnelProtocol::Ipv6)
+    );
+    assert_eq!(TunnelProtocol::from(IpNextHeaderProtocols::Udp), None);
+    assert_eq!(TunnelProtocol::from(IpNextHeaderProtocols::Igmp), None);
+}
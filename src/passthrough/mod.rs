@@ -0,0 +1,112 @@
+//! Support for forwarding tunneled packets to a peer.
+
+use log::{info, trace, warn};
+use pnet::packet::ip::IpNextHeaderProtocol;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::{Arc, Mutex};
+use tokio::io;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+use crate::packet::tunnel::TunnelProtocol;
+use crate::Forwarder;
+
+/// Represents the capacity of the queue of packets forwarded to the peer.
+const PASSTHROUGH_QUEUE_SIZE: usize = 1024;
+
+/// Represents a passthrough which forwards tunneled IPv4 packets as is to the peer in UDP
+/// datagrams, and forwards tunneled IPv4 packets replied by the peer in the same way to sources.
+pub(crate) struct Passthrough {
+    tx: mpsc::Sender<Vec<u8>>,
+}
+
+impl Passthrough {
+    /// Opens a passthrough to the peer.
+    pub(crate) async fn open(
+        peer: SocketAddrV4,
+        tx: Arc<Mutex<Forwarder>>,
+    ) -> io::Result<Passthrough> {
+        let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)).await?;
+        socket.connect(peer).await?;
+
+        let (packet_tx, packet_rx) = mpsc::channel(PASSTHROUGH_QUEUE_SIZE);
+        tokio::spawn(run(socket, packet_rx, tx));
+        info!("Forward tunneled packets to {}", peer);
+
+        Ok(Passthrough { tx: packet_tx })
+    }
+
+    /// Sends an IPv4 packet to the peer.
+    pub(crate) fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+        match self.tx.try_send(packet.to_vec()) {
+            Ok(_) => Ok(()),
+            Err(TrySendError::Full(_)) => {
+                trace!("drop tunneled packet ({} Bytes): queue full", packet.len());
+
+                Ok(())
+            }
+            Err(TrySendError::Closed(_)) => Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "passthrough is closed",
+            )),
+        }
+    }
+}
+
+async fn run(socket: UdpSocket, mut rx: mpsc::Receiver<Vec<u8>>, tx: Arc<Mutex<Forwarder>>) {
+    let (mut socket_rx, mut socket_tx) = socket.split();
+    let mut buffer = vec![0u8; u16::MAX as usize];
+
+    loop {
+        tokio::select! {
+            packet = rx.recv() => match packet {
+                Some(packet) => {
+                    if let Err(ref e) = socket_tx.send(&packet).await {
+                        warn!("send to passthrough: {}", e);
+                    }
+                }
+                None => break,
+            },
+            result = socket_rx.recv(&mut buffer) => match result {
+                Ok(size) => {
+                    let packet = &buffer[..size];
+                    if !is_tunneled(packet) {
+                        trace!("drop passthrough packet ({} Bytes): not tunneled", size);
+                        continue;
+                    }
+                    if let Err(ref e) = tx.lock().unwrap().send_ipv4_packet(packet) {
+                        warn!("handle passthrough packet: {}", e);
+                    }
+                }
+                Err(ref e) => warn!("receive from passthrough: {}", e),
+            },
+        }
+    }
+    trace!("close passthrough");
+}
+
+/// Returns if the packet is a tunneled IPv4 packet, so the peer cannot inject other traffic to
+/// sources.
+fn is_tunneled(packet: &[u8]) -> bool {
+    if packet.len() < 20 || packet[0] >> 4 != 4 {
+        return false;
+    }
+
+    TunnelProtocol::from(IpNextHeaderProtocol::new(packet[9])).is_some()
+}
+
+#[test]
+fn passthrough_is_tunneled() {
+    let mut packet = [
+        0x45, 0x00, 0x00, 0x18, 0x00, 0x01, 0x00, 0x00, 0x40, 0x2f, 0x00, 0x00, 10, 0, 0, 1, 10, 0,
+        0, 2, 0x00, 0x00, 0x08, 0x00,
+    ];
+    assert!(is_tunneled(&packet));
+    assert!(!is_tunneled(&packet[..19]));
+    packet[9] = 0x11;
+    assert!(!is_tunneled(&packet));
+    packet[9] = 0x32;
+    assert!(is_tunneled(&packet));
+    packet[0] = 0x65;
+    assert!(!is_tunneled(&packet));
+}
@@ -0,0 +1,289 @@
+//! Support for injecting network faults into a pcap device, so the retransmission, the SACK and
+//! the fast retransmit of the TCP stack can be validated under loss, duplication, delay and
+//! reordering.
+//!
+//! Faults are injected into frames between the receive half and the `Redirector`, and between the
+//! `Forwarder` and the send half. Faults are drawn from a seeded random number generator, and
+//! delays are measured by the clock of the current thread, so a scenario can be reproduced.
+
+use pnet::datalink::{self, DataLinkReceiver, DataLinkSender};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::VecDeque;
+use std::io;
+use std::time::{Duration, Instant};
+
+use super::{Receiver, Sender};
+use crate::clock;
+
+/// Represents the probabilities and the parameters of network faults.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChaosOption {
+    seed: u64,
+    drop: f64,
+    duplicate: f64,
+    delay: f64,
+    delay_time: Duration,
+    reorder: f64,
+}
+
+impl ChaosOption {
+    /// Creates a new `ChaosOption` which injects no fault with the seed of the random number
+    /// generator.
+    pub fn new(seed: u64) -> ChaosOption {
+        ChaosOption {
+            seed,
+            drop: 0.0,
+            duplicate: 0.0,
+            delay: 0.0,
+            delay_time: Duration::from_millis(0),
+            reorder: 0.0,
+        }
+    }
+
+    /// Sets the probability of dropping a frame.
+    pub fn drop(mut self, probability: f64) -> ChaosOption {
+        self.drop = probability;
+
+        self
+    }
+
+    /// Sets the probability of duplicating a frame.
+    pub fn duplicate(mut self, probability: f64) -> ChaosOption {
+        self.duplicate = probability;
+
+        self
+    }
+
+    /// Sets the probability of delaying a frame, and the time it is delayed by.
+    pub fn delay(mut self, probability: f64, time: Duration) -> ChaosOption {
+        self.delay = probability;
+        self.delay_time = time;
+
+        self
+    }
+
+    /// Sets the probability of holding a frame until the next frame passes.
+    pub fn reorder(mut self, probability: f64) -> ChaosOption {
+        self.reorder = probability;
+
+        self
+    }
+}
+
+/// Represents an injector of network faults into a sequence of frames.
+#[derive(Debug)]
+struct Chaos {
+    option: ChaosOption,
+    rng: StdRng,
+    /// Represents the frames passed and the instants they are released.
+    queue: VecDeque<(Instant, Vec<u8>)>,
+    /// Represents the frame held to be reordered.
+    held: Option<Vec<u8>>,
+}
+
+impl Chaos {
+    fn new(option: ChaosOption) -> Chaos {
+        Chaos {
+            rng: StdRng::seed_from_u64(option.seed),
+            option,
+            queue: VecDeque::new(),
+            held: None,
+        }
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        probability > 0.0 && self.rng.gen::<f64>() < probability
+    }
+
+    /// Injects faults into the frame.
+    fn push(&mut self, frame: Vec<u8>) {
+        if self.chance(self.option.drop) {
+            return;
+        }
+        if self.held.is_none() && self.chance(self.option.reorder) {
+            self.held = Some(frame);
+            return;
+        }
+
+        let mut instant = clock::now();
+        if self.chance(self.option.delay) {
+            instant += self.option.delay_time;
+        }
+        if self.chance(self.option.duplicate) {
+            self.queue.push_back((instant, frame.clone()));
+        }
+        self.queue.push_back((instant, frame));
+
+        // The held frame follows the frame after it
+        if let Some(held) = self.held.take() {
+            self.queue.push_back((instant, held));
+        }
+    }
+
+    /// Releases the held frame, and the delayed frames immediately if `is_forced` is set.
+    fn flush(&mut self, is_forced: bool) {
+        if let Some(held) = self.held.take() {
+            self.queue.push_back((clock::now(), held));
+        }
+        if is_forced {
+            let now = clock::now();
+            for (instant, _) in self.queue.iter_mut() {
+                *instant = now;
+            }
+        }
+    }
+
+    /// Returns the first frame which is released.
+    fn pop(&mut self) -> Option<Vec<u8>> {
+        let now = clock::now();
+        let index = self.queue.iter().position(|(instant, _)| *instant <= now)?;
+
+        self.queue.remove(index).map(|(_, frame)| frame)
+    }
+}
+
+/// Represents the send half of a pcap device with network faults injected. A delayed frame is
+/// sent along with the first frame sent after its delay elapses.
+pub struct ChaosSender {
+    tx: Sender,
+    chaos: Chaos,
+}
+
+impl ChaosSender {
+    fn send_released(&mut self) -> io::Result<()> {
+        while let Some(frame) = self.chaos.pop() {
+            self.tx.send_to(&frame, None).unwrap_or(Ok(()))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl DataLinkSender for ChaosSender {
+    fn build_and_send(
+        &mut self,
+        num_packets: usize,
+        packet_size: usize,
+        func: &mut dyn FnMut(&mut [u8]),
+    ) -> Option<io::Result<()>> {
+        for _ in 0..num_packets {
+            let mut buffer = vec![0u8; packet_size];
+            func(&mut buffer);
+            self.chaos.push(buffer);
+        }
+
+        Some(self.send_released())
+    }
+
+    fn send_to(
+        &mut self,
+        packet: &[u8],
+        _: Option<datalink::NetworkInterface>,
+    ) -> Option<io::Result<()>> {
+        self.chaos.push(packet.to_vec());
+
+        Some(self.send_released())
+    }
+}
+
+/// Represents the receive half of a pcap device with network faults injected. A held or delayed
+/// frame is released once a read times out, and all of them are released before an error other
+/// than `TimedOut` is reported.
+pub struct ChaosReceiver {
+    rx: Receiver,
+    chaos: Chaos,
+    buffer: Vec<u8>,
+}
+
+impl DataLinkReceiver for ChaosReceiver {
+    fn next(&mut self) -> io::Result<&[u8]> {
+        loop {
+            if let Some(frame) = self.chaos.pop() {
+                self.buffer = frame;
+
+                return Ok(&self.buffer);
+            }
+
+            let e = match self.rx.next() {
+                Ok(frame) => {
+                    let frame = frame.to_vec();
+                    self.chaos.push(frame);
+                    continue;
+                }
+                Err(e) => e,
+            };
+            let is_timed_out = e.kind() == io::ErrorKind::TimedOut;
+            self.chaos.flush(!is_timed_out);
+            if let Some(frame) = self.chaos.pop() {
+                self.buffer = frame;
+
+                return Ok(&self.buffer);
+            }
+
+            return Err(e);
+        }
+    }
+}
+
+/// Injects network faults into a pcap device, and returns its send half and its receive half
+/// with faults injected. Each half draws faults independently with the options.
+pub fn chaos(tx: Sender, rx: Receiver, option: ChaosOption) -> (Sender, Receiver) {
+    (
+        Box::new(ChaosSender {
+            tx,
+            chaos: Chaos::new(option.clone()),
+        }),
+        Box::new(ChaosReceiver {
+            rx,
+            chaos: Chaos::new(option),
+            buffer: Vec::new(),
+        }),
+    )
+}
+
+#[test]
+fn chaos_inject() {
+    use super::memory;
+    use crate::clock::VirtualClock;
+    use std::sync::Arc;
+
+    let clock = VirtualClock::new();
+    let _clock = clock::enter(Some(Arc::new(clock.clone())));
+
+    // Duplicated and reordered frames
+    let (tx, rx, mut loopback) = memory();
+    let option = ChaosOption::new(0).duplicate(1.0).reorder(1.0);
+    let (_, mut rx) = chaos(tx, rx, option);
+    loopback.inject(&[1]);
+    loopback.inject(&[2]);
+    loopback.close();
+    let mut frames = Vec::new();
+    while let Ok(frame) = rx.next() {
+        frames.push(frame.to_vec());
+    }
+    assert_eq!(frames, vec![vec![2], vec![2], vec![1]]);
+
+    // Delayed frames
+    let (tx, rx, loopback) = memory();
+    let option = ChaosOption::new(0).delay(1.0, Duration::from_millis(200));
+    let (mut tx, _) = chaos(tx, rx, option);
+    tx.send_to(&[1], None).unwrap().unwrap();
+    assert_eq!(loopback.try_recv(), None);
+    clock.advance(Duration::from_millis(200));
+    tx.send_to(&[2], None).unwrap().unwrap();
+    assert_eq!(loopback.sent(), vec![vec![1]]);
+
+    // Dropped frames are drawn from the seed
+    let count = |seed| {
+        let (tx, rx, loopback) = memory();
+        let (mut tx, _) = chaos(tx, rx, ChaosOption::new(seed).drop(0.5));
+        for i in 0..100u8 {
+            tx.send_to(&[i], None).unwrap().unwrap();
+        }
+        loopback.sent().len()
+    };
+    let sent = count(1);
+    assert!(sent > 20 && sent < 80);
+    assert_eq!(count(1), sent);
+}
@@ -0,0 +1,205 @@
+//! Support for sending and receiving frames with a TUN/TAP device opened by another process.
+
+use pnet::datalink::{DataLinkReceiver, DataLinkSender, MacAddr, NetworkInterface};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+
+use super::{HardwareAddr, Receiver, Sender, READ_TIMEOUT, TUN_HARDWARE_ADDR};
+
+/// Represents the kind of a TUN/TAP device.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum DeviceKind {
+    /// Represents a TUN device, which carries IP packets without packet information.
+    Tun,
+    /// Represents a TAP device, which carries Ethernet frames.
+    Tap,
+}
+
+/// Represents the size of the Ethernet header added to IP packets from a TUN device.
+const ETHERNET_HEADER_SIZE: usize = 14;
+
+/// Represents the size of each receive buffer.
+const RECV_BUFFER_SIZE: usize = 65536;
+
+/// Represents the EtherType of IPv4.
+const ETHER_TYPE_IPV4: u16 = 0x0800;
+/// Represents the EtherType of IPv6.
+const ETHER_TYPE_IPV6: u16 = 0x86dd;
+
+/// Creates a channel from the file descriptor of a TUN/TAP device, which may be opened by a
+/// privileged process or provided by the platform like `VpnService` in Android. Packets from a
+/// TUN device are framed in Ethernet as if they are sent from `TUN_HARDWARE_ADDR` to the
+/// `hardware_addr`, and only IPv4 packets are written back to the device.
+///
+/// # Safety
+///
+/// The channel takes the ownership of the file descriptor, which must be open and must not be
+/// closed elsewhere.
+pub unsafe fn from_raw_fd(
+    fd: RawFd,
+    kind: DeviceKind,
+    hardware_addr: HardwareAddr,
+) -> io::Result<(Sender, Receiver)> {
+    let file = File::from_raw_fd(fd);
+
+    let tx = FdSender {
+        file: file.try_clone()?,
+        kind,
+    };
+    let rx = FdReceiver {
+        file,
+        kind,
+        hardware_addr,
+        buffer: vec![0u8; ETHERNET_HEADER_SIZE + RECV_BUFFER_SIZE],
+    };
+
+    Ok((Box::new(tx), Box::new(rx)))
+}
+
+/// Represents a send half of a TUN/TAP device.
+struct FdSender {
+    file: File,
+    kind: DeviceKind,
+}
+
+impl FdSender {
+    fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+        let packet = match self.kind {
+            DeviceKind::Tap => frame,
+            DeviceKind::Tun => {
+                // Strip the Ethernet header, and drop frames like ARP which a TUN cannot carry
+                if frame.len() < ETHERNET_HEADER_SIZE
+                    || u16::from_be_bytes([frame[12], frame[13]]) != ETHER_TYPE_IPV4
+                {
+                    return Ok(());
+                }
+
+                &frame[ETHERNET_HEADER_SIZE..]
+            }
+        };
+
+        self.file.write(packet)?;
+
+        Ok(())
+    }
+}
+
+impl DataLinkSender for FdSender {
+    fn build_and_send(
+        &mut self,
+        num_packets: usize,
+        packet_size: usize,
+        func: &mut dyn FnMut(&mut [u8]),
+    ) -> Option<io::Result<()>> {
+        let mut buffer = vec![0u8; packet_size];
+        for _ in 0..num_packets {
+            func(&mut buffer);
+            if let Err(e) = self.send(&buffer) {
+                return Some(Err(e));
+            }
+        }
+
+        Some(Ok(()))
+    }
+
+    fn send_to(&mut self, packet: &[u8], _: Option<NetworkInterface>) -> Option<io::Result<()>> {
+        Some(self.send(packet))
+    }
+}
+
+/// Represents a receive half of a TUN/TAP device.
+struct FdReceiver {
+    file: File,
+    kind: DeviceKind,
+    hardware_addr: HardwareAddr,
+    buffer: Vec<u8>,
+}
+
+impl FdReceiver {
+    fn poll(&self) -> io::Result<()> {
+        let mut pollfd = libc::pollfd {
+            fd: self.file.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+
+        match unsafe { libc::poll(&mut pollfd, 1, READ_TIMEOUT as libc::c_int) } {
+            0 => Err(io::Error::from(io::ErrorKind::TimedOut)),
+            n if n < 0 => {
+                let e = io::Error::last_os_error();
+                // A signal should not stop the caller
+                if e.kind() == io::ErrorKind::Interrupted {
+                    Err(io::Error::from(io::ErrorKind::TimedOut))
+                } else {
+                    Err(e)
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl DataLinkReceiver for FdReceiver {
+    fn next(&mut self) -> io::Result<&[u8]> {
+        self.poll()?;
+
+        match self.kind {
+            DeviceKind::Tap => {
+                let size = self.file.read(&mut self.buffer)?;
+
+                Ok(&self.buffer[..size])
+            }
+            DeviceKind::Tun => {
+                let size = self.file.read(&mut self.buffer[ETHERNET_HEADER_SIZE..])?;
+                if size == 0 {
+                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+                }
+
+                // Ethernet
+                let ether_type = match self.buffer[ETHERNET_HEADER_SIZE] >> 4 {
+                    6 => ETHER_TYPE_IPV6,
+                    _ => ETHER_TYPE_IPV4,
+                };
+                self.buffer[..6].copy_from_slice(&octets(self.hardware_addr));
+                self.buffer[6..12].copy_from_slice(&octets(TUN_HARDWARE_ADDR));
+                self.buffer[12..14].copy_from_slice(&ether_type.to_be_bytes());
+
+                Ok(&self.buffer[..ETHERNET_HEADER_SIZE + size])
+            }
+        }
+    }
+}
+
+fn octets(hardware_addr: HardwareAddr) -> [u8; 6] {
+    let MacAddr(a, b, c, d, e, f) = hardware_addr;
+
+    [a, b, c, d, e, f]
+}
+
+#[test]
+fn from_raw_fd_tun() {
+    use std::os::unix::io::IntoRawFd;
+    use std::os::unix::net::UnixDatagram;
+
+    let (device, peer) = UnixDatagram::pair().unwrap();
+    let hardware_addr = MacAddr(0x00, 0x11, 0x22, 0x33, 0x44, 0x55);
+    let (mut tx, mut rx) =
+        unsafe { from_raw_fd(device.into_raw_fd(), DeviceKind::Tun, hardware_addr) }.unwrap();
+
+    let packet = [0x45, 0x00, 0x00, 0x14];
+    peer.send(&packet).unwrap();
+    let frame = rx.next().unwrap().to_vec();
+    assert_eq!(&frame[..6], &octets(hardware_addr));
+    assert_eq!(&frame[12..14], &[0x08, 0x00]);
+    assert_eq!(&frame[ETHERNET_HEADER_SIZE..], &packet);
+
+    // ARP is dropped
+    let mut arp = frame.clone();
+    arp[12..14].copy_from_slice(&[0x08, 0x06]);
+    tx.send_to(&arp, None).unwrap().unwrap();
+    tx.send_to(&frame, None).unwrap().unwrap();
+    let mut buffer = [0u8; 16];
+    let size = peer.recv(&mut buffer).unwrap();
+    assert_eq!(&buffer[..size], &packet);
+}
@@ -0,0 +1,244 @@
+//! Support for sending and receiving frames on interfaces whose link type is not Ethernet.
+
+use pnet::datalink::{DataLinkReceiver, DataLinkSender, MacAddr, NetworkInterface};
+use std::fmt::{self, Display, Formatter};
+use std::io;
+
+use super::{HardwareAddr, Receiver, Sender, TUN_HARDWARE_ADDR};
+
+/// Represents the size of the Ethernet header added to packets from the link.
+const ETHERNET_HEADER_SIZE: usize = 14;
+/// Represents the size of the header of NULL/loopback encapsulation.
+const NULL_HEADER_SIZE: usize = 4;
+
+/// Represents the size of each receive buffer.
+const RECV_BUFFER_SIZE: usize = 65536;
+
+/// Represents the EtherType of IPv4.
+const ETHER_TYPE_IPV4: u16 = 0x0800;
+/// Represents the EtherType of IPv6.
+const ETHER_TYPE_IPV6: u16 = 0x86dd;
+
+/// Represents the address family of IPv4 in NULL/loopback encapsulation.
+const AF_INET: u32 = 2;
+/// Represents the address families of IPv6 in NULL/loopback encapsulation, which differ among
+/// BSDs.
+const AF_INET6: [u32; 3] = [24, 28, 30];
+
+/// Represents the link type of an interface, which is the datalink type of its captures.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum LinkType {
+    /// Represents Ethernet (`DLT_EN10MB`).
+    Ethernet,
+    /// Represents raw IP without any link-layer header (`DLT_RAW`), like TUN devices.
+    Raw,
+    /// Represents NULL/loopback encapsulation with a 4-byte address family header (`DLT_NULL`
+    /// and `DLT_LOOP`), like loopback interfaces of BSDs.
+    Null,
+}
+
+impl Display for LinkType {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            LinkType::Ethernet => write!(f, "Ethernet"),
+            LinkType::Raw => write!(f, "Raw IP"),
+            LinkType::Null => write!(f, "NULL/Loopback"),
+        }
+    }
+}
+
+/// Creates a channel on top of the channel of a link. Packets from the link are framed in
+/// Ethernet as if they are sent from `TUN_HARDWARE_ADDR` to the `hardware_addr`, and only IPv4
+/// frames are sent to the link without their Ethernet headers. A channel on an Ethernet link is
+/// returned as is.
+pub fn channel(
+    tx: Sender,
+    rx: Receiver,
+    link_type: LinkType,
+    hardware_addr: HardwareAddr,
+) -> (Sender, Receiver) {
+    if link_type == LinkType::Ethernet {
+        return (tx, rx);
+    }
+
+    let tx = LinkSender { tx, link_type };
+    let rx = LinkReceiver {
+        rx,
+        link_type,
+        hardware_addr,
+        buffer: vec![0u8; ETHERNET_HEADER_SIZE + RECV_BUFFER_SIZE],
+    };
+
+    (Box::new(tx), Box::new(rx))
+}
+
+/// Represents a send half of a link.
+struct LinkSender {
+    tx: Sender,
+    link_type: LinkType,
+}
+
+impl DataLinkSender for LinkSender {
+    fn build_and_send(
+        &mut self,
+        num_packets: usize,
+        packet_size: usize,
+        func: &mut dyn FnMut(&mut [u8]),
+    ) -> Option<io::Result<()>> {
+        let mut buffer = vec![0u8; packet_size];
+        for _ in 0..num_packets {
+            func(&mut buffer);
+            if let Some(Err(e)) = self.send_to(&buffer, None) {
+                return Some(Err(e));
+            }
+        }
+
+        Some(Ok(()))
+    }
+
+    fn send_to(&mut self, packet: &[u8], dst: Option<NetworkInterface>) -> Option<io::Result<()>> {
+        match from_ethernet(self.link_type, packet) {
+            Some(packet) => self.tx.send_to(&packet, dst),
+            // Frames like ARP cannot be carried by the link
+            None => Some(Ok(())),
+        }
+    }
+}
+
+/// Represents a receive half of a link.
+struct LinkReceiver {
+    rx: Receiver,
+    link_type: LinkType,
+    hardware_addr: HardwareAddr,
+    buffer: Vec<u8>,
+}
+
+impl DataLinkReceiver for LinkReceiver {
+    fn next(&mut self) -> io::Result<&[u8]> {
+        loop {
+            let packet = self.rx.next()?;
+            if let Some(size) =
+                to_ethernet(self.link_type, packet, self.hardware_addr, &mut self.buffer)
+            {
+                return Ok(&self.buffer[..size]);
+            }
+        }
+    }
+}
+
+/// Frames the packet of the link in Ethernet into the buffer, and returns the size of the frame.
+/// Returns `None` if the packet is malformed or is not IP.
+fn to_ethernet(
+    link_type: LinkType,
+    packet: &[u8],
+    hardware_addr: HardwareAddr,
+    buffer: &mut [u8],
+) -> Option<usize> {
+    let (ether_type, payload) = match link_type {
+        LinkType::Ethernet => return None,
+        LinkType::Raw => {
+            let ether_type = match packet.first()? >> 4 {
+                4 => ETHER_TYPE_IPV4,
+                6 => ETHER_TYPE_IPV6,
+                _ => return None,
+            };
+
+            (ether_type, packet)
+        }
+        LinkType::Null => {
+            if packet.len() < NULL_HEADER_SIZE {
+                return None;
+            }
+            // The family is in the host byte order in DLT_NULL, but in the network byte order
+            // in DLT_LOOP
+            let header = [packet[0], packet[1], packet[2], packet[3]];
+            let families = [u32::from_ne_bytes(header), u32::from_be_bytes(header)];
+            let ether_type = if families.contains(&AF_INET) {
+                ETHER_TYPE_IPV4
+            } else if families.iter().any(|family| AF_INET6.contains(family)) {
+                ETHER_TYPE_IPV6
+            } else {
+                return None;
+            };
+
+            (ether_type, &packet[NULL_HEADER_SIZE..])
+        }
+    };
+    if ETHERNET_HEADER_SIZE + payload.len() > buffer.len() {
+        return None;
+    }
+
+    // Ethernet
+    buffer[..6].copy_from_slice(&octets(hardware_addr));
+    buffer[6..12].copy_from_slice(&octets(TUN_HARDWARE_ADDR));
+    buffer[12..14].copy_from_slice(&ether_type.to_be_bytes());
+    buffer[ETHERNET_HEADER_SIZE..ETHERNET_HEADER_SIZE + payload.len()].copy_from_slice(payload);
+
+    Some(ETHERNET_HEADER_SIZE + payload.len())
+}
+
+/// Strips the Ethernet header of the frame and encapsulates it for the link. Returns `None` if
+/// the frame is not IPv4.
+fn from_ethernet(link_type: LinkType, frame: &[u8]) -> Option<Vec<u8>> {
+    if frame.len() < ETHERNET_HEADER_SIZE
+        || u16::from_be_bytes([frame[12], frame[13]]) != ETHER_TYPE_IPV4
+    {
+        return None;
+    }
+    let payload = &frame[ETHERNET_HEADER_SIZE..];
+
+    match link_type {
+        LinkType::Ethernet => Some(frame.to_vec()),
+        LinkType::Raw => Some(payload.to_vec()),
+        LinkType::Null => {
+            let mut packet = Vec::with_capacity(NULL_HEADER_SIZE + payload.len());
+            packet.extend_from_slice(&AF_INET.to_ne_bytes());
+            packet.extend_from_slice(payload);
+
+            Some(packet)
+        }
+    }
+}
+
+fn octets(hardware_addr: HardwareAddr) -> [u8; 6] {
+    let MacAddr(a, b, c, d, e, f) = hardware_addr;
+
+    [a, b, c, d, e, f]
+}
+
+#[test]
+fn link_null_and_raw() {
+    let hardware_addr = MacAddr(0x00, 0x11, 0x22, 0x33, 0x44, 0x55);
+    let packet = [0x45, 0x00, 0x00, 0x14];
+    let mut buffer = [0u8; 32];
+
+    // Raw IP
+    let size = to_ethernet(LinkType::Raw, &packet, hardware_addr, &mut buffer).unwrap();
+    assert_eq!(&buffer[..6], &octets(hardware_addr));
+    assert_eq!(&buffer[12..14], &[0x08, 0x00]);
+    assert_eq!(&buffer[ETHERNET_HEADER_SIZE..size], &packet);
+    assert_eq!(
+        from_ethernet(LinkType::Raw, &buffer[..size]).unwrap(),
+        packet
+    );
+    assert!(to_ethernet(LinkType::Raw, &[], hardware_addr, &mut buffer).is_none());
+
+    // NULL/loopback in both byte orders
+    let mut null = AF_INET.to_ne_bytes().to_vec();
+    null.extend_from_slice(&packet);
+    let size = to_ethernet(LinkType::Null, &null, hardware_addr, &mut buffer).unwrap();
+    assert_eq!(&buffer[ETHERNET_HEADER_SIZE..size], &packet);
+    assert_eq!(
+        from_ethernet(LinkType::Null, &buffer[..size]).unwrap(),
+        null
+    );
+    null[..4].copy_from_slice(&AF_INET.to_be_bytes());
+    assert!(to_ethernet(LinkType::Null, &null, hardware_addr, &mut buffer).is_some());
+    null[..4].copy_from_slice(&30u32.to_be_bytes());
+    let size = to_ethernet(LinkType::Null, &null, hardware_addr, &mut buffer).unwrap();
+    assert_eq!(&buffer[12..14], &[0x86, 0xdd]);
+
+    // ARP is dropped
+    buffer[12..14].copy_from_slice(&[0x08, 0x06]);
+    assert!(from_ethernet(LinkType::Null, &buffer[..size]).is_none());
+}
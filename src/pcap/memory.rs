@@ -0,0 +1,138 @@
+//! Support for an in-memory pcap device, so a `Redirector` and its `Forwarder` can be exercised
+//! end-to-end by injecting frames and inspecting the frames sent, without root or real NICs.
+
+use pnet::datalink::{self, DataLinkReceiver, DataLinkSender};
+use std::io;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::Duration;
+
+use super::{Receiver, Sender, READ_TIMEOUT};
+
+/// Represents the send half of an in-memory pcap device.
+pub struct MemorySender {
+    tx: mpsc::Sender<Vec<u8>>,
+}
+
+impl DataLinkSender for MemorySender {
+    fn build_and_send(
+        &mut self,
+        num_packets: usize,
+        packet_size: usize,
+        func: &mut dyn FnMut(&mut [u8]),
+    ) -> Option<io::Result<()>> {
+        for _ in 0..num_packets {
+            let mut buffer = vec![0u8; packet_size];
+            func(&mut buffer);
+            // Frames sent after the `Loopback` is dropped are discarded
+            let _ = self.tx.send(buffer);
+        }
+
+        Some(Ok(()))
+    }
+
+    fn send_to(
+        &mut self,
+        packet: &[u8],
+        _: Option<datalink::NetworkInterface>,
+    ) -> Option<io::Result<()>> {
+        let _ = self.tx.send(packet.to_vec());
+
+        Some(Ok(()))
+    }
+}
+
+/// Represents the receive half of an in-memory pcap device. Like a pcap device, receiving times
+/// out if there is no frame, and reports an error of the kind `UnexpectedEof` once the
+/// `Loopback` is closed and all the frames injected are received.
+pub struct MemoryReceiver {
+    rx: mpsc::Receiver<Vec<u8>>,
+    buffer: Vec<u8>,
+}
+
+impl DataLinkReceiver for MemoryReceiver {
+    fn next(&mut self) -> io::Result<&[u8]> {
+        match self.rx.recv_timeout(Duration::from_millis(READ_TIMEOUT)) {
+            Ok(frame) => {
+                self.buffer = frame;
+
+                Ok(&self.buffer)
+            }
+            Err(RecvTimeoutError::Timeout) => Err(io::Error::from(io::ErrorKind::TimedOut)),
+            Err(RecvTimeoutError::Disconnected) => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "loopback closed",
+            )),
+        }
+    }
+}
+
+/// Represents the other end of an in-memory pcap device, which injects frames to its receive
+/// half and collects frames from its send half.
+pub struct Loopback {
+    tx: Option<mpsc::Sender<Vec<u8>>>,
+    rx: mpsc::Receiver<Vec<u8>>,
+}
+
+impl Loopback {
+    /// Injects a frame to the receive half.
+    pub fn inject(&self, frame: &[u8]) {
+        if let Some(ref tx) = self.tx {
+            let _ = tx.send(frame.to_vec());
+        }
+    }
+
+    /// Closes the receive half once the frames injected are received. Frames can still be
+    /// collected after the close.
+    pub fn close(&mut self) {
+        self.tx = None;
+    }
+
+    /// Returns the next frame sent from the send half if there is any.
+    pub fn try_recv(&self) -> Option<Vec<u8>> {
+        self.rx.try_recv().ok()
+    }
+
+    /// Returns all the frames sent from the send half since the last collection.
+    pub fn sent(&self) -> Vec<Vec<u8>> {
+        self.rx.try_iter().collect()
+    }
+}
+
+/// Opens an in-memory pcap device, and returns its send half, its receive half and its other end.
+pub fn memory() -> (Sender, Receiver, Loopback) {
+    let (inject_tx, inject_rx) = mpsc::channel();
+    let (sent_tx, sent_rx) = mpsc::channel();
+
+    (
+        Box::new(MemorySender { tx: sent_tx }),
+        Box::new(MemoryReceiver {
+            rx: inject_rx,
+            buffer: Vec::new(),
+        }),
+        Loopback {
+            tx: Some(inject_tx),
+            rx: sent_rx,
+        },
+    )
+}
+
+#[test]
+fn loopback_inject() {
+    let (mut tx, mut rx, mut loopback) = memory();
+
+    loopback.inject(&[1, 2, 3]);
+    assert_eq!(rx.next().unwrap(), &[1, 2, 3]);
+    tx.send_to(&[4, 5], None).unwrap().unwrap();
+    tx.build_and_send(2, 3, &mut |buffer| buffer[0] = 6)
+        .unwrap()
+        .unwrap();
+    assert_eq!(loopback.try_recv(), Some(vec![4, 5]));
+    assert_eq!(loopback.sent(), vec![vec![6, 0, 0], vec![6, 0, 0]]);
+    assert_eq!(loopback.try_recv(), None);
+
+    // Frames injected before the close are still received
+    loopback.inject(&[7]);
+    loopback.close();
+    assert_eq!(rx.next().unwrap(), &[7]);
+    assert_eq!(rx.next().unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+}
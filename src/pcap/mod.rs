@@ -1,10 +1,14 @@
 //! Support for handling pcap interfaces.
 
-use pnet::datalink::{self, Channel, Config, DataLinkReceiver, DataLinkSender, MacAddr};
+use ipnetwork::Ipv4Network;
+use pnet::datalink::{self, Channel, Config, MacAddr};
 use std::clone::Clone;
 use std::fmt::{self, Display, Formatter};
 use std::io;
 use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 #[cfg(windows)]
 use netifs;
@@ -12,12 +16,52 @@ use netifs;
 #[cfg(not(windows))]
 use interfaces as c_interfaces;
 
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+use log::{debug, warn};
+
+mod chaos;
+#[cfg(unix)]
+mod fd;
+mod link;
+mod memory;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+mod uring;
+
+pub use chaos::{chaos, ChaosOption, ChaosReceiver, ChaosSender};
+#[cfg(unix)]
+pub use fd::{from_raw_fd, DeviceKind};
+pub use link::LinkType;
+pub use memory::{memory, Loopback, MemoryReceiver, MemorySender};
+/// Traits for the send half and the receive half of a capture backend, which are implemented to
+/// send and receive frames with devices other than pcap.
+pub use pnet::datalink::{DataLinkReceiver, DataLinkSender};
+
 /// Represents the hardware address MAC in an Ethernet network.
 pub type HardwareAddr = pnet::datalink::MacAddr;
 
 /// Represents the unspecified hardware address `00:00:00:00:00:00` in an Ethernet network.
 pub const HARDWARE_ADDR_UNSPECIFIED: HardwareAddr = pnet::datalink::MacAddr(0, 0, 0, 0, 0, 0);
 
+/// Represents the broadcast hardware address `ff:ff:ff:ff:ff:ff` in an Ethernet network.
+pub const HARDWARE_ADDR_BROADCAST: HardwareAddr =
+    pnet::datalink::MacAddr(0xff, 0xff, 0xff, 0xff, 0xff, 0xff);
+
+/// Represents the hardware address of sources behind a TUN device, or an interface whose link
+/// type is not Ethernet.
+pub const TUN_HARDWARE_ADDR: HardwareAddr =
+    pnet::datalink::MacAddr(0x02, 0x00, 0x00, 0x00, 0x00, 0x01);
+
+/// Represents the suffix of the name of the Npcap loopback adapter, which captures and injects
+/// the loopback traffic of the local machine.
+#[cfg(windows)]
+const NPCAP_LOOPBACK_NAME: &str = "NPF_Loopback";
+/// Represents the prefix of the alias of the legacy Npcap loopback adapter.
+#[cfg(windows)]
+const NPCAP_LOOPBACK_ALIAS: &str = "Npcap Loopback Adapter";
+/// Represents the MTU of the Npcap loopback adapter.
+#[cfg(windows)]
+const NPCAP_LOOPBACK_MTU: usize = 65535;
+
 /// Represents the send half of a pcap device.
 pub type Sender = Box<dyn DataLinkSender>;
 /// Represents the receive half of a pcap device.
@@ -26,16 +70,23 @@ pub type Receiver = Box<dyn DataLinkReceiver>;
 /// Represents the buffer size of pcap channels.
 const BUFFER_SIZE: usize = 256 * 1024;
 
+/// Represents the read timeout of pcap channels. A timed out read gives the caller a chance to
+/// do periodic work even if there is no traffic.
+const READ_TIMEOUT: u64 = 1000;
+
 /// Represents a network interface and its associated addresses.
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
 pub struct Interface {
     name: String,
     alias: Option<String>,
+    index: u32,
     hardware_addr: MacAddr,
     ip_addrs: Vec<Ipv4Addr>,
+    ip_networks: Vec<Ipv4Network>,
     mtu: usize,
     is_up: bool,
     is_loopback: bool,
+    link_type: LinkType,
 }
 
 impl Interface {
@@ -44,15 +95,19 @@ impl Interface {
         Interface {
             name: String::new(),
             alias: None,
+            index: 0,
             hardware_addr: MacAddr::zero(),
             ip_addrs: vec![],
+            ip_networks: vec![],
             mtu: 0,
             is_up: false,
             is_loopback: false,
+            link_type: LinkType::Ethernet,
         }
     }
 
-    /// Opens the network interface for sending and receiving data.
+    /// Opens the network interface for sending and receiving data. Frames are always in Ethernet
+    /// regardless of the link type of the interface.
     pub fn open(&self) -> io::Result<(Sender, Receiver)> {
         let inters = datalink::interfaces();
         let inter = inters
@@ -64,16 +119,29 @@ impl Interface {
                 "interface not found",
             ))?;
 
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        match uring::channel(&inter) {
+            Ok((tx, rx)) => {
+                debug!("open {} with io_uring", inter.name);
+                return Ok(link::channel(tx, rx, self.link_type, self.hardware_addr));
+            }
+            Err(ref e) => warn!(
+                "Cannot open {} with io_uring, fall back to pcap: {}",
+                inter.name, e
+            ),
+        }
+
         let mut config = Config::default();
         config.write_buffer_size = BUFFER_SIZE;
         config.read_buffer_size = BUFFER_SIZE;
+        config.read_timeout = Some(Duration::from_millis(READ_TIMEOUT));
         let channel = datalink::channel(&inter, config)?;
-        let channel = match channel {
+        let (tx, rx) = match channel {
             Channel::Ethernet(tx, rx) => (tx, rx),
             _ => return Err(io::Error::new(io::ErrorKind::Other, "unknown link type")),
         };
 
-        Ok(channel)
+        Ok(link::channel(tx, rx, self.link_type, self.hardware_addr))
     }
 
     /// Returns the name of the interface.
@@ -86,6 +154,32 @@ impl Interface {
         &self.alias
     }
 
+    /// Returns the index of the interface in the OS.
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    /// Returns if the interface is designated by the string, which is its hardware address like
+    /// `00:11:22:33:44:55` or `00-11-22-33-44-55`, its index, its alias case-insensitively, or a
+    /// fragment of its name like a part of the GUID of an Npcap device.
+    pub fn matches(&self, s: &str) -> bool {
+        if let Ok(hardware_addr) = s.replace('-', ":").parse::<MacAddr>() {
+            return self.hardware_addr == hardware_addr;
+        }
+        if let Ok(index) = s.parse::<u32>() {
+            return self.index == index;
+        }
+
+        let s = s.to_lowercase();
+        if let Some(ref alias) = self.alias {
+            if alias.to_lowercase() == s {
+                return true;
+            }
+        }
+        let fragment = s.trim_matches(|c| c == '{' || c == '}');
+        !fragment.is_empty() && self.name.to_lowercase().contains(fragment)
+    }
+
     /// Returns the hardware address of the interface.
     pub fn hardware_addr(&self) -> MacAddr {
         self.hardware_addr
@@ -100,11 +194,33 @@ impl Interface {
         }
     }
 
+    /// Returns if the IPv4 address is in a subnet of the interface.
+    pub fn contains(&self, ip_addr: Ipv4Addr) -> bool {
+        self.ip_networks
+            .iter()
+            .any(|network| network.prefix() > 0 && network.contains(ip_addr))
+    }
+
     /// Returns the MTU of the interface.
     pub fn mtu(&self) -> usize {
         self.mtu
     }
 
+    /// Returns the count of frames received by the interface but dropped by the kernel, like for
+    /// lack of buffers. Returns `None` if the count is not available in the platform.
+    pub fn kernel_drops(&self) -> Option<u64> {
+        #[cfg(target_os = "linux")]
+        return std::fs::read_to_string(format!(
+            "/sys/class/net/{}/statistics/rx_dropped",
+            self.name
+        ))
+        .ok()
+        .and_then(|s| s.trim().parse().ok());
+
+        #[cfg(not(target_os = "linux"))]
+        None
+    }
+
     /// Returns if the interface is up.
     pub fn is_up(&self) -> bool {
         self.is_up
@@ -114,6 +230,11 @@ impl Interface {
     pub fn is_loopback(&self) -> bool {
         self.is_loopback
     }
+
+    /// Returns the link type of the interface.
+    pub fn link_type(&self) -> LinkType {
+        self.link_type
+    }
 }
 
 impl Display for Interface {
@@ -135,6 +256,8 @@ impl Display for Interface {
         let mut flags = String::new();
         if self.is_loopback {
             flags = String::from(" (Loopback)");
+        } else if self.link_type != LinkType::Ethernet {
+            flags = format!(" ({})", self.link_type);
         }
 
         write!(
@@ -160,8 +283,12 @@ pub fn interfaces() -> Vec<Interface> {
 
             let mut i = Interface::new();
             i.name = inter.name.clone();
+            i.index = inter.index;
+            i.link_type = link_type(inter);
             i.hardware_addr = match inter.mac {
                 Some(mac) => mac,
+                // Links like TUN devices have no hardware addresses
+                None if i.link_type != LinkType::Ethernet => HARDWARE_ADDR_UNSPECIFIED,
                 None => return Err(()),
             };
             i.ip_addrs = inter
@@ -173,6 +300,20 @@ pub fn interfaces() -> Vec<Interface> {
                 })
                 .filter_map(Result::ok)
                 .collect();
+            i.ip_networks = inter
+                .ips
+                .iter()
+                .filter_map(|ip| match ip {
+                    ipnetwork::IpNetwork::V4(ref ipv4) => Some(*ipv4),
+                    _ => None,
+                })
+                .collect();
+
+            // Loopback links like the Npcap loopback adapter have no IPv4 address of their own, so
+            // any loopback address can be the source
+            if i.ip_addrs.is_empty() && i.link_type == LinkType::Null {
+                i.ip_addrs.push(Ipv4Addr::UNSPECIFIED);
+            }
 
             // Exclude interface without any IPv4 address
             if i.ip_addrs.len() <= 0 {
@@ -192,6 +333,40 @@ pub fn interfaces() -> Vec<Interface> {
     ifs
 }
 
+/// Returns the link type of the interface according to its ARP hardware type. Loopback
+/// interfaces of BSDs are framed in Ethernet by the datalink already.
+#[cfg(target_os = "linux")]
+fn link_type(inter: &datalink::NetworkInterface) -> LinkType {
+    // See ARPHRD_* in if_arp.h
+    const ARPHRD_PPP: u32 = 512;
+    const ARPHRD_RAWIP: u32 = 519;
+    const ARPHRD_NONE: u32 = 65534;
+
+    let t = std::fs::read_to_string(format!("/sys/class/net/{}/type", inter.name))
+        .ok()
+        .and_then(|t| t.trim().parse::<u32>().ok());
+    match t {
+        Some(ARPHRD_PPP) | Some(ARPHRD_RAWIP) | Some(ARPHRD_NONE) => LinkType::Raw,
+        _ => LinkType::Ethernet,
+    }
+}
+
+/// Returns the link type of the interface. The Npcap loopback adapter is in NULL/loopback
+/// encapsulation.
+#[cfg(windows)]
+fn link_type(inter: &datalink::NetworkInterface) -> LinkType {
+    if inter.name.ends_with(NPCAP_LOOPBACK_NAME) {
+        LinkType::Null
+    } else {
+        LinkType::Ethernet
+    }
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+fn link_type(_: &datalink::NetworkInterface) -> LinkType {
+    LinkType::Ethernet
+}
+
 #[cfg(windows)]
 fn mark_interfaces(mut ifs: Vec<Interface>) -> Vec<Interface> {
     if let Ok(sys_inters) = netifs::get_interfaces() {
@@ -207,6 +382,23 @@ fn mark_interfaces(mut ifs: Vec<Interface>) -> Vec<Interface> {
         }
     }
 
+    // The Npcap loopback adapter, including the legacy one which is a real adapter, is always
+    // up and is not reported as a loopback interface by the system
+    for i in &mut ifs {
+        let is_legacy = i
+            .alias
+            .as_ref()
+            .map_or(false, |alias| alias.starts_with(NPCAP_LOOPBACK_ALIAS));
+        if i.name.ends_with(NPCAP_LOOPBACK_NAME) || is_legacy {
+            i.link_type = LinkType::Null;
+            i.is_up = true;
+            i.is_loopback = true;
+            if i.mtu == 0 {
+                i.mtu = NPCAP_LOOPBACK_MTU;
+            }
+        }
+    }
+
     ifs
 }
 
@@ -227,6 +419,45 @@ fn mark_interfaces(mut ifs: Vec<Interface>) -> Vec<Interface> {
     ifs
 }
 
+/// Returns the name of the interface carrying the IPv4 default route, or `None` if there is no
+/// default route or it cannot be determined in the platform.
+pub fn default_route_interface() -> Option<String> {
+    #[cfg(target_os = "linux")]
+    return std::fs::read_to_string("/proc/net/route")
+        .ok()
+        .and_then(|routes| parse_default_route(&routes));
+
+    #[cfg(not(target_os = "linux"))]
+    None
+}
+
+/// Parses the routing table in the format of `/proc/net/route`, and returns the interface of the
+/// default route with the lowest metric.
+#[cfg(any(target_os = "linux", test))]
+fn parse_default_route(routes: &str) -> Option<String> {
+    // See RTF_UP in route.h
+    const RTF_UP: u32 = 0x0001;
+
+    routes
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<_> = line.split_whitespace().collect();
+            if fields.len() < 8 {
+                return None;
+            }
+            let flags = u32::from_str_radix(fields[3], 16).ok()?;
+            let metric = fields[6].parse::<u32>().ok()?;
+            if fields[1] != "00000000" || fields[7] != "00000000" || flags & RTF_UP == 0 {
+                return None;
+            }
+
+            Some((metric, fields[0]))
+        })
+        .min_by_key(|&(metric, _)| metric)
+        .map(|(_, name)| name.to_string())
+}
+
 /// Represents a virtual send half which will discard all incoming traffic.
 #[derive(Debug)]
 pub struct BlackHole {}
@@ -256,3 +487,97 @@ impl DataLinkSender for BlackHole {
         Some(Ok(()))
     }
 }
+
+/// Represents a send half of a pcap device which can be shared by multiple `Forwarder`s.
+#[derive(Clone)]
+pub struct SharedSender {
+    tx: Arc<Mutex<Sender>>,
+}
+
+impl SharedSender {
+    /// Creates a new `SharedSender`.
+    pub fn new(tx: Sender) -> SharedSender {
+        SharedSender {
+            tx: Arc::new(Mutex::new(tx)),
+        }
+    }
+}
+
+impl DataLinkSender for SharedSender {
+    fn build_and_send(
+        &mut self,
+        num_packets: usize,
+        packet_size: usize,
+        func: &mut dyn FnMut(&mut [u8]),
+    ) -> Option<io::Result<()>> {
+        self.tx
+            .lock()
+            .unwrap()
+            .build_and_send(num_packets, packet_size, func)
+    }
+
+    fn send_to(
+        &mut self,
+        packet: &[u8],
+        dst: Option<datalink::NetworkInterface>,
+    ) -> Option<io::Result<()>> {
+        self.tx.lock().unwrap().send_to(packet, dst)
+    }
+}
+
+/// Represents a receive half of a pcap device which can be stopped from another thread. Once
+/// stopped, receiving reports an error of the kind `Interrupted`.
+pub struct StoppableReceiver {
+    rx: Receiver,
+    is_stopped: Arc<AtomicBool>,
+}
+
+impl StoppableReceiver {
+    /// Creates a new `StoppableReceiver` which is stopped once `is_stopped` is set.
+    pub fn new(rx: Receiver, is_stopped: Arc<AtomicBool>) -> StoppableReceiver {
+        StoppableReceiver { rx, is_stopped }
+    }
+}
+
+impl DataLinkReceiver for StoppableReceiver {
+    fn next(&mut self) -> io::Result<&[u8]> {
+        if self.is_stopped.load(Ordering::Relaxed) {
+            return Err(io::Error::new(io::ErrorKind::Interrupted, "stopped"));
+        }
+
+        self.rx.next()
+    }
+}
+
+#[test]
+fn parse_default_route_metric() {
+    let routes =
+        "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT\n\
+                  wlan0\t00000000\t0101A8C0\t0003\t0\t0\t600\t00000000\t0\t0\t0\n\
+                  eth0\t00000000\t0100000A\t0003\t0\t0\t100\t00000000\t0\t0\t0\n\
+                  eth1\t0000A8C0\t00000000\t0001\t0\t0\t0\t00FFFFFF\t0\t0\t0\n";
+    assert_eq!(parse_default_route(routes), Some(String::from("eth0")));
+    assert_eq!(
+        parse_default_route(&routes[..routes.find("eth0").unwrap()]),
+        Some(String::from("wlan0"))
+    );
+    assert_eq!(parse_default_route("Iface\tDestination\n"), None);
+}
+
+#[test]
+fn interface_matches() {
+    let mut inter = Interface::new();
+    inter.name = String::from("\\Device\\NPF_{6B1C4E2A-91D3-4F0E-8A7B-2C5D9E0F1A3B}");
+    inter.alias = Some(String::from("Ethernet 2"));
+    inter.index = 12;
+    inter.hardware_addr = MacAddr::new(0x00, 0x11, 0x22, 0x33, 0x44, 0x55);
+
+    assert!(inter.matches("00:11:22:33:44:55"));
+    assert!(inter.matches("00-11-22-33-44-55"));
+    assert!(!inter.matches("00:11:22:33:44:66"));
+    assert!(inter.matches("12"));
+    assert!(!inter.matches("1"));
+    assert!(inter.matches("ethernet 2"));
+    assert!(inter.matches("{6b1c4e2a-91d3"));
+    assert!(!inter.matches("Ethernet 3"));
+}
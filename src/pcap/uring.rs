@@ -0,0 +1,665 @@
+//! Support for sending and receiving frames with io_uring in Linux.
+
+use pnet::datalink::{DataLinkReceiver, DataLinkSender, NetworkInterface};
+use std::io;
+use std::mem;
+use std::os::unix::io::RawFd;
+use std::ptr;
+use std::sync::atomic::{self, AtomicU32, Ordering};
+use std::sync::Arc;
+
+use super::{Receiver, Sender, BUFFER_SIZE, READ_TIMEOUT};
+
+/// Represents the count of submission queue entries of each io_uring. It also limits the count
+/// of frames being sent at the same time.
+const RING_ENTRIES: u32 = 256;
+
+/// Represents the idle time of the kernel thread polling the submission queue before it sleeps.
+const SQ_THREAD_IDLE: u32 = 50;
+
+/// Represents the size of each receive buffer.
+const RECV_BUFFER_SIZE: usize = 65536;
+
+/// Represents the user data of cancellations.
+const CANCEL_USER_DATA: u64 = u64::max_value();
+
+/// Represents the count of waits for in-flight operations before dropping an io_uring.
+const MAX_DROP_WAIT: usize = 10;
+
+// Kernel ABI in <linux/io_uring.h> and <linux/if_packet.h>
+const IORING_SETUP_SQPOLL: u32 = 1 << 1;
+const IORING_FEAT_SINGLE_MMAP: u32 = 1 << 0;
+const IORING_FEAT_NODROP: u32 = 1 << 1;
+const IORING_FEAT_FAST_POLL: u32 = 1 << 5;
+const IORING_OFF_SQ_RING: libc::off_t = 0;
+const IORING_OFF_SQES: libc::off_t = 0x1000_0000;
+const IORING_ENTER_SQ_WAKEUP: u32 = 1 << 1;
+const IORING_SQ_NEED_WAKEUP: u32 = 1 << 0;
+const IORING_REGISTER_FILES: u32 = 2;
+const IOSQE_FIXED_FILE: u8 = 1 << 0;
+const IORING_OP_ASYNC_CANCEL: u8 = 14;
+const IORING_OP_SEND: u8 = 26;
+const IORING_OP_RECV: u8 = 27;
+const PACKET_ADD_MEMBERSHIP: libc::c_int = 1;
+const PACKET_MR_PROMISC: libc::c_ushort = 1;
+
+#[repr(C)]
+#[derive(Default)]
+struct SqringOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    resv2: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct CqringOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    resv2: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct Params {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: SqringOffsets,
+    cq_off: CqringOffsets,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct Sqe {
+    opcode: u8,
+    flags: u8,
+    ioprio: u16,
+    fd: i32,
+    off: u64,
+    addr: u64,
+    len: u32,
+    op_flags: u32,
+    user_data: u64,
+    buf_index: u16,
+    personality: u16,
+    splice_fd_in: i32,
+    pad: [u64; 2],
+}
+
+#[repr(C)]
+struct Cqe {
+    user_data: u64,
+    res: i32,
+    flags: u32,
+}
+
+#[repr(C)]
+struct PacketMreq {
+    mr_ifindex: libc::c_int,
+    mr_type: libc::c_ushort,
+    mr_alen: libc::c_ushort,
+    mr_address: [libc::c_uchar; 8],
+}
+
+fn cvt(ret: libc::c_long) -> io::Result<libc::c_long> {
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+/// Represents a raw packet socket bound to an interface.
+#[derive(Debug)]
+struct Socket(RawFd);
+
+impl Socket {
+    /// Opens a raw packet socket bound to the given interface in promiscuous mode.
+    fn open(inter: &NetworkInterface) -> io::Result<Socket> {
+        let protocol = (libc::ETH_P_ALL as u16).to_be();
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_PACKET,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                protocol as libc::c_int,
+            )
+        };
+        cvt(fd as libc::c_long)?;
+        let socket = Socket(fd);
+
+        let mut addr: libc::sockaddr_ll = unsafe { mem::zeroed() };
+        addr.sll_family = libc::AF_PACKET as libc::c_ushort;
+        addr.sll_protocol = protocol;
+        addr.sll_ifindex = inter.index as libc::c_int;
+        cvt(unsafe {
+            libc::bind(
+                fd,
+                &addr as *const libc::sockaddr_ll as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t,
+            )
+        } as libc::c_long)?;
+
+        let mreq = PacketMreq {
+            mr_ifindex: inter.index as libc::c_int,
+            mr_type: PACKET_MR_PROMISC,
+            mr_alen: 0,
+            mr_address: [0; 8],
+        };
+        socket.set_option(libc::SOL_PACKET, PACKET_ADD_MEMBERSHIP, &mreq)?;
+        let size = BUFFER_SIZE as libc::c_int;
+        socket.set_option(libc::SOL_SOCKET, libc::SO_RCVBUF, &size)?;
+        socket.set_option(libc::SOL_SOCKET, libc::SO_SNDBUF, &size)?;
+
+        Ok(socket)
+    }
+
+    fn set_option<T>(&self, level: libc::c_int, name: libc::c_int, value: &T) -> io::Result<()> {
+        cvt(unsafe {
+            libc::setsockopt(
+                self.0,
+                level,
+                name,
+                value as *const T as *const libc::c_void,
+                mem::size_of::<T>() as libc::socklen_t,
+            )
+        } as libc::c_long)?;
+
+        Ok(())
+    }
+}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.0);
+        }
+    }
+}
+
+/// Represents an io_uring with the socket registered as its only fixed file.
+struct Ring {
+    fd: RawFd,
+    ring: *mut libc::c_void,
+    ring_len: usize,
+    sqes: *mut Sqe,
+    sqes_len: usize,
+    sq_head: *const AtomicU32,
+    sq_tail: *const AtomicU32,
+    sq_flags: *const AtomicU32,
+    sq_mask: u32,
+    sq_entries: u32,
+    cq_head: *const AtomicU32,
+    cq_tail: *const AtomicU32,
+    cq_mask: u32,
+    cqes: *const Cqe,
+    is_sqpoll: bool,
+    pending: u32,
+}
+
+// The pointers only point to the memory mapped for the io_uring which is owned by `Ring`
+unsafe impl Send for Ring {}
+
+impl Ring {
+    /// Creates a new `Ring` for the socket. The submission queue is polled by a kernel thread if
+    /// permitted.
+    fn new(socket: &Socket) -> io::Result<Ring> {
+        let mut params = Params::default();
+        params.flags = IORING_SETUP_SQPOLL;
+        params.sq_thread_idle = SQ_THREAD_IDLE;
+        let fd = match Ring::setup(&mut params) {
+            Ok(fd) => fd,
+            // Polling the submission queue requires CAP_SYS_ADMIN before Linux 5.11
+            Err(ref e) if e.raw_os_error() == Some(libc::EPERM) => {
+                params = Params::default();
+                Ring::setup(&mut params)?
+            }
+            Err(e) => return Err(e),
+        };
+
+        let features = IORING_FEAT_SINGLE_MMAP | IORING_FEAT_NODROP | IORING_FEAT_FAST_POLL;
+        if params.features & features != features {
+            unsafe {
+                libc::close(fd);
+            }
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "io_uring is not fully supported",
+            ));
+        }
+
+        // Map the submission and completion queue in a single mapping
+        let ring_len = (params.sq_off.array as usize + params.sq_entries as usize * 4)
+            .max(params.cq_off.cqes as usize + params.cq_entries as usize * mem::size_of::<Cqe>());
+        let ring = unsafe { Ring::map(fd, ring_len, IORING_OFF_SQ_RING) };
+        if ring == libc::MAP_FAILED {
+            let e = io::Error::last_os_error();
+            unsafe {
+                libc::close(fd);
+            }
+            return Err(e);
+        }
+        let sqes_len = params.sq_entries as usize * mem::size_of::<Sqe>();
+        let sqes = unsafe { Ring::map(fd, sqes_len, IORING_OFF_SQES) };
+        if sqes == libc::MAP_FAILED {
+            let e = io::Error::last_os_error();
+            unsafe {
+                libc::munmap(ring, ring_len);
+                libc::close(fd);
+            }
+            return Err(e);
+        }
+
+        let at = |offset: u32| unsafe { (ring as *mut u8).add(offset as usize) };
+        let ring = unsafe {
+            let sq_array = at(params.sq_off.array) as *mut u32;
+            for i in 0..params.sq_entries {
+                *sq_array.add(i as usize) = i;
+            }
+
+            Ring {
+                fd,
+                ring,
+                ring_len,
+                sqes: sqes as *mut Sqe,
+                sqes_len,
+                sq_head: at(params.sq_off.head) as *const AtomicU32,
+                sq_tail: at(params.sq_off.tail) as *const AtomicU32,
+                sq_flags: at(params.sq_off.flags) as *const AtomicU32,
+                sq_mask: *(at(params.sq_off.ring_mask) as *const u32),
+                sq_entries: params.sq_entries,
+                cq_head: at(params.cq_off.head) as *const AtomicU32,
+                cq_tail: at(params.cq_off.tail) as *const AtomicU32,
+                cq_mask: *(at(params.cq_off.ring_mask) as *const u32),
+                cqes: at(params.cq_off.cqes) as *const Cqe,
+                is_sqpoll: params.flags & IORING_SETUP_SQPOLL != 0,
+                pending: 0,
+            }
+        };
+
+        // Register the socket
+        let fds = [socket.0];
+        cvt(unsafe {
+            libc::syscall(
+                libc::SYS_io_uring_register,
+                fd,
+                IORING_REGISTER_FILES,
+                fds.as_ptr(),
+                fds.len() as libc::c_uint,
+            )
+        })?;
+
+        Ok(ring)
+    }
+
+    fn setup(params: &mut Params) -> io::Result<RawFd> {
+        let fd = cvt(unsafe {
+            libc::syscall(
+                libc::SYS_io_uring_setup,
+                RING_ENTRIES,
+                params as *mut Params,
+            )
+        })?;
+
+        Ok(fd as RawFd)
+    }
+
+    unsafe fn map(fd: RawFd, len: usize, offset: libc::off_t) -> *mut libc::c_void {
+        libc::mmap(
+            ptr::null_mut(),
+            len,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED | libc::MAP_POPULATE,
+            fd,
+            offset,
+        )
+    }
+
+    /// Pushes an entry to the submission queue. Returns if the entry is pushed.
+    fn push(&mut self, opcode: u8, addr: u64, len: u32, user_data: u64) -> bool {
+        let (head, tail) = unsafe {
+            (
+                (*self.sq_head).load(Ordering::Acquire),
+                (*self.sq_tail).load(Ordering::Relaxed),
+            )
+        };
+        if tail.wrapping_sub(head) >= self.sq_entries {
+            return false;
+        }
+
+        let sqe = Sqe {
+            opcode,
+            flags: if opcode == IORING_OP_ASYNC_CANCEL {
+                0
+            } else {
+                IOSQE_FIXED_FILE
+            },
+            // Index of the socket in the fixed files
+            fd: 0,
+            addr,
+            len,
+            user_data,
+            ..Sqe::default()
+        };
+        unsafe {
+            ptr::write(self.sqes.add((tail & self.sq_mask) as usize), sqe);
+            (*self.sq_tail).store(tail.wrapping_add(1), Ordering::Release);
+        }
+        self.pending += 1;
+
+        true
+    }
+
+    /// Submits pushed entries. If the submission queue is polled by a kernel thread, the system
+    /// call is only made to wake the thread up.
+    fn submit(&mut self) -> io::Result<()> {
+        let (to_submit, flags) = if self.is_sqpoll {
+            atomic::fence(Ordering::SeqCst);
+            if unsafe { (*self.sq_flags).load(Ordering::Relaxed) } & IORING_SQ_NEED_WAKEUP == 0 {
+                self.pending = 0;
+                return Ok(());
+            }
+            (0, IORING_ENTER_SQ_WAKEUP)
+        } else {
+            if self.pending == 0 {
+                return Ok(());
+            }
+            (self.pending, 0)
+        };
+
+        let submitted = cvt(unsafe {
+            libc::syscall(
+                libc::SYS_io_uring_enter,
+                self.fd,
+                to_submit,
+                0 as libc::c_uint,
+                flags,
+                ptr::null::<libc::sigset_t>(),
+                0 as libc::size_t,
+            )
+        })?;
+        if self.is_sqpoll {
+            self.pending = 0;
+        } else {
+            self.pending = self.pending.saturating_sub(submitted as u32);
+        }
+
+        Ok(())
+    }
+
+    /// Pops an entry from the completion queue.
+    fn pop(&mut self) -> Option<Cqe> {
+        unsafe {
+            let head = (*self.cq_head).load(Ordering::Relaxed);
+            let tail = (*self.cq_tail).load(Ordering::Acquire);
+            if head == tail {
+                return None;
+            }
+
+            let cqe = ptr::read(self.cqes.add((head & self.cq_mask) as usize));
+            (*self.cq_head).store(head.wrapping_add(1), Ordering::Release);
+
+            Some(cqe)
+        }
+    }
+
+    /// Waits for entries in the completion queue. Returns if there are entries before timeout.
+    fn wait(&mut self, timeout: u64) -> io::Result<bool> {
+        let mut pollfd = libc::pollfd {
+            fd: self.fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        match unsafe { libc::poll(&mut pollfd, 1, timeout as libc::c_int) } {
+            -1 => {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::Interrupted {
+                    Ok(false)
+                } else {
+                    Err(e)
+                }
+            }
+            0 => Ok(false),
+            _ => Ok(true),
+        }
+    }
+}
+
+impl Drop for Ring {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.sqes as *mut libc::c_void, self.sqes_len);
+            libc::munmap(self.ring, self.ring_len);
+            libc::close(self.fd);
+        }
+    }
+}
+
+/// Represents the send half of a raw packet socket using io_uring. Frames are copied into
+/// buffers and sent asynchronously, and an error in sending is reported by the next send.
+pub struct UringSender {
+    ring: Ring,
+    buffers: Vec<Vec<u8>>,
+    free: Vec<usize>,
+    error: Option<io::Error>,
+    _socket: Arc<Socket>,
+}
+
+impl UringSender {
+    fn new(socket: Arc<Socket>) -> io::Result<UringSender> {
+        let ring = Ring::new(&socket)?;
+        let entries = ring.sq_entries as usize;
+
+        Ok(UringSender {
+            ring,
+            buffers: vec![Vec::new(); entries],
+            free: (0..entries).collect(),
+            error: None,
+            _socket: socket,
+        })
+    }
+
+    fn reap(&mut self) {
+        while let Some(cqe) = self.ring.pop() {
+            if cqe.user_data == CANCEL_USER_DATA {
+                continue;
+            }
+            self.free.push(cqe.user_data as usize);
+            if cqe.res < 0 && self.error.is_none() {
+                self.error = Some(io::Error::from_raw_os_error(-cqe.res));
+            }
+        }
+    }
+
+    fn send(&mut self, len: usize, func: &mut dyn FnMut(&mut [u8])) -> io::Result<()> {
+        let i = loop {
+            self.reap();
+            if let Some(i) = self.free.pop() {
+                break i;
+            }
+            if !self.ring.wait(READ_TIMEOUT)? {
+                return Err(io::Error::from(io::ErrorKind::TimedOut));
+            }
+        };
+
+        let buffer = &mut self.buffers[i];
+        buffer.clear();
+        buffer.resize(len, 0);
+        func(buffer);
+        let pushed = self.ring.push(
+            IORING_OP_SEND,
+            buffer.as_ptr() as u64,
+            buffer.len() as u32,
+            i as u64,
+        );
+        if !pushed {
+            self.free.push(i);
+            return Err(io::Error::from(io::ErrorKind::WouldBlock));
+        }
+        self.ring.submit()?;
+
+        match self.error.take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+impl DataLinkSender for UringSender {
+    fn build_and_send(
+        &mut self,
+        num_packets: usize,
+        packet_size: usize,
+        func: &mut dyn FnMut(&mut [u8]),
+    ) -> Option<io::Result<()>> {
+        for _ in 0..num_packets {
+            if let Err(e) = self.send(packet_size, func) {
+                return Some(Err(e));
+            }
+        }
+
+        Some(Ok(()))
+    }
+
+    fn send_to(&mut self, packet: &[u8], _: Option<NetworkInterface>) -> Option<io::Result<()>> {
+        Some(self.send(packet.len(), &mut |buffer| buffer.copy_from_slice(packet)))
+    }
+}
+
+impl Drop for UringSender {
+    fn drop(&mut self) {
+        for _ in 0..MAX_DROP_WAIT {
+            self.reap();
+            if self.free.len() == self.buffers.len() {
+                return;
+            }
+            let _ = self.ring.wait(READ_TIMEOUT);
+        }
+
+        // The kernel may still write to the buffers
+        mem::forget(mem::replace(&mut self.buffers, Vec::new()));
+    }
+}
+
+/// Represents the receive half of a raw packet socket using io_uring. A receive is always in
+/// flight so the next frame can be received while the current frame is being handled.
+pub struct UringReceiver {
+    ring: Ring,
+    buffers: Vec<Vec<u8>>,
+    current: usize,
+    is_in_flight: bool,
+    _socket: Arc<Socket>,
+}
+
+impl UringReceiver {
+    fn new(socket: Arc<Socket>) -> io::Result<UringReceiver> {
+        Ok(UringReceiver {
+            ring: Ring::new(&socket)?,
+            buffers: vec![vec![0u8; RECV_BUFFER_SIZE]; 2],
+            current: 0,
+            is_in_flight: false,
+            _socket: socket,
+        })
+    }
+
+    fn recv(&mut self, i: usize) -> io::Result<()> {
+        let buffer = &mut self.buffers[i];
+        if !self.ring.push(
+            IORING_OP_RECV,
+            buffer.as_mut_ptr() as u64,
+            buffer.len() as u32,
+            i as u64,
+        ) {
+            return Err(io::Error::from(io::ErrorKind::WouldBlock));
+        }
+        self.current = i;
+        self.is_in_flight = true;
+
+        self.ring.submit()
+    }
+}
+
+impl DataLinkReceiver for UringReceiver {
+    fn next(&mut self) -> io::Result<&[u8]> {
+        if !self.is_in_flight {
+            self.recv(self.current)?;
+        }
+
+        loop {
+            while let Some(cqe) = self.ring.pop() {
+                if cqe.user_data == CANCEL_USER_DATA {
+                    continue;
+                }
+                self.is_in_flight = false;
+
+                let i = cqe.user_data as usize;
+                if cqe.res < 0 {
+                    return Err(io::Error::from_raw_os_error(-cqe.res));
+                }
+
+                // Receive the next frame into the other buffer
+                self.recv(1 - i)?;
+
+                return Ok(&self.buffers[i][..cqe.res as usize]);
+            }
+
+            if !self.ring.wait(READ_TIMEOUT)? {
+                return Err(io::Error::from(io::ErrorKind::TimedOut));
+            }
+        }
+    }
+}
+
+impl Drop for UringReceiver {
+    fn drop(&mut self) {
+        if !self.is_in_flight {
+            return;
+        }
+
+        let user_data = self.current as u64;
+        if self
+            .ring
+            .push(IORING_OP_ASYNC_CANCEL, user_data, 0, CANCEL_USER_DATA)
+            && self.ring.submit().is_ok()
+        {
+            for _ in 0..MAX_DROP_WAIT {
+                while let Some(cqe) = self.ring.pop() {
+                    if cqe.user_data == user_data {
+                        return;
+                    }
+                }
+                let _ = self.ring.wait(READ_TIMEOUT);
+            }
+        }
+
+        // The kernel may still write to the buffers
+        mem::forget(mem::replace(&mut self.buffers, Vec::new()));
+    }
+}
+
+/// Opens a raw packet socket on the given interface, and returns its send and receive halves
+/// using io_uring.
+pub fn channel(inter: &NetworkInterface) -> io::Result<(Sender, Receiver)> {
+    let socket = Arc::new(Socket::open(inter)?);
+    let tx = UringSender::new(Arc::clone(&socket))?;
+    let rx = UringReceiver::new(socket)?;
+
+    Ok((Box::new(tx), Box::new(rx)))
+}
@@ -0,0 +1,324 @@
+//! Support for named profiles of destination port policies applied to sources, like allowing only
+//! the ports of game consoles and denying SMTP and NetBIOS, which are enforced before connecting
+//! through the proxy.
+
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+
+use crate::source::AddrRange;
+
+/// Represents the action of a rule of a port policy.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum PortAction {
+    /// Represents the connection is allowed.
+    Allow,
+    /// Represents the connection is refused.
+    Deny,
+}
+
+impl Display for PortAction {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            PortAction::Allow => write!(f, "allow"),
+            PortAction::Deny => write!(f, "deny"),
+        }
+    }
+}
+
+/// Represents the transport protocol of a rule of a port policy.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum PortProtocol {
+    /// Represents TCP.
+    Tcp,
+    /// Represents UDP.
+    Udp,
+}
+
+impl Display for PortProtocol {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            PortProtocol::Tcp => write!(f, "tcp"),
+            PortProtocol::Udp => write!(f, "udp"),
+        }
+    }
+}
+
+/// Represents a rule of a port policy, which matches connections of the protocol, or of both
+/// protocols if it is `None`, to the inclusive range of destination ports.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct PortRule {
+    action: PortAction,
+    protocol: Option<PortProtocol>,
+    ports: (u16, u16),
+}
+
+impl PortRule {
+    /// Creates a new `PortRule`.
+    pub fn new(action: PortAction, protocol: Option<PortProtocol>, ports: (u16, u16)) -> PortRule {
+        PortRule {
+            action,
+            protocol,
+            ports: (ports.0.min(ports.1), ports.0.max(ports.1)),
+        }
+    }
+
+    /// Returns the action of the rule.
+    pub fn action(&self) -> PortAction {
+        self.action
+    }
+
+    fn is_match(&self, protocol: PortProtocol, port: u16) -> bool {
+        let is_protocol_match = match self.protocol {
+            Some(p) => p == protocol,
+            None => true,
+        };
+
+        is_protocol_match && port >= self.ports.0 && port <= self.ports.1
+    }
+}
+
+impl Display for PortRule {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}", self.action)?;
+        if let Some(protocol) = self.protocol {
+            write!(f, ":{}", protocol)?;
+        }
+        match self.ports {
+            (0, u16::MAX) => Ok(()),
+            (start, end) if start == end => write!(f, ":{}", start),
+            (start, end) => write!(f, ":{}-{}", start, end),
+        }
+    }
+}
+
+impl FromStr for PortRule {
+    type Err = io::Error;
+
+    /// Parses a rule like `allow:tcp:443`, `allow:udp:1024-65535`, `deny:25` or `deny`. A rule
+    /// without the protocol matches both protocols, and a rule without ports matches all ports.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidInput, "invalid port rule");
+
+        let mut parts = s.trim().split(':');
+        let action = match parts.next() {
+            Some("allow") => PortAction::Allow,
+            Some("deny") => PortAction::Deny,
+            _ => return Err(invalid()),
+        };
+        let mut part = parts.next();
+        let protocol = match part {
+            Some("tcp") => Some(PortProtocol::Tcp),
+            Some("udp") => Some(PortProtocol::Udp),
+            _ => None,
+        };
+        if protocol.is_some() {
+            part = parts.next();
+        }
+        let ports = match part {
+            Some(part) => match part.find('-') {
+                Some(i) => (
+                    part[..i].parse().map_err(|_| invalid())?,
+                    part[i + 1..].parse().map_err(|_| invalid())?,
+                ),
+                None => {
+                    let port = part.parse().map_err(|_| invalid())?;
+                    (port, port)
+                }
+            },
+            None => (0, u16::MAX),
+        };
+        if parts.next().is_some() {
+            return Err(invalid());
+        }
+
+        Ok(PortRule::new(action, protocol, ports))
+    }
+}
+
+/// Represents a named profile of a port policy. Rules are matched in order, and connections
+/// matching no rule are allowed.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct PortProfile {
+    name: String,
+    rules: Vec<PortRule>,
+}
+
+impl PortProfile {
+    /// Creates a new `PortProfile`.
+    pub fn new(name: &str, rules: Vec<PortRule>) -> PortProfile {
+        PortProfile {
+            name: name.to_string(),
+            rules,
+        }
+    }
+
+    /// Returns the name of the profile.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the rules of the profile.
+    pub fn rules(&self) -> &[PortRule] {
+        &self.rules
+    }
+}
+
+impl Display for PortProfile {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let rules: Vec<_> = self.rules.iter().map(|rule| rule.to_string()).collect();
+
+        write!(f, "{}={}", self.name, rules.join(","))
+    }
+}
+
+impl FromStr for PortProfile {
+    type Err = io::Error;
+
+    /// Parses a profile like `consoles=allow:tcp:80,allow:tcp:443,allow:udp:1024-65535,deny`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let i = s
+            .find('=')
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid port profile"))?;
+        let rules = s[i + 1..]
+            .split(',')
+            .map(|rule| rule.parse())
+            .collect::<io::Result<Vec<_>>>()?;
+
+        Ok(PortProfile::new(s[..i].trim(), rules))
+    }
+}
+
+/// Represents the hits of a rule of a port profile.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PolicyHit {
+    /// Represents the name of the profile.
+    pub profile: String,
+    /// Represents the rule.
+    pub rule: PortRule,
+    /// Represents the count of connections matching the rule.
+    pub hits: u64,
+}
+
+impl Display for PolicyHit {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{} {}: {} hits", self.profile, self.rule, self.hits)
+    }
+}
+
+/// Represents port policies applied to sources, which count the hits of each rule.
+#[derive(Debug)]
+pub(crate) struct PortPolicy {
+    profiles: Vec<(PortProfile, Vec<u64>)>,
+    /// Represents the ranges of sources and the indexes of their profiles.
+    sources: Vec<(AddrRange, usize)>,
+}
+
+impl PortPolicy {
+    /// Creates a new `PortPolicy` applying profiles to the ranges of sources by their names.
+    /// Ranges are matched in order, and ranges of unknown profiles are ignored.
+    pub(crate) fn new(profiles: &[PortProfile], sources: &[(AddrRange, String)]) -> PortPolicy {
+        PortPolicy {
+            profiles: profiles
+                .iter()
+                .map(|profile| (profile.clone(), vec![0; profile.rules.len()]))
+                .collect(),
+            sources: sources
+                .iter()
+                .filter_map(|(range, name)| {
+                    profiles
+                        .iter()
+                        .position(|profile| profile.name == *name)
+                        .map(|i| (*range, i))
+                })
+                .collect(),
+        }
+    }
+
+    /// Returns if a connection of the source to the destination port is allowed, and counts the
+    /// hit of the rule matched.
+    pub(crate) fn check(&mut self, src: Ipv4Addr, protocol: PortProtocol, port: u16) -> bool {
+        match self.find(src, protocol, port) {
+            Some((i, j)) => {
+                let (ref profile, ref mut hits) = self.profiles[i];
+                hits[j] += 1;
+
+                profile.rules[j].action == PortAction::Allow
+            }
+            None => true,
+        }
+    }
+
+    /// Returns the profile and the rule matching a connection of the source to the destination
+    /// port, like `consoles deny:tcp:25`.
+    pub(crate) fn rule(&self, src: Ipv4Addr, protocol: PortProtocol, port: u16) -> Option<String> {
+        let (i, j) = self.find(src, protocol, port)?;
+        let profile = &self.profiles[i].0;
+
+        Some(format!("{} {}", profile.name, profile.rules[j]))
+    }
+
+    /// Returns the indexes of the profile and the rule matching a connection.
+    fn find(&self, src: Ipv4Addr, protocol: PortProtocol, port: u16) -> Option<(usize, usize)> {
+        let &(_, i) = self.sources.iter().find(|(range, _)| range.contains(src))?;
+        let j = self.profiles[i]
+            .0
+            .rules
+            .iter()
+            .position(|rule| rule.is_match(protocol, port))?;
+
+        Some((i, j))
+    }
+
+    /// Returns the hits of each rule.
+    pub(crate) fn hits(&self) -> Vec<PolicyHit> {
+        self.profiles
+            .iter()
+            .flat_map(|(profile, hits)| {
+                profile
+                    .rules
+                    .iter()
+                    .zip(hits.iter())
+                    .map(move |(rule, &hits)| PolicyHit {
+                        profile: profile.name.clone(),
+                        rule: *rule,
+                        hits,
+                    })
+            })
+            .collect()
+    }
+}
+
+#[test]
+fn port_policy_check() {
+    let profile: PortProfile = "consoles=allow:tcp:80,allow:tcp:443,allow:udp:1024-65535,deny"
+        .parse()
+        .unwrap();
+    assert_eq!(
+        profile.to_string(),
+        "consoles=allow:tcp:80,allow:tcp:443,allow:udp:1024-65535,deny"
+    );
+    assert!("consoles=allow:icmp:80".parse::<PortProfile>().is_err());
+
+    let mut policy = PortPolicy::new(
+        &[profile],
+        &[
+            (
+                "10.6.0.10-10.6.0.20".parse().unwrap(),
+                "consoles".to_string(),
+            ),
+            ("10.6.0.30".parse().unwrap(), "unknown".to_string()),
+        ],
+    );
+    let console = Ipv4Addr::new(10, 6, 0, 10);
+    assert!(policy.check(console, PortProtocol::Tcp, 443));
+    assert!(policy.check(console, PortProtocol::Udp, 3074));
+    assert!(!policy.check(console, PortProtocol::Tcp, 25));
+    assert!(!policy.check(console, PortProtocol::Udp, 137));
+    // Sources without profiles are not limited
+    assert!(policy.check(Ipv4Addr::new(10, 6, 0, 30), PortProtocol::Tcp, 25));
+
+    let hits: Vec<_> = policy.hits().iter().map(|hit| hit.hits).collect();
+    assert_eq!(hits, vec![0, 1, 1, 2]);
+}
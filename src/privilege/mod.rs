@@ -0,0 +1,330 @@
+//! Support for dropping the privileges of the process after the capture is opened, so the
+//! long-running process does not run as root.
+
+use std::ffi::CString;
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::mem;
+use std::ptr;
+
+/// Represents the size of the buffer of looking up users and groups.
+const LOOKUP_BUFFER_SIZE: usize = 16 * 1024;
+
+/// Represents a user and a group to run as.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct Credential {
+    user: String,
+    uid: libc::uid_t,
+    gid: libc::gid_t,
+}
+
+impl Credential {
+    /// Creates a new `Credential` of the user, which is a user name or a numeric user ID, and its
+    /// primary group. A numeric user ID without a user uses the group of the same ID.
+    pub fn from_user(user: &str) -> io::Result<Credential> {
+        let name = CString::new(user)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid user"))?;
+        let mut passwd: libc::passwd = unsafe { mem::zeroed() };
+        let mut buffer = vec![0 as libc::c_char; LOOKUP_BUFFER_SIZE];
+        let mut result = ptr::null_mut();
+        let ret = unsafe {
+            libc::getpwnam_r(
+                name.as_ptr(),
+                &mut passwd,
+                buffer.as_mut_ptr(),
+                buffer.len(),
+                &mut result,
+            )
+        };
+        if ret != 0 {
+            return Err(io::Error::from_raw_os_error(ret));
+        }
+
+        if !result.is_null() {
+            return Ok(Credential {
+                user: user.to_string(),
+                uid: passwd.pw_uid,
+                gid: passwd.pw_gid,
+            });
+        }
+        match user.parse() {
+            Ok(uid) => Ok(Credential {
+                user: user.to_string(),
+                uid,
+                gid: uid,
+            }),
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("user {} not found", user),
+            )),
+        }
+    }
+
+    /// Sets the group, which is a group name or a numeric group ID, instead of the primary group
+    /// of the user.
+    pub fn group(mut self, group: &str) -> io::Result<Credential> {
+        let name = CString::new(group)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid group"))?;
+        let mut entry: libc::group = unsafe { mem::zeroed() };
+        let mut buffer = vec![0 as libc::c_char; LOOKUP_BUFFER_SIZE];
+        let mut result = ptr::null_mut();
+        let ret = unsafe {
+            libc::getgrnam_r(
+                name.as_ptr(),
+                &mut entry,
+                buffer.as_mut_ptr(),
+                buffer.len(),
+                &mut result,
+            )
+        };
+        if ret != 0 {
+            return Err(io::Error::from_raw_os_error(ret));
+        }
+
+        self.gid = if !result.is_null() {
+            entry.gr_gid
+        } else {
+            group.parse().map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("group {} not found", group),
+                )
+            })?
+        };
+
+        Ok(self)
+    }
+
+    /// Returns the user ID.
+    pub fn uid(&self) -> libc::uid_t {
+        self.uid
+    }
+
+    /// Returns the group ID.
+    pub fn gid(&self) -> libc::gid_t {
+        self.gid
+    }
+}
+
+impl Display for Credential {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{} (uid {}, gid {})", self.user, self.uid, self.gid)
+    }
+}
+
+fn cvt(ret: libc::c_int) -> io::Result<()> {
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// Drops the privileges of the process to the credential. Supplementary groups are cleared, and
+/// it fails if the privileges can be regained.
+pub fn drop_privileges(credential: &Credential) -> io::Result<()> {
+    // Groups must be changed before the user
+    cvt(unsafe { libc::setgroups(1, &credential.gid) })?;
+    cvt(unsafe { libc::setgid(credential.gid) })?;
+    cvt(unsafe { libc::setuid(credential.uid) })?;
+
+    if credential.uid != 0 && unsafe { libc::setuid(0) } == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "privileges can be regained",
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+mod seccomp {
+    use std::io;
+
+    const PR_SET_NO_NEW_PRIVS: libc::c_int = 38;
+    const SECCOMP_SET_MODE_FILTER: libc::c_ulong = 1;
+    const SECCOMP_FILTER_FLAG_TSYNC: libc::c_ulong = 1;
+    const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+    const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+    const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+
+    #[cfg(target_arch = "x86_64")]
+    const AUDIT_ARCH: u32 = 0xc000_003e;
+    #[cfg(target_arch = "aarch64")]
+    const AUDIT_ARCH: u32 = 0xc000_00b7;
+    /// Represents the bit of x32 system calls, which are denied.
+    #[cfg(target_arch = "x86_64")]
+    const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+    const BPF_LD_W_ABS: u16 = 0x20;
+    const BPF_JMP_JEQ_K: u16 = 0x15;
+    #[cfg(target_arch = "x86_64")]
+    const BPF_JMP_JGE_K: u16 = 0x35;
+    const BPF_RET_K: u16 = 0x06;
+
+    /// Represents the offsets of fields of `struct seccomp_data`.
+    const DATA_NR: u32 = 0;
+    const DATA_ARCH: u32 = 4;
+
+    /// Represents system calls which are never made by pcap2socks once it is running, but are
+    /// useful to an attacker, like executing programs, tracing processes, changing credentials
+    /// and administering the system.
+    const DENIED_SYSCALLS: &[libc::c_long] = &[
+        libc::SYS_execve,
+        libc::SYS_execveat,
+        libc::SYS_ptrace,
+        libc::SYS_process_vm_readv,
+        libc::SYS_process_vm_writev,
+        libc::SYS_setuid,
+        libc::SYS_setgid,
+        libc::SYS_setreuid,
+        libc::SYS_setregid,
+        libc::SYS_setresuid,
+        libc::SYS_setresgid,
+        libc::SYS_setgroups,
+        libc::SYS_setns,
+        libc::SYS_unshare,
+        libc::SYS_mount,
+        libc::SYS_umount2,
+        libc::SYS_pivot_root,
+        libc::SYS_chroot,
+        libc::SYS_swapon,
+        libc::SYS_swapoff,
+        libc::SYS_reboot,
+        libc::SYS_kexec_load,
+        libc::SYS_init_module,
+        libc::SYS_finit_module,
+        libc::SYS_delete_module,
+        libc::SYS_bpf,
+        libc::SYS_perf_event_open,
+        libc::SYS_keyctl,
+        libc::SYS_add_key,
+        libc::SYS_request_key,
+    ];
+
+    #[repr(C)]
+    #[derive(Clone, Copy, Debug)]
+    struct SockFilter {
+        code: u16,
+        jt: u8,
+        jf: u8,
+        k: u32,
+    }
+
+    #[repr(C)]
+    struct SockFprog {
+        len: libc::c_ushort,
+        filter: *const SockFilter,
+    }
+
+    fn statement(code: u16, k: u32) -> SockFilter {
+        SockFilter {
+            code,
+            jt: 0,
+            jf: 0,
+            k,
+        }
+    }
+
+    fn jump(code: u16, k: u32, jt: u8, jf: u8) -> SockFilter {
+        SockFilter { code, jt, jf, k }
+    }
+
+    /// Returns the BPF program of the filter, which kills the process on foreign architectures
+    /// and fails denied system calls with `EPERM`.
+    fn program() -> Vec<SockFilter> {
+        let mut program = vec![
+            statement(BPF_LD_W_ABS, DATA_ARCH),
+            jump(BPF_JMP_JEQ_K, AUDIT_ARCH, 1, 0),
+            statement(BPF_RET_K, SECCOMP_RET_KILL_PROCESS),
+            statement(BPF_LD_W_ABS, DATA_NR),
+        ];
+        #[cfg(target_arch = "x86_64")]
+        program.extend_from_slice(&[
+            jump(BPF_JMP_JGE_K, X32_SYSCALL_BIT, 0, 1),
+            statement(BPF_RET_K, SECCOMP_RET_ERRNO | libc::EPERM as u32),
+        ]);
+        for syscall in DENIED_SYSCALLS {
+            program.push(jump(BPF_JMP_JEQ_K, *syscall as u32, 0, 1));
+            program.push(statement(BPF_RET_K, SECCOMP_RET_ERRNO | libc::EPERM as u32));
+        }
+        program.push(statement(BPF_RET_K, SECCOMP_RET_ALLOW));
+
+        program
+    }
+
+    pub(super) fn enter() -> io::Result<()> {
+        if unsafe { libc::prctl(PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let program = program();
+        let prog = SockFprog {
+            len: program.len() as libc::c_ushort,
+            filter: program.as_ptr(),
+        };
+        // Synchronize the filter to all the threads of the process
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_seccomp,
+                SECCOMP_SET_MODE_FILTER,
+                SECCOMP_FILTER_FLAG_TSYNC,
+                &prog as *const SockFprog,
+            )
+        };
+        match ret {
+            0 => Ok(()),
+            ret if ret < 0 => Err(io::Error::last_os_error()),
+            // The ID of a thread which cannot be synchronized
+            _ => Err(io::Error::new(
+                io::ErrorKind::Other,
+                "cannot synchronize the seccomp filter to all threads",
+            )),
+        }
+    }
+}
+
+/// Enters the restricted seccomp profile, which denies system calls never made by pcap2socks
+/// once it is running, like executing programs, tracing processes and changing credentials, in
+/// all the threads of the process. The profile cannot be left.
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+pub fn restrict() -> io::Result<()> {
+    seccomp::enter()
+}
+
+/// Enters the restricted seccomp profile, which is not supported on this platform.
+#[cfg(not(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+)))]
+pub fn restrict() -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "seccomp is not supported on this platform",
+    ))
+}
+
+#[test]
+fn privilege_credential() {
+    let root = Credential::from_user("root").unwrap();
+    assert_eq!((root.uid(), root.gid()), (0, 0));
+
+    let numeric = Credential::from_user("65534")
+        .unwrap()
+        .group("1234")
+        .unwrap();
+    assert_eq!((numeric.uid(), numeric.gid()), (65534, 1234));
+
+    assert!(Credential::from_user("pcap2socks-nonexistent").is_err());
+    assert!(Credential::from_user("root")
+        .unwrap()
+        .group("pcap2socks-nonexistent")
+        .is_err());
+}
@@ -0,0 +1,306 @@
+//! Support for using pcap2socks in Python.
+
+use ipnetwork::Ipv4Network;
+use pyo3::exceptions::{PyOSError, PyRuntimeError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use tokio::runtime::Runtime;
+
+use crate::packet::discovery::DiscoveryProtocol;
+use crate::packet::layer::LayerKinds;
+use crate::packet::tunnel::TunnelProtocol;
+use crate::pcap::{Receiver, StoppableReceiver};
+use crate::socks::{ConnectFailure, Secret, SocksAuth};
+use crate::{Config, Forwarder, Stats};
+
+/// Represents a network interface.
+#[pyclass(name = "Interface")]
+pub struct PyInterface {
+    #[pyo3(get)]
+    name: String,
+    #[pyo3(get)]
+    alias: Option<String>,
+    #[pyo3(get)]
+    hardware_addr: String,
+    #[pyo3(get)]
+    ip_addr: Option<String>,
+    #[pyo3(get)]
+    mtu: usize,
+}
+
+#[pymethods]
+impl PyInterface {
+    fn __str__(&self) -> String {
+        match self.alias {
+            Some(ref alias) => format!("{} ({})", self.name, alias),
+            None => self.name.clone(),
+        }
+    }
+}
+
+/// Gets a list of available network interfaces for the current machine.
+#[pyfunction]
+#[pyo3(name = "interfaces")]
+fn py_interfaces() -> Vec<PyInterface> {
+    crate::interfaces()
+        .into_iter()
+        .map(|inter| PyInterface {
+            name: inter.name().clone(),
+            alias: inter.alias().clone(),
+            hardware_addr: inter.hardware_addr().to_string(),
+            ip_addr: inter.ip_addr().map(|ip_addr| ip_addr.to_string()),
+            mtu: inter.mtu(),
+        })
+        .collect()
+}
+
+/// Represents the statistics of a `Redirector`.
+#[pyclass(name = "Stats")]
+pub struct PyStats {
+    stats: Arc<Stats>,
+}
+
+#[pymethods]
+impl PyStats {
+    /// Returns the statistics as a dict.
+    fn to_dict<'p>(&self, py: Python<'p>) -> PyResult<&'p PyDict> {
+        let stats = &self.stats;
+        let dict = PyDict::new(py);
+        dict.set_item("udp_capacity", stats.udp_capacity())?;
+        dict.set_item("udp_bindings", stats.udp_bindings())?;
+        dict.set_item("udp_expirations", stats.udp_expirations())?;
+        dict.set_item("udp_reuses", stats.udp_reuses())?;
+        dict.set_item("udp_stall_drops", stats.udp_stall_drops())?;
+        dict.set_item("udp_rate_limits", stats.udp_rate_limits())?;
+        dict.set_item("quic_sessions", stats.quic_sessions())?;
+        dict.set_item("quic_migrations", stats.quic_migrations())?;
+        dict.set_item("broadcast_drops", stats.broadcast_drops())?;
+        dict.set_item("broadcast_relays", stats.broadcast_relays())?;
+        dict.set_item("broadcast_answers", stats.broadcast_answers())?;
+        dict.set_item("multicast_groups", stats.multicast_groups())?;
+        dict.set_item("multicast_drops", stats.multicast_drops())?;
+        dict.set_item("multicast_relays", stats.multicast_relays())?;
+        dict.set_item("multicast_reflections", stats.multicast_reflections())?;
+        dict.set_item("tcp_invalid_segments", stats.tcp_invalid_segments())?;
+        dict.set_item("tcp_challenge_acks", stats.tcp_challenge_acks())?;
+        dict.set_item("tcp_refusals", stats.tcp_refusals())?;
+        dict.set_item("tcp_evictions", stats.tcp_evictions())?;
+        dict.set_item("tcp_idle_reaps", stats.tcp_idle_reaps())?;
+        dict.set_item("tcp_syn_drops", stats.tcp_syn_drops())?;
+        dict.set_item("tcp_rate_limits", stats.tcp_rate_limits())?;
+        dict.set_item("tcp_pending_expirations", stats.tcp_pending_expirations())?;
+        dict.set_item("tcp_write_stalls", stats.tcp_write_stalls())?;
+        dict.set_item("tcp_connect_retries", stats.tcp_connect_retries())?;
+        dict.set_item("tcp_out_of_order_bytes", stats.tcp_out_of_order_bytes())?;
+        dict.set_item("tcp_out_of_order_drops", stats.tcp_out_of_order_drops())?;
+        dict.set_item("tcp_retrans_segments", stats.tcp_retrans_segments())?;
+        dict.set_item("tcp_retrans_bytes", stats.tcp_retrans_bytes())?;
+        dict.set_item("tcp_fast_retransmits", stats.tcp_fast_retransmits())?;
+        dict.set_item("tcp_rto_retransmits", stats.tcp_rto_retransmits())?;
+        dict.set_item("tcp_duplicate_acks", stats.tcp_duplicate_acks())?;
+        dict.set_item(
+            "connect_auth_failures",
+            stats.connect_failures(ConnectFailure::Auth),
+        )?;
+        dict.set_item(
+            "connect_method_failures",
+            stats.connect_failures(ConnectFailure::Method),
+        )?;
+        dict.set_item(
+            "connect_reply_failures",
+            stats.connect_failures(ConnectFailure::Reply),
+        )?;
+        dict.set_item(
+            "connect_network_failures",
+            stats.connect_failures(ConnectFailure::Network),
+        )?;
+        dict.set_item(
+            "connect_other_failures",
+            stats.connect_failures(ConnectFailure::Other),
+        )?;
+        dict.set_item("arp_conflicts", stats.arp_conflicts())?;
+        dict.set_item("quarantines", stats.quarantines())?;
+        dict.set_item("quarantine_drops", stats.quarantine_drops())?;
+        dict.set_item("sni_blocks", stats.sni_blocks())?;
+        dict.set_item("sni_bypasses", stats.sni_bypasses())?;
+        dict.set_item("spoof_drops", stats.spoof_drops())?;
+        dict.set_item("frag_overlaps", stats.frag_overlaps())?;
+        dict.set_item("frag_drops", stats.frag_drops())?;
+        dict.set_item("mtu_probes", stats.mtu_probes())?;
+        dict.set_item("mtu_black_holes", stats.mtu_black_holes())?;
+        dict.set_item("qos_drops", stats.qos_drops())?;
+        dict.set_item("tx_drops", stats.tx_drops())?;
+        dict.set_item("icmp_redirects", stats.icmp_redirects())?;
+        dict.set_item("icmp_source_quenches", stats.icmp_source_quenches())?;
+        dict.set_item("icmp_echo_replies", stats.icmp_echo_replies())?;
+        dict.set_item("tunneled_gre", stats.tunneled(TunnelProtocol::Gre))?;
+        dict.set_item("tunneled_ipsec", stats.tunneled(TunnelProtocol::Ipsec))?;
+        dict.set_item("tunneled_6in4", stats.tunneled(TunnelProtocol::Ipv6))?;
+        dict.set_item("tunneled_forwards", stats.tunneled_forwards())?;
+        dict.set_item("discovery_lldp", stats.discovery(DiscoveryProtocol::Lldp))?;
+        dict.set_item("discovery_cdp", stats.discovery(DiscoveryProtocol::Cdp))?;
+        dict.set_item("discovery_stp", stats.discovery(DiscoveryProtocol::Stp))?;
+        dict.set_item("malformed_ethernet", stats.malformed(LayerKinds::Ethernet))?;
+        dict.set_item("malformed_arp", stats.malformed(LayerKinds::Arp))?;
+        dict.set_item("malformed_ipv4", stats.malformed(LayerKinds::Ipv4))?;
+        dict.set_item("malformed_icmpv4", stats.malformed(LayerKinds::Icmpv4))?;
+        dict.set_item("malformed_tcp", stats.malformed(LayerKinds::Tcp))?;
+        dict.set_item("malformed_udp", stats.malformed(LayerKinds::Udp))?;
+        dict.set_item("dispatch_drops", stats.dispatch_drops())?;
+        dict.set_item("rx_bytes", stats.rx_bytes())?;
+        dict.set_item("tx_bytes", stats.tx_bytes())?;
+
+        Ok(dict)
+    }
+
+    fn __str__(&self) -> String {
+        self.stats.to_string()
+    }
+}
+
+/// Represents a redirector which runs in a background thread.
+#[pyclass(name = "Redirector")]
+pub struct PyRedirector {
+    interface: Option<String>,
+    src: Ipv4Network,
+    dst: SocketAddrV4,
+    publish: Option<Ipv4Addr>,
+    auth: Option<SocksAuth>,
+    stats: Option<Arc<Stats>>,
+    is_stopped: Arc<AtomicBool>,
+    handle: Option<JoinHandle<io::Result<()>>>,
+}
+
+#[pymethods]
+impl PyRedirector {
+    /// Creates a new `Redirector` redirecting traffic from `src` to the SOCKS proxy `dst`.
+    #[new]
+    #[pyo3(signature = (src, dst, interface=None, publish=None, username=None, password=None))]
+    fn new(
+        src: &str,
+        dst: &str,
+        interface: Option<String>,
+        publish: Option<&str>,
+        username: Option<String>,
+        password: Option<String>,
+    ) -> PyResult<PyRedirector> {
+        let src = src
+            .parse()
+            .map_err(|e| PyValueError::new_err(format!("source {}: {}", src, e)))?;
+        let dst = dst
+            .parse()
+            .map_err(|e| PyValueError::new_err(format!("destination {}: {}", dst, e)))?;
+        let publish = match publish {
+            Some(publish) => Some(
+                publish
+                    .parse()
+                    .map_err(|e| PyValueError::new_err(format!("publish {}: {}", publish, e)))?,
+            ),
+            None => None,
+        };
+        let auth = match username {
+            Some(username) => Some(SocksAuth::new(
+                username,
+                Secret::new(password.unwrap_or_default()),
+            )),
+            None => None,
+        };
+
+        Ok(PyRedirector {
+            interface,
+            src,
+            dst,
+            publish,
+            auth,
+            stats: None,
+            is_stopped: Arc::new(AtomicBool::new(false)),
+            handle: None,
+        })
+    }
+
+    /// Starts redirecting in a background thread.
+    fn start(&mut self) -> PyResult<()> {
+        if self.handle.is_some() {
+            return Err(PyRuntimeError::new_err("redirector is already started"));
+        }
+
+        let inter = crate::interface(self.interface.clone())
+            .ok_or_else(|| PyOSError::new_err("cannot find interface"))?;
+        let ip_addr = inter
+            .ip_addr()
+            .ok_or_else(|| PyOSError::new_err("interface has no IP address"))?;
+        let gw = self.publish.unwrap_or(ip_addr);
+        let (tx, rx) = inter
+            .open()
+            .map_err(|e| PyOSError::new_err(e.to_string()))?;
+
+        let forwarder = Forwarder::new(tx, inter.mtu(), inter.hardware_addr(), ip_addr);
+        let mut redirector = crate::Redirector::new(
+            Arc::new(Mutex::new(forwarder)),
+            self.src,
+            gw,
+            self.publish,
+            self.dst,
+            false,
+            false,
+            self.auth.clone(),
+            Config::new(),
+        );
+        self.stats = Some(redirector.stats());
+
+        self.is_stopped.store(false, Ordering::Relaxed);
+        let mut rx: Receiver = Box::new(StoppableReceiver::new(rx, Arc::clone(&self.is_stopped)));
+        self.handle = Some(thread::spawn(move || {
+            let mut rt = Runtime::new()?;
+
+            rt.block_on(redirector.open(&mut rx))
+        }));
+
+        Ok(())
+    }
+
+    /// Stops redirecting and waits for the background thread to exit.
+    fn stop(&mut self, py: Python) -> PyResult<()> {
+        let handle = match self.handle.take() {
+            Some(handle) => handle,
+            None => return Ok(()),
+        };
+        self.is_stopped.store(true, Ordering::Relaxed);
+
+        match py.allow_threads(|| handle.join()) {
+            Ok(Ok(_)) => Ok(()),
+            Ok(Err(ref e)) if e.kind() == io::ErrorKind::Interrupted => Ok(()),
+            Ok(Err(e)) => Err(PyOSError::new_err(e.to_string())),
+            Err(_) => Err(PyRuntimeError::new_err("redirector panicked")),
+        }
+    }
+
+    /// Returns the statistics of the redirector, or `None` if it has never been started.
+    fn stats(&self) -> Option<PyStats> {
+        self.stats.as_ref().map(|stats| PyStats {
+            stats: Arc::clone(stats),
+        })
+    }
+}
+
+impl Drop for PyRedirector {
+    fn drop(&mut self) {
+        self.is_stopped.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Redirect traffic to a SOCKS proxy with pcap.
+#[pymodule]
+fn pcap2socks(_py: Python, m: &PyModule) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(py_interfaces, m)?)?;
+    m.add_class::<PyInterface>()?;
+    m.add_class::<PyRedirector>()?;
+    m.add_class::<PyStats>()?;
+
+    Ok(())
+}
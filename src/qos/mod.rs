@@ -0,0 +1,394 @@
+//! Support for traffic shaping with token buckets of classes of flows, which are classified by
+//! their protocols, destination ports and sources, so bulk downloads can be capped while game
+//! traffic stays untouched.
+
+use std::cmp::max;
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::time;
+
+use crate::clock;
+use crate::policy::PortProtocol;
+use crate::source::AddrRange;
+
+/// Represents the min burst in bytes of a class, which holds a few segments of the largest MTU, so
+/// a segment can always be sent once the bucket is refilled.
+const MIN_QOS_BURST: u64 = 16 * 1024;
+
+/// Represents the direction of traffic shaped.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub(crate) enum QosDirection {
+    /// Represents the traffic from sources to the proxy.
+    Upload,
+    /// Represents the traffic from the proxy to sources.
+    Download,
+}
+
+/// Represents a class of traffic shaping, which limits the traffic of its flows in each direction
+/// to the rate in bytes per second on average, and to the burst in bytes at once.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct QosClass {
+    name: String,
+    rate: u64,
+    burst: u64,
+}
+
+impl QosClass {
+    /// Creates a new `QosClass`. A burst of 0 takes a quarter of the rate.
+    pub fn new(name: &str, rate: u64, burst: u64) -> QosClass {
+        let burst = match burst {
+            0 => rate / 4,
+            burst => burst,
+        };
+
+        QosClass {
+            name: name.to_string(),
+            rate: max(rate, 1),
+            burst: max(burst, MIN_QOS_BURST),
+        }
+    }
+
+    /// Returns the name of the class.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the rate of the class in bytes per second.
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    /// Returns the burst of the class in bytes.
+    pub fn burst(&self) -> u64 {
+        self.burst
+    }
+}
+
+impl Display for QosClass {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}={}/{}", self.name, self.rate, self.burst)
+    }
+}
+
+impl FromStr for QosClass {
+    type Err = io::Error;
+
+    /// Parses a class like `bulk=1048576/262144` or `bulk=1048576`, whose rate is in bytes per
+    /// second and whose burst is in bytes.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidInput, "invalid QoS class");
+
+        let i = s.find('=').ok_or_else(invalid)?;
+        let name = s[..i].trim();
+        if name.is_empty() {
+            return Err(invalid());
+        }
+        let value = &s[i + 1..];
+        let (rate, burst) = match value.find('/') {
+            Some(j) => (
+                value[..j].trim().parse().map_err(|_| invalid())?,
+                value[j + 1..].trim().parse().map_err(|_| invalid())?,
+            ),
+            None => (value.trim().parse().map_err(|_| invalid())?, 0),
+        };
+
+        Ok(QosClass::new(name, rate, burst))
+    }
+}
+
+/// Represents a match of flows to a class of traffic shaping, which matches flows of the
+/// protocol, or of both protocols if it is `None`, to the inclusive range of destination ports,
+/// and from the range of sources if it is set.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct QosMatch {
+    class: String,
+    protocol: Option<PortProtocol>,
+    ports: (u16, u16),
+    sources: Option<AddrRange>,
+}
+
+impl QosMatch {
+    /// Creates a new `QosMatch`.
+    pub fn new(
+        class: &str,
+        protocol: Option<PortProtocol>,
+        ports: (u16, u16),
+        sources: Option<AddrRange>,
+    ) -> QosMatch {
+        QosMatch {
+            class: class.to_string(),
+            protocol,
+            ports: (ports.0.min(ports.1), ports.0.max(ports.1)),
+            sources,
+        }
+    }
+
+    /// Returns the name of the class of the match.
+    pub fn class(&self) -> &str {
+        &self.class
+    }
+
+    fn is_match(&self, protocol: PortProtocol, src: Ipv4Addr, port: u16) -> bool {
+        let is_protocol_match = match self.protocol {
+            Some(p) => p == protocol,
+            None => true,
+        };
+        let is_source_match = match self.sources {
+            Some(ref sources) => sources.contains(src),
+            None => true,
+        };
+
+        is_protocol_match && is_source_match && port >= self.ports.0 && port <= self.ports.1
+    }
+}
+
+impl Display for QosMatch {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}=", self.class)?;
+        match self.protocol {
+            Some(protocol) => write!(f, "{}", protocol)?,
+            None => write!(f, "all")?,
+        }
+        match self.ports {
+            (0, u16::MAX) => {}
+            (start, end) if start == end => write!(f, ":{}", start)?,
+            (start, end) => write!(f, ":{}-{}", start, end)?,
+        }
+        if let Some(ref sources) = self.sources {
+            write!(f, "@{}", sources)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for QosMatch {
+    type Err = io::Error;
+
+    /// Parses a match like `bulk=tcp:443`, `games=udp:3074@10.6.0.10-10.6.0.20` or
+    /// `bulk=all@10.6.0.30`. A match without ports matches all ports, and a match without the
+    /// range of sources matches all sources.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidInput, "invalid QoS match");
+
+        let i = s.find('=').ok_or_else(invalid)?;
+        let class = s[..i].trim();
+        let mut value = s[i + 1..].trim();
+        let sources = match value.find('@') {
+            Some(j) => {
+                let sources = value[j + 1..].parse()?;
+                value = &value[..j];
+
+                Some(sources)
+            }
+            None => None,
+        };
+        let mut parts = value.split(':');
+        let protocol = match parts.next() {
+            Some("tcp") => Some(PortProtocol::Tcp),
+            Some("udp") => Some(PortProtocol::Udp),
+            Some("all") => None,
+            _ => return Err(invalid()),
+        };
+        let ports = match parts.next() {
+            Some(part) => match part.find('-') {
+                Some(j) => (
+                    part[..j].parse().map_err(|_| invalid())?,
+                    part[j + 1..].parse().map_err(|_| invalid())?,
+                ),
+                None => {
+                    let port = part.parse().map_err(|_| invalid())?;
+                    (port, port)
+                }
+            },
+            None => (0, u16::MAX),
+        };
+        if class.is_empty() || parts.next().is_some() {
+            return Err(invalid());
+        }
+
+        Ok(QosMatch::new(class, protocol, ports, sources))
+    }
+}
+
+/// Represents the token bucket of a class in a direction. The tokens may go negative, which is
+/// the debt paid by waiting before sending more.
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    instant: Instant,
+}
+
+/// Represents a shaper of traffic of classes, each of which has a token bucket in each direction.
+#[derive(Debug)]
+pub(crate) struct Shaper {
+    classes: Vec<(QosClass, [Bucket; 2])>,
+    /// Represents the matches and the indexes of their classes.
+    matches: Vec<(QosMatch, usize)>,
+}
+
+impl Shaper {
+    /// Creates a new `Shaper` of the classes, whose flows are classified by the matches. Matches
+    /// are matched in order, and matches of unknown classes are ignored.
+    pub(crate) fn new(classes: &[QosClass], matches: &[QosMatch]) -> Shaper {
+        let now = clock::now();
+
+        Shaper {
+            classes: classes
+                .iter()
+                .map(|class| {
+                    let bucket = || Bucket {
+                        tokens: class.burst as f64,
+                        instant: now,
+                    };
+
+                    (class.clone(), [bucket(), bucket()])
+                })
+                .collect(),
+            matches: matches
+                .iter()
+                .filter_map(|m| {
+                    classes
+                        .iter()
+                        .position(|class| class.name == m.class)
+                        .map(|i| (m.clone(), i))
+                })
+                .collect(),
+        }
+    }
+
+    /// Returns the index of the class of a flow of the source to the destination port. Returns
+    /// `None` if the flow is not shaped.
+    pub(crate) fn classify(
+        &self,
+        protocol: PortProtocol,
+        src: Ipv4Addr,
+        port: u16,
+    ) -> Option<usize> {
+        self.matches
+            .iter()
+            .find(|(m, _)| m.is_match(protocol, src, port))
+            .map(|&(_, i)| i)
+    }
+
+    /// Returns the bytes allowed to be sent in the class and the direction now.
+    pub(crate) fn budget(&mut self, class: usize, direction: QosDirection) -> usize {
+        let tokens = self.refill(class, direction).tokens;
+
+        if tokens > 0.0 {
+            tokens as usize
+        } else {
+            0
+        }
+    }
+
+    /// Returns the time until the size is allowed to be sent in the class and the direction.
+    pub(crate) fn delay(&mut self, class: usize, direction: QosDirection, size: usize) -> Duration {
+        let rate = self.classes[class].0.rate as f64;
+        let bucket = self.refill(class, direction);
+        let needed = size as f64 - bucket.tokens;
+
+        if needed > 0.0 {
+            Duration::from_secs_f64(needed / rate)
+        } else {
+            Duration::from_millis(0)
+        }
+    }
+
+    /// Takes the size from the bucket of the class and the direction, and returns the time to
+    /// wait before sending it. The bucket is in debt until the time elapses.
+    pub(crate) fn reserve(
+        &mut self,
+        class: usize,
+        direction: QosDirection,
+        size: usize,
+    ) -> Duration {
+        let rate = self.classes[class].0.rate as f64;
+        let bucket = self.refill(class, direction);
+        bucket.tokens -= size as f64;
+
+        if bucket.tokens < 0.0 {
+            Duration::from_secs_f64(-bucket.tokens / rate)
+        } else {
+            Duration::from_millis(0)
+        }
+    }
+
+    fn refill(&mut self, class: usize, direction: QosDirection) -> &mut Bucket {
+        let now = clock::now();
+        let (ref class, ref mut buckets) = self.classes[class];
+        let bucket = &mut buckets[direction as usize];
+        let elapsed = now.saturating_duration_since(bucket.instant);
+        bucket.tokens =
+            (bucket.tokens + elapsed.as_secs_f64() * class.rate as f64).min(class.burst as f64);
+        bucket.instant = now;
+
+        bucket
+    }
+}
+
+/// Waits until the size is allowed to be sent in the class and the direction.
+pub(crate) async fn throttle(
+    shaper: &Mutex<Shaper>,
+    class: usize,
+    direction: QosDirection,
+    size: usize,
+) {
+    let delay = shaper.lock().unwrap().reserve(class, direction, size);
+    if delay > Duration::from_millis(0) {
+        time::delay_for(delay).await;
+    }
+}
+
+#[test]
+fn shaper_reserve() {
+    use crate::clock::VirtualClock;
+    use std::sync::Arc;
+
+    let clock = VirtualClock::new();
+    let _clock = clock::enter(Some(Arc::new(clock.clone())));
+
+    let class: QosClass = "bulk=100000/20000".parse().unwrap();
+    assert_eq!(class.to_string(), "bulk=100000/20000");
+    let m: QosMatch = "bulk=tcp:443@10.6.0.0/24".parse().unwrap();
+    assert_eq!(m.to_string(), "bulk=tcp:443@10.6.0.0-10.6.0.255");
+    assert!("bulk=icmp".parse::<QosMatch>().is_err());
+
+    let mut shaper = Shaper::new(&[class], &[m, "unknown=udp".parse().unwrap()]);
+    let src = Ipv4Addr::new(10, 6, 0, 1);
+    assert_eq!(shaper.classify(PortProtocol::Tcp, src, 443), Some(0));
+    assert_eq!(shaper.classify(PortProtocol::Tcp, src, 80), None);
+    assert_eq!(shaper.classify(PortProtocol::Udp, src, 443), None);
+    assert_eq!(
+        shaper.classify(PortProtocol::Tcp, Ipv4Addr::new(10, 6, 1, 1), 443),
+        None
+    );
+
+    // The burst is sent at once, and the debt is paid by waiting
+    assert_eq!(shaper.budget(0, QosDirection::Download), 20000);
+    assert_eq!(
+        shaper.reserve(0, QosDirection::Download, 20000),
+        Duration::from_millis(0)
+    );
+    assert_eq!(
+        shaper.reserve(0, QosDirection::Download, 10000),
+        Duration::from_millis(100)
+    );
+    assert_eq!(shaper.budget(0, QosDirection::Download), 0);
+    assert_eq!(
+        shaper.delay(0, QosDirection::Download, 10000),
+        Duration::from_millis(200)
+    );
+    // Directions are shaped independently
+    assert_eq!(shaper.budget(0, QosDirection::Upload), 20000);
+
+    // Tokens are refilled at the rate up to the burst
+    clock.advance(Duration::from_millis(200));
+    assert_eq!(shaper.budget(0, QosDirection::Download), 10000);
+    clock.advance(Duration::from_secs(10));
+    assert_eq!(shaper.budget(0, QosDirection::Download), 20000);
+}
@@ -0,0 +1,131 @@
+//! Support for tracking the error rate of each source, like failures connecting through the proxy,
+//! RSTs and malformed packets, and quarantining sources whose error rate exceeds the threshold
+//! for a while, so a misbehaving device cannot hurt the reputation of the proxy or exhaust the
+//! resources of the host.
+
+use lru::LruCache;
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+
+use crate::clock;
+
+/// Represents the max count of sources whose errors are tracked. Sources evicted start over.
+const SOURCE_CAPACITY: usize = 4096;
+
+/// Represents the window in milliseconds of counting errors of a source.
+const ERROR_WINDOW: u64 = 60000;
+
+/// Represents the errors of a source.
+#[derive(Debug)]
+struct Record {
+    errors: u32,
+    instant: Instant,
+    until: Option<Instant>,
+}
+
+/// Represents a quarantine of sources.
+#[derive(Debug)]
+pub(crate) struct Quarantine {
+    threshold: u32,
+    duration: Duration,
+    records: LruCache<Ipv4Addr, Record>,
+}
+
+impl Quarantine {
+    /// Creates a new `Quarantine` quarantining sources with `threshold` errors in a minute for
+    /// the duration.
+    pub(crate) fn new(threshold: u32, duration: Duration) -> Quarantine {
+        Quarantine {
+            threshold: threshold.max(1),
+            duration,
+            records: LruCache::new(SOURCE_CAPACITY),
+        }
+    }
+
+    /// Records an error of the source, and returns if the source is quarantined by the error.
+    pub(crate) fn record(&mut self, ip_addr: Ipv4Addr) -> bool {
+        let now = clock::now();
+        let record = match self.records.get_mut(&ip_addr) {
+            Some(record) => record,
+            None => {
+                self.records.put(
+                    ip_addr,
+                    Record {
+                        errors: 0,
+                        instant: now,
+                        until: None,
+                    },
+                );
+
+                self.records.get_mut(&ip_addr).unwrap()
+            }
+        };
+        if record.until.is_some() {
+            return false;
+        }
+        if now.saturating_duration_since(record.instant) >= Duration::from_millis(ERROR_WINDOW) {
+            record.errors = 0;
+            record.instant = now;
+        }
+
+        record.errors += 1;
+        if record.errors < self.threshold {
+            return false;
+        }
+        record.until = Some(now + self.duration);
+
+        true
+    }
+
+    /// Returns if the source is quarantined. The quarantine of the source is lifted if it
+    /// expires.
+    pub(crate) fn is_quarantined(&mut self, ip_addr: Ipv4Addr) -> bool {
+        let until = match self.records.peek(&ip_addr).and_then(|record| record.until) {
+            Some(until) => until,
+            None => return false,
+        };
+        if clock::now() < until {
+            return true;
+        }
+        self.records.pop(&ip_addr);
+
+        false
+    }
+
+    /// Returns the duration of a quarantine.
+    pub(crate) fn duration(&self) -> Duration {
+        self.duration
+    }
+}
+
+#[test]
+fn quarantine_record() {
+    use crate::clock::VirtualClock;
+    use std::sync::Arc;
+
+    let clock = VirtualClock::new();
+    let _clock = clock::enter(Some(Arc::new(clock.clone())));
+
+    let src = Ipv4Addr::new(10, 6, 0, 1);
+    let other = Ipv4Addr::new(10, 6, 0, 2);
+    let mut quarantine = Quarantine::new(3, Duration::from_secs(300));
+
+    // Errors out of the window are forgotten
+    assert!(!quarantine.record(src));
+    assert!(!quarantine.record(src));
+    clock.advance(Duration::from_millis(ERROR_WINDOW));
+    assert!(!quarantine.record(src));
+    assert!(!quarantine.record(src));
+    assert!(!quarantine.is_quarantined(src));
+
+    // The source is quarantined once
+    assert!(quarantine.record(src));
+    assert!(!quarantine.record(src));
+    assert!(quarantine.is_quarantined(src));
+    assert!(!quarantine.is_quarantined(other));
+
+    // The quarantine expires
+    clock.advance(Duration::from_secs(300));
+    assert!(!quarantine.is_quarantined(src));
+    assert!(!quarantine.record(src));
+}
@@ -0,0 +1,105 @@
+//! Support for limiting the rate of connection attempts of each source with token buckets, so a
+//! port-scanning or infected device cannot exhaust the connections to the proxy.
+
+use lru::LruCache;
+use std::net::Ipv4Addr;
+use std::time::Instant;
+
+use crate::clock;
+
+/// Represents the max count of sources whose buckets are kept. Sources evicted start over with a
+/// full bucket.
+const BUCKET_CAPACITY: usize = 4096;
+
+/// Represents the token bucket of a source.
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    instant: Instant,
+}
+
+/// Represents a rate limiter of connection attempts of each source.
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    rate: u32,
+    burst: u32,
+    buckets: LruCache<Ipv4Addr, Bucket>,
+}
+
+impl RateLimiter {
+    /// Creates a new `RateLimiter` admitting `rate` attempts of each source per second on
+    /// average, and at most `burst` attempts at once.
+    pub(crate) fn new(rate: u32, burst: u32) -> RateLimiter {
+        RateLimiter {
+            rate,
+            burst: burst.max(1),
+            buckets: LruCache::new(BUCKET_CAPACITY),
+        }
+    }
+
+    /// Returns if an attempt of the source is admitted, which takes a token from the bucket of
+    /// the source.
+    pub(crate) fn admit(&mut self, ip_addr: Ipv4Addr) -> bool {
+        let now = clock::now();
+        let (rate, burst) = (self.rate as f64, self.burst as f64);
+        let bucket = match self.buckets.get_mut(&ip_addr) {
+            Some(bucket) => {
+                let elapsed = now.saturating_duration_since(bucket.instant);
+                bucket.tokens = (bucket.tokens + elapsed.as_secs_f64() * rate).min(burst);
+                bucket.instant = now;
+
+                bucket
+            }
+            None => {
+                self.buckets.put(
+                    ip_addr,
+                    Bucket {
+                        tokens: burst,
+                        instant: now,
+                    },
+                );
+
+                self.buckets.get_mut(&ip_addr).unwrap()
+            }
+        };
+
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+
+        true
+    }
+}
+
+#[test]
+fn rate_limiter_admit() {
+    use crate::clock::VirtualClock;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    let clock = VirtualClock::new();
+    let _clock = clock::enter(Some(Arc::new(clock.clone())));
+
+    let src = Ipv4Addr::new(10, 6, 0, 1);
+    let other = Ipv4Addr::new(10, 6, 0, 2);
+    let mut limiter = RateLimiter::new(2, 3);
+
+    // The burst is admitted at once
+    for _ in 0..3 {
+        assert!(limiter.admit(src));
+    }
+    assert!(!limiter.admit(src));
+    // Sources are limited independently
+    assert!(limiter.admit(other));
+
+    // Tokens are refilled at the rate up to the burst
+    clock.advance(Duration::from_millis(500));
+    assert!(limiter.admit(src));
+    assert!(!limiter.admit(src));
+    clock.advance(Duration::from_secs(10));
+    for _ in 0..3 {
+        assert!(limiter.admit(src));
+    }
+    assert!(!limiter.admit(src));
+}
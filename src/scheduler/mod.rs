@@ -0,0 +1,128 @@
+//! Support for scheduling frames sent to the pcap device in two queues, so small packets, pure
+//! ACKs and game traffic are not delayed behind bulk segments of a large download while the
+//! device is busy.
+
+use std::collections::VecDeque;
+use std::io;
+
+/// Represents the max size in bytes of frames waiting in the high priority queue.
+const HIGH_QUEUE_SIZE: usize = 256 * 1024;
+/// Represents the max size in bytes of frames waiting in the low priority queue. Bulk segments
+/// beyond it are dropped, and are retransmitted later.
+const LOW_QUEUE_SIZE: usize = 1024 * 1024;
+
+/// Represents the max size in bytes of a frame which is always sent in high priority, which
+/// covers pure ACKs, ARP, ICMP and small datagrams.
+pub(crate) const SMALL_FRAME_SIZE: usize = 128;
+
+/// Represents the priority of a frame.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub(crate) enum TxPriority {
+    /// Represents small packets, pure ACKs and game traffic.
+    High,
+    /// Represents bulk segments.
+    Low,
+}
+
+/// Represents a scheduler of frames sent to the pcap device. Frames are sent at once while the
+/// device accepts them, and wait in the queue of their priority once the device is busy. The high
+/// priority queue is always drained first.
+#[derive(Debug, Default)]
+pub(crate) struct TxScheduler {
+    high: VecDeque<Vec<u8>>,
+    low: VecDeque<Vec<u8>>,
+    high_size: usize,
+    low_size: usize,
+}
+
+impl TxScheduler {
+    /// Creates a new `TxScheduler`.
+    pub(crate) fn new() -> TxScheduler {
+        TxScheduler::default()
+    }
+
+    /// Appends the frame to the queue of the priority. Returns false if the queue is full and the
+    /// frame is dropped.
+    pub(crate) fn push(&mut self, frame: Vec<u8>, priority: TxPriority) -> bool {
+        let (queue, size, limit) = match priority {
+            TxPriority::High => (&mut self.high, &mut self.high_size, HIGH_QUEUE_SIZE),
+            TxPriority::Low => (&mut self.low, &mut self.low_size, LOW_QUEUE_SIZE),
+        };
+        if *size + frame.len() > limit {
+            return false;
+        }
+        *size += frame.len();
+        queue.push_back(frame);
+
+        true
+    }
+
+    /// Puts the frame back to the front of the queue of the priority, like after the device
+    /// rejects it.
+    pub(crate) fn push_front(&mut self, frame: Vec<u8>, priority: TxPriority) {
+        match priority {
+            TxPriority::High => {
+                self.high_size += frame.len();
+                self.high.push_front(frame);
+            }
+            TxPriority::Low => {
+                self.low_size += frame.len();
+                self.low.push_front(frame);
+            }
+        }
+    }
+
+    /// Removes the next frame to be sent, which is of high priority if there is any.
+    pub(crate) fn pop(&mut self) -> Option<(Vec<u8>, TxPriority)> {
+        if let Some(frame) = self.high.pop_front() {
+            self.high_size -= frame.len();
+            return Some((frame, TxPriority::High));
+        }
+        let frame = self.low.pop_front()?;
+        self.low_size -= frame.len();
+
+        Some((frame, TxPriority::Low))
+    }
+
+    /// Returns if no frame is waiting.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.high.is_empty() && self.low.is_empty()
+    }
+}
+
+/// Returns if the error of sending to the pcap device means the device is busy, and the frame
+/// can be sent again later.
+pub(crate) fn is_busy(e: &io::Error) -> bool {
+    if e.kind() == io::ErrorKind::WouldBlock {
+        return true;
+    }
+    #[cfg(unix)]
+    if e.raw_os_error() == Some(libc::ENOBUFS) {
+        return true;
+    }
+
+    false
+}
+
+#[test]
+fn tx_scheduler_pop() {
+    let mut scheduler = TxScheduler::new();
+    assert!(scheduler.push(vec![1; 1500], TxPriority::Low));
+    assert!(scheduler.push(vec![2; 1500], TxPriority::Low));
+    assert!(scheduler.push(vec![3; 64], TxPriority::High));
+
+    // High priority frames jump ahead of bulk segments waiting
+    assert_eq!(scheduler.pop(), Some((vec![3; 64], TxPriority::High)));
+    let (frame, priority) = scheduler.pop().unwrap();
+    assert_eq!(frame[0], 1);
+    scheduler.push_front(frame, priority);
+    assert_eq!(scheduler.pop().unwrap().0[0], 1);
+    assert_eq!(scheduler.pop().unwrap().0[0], 2);
+    assert!(scheduler.pop().is_none());
+    assert!(scheduler.is_empty());
+
+    // Bulk segments beyond the queue are dropped
+    while scheduler.push(vec![0; 1500], TxPriority::Low) {}
+    assert!(scheduler.push(vec![0; 64], TxPriority::High));
+    assert!(!scheduler.push(vec![0; 1500], TxPriority::Low));
+}
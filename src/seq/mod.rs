@@ -0,0 +1,52 @@
+//! Support for the arithmetic of `u32` sequences which wrap around 2^32, as used in TCP.
+
+/// Returns the sequence `n` after the given sequence.
+pub fn seq_add(sequence: u32, n: u32) -> u32 {
+    sequence.wrapping_add(n)
+}
+
+/// Returns the distance from the sequence `rhs` forward to the sequence `lhs`.
+pub fn seq_sub(lhs: u32, rhs: u32) -> u32 {
+    lhs.wrapping_sub(rhs)
+}
+
+/// Returns if the sequence is in the range [begin, end).
+pub fn seq_between(begin: u32, sequence: u32, end: u32) -> bool {
+    seq_sub(sequence, begin) < seq_sub(end, begin)
+}
+
+#[cfg(test)]
+use proptest::prelude::*;
+
+#[cfg(test)]
+proptest! {
+    #[test]
+    fn seq_add_sub(sequence: u32, n: u32) {
+        prop_assert_eq!(seq_sub(seq_add(sequence, n), sequence), n);
+        prop_assert_eq!(seq_add(sequence, seq_sub(n, sequence)), n);
+    }
+
+    #[test]
+    fn seq_add_wrap(offset in 0u32..65536, n in 1u32..65536) {
+        let sequence = u32::MAX - offset;
+        let next = seq_add(sequence, n);
+
+        if n > offset {
+            prop_assert_eq!(next, n - offset - 1);
+        } else {
+            prop_assert_eq!(next, sequence + n);
+        }
+        prop_assert_eq!(seq_sub(next, sequence), n);
+    }
+
+    #[test]
+    fn seq_between_wrap(offset in 0u32..65536, n in 1u32..65536, i in 0u32..65536) {
+        let begin = u32::MAX - offset;
+        let end = seq_add(begin, n);
+        let sequence = seq_add(begin, i);
+
+        prop_assert_eq!(seq_between(begin, sequence, end), i < n);
+        prop_assert!(!seq_between(begin, end, end));
+        prop_assert!(seq_between(begin, begin, end));
+    }
+}
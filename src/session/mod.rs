@@ -0,0 +1,365 @@
+//! Support for recording every frame of a session with its timing to a file, and replaying the
+//! session through the stack offline, so the frames sent in the replay can be compared with a
+//! golden trace to lock in fixes for sequencing bugs.
+//!
+//! Sessions are recorded in pcapng with the direction of each frame, and can be opened in
+//! Wireshark as well.
+
+use pnet::datalink::{self, DataLinkReceiver, DataLinkSender};
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::net::SocketAddrV4;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::capture;
+use crate::clock::{self, VirtualClock};
+use crate::packet::layer::Layers;
+use crate::packet::Indicator;
+use crate::pcap::{self, Loopback, Receiver, Sender};
+
+/// Represents the max size of frames recorded.
+const SNAPLEN: u32 = 65535;
+
+/// Represents a writer of the frames of a session.
+struct SessionWriter {
+    file: File,
+    timestamp: Duration,
+    instant: Instant,
+}
+
+impl SessionWriter {
+    fn write(&mut self, frame: &[u8], is_inbound: bool) -> io::Result<()> {
+        let timestamp = self.timestamp + clock::elapsed(self.instant);
+
+        self.file.write_all(&capture::serialize_frame(
+            timestamp,
+            frame,
+            frame.len(),
+            is_inbound,
+        ))
+    }
+}
+
+/// Represents the send half of a pcap device whose frames are recorded.
+pub struct RecordSender {
+    tx: Sender,
+    writer: Arc<Mutex<SessionWriter>>,
+}
+
+impl DataLinkSender for RecordSender {
+    fn build_and_send(
+        &mut self,
+        num_packets: usize,
+        packet_size: usize,
+        func: &mut dyn FnMut(&mut [u8]),
+    ) -> Option<io::Result<()>> {
+        for _ in 0..num_packets {
+            let mut buffer = vec![0u8; packet_size];
+            func(&mut buffer);
+            if let Some(Err(e)) = self.send_to(&buffer, None) {
+                return Some(Err(e));
+            }
+        }
+
+        Some(Ok(()))
+    }
+
+    fn send_to(
+        &mut self,
+        packet: &[u8],
+        dst: Option<datalink::NetworkInterface>,
+    ) -> Option<io::Result<()>> {
+        if let Err(e) = self.writer.lock().unwrap().write(packet, false) {
+            return Some(Err(e));
+        }
+
+        self.tx.send_to(packet, dst)
+    }
+}
+
+/// Represents the receive half of a pcap device whose frames are recorded.
+pub struct RecordReceiver {
+    rx: Receiver,
+    writer: Arc<Mutex<SessionWriter>>,
+}
+
+impl DataLinkReceiver for RecordReceiver {
+    fn next(&mut self) -> io::Result<&[u8]> {
+        let frame = self.rx.next()?;
+        self.writer.lock().unwrap().write(frame, true)?;
+
+        Ok(frame)
+    }
+}
+
+/// Records every frame received from and sent to a pcap device to the file, and returns its send
+/// half and its receive half recording frames. The file is overwritten if it exists.
+pub fn record(tx: Sender, rx: Receiver, path: &Path) -> io::Result<(Sender, Receiver)> {
+    let mut file = File::create(path)?;
+    file.write_all(&capture::serialize_header("pcap2socks session", SNAPLEN))?;
+    let writer = Arc::new(Mutex::new(SessionWriter {
+        file,
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default(),
+        instant: clock::now(),
+    }));
+
+    Ok((
+        Box::new(RecordSender {
+            tx,
+            writer: Arc::clone(&writer),
+        }),
+        Box::new(RecordReceiver { rx, writer }),
+    ))
+}
+
+/// Represents a frame of a session.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SessionFrame {
+    timestamp: Duration,
+    frame: Vec<u8>,
+    is_inbound: bool,
+}
+
+impl SessionFrame {
+    /// Returns the time since the first frame of the session.
+    pub fn timestamp(&self) -> Duration {
+        self.timestamp
+    }
+
+    /// Returns the content of the frame.
+    pub fn frame(&self) -> &[u8] {
+        &self.frame
+    }
+
+    /// Returns if the frame is received from the pcap device.
+    pub fn is_inbound(&self) -> bool {
+        self.is_inbound
+    }
+}
+
+/// Represents a session recorded.
+#[derive(Clone, Debug, Default)]
+pub struct Session {
+    frames: Vec<SessionFrame>,
+}
+
+impl Session {
+    /// Opens a session recorded by `record`.
+    pub fn open(path: &Path) -> io::Result<Session> {
+        Session::parse(&fs::read(path)?)
+    }
+
+    /// Parses a session recorded by `record`.
+    pub fn parse(buffer: &[u8]) -> io::Result<Session> {
+        let frames = capture::parse(buffer)?;
+        let first = frames
+            .first()
+            .map_or(Duration::from_millis(0), |(timestamp, _, _)| *timestamp);
+        let frames = frames
+            .into_iter()
+            .map(|(timestamp, frame, is_inbound)| SessionFrame {
+                timestamp: timestamp.checked_sub(first).unwrap_or_default(),
+                frame,
+                is_inbound,
+            })
+            .collect();
+
+        Ok(Session { frames })
+    }
+
+    /// Returns all the frames of the session.
+    pub fn frames(&self) -> &[SessionFrame] {
+        &self.frames
+    }
+
+    /// Returns the frames received from the pcap device.
+    pub fn inbound(&self) -> Vec<Vec<u8>> {
+        self.frames
+            .iter()
+            .filter(|frame| frame.is_inbound)
+            .map(|frame| frame.frame.clone())
+            .collect()
+    }
+
+    /// Returns the frames sent to the pcap device.
+    pub fn outbound(&self) -> Vec<Vec<u8>> {
+        self.frames
+            .iter()
+            .filter(|frame| !frame.is_inbound)
+            .map(|frame| frame.frame.clone())
+            .collect()
+    }
+
+    /// Returns the trace of the frames sent in the session, the same as `trace`.
+    pub fn trace(&self) -> Vec<String> {
+        trace(&self.inbound(), &self.outbound())
+    }
+}
+
+/// Represents the receive half of a pcap device replaying the frames received in a session. If a
+/// virtual clock is given, it is advanced to the time of each frame before the frame is received,
+/// which should be the clock set in the `Config` of the `Redirector` receiving. Receiving reports an error of the kind
+/// `UnexpectedEof` once all the frames are received.
+pub struct ReplayReceiver {
+    frames: VecDeque<(Duration, Vec<u8>)>,
+    clock: Option<VirtualClock>,
+    instant: Instant,
+    buffer: Vec<u8>,
+}
+
+impl DataLinkReceiver for ReplayReceiver {
+    fn next(&mut self) -> io::Result<&[u8]> {
+        let (timestamp, frame) = match self.frames.pop_front() {
+            Some(frame) => frame,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "replay finished",
+                ))
+            }
+        };
+        if let Some(ref clock) = self.clock {
+            let now = clock::Clock::now(clock);
+            let instant = self.instant + timestamp;
+            if instant > now {
+                clock.advance(instant - now);
+            }
+        }
+        self.buffer = frame;
+
+        Ok(&self.buffer)
+    }
+}
+
+/// Replays the frames received in the session, and returns the send half and the receive half of
+/// an in-memory pcap device, and its other end collecting the frames sent.
+pub fn replay(session: &Session, clock: Option<VirtualClock>) -> (Sender, Receiver, Loopback) {
+    let (tx, _, loopback) = pcap::memory();
+    let instant = match clock {
+        Some(ref clock) => clock::Clock::now(clock),
+        None => Instant::now(),
+    };
+    let rx = ReplayReceiver {
+        frames: session
+            .frames
+            .iter()
+            .filter(|frame| frame.is_inbound)
+            .map(|frame| (frame.timestamp, frame.frame.clone()))
+            .collect(),
+        clock,
+        instant,
+        buffer: Vec::new(),
+    };
+
+    (tx, Box::new(rx), loopback)
+}
+
+/// Returns the trace of the frames sent, one line for each frame. TCP sequence numbers and
+/// acknowledgement numbers are relative to the initial sequence numbers of both directions like
+/// in Wireshark, so the traces of a session and its replays can be compared although initial
+/// sequence numbers are random.
+pub fn trace(inbound: &[Vec<u8>], outbound: &[Vec<u8>]) -> Vec<String> {
+    let mut isns: HashMap<(SocketAddrV4, SocketAddrV4), u32> = HashMap::new();
+    let mut lines = Vec::with_capacity(outbound.len());
+
+    let frames = inbound
+        .iter()
+        .map(|frame| (frame, true))
+        .chain(outbound.iter().map(|frame| (frame, false)));
+    for (frame, is_inbound) in frames {
+        let indicator = match Indicator::from(frame) {
+            Ok(indicator) => indicator,
+            Err(ref e) => {
+                if !is_inbound {
+                    lines.push(format!("malformed ({} Bytes): {}", frame.len(), e));
+                }
+                continue;
+            }
+        };
+        let tcp = match indicator.transport() {
+            Some(Layers::Tcp(tcp)) => tcp,
+            _ => {
+                if !is_inbound {
+                    lines.push(indicator.brief());
+                }
+                continue;
+            }
+        };
+
+        let src = SocketAddrV4::new(tcp.src_ip_addr(), tcp.src());
+        let dst = SocketAddrV4::new(tcp.dst_ip_addr(), tcp.dst());
+        if tcp.is_syn() || !isns.contains_key(&(src, dst)) {
+            isns.insert((src, dst), tcp.sequence());
+        }
+        if is_inbound {
+            continue;
+        }
+
+        let sequence = tcp.sequence().wrapping_sub(isns[&(src, dst)]);
+        let acknowledgement = match (tcp.is_ack(), isns.get(&(dst, src))) {
+            (true, Some(isn)) => tcp.acknowledgement().wrapping_sub(*isn),
+            _ => tcp.acknowledgement(),
+        };
+        let len = indicator.content_len() - indicator.len();
+        lines.push(format!(
+            "{} Seq = {}, Ack = {}, Window = {}, Length = {}",
+            indicator.brief(),
+            sequence,
+            acknowledgement,
+            tcp.window(),
+            len
+        ));
+    }
+
+    lines
+}
+
+#[test]
+fn session_record_replay() {
+    use crate::testing::FrameBuilder;
+
+    let path = std::env::temp_dir().join(format!("pcap2socks-session-{}", std::process::id()));
+    let builder = FrameBuilder::new(
+        "10.6.0.1:50000".parse().unwrap(),
+        "1.1.1.1:80".parse().unwrap(),
+    );
+
+    // Record
+    let (tx, rx, mut loopback) = pcap::memory();
+    let (mut tx, mut rx) = record(tx, rx, &path).unwrap();
+    loopback.inject(&builder.syn(1000));
+    loopback.close();
+    assert!(rx.next().is_ok());
+    tx.send_to(&builder.reverse().syn_ack(5000, 1001), None)
+        .unwrap()
+        .unwrap();
+    tx.send_to(&builder.reverse().ack(5001, 1001, b"hello"), None)
+        .unwrap()
+        .unwrap();
+    drop(tx);
+    drop(rx);
+
+    let session = Session::open(&path).unwrap();
+    let _ = fs::remove_file(&path);
+    assert_eq!(session.frames().len(), 3);
+    assert!(session.frames()[0].is_inbound());
+    assert_eq!(session.frames()[0].timestamp(), Duration::from_millis(0));
+    assert_eq!(session.outbound().len(), 2);
+    assert_eq!(
+        session.trace(),
+        vec![
+            "TCP: 1.1.1.1:80 -> 10.6.0.1:50000 [S.] Seq = 0, Ack = 1, Window = 65535, Length = 0",
+            "TCP: 1.1.1.1:80 -> 10.6.0.1:50000 [.] Seq = 1, Ack = 1, Window = 65535, Length = 5",
+        ]
+    );
+
+    // Replay
+    let (_, mut rx, _) = replay(&session, None);
+    assert_eq!(rx.next().unwrap(), session.frames()[0].frame());
+    assert_eq!(rx.next().unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+}
@@ -0,0 +1,193 @@
+//! Support for snapshotting the state learned from sources, so a restart does not break the NAT
+//! traversal of active sessions.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::pcap::HardwareAddr;
+
+/// Represents a snapshot of UDP NAT bindings, the neighbor table and the learned MTU.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub(crate) struct Snapshot {
+    /// Represents the map mapping a source IP address to its hardware address.
+    pub(crate) neighbors: HashMap<Ipv4Addr, HardwareAddr>,
+    /// Represents the map mapping a source IP address to its MTU.
+    pub(crate) src_mtus: HashMap<Ipv4Addr, usize>,
+    /// Represents the map mapping a destination IP address to its path MTU and the time it is
+    /// learned.
+    pub(crate) dst_mtus: HashMap<Ipv4Addr, (usize, SystemTime)>,
+    /// Represents the map mapping a source port to its local UDP port.
+    pub(crate) udp_ports: HashMap<SocketAddrV4, u16>,
+}
+
+impl Snapshot {
+    /// Creates a new `Snapshot`.
+    pub(crate) fn new() -> Snapshot {
+        Snapshot::default()
+    }
+
+    /// Loads the snapshot from the path. Returns an empty snapshot if the file does not exist.
+    pub(crate) fn load<P: AsRef<Path>>(path: P) -> io::Result<Snapshot> {
+        let mut snapshot = Snapshot::new();
+
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(snapshot),
+            Err(e) => return Err(e),
+        };
+        for line in content.lines() {
+            let fields: Vec<_> = line.split_whitespace().collect();
+            let is_valid = match fields.as_slice() {
+                ["neighbor", ip_addr, hardware_addr] => {
+                    match (ip_addr.parse(), hardware_addr.parse()) {
+                        (Ok(ip_addr), Ok(hardware_addr)) => {
+                            snapshot.neighbors.insert(ip_addr, hardware_addr);
+                            true
+                        }
+                        _ => false,
+                    }
+                }
+                ["mtu", ip_addr, mtu] => match (ip_addr.parse(), mtu.parse()) {
+                    (Ok(ip_addr), Ok(mtu)) => {
+                        snapshot.src_mtus.insert(ip_addr, mtu);
+                        true
+                    }
+                    _ => false,
+                },
+                ["path-mtu", ip_addr, mtu, secs] => {
+                    match (ip_addr.parse(), mtu.parse(), secs.parse()) {
+                        (Ok(ip_addr), Ok(mtu), Ok(secs)) => {
+                            let instant = UNIX_EPOCH + Duration::from_secs(secs);
+                            snapshot.dst_mtus.insert(ip_addr, (mtu, instant));
+                            true
+                        }
+                        _ => false,
+                    }
+                }
+                ["udp", src, port] => match (src.parse(), port.parse()) {
+                    (Ok(src), Ok(port)) => {
+                        snapshot.udp_ports.insert(src, port);
+                        true
+                    }
+                    _ => false,
+                },
+                _ => false,
+            };
+            if !is_valid {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "malformed snapshot",
+                ));
+            }
+        }
+
+        Ok(snapshot)
+    }
+
+    /// Saves the snapshot to the path. The file is replaced at once, so a crash while saving
+    /// never leaves a partial snapshot.
+    pub(crate) fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut content = String::new();
+        for (ip_addr, hardware_addr) in &self.neighbors {
+            content.push_str(&format!("neighbor {} {}\n", ip_addr, hardware_addr));
+        }
+        for (ip_addr, mtu) in &self.src_mtus {
+            content.push_str(&format!("mtu {} {}\n", ip_addr, mtu));
+        }
+        for (ip_addr, (mtu, instant)) in &self.dst_mtus {
+            let secs = instant
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or(Duration::from_secs(0))
+                .as_secs();
+            content.push_str(&format!("path-mtu {} {} {}\n", ip_addr, mtu, secs));
+        }
+        for (src, port) in &self.udp_ports {
+            content.push_str(&format!("udp {} {}\n", src, port));
+        }
+
+        let mut tmp_path = path.as_ref().as_os_str().to_os_string();
+        tmp_path.push(".tmp");
+        fs::write(&tmp_path, content)?;
+
+        fs::rename(&tmp_path, path)
+    }
+
+    /// Merges the other snapshot into the snapshot.
+    fn merge(&mut self, other: &Snapshot) {
+        self.neighbors.extend(other.neighbors.iter());
+        self.src_mtus.extend(other.src_mtus.iter());
+        self.dst_mtus.extend(other.dst_mtus.iter());
+        self.udp_ports.extend(other.udp_ports.iter());
+    }
+}
+
+/// Represents the snapshots of multiple workers persisted to a file as a whole.
+#[derive(Debug)]
+pub(crate) struct Snapshots {
+    path: PathBuf,
+    parts: Vec<Snapshot>,
+}
+
+impl Snapshots {
+    /// Creates a new `Snapshots` of the given count of workers persisted to the path.
+    pub(crate) fn new(path: PathBuf, n: usize) -> Snapshots {
+        Snapshots {
+            path,
+            parts: vec![Snapshot::new(); n],
+        }
+    }
+
+    /// Returns the persisted path of the snapshots.
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Replaces the snapshot of the worker and saves the snapshots of all the workers.
+    pub(crate) fn update(&mut self, i: usize, snapshot: Snapshot) -> io::Result<()> {
+        self.parts[i] = snapshot;
+
+        let mut merged = Snapshot::new();
+        for part in &self.parts {
+            merged.merge(part);
+        }
+
+        merged.save(&self.path)
+    }
+}
+
+#[test]
+fn snapshots_persist() {
+    let path = std::env::temp_dir().join(format!("pcap2socks-snapshot-{}", std::process::id()));
+    let ip_addr = Ipv4Addr::new(10, 6, 0, 2);
+    let src = SocketAddrV4::new(ip_addr, 3074);
+
+    let mut first = Snapshot::new();
+    first
+        .neighbors
+        .insert(ip_addr, pnet::datalink::MacAddr(2, 0, 0, 0, 0, 1));
+    first.src_mtus.insert(ip_addr, 1492);
+    first.dst_mtus.insert(
+        Ipv4Addr::new(1, 1, 1, 1),
+        (1400, UNIX_EPOCH + Duration::from_secs(1_600_000_000)),
+    );
+    let mut second = Snapshot::new();
+    second.udp_ports.insert(src, 54321);
+
+    // Snapshots of workers are merged
+    let mut snapshots = Snapshots::new(path.clone(), 2);
+    snapshots.update(0, first.clone()).unwrap();
+    snapshots.update(1, second).unwrap();
+
+    let snapshot = Snapshot::load(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+
+    assert_eq!(snapshot.neighbors, first.neighbors);
+    assert_eq!(snapshot.src_mtus, first.src_mtus);
+    assert_eq!(snapshot.dst_mtus, first.dst_mtus);
+    assert_eq!(snapshot.udp_ports.get(&src), Some(&54321));
+    assert_eq!(Snapshot::load(&path).unwrap(), Snapshot::new());
+}
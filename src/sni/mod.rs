@@ -0,0 +1,355 @@
+//! Support for filtering TCP connections by the server name indication (SNI) in their TLS
+//! ClientHellos, which blocks the connections or connects them directly instead of through the
+//! proxy, like for parental control and policy routing.
+
+use log::{trace, warn};
+use std::collections::HashMap;
+use std::net::SocketAddrV4;
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tokio::time;
+
+use crate::config::SniAction;
+use crate::socks::{self, SocksOption, TcpConnection};
+
+/// Represents the max size of the data buffered of a TCP connection before its ClientHello is
+/// parsed. Connections whose ClientHellos are larger cannot be inspected.
+pub(crate) const MAX_HELLO_SIZE: usize = 16 * 1024;
+
+/// Represents the timeout in milliseconds of waiting for the ClientHello of a TCP connection.
+/// Connections which have not sent a ClientHello cannot be inspected.
+const HELLO_TIMEOUT: u64 = 10000;
+
+/// Represents the TLS record type of handshakes.
+const RECORD_HANDSHAKE: u8 = 22;
+/// Represents the TLS handshake type of ClientHellos.
+const HANDSHAKE_CLIENT_HELLO: u8 = 1;
+/// Represents the TLS extension type of server names.
+const EXTENSION_SERVER_NAME: u16 = 0;
+/// Represents the server name type of host names.
+const NAME_HOST_NAME: u8 = 0;
+
+/// Represents the result of parsing the server name in a ClientHello.
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum ServerName {
+    /// Represents more data is required.
+    Incomplete,
+    /// Represents the data is not a ClientHello, or the ClientHello carries no server name.
+    Absent,
+    /// Represents the ClientHello is too large to be parsed.
+    Oversized,
+    /// Represents the server name.
+    Name(String),
+}
+
+/// Parses the server name in the ClientHello at the beginning of the data, which may span
+/// multiple TLS records.
+fn parse_server_name(data: &[u8]) -> ServerName {
+    // Reassemble the handshake from records
+    let mut handshake = Vec::new();
+    let mut offset = 0;
+    loop {
+        if handshake.len() >= 4 {
+            let len = read_u24(&handshake[1..]) + 4;
+            if handshake.len() >= len {
+                handshake.truncate(len);
+                break;
+            }
+        }
+        if data.len() < offset + 5 {
+            return ServerName::Incomplete;
+        }
+        if data[offset] != RECORD_HANDSHAKE || data[offset + 1] != 3 {
+            return ServerName::Absent;
+        }
+        let len = read_u16(&data[offset + 3..]) as usize;
+        if data.len() < offset + 5 + len {
+            return ServerName::Incomplete;
+        }
+        handshake.extend_from_slice(&data[offset + 5..offset + 5 + len]);
+        offset += 5 + len;
+    }
+    if handshake[0] != HANDSHAKE_CLIENT_HELLO {
+        return ServerName::Absent;
+    }
+
+    match find_server_name(&handshake[4..]) {
+        Some(name) => ServerName::Name(name),
+        None => ServerName::Absent,
+    }
+}
+
+fn find_server_name(hello: &[u8]) -> Option<String> {
+    // Version and random
+    let mut offset = 2 + 32;
+    // Session ID
+    offset += 1 + *hello.get(offset)? as usize;
+    // Cipher suites
+    offset += 2 + read_u16(hello.get(offset..)?) as usize;
+    // Compression methods
+    offset += 1 + *hello.get(offset)? as usize;
+
+    // Extensions
+    let len = read_u16(hello.get(offset..)?) as usize;
+    let extensions = hello.get(offset + 2..offset + 2 + len)?;
+    let mut offset = 0;
+    while offset + 4 <= extensions.len() {
+        let extension_type = read_u16(&extensions[offset..]);
+        let len = read_u16(&extensions[offset + 2..]) as usize;
+        let extension = extensions.get(offset + 4..offset + 4 + len)?;
+        offset += 4 + len;
+        if extension_type != EXTENSION_SERVER_NAME {
+            continue;
+        }
+
+        let len = read_u16(extension) as usize;
+        let list = extension.get(2..2 + len)?;
+        let mut offset = 0;
+        while offset + 3 <= list.len() {
+            let name_type = list[offset];
+            let len = read_u16(&list[offset + 1..]) as usize;
+            let name = list.get(offset + 3..offset + 3 + len)?;
+            offset += 3 + len;
+            if name_type == NAME_HOST_NAME {
+                return String::from_utf8(name.to_vec()).ok();
+            }
+        }
+
+        return None;
+    }
+
+    None
+}
+
+fn read_u16(data: &[u8]) -> u16 {
+    if data.len() < 2 {
+        return 0;
+    }
+
+    u16::from_be_bytes([data[0], data[1]])
+}
+
+fn read_u24(data: &[u8]) -> usize {
+    ((data[0] as usize) << 16) | ((data[1] as usize) << 8) | data[2] as usize
+}
+
+/// Represents the inspection of a TCP connection, which buffers the data from the source until
+/// its ClientHello is parsed.
+struct Inspection {
+    buffer: Vec<u8>,
+    /// Represents the sender of whether the connection bypasses the proxy.
+    tx: oneshot::Sender<bool>,
+}
+
+/// Represents a filter of TCP connections by the server names in their ClientHellos.
+pub(crate) struct SniFilter {
+    rules: Vec<(String, SniAction)>,
+    ports: Vec<u16>,
+    /// Represents if connections which cannot be inspected are blocked.
+    is_fail_closed: bool,
+    inspections: HashMap<(SocketAddrV4, SocketAddrV4), Inspection>,
+}
+
+impl SniFilter {
+    /// Creates a new `SniFilter` inspecting TCP connections to the ports. Connections which cannot
+    /// be inspected are blocked if any rule blocks, unless the filter fails open.
+    pub(crate) fn new(
+        rules: &[(String, SniAction)],
+        ports: &[u16],
+        is_fail_open: bool,
+    ) -> SniFilter {
+        SniFilter {
+            rules: rules
+                .iter()
+                .map(|(pattern, action)| (pattern.to_ascii_lowercase(), *action))
+                .collect(),
+            ports: ports.to_vec(),
+            is_fail_closed: !is_fail_open
+                && rules.iter().any(|(_, action)| *action == SniAction::Block),
+            inspections: HashMap::new(),
+        }
+    }
+
+    /// Returns if TCP connections to the port are inspected.
+    pub(crate) fn is_inspected(&self, port: u16) -> bool {
+        self.ports.contains(&port)
+    }
+
+    /// Inspects the `TcpConnection`, and spawns a task relaying the connection through the
+    /// proxy, or directly if it bypasses the proxy, once its ClientHello is parsed.
+    pub(crate) fn inspect(
+        &mut self,
+        connection: TcpConnection,
+        remote: SocketAddrV4,
+        options: SocksOption,
+    ) {
+        let key = (connection.src(), connection.dst());
+        let (tx, rx) = oneshot::channel();
+        tokio::spawn(relay(connection, rx, remote, options, self.is_fail_closed));
+
+        self.inspections.insert(
+            key,
+            Inspection {
+                buffer: Vec::new(),
+                tx,
+            },
+        );
+    }
+
+    /// Appends the data from the source of the TCP connection being inspected, and returns the
+    /// action once its ClientHello is parsed. A blocked connection is not relayed, and its
+    /// inspection is kept until it is removed, so the connection is not closed before it is reset.
+    pub(crate) fn append(
+        &mut self,
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+        payload: &[u8],
+    ) -> Option<SniAction> {
+        let key = (src, dst);
+        let inspection = self.inspections.get_mut(&key)?;
+        inspection.buffer.extend_from_slice(payload);
+
+        let server_name = match parse_server_name(&inspection.buffer) {
+            ServerName::Incomplete if inspection.buffer.len() < MAX_HELLO_SIZE => return None,
+            ServerName::Incomplete => ServerName::Oversized,
+            server_name => server_name,
+        };
+        let action = match server_name {
+            ServerName::Name(ref name) => {
+                trace!("TCP {} -> {} requests {}", src, dst, name);
+                self.action(name)
+            }
+            ServerName::Oversized if self.is_fail_closed => {
+                trace!("ClientHello of {} -> {} is too large", src, dst);
+                Some(SniAction::Block)
+            }
+            _ => None,
+        };
+        if action == Some(SniAction::Block) {
+            self.inspections.get_mut(&key).unwrap().buffer = Vec::new();
+
+            return action;
+        }
+        let inspection = self.inspections.remove(&key).unwrap();
+        // The relay may have timed out
+        let _ = inspection.tx.send(action == Some(SniAction::Bypass));
+
+        action
+    }
+
+    /// Removes the inspection of the TCP connection, which closes the connection if it is not
+    /// relayed yet.
+    pub(crate) fn remove(&mut self, src: SocketAddrV4, dst: SocketAddrV4) {
+        self.inspections.remove(&(src, dst));
+    }
+
+    /// Returns the action of the first rule matching the server name.
+    fn action(&self, name: &str) -> Option<SniAction> {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+
+        self.rules
+            .iter()
+            .find(|(pattern, _)| match pattern.strip_prefix("*.") {
+                Some(domain) => is_subdomain(&name, domain),
+                None => name == *pattern || is_subdomain(&name, pattern),
+            })
+            .map(|(_, action)| *action)
+    }
+}
+
+fn is_subdomain(name: &str, domain: &str) -> bool {
+    name.len() > domain.len()
+        && name.ends_with(domain)
+        && name.as_bytes()[name.len() - domain.len() - 1] == b'.'
+}
+
+async fn relay(
+    connection: TcpConnection,
+    rx: oneshot::Receiver<bool>,
+    remote: SocketAddrV4,
+    options: SocksOption,
+    is_fail_closed: bool,
+) {
+    let (src, dst) = (connection.src(), connection.dst());
+    let is_bypass = match time::timeout(Duration::from_millis(HELLO_TIMEOUT), rx).await {
+        Ok(Ok(is_bypass)) => is_bypass,
+        // Blocked or closed
+        Ok(Err(_)) => return,
+        Err(_) => {
+            trace!("wait for ClientHello of {} -> {} timed out", src, dst);
+            if is_fail_closed {
+                // Close the connection without relaying it
+                return;
+            }
+            false
+        }
+    };
+
+    let remote = if is_bypass { None } else { Some(remote) };
+    if let Err(ref e) = socks::relay(connection, remote, &options).await {
+        warn!("relay {} -> {}: {}", src, dst, e);
+    }
+}
+
+/// Returns a TLS record of a ClientHello carrying the server name.
+#[cfg(test)]
+pub(crate) fn client_hello(name: &str) -> Vec<u8> {
+    let name = name.as_bytes();
+    let mut server_name = vec![0, 0];
+    server_name.extend_from_slice(&(name.len() as u16 + 5).to_be_bytes());
+    server_name.extend_from_slice(&(name.len() as u16 + 3).to_be_bytes());
+    server_name.push(NAME_HOST_NAME);
+    server_name.extend_from_slice(&(name.len() as u16).to_be_bytes());
+    server_name.extend_from_slice(name);
+
+    // Version, random, session ID, cipher suites and compression methods
+    let mut body = vec![3, 3];
+    body.extend_from_slice(&[0u8; 32]);
+    body.extend_from_slice(&[0, 0, 2, 0x13, 0x01, 1, 0]);
+    body.extend_from_slice(&(server_name.len() as u16).to_be_bytes());
+    body.extend_from_slice(&server_name);
+    let mut hello = vec![HANDSHAKE_CLIENT_HELLO, 0];
+    hello.extend_from_slice(&(body.len() as u16).to_be_bytes());
+    hello.extend_from_slice(&body);
+
+    let mut record = vec![RECORD_HANDSHAKE, 3, 1];
+    record.extend_from_slice(&(hello.len() as u16).to_be_bytes());
+    record.extend_from_slice(&hello);
+    record
+}
+
+#[test]
+fn sni_parse_server_name() {
+    let single = client_hello("www.example.com");
+    assert_eq!(
+        parse_server_name(&single),
+        ServerName::Name("www.example.com".to_string())
+    );
+    assert_eq!(parse_server_name(&single[..20]), ServerName::Incomplete);
+    assert_eq!(parse_server_name(b"GET / HTTP/1.1\r\n"), ServerName::Absent);
+
+    // The handshake may span records
+    let hello = &single[5..];
+    let mut split = vec![RECORD_HANDSHAKE, 3, 1, 0, 10];
+    split.extend_from_slice(&hello[..10]);
+    split.extend_from_slice(&[RECORD_HANDSHAKE, 3, 1, 0, hello.len() as u8 - 10]);
+    split.extend_from_slice(&hello[10..]);
+    assert_eq!(
+        parse_server_name(&split),
+        ServerName::Name("www.example.com".to_string())
+    );
+
+    let filter = SniFilter::new(
+        &[
+            ("ads.example.com".to_string(), SniAction::Block),
+            ("*.Example.com".to_string(), SniAction::Bypass),
+        ],
+        &[443],
+        false,
+    );
+    assert_eq!(filter.action("ads.example.com"), Some(SniAction::Block));
+    assert_eq!(filter.action("x.ads.example.com."), Some(SniAction::Block));
+    assert_eq!(filter.action("WWW.example.com"), Some(SniAction::Bypass));
+    assert_eq!(filter.action("example.com"), None);
+    assert_eq!(filter.action("badexample.com"), None);
+}
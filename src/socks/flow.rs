@@ -0,0 +1,459 @@
+//! Support for terminating flows accepted from sources in user code.
+
+use log::{debug, trace, warn};
+use std::cmp::min;
+use std::future::Future;
+use std::net::SocketAddrV4;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{self, AsyncRead, AsyncWrite};
+use tokio::runtime::Handle;
+use tokio::sync::mpsc;
+use tokio::time::{self, Delay};
+
+use super::{
+    elapsed_millis, u64_to_socket_addr_v4, ForwardDatagram, ForwardFuture, ForwardStream, Peers,
+    PAUSE_WAIT,
+};
+use crate::config::NatMode;
+
+/// Represents a flow accepted from a source.
+pub enum Flow {
+    /// Represents a TCP connection.
+    Tcp(TcpConnection),
+    /// Represents a UDP session.
+    Udp(UdpSession),
+}
+
+/// Represents a TCP connection accepted from a source. Reading from the connection returns the
+/// data the source sent to the destination, and writing to the connection sends data to the
+/// source as if it is from the destination.
+pub struct TcpConnection {
+    tx: Arc<dyn ForwardStream>,
+    src: SocketAddrV4,
+    dst: SocketAddrV4,
+    rx: mpsc::UnboundedReceiver<Vec<u8>>,
+    /// Represents the data received but not read yet.
+    buffer: Vec<u8>,
+    offset: usize,
+    write_queue_size: Arc<AtomicUsize>,
+    written: Arc<AtomicU64>,
+    is_read_closed: Arc<AtomicBool>,
+    high_watermark: usize,
+    low_watermark: usize,
+    is_paused: bool,
+    pause: Option<Delay>,
+    /// Represents the data being forwarded to the source and its size.
+    write: Option<(ForwardFuture<'static>, usize)>,
+    /// Represents the closing of the connection.
+    shutdown: Option<ForwardFuture<'static>>,
+    instant: Instant,
+    last_active: Arc<AtomicU64>,
+}
+
+impl TcpConnection {
+    pub(super) fn new(
+        tx: Arc<dyn ForwardStream>,
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+        rx: mpsc::UnboundedReceiver<Vec<u8>>,
+        write_queue_size: Arc<AtomicUsize>,
+        written: Arc<AtomicU64>,
+        is_read_closed: Arc<AtomicBool>,
+        high_watermark: usize,
+        low_watermark: usize,
+        instant: Instant,
+        last_active: Arc<AtomicU64>,
+    ) -> TcpConnection {
+        TcpConnection {
+            tx,
+            src,
+            dst,
+            rx,
+            buffer: Vec::new(),
+            offset: 0,
+            write_queue_size,
+            written,
+            is_read_closed,
+            high_watermark,
+            low_watermark,
+            is_paused: false,
+            pause: None,
+            write: None,
+            shutdown: None,
+            instant,
+            last_active,
+        }
+    }
+
+    /// Returns the source of the connection.
+    pub fn src(&self) -> SocketAddrV4 {
+        self.src
+    }
+
+    /// Returns the destination of the connection.
+    pub fn dst(&self) -> SocketAddrV4 {
+        self.dst
+    }
+
+    /// Returns the future closing the connection, or `None` if the connection is closed.
+    fn close(&mut self) -> Option<ForwardFuture<'static>> {
+        if self.is_read_closed.swap(true, Ordering::Relaxed) {
+            return None;
+        }
+        trace!("close connection {} -> {}", self.dst, self.src);
+
+        let tx = Arc::clone(&self.tx);
+        let (dst, src) = (self.dst, self.src);
+
+        Some(Box::pin(async move { tx.close(dst, src).await }))
+    }
+
+    /// Polls the data being forwarded, and returns its size once it is forwarded.
+    fn poll_write_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        let (write, size) = self.write.as_mut().unwrap();
+        let size = *size;
+        match write.as_mut().poll(cx) {
+            Poll::Ready(result) => {
+                self.write = None;
+
+                Poll::Ready(result.map(|_| size))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl AsyncRead for TcpConnection {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        while this.offset >= this.buffer.len() {
+            match this.rx.poll_recv(cx) {
+                Poll::Ready(Some(payload)) => {
+                    this.write_queue_size
+                        .fetch_sub(payload.len(), Ordering::Relaxed);
+                    this.written
+                        .fetch_add(payload.len() as u64, Ordering::Relaxed);
+                    this.last_active
+                        .store(elapsed_millis(&this.instant), Ordering::Relaxed);
+
+                    this.buffer = payload;
+                    this.offset = 0;
+                }
+                // Closed by the source
+                Poll::Ready(None) => return Poll::Ready(Ok(0)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+
+        let size = min(buf.len(), this.buffer.len() - this.offset);
+        buf[..size].copy_from_slice(&this.buffer[this.offset..this.offset + size]);
+        this.offset += size;
+
+        Poll::Ready(Ok(size))
+    }
+}
+
+impl AsyncWrite for TcpConnection {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        // Complete the data being forwarded before accepting more
+        if this.write.is_some() {
+            return this.poll_write_pending(cx);
+        }
+        if this.is_read_closed.load(Ordering::Relaxed) {
+            return Poll::Ready(Err(io::Error::from(io::ErrorKind::BrokenPipe)));
+        }
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        // Backpressure
+        let mut size = buf.len();
+        if this.high_watermark > 0 {
+            loop {
+                if let Some(ref mut pause) = this.pause {
+                    match Pin::new(pause).poll(cx) {
+                        Poll::Ready(_) => this.pause = None,
+                        Poll::Pending => return Poll::Pending,
+                    }
+                }
+
+                let queue_size = this.tx.queue_size(this.dst, this.src);
+                if this.is_paused {
+                    if queue_size > this.low_watermark {
+                        this.pause = Some(time::delay_for(Duration::from_millis(PAUSE_WAIT)));
+                        continue;
+                    }
+                    this.is_paused = false;
+                    trace!("resume connection write {} -> {}", this.dst, this.src);
+                }
+                if queue_size >= this.high_watermark {
+                    this.is_paused = true;
+                    trace!("pause connection write {} -> {}", this.dst, this.src);
+                    continue;
+                }
+                size = min(size, this.high_watermark - queue_size);
+
+                break;
+            }
+        }
+
+        debug!(
+            "write to connection: {} -> {} ({} Bytes)",
+            this.dst, this.src, size
+        );
+        this.last_active
+            .store(elapsed_millis(&this.instant), Ordering::Relaxed);
+
+        // Send
+        let tx = Arc::clone(&this.tx);
+        let (dst, src) = (this.dst, this.src);
+        let payload = buf[..size].to_vec();
+        this.write = Some((
+            Box::pin(async move { tx.forward(dst, src, &payload).await }),
+            size,
+        ));
+
+        this.poll_write_pending(cx)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        if this.write.is_some() {
+            match this.poll_write_pending(cx) {
+                Poll::Ready(Ok(_)) => {}
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        if this.write.is_some() {
+            match this.poll_write_pending(cx) {
+                Poll::Ready(Ok(_)) => {}
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        if this.shutdown.is_none() {
+            this.shutdown = this.close();
+        }
+        let result = match this.shutdown {
+            Some(ref mut shutdown) => match shutdown.as_mut().poll(cx) {
+                Poll::Ready(result) => result,
+                Poll::Pending => return Poll::Pending,
+            },
+            None => Ok(()),
+        };
+        this.shutdown = None;
+
+        Poll::Ready(result)
+    }
+}
+
+impl Drop for TcpConnection {
+    fn drop(&mut self) {
+        // Close in the background, which can only be awaited in a runtime
+        if let Some(close) = self.close() {
+            match Handle::try_current() {
+                Ok(handle) => {
+                    let (dst, src) = (self.dst, self.src);
+                    handle.spawn(async move {
+                        if let Err(ref e) = close.await {
+                            warn!("handle {}: {}: {} -> {}", "TCP", e, dst, src);
+                        }
+                    });
+                }
+                Err(_) => warn!("close connection {} -> {}: no runtime", self.dst, self.src),
+            }
+        }
+        trace!("drop connection {} -> {}", self.dst, self.src);
+    }
+}
+
+/// Represents a UDP session accepted from a source. Receiving from the session returns the
+/// datagrams the source sent and their destinations, and sending to the session sends datagrams
+/// to the source as if they are from the given addresses.
+pub struct UdpSession {
+    tx: Arc<dyn ForwardDatagram>,
+    src: Arc<AtomicU64>,
+    rx: mpsc::Receiver<(Vec<u8>, SocketAddrV4)>,
+    is_closed: Arc<AtomicBool>,
+    instant: Instant,
+    last_active: Arc<AtomicU64>,
+    nat_mode: NatMode,
+    peers: Arc<Mutex<Peers>>,
+}
+
+impl UdpSession {
+    pub(super) fn new(
+        tx: Arc<dyn ForwardDatagram>,
+        src: Arc<AtomicU64>,
+        rx: mpsc::Receiver<(Vec<u8>, SocketAddrV4)>,
+        is_closed: Arc<AtomicBool>,
+        instant: Instant,
+        last_active: Arc<AtomicU64>,
+        nat_mode: NatMode,
+        peers: Arc<Mutex<Peers>>,
+    ) -> UdpSession {
+        UdpSession {
+            tx,
+            src,
+            rx,
+            is_closed,
+            instant,
+            last_active,
+            nat_mode,
+            peers,
+        }
+    }
+
+    /// Returns the source of the session.
+    pub fn src(&self) -> SocketAddrV4 {
+        u64_to_socket_addr_v4(self.src.load(Ordering::Relaxed))
+    }
+
+    /// Receives a datagram and its destination from the source. Returns `None` if the session is
+    /// closed.
+    pub async fn recv_from(&mut self) -> Option<(Vec<u8>, SocketAddrV4)> {
+        self.rx.recv().await
+    }
+
+    /// Polls to receive a datagram and its destination from the source.
+    pub fn poll_recv_from(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<(Vec<u8>, SocketAddrV4)>> {
+        self.rx.poll_recv(cx)
+    }
+
+    /// Sends a datagram to the source as if it is from the address. The datagram is dropped if
+    /// the source has not sent to the address and the NAT mode does not allow it.
+    pub async fn send_to(&mut self, payload: &[u8], addr: SocketAddrV4) -> io::Result<()> {
+        if self.is_closed.load(Ordering::Relaxed) {
+            return Err(io::Error::from(io::ErrorKind::BrokenPipe));
+        }
+
+        // Filter
+        if !self.peers.lock().unwrap().is_allowed(addr) {
+            trace!(
+                "filter datagram {} -> {} ({})",
+                addr,
+                self.src(),
+                self.nat_mode
+            );
+            return Ok(());
+        }
+        debug!(
+            "send to session: {} -> {} ({} Bytes)",
+            addr,
+            self.src(),
+            payload.len()
+        );
+        self.last_active
+            .store(elapsed_millis(&self.instant), Ordering::Relaxed);
+
+        // Send
+        self.tx.forward(addr, self.src(), payload).await
+    }
+
+    /// Returns if the session is closed.
+    pub fn is_closed(&self) -> bool {
+        self.is_closed.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for UdpSession {
+    fn drop(&mut self) {
+        self.is_closed.store(true, Ordering::Relaxed);
+        trace!("drop session {}", self.src());
+    }
+}
+
+#[cfg(test)]
+struct RecordStream {
+    forwarded: Vec<u8>,
+    is_closed: bool,
+}
+
+#[cfg(test)]
+impl ForwardStream for Mutex<RecordStream> {
+    fn open(&self, _: SocketAddrV4, _: SocketAddrV4) -> ForwardFuture<'_> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn forward<'a>(
+        &'a self,
+        _: SocketAddrV4,
+        _: SocketAddrV4,
+        payload: &'a [u8],
+    ) -> ForwardFuture<'a> {
+        self.lock().unwrap().forwarded.extend_from_slice(payload);
+
+        Box::pin(async { Ok(()) })
+    }
+
+    fn queue_size(&self, _: SocketAddrV4, _: SocketAddrV4) -> usize {
+        0
+    }
+
+    fn close(&self, _: SocketAddrV4, _: SocketAddrV4) -> ForwardFuture<'_> {
+        self.lock().unwrap().is_closed = true;
+
+        Box::pin(async { Ok(()) })
+    }
+}
+
+#[tokio::test]
+async fn tcp_connection_read_write() {
+    use super::StreamWorker;
+    use tokio::prelude::*;
+
+    let tx = Arc::new(Mutex::new(RecordStream {
+        forwarded: Vec::new(),
+        is_closed: false,
+    }));
+    let src = "10.6.0.2:1024".parse().unwrap();
+    let dst = "1.1.1.1:80".parse().unwrap();
+    let (mut worker, mut connection) = StreamWorker::accept(tx.clone(), src, dst, 0, 0)
+        .await
+        .unwrap();
+
+    worker.send(b"hello".to_vec()).unwrap();
+    assert_eq!(worker.write_queue_size(), 5);
+    let mut buffer = [0u8; 8];
+    assert_eq!(connection.read(&mut buffer).await.unwrap(), 5);
+    assert_eq!(&buffer[..5], b"hello");
+    assert_eq!(worker.write_queue_size(), 0);
+    assert_eq!(worker.written(), 5);
+
+    connection.write_all(b"world").await.unwrap();
+    connection.shutdown().await.unwrap();
+    assert_eq!(tx.lock().unwrap().forwarded, b"world");
+    assert!(tx.lock().unwrap().is_closed);
+    assert!(worker.is_read_closed());
+
+    // Closed by the source
+    worker.shutdown(std::net::Shutdown::Write);
+    assert_eq!(connection.read(&mut buffer).await.unwrap(), 0);
+}
@@ -0,0 +1,482 @@
+//! Support for relaying traffic through an HTTP/2 proxy, where TCP connections are multiplexed
+//! in CONNECT streams and UDP is tunneled in CONNECT-UDP streams of MASQUE.
+
+use bytes::{Buf, Bytes};
+use h2::client::{self, SendRequest};
+use h2::{RecvStream, SendStream};
+use http::{header, HeaderValue, Method, Request};
+use log::{debug, trace, warn};
+use std::cmp::min;
+use std::collections::HashMap;
+use std::fmt::{self, Debug, Formatter};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::Arc;
+use tokio::future;
+use tokio::io::{self, AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, Mutex};
+
+use super::secret::Secret;
+use super::tls;
+
+/// Represents the method of MASQUE tunneling UDP.
+const METHOD_CONNECT_UDP: &[u8] = b"CONNECT-UDP";
+
+/// Represents the type of the capsule carrying a datagram.
+const CAPSULE_DATAGRAM: u64 = 0x00;
+
+/// Represents the context ID of UDP payloads in datagrams.
+const CONTEXT_UDP_PAYLOAD: u64 = 0;
+
+/// Represents the capacity of the queue of datagrams received.
+const DATAGRAM_QUEUE_SIZE: usize = 64;
+
+/// Represents the prefix of the value of the header of the basic authentication.
+const BASIC_PREFIX: &[u8] = b"Basic ";
+
+/// Represents the options of an HTTP/2 proxy.
+#[derive(Clone, Debug, Default, Eq, Hash, PartialEq)]
+pub struct Http2Option {
+    domain: Option<String>,
+}
+
+impl Http2Option {
+    /// Creates a new `Http2Option`. The connection to the proxy is in cleartext with prior
+    /// knowledge.
+    pub fn new() -> Http2Option {
+        Http2Option::default()
+    }
+
+    /// Sets the domain of the proxy, which enables TLS and verifies the certificate against the
+    /// domain.
+    pub fn set_tls(&mut self, domain: String) {
+        self.domain = Some(domain);
+    }
+}
+
+/// Represents a client of an HTTP/2 proxy, which shares a connection among streams.
+#[derive(Clone)]
+pub struct Http2Client {
+    options: Http2Option,
+    auth: Option<HeaderValue>,
+    /// Represents the shared connection and the proxy it connects to.
+    send_request: Arc<Mutex<Option<(SocketAddrV4, SendRequest<Bytes>)>>>,
+}
+
+impl Http2Client {
+    /// Creates a new `Http2Client` with the username and the password of the proxy.
+    pub fn new(options: Http2Option, auth: Option<(&str, &Secret)>) -> Http2Client {
+        let auth = auth.map(|(username, password)| {
+            // Copies of the password are zeroed once the header is built
+            let mut credentials = Vec::with_capacity(username.len() + 1 + password.len());
+            credentials.extend_from_slice(username.as_bytes());
+            credentials.push(b':');
+            credentials.extend_from_slice(password.as_bytes());
+            let credentials = Secret::from_bytes(credentials);
+            let mut value = vec![0u8; BASIC_PREFIX.len() + (credentials.len() + 2) / 3 * 4];
+            value[..BASIC_PREFIX.len()].copy_from_slice(BASIC_PREFIX);
+            base64::encode_config_slice(
+                credentials.as_bytes(),
+                base64::STANDARD,
+                &mut value[BASIC_PREFIX.len()..],
+            );
+            let value = Secret::from_bytes(value);
+
+            let mut value = HeaderValue::from_bytes(value.as_bytes()).unwrap();
+            value.set_sensitive(true);
+
+            value
+        });
+
+        Http2Client {
+            options,
+            auth,
+            send_request: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    async fn ready(&self, remote: SocketAddrV4) -> io::Result<SendRequest<Bytes>> {
+        let cached = self.send_request.lock().await.clone();
+        if let Some((cached_remote, send_request)) = cached {
+            if cached_remote == remote {
+                match send_request.ready().await {
+                    Ok(send_request) => return Ok(send_request),
+                    Err(ref e) => debug!("HTTP/2 connection to {} is closed: {}", remote, e),
+                }
+            }
+        }
+
+        // Reconnect
+        let send_request = self.handshake(remote).await?;
+        *self.send_request.lock().await = Some((remote, send_request.clone()));
+
+        send_request.ready().await.map_err(into_io)
+    }
+
+    async fn handshake(&self, remote: SocketAddrV4) -> io::Result<SendRequest<Bytes>> {
+        let stream = TcpStream::connect(remote).await?;
+        stream.set_nodelay(true)?;
+
+        let send_request = match self.options.domain {
+            Some(ref domain) => handshake(tls::connect(stream, domain, &[b"h2"]).await?).await?,
+            None => handshake(stream).await?,
+        };
+        debug!("open HTTP/2 connection to {}", remote);
+
+        Ok(send_request)
+    }
+
+    /// Opens a stream to the target server through the proxy.
+    async fn open(
+        &self,
+        remote: SocketAddrV4,
+        dst: SocketAddrV4,
+        method: Method,
+    ) -> io::Result<(RecvStream, SendStream<Bytes>)> {
+        let mut send_request = self.ready(remote).await?;
+
+        let mut request = Request::builder().method(method).uri(dst.to_string());
+        if let Some(ref auth) = self.auth {
+            request = request.header(header::PROXY_AUTHORIZATION, auth.clone());
+        }
+        let request = request.body(()).unwrap();
+
+        let (response, send_stream) = send_request.send_request(request, false).map_err(into_io)?;
+        let response = response.await.map_err(into_io)?;
+        if !response.status().is_success() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("unexpected HTTP/2 status {}", response.status()),
+            ));
+        }
+
+        Ok((response.into_body(), send_stream))
+    }
+}
+
+impl Debug for Http2Client {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("Http2Client")
+            .field("options", &self.options)
+            .finish()
+    }
+}
+
+async fn handshake<T>(stream: T) -> io::Result<SendRequest<Bytes>>
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (send_request, connection) = client::handshake(stream).await.map_err(into_io)?;
+    tokio::spawn(async move {
+        if let Err(ref e) = connection.await {
+            warn!("HTTP/2 connection: {}", e);
+        }
+    });
+
+    Ok(send_request)
+}
+
+fn into_io(e: h2::Error) -> io::Error {
+    if e.is_io() {
+        return e.into_io().unwrap();
+    }
+
+    io::Error::new(io::ErrorKind::Other, e)
+}
+
+/// Sends all the data in the stream, respecting the flow control.
+async fn send_all(send_stream: &mut SendStream<Bytes>, payload: &[u8]) -> io::Result<()> {
+    let mut payload = payload;
+    while !payload.is_empty() {
+        send_stream.reserve_capacity(payload.len());
+        let mut capacity = send_stream.capacity();
+        if capacity == 0 {
+            capacity = match future::poll_fn(|cx| send_stream.poll_capacity(cx)).await {
+                Some(Ok(capacity)) => capacity,
+                Some(Err(e)) => return Err(into_io(e)),
+                None => return Err(io::Error::from(io::ErrorKind::BrokenPipe)),
+            };
+            if capacity == 0 {
+                continue;
+            }
+        }
+
+        let size = min(capacity, payload.len());
+        send_stream
+            .send_data(Bytes::copy_from_slice(&payload[..size]), false)
+            .map_err(into_io)?;
+        payload = &payload[size..];
+    }
+
+    Ok(())
+}
+
+/// Reads data from the stream. Returns `None` if the stream is closed.
+async fn recv(recv_stream: &mut RecvStream) -> io::Result<Option<Bytes>> {
+    match recv_stream.data().await {
+        Some(Ok(data)) => {
+            if let Err(e) = recv_stream.flow_control().release_capacity(data.len()) {
+                return Err(into_io(e));
+            }
+
+            Ok(Some(data))
+        }
+        Some(Err(e)) => Err(into_io(e)),
+        None => Ok(None),
+    }
+}
+
+/// Represents the read half of an HTTP/2 CONNECT stream.
+pub struct Http2ReadHalf {
+    recv_stream: RecvStream,
+    buffer: Bytes,
+}
+
+impl Http2ReadHalf {
+    /// Reads data from the stream. Returns 0 if the stream is closed.
+    pub async fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        while self.buffer.is_empty() {
+            match recv(&mut self.recv_stream).await? {
+                Some(data) => self.buffer = data,
+                None => return Ok(0),
+            }
+        }
+
+        let size = min(self.buffer.len(), buffer.len());
+        buffer[..size].copy_from_slice(&self.buffer.split_to(size));
+
+        Ok(size)
+    }
+}
+
+/// Represents the write half of an HTTP/2 CONNECT stream.
+pub struct Http2WriteHalf {
+    send_stream: SendStream<Bytes>,
+}
+
+impl Http2WriteHalf {
+    /// Writes all the data to the stream.
+    pub async fn write_all(&mut self, payload: &[u8]) -> io::Result<()> {
+        send_all(&mut self.send_stream, payload).await
+    }
+
+    /// Closes the stream for writing by ending the stream.
+    pub async fn close(mut self) {
+        if let Err(ref e) = self.send_stream.send_data(Bytes::new(), true) {
+            trace!("close HTTP/2 stream: {}", e);
+        }
+    }
+}
+
+/// Connects to a target server through an HTTP/2 proxy in a CONNECT stream.
+pub async fn connect(
+    remote: SocketAddrV4,
+    dst: SocketAddrV4,
+    client: &Http2Client,
+) -> io::Result<(Http2ReadHalf, Http2WriteHalf)> {
+    let (recv_stream, send_stream) = client.open(remote, dst, Method::CONNECT).await?;
+
+    Ok((
+        Http2ReadHalf {
+            recv_stream,
+            buffer: Bytes::new(),
+        },
+        Http2WriteHalf { send_stream },
+    ))
+}
+
+/// Appends a variable-length integer in QUIC to the buffer.
+fn put_varint(buffer: &mut Vec<u8>, value: u64) {
+    match value {
+        0..=0x3f => buffer.push(value as u8),
+        0x40..=0x3fff => buffer.extend_from_slice(&(value as u16 | 0x4000).to_be_bytes()),
+        0x4000..=0x3fff_ffff => {
+            buffer.extend_from_slice(&(value as u32 | 0x8000_0000).to_be_bytes())
+        }
+        _ => buffer.extend_from_slice(&(value | 0xc000_0000_0000_0000).to_be_bytes()),
+    }
+}
+
+/// Returns a variable-length integer in QUIC and its size, or `None` if the buffer is too short.
+fn get_varint(buffer: &[u8]) -> Option<(u64, usize)> {
+    let first = *buffer.first()?;
+    let size = 1 << (first >> 6);
+    if buffer.len() < size {
+        return None;
+    }
+
+    let mut value = (first & 0x3f) as u64;
+    for b in &buffer[1..size] {
+        value = (value << 8) | *b as u64;
+    }
+
+    Some((value, size))
+}
+
+/// Returns the datagram capsule carrying the payload.
+fn datagram_capsule(payload: &[u8]) -> Vec<u8> {
+    let mut value = Vec::with_capacity(1 + payload.len());
+    put_varint(&mut value, CONTEXT_UDP_PAYLOAD);
+    value.extend_from_slice(payload);
+
+    let mut capsule = Vec::with_capacity(value.len() + 8);
+    put_varint(&mut capsule, CAPSULE_DATAGRAM);
+    put_varint(&mut capsule, value.len() as u64);
+    capsule.extend_from_slice(&value);
+
+    capsule
+}
+
+/// Represents the parser of capsules in a CONNECT-UDP stream.
+#[derive(Default)]
+struct CapsuleParser {
+    buffer: Vec<u8>,
+}
+
+impl CapsuleParser {
+    /// Appends the data and returns the UDP payloads in complete capsules.
+    fn push(&mut self, data: &[u8]) -> Vec<Vec<u8>> {
+        self.buffer.extend_from_slice(data);
+
+        let mut payloads = Vec::new();
+        let mut offset = 0;
+        loop {
+            let (t, t_size) = match get_varint(&self.buffer[offset..]) {
+                Some(t) => t,
+                None => break,
+            };
+            let (len, len_size) = match get_varint(&self.buffer[offset + t_size..]) {
+                Some(len) => len,
+                None => break,
+            };
+            let start = offset + t_size + len_size;
+            let end = start + len as usize;
+            if end > self.buffer.len() {
+                break;
+            }
+
+            // Other capsules are ignored
+            if t == CAPSULE_DATAGRAM {
+                if let Some((CONTEXT_UDP_PAYLOAD, id_size)) = get_varint(&self.buffer[start..end]) {
+                    payloads.push(self.buffer[start + id_size..end].to_vec());
+                }
+            }
+            offset = end;
+        }
+        self.buffer.drain(..offset);
+
+        payloads
+    }
+}
+
+/// Represents the send half of an HTTP/2 UDP client. CONNECT-UDP tunnels datagrams to a single
+/// target server in a stream, so a stream is opened for each peer.
+pub struct Http2SendHalf {
+    remote: SocketAddrV4,
+    client: Http2Client,
+    peers: HashMap<SocketAddrV4, SendStream<Bytes>>,
+    recv_tx: mpsc::Sender<(Vec<u8>, SocketAddrV4)>,
+    /// Represents the socket reserving the local port.
+    _socket: std::net::UdpSocket,
+}
+
+impl Http2SendHalf {
+    /// Sends data on the socket to the given address.
+    pub async fn send_to(&mut self, payload: &[u8], dst: SocketAddrV4) -> io::Result<usize> {
+        if !self.peers.contains_key(&dst) {
+            let method = Method::from_bytes(METHOD_CONNECT_UDP).unwrap();
+            let (mut recv_stream, send_stream) = self.client.open(self.remote, dst, method).await?;
+            trace!("open HTTP/2 CONNECT-UDP stream to {}", dst);
+
+            let mut recv_tx = self.recv_tx.clone();
+            tokio::spawn(async move {
+                let mut parser = CapsuleParser::default();
+                loop {
+                    match recv(&mut recv_stream).await {
+                        Ok(Some(data)) => {
+                            for payload in parser.push(data.bytes()) {
+                                if recv_tx.send((payload, dst)).await.is_err() {
+                                    return;
+                                }
+                            }
+                        }
+                        Ok(None) => break,
+                        Err(ref e) => {
+                            warn!("HTTP/2: {}: {}: {}", "UDP", dst, e);
+                            break;
+                        }
+                    }
+                }
+                trace!("close HTTP/2 CONNECT-UDP stream to {}", dst);
+            });
+
+            self.peers.insert(dst, send_stream);
+        }
+
+        // Each datagram is sent in a capsule
+        let capsule = datagram_capsule(payload);
+        let result = send_all(self.peers.get_mut(&dst).unwrap(), &capsule).await;
+        if let Err(e) = result {
+            self.peers.remove(&dst);
+            return Err(e);
+        }
+
+        Ok(payload.len())
+    }
+}
+
+/// Represents the receive half of an HTTP/2 UDP client.
+pub struct Http2RecvHalf {
+    rx: mpsc::Receiver<(Vec<u8>, SocketAddrV4)>,
+}
+
+impl Http2RecvHalf {
+    /// Receives a single datagram message on the socket.
+    pub async fn recv_from(&mut self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddrV4)> {
+        match self.rx.recv().await {
+            Some((payload, addr)) => {
+                let size = payload.len().min(buffer.len());
+                buffer[..size].copy_from_slice(&payload[..size]);
+
+                Ok((size, addr))
+            }
+            None => Err(io::Error::from(io::ErrorKind::BrokenPipe)),
+        }
+    }
+}
+
+/// Binds a local port to target servers through an HTTP/2 proxy supporting CONNECT-UDP. The port
+/// only identifies the client and no datagram is sent from it.
+pub async fn bind(
+    remote: SocketAddrV4,
+    client: &Http2Client,
+) -> io::Result<(Http2RecvHalf, Http2SendHalf, u16)> {
+    let socket = std::net::UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0))?;
+    let local_port = socket.local_addr()?.port();
+
+    let (recv_tx, rx) = mpsc::channel(DATAGRAM_QUEUE_SIZE);
+
+    Ok((
+        Http2RecvHalf { rx },
+        Http2SendHalf {
+            remote,
+            client: client.clone(),
+            peers: HashMap::new(),
+            recv_tx,
+            _socket: socket,
+        },
+        local_port,
+    ))
+}
+
+#[test]
+fn http2_capsule() {
+    let payload = vec![0x5au8; 100];
+    let capsule = datagram_capsule(&payload);
+
+    let mut parser = CapsuleParser::default();
+    assert!(parser.push(&capsule[..3]).is_empty());
+    let mut data = capsule[3..].to_vec();
+    data.extend_from_slice(&capsule);
+    assert_eq!(parser.push(&data), vec![payload.clone(), payload]);
+}
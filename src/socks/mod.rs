@@ -1,32 +1,77 @@
 //! Support for handling SOCKS proxies.
 
 use log::{debug, trace, warn};
-use std::net::{Ipv4Addr, Shutdown, SocketAddrV4};
-use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use lru::LruCache;
+use std::cmp::{max, min};
+use std::future::Future;
+use std::net::{Ipv4Addr, Shutdown, SocketAddr, SocketAddrV4};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio::io;
-use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::udp::{RecvHalf, SendHalf};
+use tokio::net::TcpStream;
 use tokio::prelude::*;
+use tokio::sync::mpsc;
 use tokio::time;
 
+use crate::clock;
+use crate::config::NatMode;
+use crate::egress::{EgressReadHalf, EgressRecvHalf, EgressSendHalf, EgressWriteHalf};
+use crate::policy::PortProtocol;
+use crate::qos::{self, QosDirection};
+
+mod flow;
+#[cfg(feature = "http2")]
+mod http2;
+mod secret;
 mod socks;
-use self::socks::SocksSendHalf;
-pub use self::socks::{SocksAuth, SocksOption};
+#[cfg(feature = "ssh")]
+mod ssh;
+#[cfg(any(feature = "http2", feature = "websocket"))]
+mod tls;
+#[cfg(feature = "vmess")]
+mod vmess;
+#[cfg(feature = "websocket")]
+mod websocket;
+pub use self::flow::{Flow, TcpConnection, UdpSession};
+#[cfg(feature = "http2")]
+pub use self::http2::{Http2Client, Http2Option};
+pub use self::secret::Secret;
+pub use self::socks::{ConnectFailure, SocksAuth, SocksOption, SocksPool};
+#[cfg(feature = "ssh")]
+pub use self::ssh::{SshClient, SshOption};
+#[cfg(feature = "vmess")]
+pub use self::vmess::{VmessOption, VmessSecurity};
+#[cfg(feature = "websocket")]
+pub use self::websocket::WebSocketOption;
+
+/// Represents a future returned by `ForwardStream` and `ForwardDatagram`.
+pub type ForwardFuture<'a> = Pin<Box<dyn Future<Output = io::Result<()>> + Send + 'a>>;
 
-/// Trait for forwarding stream.
-pub trait ForwardStream: Send {
+/// Trait for forwarding stream to sources. Workers call it with the data read from the proxy or
+/// an `Egress`, where the `dst` is the destination of the connection and the `src` is its source.
+/// It is shared by workers, and is responsible for its own synchronization, so an implementation
+/// can await I/O in the futures it returns without blocking other workers.
+pub trait ForwardStream: Send + Sync {
     /// Opens a stream connection.
-    fn open(&mut self, dst: SocketAddrV4, src: SocketAddrV4) -> io::Result<()>;
+    fn open(&self, dst: SocketAddrV4, src: SocketAddrV4) -> ForwardFuture<'_>;
 
     /// Forwards stream.
-    fn forward(&mut self, dst: SocketAddrV4, src: SocketAddrV4, payload: &[u8]) -> io::Result<()>;
+    fn forward<'a>(
+        &'a self,
+        dst: SocketAddrV4,
+        src: SocketAddrV4,
+        payload: &'a [u8],
+    ) -> ForwardFuture<'a>;
 
-    /// Triggers a timed event. Used in retransmitting timed out data.
-    fn tick(&mut self, dst: SocketAddrV4, src: SocketAddrV4) -> io::Result<()>;
+    /// Returns the size of the data forwarded but not sent yet of a stream connection.
+    fn queue_size(&self, dst: SocketAddrV4, src: SocketAddrV4) -> usize;
 
     /// Closes a stream connection.
-    fn close(&mut self, dst: SocketAddrV4, src: SocketAddrV4) -> io::Result<()>;
+    fn close(&self, dst: SocketAddrV4, src: SocketAddrV4) -> ForwardFuture<'_>;
 }
 
 /// Represents the wait time after a `TimedOut` `IoError`.
@@ -37,50 +82,123 @@ const RECV_ZERO_WAIT: u64 = 100;
 /// Represents the maximum count of receiving 0 byte from the stream before closing it.
 const MAX_RECV_ZERO: usize = 3;
 
-/// Represents the interval of a tick.
-const TICK_INTERVAL: u64 = 1000;
+/// Represents the wait time before checking the queue again while reading is paused.
+const PAUSE_WAIT: u64 = 20;
 
-/// Represents a worker of a SOCKS5 TCP stream.
+/// Represents a worker of a SOCKS5 TCP stream, or of a `TcpConnection` terminated in user code.
 pub struct StreamWorker {
     dst: SocketAddrV4,
-    stream_tx: Option<OwnedWriteHalf>,
+    /// Represents the queue of data to be written to the stream.
+    stream_tx: Option<mpsc::UnboundedSender<Vec<u8>>>,
+    /// Represents the size of the data sent but not written to the stream yet.
+    write_queue_size: Arc<AtomicUsize>,
+    /// Represents the total size of the data written to the stream.
+    written: Arc<AtomicU64>,
+    is_write_failed: Arc<AtomicBool>,
     is_write_closed: Arc<AtomicBool>,
     is_read_closed: Arc<AtomicBool>,
+    instant: Instant,
+    /// Represents the last time in milliseconds since the creation when the worker is active.
+    last_active: Arc<AtomicU64>,
+    /// Represents the time of connecting to the destination through the proxy.
+    connect_latency: Option<Duration>,
+    /// Represents the smoothed time in microseconds of writing to the stream.
+    write_latency: Arc<AtomicU64>,
 }
 
 impl StreamWorker {
-    /// Opens a new `StreamWorker`.
+    /// Opens a new `StreamWorker`. Reading from the stream pauses once the data forwarded but not
+    /// sent reaches the high watermark, and resumes after it drains to the low watermark. A high
+    /// watermark of 0 disables the backpressure.
     pub async fn connect(
-        tx: Arc<Mutex<dyn ForwardStream>>,
+        tx: Arc<dyn ForwardStream>,
         src: SocketAddrV4,
         dst: SocketAddrV4,
         remote: SocketAddrV4,
         options: &SocksOption,
+        high_watermark: usize,
+        low_watermark: usize,
     ) -> io::Result<StreamWorker> {
         let tx_cloned = Arc::clone(&tx);
 
-        let stream = socks::connect(remote, dst, &options).await?;
-        let stream = stream.into_inner();
-        let (mut stream_rx, stream_tx) = stream.into_split();
+        let connect_instant = Instant::now();
+        let (mut stream_rx, mut stream_write_half) = connect(remote, src, dst, &options).await?;
+        let connect_latency = connect_instant.elapsed();
 
         let is_write_closed = Arc::new(AtomicBool::new(false));
         let is_write_closed_cloned = Arc::clone(&is_write_closed);
         let is_read_closed = Arc::new(AtomicBool::new(false));
         let is_read_closed_cloned = Arc::clone(&is_read_closed);
-        let is_read_closed_cloned2 = Arc::clone(&is_read_closed);
+        let instant = Instant::now();
+        let last_active = Arc::new(AtomicU64::new(0));
+        let last_active_cloned = Arc::clone(&last_active);
+        let write_queue_size = Arc::new(AtomicUsize::new(0));
+        let write_queue_size_cloned = Arc::clone(&write_queue_size);
+        let written = Arc::new(AtomicU64::new(0));
+        let written_cloned = Arc::clone(&written);
+        let is_write_failed = Arc::new(AtomicBool::new(false));
+        let is_write_failed_cloned = Arc::clone(&is_write_failed);
+        let write_latency = Arc::new(AtomicU64::new(0));
+        let write_latency_cloned = Arc::clone(&write_latency);
 
         // Open
-        tx_cloned.lock().unwrap().open(dst, src)?;
+        tx_cloned.open(dst, src).await?;
 
-        // Forward
+        // Write
+        let qos = options.qos_class(PortProtocol::Tcp, src, dst);
+        let (stream_tx, mut write_rx) = mpsc::unbounded_channel::<Vec<u8>>();
         tokio::spawn(async move {
+            while let Some(payload) = write_rx.recv().await {
+                if let Some((ref shaper, class)) = qos {
+                    qos::throttle(shaper, class, QosDirection::Upload, payload.len()).await;
+                }
+                let write_instant = Instant::now();
+                let result = stream_write_half.write_all(payload.as_slice()).await;
+                write_queue_size_cloned.fetch_sub(payload.len(), Ordering::Relaxed);
+                if let Err(ref e) = result {
+                    warn!("SOCKS: {}: {} -> {}: {}", "TCP", 0, dst, e);
+                    is_write_failed_cloned.store(true, Ordering::Relaxed);
+                    break;
+                }
+                written_cloned.fetch_add(payload.len() as u64, Ordering::Relaxed);
+                update_latency(&write_latency_cloned, write_instant.elapsed());
+            }
+
+            // The queued data is written before closing
+            stream_write_half.close().await;
+        });
+
+        // Forward
+        clock::spawn(async move {
             let mut buffer = vec![0u8; u16::MAX as usize];
             let mut recv_zero = 0;
+            let mut is_paused = false;
             loop {
                 if is_read_closed_cloned.load(Ordering::Relaxed) {
                     break;
                 }
-                match stream_rx.read(&mut buffer).await {
+
+                // Backpressure
+                let mut size = buffer.len();
+                if high_watermark > 0 {
+                    let queue_size = tx.queue_size(dst, src);
+                    if is_paused {
+                        if queue_size > low_watermark {
+                            time::delay_for(Duration::from_millis(PAUSE_WAIT)).await;
+                            continue;
+                        }
+                        is_paused = false;
+                        trace!("resume stream read {} -> {}", dst, 0);
+                    }
+                    if queue_size >= high_watermark {
+                        is_paused = true;
+                        trace!("pause stream read {} -> {}", dst, 0);
+                        continue;
+                    }
+                    size = min(size, high_watermark - queue_size);
+                }
+
+                match stream_rx.read(&mut buffer[..size]).await {
                     Ok(size) => {
                         if is_read_closed_cloned.load(Ordering::Relaxed) {
                             break;
@@ -91,7 +209,7 @@ impl StreamWorker {
                                 // Close by remote
                                 trace!("close stream read {} -> {}", dst, 0);
 
-                                if let Err(ref e) = tx.lock().unwrap().close(dst, src) {
+                                if let Err(ref e) = tx.close(dst, src).await {
                                     warn!("handle {}: {}", "TCP", e)
                                 }
                                 is_read_closed_cloned.store(true, Ordering::Relaxed);
@@ -105,9 +223,10 @@ impl StreamWorker {
                             "receive from SOCKS: {}: {} -> {} ({} Bytes)",
                             "TCP", dst, 0, size
                         );
+                        last_active_cloned.store(elapsed_millis(&instant), Ordering::Relaxed);
 
                         // Send
-                        if let Err(ref e) = tx.lock().unwrap().forward(dst, src, &buffer[..size]) {
+                        if let Err(ref e) = tx.forward(dst, src, &buffer[..size]).await {
                             warn!("handle {}: {}", "TCP", e);
                         }
                     }
@@ -125,35 +244,80 @@ impl StreamWorker {
             }
         });
 
-        // Triggers sending timed out data
-        tokio::spawn(async move {
-            loop {
-                if is_read_closed_cloned2.load(Ordering::Relaxed) {
-                    break;
-                }
-                // Tick
-                trace!("tick on {} -> {}", dst, 0);
-
-                if let Err(ref e) = tx_cloned.lock().unwrap().tick(dst, src) {
-                    warn!("handle {}: {}", "TCP", e);
-                }
-
-                time::delay_for(Duration::from_millis(TICK_INTERVAL)).await;
-            }
-        });
-
         trace!("open stream {} -> {}", 0, dst);
 
         Ok(StreamWorker {
             dst,
             stream_tx: Some(stream_tx),
+            write_queue_size,
+            written,
+            is_write_failed,
             is_write_closed,
             is_read_closed,
+            instant,
+            last_active,
+            connect_latency: Some(connect_latency),
+            write_latency,
         })
     }
 
-    /// Sends data on the SOCKS5 in TCP to the destination.
-    pub async fn send(&mut self, payload: &[u8]) -> io::Result<()> {
+    /// Opens a new `StreamWorker` whose data is read from the returned `TcpConnection` instead of
+    /// a SOCKS5 TCP stream. Writing to the connection pauses in the same way as `connect`.
+    pub async fn accept(
+        tx: Arc<dyn ForwardStream>,
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+        high_watermark: usize,
+        low_watermark: usize,
+    ) -> io::Result<(StreamWorker, TcpConnection)> {
+        let is_write_closed = Arc::new(AtomicBool::new(false));
+        let is_read_closed = Arc::new(AtomicBool::new(false));
+        let instant = Instant::now();
+        let last_active = Arc::new(AtomicU64::new(0));
+        let write_queue_size = Arc::new(AtomicUsize::new(0));
+        let written = Arc::new(AtomicU64::new(0));
+
+        // Open
+        tx.open(dst, src).await?;
+
+        let (stream_tx, stream_rx) = mpsc::unbounded_channel::<Vec<u8>>();
+        let connection = TcpConnection::new(
+            tx,
+            src,
+            dst,
+            stream_rx,
+            Arc::clone(&write_queue_size),
+            Arc::clone(&written),
+            Arc::clone(&is_read_closed),
+            high_watermark,
+            low_watermark,
+            instant,
+            Arc::clone(&last_active),
+        );
+
+        trace!("accept stream {} -> {}", src, dst);
+
+        Ok((
+            StreamWorker {
+                dst,
+                stream_tx: Some(stream_tx),
+                write_queue_size,
+                written,
+                is_write_failed: Arc::new(AtomicBool::new(false)),
+                is_write_closed,
+                is_read_closed,
+                instant,
+                last_active,
+                connect_latency: None,
+                write_latency: Arc::new(AtomicU64::new(0)),
+            },
+            connection,
+        ))
+    }
+
+    /// Sends data on the SOCKS5 in TCP to the destination. The data is queued and written to the
+    /// stream in the background, an error in writing is returned in the next call.
+    pub fn send(&mut self, payload: Vec<u8>) -> io::Result<()> {
         debug!(
             "send to SOCKS {}: {} -> {} ({} Bytes)",
             "TCP",
@@ -162,10 +326,21 @@ impl StreamWorker {
             payload.len()
         );
 
+        if self.is_write_failed.load(Ordering::Relaxed) {
+            return Err(io::Error::from(io::ErrorKind::BrokenPipe));
+        }
+
         // Send
-        match &mut self.stream_tx {
-            Some(tx) => tx.write_all(payload).await,
-            None => return Err(io::Error::from(io::ErrorKind::NotConnected)),
+        self.last_active
+            .store(elapsed_millis(&self.instant), Ordering::Relaxed);
+        match self.stream_tx {
+            Some(ref tx) => {
+                self.write_queue_size
+                    .fetch_add(payload.len(), Ordering::Relaxed);
+                tx.send(payload)
+                    .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+            }
+            None => Err(io::Error::from(io::ErrorKind::NotConnected)),
         }
     }
 
@@ -175,7 +350,7 @@ impl StreamWorker {
             Shutdown::Write => {
                 if !self.is_write_closed.load(Ordering::Relaxed) {
                     self.is_write_closed.store(true, Ordering::Relaxed);
-                    self.stream_tx.take().unwrap().forget();
+                    self.stream_tx.take();
                     trace!("close stream write {} -> {}", 0, self.dst);
                 }
             }
@@ -189,6 +364,16 @@ impl StreamWorker {
         self.is_read_closed.store(true, Ordering::Relaxed);
     }
 
+    /// Returns the size of the data sent but not written to the stream yet.
+    pub fn write_queue_size(&self) -> usize {
+        self.write_queue_size.load(Ordering::Relaxed)
+    }
+
+    /// Returns the total size of the data written to the stream.
+    pub fn written(&self) -> u64 {
+        self.written.load(Ordering::Relaxed)
+    }
+
     /// Returns if the worker is closed for writing.
     pub fn is_write_closed(&self) -> bool {
         self.is_write_closed.load(Ordering::Relaxed)
@@ -198,6 +383,30 @@ impl StreamWorker {
     pub fn is_read_closed(&self) -> bool {
         self.is_read_closed.load(Ordering::Relaxed)
     }
+
+    /// Returns the time of connecting to the destination through the proxy, or `None` if the
+    /// worker is not connected through a proxy.
+    pub fn connect_latency(&self) -> Option<Duration> {
+        self.connect_latency
+    }
+
+    /// Returns the smoothed time of writing data to the stream, or `None` if no data is written.
+    pub fn write_latency(&self) -> Option<Duration> {
+        match self.write_latency.load(Ordering::Relaxed) {
+            0 => None,
+            latency => Some(Duration::from_micros(latency)),
+        }
+    }
+
+    /// Returns the amount of time elapsed since the worker sent or received data last time.
+    pub fn idle(&self) -> Duration {
+        let last_active = Duration::from_millis(self.last_active.load(Ordering::Relaxed));
+
+        self.instant
+            .elapsed()
+            .checked_sub(last_active)
+            .unwrap_or(Duration::from_millis(0))
+    }
 }
 
 impl Drop for StreamWorker {
@@ -207,78 +416,201 @@ impl Drop for StreamWorker {
     }
 }
 
-/// Trait for forwarding datagram.
-pub trait ForwardDatagram: Send {
+/// Trait for forwarding datagram to sources. Workers call it with the datagrams received from the
+/// proxy or an `Egress`, where the `dst` is the peer which sent the datagram and the `src` is the
+/// source bound.
+/// It is shared by workers in the same way as `ForwardStream`.
+pub trait ForwardDatagram: Send + Sync {
     /// Forwards datagram.
-    fn forward(&mut self, dst: SocketAddrV4, src: SocketAddrV4, payload: &[u8]) -> io::Result<()>;
+    fn forward<'a>(
+        &'a self,
+        dst: SocketAddrV4,
+        src: SocketAddrV4,
+        payload: &'a [u8],
+    ) -> ForwardFuture<'a>;
 }
 
-/// Represents a worker of a SOCKS5 UDP client.
+/// Represents the count of datagrams queued to be sent to the proxy in each UDP worker.
+const DATAGRAM_QUEUE_SIZE: usize = 256;
+/// Represents the maximum count of peers recorded for filtering in each UDP worker.
+const DATAGRAM_PEER_CAPACITY: usize = 1024;
+
+/// Represents the peers which a source has sent to. The least recently sent peer is evicted if the
+/// capacity is reached.
+struct Peers {
+    nat_mode: NatMode,
+    lru: LruCache<SocketAddrV4, ()>,
+}
+
+impl Peers {
+    /// Creates a new `Peers` of the NAT mode.
+    fn new(nat_mode: NatMode, capacity: usize) -> Peers {
+        Peers {
+            nat_mode,
+            lru: LruCache::new(capacity),
+        }
+    }
+
+    /// Records the address as a peer.
+    fn insert(&mut self, addr: SocketAddrV4) {
+        if self.nat_mode != NatMode::FullCone {
+            self.lru.put(nat_peer(self.nat_mode, addr), ());
+        }
+    }
+
+    /// Returns if datagrams from the address pass the filter of the NAT mode.
+    fn is_allowed(&self, addr: SocketAddrV4) -> bool {
+        self.nat_mode == NatMode::FullCone || self.lru.contains(&nat_peer(self.nat_mode, addr))
+    }
+
+    /// Clears the peers.
+    fn clear(&mut self) {
+        self.lru.clear();
+    }
+}
+
+/// Represents a worker of a SOCKS5 UDP client, or of a `UdpSession` terminated in user code.
 pub struct DatagramWorker {
     src: Arc<AtomicU64>,
     local_port: u16,
-    socks_tx: SocksSendHalf,
+    /// Represents the queue of datagrams to be sent to the proxy.
+    socks_tx: mpsc::Sender<(Vec<u8>, SocketAddrV4)>,
     is_closed: Arc<AtomicBool>,
+    instant: Instant,
+    /// Represents the last time in milliseconds since the creation when the worker is active.
+    last_active: Arc<AtomicU64>,
+    /// Represents the peers which the source has sent to.
+    peers: Arc<Mutex<Peers>>,
 }
 
 impl DatagramWorker {
     /// Creates a new `DatagramWorker`.
     pub async fn bind(
-        tx: Arc<Mutex<dyn ForwardDatagram>>,
+        tx: Arc<dyn ForwardDatagram>,
         src: SocketAddrV4,
         remote: SocketAddrV4,
         options: &SocksOption,
+        nat_mode: NatMode,
     ) -> io::Result<(DatagramWorker, u16)> {
-        let (mut socks_rx, socks_tx, local_port) = socks::bind(remote, &options).await?;
+        DatagramWorker::bind_at(tx, src, remote, options, nat_mode, 0).await
+    }
+
+    /// Creates a new `DatagramWorker` on the local port. Any port is bound instead if the local
+    /// port is 0 or is in use.
+    pub async fn bind_at(
+        tx: Arc<dyn ForwardDatagram>,
+        src: SocketAddrV4,
+        remote: SocketAddrV4,
+        options: &SocksOption,
+        nat_mode: NatMode,
+        local_port: u16,
+    ) -> io::Result<(DatagramWorker, u16)> {
+        let (socks_rx, mut socks_send_half, local_port) =
+            bind(remote, src, &options, local_port).await?;
+        // Destinations connected directly share a UDP socket beside the proxy
+        let mut direct = None;
+        if options.has_direct() && !options.is_direct_all() {
+            direct = Some(bind_direct(0).await?);
+        }
 
         let a_src = Arc::new(AtomicU64::from(socket_addr_v4_to_u64(&src)));
-        let a_src_cloned = Arc::clone(&a_src);
         let is_closed = Arc::new(AtomicBool::new(false));
-        let is_closed_cloned = Arc::clone(&is_closed);
+        let instant = Instant::now();
+        let last_active = Arc::new(AtomicU64::new(0));
+        let peers = Arc::new(Mutex::new(Peers::new(nat_mode, DATAGRAM_PEER_CAPACITY)));
+
+        // Send
+        let (socks_tx, mut send_rx) = mpsc::channel::<(Vec<u8>, SocketAddrV4)>(DATAGRAM_QUEUE_SIZE);
+        let options_cloned = options.clone();
+        let a_src_cloned = Arc::clone(&a_src);
+        let (direct_rx, mut direct_send_half) = match direct {
+            Some((recv_half, send_half, _)) => (Some(recv_half), Some(send_half)),
+            None => (None, None),
+        };
         tokio::spawn(async move {
-            let mut buffer = vec![0u8; u16::MAX as usize];
-            loop {
-                if is_closed_cloned.load(Ordering::Relaxed) {
-                    break;
+            while let Some((payload, dst)) = send_rx.recv().await {
+                let src = u64_to_socket_addr_v4(a_src_cloned.load(Ordering::Relaxed));
+                if let Some((ref shaper, class)) =
+                    options_cloned.qos_class(PortProtocol::Udp, src, dst)
+                {
+                    qos::throttle(shaper, class, QosDirection::Upload, payload.len()).await;
                 }
-                match socks_rx.recv_from(&mut buffer).await {
-                    Ok((size, addr)) => {
-                        if is_closed_cloned.load(Ordering::Relaxed) {
-                            break;
-                        }
-                        debug!(
-                            "receive from SOCKS: {}: {} -> {} ({} Bytes)",
-                            "UDP", addr, local_port, size
-                        );
+                let send_half = match direct_send_half {
+                    Some(ref mut send_half) if options_cloned.is_direct(*dst.ip()) => send_half,
+                    _ => &mut socks_send_half,
+                };
+                if let Err(ref e) = send_half.send_to(payload.as_slice(), dst).await {
+                    warn!("SOCKS: {}: {} -> {}: {}", "UDP", local_port, dst, e);
+                }
+            }
+        });
 
-                        // Send
-                        if let Err(ref e) = tx.lock().unwrap().forward(
-                            addr,
-                            u64_to_socket_addr_v4(a_src_cloned.load(Ordering::Relaxed)),
-                            &buffer[..size],
-                        ) {
-                            warn!("handle {}: {}", "UDP", e);
-                        }
+        // Receive
+        let spawn_recv = |mut socks_rx: ProxyRecvHalf| {
+            let tx = Arc::clone(&tx);
+            let a_src_cloned = Arc::clone(&a_src);
+            let is_closed_cloned = Arc::clone(&is_closed);
+            let last_active_cloned = Arc::clone(&last_active);
+            let peers_cloned = Arc::clone(&peers);
+            clock::spawn(async move {
+                let mut buffer = vec![0u8; u16::MAX as usize];
+                loop {
+                    if is_closed_cloned.load(Ordering::Relaxed) {
+                        break;
                     }
-                    Err(ref e) => {
-                        if e.kind() == io::ErrorKind::TimedOut {
-                            time::delay_for(Duration::from_millis(TIMEDOUT_WAIT)).await;
-                            continue;
+                    match socks_rx.recv_from(&mut buffer).await {
+                        Ok((size, addr)) => {
+                            if is_closed_cloned.load(Ordering::Relaxed) {
+                                break;
+                            }
+                            debug!(
+                                "receive from SOCKS: {}: {} -> {} ({} Bytes)",
+                                "UDP", addr, local_port, size
+                            );
+
+                            // Filter
+                            if !peers_cloned.lock().unwrap().is_allowed(addr) {
+                                trace!("filter datagram {} -> {} ({})", addr, local_port, nat_mode);
+                                continue;
+                            }
+                            last_active_cloned.store(elapsed_millis(&instant), Ordering::Relaxed);
+
+                            // Send
+                            if let Err(ref e) = tx
+                                .forward(
+                                    addr,
+                                    u64_to_socket_addr_v4(a_src_cloned.load(Ordering::Relaxed)),
+                                    &buffer[..size],
+                                )
+                                .await
+                            {
+                                warn!("handle {}: {}", "UDP", e);
+                            }
                         }
-                        warn!(
-                            "SOCKS: {}: {} = {}: {}",
-                            "UDP",
-                            local_port,
-                            u64_to_socket_addr_v4(a_src_cloned.load(Ordering::Relaxed)),
-                            e
-                        );
-                        is_closed_cloned.store(true, Ordering::Relaxed);
+                        Err(ref e) => {
+                            if e.kind() == io::ErrorKind::TimedOut {
+                                time::delay_for(Duration::from_millis(TIMEDOUT_WAIT)).await;
+                                continue;
+                            }
+                            warn!(
+                                "SOCKS: {}: {} = {}: {}",
+                                "UDP",
+                                local_port,
+                                u64_to_socket_addr_v4(a_src_cloned.load(Ordering::Relaxed)),
+                                e
+                            );
+                            is_closed_cloned.store(true, Ordering::Relaxed);
 
-                        break;
+                            break;
+                        }
                     }
                 }
-            }
-        });
+            });
+        };
+        spawn_recv(socks_rx);
+        if let Some(direct_rx) = direct_rx {
+            spawn_recv(direct_rx);
+        }
 
         trace!("create datagram {} = {}", src, local_port);
 
@@ -288,13 +620,59 @@ impl DatagramWorker {
                 local_port,
                 socks_tx,
                 is_closed,
+                instant,
+                last_active,
+                peers,
             },
             local_port,
         ))
     }
 
-    /// Sends data on the SOCKS5 in UDP to the destination.
-    pub async fn send_to(&mut self, payload: &[u8], dst: SocketAddrV4) -> io::Result<usize> {
+    /// Creates a new `DatagramWorker` whose datagrams are received from the returned `UdpSession`
+    /// instead of a SOCKS5 UDP client. The `local_port` identifies the worker.
+    pub fn accept(
+        tx: Arc<dyn ForwardDatagram>,
+        src: SocketAddrV4,
+        local_port: u16,
+        nat_mode: NatMode,
+    ) -> (DatagramWorker, UdpSession) {
+        let a_src = Arc::new(AtomicU64::from(socket_addr_v4_to_u64(&src)));
+        let is_closed = Arc::new(AtomicBool::new(false));
+        let instant = Instant::now();
+        let last_active = Arc::new(AtomicU64::new(0));
+        let peers = Arc::new(Mutex::new(Peers::new(nat_mode, DATAGRAM_PEER_CAPACITY)));
+
+        let (socks_tx, session_rx) = mpsc::channel::<(Vec<u8>, SocketAddrV4)>(DATAGRAM_QUEUE_SIZE);
+        let session = UdpSession::new(
+            tx,
+            Arc::clone(&a_src),
+            session_rx,
+            Arc::clone(&is_closed),
+            instant,
+            Arc::clone(&last_active),
+            nat_mode,
+            Arc::clone(&peers),
+        );
+
+        trace!("accept datagram {} = {}", src, local_port);
+
+        (
+            DatagramWorker {
+                src: a_src,
+                local_port,
+                socks_tx,
+                is_closed,
+                instant,
+                last_active,
+                peers,
+            },
+            session,
+        )
+    }
+
+    /// Sends data on the SOCKS5 in UDP to the destination. The datagram is queued and sent in the
+    /// background, an error of the kind `WouldBlock` is returned if the queue is full.
+    pub fn send_to(&mut self, payload: &[u8], dst: SocketAddrV4) -> io::Result<()> {
         debug!(
             "send to SOCKS {}: {} -> {} ({} Bytes)",
             "UDP",
@@ -303,8 +681,22 @@ impl DatagramWorker {
             payload.len()
         );
 
+        // Record the peer
+        self.peers.lock().unwrap().insert(dst);
+
         // Send
-        self.socks_tx.send_to(payload, dst).await
+        self.last_active
+            .store(elapsed_millis(&self.instant), Ordering::Relaxed);
+        match self.socks_tx.try_send((payload.to_vec(), dst)) {
+            Ok(_) => Ok(()),
+            Err(mpsc::error::TrySendError::Full(_)) => Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                "datagram queue is full",
+            )),
+            Err(mpsc::error::TrySendError::Closed(_)) => {
+                Err(io::Error::from(io::ErrorKind::BrokenPipe))
+            }
+        }
     }
 
     /// Sets the source of the `DatagramWorker`.
@@ -314,6 +706,12 @@ impl DatagramWorker {
         trace!("set datagram {} = {}", src, self.local_port);
     }
 
+    /// Clears the peers which the source has sent to, so none of them can pass the filter until
+    /// the source sends to them again.
+    pub fn clear_peers(&mut self) {
+        self.peers.lock().unwrap().clear();
+    }
+
     /// Returns the source of the `DatagramWorker`.
     pub fn src(&self) -> SocketAddrV4 {
         u64_to_socket_addr_v4(self.src.load(Ordering::Relaxed))
@@ -323,6 +721,16 @@ impl DatagramWorker {
     pub fn is_closed(&self) -> bool {
         self.is_closed.load(Ordering::Relaxed)
     }
+
+    /// Returns the amount of time elapsed since the worker sent or received a datagram last time.
+    pub fn idle(&self) -> Duration {
+        let last_active = Duration::from_millis(self.last_active.load(Ordering::Relaxed));
+
+        self.instant
+            .elapsed()
+            .checked_sub(last_active)
+            .unwrap_or(Duration::from_millis(0))
+    }
 }
 
 impl Drop for DatagramWorker {
@@ -332,6 +740,389 @@ impl Drop for DatagramWorker {
     }
 }
 
+/// Represents the read half of a stream connected through the proxy, or by an `Egress`.
+enum ProxyReadHalf {
+    Socks(OwnedReadHalf),
+    Egress(EgressReadHalf),
+    #[cfg(feature = "http2")]
+    Http2(http2::Http2ReadHalf),
+    #[cfg(feature = "ssh")]
+    Ssh(ssh::SshReadHalf),
+    #[cfg(feature = "vmess")]
+    Vmess(vmess::VmessReadHalf),
+    #[cfg(feature = "websocket")]
+    WebSocket(io::ReadHalf<websocket::WebSocketStream>),
+}
+
+impl ProxyReadHalf {
+    async fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        match self {
+            ProxyReadHalf::Socks(read_half) => read_half.read(buffer).await,
+            ProxyReadHalf::Egress(read_half) => read_half.read(buffer).await,
+            #[cfg(feature = "http2")]
+            ProxyReadHalf::Http2(read_half) => read_half.read(buffer).await,
+            #[cfg(feature = "ssh")]
+            ProxyReadHalf::Ssh(read_half) => read_half.read(buffer).await,
+            #[cfg(feature = "vmess")]
+            ProxyReadHalf::Vmess(read_half) => read_half.read(buffer).await,
+            #[cfg(feature = "websocket")]
+            ProxyReadHalf::WebSocket(read_half) => read_half.read(buffer).await,
+        }
+    }
+}
+
+/// Represents the write half of a stream connected through the proxy, or by an `Egress`.
+enum ProxyWriteHalf {
+    Socks(OwnedWriteHalf),
+    Egress(EgressWriteHalf),
+    #[cfg(feature = "http2")]
+    Http2(http2::Http2WriteHalf),
+    #[cfg(feature = "ssh")]
+    Ssh(ssh::SshWriteHalf),
+    #[cfg(feature = "vmess")]
+    Vmess(vmess::VmessWriteHalf),
+    #[cfg(feature = "websocket")]
+    WebSocket(io::WriteHalf<websocket::WebSocketStream>),
+}
+
+impl ProxyWriteHalf {
+    async fn write_all(&mut self, payload: &[u8]) -> io::Result<()> {
+        match self {
+            ProxyWriteHalf::Socks(write_half) => write_half.write_all(payload).await,
+            ProxyWriteHalf::Egress(write_half) => {
+                write_half.write_all(payload).await?;
+                write_half.flush().await
+            }
+            #[cfg(feature = "http2")]
+            ProxyWriteHalf::Http2(write_half) => write_half.write_all(payload).await,
+            #[cfg(feature = "ssh")]
+            ProxyWriteHalf::Ssh(write_half) => write_half.write_all(payload).await,
+            #[cfg(feature = "vmess")]
+            ProxyWriteHalf::Vmess(write_half) => write_half.write_all(payload).await,
+            #[cfg(feature = "websocket")]
+            ProxyWriteHalf::WebSocket(write_half) => {
+                write_half.write_all(payload).await?;
+                write_half.flush().await
+            }
+        }
+    }
+
+    async fn close(self) {
+        match self {
+            ProxyWriteHalf::Socks(write_half) => write_half.forget(),
+            ProxyWriteHalf::Egress(mut write_half) => {
+                if let Err(ref e) = write_half.shutdown().await {
+                    trace!("shut down egress stream: {}", e);
+                }
+            }
+            #[cfg(feature = "http2")]
+            ProxyWriteHalf::Http2(write_half) => write_half.close().await,
+            #[cfg(feature = "ssh")]
+            ProxyWriteHalf::Ssh(write_half) => write_half.close().await,
+            #[cfg(feature = "vmess")]
+            ProxyWriteHalf::Vmess(write_half) => write_half.close().await,
+            // Closing the WebSocket connection closes both directions, so leave it to the read half
+            #[cfg(feature = "websocket")]
+            ProxyWriteHalf::WebSocket(_) => {}
+        }
+    }
+}
+
+async fn connect(
+    remote: SocketAddrV4,
+    src: SocketAddrV4,
+    dst: SocketAddrV4,
+    options: &SocksOption,
+) -> io::Result<(ProxyReadHalf, ProxyWriteHalf)> {
+    if options.is_direct(*dst.ip()) {
+        return connect_direct(dst).await;
+    }
+
+    if let Some(egress) = options.egress() {
+        let (read_half, write_half) = egress.connect(src, dst).await?;
+
+        return Ok((
+            ProxyReadHalf::Egress(read_half),
+            ProxyWriteHalf::Egress(write_half),
+        ));
+    }
+
+    #[cfg(feature = "http2")]
+    if let Some(client) = options.http2() {
+        let (read_half, write_half) = http2::connect(remote, dst, client).await?;
+
+        return Ok((
+            ProxyReadHalf::Http2(read_half),
+            ProxyWriteHalf::Http2(write_half),
+        ));
+    }
+    #[cfg(feature = "ssh")]
+    if let Some(client) = options.ssh() {
+        let (read_half, write_half) = ssh::connect(remote, dst, client).await?;
+
+        return Ok((
+            ProxyReadHalf::Ssh(read_half),
+            ProxyWriteHalf::Ssh(write_half),
+        ));
+    }
+    #[cfg(feature = "vmess")]
+    if let Some(vmess) = options.vmess() {
+        let (read_half, write_half) = vmess::connect(remote, dst, vmess).await?;
+
+        return Ok((
+            ProxyReadHalf::Vmess(read_half),
+            ProxyWriteHalf::Vmess(write_half),
+        ));
+    }
+
+    #[cfg(feature = "websocket")]
+    if let Some(websocket) = options.websocket() {
+        let mut stream = websocket::connect(remote, websocket).await?;
+        socks::handshake(&mut stream, dst, options).await?;
+        let (read_half, write_half) = io::split(stream);
+
+        return Ok((
+            ProxyReadHalf::WebSocket(read_half),
+            ProxyWriteHalf::WebSocket(write_half),
+        ));
+    }
+
+    let stream = socks::connect(remote, dst, options).await?;
+    let (read_half, write_half) = stream.into_inner().into_split();
+
+    Ok((
+        ProxyReadHalf::Socks(read_half),
+        ProxyWriteHalf::Socks(write_half),
+    ))
+}
+
+/// Connects to the destination directly without the proxy.
+async fn connect_direct(dst: SocketAddrV4) -> io::Result<(ProxyReadHalf, ProxyWriteHalf)> {
+    let (read_half, write_half) = TcpStream::connect(dst).await?.into_split();
+    trace!("connect {} directly", dst);
+
+    Ok((
+        ProxyReadHalf::Socks(read_half),
+        ProxyWriteHalf::Socks(write_half),
+    ))
+}
+
+/// Relays a `TcpConnection` to its destination through the proxy, or directly if the proxy is
+/// `None`, until both directions are closed.
+pub(crate) async fn relay(
+    connection: TcpConnection,
+    remote: Option<SocketAddrV4>,
+    options: &SocksOption,
+) -> io::Result<()> {
+    let dst = connection.dst();
+    let (mut stream_rx, mut stream_tx) = match remote {
+        Some(remote) => connect(remote, connection.src(), dst, options).await?,
+        None => connect_direct(dst).await?,
+    };
+    trace!("relay stream {} -> {}", connection.src(), dst);
+    let qos = options.qos_class(PortProtocol::Tcp, connection.src(), dst);
+    let (mut connection_rx, mut connection_tx) = io::split(connection);
+
+    let upstream = async move {
+        let mut buffer = vec![0u8; u16::MAX as usize];
+        loop {
+            let size = connection_rx.read(&mut buffer).await?;
+            if size == 0 {
+                break;
+            }
+            if let Some((ref shaper, class)) = qos {
+                qos::throttle(shaper, class, QosDirection::Upload, size).await;
+            }
+            stream_tx.write_all(&buffer[..size]).await?;
+        }
+        stream_tx.close().await;
+
+        Ok(())
+    };
+    let downstream = async move {
+        let mut buffer = vec![0u8; u16::MAX as usize];
+        loop {
+            let size = stream_rx.read(&mut buffer).await?;
+            if size == 0 {
+                break;
+            }
+            connection_tx.write_all(&buffer[..size]).await?;
+        }
+
+        connection_tx.shutdown().await
+    };
+    let (upstream, downstream): (io::Result<()>, io::Result<()>) =
+        tokio::join!(upstream, downstream);
+
+    upstream.and(downstream)
+}
+
+/// Represents the receive half of a UDP client through the proxy, of a UDP socket sending to
+/// destinations directly, or of a datagram socket bound by an `Egress`.
+enum ProxyRecvHalf {
+    Direct(RecvHalf),
+    Egress(Box<dyn EgressRecvHalf>),
+    Socks(socks::SocksRecvHalf),
+    #[cfg(feature = "http2")]
+    Http2(http2::Http2RecvHalf),
+    #[cfg(feature = "vmess")]
+    Vmess(vmess::VmessRecvHalf),
+}
+
+impl ProxyRecvHalf {
+    async fn recv_from(&mut self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddrV4)> {
+        match self {
+            ProxyRecvHalf::Direct(recv_half) => loop {
+                // Only IPv4 peers can be redirected to the source
+                if let (size, SocketAddr::V4(addr)) = recv_half.recv_from(buffer).await? {
+                    return Ok((size, addr));
+                }
+            },
+            ProxyRecvHalf::Egress(recv_half) => recv_half.recv_from(buffer).await,
+            ProxyRecvHalf::Socks(recv_half) => recv_half.recv_from(buffer).await,
+            #[cfg(feature = "http2")]
+            ProxyRecvHalf::Http2(recv_half) => recv_half.recv_from(buffer).await,
+            #[cfg(feature = "vmess")]
+            ProxyRecvHalf::Vmess(recv_half) => recv_half.recv_from(buffer).await,
+        }
+    }
+}
+
+/// Represents the send half of a UDP client through the proxy, of a UDP socket sending to
+/// destinations directly, or of a datagram socket bound by an `Egress`.
+enum ProxySendHalf {
+    Direct(SendHalf),
+    Egress(Box<dyn EgressSendHalf>),
+    Socks(socks::SocksSendHalf),
+    #[cfg(feature = "http2")]
+    Http2(http2::Http2SendHalf),
+    #[cfg(feature = "vmess")]
+    Vmess(vmess::VmessSendHalf),
+}
+
+impl ProxySendHalf {
+    async fn send_to(&mut self, payload: &[u8], dst: SocketAddrV4) -> io::Result<usize> {
+        match self {
+            ProxySendHalf::Direct(send_half) => send_half.send_to(payload, &dst.into()).await,
+            ProxySendHalf::Egress(send_half) => send_half.send_to(payload, dst).await,
+            ProxySendHalf::Socks(send_half) => send_half.send_to(payload, dst).await,
+            #[cfg(feature = "http2")]
+            ProxySendHalf::Http2(send_half) => send_half.send_to(payload, dst).await,
+            #[cfg(feature = "vmess")]
+            ProxySendHalf::Vmess(send_half) => send_half.send_to(payload, dst).await,
+        }
+    }
+}
+
+async fn bind(
+    remote: SocketAddrV4,
+    src: SocketAddrV4,
+    options: &SocksOption,
+    local_port: u16,
+) -> io::Result<(ProxyRecvHalf, ProxySendHalf, u16)> {
+    if options.is_direct_all() {
+        return bind_direct(local_port).await;
+    }
+
+    if let Some(egress) = options.egress() {
+        let (recv_half, send_half, local_port) = egress.bind(src, local_port).await?;
+
+        return Ok((
+            ProxyRecvHalf::Egress(recv_half),
+            ProxySendHalf::Egress(send_half),
+            local_port,
+        ));
+    }
+
+    #[cfg(feature = "http2")]
+    if let Some(client) = options.http2() {
+        let (recv_half, send_half, local_port) = http2::bind(remote, client).await?;
+
+        return Ok((
+            ProxyRecvHalf::Http2(recv_half),
+            ProxySendHalf::Http2(send_half),
+            local_port,
+        ));
+    }
+    // Only TCP can be forwarded in direct-tcpip channels
+    #[cfg(feature = "ssh")]
+    if options.ssh().is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "UDP is not supported over SSH",
+        ));
+    }
+    #[cfg(feature = "vmess")]
+    if let Some(vmess) = options.vmess() {
+        let (recv_half, send_half, local_port) = vmess::bind(remote, vmess).await?;
+
+        return Ok((
+            ProxyRecvHalf::Vmess(recv_half),
+            ProxySendHalf::Vmess(send_half),
+            local_port,
+        ));
+    }
+
+    // SOCKS5 relays UDP out of the stream, which cannot be carried in WebSocket
+    #[cfg(feature = "websocket")]
+    if options.websocket().is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "UDP is not supported over WebSocket",
+        ));
+    }
+
+    let (recv_half, send_half, local_port) = socks::bind(remote, options, local_port).await?;
+
+    Ok((
+        ProxyRecvHalf::Socks(recv_half),
+        ProxySendHalf::Socks(send_half),
+        local_port,
+    ))
+}
+
+/// Binds a UDP socket sending to destinations directly without the proxy.
+async fn bind_direct(local_port: u16) -> io::Result<(ProxyRecvHalf, ProxySendHalf, u16)> {
+    let socket = socks::bind_udp(local_port).await?;
+    let local_port = socket.local_addr()?.port();
+    let (recv_half, send_half) = socket.split();
+
+    Ok((
+        ProxyRecvHalf::Direct(recv_half),
+        ProxySendHalf::Direct(send_half),
+        local_port,
+    ))
+}
+
+fn nat_peer(nat_mode: NatMode, addr: SocketAddrV4) -> SocketAddrV4 {
+    match nat_mode {
+        NatMode::AddressRestricted => SocketAddrV4::new(addr.ip().clone(), 0),
+        _ => addr,
+    }
+}
+
+/// Updates the smoothed latency in microseconds with a new sample in the way of the SRTT of TCP.
+fn update_latency(latency: &AtomicU64, sample: Duration) {
+    // Keep the latency non-zero once sampled
+    let sample = max(min(sample.as_micros(), u64::MAX as u128) as u64, 1);
+    let prev = latency.load(Ordering::Relaxed);
+    let latency_new = match prev {
+        0 => sample,
+        prev => prev - prev / 8 + sample / 8,
+    };
+
+    latency.store(latency_new, Ordering::Relaxed);
+}
+
+fn elapsed_millis(instant: &Instant) -> u64 {
+    let elapsed = instant.elapsed().as_millis();
+    if elapsed > u64::MAX as u128 {
+        u64::MAX
+    } else {
+        elapsed as u64
+    }
+}
+
 fn socket_addr_v4_to_u64(addr: &SocketAddrV4) -> u64 {
     let ip = u32::from(addr.ip().clone());
 
@@ -345,3 +1136,378 @@ fn u64_to_socket_addr_v4(v: u64) -> SocketAddrV4 {
 
     SocketAddrV4::new(ip, port)
 }
+
+#[tokio::test]
+async fn stream_worker_mock_socks() {
+    use crate::testing::{Fault, MockSocks};
+
+    struct RecordStream {
+        forwarded: Vec<u8>,
+    }
+
+    impl ForwardStream for Mutex<RecordStream> {
+        fn open(&self, _: SocketAddrV4, _: SocketAddrV4) -> ForwardFuture<'_> {
+            Box::pin(async { Ok(()) })
+        }
+
+        fn forward<'a>(
+            &'a self,
+            _: SocketAddrV4,
+            _: SocketAddrV4,
+            payload: &'a [u8],
+        ) -> ForwardFuture<'a> {
+            self.lock().unwrap().forwarded.extend_from_slice(payload);
+
+            Box::pin(async { Ok(()) })
+        }
+
+        fn queue_size(&self, _: SocketAddrV4, _: SocketAddrV4) -> usize {
+            0
+        }
+
+        fn close(&self, _: SocketAddrV4, _: SocketAddrV4) -> ForwardFuture<'_> {
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    // An echo server as the destination
+    let mut listener = tokio::net::TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))
+        .await
+        .unwrap();
+    let dst = match listener.local_addr().unwrap() {
+        std::net::SocketAddr::V4(addr) => addr,
+        _ => unreachable!(),
+    };
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let (mut rx, mut tx) = stream.split();
+                let _ = io::copy(&mut rx, &mut tx).await;
+            });
+        }
+    });
+
+    let server = MockSocks::new().auth("us", "pw");
+    let remote = server.spawn().await.unwrap();
+    let auth = SocksAuth::new("us".to_string(), Secret::new("pw".to_string()));
+    let options = SocksOption::new(false, false, Some(auth));
+    let src = "10.6.0.2:1024".parse().unwrap();
+    let tx = Arc::new(Mutex::new(RecordStream {
+        forwarded: Vec::new(),
+    }));
+    let mut worker = StreamWorker::connect(tx.clone(), src, dst, remote, &options, 0, 0)
+        .await
+        .unwrap();
+    assert!(worker.connect_latency().is_some());
+
+    worker.send(b"hello".to_vec()).unwrap();
+    for _ in 0..100 {
+        if tx.lock().unwrap().forwarded.len() >= 5 {
+            break;
+        }
+        time::delay_for(Duration::from_millis(10)).await;
+    }
+    assert_eq!(tx.lock().unwrap().forwarded, b"hello");
+    assert_eq!(server.requests(), 1);
+
+    // Handshake failures
+    let cases = vec![
+        (None, Some(("us", "wrong")), ConnectFailure::Auth),
+        (None, None, ConnectFailure::Method),
+        (
+            Some(Fault::NoAcceptableMethods),
+            Some(("us", "pw")),
+            ConnectFailure::Method,
+        ),
+        (
+            Some(Fault::AuthFailure),
+            Some(("us", "pw")),
+            ConnectFailure::Auth,
+        ),
+        (
+            Some(Fault::Reply(2)),
+            Some(("us", "pw")),
+            ConnectFailure::Reply,
+        ),
+        (
+            Some(Fault::CloseBeforeReply),
+            Some(("us", "pw")),
+            ConnectFailure::Network,
+        ),
+    ];
+    for (fault, auth, failure) in cases {
+        let server = match fault {
+            Some(fault) => MockSocks::new().auth("us", "pw").fault(fault),
+            None => MockSocks::new().auth("us", "pw"),
+        };
+        let remote = server.spawn().await.unwrap();
+        let auth = auth.map(|(username, password)| {
+            SocksAuth::new(username.to_string(), Secret::new(password.to_string()))
+        });
+        let options = SocksOption::new(false, false, auth);
+        let e = match connect(remote, "10.6.0.2:1024".parse().unwrap(), dst, &options).await {
+            Ok(_) => panic!("connect with {:?}", fault),
+            Err(e) => e,
+        };
+        assert_eq!(
+            ConnectFailure::from_error(&e),
+            failure,
+            "{:?}: {}",
+            fault,
+            e
+        );
+    }
+}
+
+#[tokio::test]
+async fn datagram_worker_mock_socks() {
+    use crate::testing::{Fault, MockSocks};
+
+    struct RecordDatagram {
+        forwarded: Vec<(SocketAddrV4, Vec<u8>)>,
+    }
+
+    impl ForwardDatagram for Mutex<RecordDatagram> {
+        fn forward<'a>(
+            &'a self,
+            dst: SocketAddrV4,
+            _: SocketAddrV4,
+            payload: &'a [u8],
+        ) -> ForwardFuture<'a> {
+            self.lock().unwrap().forwarded.push((dst, payload.to_vec()));
+
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    // An echo server as the destination
+    let mut socket = tokio::net::UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))
+        .await
+        .unwrap();
+    let dst = match socket.local_addr().unwrap() {
+        std::net::SocketAddr::V4(addr) => addr,
+        _ => unreachable!(),
+    };
+    tokio::spawn(async move {
+        let mut buffer = [0u8; 1500];
+        while let Ok((size, addr)) = socket.recv_from(&mut buffer).await {
+            let _ = socket.send_to(&buffer[..size], &addr).await;
+        }
+    });
+
+    // The unspecified ASSOCIATE address is rewritten to the address of the proxy
+    let server = MockSocks::new().fault(Fault::UnspecifiedAssociateAddr);
+    let remote = server.spawn().await.unwrap();
+    let options = SocksOption::new(false, false, None);
+    let src = "10.6.0.2:1024".parse().unwrap();
+    let tx = Arc::new(Mutex::new(RecordDatagram {
+        forwarded: Vec::new(),
+    }));
+    let (mut worker, _) =
+        DatagramWorker::bind(tx.clone(), src, remote, &options, NatMode::FullCone)
+            .await
+            .unwrap();
+
+    worker.send_to(b"hello", dst).unwrap();
+    for _ in 0..100 {
+        if !tx.lock().unwrap().forwarded.is_empty() {
+            break;
+        }
+        time::delay_for(Duration::from_millis(10)).await;
+    }
+    assert_eq!(tx.lock().unwrap().forwarded, vec![(dst, b"hello".to_vec())]);
+}
+
+#[tokio::test]
+async fn datagram_worker_direct() {
+    use crate::source::AddrRange;
+
+    struct RecordDatagram {
+        forwarded: Vec<(SocketAddrV4, Vec<u8>)>,
+    }
+
+    impl ForwardDatagram for Mutex<RecordDatagram> {
+        fn forward<'a>(
+            &'a self,
+            dst: SocketAddrV4,
+            _: SocketAddrV4,
+            payload: &'a [u8],
+        ) -> ForwardFuture<'a> {
+            self.lock().unwrap().forwarded.push((dst, payload.to_vec()));
+
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    // An echo server as the destination
+    let mut socket = tokio::net::UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))
+        .await
+        .unwrap();
+    let dst = match socket.local_addr().unwrap() {
+        SocketAddr::V4(addr) => addr,
+        _ => unreachable!(),
+    };
+    tokio::spawn(async move {
+        let mut buffer = [0u8; 1500];
+        while let Ok((size, addr)) = socket.recv_from(&mut buffer).await {
+            let _ = socket.send_to(&buffer[..size], &addr).await;
+        }
+    });
+
+    // No proxy is listening, so the datagram can only be sent directly
+    let remote = "127.0.0.1:1".parse().unwrap();
+    let mut options = SocksOption::new(false, false, None);
+    options.set_direct(&[AddrRange::new(Ipv4Addr::UNSPECIFIED, Ipv4Addr::BROADCAST)]);
+    let src = "10.6.0.2:1024".parse().unwrap();
+    let tx = Arc::new(Mutex::new(RecordDatagram {
+        forwarded: Vec::new(),
+    }));
+    let (mut worker, _) =
+        DatagramWorker::bind(tx.clone(), src, remote, &options, NatMode::FullCone)
+            .await
+            .unwrap();
+
+    worker.send_to(b"hello", dst).unwrap();
+    for _ in 0..100 {
+        if !tx.lock().unwrap().forwarded.is_empty() {
+            break;
+        }
+        time::delay_for(Duration::from_millis(10)).await;
+    }
+    assert_eq!(tx.lock().unwrap().forwarded, vec![(dst, b"hello".to_vec())]);
+}
+
+#[tokio::test]
+async fn datagram_worker_egress() {
+    use crate::egress::{Egress, EgressBinding, EgressFuture, EgressReadHalf, EgressWriteHalf};
+
+    struct RecordDatagram {
+        forwarded: Vec<(SocketAddrV4, Vec<u8>)>,
+    }
+
+    impl ForwardDatagram for Mutex<RecordDatagram> {
+        fn forward<'a>(
+            &'a self,
+            dst: SocketAddrV4,
+            _: SocketAddrV4,
+            payload: &'a [u8],
+        ) -> ForwardFuture<'a> {
+            self.lock().unwrap().forwarded.push((dst, payload.to_vec()));
+
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    // An egress echoes datagrams back from their destinations without any socket
+    struct EchoEgress;
+
+    struct EchoRecvHalf(mpsc::UnboundedReceiver<(Vec<u8>, SocketAddrV4)>);
+
+    impl EgressRecvHalf for EchoRecvHalf {
+        fn recv_from<'a>(
+            &'a mut self,
+            buffer: &'a mut [u8],
+        ) -> EgressFuture<'a, (usize, SocketAddrV4)> {
+            Box::pin(async move {
+                match self.0.recv().await {
+                    Some((payload, addr)) => {
+                        buffer[..payload.len()].copy_from_slice(&payload);
+                        Ok((payload.len(), addr))
+                    }
+                    None => Err(io::Error::from(io::ErrorKind::BrokenPipe)),
+                }
+            })
+        }
+    }
+
+    struct EchoSendHalf(mpsc::UnboundedSender<(Vec<u8>, SocketAddrV4)>);
+
+    impl EgressSendHalf for EchoSendHalf {
+        fn send_to<'a>(
+            &'a mut self,
+            payload: &'a [u8],
+            dst: SocketAddrV4,
+        ) -> EgressFuture<'a, usize> {
+            let result = self
+                .0
+                .send((payload.to_vec(), dst))
+                .map(|_| payload.len())
+                .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe));
+
+            Box::pin(async move { result })
+        }
+    }
+
+    impl Egress for EchoEgress {
+        fn connect(
+            &self,
+            _: SocketAddrV4,
+            _: SocketAddrV4,
+        ) -> EgressFuture<'_, (EgressReadHalf, EgressWriteHalf)> {
+            Box::pin(async { Err(io::Error::from(io::ErrorKind::ConnectionRefused)) })
+        }
+
+        fn bind(&self, _: SocketAddrV4, local_port: u16) -> EgressFuture<'_, EgressBinding> {
+            let (tx, rx) = mpsc::unbounded_channel();
+
+            Box::pin(async move {
+                let recv_half: Box<dyn EgressRecvHalf> = Box::new(EchoRecvHalf(rx));
+                let send_half: Box<dyn EgressSendHalf> = Box::new(EchoSendHalf(tx));
+
+                Ok((recv_half, send_half, local_port))
+            })
+        }
+    }
+
+    // No proxy is listening, so the datagram can only be sent by the egress
+    let remote = "127.0.0.1:1".parse().unwrap();
+    let mut options = SocksOption::new(false, false, None);
+    options.set_egress(Arc::new(EchoEgress));
+    let src = "10.6.0.2:1024".parse().unwrap();
+    let dst = "198.51.100.1:3074".parse().unwrap();
+    let tx = Arc::new(Mutex::new(RecordDatagram {
+        forwarded: Vec::new(),
+    }));
+    let (mut worker, port) =
+        DatagramWorker::bind_at(tx.clone(), src, remote, &options, NatMode::FullCone, 40000)
+            .await
+            .unwrap();
+    assert_eq!(port, 40000);
+
+    worker.send_to(b"hello", dst).unwrap();
+    for _ in 0..100 {
+        if !tx.lock().unwrap().forwarded.is_empty() {
+            break;
+        }
+        time::delay_for(Duration::from_millis(10)).await;
+    }
+    assert_eq!(tx.lock().unwrap().forwarded, vec![(dst, b"hello".to_vec())]);
+}
+
+#[test]
+fn peers_capacity() {
+    let addr = |port| SocketAddrV4::new(Ipv4Addr::new(1, 1, 1, 1), port);
+
+    let mut peers = Peers::new(NatMode::PortRestricted, 2);
+    peers.insert(addr(1000));
+    peers.insert(addr(1001));
+    assert!(peers.is_allowed(addr(1000)));
+    assert!(!peers.is_allowed(addr(1002)));
+
+    // The least recently sent peer is evicted
+    peers.insert(addr(1001));
+    peers.insert(addr(1002));
+    assert!(!peers.is_allowed(addr(1000)));
+    assert!(peers.is_allowed(addr(1001)));
+    assert!(peers.is_allowed(addr(1002)));
+
+    // Ports of peers are ignored in the address-restricted mode
+    let mut peers = Peers::new(NatMode::AddressRestricted, 2);
+    peers.insert(addr(1000));
+    assert!(peers.is_allowed(addr(2000)));
+    assert!(!peers.is_allowed(SocketAddrV4::new(Ipv4Addr::new(1, 0, 0, 1), 1000)));
+
+    // All peers are allowed in the full-cone mode
+    let peers = Peers::new(NatMode::FullCone, 2);
+    assert!(peers.is_allowed(addr(1000)));
+}
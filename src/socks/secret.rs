@@ -0,0 +1,201 @@
+//! Support for keeping secrets, like passwords of proxies, which are zeroed when they are dropped.
+
+use std::fmt::{self, Debug, Formatter};
+use std::io;
+use std::ptr;
+use std::str;
+use std::sync::atomic::{self, Ordering};
+
+#[cfg(unix)]
+use std::fs::File;
+#[cfg(unix)]
+use std::io::Read;
+#[cfg(unix)]
+use std::os::unix::io::{FromRawFd, RawFd};
+
+/// Represents the max size of a secret.
+const MAX_SECRET_SIZE: usize = 4096;
+
+/// Represents a secret, like the password of a proxy, which is zeroed when it is dropped and is
+/// never shown in debug output.
+pub struct Secret(Vec<u8>);
+
+impl Secret {
+    /// Creates a new `Secret` which takes the string without copying it.
+    pub fn new(s: String) -> Secret {
+        Secret(s.into_bytes())
+    }
+
+    pub(crate) fn from_bytes(bytes: Vec<u8>) -> Secret {
+        Secret(bytes)
+    }
+
+    /// Creates a new `Secret` read from the file descriptor until the end, like a pipe from a
+    /// secret manager. A trailing newline is trimmed, and the file descriptor is closed.
+    #[cfg(unix)]
+    pub fn from_fd(fd: RawFd) -> io::Result<Secret> {
+        let mut file = unsafe { File::from_raw_fd(fd) };
+        // Reserve the buffer ahead, so no copy is left behind by reallocations
+        let mut secret = Secret(Vec::with_capacity(MAX_SECRET_SIZE + 1));
+        let mut buffer = [0u8; 512];
+        let result = loop {
+            match file.read(&mut buffer) {
+                Ok(0) => break Ok(()),
+                Ok(size) if secret.0.len() + size > MAX_SECRET_SIZE => {
+                    break Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "secret too long",
+                    ))
+                }
+                Ok(size) => secret.0.extend_from_slice(&buffer[..size]),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => break Err(e),
+            }
+        };
+        zeroize(&mut buffer);
+        result?;
+
+        secret.trim_end();
+        secret.validate()?;
+
+        Ok(secret)
+    }
+
+    /// Creates a new `Secret` from the user key of the description in the Linux kernel keyring,
+    /// like one added by `keyctl add user pcap2socks <PASSWORD> @u`.
+    #[cfg(target_os = "linux")]
+    pub fn from_keyring(description: &str) -> io::Result<Secret> {
+        const KEYCTL_READ: libc::c_int = 11;
+
+        let key_type = b"user\0";
+        let description = std::ffi::CString::new(description)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid key description"))?;
+        let id = unsafe {
+            libc::syscall(
+                libc::SYS_request_key,
+                key_type.as_ptr(),
+                description.as_ptr(),
+                ptr::null::<libc::c_char>(),
+                0,
+            )
+        };
+        if id < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut secret = Secret(vec![0; MAX_SECRET_SIZE]);
+        let size = unsafe {
+            libc::syscall(
+                libc::SYS_keyctl,
+                KEYCTL_READ,
+                id,
+                secret.0.as_mut_ptr(),
+                secret.0.len(),
+            )
+        };
+        if size < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if size as usize > MAX_SECRET_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "secret too long",
+            ));
+        }
+        // Shrinking does not reallocate
+        secret.0.truncate(size as usize);
+        secret.validate()?;
+
+        Ok(secret)
+    }
+
+    /// Returns the secret as a string.
+    pub fn as_str(&self) -> &str {
+        str::from_utf8(&self.0).unwrap_or_default()
+    }
+
+    /// Returns the secret as bytes.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Returns the length of the secret in bytes.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Returns if the secret is empty.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    #[cfg(unix)]
+    fn trim_end(&mut self) {
+        while let Some(b'\n') | Some(b'\r') = self.0.last() {
+            self.0.pop();
+        }
+    }
+
+    #[cfg(unix)]
+    fn validate(&self) -> io::Result<()> {
+        match str::from_utf8(&self.0) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "secret is not valid UTF-8",
+            )),
+        }
+    }
+}
+
+impl Clone for Secret {
+    fn clone(&self) -> Self {
+        let mut bytes = Vec::with_capacity(self.0.len());
+        bytes.extend_from_slice(&self.0);
+
+        Secret(bytes)
+    }
+}
+
+impl Debug for Secret {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "Secret(***)")
+    }
+}
+
+impl Drop for Secret {
+    fn drop(&mut self) {
+        // Zero the whole allocation, including bytes truncated before
+        let capacity = self.0.capacity();
+        self.0.resize(capacity, 0);
+        zeroize(&mut self.0);
+    }
+}
+
+/// Zeroes the buffer in a way which is not optimized away.
+fn zeroize(buffer: &mut [u8]) {
+    for b in buffer.iter_mut() {
+        unsafe { ptr::write_volatile(b, 0) };
+    }
+    atomic::compiler_fence(Ordering::SeqCst);
+}
+
+#[cfg(unix)]
+#[test]
+fn secret_from_fd() {
+    use std::io::Write;
+    use std::os::unix::io::IntoRawFd;
+    use std::os::unix::net::UnixStream;
+
+    let (mut tx, rx) = UnixStream::pair().unwrap();
+    tx.write_all(b"p@ssw0rd\n").unwrap();
+    drop(tx);
+    let secret = Secret::from_fd(rx.into_raw_fd()).unwrap();
+    assert_eq!(secret.as_str(), "p@ssw0rd");
+    assert_eq!(format!("{:?}", secret), "Secret(***)");
+
+    let (mut tx, rx) = UnixStream::pair().unwrap();
+    tx.write_all(&[0xff, 0xfe]).unwrap();
+    drop(tx);
+    assert!(Secret::from_fd(rx.into_raw_fd()).is_err());
+}
@@ -1,31 +1,136 @@
-use async_socks5::{self, AddrKind, Auth};
-use log::trace;
+use log::debug;
+use std::fmt::{self, Display, Formatter};
 use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
-use std::sync::Arc;
-use tokio::io::{self, BufStream};
+use std::sync::{Arc, Mutex};
+use tokio::io::{self, AsyncRead, AsyncWrite, BufStream};
 use tokio::net::udp::{RecvHalf, SendHalf};
 use tokio::net::{TcpStream, UdpSocket};
 
+mod pool;
+use self::pool::ReplyError;
+pub use self::pool::SocksPool;
+
+#[cfg(feature = "http2")]
+use super::http2::{Http2Client, Http2Option};
+use super::secret::Secret;
+#[cfg(feature = "ssh")]
+use super::ssh::{SshClient, SshOption};
+#[cfg(feature = "vmess")]
+use super::vmess::VmessOption;
+#[cfg(feature = "websocket")]
+use super::websocket::WebSocketOption;
+use crate::egress::{Egress, SharedEgress};
+use crate::policy::PortProtocol;
+use crate::qos::Shaper;
+use crate::source::AddrRange;
+
 /// Represents the username and the password of the authentication connecting to a SOCKS5 server.
+/// The password is zeroed when it is dropped and is never shown in debug output.
 #[derive(Clone, Debug)]
 pub struct SocksAuth {
     username: String,
-    password: String,
+    password: Secret,
 }
 
 impl SocksAuth {
     /// Creates a `SocksAuth`.
-    pub fn new(username: String, password: String) -> SocksAuth {
+    pub fn new(username: String, password: Secret) -> SocksAuth {
         SocksAuth { username, password }
     }
+
+    /// Returns the username.
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+}
+
+/// Represents the kind of a failure connecting to a target server through a proxy.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum ConnectFailure {
+    /// Represents the proxy rejected the username and the password.
+    Auth,
+    /// Represents the proxy accepts none of the offered authentication methods.
+    Method,
+    /// Represents the proxy replied it failed to connect to the target server.
+    Reply,
+    /// Represents the proxy is unreachable or the connection to it is broken, e.g. while the
+    /// proxy is restarting.
+    Network,
+    /// Represents other failures, like malformed replies.
+    Other,
+}
+
+impl ConnectFailure {
+    /// Classifies an error of connecting to a target server through a proxy.
+    pub fn from_error(e: &io::Error) -> ConnectFailure {
+        if let Some(e) = e
+            .get_ref()
+            .and_then(|e| e.downcast_ref::<async_socks5::Error>())
+        {
+            return match e {
+                async_socks5::Error::Io(e) => ConnectFailure::from_error(e),
+                async_socks5::Error::InvalidAuthStatus(_) => ConnectFailure::Auth,
+                async_socks5::Error::NoAcceptableMethods
+                | async_socks5::Error::InvalidAuthMethod(_) => ConnectFailure::Method,
+                async_socks5::Error::Response(_) => ConnectFailure::Reply,
+                _ => ConnectFailure::Other,
+            };
+        }
+        if e.get_ref().map_or(false, |e| e.is::<ReplyError>()) {
+            return ConnectFailure::Reply;
+        }
+
+        match e.kind() {
+            io::ErrorKind::PermissionDenied => ConnectFailure::Auth,
+            io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::NotConnected
+            | io::ErrorKind::AddrNotAvailable
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::UnexpectedEof => ConnectFailure::Network,
+            _ => ConnectFailure::Other,
+        }
+    }
+
+    /// Returns if the failure may be gone soon, so connecting again is worthwhile.
+    pub fn is_transient(&self) -> bool {
+        *self == ConnectFailure::Network
+    }
 }
 
-/// Represents the options connecting to a SOCKS5 server.
+impl Display for ConnectFailure {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            ConnectFailure::Auth => write!(f, "auth"),
+            ConnectFailure::Method => write!(f, "method"),
+            ConnectFailure::Reply => write!(f, "reply"),
+            ConnectFailure::Network => write!(f, "network"),
+            ConnectFailure::Other => write!(f, "other"),
+        }
+    }
+}
+
+/// Represents the options connecting to a SOCKS5 server, or to an HTTP/2 proxy, an SSH server or
+/// a VMess server if it is set.
 #[derive(Clone, Debug)]
 pub struct SocksOption {
     force_associate_remote: bool,
     force_associate_bind_addr: bool,
     auth: Option<SocksAuth>,
+    pool: Option<SocksPool>,
+    direct: Vec<AddrRange>,
+    shaper: Option<Arc<Mutex<Shaper>>>,
+    egress: Option<SharedEgress>,
+    #[cfg(feature = "http2")]
+    http2: Option<Http2Client>,
+    #[cfg(feature = "ssh")]
+    ssh: Option<SshClient>,
+    #[cfg(feature = "vmess")]
+    vmess: Option<VmessOption>,
+    #[cfg(feature = "websocket")]
+    websocket: Option<WebSocketOption>,
 }
 
 impl SocksOption {
@@ -39,14 +144,175 @@ impl SocksOption {
             force_associate_remote,
             force_associate_bind_addr: force_associate_bind_addr,
             auth,
+            pool: None,
+            direct: Vec::new(),
+            shaper: None,
+            egress: None,
+            #[cfg(feature = "http2")]
+            http2: None,
+            #[cfg(feature = "ssh")]
+            ssh: None,
+            #[cfg(feature = "vmess")]
+            vmess: None,
+            #[cfg(feature = "websocket")]
+            websocket: None,
+        }
+    }
+
+    /// Sets the count of pre-connected connections kept to each SOCKS5 server, which have
+    /// negotiated the method and authenticated. A count of 0 disables pooling.
+    pub fn set_pool(&mut self, size: usize) {
+        self.pool = match size {
+            0 => None,
+            size => Some(SocksPool::new(size)),
+        };
+    }
+
+    /// Sets the ranges of destinations which are connected directly with ordinary sockets instead
+    /// of through the proxy.
+    pub fn set_direct(&mut self, ranges: &[AddrRange]) {
+        self.direct = ranges.to_vec();
+    }
+
+    /// Returns if the destination is connected directly instead of through the proxy.
+    pub fn is_direct(&self, dst: Ipv4Addr) -> bool {
+        self.direct.iter().any(|range| range.contains(dst))
+    }
+
+    /// Returns if any destination is connected directly.
+    pub(crate) fn has_direct(&self) -> bool {
+        !self.direct.is_empty()
+    }
+
+    /// Returns if every destination is connected directly, so the proxy is never used.
+    pub(crate) fn is_direct_all(&self) -> bool {
+        self.direct.iter().any(|range| {
+            range.start() == Ipv4Addr::UNSPECIFIED && range.end() == Ipv4Addr::BROADCAST
+        })
+    }
+
+    /// Sets the shaper which shapes the traffic from sources by their classes.
+    pub(crate) fn set_shaper(&mut self, shaper: Arc<Mutex<Shaper>>) {
+        self.shaper = Some(shaper);
+    }
+
+    /// Sets the egress, which carries the traffic not sent directly instead of the proxy.
+    pub(crate) fn set_egress(&mut self, egress: Arc<dyn Egress>) {
+        self.egress = Some(SharedEgress::new(egress));
+    }
+
+    /// Returns the egress if it is set.
+    pub(crate) fn egress(&self) -> Option<&dyn Egress> {
+        self.egress.as_ref().map(|egress| egress.get())
+    }
+
+    /// Returns the shaper and the class of a flow of the source to the destination if the flow is
+    /// shaped.
+    pub(crate) fn qos_class(
+        &self,
+        protocol: PortProtocol,
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+    ) -> Option<(Arc<Mutex<Shaper>>, usize)> {
+        let shaper = self.shaper.as_ref()?;
+        let class = shaper
+            .lock()
+            .unwrap()
+            .classify(protocol, *src.ip(), dst.port())?;
+
+        Some((Arc::clone(shaper), class))
+    }
+
+    /// Fills the pool of pre-connected connections to the SOCKS5 server in the background if
+    /// pooling is enabled and the proxy is a SOCKS5 server.
+    pub fn warm_up(&self, remote: SocketAddrV4) {
+        if !self.is_socks() {
+            return;
+        }
+        if let Some(ref pool) = self.pool {
+            pool.fill(remote, self.auth.clone());
         }
     }
 
-    fn auth(&self) -> Option<Auth> {
-        match self.auth {
-            Some(ref auth) => Some(Auth::new(auth.username.clone(), auth.password.clone())),
-            None => None,
+    fn is_socks(&self) -> bool {
+        if self.egress.is_some() {
+            return false;
+        }
+        #[cfg(feature = "http2")]
+        if self.http2.is_some() {
+            return false;
         }
+        #[cfg(feature = "ssh")]
+        if self.ssh.is_some() {
+            return false;
+        }
+        #[cfg(feature = "vmess")]
+        if self.vmess.is_some() {
+            return false;
+        }
+        #[cfg(feature = "websocket")]
+        if self.websocket.is_some() {
+            return false;
+        }
+
+        true
+    }
+
+    /// Sets the options of the HTTP/2 proxy. Once set, the proxy is an HTTP/2 proxy instead of a
+    /// SOCKS5 server, which shares a connection among streams and authenticates with the username
+    /// and the password of SOCKS5.
+    #[cfg(feature = "http2")]
+    pub fn set_http2(&mut self, http2: Http2Option) {
+        let auth = self
+            .auth
+            .as_ref()
+            .map(|auth| (auth.username.as_str(), &auth.password));
+        self.http2 = Some(Http2Client::new(http2, auth));
+    }
+
+    /// Returns the client of the HTTP/2 proxy.
+    #[cfg(feature = "http2")]
+    pub fn http2(&self) -> Option<&Http2Client> {
+        self.http2.as_ref()
+    }
+
+    /// Sets the options of the SSH server. Once set, the proxy is an SSH server instead of a
+    /// SOCKS5 server, which shares a connection among direct-tcpip channels.
+    #[cfg(feature = "ssh")]
+    pub fn set_ssh(&mut self, ssh: SshOption) {
+        self.ssh = Some(SshClient::new(ssh));
+    }
+
+    /// Returns the client of the SSH server.
+    #[cfg(feature = "ssh")]
+    pub fn ssh(&self) -> Option<&SshClient> {
+        self.ssh.as_ref()
+    }
+
+    /// Sets the options of the VMess server. Once set, the proxy is a VMess server instead of a
+    /// SOCKS5 server, and the options of SOCKS5 are ignored.
+    #[cfg(feature = "vmess")]
+    pub fn set_vmess(&mut self, vmess: VmessOption) {
+        self.vmess = Some(vmess);
+    }
+
+    /// Returns the options of the VMess server.
+    #[cfg(feature = "vmess")]
+    pub fn vmess(&self) -> Option<&VmessOption> {
+        self.vmess.as_ref()
+    }
+
+    /// Sets the options of the WebSocket transport. Once set, the SOCKS5 byte stream is carried in
+    /// a WebSocket connection to the SOCKS5 server.
+    #[cfg(feature = "websocket")]
+    pub fn set_websocket(&mut self, websocket: WebSocketOption) {
+        self.websocket = Some(websocket);
+    }
+
+    /// Returns the options of the WebSocket transport.
+    #[cfg(feature = "websocket")]
+    pub fn websocket(&self) -> Option<&WebSocketOption> {
+        self.websocket.as_ref()
     }
 }
 
@@ -56,18 +322,34 @@ pub async fn connect(
     dst: SocketAddrV4,
     options: &SocksOption,
 ) -> io::Result<BufStream<TcpStream>> {
-    let stream = TcpStream::connect(remote).await?;
-    let mut stream = BufStream::new(stream);
-    if let Err(e) = async_socks5::connect(&mut stream, dst, options.auth()).await {
-        match e {
-            async_socks5::Error::Io(e) => return Err(e),
-            _ => return Err(io::Error::new(io::ErrorKind::Other, e)),
+    if let Some(ref pool) = options.pool {
+        if let Some(result) = pool.connect(remote, dst, options.auth.clone()).await {
+            return result;
         }
     }
 
+    let stream = TcpStream::connect(remote).await?;
+    let mut stream = BufStream::new(stream);
+    handshake(&mut stream, dst, options).await?;
+
     Ok(stream)
 }
 
+/// Requests a SOCKS5 proxy over the stream to connect to a target server.
+pub async fn handshake<S>(
+    stream: &mut S,
+    dst: SocketAddrV4,
+    options: &SocksOption,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    pool::authenticate(stream, options.auth.as_ref()).await?;
+    pool::request(stream, pool::CMD_CONNECT, dst).await?;
+
+    Ok(())
+}
+
 const RSV_SIZE: usize = 2;
 const FRAG_SIZE: usize = 1;
 const ATYP_SIZE: usize = 1;
@@ -152,59 +434,89 @@ impl SocksRecvHalf {
     }
 }
 
-/// Bind a local address to a target server through a SOCKS5 proxy.
+/// Returns the address to send datagrams to and the reason if the ASSOCIATE address replied by
+/// the proxy is unroutable from here, e.g. an unspecified or a private address. The address falls
+/// back to the address of the proxy, where the replied port is kept unless it is 0.
+fn correct_associate_addr(
+    remote: SocketAddrV4,
+    proxy_addr: SocketAddr,
+    options: &SocksOption,
+) -> Option<(SocketAddrV4, &'static str)> {
+    let reason = if options.force_associate_remote {
+        "forced"
+    } else {
+        match proxy_addr {
+            SocketAddr::V4(proxy_addr) => {
+                let ip = proxy_addr.ip();
+                if options.force_associate_bind_addr {
+                    return None;
+                } else if ip.is_unspecified() {
+                    "unspecified address"
+                } else if ip.is_loopback() && !remote.ip().is_loopback() {
+                    "loopback address from a non-loopback proxy"
+                } else if ip.is_private() {
+                    "private address"
+                } else if ip.is_link_local() && !remote.ip().is_link_local() {
+                    "link-local address from a non-link-local proxy"
+                } else if ip.is_broadcast() || ip.is_multicast() {
+                    "non-unicast address"
+                } else if proxy_addr.port() == 0 {
+                    "unspecified port"
+                } else {
+                    return None;
+                }
+            }
+            SocketAddr::V6(_) => match options.force_associate_bind_addr {
+                true => panic!("IPv6 is not supported yet"),
+                false => "IPv6 address",
+            },
+        }
+    };
+
+    let port = match proxy_addr.port() {
+        0 => remote.port(),
+        port => port,
+    };
+    let next_proxy_addr = SocketAddrV4::new(*remote.ip(), port);
+    if SocketAddr::V4(next_proxy_addr) == proxy_addr {
+        return None;
+    }
+
+    Some((next_proxy_addr, reason))
+}
+
+/// Bind a local address on the local port to a target server through a SOCKS5 proxy. Any port is
+/// bound instead if the local port is 0 or is in use.
 pub async fn bind(
     remote: SocketAddrV4,
     options: &SocksOption,
+    local_port: u16,
 ) -> io::Result<(SocksRecvHalf, SocksSendHalf, u16)> {
     // Connect
     let stream = TcpStream::connect(remote).await?;
-    let stream = BufStream::new(stream);
+    let mut stream = BufStream::new(stream);
 
-    let local = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0);
-    let socket = UdpSocket::bind(local).await?;
+    let socket = bind_udp(local_port).await?;
     let local_port = socket.local_addr().unwrap().port();
-    let datagram = match async_socks5::SocksDatagram::associate::<SocketAddrV4>(
-        stream,
-        socket,
-        options.auth(),
-        None,
-    )
-    .await
-    {
-        Ok(datagram) => datagram,
-        Err(e) => match e {
-            async_socks5::Error::Io(e) => return Err(e),
-            _ => return Err(io::Error::new(io::ErrorKind::Other, e)),
-        },
-    };
-
-    let proxy_addr = match datagram.proxy_addr().clone() {
-        AddrKind::Ip(proxy_addr) => proxy_addr,
-        _ => unimplemented!(),
+    pool::authenticate(&mut stream, options.auth.as_ref()).await?;
+    let unspecified = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0);
+    let proxy_addr = match pool::request(&mut stream, pool::CMD_UDP_ASSOCIATE, unspecified).await? {
+        Some(proxy_addr) => proxy_addr,
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unexpected domain name of ASSOCIATE address",
+            ))
+        }
     };
-    let (stream, socket) = datagram.into_inner();
 
     // Rewrite ASSOCIATE address
-    let is_rewrite = options.force_associate_remote
-        || match proxy_addr {
-            SocketAddr::V4(proxy_addr) => match options.force_associate_bind_addr {
-                true => false,
-                false => proxy_addr.ip().is_private(),
-            },
-            SocketAddr::V6(_) => match options.force_associate_bind_addr {
-                true => panic!("IPv6 is not supported yet"),
-                false => true,
-            },
-        };
-    if is_rewrite {
-        let next_proxy_addr = SocketAddrV4::new(remote.ip().clone(), proxy_addr.port());
+    if let Some((next_proxy_addr, reason)) = correct_associate_addr(remote, proxy_addr, options) {
         socket.connect(next_proxy_addr).await?;
 
-        trace!(
-            "rewrite ASSOCIATE address {} to {}",
-            proxy_addr,
-            next_proxy_addr
+        debug!(
+            "rewrite ASSOCIATE address {} to {} ({})",
+            proxy_addr, next_proxy_addr, reason
         );
     }
 
@@ -219,3 +531,107 @@ pub async fn bind(
         local_port,
     ))
 }
+
+/// Binds a UDP socket on the local port, or on any port if the local port is 0 or is in use.
+pub(crate) async fn bind_udp(local_port: u16) -> io::Result<UdpSocket> {
+    if local_port != 0 {
+        match UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, local_port)).await {
+            Ok(socket) => return Ok(socket),
+            Err(ref e) => debug!("bind UDP port {}: {}", local_port, e),
+        }
+    }
+
+    UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)).await
+}
+
+#[test]
+fn socks_associate_addr() {
+    let options = SocksOption::new(false, false, None);
+    let remote: SocketAddrV4 = "203.0.113.1:1080".parse().unwrap();
+    let correct = |proxy_addr: &str| {
+        correct_associate_addr(remote, proxy_addr.parse().unwrap(), &options)
+            .map(|(addr, _)| addr.to_string())
+    };
+
+    assert_eq!(correct("198.51.100.1:2000"), None);
+    assert_eq!(
+        correct("0.0.0.0:2000"),
+        Some("203.0.113.1:2000".to_string())
+    );
+    assert_eq!(
+        correct("10.0.0.1:2000"),
+        Some("203.0.113.1:2000".to_string())
+    );
+    assert_eq!(
+        correct("127.0.0.1:2000"),
+        Some("203.0.113.1:2000".to_string())
+    );
+    assert_eq!(
+        correct("203.0.113.1:0"),
+        Some("203.0.113.1:1080".to_string())
+    );
+    assert_eq!(correct("203.0.113.1:2000"), None);
+
+    // A loopback proxy may reply its loopback address
+    let remote = "127.0.0.1:1080".parse().unwrap();
+    assert_eq!(
+        correct_associate_addr(remote, "127.0.0.1:2000".parse().unwrap(), &options),
+        None
+    );
+}
+
+#[test]
+fn socks_connect_failure() {
+    let e = io::Error::from(io::ErrorKind::ConnectionRefused);
+    assert_eq!(ConnectFailure::from_error(&e), ConnectFailure::Network);
+    assert!(ConnectFailure::from_error(&e).is_transient());
+
+    let e = io::Error::new(
+        io::ErrorKind::Other,
+        async_socks5::Error::NoAcceptableMethods,
+    );
+    assert_eq!(ConnectFailure::from_error(&e), ConnectFailure::Method);
+    assert!(!ConnectFailure::from_error(&e).is_transient());
+
+    let e = io::Error::new(
+        io::ErrorKind::Other,
+        async_socks5::Error::InvalidAuthStatus(1),
+    );
+    assert_eq!(ConnectFailure::from_error(&e), ConnectFailure::Auth);
+}
+
+#[tokio::test]
+async fn socks_bind_auth() {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    // A SOCKS5 server replying to the ASSOCIATE request only after the authentication
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let remote = match listener.local_addr().unwrap() {
+        SocketAddr::V4(addr) => addr,
+        _ => unreachable!(),
+    };
+    let handle = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut buffer = [0u8; 32];
+        stream.read_exact(&mut buffer[..3]).unwrap();
+        assert_eq!(&buffer[..3], &[5, 1, 2]);
+        stream.write_all(&[5, 2]).unwrap();
+        stream.read_exact(&mut buffer[..7]).unwrap();
+        assert_eq!(&buffer[..7], &[1, 2, b'u', b's', 2, b'p', b'w']);
+        stream.write_all(&[1, 0]).unwrap();
+        stream.read_exact(&mut buffer[..10]).unwrap();
+        assert_eq!(&buffer[..10], &[5, 3, 0, 1, 0, 0, 0, 0, 0, 0]);
+        stream
+            .write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0x07, 0xd0])
+            .unwrap();
+
+        stream
+    });
+
+    let auth = SocksAuth::new("us".to_string(), Secret::new("pw".to_string()));
+    let options = SocksOption::new(false, false, Some(auth));
+    assert!(bind(remote, &options, 0).await.is_ok());
+    drop(handle.join().unwrap());
+}
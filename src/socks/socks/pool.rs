@@ -0,0 +1,345 @@
+//! Support for pooling pre-connected connections to SOCKS5 proxies.
+
+use log::{debug, trace};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::net::{IpAddr, SocketAddr, SocketAddrV4};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufStream};
+use tokio::net::TcpStream;
+
+use super::{Secret, SocksAuth, ATYP_IPV4};
+
+/// Represents the max idle time of a pre-connected connection, after which the proxy may have
+/// closed it.
+const POOL_IDLE_TIMEOUT: u64 = 30000;
+
+const SOCKS_VERSION: u8 = 5;
+const AUTH_VERSION: u8 = 1;
+const METHOD_NONE: u8 = 0;
+const METHOD_USERNAME: u8 = 2;
+const METHOD_NOT_ACCEPTABLE: u8 = 0xff;
+pub(super) const CMD_CONNECT: u8 = 1;
+pub(super) const CMD_UDP_ASSOCIATE: u8 = 3;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+/// Represents the map mapping a proxy to its pre-connected connections and the time they are
+/// connected.
+type IdleMap = HashMap<SocketAddrV4, VecDeque<(BufStream<TcpStream>, Instant)>>;
+
+/// Represents an unsuccessful reply of a SOCKS5 request.
+#[derive(Debug)]
+pub(crate) struct ReplyError(u8);
+
+impl Display for ReplyError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self.0 {
+            1 => write!(f, "general SOCKS server failure"),
+            2 => write!(f, "connection not allowed by ruleset"),
+            3 => write!(f, "network unreachable"),
+            4 => write!(f, "host unreachable"),
+            5 => write!(f, "connection refused"),
+            6 => write!(f, "TTL expired"),
+            7 => write!(f, "command not supported"),
+            8 => write!(f, "address type not supported"),
+            reply => write!(f, "unassigned reply {}", reply),
+        }
+    }
+}
+
+impl Error for ReplyError {}
+
+/// Represents a pool of connections to SOCKS5 proxies which have negotiated the method and
+/// authenticated, so a new connection only takes the round trip of the CONNECT request. Each
+/// proxy has its own connections, which are refilled in the background once taken.
+#[derive(Clone, Debug)]
+pub struct SocksPool {
+    size: usize,
+    idle: Arc<Mutex<IdleMap>>,
+    filling: Arc<Mutex<HashSet<SocketAddrV4>>>,
+}
+
+impl SocksPool {
+    /// Creates a new `SocksPool` which keeps the given count of connections to each proxy.
+    pub fn new(size: usize) -> SocksPool {
+        SocksPool {
+            size,
+            idle: Arc::new(Mutex::new(HashMap::new())),
+            filling: Arc::new(Mutex::new(HashSet::new())),
+        }
+    }
+
+    /// Returns the count of connections kept to each proxy.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Fills the pool of the proxy in the background if it is not being filled.
+    pub fn fill(&self, remote: SocketAddrV4, auth: Option<SocksAuth>) {
+        if self.size == 0 || !self.filling.lock().unwrap().insert(remote) {
+            return;
+        }
+
+        let pool = self.clone();
+        tokio::spawn(async move {
+            loop {
+                let len = pool
+                    .idle
+                    .lock()
+                    .unwrap()
+                    .get(&remote)
+                    .map_or(0, |queue| queue.len());
+                if len >= pool.size {
+                    break;
+                }
+
+                match preconnect(remote, auth.as_ref()).await {
+                    Ok(stream) => {
+                        trace!("pre-connect to {}", remote);
+                        pool.idle
+                            .lock()
+                            .unwrap()
+                            .entry(remote)
+                            .or_default()
+                            .push_back((stream, Instant::now()));
+                    }
+                    Err(ref e) => {
+                        debug!("pre-connect to {}: {}", remote, e);
+                        break;
+                    }
+                }
+            }
+
+            pool.filling.lock().unwrap().remove(&remote);
+        });
+    }
+
+    /// Takes the freshest pre-connected connection to the proxy, and refills the pool.
+    fn take(&self, remote: SocketAddrV4, auth: Option<SocksAuth>) -> Option<BufStream<TcpStream>> {
+        let stream = {
+            let mut idle = self.idle.lock().unwrap();
+            let queue = idle.entry(remote).or_default();
+            let timeout = Duration::from_millis(POOL_IDLE_TIMEOUT);
+            queue.retain(|(_, instant)| instant.elapsed() < timeout);
+
+            queue.pop_back().map(|(stream, _)| stream)
+        };
+        self.fill(remote, auth);
+
+        stream
+    }
+
+    /// Connects to a target server through a pre-connected connection to the proxy. Returns
+    /// `None` if there is no usable pre-connected connection.
+    pub(super) async fn connect(
+        &self,
+        remote: SocketAddrV4,
+        dst: SocketAddrV4,
+        auth: Option<SocksAuth>,
+    ) -> Option<io::Result<BufStream<TcpStream>>> {
+        let mut stream = self.take(remote, auth)?;
+        match request(&mut stream, CMD_CONNECT, dst).await {
+            Ok(_) => {
+                trace!("connect to {} in a pre-connected connection", dst);
+
+                Some(Ok(stream))
+            }
+            Err(e) => {
+                if e.get_ref().map_or(false, |e| e.is::<ReplyError>()) {
+                    return Some(Err(e));
+                }
+                // The proxy may have closed the connection
+                debug!("pre-connected connection to {}: {}", remote, e);
+
+                None
+            }
+        }
+    }
+}
+
+/// Connects to a SOCKS5 proxy, and negotiates the method and authenticates.
+async fn preconnect(
+    remote: SocketAddrV4,
+    auth: Option<&SocksAuth>,
+) -> io::Result<BufStream<TcpStream>> {
+    let stream = TcpStream::connect(remote).await?;
+    let mut stream = BufStream::new(stream);
+    authenticate(&mut stream, auth).await?;
+
+    Ok(stream)
+}
+
+/// Negotiates the method with the SOCKS5 proxy, and authenticates with the username and the
+/// password if any. The password is only copied into a request which is zeroed once it is sent.
+pub(super) async fn authenticate<S>(stream: &mut S, auth: Option<&SocksAuth>) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // Method
+    let method = match auth {
+        Some(_) => METHOD_USERNAME,
+        None => METHOD_NONE,
+    };
+    stream.write_all(&[SOCKS_VERSION, 1, method]).await?;
+    stream.flush().await?;
+    let mut buffer = [0u8; 2];
+    stream.read_exact(&mut buffer).await?;
+    if buffer[0] != SOCKS_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unexpected SOCKS version {}", buffer[0]),
+        ));
+    }
+    if buffer[1] == METHOD_NOT_ACCEPTABLE {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            async_socks5::Error::NoAcceptableMethods,
+        ));
+    }
+    if buffer[1] != method {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unexpected method {}", buffer[1]),
+        ));
+    }
+
+    // Authentication (RFC 1929)
+    if let Some(auth) = auth {
+        if auth.username.len() > u8::MAX as usize || auth.password.len() > u8::MAX as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "username or password too long",
+            ));
+        }
+        // The request carrying the password is zeroed once it is sent
+        let mut request = Vec::with_capacity(3 + auth.username.len() + auth.password.len());
+        request.extend_from_slice(&[AUTH_VERSION, auth.username.len() as u8]);
+        request.extend_from_slice(auth.username.as_bytes());
+        request.push(auth.password.len() as u8);
+        request.extend_from_slice(auth.password.as_bytes());
+        let request = Secret::from_bytes(request);
+        stream.write_all(request.as_bytes()).await?;
+        stream.flush().await?;
+        stream.read_exact(&mut buffer).await?;
+        if buffer[1] != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                async_socks5::Error::InvalidAuthStatus(buffer[1]),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Requests the SOCKS5 proxy with the command, and returns the bound address in the reply, which
+/// is `None` if it is a domain name.
+pub(super) async fn request<S>(
+    stream: &mut S,
+    cmd: u8,
+    dst: SocketAddrV4,
+) -> io::Result<Option<SocketAddr>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut request = vec![SOCKS_VERSION, cmd, 0, ATYP_IPV4];
+    request.extend_from_slice(&dst.ip().octets());
+    request.extend_from_slice(&dst.port().to_be_bytes());
+    stream.write_all(&request).await?;
+    stream.flush().await?;
+
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != SOCKS_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unexpected SOCKS version {}", reply[0]),
+        ));
+    }
+    if reply[1] != 0 {
+        return Err(io::Error::new(io::ErrorKind::Other, ReplyError(reply[1])));
+    }
+
+    // Bound address
+    let len = match reply[3] {
+        ATYP_IPV4 => 4,
+        ATYP_IPV6 => 16,
+        ATYP_DOMAIN => stream.read_u8().await? as usize,
+        atyp => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unexpected address type {}", atyp),
+            ))
+        }
+    };
+    let mut addr = vec![0u8; len + 2];
+    stream.read_exact(&mut addr).await?;
+    let port = u16::from_be_bytes([addr[len], addr[len + 1]]);
+    let ip: IpAddr = match reply[3] {
+        ATYP_IPV4 => {
+            let mut octets = [0u8; 4];
+            octets.copy_from_slice(&addr[..4]);
+            octets.into()
+        }
+        ATYP_IPV6 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&addr[..16]);
+            octets.into()
+        }
+        _ => return Ok(None),
+    };
+
+    Ok(Some(SocketAddr::new(ip, port)))
+}
+
+#[tokio::test]
+async fn socks_pool_connect() {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    // A SOCKS5 server replying to the CONNECT request only after the authentication
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let remote = match listener.local_addr().unwrap() {
+        std::net::SocketAddr::V4(addr) => addr,
+        _ => unreachable!(),
+    };
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut buffer = [0u8; 32];
+        stream.read_exact(&mut buffer[..3]).unwrap();
+        assert_eq!(&buffer[..3], &[5, 1, 2]);
+        stream.write_all(&[5, 2]).unwrap();
+        stream.read_exact(&mut buffer[..7]).unwrap();
+        assert_eq!(&buffer[..7], &[1, 2, b'u', b's', 2, b'p', b'w']);
+        stream.write_all(&[1, 0]).unwrap();
+        stream.read_exact(&mut buffer[..10]).unwrap();
+        assert_eq!(&buffer[..10], &[5, 1, 0, 1, 1, 1, 1, 1, 0, 80]);
+        stream
+            .write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0x04, 0x38])
+            .unwrap();
+    });
+
+    let pool = SocksPool::new(1);
+    let auth = Some(SocksAuth::new(
+        "us".to_string(),
+        Secret::new("pw".to_string()),
+    ));
+    pool.fill(remote, auth.clone());
+    while pool
+        .idle
+        .lock()
+        .unwrap()
+        .get(&remote)
+        .map_or(0, |queue| queue.len())
+        == 0
+    {
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+    }
+
+    let dst = "1.1.1.1:80".parse().unwrap();
+    assert!(pool.connect(remote, dst, auth).await.unwrap().is_ok());
+}
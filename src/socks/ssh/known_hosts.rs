@@ -0,0 +1,180 @@
+//! Support for verifying host keys of SSH servers against known hosts files in the OpenSSH
+//! format, where host keys of unknown servers are trusted on first use.
+
+use log::info;
+use sha1::Sha1;
+use sha2::Digest;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::net::SocketAddrV4;
+use std::path::Path;
+use tokio::io;
+
+use super::transport::{fingerprint, HOST_KEY_ALGORITHM};
+
+/// Represents the prefix of hashed hosts.
+const HASHED_PREFIX: &str = "|1|";
+
+/// Represents the block size of SHA-1 in HMAC.
+const SHA1_BLOCK_SIZE: usize = 64;
+
+/// Returns the host of the server in known hosts files.
+fn host(remote: SocketAddrV4) -> String {
+    if remote.port() == 22 {
+        remote.ip().to_string()
+    } else {
+        format!("[{}]:{}", remote.ip(), remote.port())
+    }
+}
+
+/// Returns the HMAC-SHA1 of the data with the key.
+fn hmac_sha1(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut block = [0u8; SHA1_BLOCK_SIZE];
+    if key.len() > SHA1_BLOCK_SIZE {
+        let key = Sha1::digest(key);
+        block[..key.len()].copy_from_slice(&key);
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+
+    let mut inner = Sha1::new();
+    inner.update(block.iter().map(|b| b ^ 0x36).collect::<Vec<_>>());
+    inner.update(data);
+    let mut outer = Sha1::new();
+    outer.update(block.iter().map(|b| b ^ 0x5c).collect::<Vec<_>>());
+    outer.update(inner.finalize());
+
+    outer.finalize().to_vec()
+}
+
+/// Returns if the pattern of hosts, which may be hashed, matches the host.
+fn is_match(hosts: &str, host: &str) -> bool {
+    match hosts.strip_prefix(HASHED_PREFIX) {
+        Some(hashed) => {
+            let mut parts = hashed.splitn(2, '|');
+            let salt = parts.next().and_then(|salt| base64::decode(salt).ok());
+            let hash = parts.next().and_then(|hash| base64::decode(hash).ok());
+            match (salt, hash) {
+                (Some(salt), Some(hash)) => hmac_sha1(&salt, host.as_bytes()) == hash,
+                _ => false,
+            }
+        }
+        None => hosts.split(',').any(|pattern| pattern == host),
+    }
+}
+
+/// Verifies the host key of the server against the known hosts file. If the server is not in the
+/// file, the host key is trusted and appended to the file. Returns an error if the file has
+/// another host key of the server, or revokes the host key.
+pub fn verify(path: &Path, remote: SocketAddrV4, host_key: &[u8]) -> io::Result<()> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e),
+    };
+
+    let host = host(remote);
+    let key = base64::encode(host_key);
+    let mut is_known = false;
+    for line in content.lines() {
+        let mut fields = line.split_whitespace().peekable();
+        let marker = match fields.peek() {
+            Some(field) if field.starts_with('#') => continue,
+            Some(field) if field.starts_with('@') => fields.next(),
+            Some(_) => None,
+            None => continue,
+        };
+        let (hosts, algorithm, blob) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(hosts), Some(algorithm), Some(blob)) => (hosts, algorithm, blob),
+            _ => continue,
+        };
+
+        match marker {
+            Some("@revoked") => {
+                if blob == key {
+                    return Err(io::Error::new(
+                        io::ErrorKind::PermissionDenied,
+                        format!("SSH host key is revoked: {}", fingerprint(host_key)),
+                    ));
+                }
+            }
+            Some(_) => {}
+            None => {
+                if algorithm == HOST_KEY_ALGORITHM && is_match(hosts, &host) {
+                    if blob == key {
+                        return Ok(());
+                    }
+                    is_known = true;
+                }
+            }
+        }
+    }
+    if is_known {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("SSH host key mismatch: {}", fingerprint(host_key)),
+        ));
+    }
+
+    // Trust on first use
+    info!(
+        "Trust SSH host key {} of {} on first use",
+        fingerprint(host_key),
+        remote
+    );
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    if !content.is_empty() && !content.ends_with('\n') {
+        writeln!(file)?;
+    }
+    writeln!(file, "{} {} {}", host, HOST_KEY_ALGORITHM, key)?;
+
+    Ok(())
+}
+
+#[test]
+fn ssh_known_hosts() {
+    use std::env;
+
+    let path = env::temp_dir().join(format!("pcap2socks-known-hosts-{}", std::process::id()));
+    let _ = fs::remove_file(&path);
+    let remote = "127.0.0.1:2222".parse().unwrap();
+    let mut host_key = Vec::new();
+    super::transport::put_string(&mut host_key, HOST_KEY_ALGORITHM.as_bytes());
+    super::transport::put_string(&mut host_key, &[1u8; 32]);
+    let mut other_key = Vec::new();
+    super::transport::put_string(&mut other_key, HOST_KEY_ALGORITHM.as_bytes());
+    super::transport::put_string(&mut other_key, &[2u8; 32]);
+
+    // Trusted on first use, and verified later
+    verify(&path, remote, &host_key).unwrap();
+    assert_eq!(
+        fs::read_to_string(&path).unwrap(),
+        format!(
+            "[127.0.0.1]:2222 ssh-ed25519 {}\n",
+            base64::encode(&host_key)
+        )
+    );
+    verify(&path, remote, &host_key).unwrap();
+    let e = verify(&path, remote, &other_key).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+
+    // Hashed hosts, generated by `ssh-keygen -H`, and revoked keys
+    fs::write(
+        &path,
+        format!(
+            "|1|g2lbK68NED2Pk7ODrA9FzQccNgw=|3ckJlPj9Zdlx8z2VMb7Ylc461iI= ssh-ed25519 {}\n",
+            base64::encode(&host_key)
+        ),
+    )
+    .unwrap();
+    verify(&path, remote, &host_key).unwrap();
+    assert!(verify(&path, remote, &other_key).is_err());
+    fs::write(
+        &path,
+        format!("@revoked * ssh-ed25519 {}\n", base64::encode(&other_key)),
+    )
+    .unwrap();
+    assert!(verify(&path, remote, &other_key).is_err());
+
+    let _ = fs::remove_file(&path);
+}
@@ -0,0 +1,708 @@
+//! Support for the transport layer of SSH, which only supports the key exchange of
+//! curve25519-sha256, the host key of ssh-ed25519 and the cipher of aes256-gcm@openssh.com.
+
+use aes_gcm::aead::generic_array::GenericArray;
+use aes_gcm::aead::{AeadInPlace, NewAead};
+use aes_gcm::Aes256Gcm;
+use ed25519_dalek::{PublicKey as HostKey, Signature, Verifier};
+use log::trace;
+use rand::{self, Rng, RngCore};
+use sha2::{Digest, Sha256};
+use std::convert::TryFrom;
+use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+pub const MSG_DISCONNECT: u8 = 1;
+pub const MSG_IGNORE: u8 = 2;
+pub const MSG_UNIMPLEMENTED: u8 = 3;
+pub const MSG_DEBUG: u8 = 4;
+pub const MSG_SERVICE_REQUEST: u8 = 5;
+pub const MSG_SERVICE_ACCEPT: u8 = 6;
+pub const MSG_KEXINIT: u8 = 20;
+pub const MSG_NEWKEYS: u8 = 21;
+pub const MSG_KEX_ECDH_INIT: u8 = 30;
+pub const MSG_KEX_ECDH_REPLY: u8 = 31;
+pub const MSG_USERAUTH_REQUEST: u8 = 50;
+pub const MSG_USERAUTH_FAILURE: u8 = 51;
+pub const MSG_USERAUTH_SUCCESS: u8 = 52;
+pub const MSG_USERAUTH_BANNER: u8 = 53;
+pub const MSG_GLOBAL_REQUEST: u8 = 80;
+pub const MSG_REQUEST_FAILURE: u8 = 82;
+pub const MSG_CHANNEL_OPEN: u8 = 90;
+pub const MSG_CHANNEL_OPEN_CONFIRMATION: u8 = 91;
+pub const MSG_CHANNEL_OPEN_FAILURE: u8 = 92;
+pub const MSG_CHANNEL_WINDOW_ADJUST: u8 = 93;
+pub const MSG_CHANNEL_DATA: u8 = 94;
+pub const MSG_CHANNEL_EOF: u8 = 96;
+pub const MSG_CHANNEL_CLOSE: u8 = 97;
+pub const MSG_CHANNEL_REQUEST: u8 = 98;
+pub const MSG_CHANNEL_FAILURE: u8 = 100;
+
+/// Represents the identification of the client.
+const CLIENT_VERSION: &str = concat!("SSH-2.0-pcap2socks_", env!("CARGO_PKG_VERSION"));
+
+const KEX_ALGORITHMS: &str = "curve25519-sha256,curve25519-sha256@libssh.org";
+pub const HOST_KEY_ALGORITHM: &str = "ssh-ed25519";
+const CIPHER: &str = "aes256-gcm@openssh.com";
+const MAC: &str = "hmac-sha2-256";
+const COMPRESSION: &str = "none";
+
+/// Represents the max length of a line of the identification.
+const MAX_VERSION_LEN: usize = 255;
+/// Represents the max count of lines before the identification.
+const MAX_VERSION_LINES: usize = 64;
+/// Represents the max length of a packet.
+const MAX_PACKET_LEN: usize = 256 * 1024;
+
+/// Represents the length of the authentication tag.
+const TAG_LEN: usize = 16;
+
+/// Appends a `uint32` to the buffer.
+pub fn put_u32(buffer: &mut Vec<u8>, v: u32) {
+    buffer.extend_from_slice(&v.to_be_bytes());
+}
+
+/// Appends a `string` to the buffer.
+pub fn put_string(buffer: &mut Vec<u8>, s: &[u8]) {
+    put_u32(buffer, s.len() as u32);
+    buffer.extend_from_slice(s);
+}
+
+/// Appends an unsigned big-endian integer as an `mpint` to the buffer.
+pub fn put_mpint(buffer: &mut Vec<u8>, v: &[u8]) {
+    let v = match v.iter().position(|b| *b != 0) {
+        Some(i) => &v[i..],
+        None => &[],
+    };
+    if v.first().map_or(false, |b| b & 0x80 != 0) {
+        put_u32(buffer, v.len() as u32 + 1);
+        buffer.push(0);
+        buffer.extend_from_slice(v);
+    } else {
+        put_string(buffer, v);
+    }
+}
+
+fn invalid() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "invalid SSH message")
+}
+
+/// Represents a decoder of an SSH message.
+pub struct Decoder<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    /// Creates a new `Decoder`.
+    pub fn new(data: &'a [u8]) -> Decoder<'a> {
+        Decoder { data }
+    }
+
+    /// Returns the next `byte`.
+    pub fn u8(&mut self) -> io::Result<u8> {
+        let (b, data) = self.data.split_first().ok_or_else(invalid)?;
+        self.data = data;
+
+        Ok(*b)
+    }
+
+    /// Returns the next `boolean`.
+    pub fn bool(&mut self) -> io::Result<bool> {
+        Ok(self.u8()? != 0)
+    }
+
+    /// Returns the next `uint32`.
+    pub fn u32(&mut self) -> io::Result<u32> {
+        let v = self.bytes(4)?;
+
+        Ok(u32::from_be_bytes([v[0], v[1], v[2], v[3]]))
+    }
+
+    /// Returns the next `string`.
+    pub fn string(&mut self) -> io::Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+
+        self.bytes(len)
+    }
+
+    /// Returns the next bytes of the length.
+    pub fn bytes(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.data.len() < len {
+            return Err(invalid());
+        }
+        let (v, data) = self.data.split_at(len);
+        self.data = data;
+
+        Ok(v)
+    }
+}
+
+/// Represents the cipher of a direction.
+pub struct Cipher {
+    cipher: Aes256Gcm,
+    fixed: [u8; 4],
+    counter: u64,
+}
+
+impl Cipher {
+    fn new(key: &[u8], iv: &[u8]) -> Cipher {
+        let mut fixed = [0u8; 4];
+        fixed.copy_from_slice(&iv[..4]);
+        let mut counter = [0u8; 8];
+        counter.copy_from_slice(&iv[4..12]);
+
+        Cipher {
+            cipher: Aes256Gcm::new(GenericArray::from_slice(&key[..32])),
+            fixed,
+            counter: u64::from_be_bytes(counter),
+        }
+    }
+
+    fn nonce(&mut self) -> [u8; 12] {
+        let mut nonce = [0u8; 12];
+        nonce[..4].copy_from_slice(&self.fixed);
+        nonce[4..].copy_from_slice(&self.counter.to_be_bytes());
+        self.counter = self.counter.wrapping_add(1);
+
+        nonce
+    }
+}
+
+/// Encodes the payload into a packet, and encrypts it if the cipher is set.
+fn seal(payload: &[u8], cipher: Option<&mut Cipher>) -> Vec<u8> {
+    // The length is not encrypted in AES-GCM, and is excluded from the alignment
+    let (block, offset) = match cipher {
+        Some(_) => (16, 0),
+        None => (8, 4),
+    };
+    let mut padding = block - (offset + 1 + payload.len()) % block;
+    if padding < 4 {
+        padding += block;
+    }
+
+    let mut packet = Vec::with_capacity(4 + 1 + payload.len() + padding + TAG_LEN);
+    put_u32(&mut packet, (1 + payload.len() + padding) as u32);
+    packet.push(padding as u8);
+    packet.extend_from_slice(payload);
+    let mut random = vec![0u8; padding];
+    rand::thread_rng().fill_bytes(&mut random);
+    packet.extend_from_slice(&random);
+
+    if let Some(cipher) = cipher {
+        let nonce = cipher.nonce();
+        let (len, body) = packet.split_at_mut(4);
+        let tag = cipher
+            .cipher
+            .encrypt_in_place_detached(GenericArray::from_slice(&nonce), len, body)
+            .unwrap();
+        packet.extend_from_slice(&tag);
+    }
+
+    packet
+}
+
+/// Decrypts the body of a packet and returns the payload.
+fn open(len: &[u8], body: &mut [u8], cipher: Option<&mut Cipher>) -> io::Result<Vec<u8>> {
+    let body = match cipher {
+        Some(cipher) => {
+            let nonce = cipher.nonce();
+            let (body, tag) = body.split_at_mut(body.len() - TAG_LEN);
+            cipher
+                .cipher
+                .decrypt_in_place_detached(
+                    GenericArray::from_slice(&nonce),
+                    len,
+                    body,
+                    GenericArray::from_slice(tag),
+                )
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid SSH packet"))?;
+
+            body
+        }
+        None => body,
+    };
+
+    let padding = *body.first().ok_or_else(invalid)? as usize;
+    if padding + 1 >= body.len() {
+        return Err(invalid());
+    }
+
+    Ok(body[1..body.len() - padding].to_vec())
+}
+
+/// Represents the read half of an SSH connection.
+pub struct Reader {
+    read_half: OwnedReadHalf,
+    cipher: Option<Cipher>,
+}
+
+impl Reader {
+    /// Reads a packet and returns the payload. Messages of ignore and debug are skipped.
+    pub async fn read(&mut self) -> io::Result<Vec<u8>> {
+        loop {
+            let mut len = [0u8; 4];
+            self.read_half.read_exact(&mut len).await?;
+            let size = u32::from_be_bytes(len) as usize;
+            if size > MAX_PACKET_LEN {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "SSH packet too large",
+                ));
+            }
+
+            let tag_len = match self.cipher {
+                Some(_) => TAG_LEN,
+                None => 0,
+            };
+            let mut body = vec![0u8; size + tag_len];
+            self.read_half.read_exact(&mut body).await?;
+
+            let payload = open(&len, &mut body, self.cipher.as_mut())?;
+            match payload[0] {
+                MSG_IGNORE | MSG_DEBUG | MSG_UNIMPLEMENTED => {
+                    trace!("skip SSH message {}", payload[0]);
+                }
+                _ => return Ok(payload),
+            }
+        }
+    }
+
+    /// Sets the cipher of packets after the new keys.
+    pub fn set_cipher(&mut self, cipher: Cipher) {
+        self.cipher = Some(cipher);
+    }
+}
+
+/// Represents the write half of an SSH connection.
+pub struct Writer {
+    write_half: OwnedWriteHalf,
+    cipher: Option<Cipher>,
+}
+
+impl Writer {
+    /// Writes a packet of the payload.
+    pub async fn write(&mut self, payload: &[u8]) -> io::Result<()> {
+        let packet = seal(payload, self.cipher.as_mut());
+
+        self.write_half.write_all(&packet).await
+    }
+
+    /// Sets the cipher of packets after the new keys.
+    pub fn set_cipher(&mut self, cipher: Cipher) {
+        self.cipher = Some(cipher);
+    }
+}
+
+/// Exchanges identifications with the server, and returns the read half, the write half and the
+/// identifications of the client and of the server.
+pub async fn identify(mut stream: TcpStream) -> io::Result<(Reader, Writer, Vec<u8>, Vec<u8>)> {
+    stream
+        .write_all(format!("{}\r\n", CLIENT_VERSION).as_bytes())
+        .await?;
+
+    // Lines other than the identification may be sent before it
+    let mut server_version = Vec::new();
+    for _ in 0..MAX_VERSION_LINES {
+        let mut line = Vec::new();
+        loop {
+            let b = stream.read_u8().await?;
+            if b == b'\n' {
+                break;
+            }
+            if line.len() >= MAX_VERSION_LEN {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "SSH identification too long",
+                ));
+            }
+            line.push(b);
+        }
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+        if line.starts_with(b"SSH-") {
+            server_version = line;
+            break;
+        }
+    }
+    if !server_version.starts_with(b"SSH-2.0-") && !server_version.starts_with(b"SSH-1.99-") {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "unsupported SSH version",
+        ));
+    }
+
+    let (read_half, write_half) = stream.into_split();
+
+    Ok((
+        Reader {
+            read_half,
+            cipher: None,
+        },
+        Writer {
+            write_half,
+            cipher: None,
+        },
+        CLIENT_VERSION.as_bytes().to_vec(),
+        server_version,
+    ))
+}
+
+/// Returns the fingerprint of the host key in SHA256.
+pub fn fingerprint(host_key: &[u8]) -> String {
+    format!(
+        "SHA256:{}",
+        base64::encode_config(Sha256::digest(host_key), base64::STANDARD_NO_PAD)
+    )
+}
+
+/// Represents the keys derived from a key exchange.
+pub struct Keys {
+    pub session_id: [u8; 32],
+    /// Represents the host key of the server, which is verified by the caller.
+    pub host_key: Vec<u8>,
+    pub sealing: Cipher,
+    pub opening: Cipher,
+}
+
+/// Represents a key exchange of curve25519-sha256.
+pub struct Kex {
+    client_kexinit: Vec<u8>,
+    ephemeral: StaticSecret,
+}
+
+impl Kex {
+    /// Creates a new `Kex`.
+    pub fn new() -> Kex {
+        let mut rng = rand::thread_rng();
+
+        Kex::with_secret(rng.gen(), rng.gen())
+    }
+
+    /// Creates a new `Kex` with the cookie and the ephemeral private key.
+    fn with_secret(cookie: [u8; 16], ephemeral: [u8; 32]) -> Kex {
+        let mut kexinit = vec![MSG_KEXINIT];
+        kexinit.extend_from_slice(&cookie);
+        for name_list in [
+            KEX_ALGORITHMS,
+            HOST_KEY_ALGORITHM,
+            CIPHER,
+            CIPHER,
+            MAC,
+            MAC,
+            COMPRESSION,
+            COMPRESSION,
+            "",
+            "",
+        ]
+        .iter()
+        {
+            put_string(&mut kexinit, name_list.as_bytes());
+        }
+        // First key exchange packet follows and reserved
+        kexinit.push(0);
+        put_u32(&mut kexinit, 0);
+
+        Kex {
+            client_kexinit: kexinit,
+            ephemeral: StaticSecret::from(ephemeral),
+        }
+    }
+
+    /// Returns the key exchange init message of the client.
+    pub fn kexinit(&self) -> &[u8] {
+        &self.client_kexinit
+    }
+
+    /// Checks the algorithms of the server, and returns the ECDH init message.
+    pub fn ecdh_init(&self, server_kexinit: &[u8]) -> io::Result<Vec<u8>> {
+        let mut decoder = Decoder::new(server_kexinit);
+        decoder.u8()?;
+        decoder.bytes(16)?;
+        let mut name_lists = Vec::new();
+        for _ in 0..10 {
+            name_lists.push(decoder.string()?);
+        }
+
+        // The MAC is implicit in AES-GCM
+        let expects = [
+            (0, KEX_ALGORITHMS),
+            (1, HOST_KEY_ALGORITHM),
+            (2, CIPHER),
+            (3, CIPHER),
+            (6, COMPRESSION),
+            (7, COMPRESSION),
+        ];
+        for (i, expect) in expects.iter() {
+            let is_supported = name_lists[*i]
+                .split(|b| *b == b',')
+                .any(|name| expect.split(',').any(|expect| expect.as_bytes() == name));
+            if !is_supported {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("SSH server does not support {}", expect),
+                ));
+            }
+        }
+
+        let mut init = vec![MSG_KEX_ECDH_INIT];
+        put_string(&mut init, PublicKey::from(&self.ephemeral).as_bytes());
+
+        Ok(init)
+    }
+
+    /// Verifies the signature in the ECDH reply of the server and derives the keys. The host key
+    /// is not verified, which is left to the caller.
+    pub fn finish(
+        self,
+        client_version: &[u8],
+        server_version: &[u8],
+        server_kexinit: &[u8],
+        reply: &[u8],
+        session_id: Option<&[u8; 32]>,
+    ) -> io::Result<Keys> {
+        let mut decoder = Decoder::new(reply);
+        decoder.u8()?;
+        let host_key = decoder.string()?;
+        let server_ephemeral = decoder.string()?;
+        let signature = decoder.string()?;
+        if server_ephemeral.len() != 32 {
+            return Err(invalid());
+        }
+
+        // Shared secret
+        let mut server_public_key = [0u8; 32];
+        server_public_key.copy_from_slice(server_ephemeral);
+        let shared_secret = self
+            .ephemeral
+            .diffie_hellman(&PublicKey::from(server_public_key));
+        if shared_secret.as_bytes().iter().all(|b| *b == 0) {
+            return Err(invalid());
+        }
+        let mut k = Vec::new();
+        put_mpint(&mut k, shared_secret.as_bytes());
+
+        // Exchange hash
+        let mut hasher = Sha256::new();
+        for s in [
+            client_version,
+            server_version,
+            &self.client_kexinit,
+            server_kexinit,
+            host_key,
+            PublicKey::from(&self.ephemeral).as_bytes(),
+            server_ephemeral,
+        ]
+        .iter()
+        {
+            let mut buffer = Vec::new();
+            put_string(&mut buffer, s);
+            hasher.update(&buffer);
+        }
+        hasher.update(&k);
+        let mut h = [0u8; 32];
+        h.copy_from_slice(&hasher.finalize());
+
+        verify(host_key, signature, &h)?;
+
+        // The session ID is the exchange hash of the first key exchange
+        let session_id = *session_id.unwrap_or(&h);
+        let derive = |c: u8| {
+            let mut hasher = Sha256::new();
+            hasher.update(&k);
+            hasher.update(h);
+            hasher.update([c]);
+            hasher.update(session_id);
+
+            hasher.finalize()
+        };
+
+        Ok(Keys {
+            session_id,
+            host_key: host_key.to_vec(),
+            sealing: Cipher::new(&derive(b'C'), &derive(b'A')),
+            opening: Cipher::new(&derive(b'D'), &derive(b'B')),
+        })
+    }
+}
+
+/// Verifies the signature of the exchange hash against the host key.
+fn verify(host_key: &[u8], signature: &[u8], h: &[u8]) -> io::Result<()> {
+    let mut decoder = Decoder::new(host_key);
+    if decoder.string()? != HOST_KEY_ALGORITHM.as_bytes() {
+        return Err(invalid());
+    }
+    let host_key = HostKey::from_bytes(decoder.string()?).map_err(|_| invalid())?;
+
+    let mut decoder = Decoder::new(signature);
+    if decoder.string()? != HOST_KEY_ALGORITHM.as_bytes() {
+        return Err(invalid());
+    }
+    let signature = Signature::try_from(decoder.string()?).map_err(|_| invalid())?;
+
+    host_key.verify(h, &signature).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "invalid SSH host key signature",
+        )
+    })
+}
+
+#[test]
+fn ssh_packet() {
+    let mut buffer = Vec::new();
+    put_mpint(&mut buffer, &[0x00, 0x80, 0x01]);
+    assert_eq!(buffer, [0, 0, 0, 3, 0x00, 0x80, 0x01]);
+    buffer.clear();
+    put_mpint(&mut buffer, &[0x00, 0x00]);
+    assert_eq!(buffer, [0, 0, 0, 0]);
+
+    let key = [1u8; 32];
+    let iv = [2u8; 12];
+    let mut sealing = Cipher::new(&key, &iv);
+    let mut opening = Cipher::new(&key, &iv);
+    for payload in [&b"\x5ehello"[..], &[MSG_NEWKEYS]].iter() {
+        let mut packet = seal(payload, Some(&mut sealing));
+        assert_eq!((packet.len() - 4 - TAG_LEN) % 16, 0);
+        let (len, body) = packet.split_at_mut(4);
+        assert_eq!(
+            open(len, body, Some(&mut opening)).unwrap().as_slice(),
+            *payload
+        );
+    }
+
+    let mut packet = seal(b"\x5ehello", None);
+    assert_eq!(packet.len() % 8, 0);
+    let (len, body) = packet.split_at_mut(4);
+    assert_eq!(open(len, body, None).unwrap(), b"\x5ehello");
+
+    // An unencrypted packet of new keys with 10 Bytes of padding
+    let mut packet = [0u8; 16];
+    packet[..6].copy_from_slice(&[0, 0, 0, 12, 10, MSG_NEWKEYS]);
+    let (len, body) = packet.split_at_mut(4);
+    assert_eq!(open(len, body, None).unwrap(), [MSG_NEWKEYS]);
+}
+
+#[test]
+fn ssh_kex() {
+    // The vectors are computed independently by RFC 4253, RFC 5656 and RFC 8731, with the X25519
+    // keys in RFC 7748 and the Ed25519 host key in RFC 8032
+    let hex = |s: &str| {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect::<Vec<_>>()
+    };
+    let client_kexinit = hex(concat!(
+        "14000102030405060708090a0b0c0d0e0f0000002e637572766532353531392d",
+        "7368613235362c637572766532353531392d736861323536406c69627373682e",
+        "6f72670000000b7373682d65643235353139000000166165733235362d67636d",
+        "406f70656e7373682e636f6d000000166165733235362d67636d406f70656e73",
+        "73682e636f6d0000000d686d61632d736861322d3235360000000d686d61632d",
+        "736861322d323536000000046e6f6e65000000046e6f6e650000000000000000",
+        "0000000000",
+    ));
+    let server_kexinit = hex(concat!(
+        "14ffffffffffffffffffffffffffffffff00000011637572766532353531392d",
+        "736861323536000000187273612d736861322d3531322c7373682d6564323535",
+        "31390000003463686163686132302d706f6c7931333035406f70656e7373682e",
+        "636f6d2c6165733235362d67636d406f70656e7373682e636f6d000000166165",
+        "733235362d67636d406f70656e7373682e636f6d00000017756d61632d36342d",
+        "65746d406f70656e7373682e636f6d00000017756d61632d36342d65746d406f",
+        "70656e7373682e636f6d000000156e6f6e652c7a6c6962406f70656e7373682e",
+        "636f6d000000156e6f6e652c7a6c6962406f70656e7373682e636f6d00000000",
+        "000000000000000000",
+    ));
+    let reply = hex(concat!(
+        "1f000000330000000b7373682d6564323535313900000020d75a980182b10ab7",
+        "d54bfed3c964073a0ee172f3daa62325af021a68f707511a00000020de9edb7d",
+        "7b7dc1b4d35b61c2ece435373f8343c85b78674dadfc7e146f882b4f00000053",
+        "0000000b7373682d65643235353139000000406d302e6db043d4132adbaebbac",
+        "c07a1673c528a459a85b11121c9d3c73b5ce4e060915a4bdb94aa64b1459d382",
+        "e400374be4a69c270c939e83ed0f5e984c1704",
+    ));
+    let h = hex("f1d2994cd2753b86bffd154b98858e77f6b64fa9d7cdb729c91f144a1d764385");
+    let client_key = hex("3fee5a0ca9210e72cefd3767b310d9301e5f3185f72996abc7585f7c1c2e3525");
+    let client_iv = hex("c4ec9b511df1d35c006c9ffb5a98d033abbf4f4d889635959332b1bb6f52544d");
+    let q_c = hex("8520f0098930a754748b7ddcb43ef75a0dbf3a0d26381af4eba4a98eaa9b4e6a");
+
+    let mut cookie = [0u8; 16];
+    for (i, b) in cookie.iter_mut().enumerate() {
+        *b = i as u8;
+    }
+    let mut ephemeral = [0u8; 32];
+    ephemeral.copy_from_slice(&hex(
+        "77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a",
+    ));
+    let kex = Kex::with_secret(cookie, ephemeral);
+    assert_eq!(kex.kexinit(), client_kexinit.as_slice());
+    let mut init = vec![MSG_KEX_ECDH_INIT];
+    put_string(&mut init, &q_c);
+    assert_eq!(kex.ecdh_init(&server_kexinit).unwrap(), init);
+    let keys = kex
+        .finish(
+            b"SSH-2.0-pcap2socks_test",
+            b"SSH-2.0-OpenSSH_8.4",
+            &server_kexinit,
+            &reply,
+            None,
+        )
+        .unwrap();
+    assert_eq!(&keys.session_id[..], h.as_slice());
+    assert_eq!(keys.host_key.as_slice(), &reply[5..56]);
+
+    // Packets from the server, with fixed padding
+    let mut opening = keys.opening;
+    let mut data = vec![MSG_CHANNEL_DATA];
+    put_u32(&mut data, 0);
+    put_string(&mut data, b"hello");
+    let packets = [
+        (
+            hex(concat!(
+                "000000208426f6df227d9e64714fb2f0dab5a030abe9f4ac8bb64c1eb5fae01f",
+                "1fd64e6f0ad1ac7ff68c58a0b8a45bf20b5a1fe0",
+            )),
+            data,
+        ),
+        (
+            hex("000000105fd7d044766eee37e0b109c41e019cb7b9a66f1dc290e45d6cea6df777d1c8b2"),
+            vec![MSG_NEWKEYS],
+        ),
+    ];
+    for (packet, payload) in packets.iter() {
+        let mut packet = packet.clone();
+        let (len, body) = packet.split_at_mut(4);
+        assert_eq!(&open(len, body, Some(&mut opening)).unwrap(), payload);
+    }
+
+    // Packets to the server, opened with the keys derived independently
+    let mut sealing = keys.sealing;
+    let mut opening = Cipher::new(&client_key, &client_iv);
+    for payload in [&b"\x5ehello"[..], &[MSG_NEWKEYS]].iter() {
+        let mut packet = seal(payload, Some(&mut sealing));
+        let (len, body) = packet.split_at_mut(4);
+        assert_eq!(
+            open(len, body, Some(&mut opening)).unwrap().as_slice(),
+            *payload
+        );
+    }
+
+    // A reply whose signature is tampered
+    let kex = Kex::with_secret(cookie, ephemeral);
+    let mut reply = reply;
+    let i = reply.len() - 64;
+    reply[i] ^= 1;
+    let e = kex
+        .finish(
+            b"SSH-2.0-pcap2socks_test",
+            b"SSH-2.0-OpenSSH_8.4",
+            &server_kexinit,
+            &reply,
+            None,
+        )
+        .err()
+        .unwrap();
+    assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+}
@@ -0,0 +1,30 @@
+//! Support for connecting to proxies in TLS.
+
+use std::sync::Arc;
+use tokio::io;
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::ClientConfig;
+use tokio_rustls::webpki::DNSNameRef;
+use tokio_rustls::TlsConnector;
+
+/// Connects in TLS over the stream. The certificate is verified against the domain, and the
+/// protocols are negotiated in ALPN.
+pub async fn connect(
+    stream: TcpStream,
+    domain: &str,
+    protocols: &[&[u8]],
+) -> io::Result<TlsStream<TcpStream>> {
+    let mut config = ClientConfig::new();
+    config
+        .root_store
+        .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+    let protocols: Vec<_> = protocols.iter().map(|protocol| protocol.to_vec()).collect();
+    config.set_protocols(&protocols);
+    let domain = DNSNameRef::try_from_ascii_str(domain)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid domain"))?;
+
+    TlsConnector::from(Arc::new(config))
+        .connect(domain, stream)
+        .await
+}
@@ -0,0 +1,693 @@
+//! Support for relaying traffic through a VMess server with AEAD headers.
+
+use aes_gcm::aead::generic_array::GenericArray;
+use aes_gcm::aead::{Aead, NewAead, Payload};
+use aes_gcm::aes::{Aes128, BlockCipher, NewBlockCipher};
+use aes_gcm::Aes128Gcm;
+use chacha20poly1305::ChaCha20Poly1305;
+use log::{trace, warn};
+use md5::{Digest, Md5};
+use rand::{self, Rng};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{self, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+/// Represents the security of the data of a VMess connection.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum VmessSecurity {
+    /// Represents the data is encrypted with AES-128-GCM.
+    Aes128Gcm,
+    /// Represents the data is encrypted with ChaCha20-Poly1305.
+    Chacha20Poly1305,
+    /// Represents the data is not encrypted. The header is encrypted anyway.
+    None,
+}
+
+impl VmessSecurity {
+    fn to_u8(&self) -> u8 {
+        match self {
+            VmessSecurity::Aes128Gcm => 3,
+            VmessSecurity::Chacha20Poly1305 => 4,
+            VmessSecurity::None => 5,
+        }
+    }
+}
+
+impl Display for VmessSecurity {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            VmessSecurity::Aes128Gcm => write!(f, "aes-128-gcm"),
+            VmessSecurity::Chacha20Poly1305 => write!(f, "chacha20-poly1305"),
+            VmessSecurity::None => write!(f, "none"),
+        }
+    }
+}
+
+impl FromStr for VmessSecurity {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" | "aes-128-gcm" => Ok(VmessSecurity::Aes128Gcm),
+            "chacha20-poly1305" => Ok(VmessSecurity::Chacha20Poly1305),
+            "none" => Ok(VmessSecurity::None),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "unknown VMess security",
+            )),
+        }
+    }
+}
+
+/// Represents the options connecting to a VMess server.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct VmessOption {
+    id: [u8; 16],
+    security: VmessSecurity,
+}
+
+impl VmessOption {
+    /// Creates a `VmessOption` from the UUID of the user. Only the user with an alter ID of 0 is
+    /// supported, which is the default of V2Ray.
+    pub fn new(id: &str, security: VmessSecurity) -> io::Result<VmessOption> {
+        let id = match Uuid::parse_str(id) {
+            Ok(id) => id,
+            Err(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "invalid VMess ID",
+                ))
+            }
+        };
+
+        Ok(VmessOption {
+            id: *id.as_bytes(),
+            security,
+        })
+    }
+
+    /// Returns the security of the data.
+    pub fn security(&self) -> VmessSecurity {
+        self.security
+    }
+
+    fn cmd_key(&self) -> [u8; 16] {
+        let mut hasher = Md5::new();
+        hasher.update(&self.id);
+        hasher.update(CMD_KEY_SALT);
+
+        let mut key = [0u8; 16];
+        key.copy_from_slice(&hasher.finalize());
+
+        key
+    }
+}
+
+const CMD_KEY_SALT: &[u8] = b"c48619fe-8f02-49e0-b9e9-edf763e17e21";
+
+const KDF_SALT: &[u8] = b"VMess AEAD KDF";
+const KDF_SALT_AUTH_ID_KEY: &[u8] = b"AES Auth ID Encryption";
+const KDF_SALT_HEADER_LEN_KEY: &[u8] = b"VMess Header AEAD Key_Length";
+const KDF_SALT_HEADER_LEN_IV: &[u8] = b"VMess Header AEAD Nonce_Length";
+const KDF_SALT_HEADER_KEY: &[u8] = b"VMess Header AEAD Key";
+const KDF_SALT_HEADER_IV: &[u8] = b"VMess Header AEAD Nonce";
+const KDF_SALT_RESP_HEADER_LEN_KEY: &[u8] = b"AEAD Resp Header Len Key";
+const KDF_SALT_RESP_HEADER_LEN_IV: &[u8] = b"AEAD Resp Header Len IV";
+const KDF_SALT_RESP_HEADER_KEY: &[u8] = b"AEAD Resp Header Key";
+const KDF_SALT_RESP_HEADER_IV: &[u8] = b"AEAD Resp Header IV";
+
+const VERSION: u8 = 1;
+const OPTION_CHUNK_STREAM: u8 = 0x01;
+const COMMAND_TCP: u8 = 0x01;
+const COMMAND_UDP: u8 = 0x02;
+const ATYP_IPV4: u8 = 0x01;
+
+const HMAC_BLOCK_SIZE: usize = 64;
+const TAG_SIZE: usize = 16;
+const LENGTH_SIZE: usize = 2;
+
+/// Represents the max size of the payload of a chunk of a stream. V2Ray uses a buffer of 8192
+/// bytes for each chunk.
+const MAX_CHUNK_PAYLOAD_SIZE: usize = 8192 - LENGTH_SIZE - TAG_SIZE;
+
+/// Represents the count of datagrams received from the VMess server queued to be read.
+const DATAGRAM_QUEUE_SIZE: usize = 256;
+
+/// Derives a key with the KDF of VMess, which is a chain of HMACs each keyed with the next element
+/// of the path and hashed with the previous HMAC, starting from an HMAC-SHA256.
+fn kdf(key: &[u8], path: &[&[u8]]) -> [u8; 32] {
+    let mut keys = vec![KDF_SALT];
+    keys.extend_from_slice(path);
+
+    hmac(&keys, key)
+}
+
+fn kdf16(key: &[u8], path: &[&[u8]]) -> [u8; 16] {
+    let mut result = [0u8; 16];
+    result.copy_from_slice(&kdf(key, path)[..16]);
+
+    result
+}
+
+fn hmac(keys: &[&[u8]], message: &[u8]) -> [u8; 32] {
+    let (key, keys) = match keys.split_last() {
+        Some(pair) => pair,
+        None => {
+            let mut result = [0u8; 32];
+            result.copy_from_slice(&Sha256::digest(message));
+
+            return result;
+        }
+    };
+
+    let mut pad = [0u8; HMAC_BLOCK_SIZE];
+    pad[..key.len()].copy_from_slice(key);
+
+    let mut inner: Vec<_> = pad.iter().map(|b| b ^ 0x36).collect();
+    inner.extend_from_slice(message);
+    let inner = hmac(keys, &inner);
+    let mut outer: Vec<_> = pad.iter().map(|b| b ^ 0x5c).collect();
+    outer.extend_from_slice(&inner);
+
+    hmac(keys, &outer)
+}
+
+fn fnv1a(data: &[u8]) -> u32 {
+    let mut hash = 0x811c9dc5u32;
+    for b in data {
+        hash ^= *b as u32;
+        hash = hash.wrapping_mul(0x01000193);
+    }
+
+    hash
+}
+
+fn invalid_data(error: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+/// Returns the authentication ID of a request, which is the timestamp with a checksum encrypted
+/// by the key of the user.
+fn auth_id(cmd_key: &[u8; 16]) -> [u8; 16] {
+    let timestamp = match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(duration) => duration.as_secs(),
+        Err(_) => 0,
+    };
+
+    let mut id = [0u8; 16];
+    id[..8].copy_from_slice(&timestamp.to_be_bytes());
+    rand::thread_rng().fill(&mut id[8..12]);
+    let checksum = crc32fast::hash(&id[..12]);
+    id[12..].copy_from_slice(&checksum.to_be_bytes());
+
+    let cipher = Aes128::new(GenericArray::from_slice(&kdf16(
+        cmd_key,
+        &[KDF_SALT_AUTH_ID_KEY],
+    )));
+    let block = GenericArray::from_mut_slice(&mut id);
+    cipher.encrypt_block(block);
+
+    id
+}
+
+/// Seals the request header with AEAD.
+fn seal_header(cmd_key: &[u8; 16], header: &[u8]) -> Vec<u8> {
+    let auth_id = auth_id(cmd_key);
+    let nonce: [u8; 8] = rand::thread_rng().gen();
+
+    let len_key = kdf16(cmd_key, &[KDF_SALT_HEADER_LEN_KEY, &auth_id, &nonce]);
+    let len_iv = kdf(cmd_key, &[KDF_SALT_HEADER_LEN_IV, &auth_id, &nonce]);
+    let len = Aes128Gcm::new(GenericArray::from_slice(&len_key))
+        .encrypt(
+            GenericArray::from_slice(&len_iv[..12]),
+            Payload {
+                msg: &(header.len() as u16).to_be_bytes(),
+                aad: &auth_id,
+            },
+        )
+        .unwrap();
+
+    let key = kdf16(cmd_key, &[KDF_SALT_HEADER_KEY, &auth_id, &nonce]);
+    let iv = kdf(cmd_key, &[KDF_SALT_HEADER_IV, &auth_id, &nonce]);
+    let header = Aes128Gcm::new(GenericArray::from_slice(&key))
+        .encrypt(
+            GenericArray::from_slice(&iv[..12]),
+            Payload {
+                msg: header,
+                aad: &auth_id,
+            },
+        )
+        .unwrap();
+
+    let mut buffer = Vec::with_capacity(auth_id.len() + len.len() + nonce.len() + header.len());
+    buffer.extend_from_slice(&auth_id);
+    buffer.extend_from_slice(&len);
+    buffer.extend_from_slice(&nonce);
+    buffer.extend_from_slice(&header);
+
+    buffer
+}
+
+/// Opens a part of the response header with AEAD.
+fn open_header(key: &[u8; 16], iv: &[u8; 32], data: &[u8]) -> io::Result<Vec<u8>> {
+    Aes128Gcm::new(GenericArray::from_slice(key))
+        .decrypt(GenericArray::from_slice(&iv[..12]), data)
+        .map_err(|_| invalid_data("invalid VMess response header"))
+}
+
+enum ChunkCipher {
+    Aes128Gcm(Box<Aes128Gcm>),
+    Chacha20Poly1305(Box<ChaCha20Poly1305>),
+    None,
+}
+
+/// Represents the sealer or the opener of the chunks of a direction of a VMess connection.
+struct Chunker {
+    cipher: ChunkCipher,
+    iv: [u8; 16],
+    count: u16,
+}
+
+impl Chunker {
+    fn new(security: VmessSecurity, key: &[u8; 16], iv: &[u8; 16]) -> Chunker {
+        let cipher = match security {
+            VmessSecurity::Aes128Gcm => {
+                ChunkCipher::Aes128Gcm(Box::new(Aes128Gcm::new(GenericArray::from_slice(key))))
+            }
+            VmessSecurity::Chacha20Poly1305 => {
+                // The key is expanded by MD5
+                let mut expanded = [0u8; 32];
+                expanded[..16].copy_from_slice(&Md5::digest(key));
+                let hash = Md5::digest(&expanded[..16]);
+                expanded[16..].copy_from_slice(&hash);
+
+                ChunkCipher::Chacha20Poly1305(Box::new(ChaCha20Poly1305::new(
+                    GenericArray::from_slice(&expanded),
+                )))
+            }
+            VmessSecurity::None => ChunkCipher::None,
+        };
+
+        Chunker {
+            cipher,
+            iv: *iv,
+            count: 0,
+        }
+    }
+
+    fn overhead(&self) -> usize {
+        match self.cipher {
+            ChunkCipher::None => 0,
+            _ => TAG_SIZE,
+        }
+    }
+
+    fn nonce(&mut self) -> [u8; 12] {
+        let mut nonce = [0u8; 12];
+        nonce[..2].copy_from_slice(&self.count.to_be_bytes());
+        nonce[2..].copy_from_slice(&self.iv[2..12]);
+        self.count = self.count.wrapping_add(1);
+
+        nonce
+    }
+
+    /// Seals the payload into a chunk and appends it to the buffer.
+    fn seal(&mut self, payload: &[u8], buffer: &mut Vec<u8>) -> io::Result<()> {
+        let size = payload.len() + self.overhead();
+        if size > u16::MAX as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "VMess chunk is too large",
+            ));
+        }
+
+        let nonce = self.nonce();
+        let nonce = GenericArray::from_slice(&nonce);
+        buffer.extend_from_slice(&(size as u16).to_be_bytes());
+        match self.cipher {
+            ChunkCipher::Aes128Gcm(ref cipher) => {
+                buffer.extend_from_slice(&cipher.encrypt(nonce, payload).unwrap())
+            }
+            ChunkCipher::Chacha20Poly1305(ref cipher) => {
+                buffer.extend_from_slice(&cipher.encrypt(nonce, payload).unwrap())
+            }
+            ChunkCipher::None => buffer.extend_from_slice(payload),
+        }
+
+        Ok(())
+    }
+
+    /// Opens a chunk without its length.
+    fn open(&mut self, chunk: Vec<u8>) -> io::Result<Vec<u8>> {
+        let nonce = self.nonce();
+        let nonce = GenericArray::from_slice(&nonce);
+        let payload = match self.cipher {
+            ChunkCipher::Aes128Gcm(ref cipher) => cipher.decrypt(nonce, chunk.as_slice()),
+            ChunkCipher::Chacha20Poly1305(ref cipher) => cipher.decrypt(nonce, chunk.as_slice()),
+            ChunkCipher::None => return Ok(chunk),
+        };
+
+        payload.map_err(|_| invalid_data("invalid VMess chunk"))
+    }
+}
+
+/// Represents the read half of a VMess connection.
+pub struct VmessReadHalf {
+    reader: BufReader<OwnedReadHalf>,
+    security: VmessSecurity,
+    key: [u8; 16],
+    iv: [u8; 16],
+    auth: u8,
+    /// Represents the opener of chunks, which is created once the response header is read.
+    opener: Option<Chunker>,
+    buffer: Vec<u8>,
+    offset: usize,
+    is_eof: bool,
+}
+
+impl VmessReadHalf {
+    async fn read_header(&mut self) -> io::Result<()> {
+        let len_key = kdf16(&self.key, &[KDF_SALT_RESP_HEADER_LEN_KEY]);
+        let len_iv = kdf(&self.iv, &[KDF_SALT_RESP_HEADER_LEN_IV]);
+        let mut len = [0u8; LENGTH_SIZE + TAG_SIZE];
+        self.reader.read_exact(&mut len).await?;
+        let len = open_header(&len_key, &len_iv, &len)?;
+        let len = u16::from_be_bytes([len[0], len[1]]) as usize;
+
+        let key = kdf16(&self.key, &[KDF_SALT_RESP_HEADER_KEY]);
+        let iv = kdf(&self.iv, &[KDF_SALT_RESP_HEADER_IV]);
+        let mut header = vec![0u8; len + TAG_SIZE];
+        self.reader.read_exact(&mut header).await?;
+        let header = open_header(&key, &iv, &header)?;
+        if header.len() < 4 || header[0] != self.auth {
+            return Err(invalid_data("unexpected VMess response header"));
+        }
+
+        self.opener = Some(Chunker::new(self.security, &self.key, &self.iv));
+
+        Ok(())
+    }
+
+    /// Reads a chunk from the connection. Returns `None` if the connection is closed.
+    async fn read_chunk(&mut self) -> io::Result<Option<Vec<u8>>> {
+        if self.is_eof {
+            return Ok(None);
+        }
+        if self.opener.is_none() {
+            self.read_header().await?;
+        }
+        let opener = self.opener.as_mut().unwrap();
+
+        let mut size = [0u8; LENGTH_SIZE];
+        if let Err(e) = self.reader.read_exact(&mut size).await {
+            if e.kind() == io::ErrorKind::UnexpectedEof {
+                self.is_eof = true;
+                return Ok(None);
+            }
+            return Err(e);
+        }
+        let size = u16::from_be_bytes(size) as usize;
+        if size < opener.overhead() {
+            return Err(invalid_data("invalid VMess chunk"));
+        }
+        let mut chunk = vec![0u8; size];
+        self.reader.read_exact(&mut chunk).await?;
+
+        // An empty chunk ends the stream
+        let payload = opener.open(chunk)?;
+        if payload.is_empty() {
+            self.is_eof = true;
+            return Ok(None);
+        }
+
+        Ok(Some(payload))
+    }
+
+    /// Reads data from the connection. Returns 0 if the connection is closed.
+    pub async fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        if self.offset >= self.buffer.len() {
+            match self.read_chunk().await? {
+                Some(payload) => {
+                    self.buffer = payload;
+                    self.offset = 0;
+                }
+                None => return Ok(0),
+            }
+        }
+
+        let size = (self.buffer.len() - self.offset).min(buffer.len());
+        buffer[..size].copy_from_slice(&self.buffer[self.offset..self.offset + size]);
+        self.offset += size;
+
+        Ok(size)
+    }
+}
+
+/// Represents the write half of a VMess connection.
+pub struct VmessWriteHalf {
+    write_half: OwnedWriteHalf,
+    sealer: Chunker,
+}
+
+impl VmessWriteHalf {
+    async fn write_chunks(&mut self, payloads: &[&[u8]]) -> io::Result<()> {
+        let mut buffer = Vec::new();
+        for payload in payloads {
+            self.sealer.seal(payload, &mut buffer)?;
+        }
+
+        self.write_half.write_all(&buffer).await
+    }
+
+    /// Writes all the data to the connection.
+    pub async fn write_all(&mut self, payload: &[u8]) -> io::Result<()> {
+        let payloads: Vec<_> = payload.chunks(MAX_CHUNK_PAYLOAD_SIZE).collect();
+
+        self.write_chunks(&payloads).await
+    }
+
+    /// Closes the connection for writing by sending an empty chunk.
+    pub async fn close(mut self) {
+        if let Err(ref e) = self.write_chunks(&[&[]]).await {
+            trace!("close VMess connection: {}", e);
+        }
+        self.write_half.forget();
+    }
+}
+
+/// Connects to a target server through a VMess server. The response header is read with the first
+/// data, so the target server may be unreachable even if the connection is established.
+pub async fn connect(
+    remote: SocketAddrV4,
+    dst: SocketAddrV4,
+    options: &VmessOption,
+) -> io::Result<(VmessReadHalf, VmessWriteHalf)> {
+    open(remote, dst, options, COMMAND_TCP).await
+}
+
+async fn open(
+    remote: SocketAddrV4,
+    dst: SocketAddrV4,
+    options: &VmessOption,
+    command: u8,
+) -> io::Result<(VmessReadHalf, VmessWriteHalf)> {
+    let stream = TcpStream::connect(remote).await?;
+    stream.set_nodelay(true)?;
+    let (read_half, mut write_half) = stream.into_split();
+
+    let (header, key, iv, auth) = request(dst, options, command);
+    write_half.write_all(&header).await?;
+
+    // Keys and IVs of the response
+    let mut resp_key = [0u8; 16];
+    resp_key.copy_from_slice(&Sha256::digest(&key)[..16]);
+    let mut resp_iv = [0u8; 16];
+    resp_iv.copy_from_slice(&Sha256::digest(&iv)[..16]);
+
+    Ok((
+        VmessReadHalf {
+            reader: BufReader::new(read_half),
+            security: options.security,
+            key: resp_key,
+            iv: resp_iv,
+            auth,
+            opener: None,
+            buffer: Vec::new(),
+            offset: 0,
+            is_eof: false,
+        },
+        VmessWriteHalf {
+            write_half,
+            sealer: Chunker::new(options.security, &key, &iv),
+        },
+    ))
+}
+
+/// Returns the sealed request header, with the key, the IV and the response authentication of
+/// the data.
+fn request(
+    dst: SocketAddrV4,
+    options: &VmessOption,
+    command: u8,
+) -> (Vec<u8>, [u8; 16], [u8; 16], u8) {
+    let mut rng = rand::thread_rng();
+    let key: [u8; 16] = rng.gen();
+    let iv: [u8; 16] = rng.gen();
+    let auth: u8 = rng.gen();
+    let padding = rng.gen_range(0, 16);
+
+    let mut header = Vec::with_capacity(64);
+    header.push(VERSION);
+    header.extend_from_slice(&iv);
+    header.extend_from_slice(&key);
+    header.push(auth);
+    header.push(OPTION_CHUNK_STREAM);
+    header.push(((padding as u8) << 4) | options.security.to_u8());
+    // Reserved
+    header.push(0);
+    header.push(command);
+    header.extend_from_slice(&dst.port().to_be_bytes());
+    header.push(ATYP_IPV4);
+    header.extend_from_slice(&dst.ip().octets());
+    for _ in 0..padding {
+        header.push(rng.gen());
+    }
+    let checksum = fnv1a(&header);
+    header.extend_from_slice(&checksum.to_be_bytes());
+
+    (seal_header(&options.cmd_key(), &header), key, iv, auth)
+}
+
+/// Represents the send half of a VMess UDP client. VMess carries datagrams to a single target
+/// server in a connection, so a connection is opened for each peer.
+pub struct VmessSendHalf {
+    remote: SocketAddrV4,
+    options: VmessOption,
+    peers: HashMap<SocketAddrV4, VmessWriteHalf>,
+    recv_tx: mpsc::Sender<(Vec<u8>, SocketAddrV4)>,
+    /// Represents the socket reserving the local port.
+    _socket: std::net::UdpSocket,
+}
+
+impl VmessSendHalf {
+    /// Sends data on the socket to the given address.
+    pub async fn send_to(&mut self, payload: &[u8], dst: SocketAddrV4) -> io::Result<usize> {
+        if !self.peers.contains_key(&dst) {
+            let (mut read_half, write_half) =
+                open(self.remote, dst, &self.options, COMMAND_UDP).await?;
+            trace!("open VMess datagram connection to {}", dst);
+
+            let mut recv_tx = self.recv_tx.clone();
+            tokio::spawn(async move {
+                loop {
+                    match read_half.read_chunk().await {
+                        Ok(Some(payload)) => {
+                            if recv_tx.send((payload, dst)).await.is_err() {
+                                break;
+                            }
+                        }
+                        Ok(None) => break,
+                        Err(ref e) => {
+                            warn!("VMess: {}: {} -> {}: {}", "UDP", dst, 0, e);
+                            break;
+                        }
+                    }
+                }
+                trace!("close VMess datagram connection to {}", dst);
+            });
+
+            self.peers.insert(dst, write_half);
+        }
+
+        // Each datagram is sent in a chunk
+        let result = self
+            .peers
+            .get_mut(&dst)
+            .unwrap()
+            .write_chunks(&[payload])
+            .await;
+        if let Err(e) = result {
+            self.peers.remove(&dst);
+            return Err(e);
+        }
+
+        Ok(payload.len())
+    }
+}
+
+/// Represents the receive half of a VMess UDP client.
+pub struct VmessRecvHalf {
+    rx: mpsc::Receiver<(Vec<u8>, SocketAddrV4)>,
+}
+
+impl VmessRecvHalf {
+    /// Receives a single datagram message on the socket.
+    pub async fn recv_from(&mut self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddrV4)> {
+        match self.rx.recv().await {
+            Some((payload, addr)) => {
+                let size = payload.len().min(buffer.len());
+                buffer[..size].copy_from_slice(&payload[..size]);
+
+                Ok((size, addr))
+            }
+            None => Err(io::Error::from(io::ErrorKind::BrokenPipe)),
+        }
+    }
+}
+
+/// Binds a local port to target servers through a VMess server. The port only identifies the
+/// client and no datagram is sent from it.
+pub async fn bind(
+    remote: SocketAddrV4,
+    options: &VmessOption,
+) -> io::Result<(VmessRecvHalf, VmessSendHalf, u16)> {
+    let socket = std::net::UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0))?;
+    let local_port = socket.local_addr()?.port();
+
+    let (recv_tx, rx) = mpsc::channel(DATAGRAM_QUEUE_SIZE);
+
+    Ok((
+        VmessRecvHalf { rx },
+        VmessSendHalf {
+            remote,
+            options: options.clone(),
+            peers: HashMap::new(),
+            recv_tx,
+            _socket: socket,
+        },
+        local_port,
+    ))
+}
+
+#[test]
+fn vmess_chunker() {
+    let key = [1u8; 16];
+    let iv = [2u8; 16];
+    for &security in &[
+        VmessSecurity::Aes128Gcm,
+        VmessSecurity::Chacha20Poly1305,
+        VmessSecurity::None,
+    ] {
+        let mut sealer = Chunker::new(security, &key, &iv);
+        let mut opener = Chunker::new(security, &key, &iv);
+
+        let mut buffer = Vec::new();
+        sealer.seal(b"hello", &mut buffer).unwrap();
+        sealer.seal(b"world", &mut buffer).unwrap();
+        let size = u16::from_be_bytes([buffer[0], buffer[1]]) as usize;
+        assert_eq!(size, 5 + sealer.overhead());
+
+        let first = buffer[LENGTH_SIZE..LENGTH_SIZE + size].to_vec();
+        let second = buffer[2 * LENGTH_SIZE + size..].to_vec();
+        assert_eq!(opener.open(first).unwrap(), b"hello");
+        assert_eq!(opener.open(second).unwrap(), b"world");
+    }
+}
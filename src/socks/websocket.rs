@@ -0,0 +1,205 @@
+//! Support for carrying the byte stream of SOCKS5 in a WebSocket connection.
+
+use futures_util::{ready, Sink, Stream};
+use http::header::{HeaderName, HeaderValue};
+use http::Request;
+use log::debug;
+use std::net::SocketAddrV4;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{self, AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::{self, Message};
+
+use super::tls;
+
+/// Represents the options of a WebSocket transport.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct WebSocketOption {
+    path: String,
+    host: Option<String>,
+    is_tls: bool,
+    headers: Vec<(String, String)>,
+}
+
+impl WebSocketOption {
+    /// Creates a new `WebSocketOption` with the path of the WebSocket endpoint.
+    pub fn new(path: String) -> WebSocketOption {
+        WebSocketOption {
+            path,
+            host: None,
+            is_tls: false,
+            headers: Vec::new(),
+        }
+    }
+
+    /// Sets the host of the WebSocket endpoint, which is sent in the `Host` header instead of the
+    /// address of the destination.
+    pub fn set_host(&mut self, host: String) {
+        self.host = Some(host);
+    }
+
+    /// Sets if the WebSocket connection is in TLS. The certificate is verified against the host.
+    pub fn set_tls(&mut self, is_tls: bool) {
+        self.is_tls = is_tls;
+    }
+
+    /// Adds a header in the form of `Name: Value` to the request of the WebSocket connection.
+    pub fn add_header(&mut self, header: &str) -> io::Result<()> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidInput, "invalid header");
+
+        let mut parts = header.splitn(2, ':');
+        let name = parts.next().unwrap().trim();
+        let value = parts.next().ok_or_else(invalid)?.trim();
+        HeaderName::from_bytes(name.as_bytes()).map_err(|_| invalid())?;
+        HeaderValue::from_str(value).map_err(|_| invalid())?;
+
+        self.headers.push((name.to_string(), value.to_string()));
+
+        Ok(())
+    }
+
+    fn request(&self, remote: SocketAddrV4) -> io::Result<Request<()>> {
+        let scheme = match self.is_tls {
+            true => "wss",
+            false => "ws",
+        };
+        let host = match self.host {
+            Some(ref host) => host.clone(),
+            None => remote.to_string(),
+        };
+        let path = match self.path.starts_with('/') {
+            true => self.path.clone(),
+            false => format!("/{}", self.path),
+        };
+
+        let mut request = Request::builder().uri(format!("{}://{}{}", scheme, host, path));
+        for (name, value) in self.headers.iter() {
+            request = request.header(name.as_str(), value.as_str());
+        }
+
+        request
+            .body(())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    }
+}
+
+/// Represents a stream which can be read and written.
+trait Io: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Io for T {}
+
+/// Represents a WebSocket connection as a byte stream. Data is written in binary messages.
+pub struct WebSocketStream {
+    inner: tokio_tungstenite::WebSocketStream<Box<dyn Io>>,
+    buffer: Vec<u8>,
+    offset: usize,
+}
+
+impl AsyncRead for WebSocketStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buffer: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        while this.offset >= this.buffer.len() {
+            match ready!(Pin::new(&mut this.inner).poll_next(cx)) {
+                Some(Ok(Message::Binary(data))) => {
+                    this.buffer = data;
+                    this.offset = 0;
+                }
+                Some(Ok(Message::Close(_))) | None => return Poll::Ready(Ok(0)),
+                // Pings are replied by the WebSocket stream itself
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Poll::Ready(Err(into_io(e))),
+            }
+        }
+
+        let size = (this.buffer.len() - this.offset).min(buffer.len());
+        buffer[..size].copy_from_slice(&this.buffer[this.offset..this.offset + size]);
+        this.offset += size;
+
+        Poll::Ready(Ok(size))
+    }
+}
+
+impl AsyncWrite for WebSocketStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buffer: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(Pin::new(&mut this.inner).poll_ready(cx)).map_err(into_io)?;
+        Pin::new(&mut this.inner)
+            .start_send(Message::Binary(buffer.to_vec()))
+            .map_err(into_io)?;
+
+        Poll::Ready(Ok(buffer.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner)
+            .poll_flush(cx)
+            .map_err(into_io)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner)
+            .poll_close(cx)
+            .map_err(into_io)
+    }
+}
+
+fn into_io(e: tungstenite::Error) -> io::Error {
+    match e {
+        tungstenite::Error::Io(e) => e,
+        tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed => {
+            io::Error::from(io::ErrorKind::BrokenPipe)
+        }
+        _ => io::Error::new(io::ErrorKind::Other, e),
+    }
+}
+
+/// Opens a WebSocket connection to the remote.
+pub async fn connect(
+    remote: SocketAddrV4,
+    options: &WebSocketOption,
+) -> io::Result<WebSocketStream> {
+    let request = options.request(remote)?;
+
+    let stream = TcpStream::connect(remote).await?;
+    stream.set_nodelay(true)?;
+    let stream: Box<dyn Io> = match options.is_tls {
+        true => {
+            let host = request.uri().host().unwrap_or_default().to_string();
+            Box::new(tls::connect(stream, &host, &[b"http/1.1"]).await?)
+        }
+        false => Box::new(stream),
+    };
+
+    let (inner, _) = tokio_tungstenite::client_async(request, stream)
+        .await
+        .map_err(into_io)?;
+    debug!("open WebSocket connection to {}", remote);
+
+    Ok(WebSocketStream {
+        inner,
+        buffer: Vec::new(),
+        offset: 0,
+    })
+}
+
+#[test]
+fn websocket_request() {
+    let mut options = WebSocketOption::new("ws".to_string());
+    options.set_host("example.com".to_string());
+    options.set_tls(true);
+    options.add_header("X-Forwarded-For: 10.6.0.1").unwrap();
+    assert!(options.add_header("Invalid").is_err());
+
+    let request = options.request("127.0.0.1:443".parse().unwrap()).unwrap();
+    assert_eq!(request.uri().to_string(), "wss://example.com/ws");
+    assert_eq!(request.headers()["x-forwarded-for"], "10.6.0.1");
+}
@@ -0,0 +1,273 @@
+//! Support for sources of multiple networks with exclusions, like proxying a whole subnet except
+//! a few devices in it.
+
+use ipnetwork::Ipv4Network;
+use std::cmp::Ordering;
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+
+/// Represents an inclusive range of IPv4 addresses.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct AddrRange {
+    start: Ipv4Addr,
+    end: Ipv4Addr,
+}
+
+impl AddrRange {
+    /// Creates a new `AddrRange` from `start` to `end` inclusively.
+    pub fn new(start: Ipv4Addr, end: Ipv4Addr) -> AddrRange {
+        if start <= end {
+            AddrRange { start, end }
+        } else {
+            AddrRange {
+                start: end,
+                end: start,
+            }
+        }
+    }
+
+    /// Returns the first address of the range.
+    pub fn start(&self) -> Ipv4Addr {
+        self.start
+    }
+
+    /// Returns the last address of the range.
+    pub fn end(&self) -> Ipv4Addr {
+        self.end
+    }
+
+    /// Returns if the address is in the range.
+    pub fn contains(&self, ip_addr: Ipv4Addr) -> bool {
+        ip_addr >= self.start && ip_addr <= self.end
+    }
+
+    fn bounds(&self) -> (u32, u32) {
+        (u32::from(self.start), u32::from(self.end))
+    }
+}
+
+impl From<Ipv4Network> for AddrRange {
+    fn from(network: Ipv4Network) -> Self {
+        AddrRange::new(network.network(), network.broadcast())
+    }
+}
+
+impl Display for AddrRange {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        if self.start == self.end {
+            write!(f, "{}", self.start)
+        } else {
+            write!(f, "{}-{}", self.start, self.end)
+        }
+    }
+}
+
+impl FromStr for AddrRange {
+    type Err = io::Error;
+
+    /// Parses a range like `192.168.1.10-192.168.1.20`, a network like `192.168.1.0/28` or a single
+    /// address.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidInput, "invalid address range");
+
+        match s.find('-') {
+            Some(i) => {
+                let start = s[..i].trim().parse().map_err(|_| invalid())?;
+                let end = s[i + 1..].trim().parse().map_err(|_| invalid())?;
+
+                Ok(AddrRange::new(start, end))
+            }
+            None => s
+                .trim()
+                .parse::<Ipv4Network>()
+                .map(AddrRange::from)
+                .map_err(|_| invalid()),
+        }
+    }
+}
+
+/// Represents a set of sources, which are the addresses in any of the included networks but not
+/// in any of the excluded ranges. Both are merged into sorted and disjoint ranges, so whether an
+/// address is a source is looked up in logarithmic time.
+#[derive(Clone, Debug)]
+pub struct SourceSet {
+    includes: Vec<Ipv4Network>,
+    excludes: Vec<AddrRange>,
+    ranges: Vec<(u32, u32)>,
+}
+
+impl SourceSet {
+    /// Creates a new `SourceSet` of the addresses in the `includes` but not in the `excludes`.
+    pub fn new(includes: Vec<Ipv4Network>, excludes: Vec<AddrRange>) -> SourceSet {
+        let includes_ranges = merge(
+            includes
+                .iter()
+                .map(|network| AddrRange::from(*network).bounds())
+                .collect(),
+        );
+        let excludes_ranges = merge(excludes.iter().map(|range| range.bounds()).collect());
+
+        // Subtract the excluded ranges from the included ranges
+        let mut ranges = Vec::with_capacity(includes_ranges.len());
+        let mut excludes_iter = excludes_ranges.iter().peekable();
+        for (start, end) in includes_ranges {
+            let mut start = Some(start);
+            while let Some(&&(exclude_start, exclude_end)) = excludes_iter.peek() {
+                let curr = match start {
+                    Some(curr) => curr,
+                    None => break,
+                };
+                if exclude_end < curr {
+                    excludes_iter.next();
+                    continue;
+                }
+                if exclude_start > end {
+                    break;
+                }
+                if exclude_start > curr {
+                    ranges.push((curr, exclude_start - 1));
+                }
+                start = exclude_end.checked_add(1).filter(|next| *next <= end);
+                if exclude_end <= end {
+                    excludes_iter.next();
+                }
+            }
+            if let Some(start) = start {
+                ranges.push((start, end));
+            }
+        }
+
+        SourceSet {
+            includes,
+            excludes,
+            ranges,
+        }
+    }
+
+    /// Returns the included networks.
+    pub fn includes(&self) -> &[Ipv4Network] {
+        &self.includes
+    }
+
+    /// Returns the excluded ranges.
+    pub fn excludes(&self) -> &[AddrRange] {
+        &self.excludes
+    }
+
+    /// Returns if the address is a source.
+    pub fn contains(&self, ip_addr: Ipv4Addr) -> bool {
+        let ip_addr = u32::from(ip_addr);
+
+        self.ranges
+            .binary_search_by(|&(start, end)| {
+                if end < ip_addr {
+                    Ordering::Less
+                } else if start > ip_addr {
+                    Ordering::Greater
+                } else {
+                    Ordering::Equal
+                }
+            })
+            .is_ok()
+    }
+
+    /// Returns if the address is the broadcast address of any of the included networks.
+    pub fn is_broadcast(&self, ip_addr: Ipv4Addr) -> bool {
+        self.includes
+            .iter()
+            .any(|network| network.prefix() < 31 && ip_addr == network.broadcast())
+    }
+}
+
+impl From<Ipv4Network> for SourceSet {
+    fn from(network: Ipv4Network) -> Self {
+        SourceSet::new(vec![network], Vec::new())
+    }
+}
+
+impl Display for SourceSet {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let includes = self
+            .includes
+            .iter()
+            .map(|network| network.to_string())
+            .collect::<Vec<_>>();
+        write!(f, "{}", includes.join(", "))?;
+        if !self.excludes.is_empty() {
+            let excludes = self
+                .excludes
+                .iter()
+                .map(|range| range.to_string())
+                .collect::<Vec<_>>();
+            write!(f, " except {}", excludes.join(", "))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Sorts and merges the overlapping or adjacent ranges.
+fn merge(mut ranges: Vec<(u32, u32)>) -> Vec<(u32, u32)> {
+    ranges.sort_unstable();
+
+    let mut merged: Vec<(u32, u32)> = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some(last) if start <= last.1.saturating_add(1) => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+
+    merged
+}
+
+#[test]
+fn source_set_contains() {
+    let includes = vec![
+        "192.168.1.0/24".parse().unwrap(),
+        "10.6.0.0/30".parse().unwrap(),
+        "192.168.1.128/25".parse().unwrap(),
+    ];
+    let excludes = vec![
+        "192.168.1.10".parse().unwrap(),
+        "192.168.1.200-192.168.1.255".parse().unwrap(),
+        "10.6.0.0/31".parse().unwrap(),
+    ];
+    let sources = SourceSet::new(includes, excludes);
+    assert_eq!(
+        sources.ranges,
+        vec![
+            (
+                u32::from(Ipv4Addr::new(10, 6, 0, 2)),
+                u32::from(Ipv4Addr::new(10, 6, 0, 3))
+            ),
+            (
+                u32::from(Ipv4Addr::new(192, 168, 1, 0)),
+                u32::from(Ipv4Addr::new(192, 168, 1, 9))
+            ),
+            (
+                u32::from(Ipv4Addr::new(192, 168, 1, 11)),
+                u32::from(Ipv4Addr::new(192, 168, 1, 199))
+            ),
+        ]
+    );
+    assert!(sources.contains(Ipv4Addr::new(192, 168, 1, 9)));
+    assert!(!sources.contains(Ipv4Addr::new(192, 168, 1, 10)));
+    assert!(sources.contains(Ipv4Addr::new(192, 168, 1, 11)));
+    assert!(!sources.contains(Ipv4Addr::new(192, 168, 1, 200)));
+    assert!(!sources.contains(Ipv4Addr::new(10, 6, 0, 1)));
+    assert!(sources.contains(Ipv4Addr::new(10, 6, 0, 3)));
+    assert!(!sources.contains(Ipv4Addr::new(172, 16, 0, 1)));
+    // Broadcasts of excluded addresses are still broadcasts
+    assert!(sources.is_broadcast(Ipv4Addr::new(192, 168, 1, 255)));
+    assert_eq!(
+        sources.to_string(),
+        "192.168.1.0/24, 10.6.0.0/30, 192.168.1.128/25 except 192.168.1.10, 192.168.1.200-192.168.1.255, 10.6.0.0-10.6.0.1"
+    );
+
+    let sources = SourceSet::from(Ipv4Network::new(Ipv4Addr::new(0, 0, 0, 0), 0).unwrap());
+    assert!(sources.contains(Ipv4Addr::new(255, 255, 255, 255)));
+    assert!("1.1.1.1-x".parse::<AddrRange>().is_err());
+}
@@ -0,0 +1,920 @@
+//! Support for collecting statistics of the redirector.
+
+use crate::packet::discovery::DiscoveryProtocol;
+use crate::packet::layer::{LayerKind, LayerKinds};
+use crate::packet::tunnel::TunnelProtocol;
+use crate::socks::ConnectFailure;
+use std::cmp::max;
+use std::fmt::{self, Display, Formatter};
+use std::net::SocketAddrV4;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[cfg(feature = "metrics")]
+pub mod statsd;
+
+/// Represents the statistics of a `Redirector`. The statistics can be shared and read while the
+/// `Redirector` is running.
+#[derive(Debug, Default)]
+pub struct Stats {
+    udp_capacity: AtomicUsize,
+    udp_bindings: AtomicUsize,
+    udp_expirations: AtomicU64,
+    udp_reuses: AtomicU64,
+    udp_stall_drops: AtomicU64,
+    udp_rate_limits: AtomicU64,
+    quic_sessions: AtomicUsize,
+    quic_migrations: AtomicU64,
+    broadcast_drops: AtomicU64,
+    broadcast_relays: AtomicU64,
+    broadcast_answers: AtomicU64,
+    multicast_groups: AtomicUsize,
+    multicast_drops: AtomicU64,
+    multicast_relays: AtomicU64,
+    multicast_reflections: AtomicU64,
+    tcp_invalid_segments: AtomicU64,
+    tcp_challenge_acks: AtomicU64,
+    tcp_refusals: AtomicU64,
+    tcp_evictions: AtomicU64,
+    tcp_idle_reaps: AtomicU64,
+    tcp_syn_drops: AtomicU64,
+    tcp_rate_limits: AtomicU64,
+    tcp_pending_expirations: AtomicU64,
+    tcp_write_stalls: AtomicU64,
+    tcp_connect_retries: AtomicU64,
+    tcp_out_of_order_bytes: AtomicU64,
+    tcp_out_of_order_drops: AtomicU64,
+    tcp_retrans_segments: AtomicU64,
+    tcp_retrans_bytes: AtomicU64,
+    tcp_fast_retransmits: AtomicU64,
+    tcp_rto_retransmits: AtomicU64,
+    tcp_duplicate_acks: AtomicU64,
+    connect_auth_failures: AtomicU64,
+    connect_method_failures: AtomicU64,
+    connect_reply_failures: AtomicU64,
+    connect_network_failures: AtomicU64,
+    connect_other_failures: AtomicU64,
+    arp_conflicts: AtomicU64,
+    quarantines: AtomicU64,
+    quarantine_drops: AtomicU64,
+    spoof_drops: AtomicU64,
+    frag_overlaps: AtomicU64,
+    frag_drops: AtomicU64,
+    mtu_probes: AtomicU64,
+    mtu_black_holes: AtomicU64,
+    qos_drops: AtomicU64,
+    tx_drops: AtomicU64,
+    sni_blocks: AtomicU64,
+    sni_bypasses: AtomicU64,
+    icmp_redirects: AtomicU64,
+    icmp_source_quenches: AtomicU64,
+    icmp_echo_replies: AtomicU64,
+    tunneled_gre: AtomicU64,
+    tunneled_ipsec: AtomicU64,
+    tunneled_6in4: AtomicU64,
+    tunneled_forwards: AtomicU64,
+    discovery_lldp: AtomicU64,
+    discovery_cdp: AtomicU64,
+    discovery_stp: AtomicU64,
+    malformed_ethernet: AtomicU64,
+    malformed_arp: AtomicU64,
+    malformed_ipv4: AtomicU64,
+    malformed_icmpv4: AtomicU64,
+    malformed_tcp: AtomicU64,
+    malformed_udp: AtomicU64,
+    dispatch_drops: AtomicU64,
+    rx_bytes: AtomicU64,
+    tx_bytes: AtomicU64,
+    proxy_rtts: Mutex<Vec<(SocketAddrV4, Option<Duration>)>>,
+    /// Represents the time in milliseconds since the Unix epoch of the last iteration of the
+    /// capture loop.
+    loop_timestamp: AtomicU64,
+    /// Represents the time in milliseconds since the Unix epoch of the last frame captured.
+    frame_timestamp: AtomicU64,
+}
+
+impl Stats {
+    /// Creates a new `Stats`.
+    pub fn new() -> Stats {
+        Stats::default()
+    }
+
+    pub(crate) fn set_udp_capacity(&self, capacity: usize) {
+        self.udp_capacity.store(capacity, Ordering::Relaxed);
+    }
+
+    #[cfg(feature = "udp")]
+    pub(crate) fn set_udp_bindings(&self, bindings: usize) {
+        self.udp_bindings.store(bindings, Ordering::Relaxed);
+    }
+
+    #[cfg(feature = "udp")]
+    pub(crate) fn increase_udp_expirations(&self) {
+        self.udp_expirations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[cfg(feature = "udp")]
+    pub(crate) fn increase_udp_reuses(&self) {
+        self.udp_reuses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[cfg(feature = "udp")]
+    pub(crate) fn increase_udp_stall_drops(&self) {
+        self.udp_stall_drops.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[cfg(feature = "udp")]
+    pub(crate) fn increase_udp_rate_limits(&self) {
+        self.udp_rate_limits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[cfg(feature = "udp")]
+    pub(crate) fn set_quic_sessions(&self, sessions: usize) {
+        self.quic_sessions.store(sessions, Ordering::Relaxed);
+    }
+
+    #[cfg(feature = "udp")]
+    pub(crate) fn increase_quic_migrations(&self) {
+        self.quic_migrations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[cfg(feature = "udp")]
+    pub(crate) fn increase_broadcast_drops(&self) {
+        self.broadcast_drops.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[cfg(feature = "udp")]
+    pub(crate) fn increase_broadcast_relays(&self) {
+        self.broadcast_relays.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[cfg(feature = "udp")]
+    pub(crate) fn increase_broadcast_answers(&self) {
+        self.broadcast_answers.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_multicast_groups(&self, groups: usize) {
+        self.multicast_groups.store(groups, Ordering::Relaxed);
+    }
+
+    #[cfg(feature = "udp")]
+    pub(crate) fn increase_multicast_drops(&self) {
+        self.multicast_drops.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[cfg(feature = "udp")]
+    pub(crate) fn increase_multicast_relays(&self) {
+        self.multicast_relays.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[cfg(feature = "udp")]
+    pub(crate) fn increase_multicast_reflections(&self) {
+        self.multicast_reflections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn increase_tcp_invalid_segments(&self) {
+        self.tcp_invalid_segments.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn increase_tcp_challenge_acks(&self) {
+        self.tcp_challenge_acks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn increase_tcp_refusals(&self) {
+        self.tcp_refusals.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn increase_tcp_evictions(&self) {
+        self.tcp_evictions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn increase_tcp_idle_reaps(&self) {
+        self.tcp_idle_reaps.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn increase_tcp_syn_drops(&self) {
+        self.tcp_syn_drops.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn increase_tcp_rate_limits(&self) {
+        self.tcp_rate_limits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn increase_tcp_pending_expirations(&self) {
+        self.tcp_pending_expirations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn increase_tcp_write_stalls(&self) {
+        self.tcp_write_stalls.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn increase_tcp_connect_retries(&self) {
+        self.tcp_connect_retries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_tcp_out_of_order_bytes(&self, n: usize) {
+        self.tcp_out_of_order_bytes
+            .fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_tcp_out_of_order_drops(&self, n: usize) {
+        self.tcp_out_of_order_drops
+            .fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_tcp_retrans_segments(&self, n: usize) {
+        self.tcp_retrans_segments
+            .fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_tcp_retrans_bytes(&self, n: usize) {
+        self.tcp_retrans_bytes
+            .fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn increase_tcp_fast_retransmits(&self) {
+        self.tcp_fast_retransmits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn increase_tcp_rto_retransmits(&self) {
+        self.tcp_rto_retransmits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn increase_tcp_duplicate_acks(&self) {
+        self.tcp_duplicate_acks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn increase_connect_failures(&self, failure: ConnectFailure) {
+        let counter = match failure {
+            ConnectFailure::Auth => &self.connect_auth_failures,
+            ConnectFailure::Method => &self.connect_method_failures,
+            ConnectFailure::Reply => &self.connect_reply_failures,
+            ConnectFailure::Network => &self.connect_network_failures,
+            ConnectFailure::Other => &self.connect_other_failures,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn increase_arp_conflicts(&self) {
+        self.arp_conflicts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn increase_quarantines(&self) {
+        self.quarantines.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn increase_quarantine_drops(&self) {
+        self.quarantine_drops.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn increase_spoof_drops(&self) {
+        self.spoof_drops.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[cfg(feature = "defrag")]
+    pub(crate) fn increase_frag_overlaps(&self) {
+        self.frag_overlaps.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[cfg(feature = "defrag")]
+    pub(crate) fn increase_frag_drops(&self) {
+        self.frag_drops.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn increase_mtu_probes(&self) {
+        self.mtu_probes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn increase_mtu_black_holes(&self) {
+        self.mtu_black_holes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[cfg(feature = "udp")]
+    pub(crate) fn increase_qos_drops(&self) {
+        self.qos_drops.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn increase_tx_drops(&self) {
+        self.tx_drops.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn increase_sni_blocks(&self) {
+        self.sni_blocks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn increase_sni_bypasses(&self) {
+        self.sni_bypasses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[cfg(feature = "icmp")]
+    pub(crate) fn increase_icmp_redirects(&self) {
+        self.icmp_redirects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[cfg(feature = "icmp")]
+    pub(crate) fn increase_icmp_source_quenches(&self) {
+        self.icmp_source_quenches.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[cfg(feature = "icmp")]
+    pub(crate) fn increase_icmp_echo_replies(&self) {
+        self.icmp_echo_replies.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn increase_tunneled(&self, protocol: TunnelProtocol) {
+        let counter = match protocol {
+            TunnelProtocol::Gre => &self.tunneled_gre,
+            TunnelProtocol::Ipsec => &self.tunneled_ipsec,
+            TunnelProtocol::Ipv6 => &self.tunneled_6in4,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn increase_tunneled_forwards(&self) {
+        self.tunneled_forwards.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn increase_discovery(&self, protocol: DiscoveryProtocol) {
+        let counter = match protocol {
+            DiscoveryProtocol::Lldp => &self.discovery_lldp,
+            DiscoveryProtocol::Cdp => &self.discovery_cdp,
+            DiscoveryProtocol::Stp => &self.discovery_stp,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn increase_malformed(&self, kind: LayerKind) {
+        let counter = match kind {
+            LayerKinds::Ethernet => &self.malformed_ethernet,
+            LayerKinds::Arp => &self.malformed_arp,
+            LayerKinds::Ipv4 => &self.malformed_ipv4,
+            LayerKinds::Icmpv4 => &self.malformed_icmpv4,
+            LayerKinds::Tcp => &self.malformed_tcp,
+            LayerKinds::Udp => &self.malformed_udp,
+            _ => return,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn increase_dispatch_drops(&self) {
+        self.dispatch_drops.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_rx_bytes(&self, n: usize) {
+        self.rx_bytes.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_tx_bytes(&self, n: usize) {
+        self.tx_bytes.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_proxy_rtts(&self, rtts: Vec<(SocketAddrV4, Option<Duration>)>) {
+        *self.proxy_rtts.lock().unwrap() = rtts;
+    }
+
+    pub(crate) fn mark_loop(&self) {
+        self.loop_timestamp.store(now_millis(), Ordering::Relaxed);
+    }
+
+    pub(crate) fn mark_frame(&self) {
+        self.frame_timestamp.store(now_millis(), Ordering::Relaxed);
+    }
+
+    /// Adds up the statistics of another `Stats`. Multicast groups are joined by sources in every
+    /// worker, so the larger count is kept.
+    pub(crate) fn accumulate(&self, other: &Stats) {
+        let counters = [
+            (&self.udp_expirations, &other.udp_expirations),
+            (&self.udp_reuses, &other.udp_reuses),
+            (&self.udp_stall_drops, &other.udp_stall_drops),
+            (&self.udp_rate_limits, &other.udp_rate_limits),
+            (&self.quic_migrations, &other.quic_migrations),
+            (&self.broadcast_drops, &other.broadcast_drops),
+            (&self.broadcast_relays, &other.broadcast_relays),
+            (&self.broadcast_answers, &other.broadcast_answers),
+            (&self.multicast_drops, &other.multicast_drops),
+            (&self.multicast_relays, &other.multicast_relays),
+            (&self.multicast_reflections, &other.multicast_reflections),
+            (&self.tcp_invalid_segments, &other.tcp_invalid_segments),
+            (&self.tcp_challenge_acks, &other.tcp_challenge_acks),
+            (&self.tcp_refusals, &other.tcp_refusals),
+            (&self.tcp_evictions, &other.tcp_evictions),
+            (&self.tcp_idle_reaps, &other.tcp_idle_reaps),
+            (&self.tcp_syn_drops, &other.tcp_syn_drops),
+            (&self.tcp_rate_limits, &other.tcp_rate_limits),
+            (
+                &self.tcp_pending_expirations,
+                &other.tcp_pending_expirations,
+            ),
+            (&self.tcp_write_stalls, &other.tcp_write_stalls),
+            (&self.tcp_connect_retries, &other.tcp_connect_retries),
+            (&self.tcp_out_of_order_bytes, &other.tcp_out_of_order_bytes),
+            (&self.tcp_out_of_order_drops, &other.tcp_out_of_order_drops),
+            (&self.tcp_retrans_segments, &other.tcp_retrans_segments),
+            (&self.tcp_retrans_bytes, &other.tcp_retrans_bytes),
+            (&self.tcp_fast_retransmits, &other.tcp_fast_retransmits),
+            (&self.tcp_rto_retransmits, &other.tcp_rto_retransmits),
+            (&self.tcp_duplicate_acks, &other.tcp_duplicate_acks),
+            (&self.connect_auth_failures, &other.connect_auth_failures),
+            (
+                &self.connect_method_failures,
+                &other.connect_method_failures,
+            ),
+            (&self.connect_reply_failures, &other.connect_reply_failures),
+            (
+                &self.connect_network_failures,
+                &other.connect_network_failures,
+            ),
+            (&self.connect_other_failures, &other.connect_other_failures),
+            (&self.arp_conflicts, &other.arp_conflicts),
+            (&self.quarantines, &other.quarantines),
+            (&self.quarantine_drops, &other.quarantine_drops),
+            (&self.spoof_drops, &other.spoof_drops),
+            (&self.frag_overlaps, &other.frag_overlaps),
+            (&self.frag_drops, &other.frag_drops),
+            (&self.mtu_probes, &other.mtu_probes),
+            (&self.mtu_black_holes, &other.mtu_black_holes),
+            (&self.qos_drops, &other.qos_drops),
+            (&self.tx_drops, &other.tx_drops),
+            (&self.sni_blocks, &other.sni_blocks),
+            (&self.sni_bypasses, &other.sni_bypasses),
+            (&self.icmp_redirects, &other.icmp_redirects),
+            (&self.icmp_source_quenches, &other.icmp_source_quenches),
+            (&self.icmp_echo_replies, &other.icmp_echo_replies),
+            (&self.tunneled_gre, &other.tunneled_gre),
+            (&self.tunneled_ipsec, &other.tunneled_ipsec),
+            (&self.tunneled_6in4, &other.tunneled_6in4),
+            (&self.tunneled_forwards, &other.tunneled_forwards),
+            (&self.discovery_lldp, &other.discovery_lldp),
+            (&self.discovery_cdp, &other.discovery_cdp),
+            (&self.discovery_stp, &other.discovery_stp),
+            (&self.malformed_ethernet, &other.malformed_ethernet),
+            (&self.malformed_arp, &other.malformed_arp),
+            (&self.malformed_ipv4, &other.malformed_ipv4),
+            (&self.malformed_icmpv4, &other.malformed_icmpv4),
+            (&self.malformed_tcp, &other.malformed_tcp),
+            (&self.malformed_udp, &other.malformed_udp),
+            (&self.dispatch_drops, &other.dispatch_drops),
+            (&self.rx_bytes, &other.rx_bytes),
+            (&self.tx_bytes, &other.tx_bytes),
+        ];
+        for (counter, other) in counters.iter() {
+            counter.fetch_add(other.load(Ordering::Relaxed), Ordering::Relaxed);
+        }
+
+        let gauges = [
+            (&self.udp_capacity, &other.udp_capacity),
+            (&self.udp_bindings, &other.udp_bindings),
+            (&self.quic_sessions, &other.quic_sessions),
+        ];
+        for (gauge, other) in gauges.iter() {
+            gauge.fetch_add(other.load(Ordering::Relaxed), Ordering::Relaxed);
+        }
+        let groups = max(self.multicast_groups(), other.multicast_groups());
+        self.set_multicast_groups(groups);
+        // Only the capture loop marks timestamps, so the latest ones are kept
+        self.loop_timestamp.fetch_max(
+            other.loop_timestamp.load(Ordering::Relaxed),
+            Ordering::Relaxed,
+        );
+        self.frame_timestamp.fetch_max(
+            other.frame_timestamp.load(Ordering::Relaxed),
+            Ordering::Relaxed,
+        );
+        // Proxies are probed once for all the workers
+        let rtts = other.proxy_rtts();
+        if !rtts.is_empty() {
+            self.set_proxy_rtts(rtts);
+        }
+    }
+
+    /// Returns the max limit of UDP port for binding in local.
+    pub fn udp_capacity(&self) -> usize {
+        self.udp_capacity.load(Ordering::Relaxed)
+    }
+
+    /// Returns the count of UDP ports bound in local currently.
+    pub fn udp_bindings(&self) -> usize {
+        self.udp_bindings.load(Ordering::Relaxed)
+    }
+
+    /// Returns the count of UDP ports unbound because of idle.
+    pub fn udp_expirations(&self) -> u64 {
+        self.udp_expirations.load(Ordering::Relaxed)
+    }
+
+    /// Returns the count of UDP ports reused by another source because all the ports are in use.
+    pub fn udp_reuses(&self) -> u64 {
+        self.udp_reuses.load(Ordering::Relaxed)
+    }
+
+    /// Returns the count of UDP datagrams dropped because the queue to the proxy is full.
+    pub fn udp_stall_drops(&self) -> u64 {
+        self.udp_stall_drops.load(Ordering::Relaxed)
+    }
+
+    /// Returns the count of UDP datagrams dropped because the source exceeds the rate of
+    /// connection attempts.
+    pub fn udp_rate_limits(&self) -> u64 {
+        self.udp_rate_limits.load(Ordering::Relaxed)
+    }
+
+    /// Returns the count of UDP ports carrying QUIC sessions currently.
+    pub fn quic_sessions(&self) -> usize {
+        self.quic_sessions.load(Ordering::Relaxed)
+    }
+
+    /// Returns the count of QUIC sessions kept on the same UDP port after the source port changed.
+    pub fn quic_migrations(&self) -> u64 {
+        self.quic_migrations.load(Ordering::Relaxed)
+    }
+
+    /// Returns the count of UDP broadcast datagrams dropped.
+    pub fn broadcast_drops(&self) -> u64 {
+        self.broadcast_drops.load(Ordering::Relaxed)
+    }
+
+    /// Returns the count of UDP broadcast datagrams relayed to a unicast address.
+    pub fn broadcast_relays(&self) -> u64 {
+        self.broadcast_relays.load(Ordering::Relaxed)
+    }
+
+    /// Returns the count of UDP broadcast discovery queries answered locally.
+    pub fn broadcast_answers(&self) -> u64 {
+        self.broadcast_answers.load(Ordering::Relaxed)
+    }
+
+    /// Returns the count of multicast groups joined by sources currently.
+    pub fn multicast_groups(&self) -> usize {
+        self.multicast_groups.load(Ordering::Relaxed)
+    }
+
+    /// Returns the count of UDP multicast datagrams dropped.
+    pub fn multicast_drops(&self) -> u64 {
+        self.multicast_drops.load(Ordering::Relaxed)
+    }
+
+    /// Returns the count of UDP multicast datagrams relayed to a unicast address.
+    pub fn multicast_relays(&self) -> u64 {
+        self.multicast_relays.load(Ordering::Relaxed)
+    }
+
+    /// Returns the count of UDP multicast datagrams reflected to another source.
+    pub fn multicast_reflections(&self) -> u64 {
+        self.multicast_reflections.load(Ordering::Relaxed)
+    }
+
+    /// Returns the count of TCP RSTs and segments ignored because they are out of the receive
+    /// window.
+    pub fn tcp_invalid_segments(&self) -> u64 {
+        self.tcp_invalid_segments.load(Ordering::Relaxed)
+    }
+
+    /// Returns the count of TCP challenge ACKs sent.
+    pub fn tcp_challenge_acks(&self) -> u64 {
+        self.tcp_challenge_acks.load(Ordering::Relaxed)
+    }
+
+    /// Returns the count of TCP connections refused because the connection limit is reached.
+    pub fn tcp_refusals(&self) -> u64 {
+        self.tcp_refusals.load(Ordering::Relaxed)
+    }
+
+    /// Returns the count of TCP connections evicted because the connection limit is reached.
+    pub fn tcp_evictions(&self) -> u64 {
+        self.tcp_evictions.load(Ordering::Relaxed)
+    }
+
+    /// Returns the count of TCP connections reaped because they are idle for the idle timeout.
+    pub fn tcp_idle_reaps(&self) -> u64 {
+        self.tcp_idle_reaps.load(Ordering::Relaxed)
+    }
+
+    /// Returns the count of TCP SYNs dropped because the source has too many pending connections.
+    pub fn tcp_syn_drops(&self) -> u64 {
+        self.tcp_syn_drops.load(Ordering::Relaxed)
+    }
+
+    /// Returns the count of TCP SYNs refused because the source exceeds the rate of connection
+    /// attempts.
+    pub fn tcp_rate_limits(&self) -> u64 {
+        self.tcp_rate_limits.load(Ordering::Relaxed)
+    }
+
+    /// Returns the count of pending TCP connections reset because of timeout.
+    pub fn tcp_pending_expirations(&self) -> u64 {
+        self.tcp_pending_expirations.load(Ordering::Relaxed)
+    }
+
+    /// Returns the count of TCP zero windows advertised because the write queue to the proxy
+    /// reaches the limit.
+    pub fn tcp_write_stalls(&self) -> u64 {
+        self.tcp_write_stalls.load(Ordering::Relaxed)
+    }
+
+    /// Returns the count of TCP SYNs dropped to back off after failing to connect to the proxy,
+    /// where the source retransmits the SYN to retry.
+    pub fn tcp_connect_retries(&self) -> u64 {
+        self.tcp_connect_retries.load(Ordering::Relaxed)
+    }
+
+    /// Returns the size of TCP payloads received out of order from the source.
+    pub fn tcp_out_of_order_bytes(&self) -> u64 {
+        self.tcp_out_of_order_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Returns the size of TCP payloads received out of order but dropped because the
+    /// out-of-order data of a connection exceeds the limit.
+    pub fn tcp_out_of_order_drops(&self) -> u64 {
+        self.tcp_out_of_order_drops.load(Ordering::Relaxed)
+    }
+
+    /// Returns the count of TCP segments retransmitted to the source.
+    pub fn tcp_retrans_segments(&self) -> u64 {
+        self.tcp_retrans_segments.load(Ordering::Relaxed)
+    }
+
+    /// Returns the size of TCP payloads retransmitted to the source.
+    pub fn tcp_retrans_bytes(&self) -> u64 {
+        self.tcp_retrans_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Returns the count of TCP fast retransmissions, which are triggered by duplicate ACKs.
+    pub fn tcp_fast_retransmits(&self) -> u64 {
+        self.tcp_fast_retransmits.load(Ordering::Relaxed)
+    }
+
+    /// Returns the count of TCP retransmissions triggered by timeouts.
+    pub fn tcp_rto_retransmits(&self) -> u64 {
+        self.tcp_rto_retransmits.load(Ordering::Relaxed)
+    }
+
+    /// Returns the count of duplicate TCP ACKs received from the source.
+    pub fn tcp_duplicate_acks(&self) -> u64 {
+        self.tcp_duplicate_acks.load(Ordering::Relaxed)
+    }
+
+    /// Returns the count of failures of the given kind connecting to the proxy.
+    pub fn connect_failures(&self, failure: ConnectFailure) -> u64 {
+        match failure {
+            ConnectFailure::Auth => self.connect_auth_failures.load(Ordering::Relaxed),
+            ConnectFailure::Method => self.connect_method_failures.load(Ordering::Relaxed),
+            ConnectFailure::Reply => self.connect_reply_failures.load(Ordering::Relaxed),
+            ConnectFailure::Network => self.connect_network_failures.load(Ordering::Relaxed),
+            ConnectFailure::Other => self.connect_other_failures.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Returns the count of conflicts of the gateway IP address detected.
+    pub fn arp_conflicts(&self) -> u64 {
+        self.arp_conflicts.load(Ordering::Relaxed)
+    }
+
+    /// Returns the count of sources quarantined because their error rates exceed the threshold.
+    pub fn quarantines(&self) -> u64 {
+        self.quarantines.load(Ordering::Relaxed)
+    }
+
+    /// Returns the count of frames dropped because their sources are quarantined.
+    pub fn quarantine_drops(&self) -> u64 {
+        self.quarantine_drops.load(Ordering::Relaxed)
+    }
+
+    /// Returns the count of frames dropped because their sources are not bound to their hardware
+    /// addresses.
+    pub fn spoof_drops(&self) -> u64 {
+        self.spoof_drops.load(Ordering::Relaxed)
+    }
+
+    /// Returns the count of fragments overlapping other fragments, whose groups of fragments are
+    /// dropped.
+    pub fn frag_overlaps(&self) -> u64 {
+        self.frag_overlaps.load(Ordering::Relaxed)
+    }
+
+    /// Returns the count of fragments dropped because of the limits of reassembly.
+    pub fn frag_drops(&self) -> u64 {
+        self.frag_drops.load(Ordering::Relaxed)
+    }
+
+    /// Returns the count of probes of the path MTU sent.
+    pub fn mtu_probes(&self) -> u64 {
+        self.mtu_probes.load(Ordering::Relaxed)
+    }
+
+    /// Returns the count of paths dropping large packets silently, whose path MTU falls back to
+    /// the base MTU.
+    pub fn mtu_black_holes(&self) -> u64 {
+        self.mtu_black_holes.load(Ordering::Relaxed)
+    }
+
+    /// Returns the count of UDP datagrams to the source dropped by traffic shaping.
+    pub fn qos_drops(&self) -> u64 {
+        self.qos_drops.load(Ordering::Relaxed)
+    }
+
+    /// Returns the count of frames to the source dropped because the TX queue is full.
+    pub fn tx_drops(&self) -> u64 {
+        self.tx_drops.load(Ordering::Relaxed)
+    }
+
+    /// Returns the count of TCP connections blocked by the server names in their ClientHellos.
+    pub fn sni_blocks(&self) -> u64 {
+        self.sni_blocks.load(Ordering::Relaxed)
+    }
+
+    /// Returns the count of TCP connections bypassing the proxy by the server names in their
+    /// ClientHellos.
+    pub fn sni_bypasses(&self) -> u64 {
+        self.sni_bypasses.load(Ordering::Relaxed)
+    }
+
+    /// Returns the count of ICMPv4 redirects received from the source.
+    pub fn icmp_redirects(&self) -> u64 {
+        self.icmp_redirects.load(Ordering::Relaxed)
+    }
+
+    /// Returns the count of ICMPv4 source quenches received from the source.
+    pub fn icmp_source_quenches(&self) -> u64 {
+        self.icmp_source_quenches.load(Ordering::Relaxed)
+    }
+
+    /// Returns the count of synthetic ICMPv4 echo replies sent to the source.
+    pub fn icmp_echo_replies(&self) -> u64 {
+        self.icmp_echo_replies.load(Ordering::Relaxed)
+    }
+
+    /// Returns the count of tunneled packets received from the source in the given protocol.
+    pub fn tunneled(&self, protocol: TunnelProtocol) -> u64 {
+        match protocol {
+            TunnelProtocol::Gre => self.tunneled_gre.load(Ordering::Relaxed),
+            TunnelProtocol::Ipsec => self.tunneled_ipsec.load(Ordering::Relaxed),
+            TunnelProtocol::Ipv6 => self.tunneled_6in4.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Returns the count of tunneled packets forwarded to the peer.
+    pub fn tunneled_forwards(&self) -> u64 {
+        self.tunneled_forwards.load(Ordering::Relaxed)
+    }
+
+    /// Returns the count of link-layer discovery frames received in the given protocol.
+    pub fn discovery(&self, protocol: DiscoveryProtocol) -> u64 {
+        match protocol {
+            DiscoveryProtocol::Lldp => self.discovery_lldp.load(Ordering::Relaxed),
+            DiscoveryProtocol::Cdp => self.discovery_cdp.load(Ordering::Relaxed),
+            DiscoveryProtocol::Stp => self.discovery_stp.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Returns the count of malformed frames dropped in the given layer.
+    pub fn malformed(&self, kind: LayerKind) -> u64 {
+        match kind {
+            LayerKinds::Ethernet => self.malformed_ethernet.load(Ordering::Relaxed),
+            LayerKinds::Arp => self.malformed_arp.load(Ordering::Relaxed),
+            LayerKinds::Ipv4 => self.malformed_ipv4.load(Ordering::Relaxed),
+            LayerKinds::Icmpv4 => self.malformed_icmpv4.load(Ordering::Relaxed),
+            LayerKinds::Tcp => self.malformed_tcp.load(Ordering::Relaxed),
+            LayerKinds::Udp => self.malformed_udp.load(Ordering::Relaxed),
+            _ => 0,
+        }
+    }
+
+    /// Returns the count of frames dropped because the queue of the worker is full, or because of
+    /// the limits of fragments waiting for the first fragment.
+    pub fn dispatch_drops(&self) -> u64 {
+        self.dispatch_drops.load(Ordering::Relaxed)
+    }
+
+    /// Returns the bytes of frames received from sources.
+    pub fn rx_bytes(&self) -> u64 {
+        self.rx_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Returns the bytes of frames sent to sources.
+    pub fn tx_bytes(&self) -> u64 {
+        self.tx_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Returns the RTTs of proxies in the last probe, where `None` represents a proxy is
+    /// unreachable.
+    pub fn proxy_rtts(&self) -> Vec<(SocketAddrV4, Option<Duration>)> {
+        self.proxy_rtts.lock().unwrap().clone()
+    }
+
+    /// Returns the time of the last iteration of the capture loop, which iterates at least once
+    /// a second even if there is no traffic.
+    pub fn last_loop(&self) -> Option<SystemTime> {
+        from_millis(self.loop_timestamp.load(Ordering::Relaxed))
+    }
+
+    /// Returns the time of the last frame captured.
+    pub fn last_frame(&self) -> Option<SystemTime> {
+        from_millis(self.frame_timestamp.load(Ordering::Relaxed))
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_millis() as u64)
+}
+
+fn from_millis(millis: u64) -> Option<SystemTime> {
+    match millis {
+        0 => None,
+        _ => Some(UNIX_EPOCH + Duration::from_millis(millis)),
+    }
+}
+
+impl Display for Stats {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "UDP: {}/{} bound, {} expired, {} reused, {} stall dropped, {} rate limited; QUIC: {} sessions, {} migrated; Broadcast: {} dropped, {} relayed, {} answered; Multicast: {} groups, {} dropped, {} relayed, {} reflected; TCP: {} invalid, {} challenged, {} refused, {} evicted, {} idle reaped, {} SYN dropped, {} rate limited, {} pending expired, {} write stalled, {} connect retried, {} Bytes out of order, {} Bytes out of order dropped, {} retransmitted ({} Bytes, {} fast, {} timed out), {} duplicate ACKs; Connect: {} auth failed, {} method failed, {} reply failed, {} network failed, {} other failed; ARP: {} conflicts; Quarantine: {} sources, {} dropped; Spoofed: {} dropped; Fragment: {} overlapped, {} dropped; Path MTU: {} probes, {} black holes; QoS: {} dropped; TX: {} dropped; SNI: {} blocked, {} bypassed; ICMP: {} redirects, {} source quenches, {} echo replies; Tunneled: {} GRE, {} IPsec, {} 6in4, {} forwarded; Discovery: {} LLDP, {} CDP, {} STP; Malformed: {} Ethernet, {} ARP, {} IPv4, {} ICMPv4, {} TCP, {} UDP; Dispatch: {} dropped; Traffic: {} Bytes received, {} Bytes sent",
+            self.udp_bindings(),
+            self.udp_capacity(),
+            self.udp_expirations(),
+            self.udp_reuses(),
+            self.udp_stall_drops(),
+            self.udp_rate_limits(),
+            self.quic_sessions(),
+            self.quic_migrations(),
+            self.broadcast_drops(),
+            self.broadcast_relays(),
+            self.broadcast_answers(),
+            self.multicast_groups(),
+            self.multicast_drops(),
+            self.multicast_relays(),
+            self.multicast_reflections(),
+            self.tcp_invalid_segments(),
+            self.tcp_challenge_acks(),
+            self.tcp_refusals(),
+            self.tcp_evictions(),
+            self.tcp_idle_reaps(),
+            self.tcp_syn_drops(),
+            self.tcp_rate_limits(),
+            self.tcp_pending_expirations(),
+            self.tcp_write_stalls(),
+            self.tcp_connect_retries(),
+            self.tcp_out_of_order_bytes(),
+            self.tcp_out_of_order_drops(),
+            self.tcp_retrans_segments(),
+            self.tcp_retrans_bytes(),
+            self.tcp_fast_retransmits(),
+            self.tcp_rto_retransmits(),
+            self.tcp_duplicate_acks(),
+            self.connect_failures(ConnectFailure::Auth),
+            self.connect_failures(ConnectFailure::Method),
+            self.connect_failures(ConnectFailure::Reply),
+            self.connect_failures(ConnectFailure::Network),
+            self.connect_failures(ConnectFailure::Other),
+            self.arp_conflicts(),
+            self.quarantines(),
+            self.quarantine_drops(),
+            self.spoof_drops(),
+            self.frag_overlaps(),
+            self.frag_drops(),
+            self.mtu_probes(),
+            self.mtu_black_holes(),
+            self.qos_drops(),
+            self.tx_drops(),
+            self.sni_blocks(),
+            self.sni_bypasses(),
+            self.icmp_redirects(),
+            self.icmp_source_quenches(),
+            self.icmp_echo_replies(),
+            self.tunneled(TunnelProtocol::Gre),
+            self.tunneled(TunnelProtocol::Ipsec),
+            self.tunneled(TunnelProtocol::Ipv6),
+            self.tunneled_forwards(),
+            self.discovery(DiscoveryProtocol::Lldp),
+            self.discovery(DiscoveryProtocol::Cdp),
+            self.discovery(DiscoveryProtocol::Stp),
+            self.malformed(LayerKinds::Ethernet),
+            self.malformed(LayerKinds::Arp),
+            self.malformed(LayerKinds::Ipv4),
+            self.malformed(LayerKinds::Icmpv4),
+            self.malformed(LayerKinds::Tcp),
+            self.malformed(LayerKinds::Udp),
+            self.dispatch_drops(),
+            self.rx_bytes(),
+            self.tx_bytes()
+        )?;
+
+        let rtts = self.proxy_rtts();
+        if !rtts.is_empty() {
+            write!(f, "; Proxies:")?;
+            for (i, (proxy, rtt)) in rtts.iter().enumerate() {
+                let sep = if i == 0 { "" } else { "," };
+                match rtt {
+                    Some(rtt) => write!(f, "{} {} {} ms", sep, proxy, rtt.as_millis())?,
+                    None => write!(f, "{} {} unreachable", sep, proxy)?,
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,210 @@
+//! Support for emitting statistics to a StatsD or DogStatsD server.
+
+use log::warn;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io;
+use tokio::net::UdpSocket;
+use tokio::time;
+
+use super::Stats;
+use crate::control::Controller;
+use crate::socks::ConnectFailure;
+
+/// Represents the default prefix of the names of metrics.
+pub const DEFAULT_PREFIX: &str = "pcap2socks";
+
+/// Represents the default interval in milliseconds between emissions.
+pub const DEFAULT_INTERVAL: u64 = 10000;
+
+/// Represents the max size of each datagram, which fits in the MTU of most networks.
+const MAX_DATAGRAM_SIZE: usize = 1432;
+
+/// Represents the kinds of failures connecting through proxies and their names.
+const CONNECT_FAILURES: [(ConnectFailure, &str); 5] = [
+    (ConnectFailure::Auth, "auth"),
+    (ConnectFailure::Method, "method"),
+    (ConnectFailure::Reply, "reply"),
+    (ConnectFailure::Network, "network"),
+    (ConnectFailure::Other, "other"),
+];
+
+/// Represents a client of a StatsD server. Counters are emitted as the increase since the last
+/// emission, and gauges as the current value.
+pub struct Client {
+    prefix: String,
+    is_dogstatsd: bool,
+    counters: HashMap<String, u64>,
+}
+
+impl Client {
+    /// Creates a new `Client`. Failures connecting through proxies are tagged by their
+    /// kinds in DogStatsD, or are emitted in a metric of each kind otherwise.
+    pub fn new(prefix: &str, is_dogstatsd: bool) -> Client {
+        Client {
+            prefix: prefix.to_string(),
+            is_dogstatsd,
+            counters: HashMap::new(),
+        }
+    }
+
+    /// Returns the lines of metrics of the statistics and the count of TCP connections.
+    fn lines(&mut self, stats: &Stats, tcp_connections: usize) -> Vec<String> {
+        let mut lines = Vec::new();
+
+        // Gauges
+        for &(name, value) in &[
+            ("tcp.connections", tcp_connections),
+            ("udp.bindings", stats.udp_bindings()),
+            ("quic.sessions", stats.quic_sessions()),
+        ] {
+            lines.push(format!("{}.{}:{}|g", self.prefix, name, value));
+        }
+
+        // Counters
+        let mut counters = vec![
+            ("rx_bytes".to_string(), stats.rx_bytes(), None),
+            ("tx_bytes".to_string(), stats.tx_bytes(), None),
+            (
+                "tcp.retrans_segments".to_string(),
+                stats.tcp_retrans_segments(),
+                None,
+            ),
+            (
+                "tcp.retrans_bytes".to_string(),
+                stats.tcp_retrans_bytes(),
+                None,
+            ),
+            (
+                "tcp.fast_retransmits".to_string(),
+                stats.tcp_fast_retransmits(),
+                None,
+            ),
+            (
+                "tcp.rto_retransmits".to_string(),
+                stats.tcp_rto_retransmits(),
+                None,
+            ),
+        ];
+        for &(failure, kind) in &CONNECT_FAILURES {
+            let value = stats.connect_failures(failure);
+            if self.is_dogstatsd {
+                counters.push(("socks.errors".to_string(), value, Some(kind)));
+            } else {
+                counters.push((format!("socks.errors.{}", kind), value, None));
+            }
+        }
+        for (name, value, kind) in counters {
+            let key = match kind {
+                Some(kind) => format!("{}#{}", name, kind),
+                None => name.clone(),
+            };
+            let prev = self.counters.insert(key, value).unwrap_or(0);
+            let delta = value.saturating_sub(prev);
+            if delta == 0 {
+                continue;
+            }
+            match kind {
+                Some(kind) => lines.push(format!(
+                    "{}.{}:{}|c|#kind:{}",
+                    self.prefix, name, delta, kind
+                )),
+                None => lines.push(format!("{}.{}:{}|c", self.prefix, name, delta)),
+            }
+        }
+
+        lines
+    }
+
+    /// Emits the metrics of the statistics and the count of TCP connections to the server.
+    pub async fn emit(
+        &mut self,
+        socket: &mut UdpSocket,
+        stats: &Stats,
+        tcp_connections: usize,
+    ) -> io::Result<()> {
+        for datagram in pack(&self.lines(stats, tcp_connections)) {
+            socket.send(datagram.as_bytes()).await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Packs lines into datagrams separated by newlines.
+fn pack(lines: &[String]) -> Vec<String> {
+    let mut datagrams = Vec::new();
+    let mut datagram = String::new();
+    for line in lines {
+        if !datagram.is_empty() && datagram.len() + 1 + line.len() > MAX_DATAGRAM_SIZE {
+            datagrams.push(datagram);
+            datagram = String::new();
+        }
+        if !datagram.is_empty() {
+            datagram.push('\n');
+        }
+        datagram.push_str(line);
+    }
+    if !datagram.is_empty() {
+        datagrams.push(datagram);
+    }
+
+    datagrams
+}
+
+/// Emits the statistics of the controller to the StatsD server at the interval in milliseconds.
+/// Failures of emissions are logged and the metrics are emitted again in the next interval.
+pub async fn emit(
+    controller: Controller,
+    server: SocketAddr,
+    mut client: Client,
+    interval: u64,
+) -> io::Result<()> {
+    let bind_addr: SocketAddr = match server {
+        SocketAddr::V4(_) => "0.0.0.0:0".parse().unwrap(),
+        SocketAddr::V6(_) => "[::]:0".parse().unwrap(),
+    };
+    let mut socket = UdpSocket::bind(bind_addr).await?;
+    socket.connect(server).await?;
+
+    let mut interval = time::interval(Duration::from_millis(interval));
+    loop {
+        interval.tick().await;
+
+        let tcp_connections = controller.connections().await?.len();
+        let stats = controller.stats();
+        if let Err(ref e) = client.emit(&mut socket, &stats, tcp_connections).await {
+            warn!("emit to StatsD server {}: {}", server, e);
+        }
+    }
+}
+
+#[test]
+fn statsd_client_lines() {
+    let stats = Stats::new();
+    stats.add_rx_bytes(1000);
+    stats.increase_connect_failures(ConnectFailure::Auth);
+
+    let mut client = Client::new("p2s", false);
+    let lines = client.lines(&stats, 2);
+    assert!(lines.contains(&"p2s.tcp.connections:2|g".to_string()));
+    assert!(lines.contains(&"p2s.rx_bytes:1000|c".to_string()));
+    assert!(lines.contains(&"p2s.socks.errors.auth:1|c".to_string()));
+    assert!(!lines.iter().any(|line| line.starts_with("p2s.tx_bytes")));
+
+    // Counters are deltas
+    stats.add_rx_bytes(500);
+    let lines = client.lines(&stats, 2);
+    assert!(lines.contains(&"p2s.rx_bytes:500|c".to_string()));
+    assert!(!lines.iter().any(|line| line.contains("socks.errors")));
+
+    // DogStatsD tags
+    let mut client = Client::new("p2s", true);
+    let lines = client.lines(&stats, 0);
+    assert!(lines.contains(&"p2s.socks.errors:1|c|#kind:auth".to_string()));
+
+    let datagrams = pack(&vec!["a".repeat(1000), "b".repeat(400), "c".repeat(100)]);
+    assert_eq!(datagrams.len(), 2);
+    assert_eq!(datagrams[0].len(), 1401);
+}
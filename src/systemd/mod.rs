@@ -0,0 +1,120 @@
+//! Support for integrating with systemd.
+
+use log::trace;
+use std::env;
+use std::io;
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::{Duration, Instant};
+
+/// Represents a notifier which notifies systemd of the state of the service.
+#[derive(Debug)]
+pub struct Notifier {
+    socket: UnixDatagram,
+    path: PathBuf,
+    /// Represents the interval of feeding the watchdog, which is half of the watchdog timeout.
+    watchdog: Option<Duration>,
+    watchdog_instant: Instant,
+}
+
+impl Notifier {
+    /// Creates a new `Notifier` from the environment variables `NOTIFY_SOCKET` and
+    /// `WATCHDOG_USEC`. Returns `None` if the service is not supervised by systemd.
+    pub fn from_env() -> Option<Notifier> {
+        let path = env::var_os("NOTIFY_SOCKET")?;
+        let mut path = PathBuf::from(path);
+        // Abstract socket
+        if let Some(name) = path.to_str().and_then(|path| path.strip_prefix('@')) {
+            path = PathBuf::from(format!("\0{}", name));
+        }
+
+        let is_watched = match env::var("WATCHDOG_PID") {
+            Ok(pid) => pid.parse::<u32>().ok() == Some(process::id()),
+            Err(_) => true,
+        };
+        let watchdog = match env::var("WATCHDOG_USEC") {
+            Ok(usec) if is_watched => usec
+                .parse::<u64>()
+                .ok()
+                .filter(|usec| *usec > 0)
+                .map(|usec| Duration::from_micros(usec / 2)),
+            _ => None,
+        };
+
+        Notifier::new(path, watchdog).ok()
+    }
+
+    fn new<P: AsRef<Path>>(path: P, watchdog: Option<Duration>) -> io::Result<Notifier> {
+        Ok(Notifier {
+            socket: UnixDatagram::unbound()?,
+            path: path.as_ref().to_path_buf(),
+            watchdog,
+            watchdog_instant: Instant::now(),
+        })
+    }
+
+    /// Creates a new independently owned handle to the same notifier.
+    pub fn try_clone(&self) -> io::Result<Notifier> {
+        Ok(Notifier {
+            socket: self.socket.try_clone()?,
+            path: self.path.clone(),
+            watchdog: self.watchdog,
+            watchdog_instant: self.watchdog_instant,
+        })
+    }
+
+    /// Notifies that the service is ready with the status.
+    pub fn ready(&self, status: &str) -> io::Result<()> {
+        self.notify(&format!("READY=1\nSTATUS={}", status))
+    }
+
+    /// Notifies the status of the service.
+    pub fn status(&self, status: &str) -> io::Result<()> {
+        self.notify(&format!("STATUS={}", status))
+    }
+
+    /// Notifies that the service is stopping with the status.
+    pub fn stopping(&self, status: &str) -> io::Result<()> {
+        self.notify(&format!("STOPPING=1\nSTATUS={}", status))
+    }
+
+    /// Feeds the watchdog if it is due. The watchdog is fed at half of its timeout.
+    pub fn watchdog(&mut self) -> io::Result<()> {
+        match self.watchdog {
+            Some(interval) if self.watchdog_instant.elapsed() >= interval => {
+                self.watchdog_instant = Instant::now();
+
+                self.notify("WATCHDOG=1")
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn notify(&self, state: &str) -> io::Result<()> {
+        trace!("notify systemd {}", state.replace('\n', ", "));
+
+        self.socket.send_to(state.as_bytes(), &self.path)?;
+
+        Ok(())
+    }
+}
+
+#[test]
+fn notifier_notify() {
+    let path = env::temp_dir().join(format!("pcap2socks-notify-{}", process::id()));
+    let _ = std::fs::remove_file(&path);
+    let socket = UnixDatagram::bind(&path).unwrap();
+    let mut buffer = [0u8; 64];
+
+    let mut notifier = Notifier::new(&path, Some(Duration::from_millis(0))).unwrap();
+    notifier.ready("running").unwrap();
+    let size = socket.recv(&mut buffer).unwrap();
+    assert_eq!(&buffer[..size], b"READY=1\nSTATUS=running");
+
+    notifier.watchdog().unwrap();
+    let size = socket.recv(&mut buffer).unwrap();
+    assert_eq!(&buffer[..size], b"WATCHDOG=1");
+
+    std::fs::remove_file(&path).unwrap();
+}
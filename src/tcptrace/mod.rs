@@ -0,0 +1,326 @@
+//! Support for keeping a compact trace of the protocol events of each TCP connection, including
+//! state transitions, sequence numbers, acknowledgements and windows of segments, and timers
+//! fired, so reports like stalled downloads can be diagnosed.
+//!
+//! Traces are dumped in plain text to the directory when the connections are closed, or on
+//! demand. Sequence numbers and acknowledgements are relative, and each line starts with the time
+//! in a fixed-width column, so traces of different runs can be compared with `diff` after the
+//! first column is cut.
+
+use log::{info, warn};
+use lru::LruCache;
+use std::collections::VecDeque;
+use std::fmt::{self, Display, Formatter};
+use std::fs::{self, File};
+use std::io::Write;
+use std::net::SocketAddrV4;
+use std::path::PathBuf;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::clock;
+use crate::packet::layer::tcp::Tcp;
+
+/// Represents the max count of TCP connections whose traces are kept.
+const TRACE_CAPACITY: usize = 1024;
+/// Represents the max count of lines kept of each TCP connection. Older lines are dropped.
+const MAX_LINES: usize = 4096;
+
+/// Represents the state of a TCP connection observed from its segments.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum State {
+    Listen,
+    SynReceived,
+    Established,
+    FinWait,
+    CloseWait,
+    LastAck,
+    TimeWait,
+    Closed,
+}
+
+impl Display for State {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let s = match self {
+            State::Listen => "LISTEN",
+            State::SynReceived => "SYN-RECEIVED",
+            State::Established => "ESTABLISHED",
+            State::FinWait => "FIN-WAIT",
+            State::CloseWait => "CLOSE-WAIT",
+            State::LastAck => "LAST-ACK",
+            State::TimeWait => "TIME-WAIT",
+            State::Closed => "CLOSED",
+        };
+
+        write!(f, "{}", s)
+    }
+}
+
+impl State {
+    /// Returns the state after a segment, which is either received from or sent to the source.
+    fn next(self, tcp: &Tcp, is_inbound: bool) -> State {
+        if tcp.is_rst() {
+            return State::Closed;
+        }
+        match (self, is_inbound) {
+            (State::Listen, true) if tcp.is_syn() => State::SynReceived,
+            (State::SynReceived, true) if tcp.is_ack() && !tcp.is_syn() => {
+                if tcp.is_fin() {
+                    State::CloseWait
+                } else {
+                    State::Established
+                }
+            }
+            (State::Established, true) if tcp.is_fin() => State::CloseWait,
+            (State::Established, false) if tcp.is_fin() => State::FinWait,
+            (State::FinWait, true) if tcp.is_fin() => State::TimeWait,
+            (State::CloseWait, false) if tcp.is_fin() => State::LastAck,
+            (State::LastAck, true) if tcp.is_ack() => State::Closed,
+            (state, _) => state,
+        }
+    }
+}
+
+/// Represents an event of a TCP connection which is not a segment.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) enum Event {
+    /// Represents a retransmission timer fired, with the RTO in milliseconds and the count of
+    /// retransmissions due to timeout.
+    Rto(u64, u64),
+    /// Represents a fast retransmission.
+    FastRetransmit,
+    /// Represents a zero window probe timer fired.
+    Probe,
+    /// Represents the connection closed.
+    Close,
+}
+
+impl Display for Event {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Event::Rto(rto, retries) => {
+                write!(f, "timer RTO, RTO = {} ms, Retries = {}", rto, retries)
+            }
+            Event::FastRetransmit => write!(f, "fast retransmit"),
+            Event::Probe => write!(f, "timer probe"),
+            Event::Close => write!(f, "close"),
+        }
+    }
+}
+
+/// Represents the trace of a TCP connection.
+struct Trace {
+    instant: Instant,
+    state: State,
+    /// Represents the initial sequence numbers of the source and of the destination.
+    isns: (Option<u32>, Option<u32>),
+    lines: VecDeque<String>,
+    dropped: usize,
+}
+
+impl Trace {
+    fn new() -> Trace {
+        Trace {
+            instant: clock::now(),
+            state: State::Listen,
+            isns: (None, None),
+            lines: VecDeque::new(),
+            dropped: 0,
+        }
+    }
+
+    fn push(&mut self, line: String) {
+        if self.lines.len() >= MAX_LINES {
+            self.lines.pop_front();
+            self.dropped += 1;
+        }
+        let elapsed = clock::elapsed(self.instant);
+        self.lines.push_back(format!(
+            "{:>6}.{:03} {}",
+            elapsed.as_secs(),
+            elapsed.subsec_millis(),
+            line
+        ));
+    }
+
+    fn segment(&mut self, tcp: &Tcp, len: usize, is_inbound: bool) {
+        let (isn, peer_isn) = if is_inbound {
+            (&mut self.isns.0, self.isns.1)
+        } else {
+            (&mut self.isns.1, self.isns.0)
+        };
+        if tcp.is_syn() || isn.is_none() {
+            *isn = Some(tcp.sequence());
+        }
+        let sequence = tcp.sequence().wrapping_sub(isn.unwrap());
+        let acknowledgement = match (tcp.is_ack(), peer_isn) {
+            (true, Some(isn)) => tcp.acknowledgement().wrapping_sub(isn),
+            _ => tcp.acknowledgement(),
+        };
+        self.push(format!(
+            "{} {} Seq = {}, Ack = {}, Window = {}, Length = {}",
+            if is_inbound { "rx" } else { "tx" },
+            tcp.flag_string(),
+            sequence,
+            acknowledgement,
+            tcp.window(),
+            len
+        ));
+
+        let state = self.state.next(tcp, is_inbound);
+        if state != self.state {
+            self.push(format!("state {} -> {}", self.state, state));
+            self.state = state;
+        }
+    }
+
+    fn format(&self, src: SocketAddrV4, dst: SocketAddrV4) -> String {
+        let mut s = format!("# TCP {} -> {}\n", src, dst);
+        if self.dropped > 0 {
+            s.push_str(&format!("# {} lines dropped\n", self.dropped));
+        }
+        for line in &self.lines {
+            s.push_str(line);
+            s.push('\n');
+        }
+
+        s
+    }
+}
+
+/// Represents a tracer keeping the trace of each TCP connection, and dumping it to the directory
+/// when the connection is closed.
+pub(crate) struct Tracer {
+    dir: PathBuf,
+    traces: LruCache<(SocketAddrV4, SocketAddrV4), Trace>,
+}
+
+impl Tracer {
+    /// Creates a new `Tracer` dumping traces to the directory.
+    pub(crate) fn new(dir: PathBuf) -> Tracer {
+        Tracer {
+            dir,
+            traces: LruCache::new(TRACE_CAPACITY),
+        }
+    }
+
+    fn get_mut(&mut self, src: SocketAddrV4, dst: SocketAddrV4) -> &mut Trace {
+        let key = (src, dst);
+        if !self.traces.contains(&key) {
+            self.traces.put(key, Trace::new());
+        }
+
+        self.traces.get_mut(&key).unwrap()
+    }
+
+    /// Traces a segment with the payload size of the TCP connection from the source to the
+    /// destination, which is either received from or sent to the source.
+    pub(crate) fn segment(
+        &mut self,
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+        tcp: &Tcp,
+        len: usize,
+        is_inbound: bool,
+    ) {
+        self.get_mut(src, dst).segment(tcp, len, is_inbound);
+    }
+
+    /// Traces an event of the TCP connection from the source to the destination.
+    pub(crate) fn event(&mut self, src: SocketAddrV4, dst: SocketAddrV4, event: Event) {
+        if let Some(trace) = self.traces.get_mut(&(src, dst)) {
+            trace.push(event.to_string());
+        }
+    }
+
+    /// Returns the trace of the TCP connection from the source to the destination.
+    pub(crate) fn get(&mut self, src: SocketAddrV4, dst: SocketAddrV4) -> Option<String> {
+        self.traces
+            .peek(&(src, dst))
+            .map(|trace| trace.format(src, dst))
+    }
+
+    /// Closes the trace of the TCP connection from the source to the destination, and dumps it
+    /// to the directory.
+    pub(crate) fn close(&mut self, src: SocketAddrV4, dst: SocketAddrV4) {
+        let mut trace = match self.traces.pop(&(src, dst)) {
+            Some(trace) => trace,
+            None => return,
+        };
+        trace.push(Event::Close.to_string());
+
+        let secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let name = format!("{}-{}-{}.trace", secs, src, dst).replace(':', "_");
+        let path = self.dir.join(name);
+
+        let result = fs::create_dir_all(&self.dir)
+            .and_then(|_| File::create(&path))
+            .and_then(|mut file| file.write_all(trace.format(src, dst).as_bytes()));
+        match result {
+            Ok(_) => info!(
+                "Dump trace of {} lines to {}",
+                trace.lines.len(),
+                path.display()
+            ),
+            Err(ref e) => warn!("dump {}: {}", path.display(), e),
+        }
+    }
+}
+
+#[test]
+fn tracer_close() {
+    use crate::packet::Indicator;
+    use crate::testing::FrameBuilder;
+
+    let dir = std::env::temp_dir().join(format!("pcap2socks-tcptrace-{}", std::process::id()));
+    let src = "10.6.0.2:50000".parse().unwrap();
+    let dst = "1.1.1.1:443".parse().unwrap();
+    let builder = FrameBuilder::new(src, dst);
+
+    let mut tracer = Tracer::new(dir.clone());
+    let mut trace = |frame: Vec<u8>, is_inbound| {
+        let indicator = Indicator::from(&frame).unwrap();
+        let len = indicator.content_len() - indicator.len();
+        tracer.segment(src, dst, indicator.tcp().unwrap(), len, is_inbound);
+    };
+    trace(builder.syn(1000), true);
+    trace(builder.reverse().syn_ack(5000, 1001), false);
+    trace(builder.ack(1001, 5001, b""), true);
+    trace(builder.reverse().ack(5001, 1001, b"hello"), false);
+    tracer.event(src, dst, Event::Rto(400, 1));
+
+    let s = tracer.get(src, dst).unwrap();
+    assert!(s.starts_with("# TCP 10.6.0.2:50000 -> 1.1.1.1:443\n"));
+    let lines = s
+        .lines()
+        .skip(1)
+        .map(|line| line[11..].to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        lines,
+        vec![
+            "rx [S] Seq = 0, Ack = 0, Window = 65535, Length = 0",
+            "state LISTEN -> SYN-RECEIVED",
+            "tx [S.] Seq = 0, Ack = 1, Window = 65535, Length = 0",
+            "rx [.] Seq = 1, Ack = 1, Window = 65535, Length = 0",
+            "state SYN-RECEIVED -> ESTABLISHED",
+            "tx [.] Seq = 1, Ack = 1, Window = 65535, Length = 5",
+            "timer RTO, RTO = 400 ms, Retries = 1",
+        ]
+    );
+
+    tracer.close(src, dst);
+    assert!(tracer.get(src, dst).is_none());
+    let entries = fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect::<Vec<_>>();
+    let _ = fs::remove_dir_all(&dir);
+    assert_eq!(entries.len(), 1);
+    assert!(entries[0]
+        .to_str()
+        .unwrap()
+        .ends_with("-10.6.0.2_50000-1.1.1.1_443.trace"));
+}
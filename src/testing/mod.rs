@@ -0,0 +1,400 @@
+//! Support for crafting frames in tests, so protocol scenarios can be scripted concisely and
+//! injected into a `Redirector`, like through a `pcap::Loopback`.
+//!
+//! Frames are serialized with valid checksums, and are padded to the minimum size of Ethernet
+//! frames like frames captured from real NICs.
+
+use ipnetwork::Ipv4Network;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::{Arc, Mutex};
+
+#[cfg(all(target_os = "linux", feature = "conformance"))]
+mod netns;
+mod socks;
+#[cfg(all(target_os = "linux", feature = "conformance"))]
+pub use netns::{Netns, GW_IP_ADDR, LOCAL_IP_ADDR, PEER_IP_ADDR};
+pub use socks::{Fault, MockSocks};
+
+use crate::packet::layer::ethernet::Ethernet;
+use crate::packet::layer::icmpv4::Icmpv4;
+use crate::packet::layer::ipv4::Ipv4;
+use crate::packet::layer::tcp::Tcp;
+use crate::packet::layer::udp::Udp;
+use crate::packet::layer::{Layer, LayerKind, LayerKinds, Layers};
+use crate::packet::Indicator;
+use crate::pcap::{self, HardwareAddr, Loopback, Receiver, Sender};
+use crate::{Config, Forwarder, Redirector};
+
+/// Represents the default hardware address of the source of frames.
+pub const SRC_HARDWARE_ADDR: HardwareAddr =
+    pnet::datalink::MacAddr(0x02, 0x00, 0x00, 0x00, 0x00, 0x02);
+
+/// Represents the default hardware address of the destination of frames.
+pub const DST_HARDWARE_ADDR: HardwareAddr =
+    pnet::datalink::MacAddr(0x02, 0x00, 0x00, 0x00, 0x00, 0x01);
+
+/// Represents the min size of Ethernet frames without the FCS.
+const MINIMUM_FRAME_SIZE: usize = 60;
+
+/// Represents a builder of Ethernet frames from a source to a destination. The options of TCP
+/// only apply to the frames they are valid in, like the MSS only applies to TCP SYNs.
+#[derive(Clone, Debug)]
+pub struct FrameBuilder {
+    src: SocketAddrV4,
+    dst: SocketAddrV4,
+    src_hardware_addr: HardwareAddr,
+    dst_hardware_addr: HardwareAddr,
+    identification: u16,
+    window: u16,
+    mss: Option<u16>,
+    wscale: Option<u8>,
+    sack_perm: bool,
+    sacks: Option<Vec<(u32, u32)>>,
+    ts: Option<(u32, u32)>,
+}
+
+impl FrameBuilder {
+    /// Creates a new `FrameBuilder` of frames from the source to the destination, with a window
+    /// of 65535 and no options.
+    pub fn new(src: SocketAddrV4, dst: SocketAddrV4) -> FrameBuilder {
+        FrameBuilder {
+            src,
+            dst,
+            src_hardware_addr: SRC_HARDWARE_ADDR,
+            dst_hardware_addr: DST_HARDWARE_ADDR,
+            identification: 0,
+            window: u16::MAX,
+            mss: None,
+            wscale: None,
+            sack_perm: false,
+            sacks: None,
+            ts: None,
+        }
+    }
+
+    /// Sets the hardware addresses of the source and the destination.
+    pub fn hardware_addrs(mut self, src: HardwareAddr, dst: HardwareAddr) -> FrameBuilder {
+        self.src_hardware_addr = src;
+        self.dst_hardware_addr = dst;
+
+        self
+    }
+
+    /// Sets the IPv4 identification.
+    pub fn identification(mut self, identification: u16) -> FrameBuilder {
+        self.identification = identification;
+
+        self
+    }
+
+    /// Sets the TCP window.
+    pub fn window(mut self, window: u16) -> FrameBuilder {
+        self.window = window;
+
+        self
+    }
+
+    /// Sets the TCP MSS option in TCP SYNs.
+    pub fn mss(mut self, mss: u16) -> FrameBuilder {
+        self.mss = Some(mss);
+
+        self
+    }
+
+    /// Sets the TCP window scale option in TCP SYNs.
+    pub fn wscale(mut self, wscale: u8) -> FrameBuilder {
+        self.wscale = Some(wscale);
+
+        self
+    }
+
+    /// Sets the TCP SACK permitted option in TCP SYNs.
+    pub fn sack_perm(mut self, sack_perm: bool) -> FrameBuilder {
+        self.sack_perm = sack_perm;
+
+        self
+    }
+
+    /// Sets the TCP SACK option in TCP ACKs.
+    pub fn sacks(mut self, sacks: Vec<(u32, u32)>) -> FrameBuilder {
+        self.sacks = Some(sacks);
+
+        self
+    }
+
+    /// Sets the TCP timestamps option.
+    pub fn ts(mut self, ts: u32, ts_ecr: u32) -> FrameBuilder {
+        self.ts = Some((ts, ts_ecr));
+
+        self
+    }
+
+    /// Returns a `FrameBuilder` of frames in the reverse direction with the same options.
+    pub fn reverse(&self) -> FrameBuilder {
+        let mut builder = self.clone();
+        builder.src = self.dst;
+        builder.dst = self.src;
+        builder.src_hardware_addr = self.dst_hardware_addr;
+        builder.dst_hardware_addr = self.src_hardware_addr;
+
+        builder
+    }
+
+    /// Returns a TCP SYN.
+    pub fn syn(&self, sequence: u32) -> Vec<u8> {
+        let tcp = Tcp::new_syn(
+            self.src.port(),
+            self.dst.port(),
+            sequence,
+            self.window,
+            self.mss,
+            self.wscale,
+            self.sack_perm,
+            self.ts,
+        );
+
+        self.tcp(tcp, &[])
+    }
+
+    /// Returns a TCP ACK/SYN.
+    pub fn syn_ack(&self, sequence: u32, acknowledgement: u32) -> Vec<u8> {
+        let tcp = Tcp::new_ack_syn(
+            self.src.port(),
+            self.dst.port(),
+            sequence,
+            acknowledgement,
+            self.window,
+            self.mss,
+            self.wscale,
+            self.sack_perm,
+            self.ts,
+        );
+
+        self.tcp(tcp, &[])
+    }
+
+    /// Returns a TCP ACK with the payload.
+    pub fn ack(&self, sequence: u32, acknowledgement: u32, payload: &[u8]) -> Vec<u8> {
+        let tcp = Tcp::new_ack(
+            self.src.port(),
+            self.dst.port(),
+            sequence,
+            acknowledgement,
+            self.window,
+            self.sacks.clone(),
+            self.ts,
+        );
+
+        self.tcp(tcp, payload)
+    }
+
+    /// Returns a TCP ACK/FIN.
+    pub fn fin(&self, sequence: u32, acknowledgement: u32) -> Vec<u8> {
+        let tcp = Tcp::new_ack_fin(
+            self.src.port(),
+            self.dst.port(),
+            sequence,
+            acknowledgement,
+            self.window,
+            self.ts,
+        );
+
+        self.tcp(tcp, &[])
+    }
+
+    /// Returns a TCP RST.
+    pub fn rst(&self, sequence: u32) -> Vec<u8> {
+        let tcp = Tcp::new_rst(
+            self.src.port(),
+            self.dst.port(),
+            sequence,
+            0,
+            self.window,
+            self.ts,
+        );
+
+        self.tcp(tcp, &[])
+    }
+
+    /// Returns a UDP datagram with the payload.
+    pub fn udp(&self, payload: &[u8]) -> Vec<u8> {
+        let mut udp = Udp::new(self.src.port(), self.dst.port());
+        let ipv4 = self.ipv4(LayerKinds::Udp);
+        udp.set_ipv4_layer(&ipv4);
+
+        self.serialize(Layers::Ipv4(ipv4), Some(Layers::Udp(udp)), payload)
+    }
+
+    /// Returns the IPv4 fragments of a UDP datagram with the payload, where each fragment carries
+    /// at most `size` bytes of the datagram, which is rounded down to a multiple of 8.
+    pub fn udp_fragments(&self, payload: &[u8], size: usize) -> Vec<Vec<u8>> {
+        let size = (size / 8 * 8).max(8);
+
+        // The whole datagram, in which the UDP checksum covers the payload
+        let mut udp = Udp::new(self.src.port(), self.dst.port());
+        udp.set_ipv4_layer(&self.ipv4(LayerKinds::Udp));
+        let len = udp.len() + payload.len();
+        let mut datagram = vec![0u8; len];
+        udp.serialize_with_payload(&mut datagram, payload, len)
+            .unwrap();
+
+        datagram
+            .chunks(size)
+            .enumerate()
+            .map(|(i, chunk)| {
+                let fragment_offset = (i * size / 8) as u16;
+                let is_last = (i + 1) * size >= datagram.len();
+                let ipv4 = if is_last {
+                    Ipv4::new_last_fragment(
+                        self.identification,
+                        LayerKinds::Udp,
+                        fragment_offset,
+                        *self.src.ip(),
+                        *self.dst.ip(),
+                    )
+                } else {
+                    Ipv4::new_more_fragment(
+                        self.identification,
+                        LayerKinds::Udp,
+                        fragment_offset,
+                        *self.src.ip(),
+                        *self.dst.ip(),
+                    )
+                }
+                .unwrap();
+
+                self.serialize(Layers::Ipv4(ipv4), None, chunk)
+            })
+            .collect()
+    }
+
+    fn ipv4(&self, t: LayerKind) -> Ipv4 {
+        Ipv4::new(self.identification, t, *self.src.ip(), *self.dst.ip()).unwrap()
+    }
+
+    /// Returns an ICMPv4 echo request with the data from the IP address of the source to the IP
+    /// address of the destination.
+    pub fn icmpv4_echo(&self, identifier: u16, sequence_number: u16, data: &[u8]) -> Vec<u8> {
+        let icmpv4 = Icmpv4::new_echo_request(identifier, sequence_number, data);
+
+        self.serialize(
+            Layers::Ipv4(self.ipv4(LayerKinds::Icmpv4)),
+            Some(Layers::Icmpv4(icmpv4)),
+            &[],
+        )
+    }
+
+    fn tcp(&self, mut tcp: Tcp, payload: &[u8]) -> Vec<u8> {
+        let ipv4 = self.ipv4(LayerKinds::Tcp);
+        tcp.set_ipv4_layer(&ipv4);
+
+        self.serialize(Layers::Ipv4(ipv4), Some(Layers::Tcp(tcp)), payload)
+    }
+
+    fn serialize(&self, network: Layers, transport: Option<Layers>, payload: &[u8]) -> Vec<u8> {
+        let ethernet = Ethernet::new(
+            network.kind(),
+            self.src_hardware_addr,
+            self.dst_hardware_addr,
+        )
+        .unwrap();
+        let indicator = Indicator::new(Layers::Ethernet(ethernet), Some(network), transport);
+
+        let size = indicator.len() + payload.len();
+        let mut buffer = vec![0u8; size.max(MINIMUM_FRAME_SIZE)];
+        indicator
+            .serialize_with_payload(&mut buffer[..size], payload)
+            .unwrap();
+
+        buffer
+    }
+}
+
+/// Creates a new `Forwarder` of the gateway 10.6.0.254 sending frames to the send half, which
+/// knows the hardware address of the source 10.6.0.1 is `SRC_HARDWARE_ADDR`.
+pub fn forwarder(tx: Sender) -> Forwarder {
+    let mut forwarder = Forwarder::new(tx, 1500, DST_HARDWARE_ADDR, Ipv4Addr::new(10, 6, 0, 254));
+    forwarder.set_src_hardware_addr(Ipv4Addr::new(10, 6, 0, 1), SRC_HARDWARE_ADDR);
+
+    forwarder
+}
+
+/// Creates a new `Redirector` of the gateway 10.6.0.254 in the network 10.6.0.0/24 sending frames
+/// to the send half, which redirects traffic to the SOCKS proxy 127.0.0.1:1080.
+pub fn redirector_with_sender(tx: Sender, config: Config) -> Redirector {
+    Redirector::new(
+        Arc::new(Mutex::new(forwarder(tx))),
+        Ipv4Network::new(Ipv4Addr::new(10, 6, 0, 0), 24).unwrap(),
+        Ipv4Addr::new(10, 6, 0, 254),
+        None,
+        SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1080),
+        false,
+        false,
+        None,
+        config,
+    )
+}
+
+/// Creates a new `Redirector` like `redirector_with_sender` on an in-memory pcap device, and
+/// returns the `Redirector`, the receive half and the other end of the device.
+pub fn redirector(config: Config) -> (Redirector, Receiver, Loopback) {
+    let (tx, rx, loopback) = pcap::memory();
+
+    (redirector_with_sender(tx, config), rx, loopback)
+}
+
+#[test]
+fn frame_builder_build() {
+    use pnet::packet::ipv4;
+
+    let src = "10.6.0.1:50000".parse().unwrap();
+    let dst = "1.1.1.1:80".parse().unwrap();
+    let builder = FrameBuilder::new(src, dst)
+        .mss(1460)
+        .wscale(7)
+        .sack_perm(true);
+
+    let syn = Indicator::from(&builder.syn(1000)).unwrap();
+    let tcp = syn.tcp().unwrap();
+    assert!(tcp.is_syn() && !tcp.is_ack());
+    assert_eq!(tcp.sequence(), 1000);
+    assert_eq!(tcp.mss(), Some(1460));
+    assert_eq!(tcp.wscale(), Some(7));
+    assert!(tcp.is_sack_perm());
+    assert_eq!(syn.ethernet().unwrap().src(), SRC_HARDWARE_ADDR);
+
+    let frame = builder.reverse().ack(1, 1001, b"hello");
+    let ack = Indicator::from(&frame).unwrap();
+    let tcp = ack.tcp().unwrap();
+    assert_eq!(tcp.src(), 80);
+    assert_eq!(tcp.acknowledgement(), 1001);
+    assert_eq!(&frame[ack.len()..ack.len() + 5], b"hello");
+    assert!(Indicator::from(&builder.fin(1001, 1))
+        .unwrap()
+        .tcp()
+        .unwrap()
+        .is_ack_fin());
+    assert!(Indicator::from(&builder.rst(1001))
+        .unwrap()
+        .tcp()
+        .unwrap()
+        .is_rst());
+
+    // Valid checksums
+    let frame = builder.udp(b"hello");
+    let packet = pnet::packet::ipv4::Ipv4Packet::new(&frame[14..]).unwrap();
+    assert_eq!(packet.get_checksum(), ipv4::checksum(&packet));
+    let udp = pnet::packet::udp::UdpPacket::new(&frame[34..47]).unwrap();
+    assert_eq!(
+        udp.get_checksum(),
+        pnet::packet::udp::ipv4_checksum(&udp, &src.ip(), &dst.ip())
+    );
+
+    // 8 bytes of the UDP header and 20 bytes of payload in 16 and 12 bytes
+    let fragments = builder.udp_fragments(&[0u8; 20], 20);
+    assert_eq!(fragments.len(), 2);
+    let last = Indicator::from(&fragments[1]).unwrap();
+    let ipv4 = last.ipv4().unwrap();
+    assert!(ipv4.is_fragment() && !ipv4.is_more_fragment());
+    assert_eq!(ipv4.fragment_offset(), 2);
+}
@@ -0,0 +1,410 @@
+//! Support for running the stack against the TCP stack of Linux in a network namespace, so the
+//! interoperability with a real peer, like MSS, SACK, window scaling and FIN handling, can be
+//! verified.
+//!
+//! A `Netns` creates a network namespace connected to the current namespace through a veth pair.
+//! The stack opens the end in the current namespace with the in-crate raw backend, and tools like
+//! curl and iperf3 run in the namespace as the source. Creating namespaces requires root, so the
+//! tests are ignored by default.
+
+use std::fs;
+use std::io;
+use std::net::Ipv4Addr;
+use std::process::{self, Command, Output};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::pcap::{self, Interface};
+
+/// Represents the IP address of the peer in the namespace.
+pub const PEER_IP_ADDR: Ipv4Addr = Ipv4Addr::new(10, 213, 0, 1);
+/// Represents the IP address of the end of the veth pair in the current namespace.
+pub const LOCAL_IP_ADDR: Ipv4Addr = Ipv4Addr::new(10, 213, 0, 253);
+/// Represents the IP address the stack publishes as the gateway of the peer.
+pub const GW_IP_ADDR: Ipv4Addr = Ipv4Addr::new(10, 213, 0, 254);
+/// Represents the prefix of the network of the veth pair.
+const PREFIX: u8 = 24;
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Represents a network namespace connected to the current namespace through a veth pair. The
+/// namespace and the veth pair are deleted when it is dropped.
+#[derive(Debug)]
+pub struct Netns {
+    name: String,
+    veth: String,
+    mtu: usize,
+}
+
+impl Netns {
+    /// Creates a new `Netns` whose veth pair has the MTU. The peer in the namespace routes all
+    /// traffic to the gateway published by the stack.
+    pub fn new(mtu: usize) -> io::Result<Netns> {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let veth = format!("p2s{}n{}", process::id() % 100000, id);
+        let netns = Netns {
+            name: veth.clone(),
+            veth,
+            mtu,
+        };
+        let peer = format!("{}p", netns.veth);
+        let mtu = mtu.to_string();
+        let local = format!("{}/{}", LOCAL_IP_ADDR, PREFIX);
+        let remote = format!("{}/{}", PEER_IP_ADDR, PREFIX);
+        let gw = GW_IP_ADDR.to_string();
+
+        run("ip", &["netns", "add", &netns.name])?;
+        run(
+            "ip",
+            &[
+                "link",
+                "add",
+                &netns.veth,
+                "mtu",
+                &mtu,
+                "type",
+                "veth",
+                "peer",
+                "name",
+                &peer,
+                "mtu",
+                &mtu,
+                "netns",
+                &netns.name,
+            ],
+        )?;
+        run("ip", &["addr", "add", &local, "dev", &netns.veth])?;
+        run("ip", &["link", "set", &netns.veth, "up"])?;
+        // Frames to the gateway are handled by the stack instead of being forwarded by the kernel
+        fs::write(
+            format!("/proc/sys/net/ipv4/conf/{}/forwarding", netns.veth),
+            "0",
+        )?;
+
+        netns.exec("ip", &["link", "set", "lo", "up"])?;
+        netns.exec("ip", &["addr", "add", &remote, "dev", &peer])?;
+        netns.exec("ip", &["link", "set", &peer, "up"])?;
+        netns.exec("ip", &["route", "add", "default", "via", &gw])?;
+
+        Ok(netns)
+    }
+
+    /// Returns the MTU of the veth pair.
+    pub fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    /// Returns the end of the veth pair in the current namespace.
+    pub fn interface(&self) -> io::Result<Interface> {
+        pcap::interfaces()
+            .into_iter()
+            .find(|inter| inter.name() == &self.veth)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "interface not found"))
+    }
+
+    /// Runs the program with the arguments in the namespace, and returns its output. Returns an
+    /// error if the program does not exit successfully.
+    pub fn exec(&self, program: &str, args: &[&str]) -> io::Result<Output> {
+        let mut netns_args = vec!["netns", "exec", &self.name, program];
+        netns_args.extend_from_slice(args);
+
+        run("ip", &netns_args)
+    }
+
+    /// Sets the kernel parameter in the namespace, like `net.ipv4.tcp_sack`.
+    pub fn sysctl(&self, key: &str, value: &str) -> io::Result<()> {
+        self.exec("sysctl", &["-w", &format!("{}={}", key, value)])
+            .map(|_| ())
+    }
+}
+
+impl Drop for Netns {
+    fn drop(&mut self) {
+        // The veth pair is deleted along with its end in the namespace
+        let _ = run("ip", &["netns", "del", &self.name]);
+    }
+}
+
+fn run(program: &str, args: &[&str]) -> io::Result<Output> {
+    let output = Command::new(program).args(args).output()?;
+    if !output.status.success() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!(
+                "{} {}: {}",
+                program,
+                args.join(" "),
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+        ));
+    }
+
+    Ok(output)
+}
+
+#[cfg(test)]
+use crate::packet::layer::tcp::Tcp;
+#[cfg(test)]
+use crate::packet::Indicator;
+#[cfg(test)]
+use crate::session::{self, Session};
+#[cfg(test)]
+use crate::testing::MockSocks;
+#[cfg(test)]
+use crate::{Config, Forwarder, Redirector};
+#[cfg(test)]
+use ipnetwork::Ipv4Network;
+#[cfg(test)]
+use std::io::{Read, Write};
+#[cfg(test)]
+use std::net::{SocketAddr, SocketAddrV4, TcpListener};
+#[cfg(test)]
+use std::path::{Path, PathBuf};
+#[cfg(test)]
+use std::sync::{mpsc, Arc, Mutex};
+#[cfg(test)]
+use std::thread;
+#[cfg(test)]
+use std::time::Duration;
+
+/// Represents the time in milliseconds the stack takes to claim the gateway after probing if
+/// another device owns it.
+#[cfg(test)]
+const GW_CLAIM_WAIT: u64 = 5000;
+
+/// Represents the time in milliseconds to wait for the connections to be closed after the peer
+/// exits.
+#[cfg(test)]
+const CLOSE_WAIT: u64 = 1000;
+
+/// Represents the destination the peer connects to, which is redirected to local servers by
+/// the SOCKS proxy.
+#[cfg(test)]
+const DST: &str = "192.0.2.1";
+
+/// Serves a single HTTP response with the body of the size for each request on the loopback
+/// interface, and returns its address.
+#[cfg(test)]
+fn serve(size: usize) -> SocketAddrV4 {
+    let listener = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let addr = match listener.local_addr().unwrap() {
+        SocketAddr::V4(addr) => addr,
+        SocketAddr::V6(_) => unreachable!(),
+    };
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+            thread::spawn(move || {
+                let mut request = Vec::new();
+                let mut buffer = [0u8; 1024];
+                while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                    match stream.read(&mut buffer) {
+                        Ok(0) | Err(_) => return,
+                        Ok(n) => request.extend_from_slice(&buffer[..n]),
+                    }
+                }
+                let header = format!("HTTP/1.0 200 OK\r\nContent-Length: {}\r\n\r\n", size);
+                let _ = stream.write_all(header.as_bytes());
+                let _ = stream.write_all(&vec![0x5a; size]);
+            });
+        }
+    });
+
+    addr
+}
+
+/// Runs the stack on the veth pair of the namespace in the background with the SOCKS proxy,
+/// recording the session to the path. The stack stops once the namespace is dropped.
+#[cfg(test)]
+fn redirect(netns: &Netns, socks: MockSocks, path: &Path) {
+    let inter = netns.interface().unwrap();
+    let (tx, rx) = inter.open().unwrap();
+    let (tx, mut rx) = session::record(tx, rx, path).unwrap();
+    let mtu = netns.mtu();
+    let (ready_tx, ready_rx) = mpsc::channel();
+
+    thread::spawn(move || {
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let remote = socks.spawn().await.unwrap();
+            let forwarder = Forwarder::new(tx, mtu, inter.hardware_addr(), LOCAL_IP_ADDR);
+            let mut redirector = Redirector::new(
+                Arc::new(Mutex::new(forwarder)),
+                Ipv4Network::new(PEER_IP_ADDR, 32).unwrap(),
+                GW_IP_ADDR,
+                Some(GW_IP_ADDR),
+                remote,
+                false,
+                false,
+                None,
+                Config::new(),
+            );
+            let _ = ready_tx.send(());
+            let _ = redirector.open(&mut rx).await;
+        });
+    });
+    ready_rx.recv().unwrap();
+    thread::sleep(Duration::from_millis(GW_CLAIM_WAIT));
+}
+
+#[cfg(test)]
+fn session_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("pcap2socks-conformance-{}-{}", name, process::id()))
+}
+
+/// Opens the session recorded once the connections are closed, and removes its file.
+#[cfg(test)]
+fn close_session(path: &Path) -> Session {
+    // The last ACK and FIN from the peer may follow its exit
+    thread::sleep(Duration::from_millis(CLOSE_WAIT));
+    let session = Session::open(path).unwrap();
+    let _ = fs::remove_file(path);
+
+    session
+}
+
+/// Returns the TCP segments and their payload sizes in the frames.
+#[cfg(test)]
+fn segments(frames: Vec<Vec<u8>>) -> Vec<(Tcp, usize)> {
+    frames
+        .iter()
+        .filter_map(|frame| {
+            let indicator = Indicator::from(frame).ok()?;
+            let len = indicator.content_len() - indicator.len();
+
+            indicator.tcp().cloned().map(|tcp| (tcp, len))
+        })
+        .collect()
+}
+
+/// Verifies the options negotiated, the sizes of segments and the closing of the only TCP
+/// connection in the session.
+#[cfg(test)]
+fn verify(session: &Session, mtu: usize) {
+    let inbound = segments(session.inbound());
+    let outbound = segments(session.outbound());
+
+    let syn = &inbound
+        .iter()
+        .find(|(tcp, _)| tcp.is_syn() && !tcp.is_ack())
+        .expect("no SYN from the peer")
+        .0;
+    let syn_ack = &outbound
+        .iter()
+        .find(|(tcp, _)| tcp.is_syn() && tcp.is_ack())
+        .expect("no SYN/ACK to the peer")
+        .0;
+
+    // MSS
+    let mss = syn_ack.mss().expect("no MSS in the SYN/ACK") as usize;
+    assert!(mss <= mtu - 40);
+    let peer_mss = syn.mss().map_or(536, |mss| mss as usize);
+    assert!(outbound.iter().all(|(_, len)| *len <= peer_mss));
+    assert!(inbound.iter().all(|(_, len)| *len <= mss));
+
+    // SACK and window scaling are only negotiated if the peer offers them
+    assert_eq!(syn_ack.is_sack_perm(), syn.is_sack_perm());
+    assert_eq!(syn_ack.wscale().is_some(), syn.wscale().is_some());
+
+    // Both sides close with FINs instead of RSTs
+    assert!(inbound.iter().any(|(tcp, _)| tcp.is_fin()));
+    assert!(outbound.iter().any(|(tcp, _)| tcp.is_fin()));
+    assert!(!outbound.iter().any(|(tcp, _)| tcp.is_rst()));
+}
+
+#[cfg(test)]
+fn curl(netns: &Netns, port: u16) -> usize {
+    let url = format!("http://{}:{}/", DST, port);
+    let output = netns
+        .exec(
+            "curl",
+            &[
+                "-sS",
+                "-o",
+                "/dev/null",
+                "-m",
+                "30",
+                "-w",
+                "%{size_download}",
+                &url,
+            ],
+        )
+        .unwrap();
+
+    String::from_utf8_lossy(&output.stdout)
+        .trim()
+        .parse()
+        .unwrap()
+}
+
+#[test]
+#[ignore]
+fn conformance_curl() {
+    let size = 4 * 1024 * 1024;
+    let netns = Netns::new(1500).unwrap();
+    let server = serve(size);
+    let path = session_path("curl");
+    redirect(&netns, MockSocks::new().redirect(server), &path);
+
+    assert_eq!(curl(&netns, 80), size);
+    let session = close_session(&path);
+    verify(&session, 1500);
+}
+
+#[test]
+#[ignore]
+fn conformance_mss() {
+    let size = 1024 * 1024;
+    let netns = Netns::new(1280).unwrap();
+    let server = serve(size);
+    let path = session_path("mss");
+    redirect(&netns, MockSocks::new().redirect(server), &path);
+
+    assert_eq!(curl(&netns, 80), size);
+    let session = close_session(&path);
+    verify(&session, 1280);
+}
+
+#[test]
+#[ignore]
+fn conformance_no_sack_wscale() {
+    let size = 1024 * 1024;
+    let netns = Netns::new(1500).unwrap();
+    netns.sysctl("net.ipv4.tcp_sack", "0").unwrap();
+    netns.sysctl("net.ipv4.tcp_window_scaling", "0").unwrap();
+    let server = serve(size);
+    let path = session_path("no-sack-wscale");
+    redirect(&netns, MockSocks::new().redirect(server), &path);
+
+    assert_eq!(curl(&netns, 80), size);
+    let session = close_session(&path);
+    verify(&session, 1500);
+}
+
+#[test]
+#[ignore]
+fn conformance_iperf() {
+    let listener = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let port = listener.local_addr().unwrap().port();
+    drop(listener);
+    let mut server = Command::new("iperf3")
+        .args(["-s", "-1", "-B", "127.0.0.1", "-p", &port.to_string()])
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_millis(500));
+
+    let netns = Netns::new(1500).unwrap();
+    let path = session_path("iperf");
+    let socks = MockSocks::new().redirect(SocketAddrV4::new(Ipv4Addr::LOCALHOST, port));
+    redirect(&netns, socks, &path);
+
+    // Upload from the peer, which exercises the receive path of the stack
+    let result = netns.exec("iperf3", &["-c", DST, "-p", &port.to_string(), "-t", "3"]);
+    let _ = server.kill();
+    let _ = server.wait();
+    result.unwrap();
+    let session = close_session(&path);
+    verify(&session, 1500);
+}
@@ -0,0 +1,317 @@
+//! Support for a minimal SOCKS5 server in tests, so the SOCKS client code, the `StreamWorker` and
+//! the `DatagramWorker` can be exercised against a real handshake without an external proxy.
+//!
+//! The server supports CONNECT and UDP ASSOCIATE of IPv4 addresses, the username/password
+//! authentication, and faults injected into the handshakes.
+
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+
+const SOCKS_VERSION: u8 = 5;
+const AUTH_VERSION: u8 = 1;
+const METHOD_NONE: u8 = 0;
+const METHOD_USERNAME: u8 = 2;
+const METHOD_NOT_ACCEPTABLE: u8 = 0xff;
+const CMD_CONNECT: u8 = 1;
+const CMD_UDP_ASSOCIATE: u8 = 3;
+const ATYP_IPV4: u8 = 1;
+const REPLY_SUCCEEDED: u8 = 0;
+const REPLY_CONNECTION_REFUSED: u8 = 5;
+const REPLY_COMMAND_NOT_SUPPORTED: u8 = 7;
+const REPLY_ADDRESS_TYPE_NOT_SUPPORTED: u8 = 8;
+
+/// Represents the size of the header of a SOCKS5 UDP datagram of an IPv4 address.
+const UDP_HEADER_SIZE: usize = 10;
+
+/// Represents a fault injected into the handshakes of a `MockSocks`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Fault {
+    /// Replies that none of the offered methods is acceptable.
+    NoAcceptableMethods,
+    /// Rejects the username and the password even if they match.
+    AuthFailure,
+    /// Replies to the request with the unsuccessful reply.
+    Reply(u8),
+    /// Closes the connection after receiving the request without replying.
+    CloseBeforeReply,
+    /// Replies to the UDP ASSOCIATE request with the unspecified address, like proxies behind
+    /// NATs.
+    UnspecifiedAssociateAddr,
+}
+
+/// Represents a minimal SOCKS5 server on the loopback interface. Clones of a `MockSocks` share
+/// the counters.
+#[derive(Clone, Debug, Default)]
+pub struct MockSocks {
+    auth: Option<(String, String)>,
+    fault: Option<Fault>,
+    redirect: Option<SocketAddrV4>,
+    connections: Arc<AtomicUsize>,
+    requests: Arc<AtomicUsize>,
+}
+
+impl MockSocks {
+    /// Creates a new `MockSocks` which requires no authentication and injects no fault.
+    pub fn new() -> MockSocks {
+        MockSocks::default()
+    }
+
+    /// Sets the username and the password the server requires.
+    pub fn auth(mut self, username: &str, password: &str) -> MockSocks {
+        self.auth = Some((username.to_string(), password.to_string()));
+
+        self
+    }
+
+    /// Sets the fault injected into the handshakes.
+    pub fn fault(mut self, fault: Fault) -> MockSocks {
+        self.fault = Some(fault);
+
+        self
+    }
+
+    /// Sets the address CONNECT requests are redirected to regardless of their destinations, so
+    /// clients can reach a local server through arbitrary destinations.
+    pub fn redirect(mut self, addr: SocketAddrV4) -> MockSocks {
+        self.redirect = Some(addr);
+
+        self
+    }
+
+    /// Returns the count of connections accepted.
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::Relaxed)
+    }
+
+    /// Returns the count of requests received after the method is negotiated and the client is
+    /// authenticated.
+    pub fn requests(&self) -> usize {
+        self.requests.load(Ordering::Relaxed)
+    }
+
+    /// Starts the server on a random port of the loopback interface in the background, and
+    /// returns its address.
+    pub async fn spawn(&self) -> io::Result<SocketAddrV4> {
+        let mut listener = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)).await?;
+        let addr = match listener.local_addr()? {
+            SocketAddr::V4(addr) => addr,
+            SocketAddr::V6(_) => unreachable!(),
+        };
+
+        let server = self.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                server.connections.fetch_add(1, Ordering::Relaxed);
+                let server = server.clone();
+                tokio::spawn(async move {
+                    // Errors only close the connection
+                    let _ = server.handle(stream).await;
+                });
+            }
+        });
+
+        Ok(addr)
+    }
+
+    async fn handle(&self, mut stream: TcpStream) -> io::Result<()> {
+        if !self.negotiate(&mut stream).await? {
+            return Ok(());
+        }
+
+        // Request
+        let mut request = [0u8; 4];
+        stream.read_exact(&mut request).await?;
+        if request[0] != SOCKS_VERSION {
+            return Ok(());
+        }
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        if request[3] != ATYP_IPV4 {
+            return reply(&mut stream, REPLY_ADDRESS_TYPE_NOT_SUPPORTED, None).await;
+        }
+        let dst = read_addr(&mut stream).await?;
+
+        match self.fault {
+            Some(Fault::CloseBeforeReply) => return Ok(()),
+            Some(Fault::Reply(rep)) => return reply(&mut stream, rep, None).await,
+            _ => {}
+        }
+
+        match request[1] {
+            CMD_CONNECT => self.connect(stream, dst).await,
+            CMD_UDP_ASSOCIATE => self.associate(stream).await,
+            _ => reply(&mut stream, REPLY_COMMAND_NOT_SUPPORTED, None).await,
+        }
+    }
+
+    /// Negotiates the method and authenticates the client. Returns if the client can request.
+    async fn negotiate(&self, stream: &mut TcpStream) -> io::Result<bool> {
+        // Method
+        let mut buffer = [0u8; 2];
+        stream.read_exact(&mut buffer).await?;
+        if buffer[0] != SOCKS_VERSION {
+            return Ok(false);
+        }
+        let mut methods = vec![0u8; buffer[1] as usize];
+        stream.read_exact(&mut methods).await?;
+        let method = match self.auth {
+            Some(_) => METHOD_USERNAME,
+            None => METHOD_NONE,
+        };
+        if self.fault == Some(Fault::NoAcceptableMethods) || !methods.contains(&method) {
+            stream
+                .write_all(&[SOCKS_VERSION, METHOD_NOT_ACCEPTABLE])
+                .await?;
+
+            return Ok(false);
+        }
+        stream.write_all(&[SOCKS_VERSION, method]).await?;
+
+        // Authentication (RFC 1929)
+        if let Some((ref username, ref password)) = self.auth {
+            stream.read_exact(&mut buffer).await?;
+            let mut client_username = vec![0u8; buffer[1] as usize];
+            stream.read_exact(&mut client_username).await?;
+            let mut client_password = vec![0u8; stream.read_u8().await? as usize];
+            stream.read_exact(&mut client_password).await?;
+
+            let is_matched = client_username == username.as_bytes()
+                && client_password == password.as_bytes()
+                && self.fault != Some(Fault::AuthFailure);
+            stream
+                .write_all(&[AUTH_VERSION, if is_matched { 0 } else { 1 }])
+                .await?;
+
+            return Ok(is_matched);
+        }
+
+        Ok(true)
+    }
+
+    /// Connects to the destination and relays the streams in both directions.
+    async fn connect(&self, mut stream: TcpStream, dst: SocketAddrV4) -> io::Result<()> {
+        let target = match TcpStream::connect(self.redirect.unwrap_or(dst)).await {
+            Ok(target) => target,
+            Err(_) => return reply(&mut stream, REPLY_CONNECTION_REFUSED, None).await,
+        };
+        let bind_addr = match target.local_addr()? {
+            SocketAddr::V4(addr) => addr,
+            SocketAddr::V6(_) => unreachable!(),
+        };
+        reply(&mut stream, REPLY_SUCCEEDED, Some(bind_addr)).await?;
+
+        let (mut stream_rx, mut stream_tx) = stream.into_split();
+        let (mut target_rx, mut target_tx) = target.into_split();
+        tokio::spawn(async move {
+            let _ = io::copy(&mut stream_rx, &mut target_tx).await;
+            let _ = target_tx.shutdown().await;
+        });
+        let _ = io::copy(&mut target_rx, &mut stream_tx).await;
+        let _ = stream_tx.shutdown().await;
+
+        Ok(())
+    }
+
+    /// Relays datagrams between the client and destinations until the connection is closed.
+    async fn associate(&self, mut stream: TcpStream) -> io::Result<()> {
+        let mut relay = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)).await?;
+        let mut target = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)).await?;
+        let bind_addr = match relay.local_addr()? {
+            SocketAddr::V4(addr) => match self.fault {
+                Some(Fault::UnspecifiedAssociateAddr) => {
+                    SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, addr.port())
+                }
+                _ => addr,
+            },
+            SocketAddr::V6(_) => unreachable!(),
+        };
+        reply(&mut stream, REPLY_SUCCEEDED, Some(bind_addr)).await?;
+
+        let mut client = None;
+        let mut relay_buffer = vec![0u8; u16::MAX as usize];
+        let mut target_buffer = vec![0u8; u16::MAX as usize];
+        let mut control_buffer = [0u8; 1];
+        loop {
+            tokio::select! {
+                result = relay.recv_from(&mut relay_buffer) => {
+                    let (size, addr) = result?;
+                    // Fragments and other address types are dropped
+                    if size < UDP_HEADER_SIZE
+                        || relay_buffer[2] != 0
+                        || relay_buffer[3] != ATYP_IPV4
+                    {
+                        continue;
+                    }
+                    client = Some(addr);
+                    let dst = SocketAddrV4::new(
+                        Ipv4Addr::new(
+                            relay_buffer[4],
+                            relay_buffer[5],
+                            relay_buffer[6],
+                            relay_buffer[7],
+                        ),
+                        u16::from_be_bytes([relay_buffer[8], relay_buffer[9]]),
+                    );
+                    target
+                        .send_to(&relay_buffer[UDP_HEADER_SIZE..size], &SocketAddr::V4(dst))
+                        .await?;
+                }
+                result = target.recv_from(&mut target_buffer[UDP_HEADER_SIZE..]) => {
+                    let (size, addr) = result?;
+                    let (client, addr) = match (client, addr) {
+                        (Some(client), SocketAddr::V4(addr)) => (client, addr),
+                        _ => continue,
+                    };
+                    target_buffer[..UDP_HEADER_SIZE].copy_from_slice(&header(addr));
+                    relay
+                        .send_to(&target_buffer[..UDP_HEADER_SIZE + size], &client)
+                        .await?;
+                }
+                result = stream.read(&mut control_buffer) => {
+                    // The association terminates with the connection
+                    match result {
+                        Ok(0) | Err(_) => return Ok(()),
+                        Ok(_) => {}
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Returns the header of a SOCKS5 UDP datagram, or the address part of a reply, of the address.
+fn header(addr: SocketAddrV4) -> [u8; UDP_HEADER_SIZE] {
+    let mut header = [0u8; UDP_HEADER_SIZE];
+    header[3] = ATYP_IPV4;
+    header[4..8].copy_from_slice(&addr.ip().octets());
+    header[8..].copy_from_slice(&addr.port().to_be_bytes());
+
+    header
+}
+
+async fn read_addr<S>(stream: &mut S) -> io::Result<SocketAddrV4>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut addr = [0u8; 6];
+    stream.read_exact(&mut addr).await?;
+
+    Ok(SocketAddrV4::new(
+        Ipv4Addr::new(addr[0], addr[1], addr[2], addr[3]),
+        u16::from_be_bytes([addr[4], addr[5]]),
+    ))
+}
+
+async fn reply<S>(stream: &mut S, rep: u8, bind_addr: Option<SocketAddrV4>) -> io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let bind_addr = bind_addr.unwrap_or_else(|| SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0));
+    let mut reply = header(bind_addr);
+    reply[0] = SOCKS_VERSION;
+    reply[1] = rep;
+
+    stream.write_all(&reply).await
+}
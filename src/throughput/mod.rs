@@ -0,0 +1,272 @@
+//! Support for tracking the throughput of sources and TCP connections over rolling windows, like
+//! for rendering live bandwidth graphs in frontends.
+
+use std::cmp::{min, Reverse};
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::{Arc, Mutex};
+#[cfg(test)]
+use std::time::Duration;
+use std::time::Instant;
+
+/// Represents the count of one-second slots of each meter, which is the longest window.
+const SLOTS: u64 = 60;
+
+/// Represents a rolling window of throughput. Only complete seconds are counted, so the
+/// throughput of the current second shows up in the next second.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Window {
+    /// Represents the last second.
+    OneSecond,
+    /// Represents the last 10 seconds.
+    TenSeconds,
+    /// Represents the last 60 seconds.
+    SixtySeconds,
+}
+
+impl Window {
+    fn seconds(&self) -> u64 {
+        match self {
+            Window::OneSecond => 1,
+            Window::TenSeconds => 10,
+            Window::SixtySeconds => SLOTS,
+        }
+    }
+}
+
+/// Represents the throughput in bytes per second of frames received from and sent to a source.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Throughput {
+    /// Represents the throughput received from the source.
+    pub rx: u64,
+    /// Represents the throughput sent to the source.
+    pub tx: u64,
+}
+
+impl Throughput {
+    /// Returns the throughput in both directions.
+    pub fn total(&self) -> u64 {
+        self.rx + self.tx
+    }
+}
+
+/// Represents the sources and the TCP connections with the most throughput in a window, in
+/// descending order of the throughput in both directions.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct TopTalkers {
+    /// Represents the sources.
+    pub clients: Vec<(Ipv4Addr, Throughput)>,
+    /// Represents the TCP connections from the source to the destination.
+    pub connections: Vec<((SocketAddrV4, SocketAddrV4), Throughput)>,
+}
+
+/// Represents a meter counting bytes in one-second slots.
+struct Meter {
+    slots: [(u64, u64); SLOTS as usize],
+    /// Represents the second of the latest slot since the creation of the tracker.
+    second: u64,
+}
+
+impl Meter {
+    fn new(second: u64) -> Meter {
+        Meter {
+            slots: [(0, 0); SLOTS as usize],
+            second,
+        }
+    }
+
+    fn add(&mut self, second: u64, rx: usize, tx: usize) {
+        // Clear slots skipped since the latest slot
+        if second > self.second {
+            for i in 1..=min(second - self.second, SLOTS) {
+                self.slots[((self.second + i) % SLOTS) as usize] = (0, 0);
+            }
+            self.second = second;
+        }
+
+        let slot = &mut self.slots[(second % SLOTS) as usize];
+        slot.0 += rx as u64;
+        slot.1 += tx as u64;
+    }
+
+    fn throughput(&self, second: u64, window: Window) -> Throughput {
+        let seconds = window.seconds();
+
+        let mut throughput = Throughput::default();
+        for s in second.saturating_sub(seconds)..second {
+            // Slots not written yet or overwritten are empty
+            if s > self.second || self.second - s >= SLOTS {
+                continue;
+            }
+            let slot = self.slots[(s % SLOTS) as usize];
+            throughput.rx += slot.0;
+            throughput.tx += slot.1;
+        }
+        throughput.rx /= seconds;
+        throughput.tx /= seconds;
+
+        throughput
+    }
+
+    fn is_expired(&self, second: u64) -> bool {
+        second > self.second + SLOTS
+    }
+}
+
+/// Represents a tracker of the throughput of sources and TCP connections.
+pub(crate) struct Tracker {
+    clients: HashMap<Ipv4Addr, Meter>,
+    connections: HashMap<(SocketAddrV4, SocketAddrV4), Meter>,
+    instant: Instant,
+}
+
+impl Tracker {
+    /// Creates a new `Tracker`.
+    pub(crate) fn new() -> Tracker {
+        Tracker {
+            clients: HashMap::new(),
+            connections: HashMap::new(),
+            instant: Instant::now(),
+        }
+    }
+
+    fn second(&self) -> u64 {
+        self.instant.elapsed().as_secs()
+    }
+
+    /// Adds the size of a frame received from the source, which belongs to the TCP connection
+    /// from the source to the destination if any.
+    pub(crate) fn add_rx(
+        &mut self,
+        src: Ipv4Addr,
+        connection: Option<(SocketAddrV4, SocketAddrV4)>,
+        n: usize,
+    ) {
+        self.add_at(self.second(), src, connection, n, 0);
+    }
+
+    /// Adds the size of a frame sent to the source, which belongs to the TCP connection from the
+    /// source to the destination if any.
+    pub(crate) fn add_tx(
+        &mut self,
+        src: Ipv4Addr,
+        connection: Option<(SocketAddrV4, SocketAddrV4)>,
+        n: usize,
+    ) {
+        self.add_at(self.second(), src, connection, 0, n);
+    }
+
+    fn add_at(
+        &mut self,
+        second: u64,
+        src: Ipv4Addr,
+        connection: Option<(SocketAddrV4, SocketAddrV4)>,
+        rx: usize,
+        tx: usize,
+    ) {
+        self.clients
+            .entry(src)
+            .or_insert_with(|| Meter::new(second))
+            .add(second, rx, tx);
+        if let Some(connection) = connection {
+            self.connections
+                .entry(connection)
+                .or_insert_with(|| Meter::new(second))
+                .add(second, rx, tx);
+        }
+    }
+
+    /// Removes the sources and the TCP connections which have been silent for the longest
+    /// window.
+    pub(crate) fn expire(&mut self) {
+        let second = self.second();
+
+        self.clients.retain(|_, meter| !meter.is_expired(second));
+        self.connections
+            .retain(|_, meter| !meter.is_expired(second));
+    }
+}
+
+/// Returns the sources and the TCP connections with the most throughput in the window across
+/// the trackers. The throughput of a source is added up if it appears in multiple trackers.
+pub(crate) fn top_n(trackers: &[Arc<Mutex<Tracker>>], n: usize, window: Window) -> TopTalkers {
+    let mut clients = HashMap::new();
+    let mut connections = HashMap::new();
+    for tracker in trackers {
+        let tracker = tracker.lock().unwrap();
+        let second = tracker.second();
+        accumulate(&mut clients, &tracker.clients, second, window);
+        accumulate(&mut connections, &tracker.connections, second, window);
+    }
+
+    TopTalkers {
+        clients: sort(clients, n),
+        connections: sort(connections, n),
+    }
+}
+
+fn accumulate<K: Copy + Eq + Hash>(
+    throughputs: &mut HashMap<K, Throughput>,
+    meters: &HashMap<K, Meter>,
+    second: u64,
+    window: Window,
+) {
+    for (&key, meter) in meters {
+        let throughput = meter.throughput(second, window);
+        let entry = throughputs.entry(key).or_default();
+        entry.rx += throughput.rx;
+        entry.tx += throughput.tx;
+    }
+}
+
+fn sort<K>(throughputs: HashMap<K, Throughput>, n: usize) -> Vec<(K, Throughput)> {
+    let mut throughputs: Vec<_> = throughputs
+        .into_iter()
+        .filter(|(_, throughput)| throughput.total() > 0)
+        .collect();
+    throughputs.sort_by_key(|(_, throughput)| Reverse(throughput.total()));
+    throughputs.truncate(n);
+
+    throughputs
+}
+
+#[test]
+fn tracker_top_n() {
+    let src = Ipv4Addr::new(10, 6, 0, 2);
+    let other = Ipv4Addr::new(10, 6, 0, 3);
+    let connection = (
+        SocketAddrV4::new(src, 50000),
+        SocketAddrV4::new(Ipv4Addr::new(1, 1, 1, 1), 443),
+    );
+
+    let mut tracker = Tracker::new();
+    for second in 0..10 {
+        tracker.add_at(second, src, Some(connection), 1000, 2000);
+        tracker.add_at(second, other, None, 100, 0);
+    }
+    let second = 10;
+    let throughput = tracker.clients[&src].throughput(second, Window::OneSecond);
+    assert_eq!(throughput, Throughput { rx: 1000, tx: 2000 });
+    let throughput = tracker.clients[&src].throughput(second, Window::SixtySeconds);
+    assert_eq!(throughput, Throughput { rx: 166, tx: 333 });
+
+    // Silent seconds are not counted
+    let throughput = tracker.clients[&src].throughput(second + 5, Window::TenSeconds);
+    assert_eq!(throughput, Throughput { rx: 500, tx: 1000 });
+    assert!(!tracker.clients[&src].is_expired(second + 5));
+    assert!(tracker.clients[&src].is_expired(second + SLOTS));
+
+    // Top talkers across trackers
+    tracker.instant = Instant::now() - Duration::from_secs(second);
+    let mut another = Tracker::new();
+    another.instant = tracker.instant;
+    another.add_at(second - 1, other, None, 3000, 0);
+    let trackers = [Arc::new(Mutex::new(tracker)), Arc::new(Mutex::new(another))];
+    let talkers = top_n(&trackers, 1, Window::OneSecond);
+    assert_eq!(
+        talkers.clients,
+        vec![(other, Throughput { rx: 3100, tx: 0 })]
+    );
+    assert_eq!(talkers.connections.len(), 1);
+}
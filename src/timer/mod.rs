@@ -0,0 +1,138 @@
+//! Support for scheduling timers with a hashed timer wheel.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+/// Represents a hashed timer wheel. Each key has at most one timer, and the timer is fired in the
+/// first tick after its instant.
+#[derive(Debug)]
+pub struct TimerWheel<K> {
+    slots: Vec<Vec<(K, u64)>>,
+    /// Represents the tick which each key is scheduled at.
+    ticks: HashMap<K, u64>,
+    tick: Duration,
+    start: Instant,
+    /// Represents the next tick to be expired.
+    current: u64,
+}
+
+impl<K: Clone + Eq + Hash> TimerWheel<K> {
+    /// Creates a new `TimerWheel` with the specified count of slots and the duration of a tick.
+    pub fn new(slots: usize, tick: Duration) -> TimerWheel<K> {
+        TimerWheel {
+            slots: vec![Vec::new(); slots.max(1)],
+            ticks: HashMap::new(),
+            tick,
+            start: crate::clock::now(),
+            current: 0,
+        }
+    }
+
+    /// Schedules a timer of the key at the instant. The previous timer of the key is replaced.
+    pub fn schedule(&mut self, key: K, instant: Instant) {
+        // Round up so a timer is never fired before its instant
+        let tick = self.tick_of(instant, true).max(self.current);
+        if self.ticks.get(&key) == Some(&tick) {
+            return;
+        }
+
+        let slot = (tick % self.slots.len() as u64) as usize;
+        self.slots[slot].push((key.clone(), tick));
+        self.ticks.insert(key, tick);
+    }
+
+    /// Cancels the timer of the key.
+    pub fn cancel(&mut self, key: &K) {
+        // The entry in the slot is dropped lazily
+        self.ticks.remove(key);
+    }
+
+    /// Returns the keys whose timers are fired until the instant.
+    pub fn expire(&mut self, instant: Instant) -> Vec<K> {
+        let mut keys = Vec::new();
+
+        // Round down so only ticks which have fully passed are expired
+        let now = self.tick_of(instant, false);
+        if now < self.current {
+            return keys;
+        }
+        // Every slot is visited at most once
+        let count = (now - self.current + 1).min(self.slots.len() as u64);
+        for i in 0..count {
+            let slot = ((self.current + i) % self.slots.len() as u64) as usize;
+            let ticks = &mut self.ticks;
+            self.slots[slot].retain(|(key, tick)| {
+                if ticks.get(key) != Some(tick) {
+                    // Canceled or rescheduled
+                    return false;
+                }
+                if *tick > now {
+                    // In a later round
+                    return true;
+                }
+                ticks.remove(key);
+                keys.push(key.clone());
+
+                false
+            });
+        }
+        self.current = now + 1;
+
+        keys
+    }
+
+    /// Returns the count of timers scheduled.
+    pub fn len(&self) -> usize {
+        self.ticks.len()
+    }
+
+    /// Returns if there is no timer scheduled.
+    pub fn is_empty(&self) -> bool {
+        self.ticks.is_empty()
+    }
+
+    /// Returns the tick of the instant, rounded up or down.
+    fn tick_of(&self, instant: Instant, is_round_up: bool) -> u64 {
+        let elapsed = instant.saturating_duration_since(self.start).as_micros();
+        let tick = self.tick.as_micros().max(1);
+
+        match is_round_up {
+            true => ((elapsed + tick - 1) / tick) as u64,
+            false => (elapsed / tick) as u64,
+        }
+    }
+}
+
+#[test]
+fn timer_wheel_expire() {
+    let mut wheel = TimerWheel::new(8, Duration::from_millis(10));
+    let start = wheel.start;
+
+    wheel.schedule(1, start + Duration::from_millis(25));
+    wheel.schedule(2, start + Duration::from_millis(200));
+    wheel.schedule(3, start + Duration::from_millis(50));
+    wheel.cancel(&3);
+    assert_eq!(wheel.len(), 2);
+
+    assert!(wheel.expire(start + Duration::from_millis(20)).is_empty());
+    assert_eq!(wheel.expire(start + Duration::from_millis(30)), vec![1]);
+
+    // Reschedule to a later round
+    wheel.schedule(1, start + Duration::from_millis(150));
+    assert_eq!(wheel.expire(start + Duration::from_millis(160)), vec![1]);
+    assert_eq!(wheel.expire(start + Duration::from_millis(1000)), vec![2]);
+    assert!(wheel.is_empty());
+}
+
+#[test]
+fn timer_wheel_never_early() {
+    let mut wheel = TimerWheel::new(8, Duration::from_millis(10));
+    let start = wheel.start;
+
+    wheel.schedule(1, start + Duration::from_millis(25));
+    wheel.schedule(2, start + Duration::from_millis(30));
+    assert!(wheel.expire(start + Duration::from_millis(21)).is_empty());
+    assert!(wheel.expire(start + Duration::from_millis(29)).is_empty());
+    assert_eq!(wheel.expire(start + Duration::from_millis(30)), vec![1, 2]);
+}
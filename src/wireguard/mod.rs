@@ -0,0 +1,444 @@
+//! Support for relaying traffic through a WireGuard tunnel.
+
+use log::{debug, info, trace, warn};
+use rand::{self, Rng};
+use std::collections::VecDeque;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::time;
+
+mod nat;
+mod noise;
+
+use nat::Nat;
+use noise::{Handshake, Keys, Session};
+
+/// Represents the MTU of the WireGuard tunnel.
+const TUNNEL_MTU: usize = 1420;
+
+/// Represents the capacity of the queue of packets sent to the tunnel.
+const TUNNEL_QUEUE_SIZE: usize = 1024;
+
+/// Represents the duration of a tick of the tunnel.
+const TUNNEL_TICK: u64 = 1000;
+
+/// Represents the age of a session after which a new handshake is initiated.
+const REKEY_AFTER_TIME: u64 = 120;
+/// Represents the age of a session after which it is no longer used.
+const REJECT_AFTER_TIME: u64 = 180;
+/// Represents the timeout of a handshake before it is retried.
+const REKEY_TIMEOUT: u64 = 5;
+/// Represents the duration retrying a handshake before giving up.
+const REKEY_ATTEMPT_TIME: u64 = 90;
+
+/// Represents the options of a WireGuard tunnel.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct WireGuardOption {
+    private_key: [u8; 32],
+    peer_public_key: [u8; 32],
+    preshared_key: [u8; 32],
+    addr: Ipv4Addr,
+    keepalive: Option<u64>,
+}
+
+impl WireGuardOption {
+    /// Creates a new `WireGuardOption` with keys in base64 and the address in the tunnel.
+    pub fn new(
+        private_key: &str,
+        peer_public_key: &str,
+        addr: Ipv4Addr,
+    ) -> io::Result<WireGuardOption> {
+        Ok(WireGuardOption {
+            private_key: parse_key(private_key)?,
+            peer_public_key: parse_key(peer_public_key)?,
+            preshared_key: [0u8; 32],
+            addr,
+            keepalive: None,
+        })
+    }
+
+    /// Sets the preshared key in base64.
+    pub fn set_preshared_key(&mut self, preshared_key: &str) -> io::Result<()> {
+        self.preshared_key = parse_key(preshared_key)?;
+
+        Ok(())
+    }
+
+    /// Sets the interval in seconds of persistent keepalives.
+    pub fn set_keepalive(&mut self, keepalive: u64) {
+        self.keepalive = match keepalive {
+            0 => None,
+            _ => Some(keepalive),
+        };
+    }
+
+    /// Returns the address in the tunnel.
+    pub fn addr(&self) -> Ipv4Addr {
+        self.addr
+    }
+}
+
+fn parse_key(key: &str) -> io::Result<[u8; 32]> {
+    let decoded = base64::decode(key)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid WireGuard key"))?;
+    if decoded.len() != 32 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "invalid WireGuard key",
+        ));
+    }
+
+    let mut result = [0u8; 32];
+    result.copy_from_slice(&decoded);
+
+    Ok(result)
+}
+
+/// Represents a channel forward IPv4 packets from the WireGuard tunnel to the source.
+pub trait ForwardPacket: Send {
+    /// Forward an IPv4 packet to the source.
+    fn forward(&mut self, packet: &[u8]) -> io::Result<()>;
+}
+
+/// Represents a WireGuard tunnel which relays IPv4 packets from sources.
+pub(crate) struct Tunnel {
+    tx: mpsc::Sender<Vec<u8>>,
+}
+
+impl Tunnel {
+    /// Opens a WireGuard tunnel to the endpoint.
+    pub(crate) async fn open(
+        endpoint: SocketAddrV4,
+        options: &WireGuardOption,
+        tx: Arc<Mutex<dyn ForwardPacket>>,
+    ) -> io::Result<Tunnel> {
+        let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)).await?;
+        socket.connect(endpoint).await?;
+
+        let (packet_tx, packet_rx) = mpsc::channel(TUNNEL_QUEUE_SIZE);
+        let peer = Peer::new(options, tx);
+        tokio::spawn(peer.run(socket, packet_rx));
+        info!(
+            "Open WireGuard tunnel to {} as {}",
+            endpoint,
+            options.addr()
+        );
+
+        Ok(Tunnel { tx: packet_tx })
+    }
+
+    /// Sends an IPv4 packet to the tunnel.
+    pub(crate) fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+        match self.tx.try_send(packet.to_vec()) {
+            Ok(_) => Ok(()),
+            Err(TrySendError::Full(_)) => {
+                trace!("drop WireGuard packet ({} Bytes): queue full", packet.len());
+
+                Ok(())
+            }
+            Err(TrySendError::Closed(_)) => Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "WireGuard tunnel is closed",
+            )),
+        }
+    }
+}
+
+/// Represents the peer of a WireGuard tunnel, which handshakes, seals and opens messages.
+struct Peer {
+    keys: Keys,
+    nat: Nat,
+    keepalive: Option<Duration>,
+    tx: Arc<Mutex<dyn ForwardPacket>>,
+    /// Represents the pending handshake and the instant it is initiated.
+    handshake: Option<(Handshake, Instant)>,
+    /// Represents the instant the first attempt of the pending handshake is initiated.
+    attempt_instant: Option<Instant>,
+    current: Option<Session>,
+    previous: Option<Session>,
+    /// Represents the packets waiting for the handshake.
+    queue: VecDeque<Vec<u8>>,
+    send_instant: Instant,
+}
+
+impl Peer {
+    fn new(options: &WireGuardOption, tx: Arc<Mutex<dyn ForwardPacket>>) -> Peer {
+        Peer {
+            keys: Keys::new(
+                options.private_key,
+                options.peer_public_key,
+                options.preshared_key,
+            ),
+            // Exclude the IPv4 and the TCP headers
+            nat: Nat::new(options.addr, (TUNNEL_MTU - 40) as u16),
+            keepalive: options.keepalive.map(Duration::from_secs),
+            tx,
+            handshake: None,
+            attempt_instant: None,
+            current: None,
+            previous: None,
+            queue: VecDeque::new(),
+            send_instant: Instant::now(),
+        }
+    }
+
+    async fn run(mut self, socket: UdpSocket, mut rx: mpsc::Receiver<Vec<u8>>) {
+        let (mut socket_rx, mut socket_tx) = socket.split();
+        let mut interval = time::interval(Duration::from_millis(TUNNEL_TICK));
+        let mut buffer = vec![0u8; u16::MAX as usize];
+
+        loop {
+            let messages = tokio::select! {
+                packet = rx.recv() => match packet {
+                    Some(packet) => self.handle_outbound(packet),
+                    None => break,
+                },
+                result = socket_rx.recv(&mut buffer) => match result {
+                    Ok(size) => self.handle_inbound(&buffer[..size]),
+                    Err(ref e) => {
+                        warn!("receive from WireGuard tunnel: {}", e);
+                        continue;
+                    }
+                },
+                _ = interval.tick() => self.update(),
+            };
+
+            for message in messages {
+                if let Err(ref e) = socket_tx.send(&message).await {
+                    warn!("send to WireGuard tunnel: {}", e);
+                }
+            }
+        }
+        trace!("close WireGuard tunnel");
+    }
+
+    fn handle_outbound(&mut self, mut packet: Vec<u8>) -> Vec<Vec<u8>> {
+        if !self.nat.translate_outbound(&mut packet) {
+            trace!(
+                "drop WireGuard packet ({} Bytes): untranslatable",
+                packet.len()
+            );
+            return vec![];
+        }
+
+        let mut messages = Vec::new();
+        let age = self
+            .current
+            .as_ref()
+            .map(|session| session.instant().elapsed());
+        match age {
+            Some(age) if age < Duration::from_secs(REJECT_AFTER_TIME) => {
+                if age >= Duration::from_secs(REKEY_AFTER_TIME) && self.handshake.is_none() {
+                    messages.push(self.initiate());
+                }
+                if let Some(message) = self.seal(&packet) {
+                    messages.push(message);
+                }
+            }
+            _ => {
+                if self.queue.len() >= TUNNEL_QUEUE_SIZE {
+                    self.queue.pop_front();
+                }
+                self.queue.push_back(packet);
+                if self.handshake.is_none() {
+                    messages.push(self.initiate());
+                }
+            }
+        }
+
+        messages
+    }
+
+    fn handle_inbound(&mut self, message: &[u8]) -> Vec<Vec<u8>> {
+        match message.first() {
+            Some(&noise::MESSAGE_RESPONSE) => self.handle_response(message),
+            Some(&noise::MESSAGE_TRANSPORT) => {
+                self.handle_transport(message);
+                vec![]
+            }
+            Some(&noise::MESSAGE_COOKIE_REPLY) => {
+                debug!("receive WireGuard cookie reply, which is not supported");
+                vec![]
+            }
+            _ => {
+                trace!(
+                    "drop WireGuard message ({} Bytes): unexpected type",
+                    message.len()
+                );
+                vec![]
+            }
+        }
+    }
+
+    fn handle_response(&mut self, message: &[u8]) -> Vec<Vec<u8>> {
+        let session = match self.handshake {
+            Some((ref handshake, _))
+                if noise::receiver_index(message) == Some(handshake.sender_index()) =>
+            {
+                match handshake.consume_response(&self.keys, message) {
+                    Ok(session) => session,
+                    Err(ref e) => {
+                        warn!("handshake with WireGuard peer: {}", e);
+                        return vec![];
+                    }
+                }
+            }
+            _ => {
+                trace!("drop WireGuard handshake response: unknown index");
+                return vec![];
+            }
+        };
+
+        self.handshake = None;
+        self.attempt_instant = None;
+        if self.current.is_none() {
+            info!("WireGuard handshake completed");
+        } else {
+            debug!("WireGuard handshake completed");
+        }
+        self.previous = self.current.replace(session);
+
+        // Send queued packets, or a keepalive to confirm the session
+        let mut messages = Vec::new();
+        let queue: Vec<_> = self.queue.drain(..).collect();
+        for packet in queue.iter() {
+            if let Some(message) = self.seal(packet) {
+                messages.push(message);
+            }
+        }
+        if messages.is_empty() {
+            if let Some(message) = self.seal(&[]) {
+                messages.push(message);
+            }
+        }
+
+        messages
+    }
+
+    fn handle_transport(&mut self, message: &[u8]) {
+        let index = noise::receiver_index(message);
+        let session = match (self.current.as_mut(), self.previous.as_mut()) {
+            (Some(current), _) if Some(current.sender_index()) == index => current,
+            (_, Some(previous)) if Some(previous.sender_index()) == index => previous,
+            _ => {
+                trace!("drop WireGuard message: unknown index");
+                return;
+            }
+        };
+
+        let mut packet = match session.open(message) {
+            Ok(packet) => packet,
+            Err(ref e) => {
+                trace!("drop WireGuard message: {}", e);
+                return;
+            }
+        };
+        if packet.is_empty() {
+            trace!("receive WireGuard keepalive");
+            return;
+        }
+
+        // Remove paddings
+        if packet.len() < 20 {
+            trace!("drop WireGuard packet ({} Bytes): too short", packet.len());
+            return;
+        }
+        let size = u16::from_be_bytes([packet[2], packet[3]]) as usize;
+        if size > packet.len() {
+            trace!("drop WireGuard packet ({} Bytes): truncated", packet.len());
+            return;
+        }
+        packet.truncate(size);
+
+        if !self.nat.translate_inbound(&mut packet) {
+            trace!(
+                "drop WireGuard packet ({} Bytes): untranslatable",
+                packet.len()
+            );
+            return;
+        }
+        if let Err(ref e) = self.tx.lock().unwrap().forward(&packet) {
+            warn!("forward WireGuard packet: {}", e);
+        }
+    }
+
+    fn update(&mut self) -> Vec<Vec<u8>> {
+        self.nat.expire();
+
+        let mut messages = Vec::new();
+
+        // Retry or give up the handshake
+        if let Some((_, instant)) = self.handshake {
+            if instant.elapsed() >= Duration::from_secs(REKEY_TIMEOUT) {
+                match self.attempt_instant {
+                    Some(attempt_instant)
+                        if attempt_instant.elapsed() >= Duration::from_secs(REKEY_ATTEMPT_TIME) =>
+                    {
+                        warn!("WireGuard handshake timed out");
+                        self.handshake = None;
+                        self.attempt_instant = None;
+                        self.queue.clear();
+                    }
+                    _ => messages.push(self.initiate()),
+                }
+            }
+        }
+
+        // Expire sessions
+        let reject_after_time = Duration::from_secs(REJECT_AFTER_TIME);
+        if let Some(ref previous) = self.previous {
+            if previous.instant().elapsed() >= reject_after_time {
+                self.previous = None;
+            }
+        }
+        if let Some(ref current) = self.current {
+            if current.instant().elapsed() >= reject_after_time {
+                self.current = None;
+                debug!("WireGuard session expired");
+            }
+        }
+
+        // Persistent keepalive
+        if let Some(keepalive) = self.keepalive {
+            if self.current.is_some() && self.send_instant.elapsed() >= keepalive {
+                if let Some(message) = self.seal(&[]) {
+                    messages.push(message);
+                }
+            }
+        }
+
+        messages
+    }
+
+    fn initiate(&mut self) -> Vec<u8> {
+        let (handshake, message) = Handshake::initiate(&self.keys, rand::thread_rng().gen());
+        self.handshake = Some((handshake, Instant::now()));
+        if self.attempt_instant.is_none() {
+            self.attempt_instant = Some(Instant::now());
+        }
+        trace!("initiate WireGuard handshake");
+
+        message
+    }
+
+    fn seal(&mut self, packet: &[u8]) -> Option<Vec<u8>> {
+        let session = self.current.as_mut()?;
+        match session.seal(packet) {
+            Ok(message) => {
+                self.send_instant = Instant::now();
+
+                Some(message)
+            }
+            Err(ref e) => {
+                warn!("seal WireGuard packet: {}", e);
+                self.current = None;
+
+                None
+            }
+        }
+    }
+}
@@ -0,0 +1,465 @@
+//! Support for translating addresses of IPv4 packets relayed through a WireGuard tunnel.
+
+use log::trace;
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::time::{Duration, Instant};
+
+const PROTOCOL_ICMPV4: u8 = 1;
+const PROTOCOL_TCP: u8 = 6;
+const PROTOCOL_UDP: u8 = 17;
+
+const ICMPV4_ECHO_REPLY: u8 = 0;
+const ICMPV4_DESTINATION_UNREACHABLE: u8 = 3;
+const ICMPV4_ECHO_REQUEST: u8 = 8;
+const ICMPV4_TIME_EXCEEDED: u8 = 11;
+
+const TCP_FLAG_SYN: u8 = 0x02;
+const TCP_OPTION_END: u8 = 0;
+const TCP_OPTION_NOP: u8 = 1;
+const TCP_OPTION_MSS: u8 = 2;
+
+/// Represents the first port of the range for mapping.
+const PORT_MIN: u16 = 10000;
+
+/// Represents the timeout of a mapping or a group of fragments.
+const NAT_TIMEOUT: u64 = 300;
+
+/// Represents the translator of addresses of IPv4 packets relayed through a WireGuard tunnel.
+/// Packets from sources are masqueraded as from the address in the tunnel, with their ports, or
+/// identifiers of ICMPv4 echoes, mapped.
+pub struct Nat {
+    addr: Ipv4Addr,
+    max_mss: u16,
+    /// Represents the map mapping a protocol and a source to a port.
+    outbound: HashMap<(u8, SocketAddrV4), u16>,
+    /// Represents the map mapping a protocol and a port to a source.
+    inbound: HashMap<(u8, u16), (SocketAddrV4, Instant)>,
+    /// Represents the map mapping a source of fragments and their identification to a source.
+    fragments: HashMap<(Ipv4Addr, u16), (Ipv4Addr, Instant)>,
+    next_port: u16,
+}
+
+impl Nat {
+    /// Creates a new `Nat`.
+    pub fn new(addr: Ipv4Addr, max_mss: u16) -> Nat {
+        Nat {
+            addr,
+            max_mss,
+            outbound: HashMap::new(),
+            inbound: HashMap::new(),
+            fragments: HashMap::new(),
+            next_port: PORT_MIN,
+        }
+    }
+
+    /// Translates a packet from a source. Returns `false` if the packet cannot be translated.
+    pub fn translate_outbound(&mut self, packet: &mut [u8]) -> bool {
+        let ihl = match header_len(packet) {
+            Some(ihl) => ihl,
+            None => return false,
+        };
+        let protocol = packet[9];
+        let src_ip_addr = Ipv4Addr::new(packet[12], packet[13], packet[14], packet[15]);
+
+        if fragment_offset(packet) == 0 {
+            let offset = match protocol {
+                PROTOCOL_TCP if packet.len() >= ihl + 20 => ihl,
+                PROTOCOL_UDP if packet.len() >= ihl + 8 => ihl,
+                PROTOCOL_ICMPV4 if packet.len() >= ihl + 8 => match packet[ihl] {
+                    ICMPV4_ECHO_REQUEST => ihl + 4,
+                    _ => return false,
+                },
+                _ => return false,
+            };
+            let src_port = u16::from_be_bytes([packet[offset], packet[offset + 1]]);
+            let port = self.map(protocol, SocketAddrV4::new(src_ip_addr, src_port));
+
+            // Port or identifier
+            packet[offset..offset + 2].copy_from_slice(&port.to_be_bytes());
+            match protocol {
+                PROTOCOL_TCP => {
+                    let checksum = ihl + 16;
+                    adjust(
+                        packet,
+                        checksum,
+                        &src_port.to_be_bytes(),
+                        &port.to_be_bytes(),
+                    );
+                    adjust(packet, checksum, &src_ip_addr.octets(), &self.addr.octets());
+                    self.clamp_mss(packet, ihl);
+                }
+                PROTOCOL_UDP => {
+                    let checksum = ihl + 6;
+                    if packet[checksum] != 0 || packet[checksum + 1] != 0 {
+                        adjust(
+                            packet,
+                            checksum,
+                            &src_port.to_be_bytes(),
+                            &port.to_be_bytes(),
+                        );
+                        adjust(packet, checksum, &src_ip_addr.octets(), &self.addr.octets());
+                        if packet[checksum] == 0 && packet[checksum + 1] == 0 {
+                            packet[checksum..checksum + 2].copy_from_slice(&[0xff, 0xff]);
+                        }
+                    }
+                }
+                PROTOCOL_ICMPV4 => adjust(
+                    packet,
+                    ihl + 2,
+                    &src_port.to_be_bytes(),
+                    &port.to_be_bytes(),
+                ),
+                _ => unreachable!(),
+            }
+        } else if protocol != PROTOCOL_TCP
+            && protocol != PROTOCOL_UDP
+            && protocol != PROTOCOL_ICMPV4
+        {
+            return false;
+        }
+
+        // Source
+        let addr = self.addr;
+        set_ip_addr(packet, 12, addr);
+
+        true
+    }
+
+    /// Translates a packet to a source. Returns `false` if the packet is not for any source.
+    pub fn translate_inbound(&mut self, packet: &mut [u8]) -> bool {
+        let ihl = match header_len(packet) {
+            Some(ihl) => ihl,
+            None => return false,
+        };
+        if packet[16..20] != self.addr.octets() {
+            return false;
+        }
+        let src_ip_addr = Ipv4Addr::new(packet[12], packet[13], packet[14], packet[15]);
+        let identification = u16::from_be_bytes([packet[4], packet[5]]);
+        let is_fragment = packet[6] & 0x20 != 0 || fragment_offset(packet) != 0;
+
+        let dst_ip_addr = if fragment_offset(packet) == 0 {
+            let dst_ip_addr = match self.translate_inbound_transport(packet, ihl, is_fragment) {
+                Some(dst_ip_addr) => dst_ip_addr,
+                None => return false,
+            };
+            if is_fragment {
+                self.fragments
+                    .insert((src_ip_addr, identification), (dst_ip_addr, Instant::now()));
+            }
+
+            dst_ip_addr
+        } else {
+            match self.fragments.get(&(src_ip_addr, identification)) {
+                Some((dst_ip_addr, _)) => *dst_ip_addr,
+                None => {
+                    trace!(
+                        "drop WireGuard fragment {} of {} before the first fragment",
+                        identification,
+                        src_ip_addr
+                    );
+                    return false;
+                }
+            }
+        };
+
+        // Destination
+        set_ip_addr(packet, 16, dst_ip_addr);
+
+        true
+    }
+
+    fn translate_inbound_transport(
+        &mut self,
+        packet: &mut [u8],
+        ihl: usize,
+        is_fragment: bool,
+    ) -> Option<Ipv4Addr> {
+        let protocol = packet[9];
+        if packet.len() < ihl + 8 || (protocol == PROTOCOL_TCP && packet.len() < ihl + 20) {
+            return None;
+        }
+
+        match protocol {
+            PROTOCOL_TCP | PROTOCOL_UDP => {
+                let port = u16::from_be_bytes([packet[ihl + 2], packet[ihl + 3]]);
+                let dst = self.lookup(protocol, port)?;
+
+                packet[ihl + 2..ihl + 4].copy_from_slice(&dst.port().to_be_bytes());
+                let checksum = match protocol {
+                    PROTOCOL_TCP => ihl + 16,
+                    _ => ihl + 6,
+                };
+                if protocol == PROTOCOL_TCP || packet[checksum] != 0 || packet[checksum + 1] != 0 {
+                    adjust(
+                        packet,
+                        checksum,
+                        &port.to_be_bytes(),
+                        &dst.port().to_be_bytes(),
+                    );
+                    adjust(packet, checksum, &self.addr.octets(), &dst.ip().octets());
+                    if protocol == PROTOCOL_UDP
+                        && packet[checksum] == 0
+                        && packet[checksum + 1] == 0
+                    {
+                        packet[checksum..checksum + 2].copy_from_slice(&[0xff, 0xff]);
+                    }
+                }
+
+                Some(*dst.ip())
+            }
+            PROTOCOL_ICMPV4 => match packet[ihl] {
+                ICMPV4_ECHO_REPLY => {
+                    let id = u16::from_be_bytes([packet[ihl + 4], packet[ihl + 5]]);
+                    let dst = self.lookup(protocol, id)?;
+
+                    packet[ihl + 4..ihl + 6].copy_from_slice(&dst.port().to_be_bytes());
+                    adjust(
+                        packet,
+                        ihl + 2,
+                        &id.to_be_bytes(),
+                        &dst.port().to_be_bytes(),
+                    );
+
+                    Some(*dst.ip())
+                }
+                ICMPV4_DESTINATION_UNREACHABLE | ICMPV4_TIME_EXCEEDED if !is_fragment => {
+                    self.translate_inbound_icmpv4_error(packet, ihl)
+                }
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn translate_inbound_icmpv4_error(
+        &mut self,
+        packet: &mut [u8],
+        ihl: usize,
+    ) -> Option<Ipv4Addr> {
+        // The original packet which caused the error
+        let inner = ihl + 8;
+        let inner_ihl = header_len(&packet[inner..])?;
+        if packet[inner + 12..inner + 16] != self.addr.octets()
+            || packet.len() < inner + inner_ihl + 8
+        {
+            return None;
+        }
+        let protocol = packet[inner + 9];
+        let offset = match protocol {
+            PROTOCOL_TCP | PROTOCOL_UDP => inner + inner_ihl,
+            PROTOCOL_ICMPV4 => inner + inner_ihl + 4,
+            _ => return None,
+        };
+        let port = u16::from_be_bytes([packet[offset], packet[offset + 1]]);
+        let src = self.lookup(protocol, port)?;
+
+        packet[offset..offset + 2].copy_from_slice(&src.port().to_be_bytes());
+        set_ip_addr(&mut packet[inner..], 12, *src.ip());
+
+        // Recompute the checksum of the ICMPv4 message
+        packet[ihl + 2..ihl + 4].copy_from_slice(&[0, 0]);
+        let checksum = checksum(&packet[ihl..]);
+        packet[ihl + 2..ihl + 4].copy_from_slice(&checksum.to_be_bytes());
+
+        Some(*src.ip())
+    }
+
+    /// Removes the mappings and the groups of fragments which are idle.
+    pub fn expire(&mut self) {
+        let timeout = Duration::from_secs(NAT_TIMEOUT);
+
+        let inbound = &mut self.inbound;
+        self.outbound.retain(
+            |(protocol, _), port| match inbound.get(&(*protocol, *port)) {
+                Some((_, instant)) if instant.elapsed() < timeout => true,
+                _ => {
+                    inbound.remove(&(*protocol, *port));
+                    false
+                }
+            },
+        );
+        self.fragments
+            .retain(|_, (_, instant)| instant.elapsed() < timeout);
+    }
+
+    fn map(&mut self, protocol: u8, src: SocketAddrV4) -> u16 {
+        if let Some(port) = self.outbound.get(&(protocol, src)) {
+            let port = *port;
+            if let Some((_, instant)) = self.inbound.get_mut(&(protocol, port)) {
+                *instant = Instant::now();
+            }
+
+            return port;
+        }
+
+        // Find an unused port, or reuse the least recently used one
+        let mut port = self.next_port;
+        let mut lru: Option<(u16, Instant)> = None;
+        for _ in PORT_MIN..=u16::MAX {
+            port = self.next_port;
+            self.next_port = match self.next_port {
+                u16::MAX => PORT_MIN,
+                _ => self.next_port + 1,
+            };
+            match self.inbound.get(&(protocol, port)) {
+                Some((_, instant)) => {
+                    if lru.map_or(true, |(_, lru_instant)| *instant < lru_instant) {
+                        lru = Some((port, *instant));
+                    }
+                }
+                None => {
+                    lru = None;
+                    break;
+                }
+            }
+        }
+        if let Some((lru_port, _)) = lru {
+            port = lru_port;
+            if let Some((prev_src, _)) = self.inbound.remove(&(protocol, port)) {
+                self.outbound.remove(&(protocol, prev_src));
+            }
+        }
+
+        self.outbound.insert((protocol, src), port);
+        self.inbound.insert((protocol, port), (src, Instant::now()));
+        trace!("map WireGuard {} {} to port {}", protocol, src, port);
+
+        port
+    }
+
+    fn lookup(&mut self, protocol: u8, port: u16) -> Option<SocketAddrV4> {
+        let (src, instant) = self.inbound.get_mut(&(protocol, port))?;
+        *instant = Instant::now();
+
+        Some(*src)
+    }
+
+    fn clamp_mss(&self, packet: &mut [u8], ihl: usize) {
+        if packet[ihl + 13] & TCP_FLAG_SYN == 0 {
+            return;
+        }
+        let end = ihl + (packet[ihl + 12] >> 4) as usize * 4;
+        if end > packet.len() {
+            return;
+        }
+
+        let mut i = ihl + 20;
+        while i < end {
+            match packet[i] {
+                TCP_OPTION_END => break,
+                TCP_OPTION_NOP => i += 1,
+                kind => {
+                    if i + 1 >= end || packet[i + 1] < 2 {
+                        break;
+                    }
+                    let len = packet[i + 1] as usize;
+                    if kind == TCP_OPTION_MSS && len == 4 && i + 4 <= end {
+                        let mss = u16::from_be_bytes([packet[i + 2], packet[i + 3]]);
+                        if mss > self.max_mss {
+                            packet[i + 2..i + 4].copy_from_slice(&self.max_mss.to_be_bytes());
+                            adjust(
+                                packet,
+                                ihl + 16,
+                                &mss.to_be_bytes(),
+                                &self.max_mss.to_be_bytes(),
+                            );
+                            trace!("clamp WireGuard MSS {} to {}", mss, self.max_mss);
+                        }
+                    }
+                    i += len;
+                }
+            }
+        }
+    }
+}
+
+/// Returns the length of the header of an IPv4 packet.
+fn header_len(packet: &[u8]) -> Option<usize> {
+    if packet.len() < 20 || packet[0] >> 4 != 4 {
+        return None;
+    }
+    let ihl = (packet[0] & 0x0f) as usize * 4;
+    if ihl < 20 || packet.len() < ihl {
+        return None;
+    }
+
+    Some(ihl)
+}
+
+fn fragment_offset(packet: &[u8]) -> u16 {
+    u16::from_be_bytes([packet[6] & 0x1f, packet[7]])
+}
+
+/// Sets the address at the offset of an IPv4 header, and updates the checksum of the header.
+fn set_ip_addr(packet: &mut [u8], offset: usize, addr: Ipv4Addr) {
+    let mut prev = [0u8; 4];
+    prev.copy_from_slice(&packet[offset..offset + 4]);
+
+    packet[offset..offset + 4].copy_from_slice(&addr.octets());
+    adjust(packet, 10, &prev, &addr.octets());
+}
+
+/// Updates the checksum at the offset incrementally, as described in RFC 1624.
+fn adjust(packet: &mut [u8], offset: usize, prev: &[u8], next: &[u8]) {
+    let mut sum = !u16::from_be_bytes([packet[offset], packet[offset + 1]]) as u32;
+    for word in prev.chunks(2) {
+        sum += !u16::from_be_bytes([word[0], word[1]]) as u32;
+    }
+    for word in next.chunks(2) {
+        sum += u16::from_be_bytes([word[0], word[1]]) as u32;
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+
+    packet[offset..offset + 2].copy_from_slice(&(!(sum as u16)).to_be_bytes());
+}
+
+/// Returns the Internet checksum of the data.
+fn checksum(data: &[u8]) -> u16 {
+    let mut sum = 0u32;
+    for word in data.chunks(2) {
+        sum += match word.len() {
+            2 => u16::from_be_bytes([word[0], word[1]]) as u32,
+            _ => (word[0] as u32) << 8,
+        };
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+
+    !(sum as u16)
+}
+
+#[test]
+fn wireguard_nat() {
+    let mut packet = vec![
+        // IPv4
+        0x45, 0x00, 0x00, 0x1c, 0x00, 0x01, 0x00, 0x00, 0x40, 0x11, 0x00, 0x00, 10, 6, 0, 1, 1, 1,
+        1, 1, // UDP
+        0x30, 0x39, 0x00, 0x35, 0x00, 0x08, 0x00, 0x00,
+    ];
+    let ip_checksum = checksum(&packet[..20]);
+    packet[10..12].copy_from_slice(&ip_checksum.to_be_bytes());
+    let original = packet.clone();
+
+    let mut nat = Nat::new(Ipv4Addr::new(10, 0, 0, 2), 1380);
+    assert!(nat.translate_outbound(&mut packet));
+    assert_eq!(&packet[12..16], &[10, 0, 0, 2]);
+    assert_eq!(u16::from_be_bytes([packet[20], packet[21]]), PORT_MIN);
+    assert_eq!(checksum(&packet[..20]), 0);
+
+    // Reply
+    packet.swap(12, 16);
+    packet.swap(13, 17);
+    packet.swap(14, 18);
+    packet.swap(15, 19);
+    packet.swap(20, 22);
+    packet.swap(21, 23);
+    assert!(nat.translate_inbound(&mut packet));
+    assert_eq!(&packet[16..20], &original[12..16]);
+    assert_eq!(&packet[22..24], &original[20..22]);
+    assert_eq!(checksum(&packet[..20]), 0);
+}
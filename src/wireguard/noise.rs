@@ -0,0 +1,386 @@
+//! Support for the Noise_IKpsk2 handshake and the transport data messages of WireGuard.
+
+use blake2s_simd::{self, Params};
+use chacha20poly1305::aead::generic_array::GenericArray;
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::ChaCha20Poly1305;
+use rand::{self, Rng};
+use std::io;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+const CONSTRUCTION: &[u8] = b"Noise_IKpsk2_25519_ChaChaPoly_BLAKE2s";
+const IDENTIFIER: &[u8] = b"WireGuard v1 zx2c4 Jason@zx2c4.com";
+const LABEL_MAC1: &[u8] = b"mac1----";
+
+pub const MESSAGE_INITIATION: u8 = 1;
+pub const MESSAGE_RESPONSE: u8 = 2;
+pub const MESSAGE_COOKIE_REPLY: u8 = 3;
+pub const MESSAGE_TRANSPORT: u8 = 4;
+
+const INITIATION_SIZE: usize = 148;
+const RESPONSE_SIZE: usize = 92;
+const TRANSPORT_HEADER_SIZE: usize = 16;
+const TAG_SIZE: usize = 16;
+const HMAC_BLOCK_SIZE: usize = 64;
+const PADDING_MULTIPLE: usize = 16;
+
+/// Represents the max count of messages sent or received in a session.
+const REJECT_AFTER_MESSAGES: u64 = u64::MAX - (1 << 13);
+
+/// Represents the size in bits of the window of the replay filter.
+const REPLAY_WINDOW_SIZE: u64 = 64;
+
+fn hash(parts: &[&[u8]]) -> [u8; 32] {
+    let mut state = blake2s_simd::State::new();
+    for part in parts {
+        state.update(part);
+    }
+
+    let mut result = [0u8; 32];
+    result.copy_from_slice(state.finalize().as_bytes());
+
+    result
+}
+
+fn mac(key: &[u8], data: &[u8]) -> [u8; 16] {
+    let mut result = [0u8; 16];
+    result.copy_from_slice(Params::new().hash_length(16).key(key).hash(data).as_bytes());
+
+    result
+}
+
+fn hmac(key: &[u8], parts: &[&[u8]]) -> [u8; 32] {
+    let mut pad = [0u8; HMAC_BLOCK_SIZE];
+    pad[..key.len()].copy_from_slice(key);
+
+    let ipad: Vec<_> = pad.iter().map(|b| b ^ 0x36).collect();
+    let mut inner_parts = vec![ipad.as_slice()];
+    inner_parts.extend_from_slice(parts);
+    let inner = hash(&inner_parts);
+    let opad: Vec<_> = pad.iter().map(|b| b ^ 0x5c).collect();
+
+    hash(&[&opad, &inner])
+}
+
+/// Derives `n` keys from the chaining key and the input.
+fn kdf(key: &[u8; 32], input: &[u8], n: usize) -> Vec<[u8; 32]> {
+    let tau = hmac(key, &[input]);
+
+    let mut keys: Vec<[u8; 32]> = Vec::with_capacity(n);
+    for i in 1..=n {
+        let key = match keys.last() {
+            Some(prev) => hmac(&tau, &[prev, &[i as u8]]),
+            None => hmac(&tau, &[&[i as u8]]),
+        };
+        keys.push(key);
+    }
+
+    keys
+}
+
+fn nonce(counter: u64) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&counter.to_le_bytes());
+
+    nonce
+}
+
+fn seal(key: &[u8; 32], counter: u64, plaintext: &[u8], aad: &[u8]) -> Vec<u8> {
+    ChaCha20Poly1305::new(GenericArray::from_slice(key))
+        .encrypt(
+            GenericArray::from_slice(&nonce(counter)),
+            Payload {
+                msg: plaintext,
+                aad,
+            },
+        )
+        .unwrap()
+}
+
+fn open(key: &[u8; 32], counter: u64, ciphertext: &[u8], aad: &[u8]) -> io::Result<Vec<u8>> {
+    ChaCha20Poly1305::new(GenericArray::from_slice(key))
+        .decrypt(
+            GenericArray::from_slice(&nonce(counter)),
+            Payload {
+                msg: ciphertext,
+                aad,
+            },
+        )
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid WireGuard message"))
+}
+
+fn dh(private_key: &StaticSecret, public_key: &[u8; 32]) -> [u8; 32] {
+    *private_key
+        .diffie_hellman(&PublicKey::from(*public_key))
+        .as_bytes()
+}
+
+/// Returns the current time in TAI64N.
+fn tai64n() -> [u8; 12] {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+
+    let mut timestamp = [0u8; 12];
+    timestamp[..8].copy_from_slice(&(0x400000000000000a + now.as_secs()).to_be_bytes());
+    timestamp[8..].copy_from_slice(&now.subsec_nanos().to_be_bytes());
+
+    timestamp
+}
+
+/// Represents the keys of the local and of the peer.
+pub struct Keys {
+    private_key: StaticSecret,
+    public_key: [u8; 32],
+    peer_public_key: [u8; 32],
+    preshared_key: [u8; 32],
+    mac1_key: [u8; 32],
+}
+
+impl Keys {
+    pub fn new(private_key: [u8; 32], peer_public_key: [u8; 32], preshared_key: [u8; 32]) -> Keys {
+        let private_key = StaticSecret::from(private_key);
+        let public_key = *PublicKey::from(&private_key).as_bytes();
+
+        Keys {
+            private_key,
+            public_key,
+            peer_public_key,
+            preshared_key,
+            mac1_key: hash(&[LABEL_MAC1, &peer_public_key]),
+        }
+    }
+}
+
+/// Represents a handshake initiated by the local.
+pub struct Handshake {
+    sender_index: u32,
+    chaining_key: [u8; 32],
+    hash: [u8; 32],
+    ephemeral: StaticSecret,
+}
+
+impl Handshake {
+    /// Initiates a handshake. Returns the handshake and its initiation message.
+    pub fn initiate(keys: &Keys, sender_index: u32) -> (Handshake, Vec<u8>) {
+        let ephemeral = StaticSecret::from(rand::thread_rng().gen::<[u8; 32]>());
+        let ephemeral_public = *PublicKey::from(&ephemeral).as_bytes();
+
+        let chaining_key = hash(&[CONSTRUCTION]);
+        let h = hash(&[&chaining_key, IDENTIFIER]);
+        let h = hash(&[&h, &keys.peer_public_key]);
+
+        let chaining_key = kdf(&chaining_key, &ephemeral_public, 1)[0];
+        let h = hash(&[&h, &ephemeral_public]);
+
+        let derived = kdf(&chaining_key, &dh(&ephemeral, &keys.peer_public_key), 2);
+        let (chaining_key, key) = (derived[0], derived[1]);
+        let encrypted_static = seal(&key, 0, &keys.public_key, &h);
+        let h = hash(&[&h, &encrypted_static]);
+
+        let derived = kdf(
+            &chaining_key,
+            &dh(&keys.private_key, &keys.peer_public_key),
+            2,
+        );
+        let (chaining_key, key) = (derived[0], derived[1]);
+        let encrypted_timestamp = seal(&key, 0, &tai64n(), &h);
+        let h = hash(&[&h, &encrypted_timestamp]);
+
+        let mut message = Vec::with_capacity(INITIATION_SIZE);
+        message.extend_from_slice(&[MESSAGE_INITIATION, 0, 0, 0]);
+        message.extend_from_slice(&sender_index.to_le_bytes());
+        message.extend_from_slice(&ephemeral_public);
+        message.extend_from_slice(&encrypted_static);
+        message.extend_from_slice(&encrypted_timestamp);
+        let mac1 = mac(&keys.mac1_key, &message);
+        message.extend_from_slice(&mac1);
+        // No cookie
+        message.extend_from_slice(&[0u8; 16]);
+
+        (
+            Handshake {
+                sender_index,
+                chaining_key,
+                hash: h,
+                ephemeral,
+            },
+            message,
+        )
+    }
+
+    /// Returns the index of the handshake, which is the receiver index of the response.
+    pub fn sender_index(&self) -> u32 {
+        self.sender_index
+    }
+
+    /// Consumes the response of the handshake and returns the established session.
+    pub fn consume_response(&self, keys: &Keys, message: &[u8]) -> io::Result<Session> {
+        if message.len() != RESPONSE_SIZE || message[0] != MESSAGE_RESPONSE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid WireGuard handshake response",
+            ));
+        }
+        let receiver_index = u32::from_le_bytes([message[4], message[5], message[6], message[7]]);
+        let mut ephemeral_public = [0u8; 32];
+        ephemeral_public.copy_from_slice(&message[12..44]);
+        let encrypted_nothing = &message[44..60];
+
+        let chaining_key = kdf(&self.chaining_key, &ephemeral_public, 1)[0];
+        let h = hash(&[&self.hash, &ephemeral_public]);
+        let chaining_key = kdf(&chaining_key, &dh(&self.ephemeral, &ephemeral_public), 1)[0];
+        let chaining_key = kdf(&chaining_key, &dh(&keys.private_key, &ephemeral_public), 1)[0];
+
+        let derived = kdf(&chaining_key, &keys.preshared_key, 3);
+        let (chaining_key, tau, key) = (derived[0], derived[1], derived[2]);
+        let h = hash(&[&h, &tau]);
+        open(&key, 0, encrypted_nothing, &h)?;
+
+        let derived = kdf(&chaining_key, &[], 2);
+
+        Ok(Session {
+            sender_index: self.sender_index,
+            receiver_index,
+            send_key: derived[0],
+            recv_key: derived[1],
+            send_counter: 0,
+            replay: ReplayFilter::default(),
+            instant: Instant::now(),
+        })
+    }
+}
+
+/// Represents a filter of replayed counters of transport data messages.
+#[derive(Default)]
+struct ReplayFilter {
+    next: u64,
+    bitmap: u64,
+}
+
+impl ReplayFilter {
+    /// Updates the filter with the counter. Returns `false` if the counter has been seen or is
+    /// too old.
+    fn update(&mut self, counter: u64) -> bool {
+        if counter >= REJECT_AFTER_MESSAGES {
+            return false;
+        }
+        if counter >= self.next {
+            let shift = counter - self.next + 1;
+            self.bitmap = if shift >= REPLAY_WINDOW_SIZE {
+                0
+            } else {
+                self.bitmap << shift
+            };
+            self.bitmap |= 1;
+            self.next = counter + 1;
+
+            return true;
+        }
+
+        let offset = self.next - 1 - counter;
+        if offset >= REPLAY_WINDOW_SIZE || self.bitmap & (1 << offset) != 0 {
+            return false;
+        }
+        self.bitmap |= 1 << offset;
+
+        true
+    }
+}
+
+/// Represents a session established by a handshake.
+pub struct Session {
+    sender_index: u32,
+    receiver_index: u32,
+    send_key: [u8; 32],
+    recv_key: [u8; 32],
+    send_counter: u64,
+    replay: ReplayFilter,
+    instant: Instant,
+}
+
+impl Session {
+    /// Seals the packet into a transport data message. An empty packet is a keepalive.
+    pub fn seal(&mut self, packet: &[u8]) -> io::Result<Vec<u8>> {
+        if self.send_counter >= REJECT_AFTER_MESSAGES {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "WireGuard session is exhausted",
+            ));
+        }
+        let counter = self.send_counter;
+        self.send_counter += 1;
+
+        // Pad the packet
+        let size = (packet.len() + PADDING_MULTIPLE - 1) / PADDING_MULTIPLE * PADDING_MULTIPLE;
+        let mut padded = packet.to_vec();
+        padded.resize(size, 0);
+
+        let mut message = Vec::with_capacity(TRANSPORT_HEADER_SIZE + size + TAG_SIZE);
+        message.extend_from_slice(&[MESSAGE_TRANSPORT, 0, 0, 0]);
+        message.extend_from_slice(&self.receiver_index.to_le_bytes());
+        message.extend_from_slice(&counter.to_le_bytes());
+        message.extend_from_slice(&seal(&self.send_key, counter, &padded, &[]));
+
+        Ok(message)
+    }
+
+    /// Opens a transport data message. Returns the packet with paddings, or an empty one if it is
+    /// a keepalive.
+    pub fn open(&mut self, message: &[u8]) -> io::Result<Vec<u8>> {
+        if message.len() < TRANSPORT_HEADER_SIZE + TAG_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid WireGuard message",
+            ));
+        }
+        let mut counter = [0u8; 8];
+        counter.copy_from_slice(&message[8..16]);
+        let counter = u64::from_le_bytes(counter);
+
+        let packet = open(
+            &self.recv_key,
+            counter,
+            &message[TRANSPORT_HEADER_SIZE..],
+            &[],
+        )?;
+        if !self.replay.update(counter) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "replayed WireGuard message",
+            ));
+        }
+
+        Ok(packet)
+    }
+
+    /// Returns the index of the local, which is the receiver index of messages from the peer.
+    pub fn sender_index(&self) -> u32 {
+        self.sender_index
+    }
+
+    /// Returns the instant when the session is established.
+    pub fn instant(&self) -> Instant {
+        self.instant
+    }
+}
+
+/// Returns the receiver index of a response or a transport data message.
+pub fn receiver_index(message: &[u8]) -> Option<u32> {
+    let offset = match message.first() {
+        Some(&MESSAGE_RESPONSE) => 8,
+        Some(&MESSAGE_TRANSPORT) | Some(&MESSAGE_COOKIE_REPLY) => 4,
+        _ => return None,
+    };
+    if message.len() < offset + 4 {
+        return None;
+    }
+
+    Some(u32::from_le_bytes([
+        message[offset],
+        message[offset + 1],
+        message[offset + 2],
+        message[offset + 3],
+    ]))
+}